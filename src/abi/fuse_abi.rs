@@ -7,6 +7,7 @@
 #![allow(missing_docs)]
 
 use std::mem;
+use std::time::Duration;
 
 use bitflags::bitflags;
 use vm_memory::ByteValued;
@@ -42,6 +43,21 @@ pub const KERNEL_MINOR_VERSION_INIT_22_OUT_SIZE: u32 = 23;
 /// Lookup negative dentry using inode number 0
 pub const KERNEL_MINOR_VERSION_LOOKUP_NEGATIVE_ENTRY_ZERO: u32 = 4;
 
+/// FUSE_NOTIFY_INVAL_INODE and FUSE_NOTIFY_INVAL_ENTRY are supported
+pub const KERNEL_MINOR_VERSION_NOTIFY_INVAL: u32 = 12;
+
+/// FUSE_NOTIFY_RETRIEVE is supported
+pub const KERNEL_MINOR_VERSION_NOTIFY_RETRIEVE: u32 = 15;
+
+/// FUSE_NOTIFY_DELETE is supported
+pub const KERNEL_MINOR_VERSION_NOTIFY_DELETE: u32 = 18;
+
+/// The INIT request and reply carry extended flags with `INIT_EXT`
+pub const KERNEL_MINOR_VERSION_INIT_EXT: u32 = 36;
+
+/// Maximum length of names in directory entry notifications.
+pub const FUSE_NAME_MAX: usize = 1024;
+
 /// The ID of the inode corresponding to the root directory of the file system.
 pub const ROOT_ID: u64 = 1;
 
@@ -94,15 +110,31 @@ const FOPEN_CACHE_DIR: u32 = 8;
 /// the file is stream-like (no file position at all)
 const FOPEN_STREAM: u32 = 16;
 
+/// don't flush data cache on close (unless FUSE_WRITEBACK_CACHE)
+const FOPEN_NOFLUSH: u32 = 32;
+
 bitflags! {
     /// Options controlling the behavior of files opened by the server in response
     /// to an open or create request.
     pub struct OpenOptions: u32 {
+        /// Bypass the page cache, so every read and write is forwarded to the file system and a
+        /// short read is returned as is to the application instead of being treated as EOF.
         const DIRECT_IO = FOPEN_DIRECT_IO;
+        /// Don't invalidate the data cache on open.
         const KEEP_CACHE = FOPEN_KEEP_CACHE;
+        /// The file is not seekable, `lseek(2)`, `pread(2)` and `pwrite(2)` fail with `ESPIPE`.
         const NONSEEKABLE = FOPEN_NONSEEKABLE;
+        /// Allow caching this directory.
         const CACHE_DIR = FOPEN_CACHE_DIR;
+        /// The file is stream-like and has no file position at all, so the offset of read and
+        /// write requests is meaningless. Reads and writes of the file are not serialized by the
+        /// kernel either. Usually combined with `NONSEEKABLE` and `DIRECT_IO` for pipe-like files.
         const STREAM = FOPEN_STREAM;
+        /// Don't send a flush request when the file is closed, unless writeback caching is
+        /// enabled. Kernels older than protocol 7.35 ignore it and always flush. Locks held by the
+        /// closing process are then released by the kernel alone, so it must not be set when the
+        /// file system keeps POSIX locks itself.
+        const NOFLUSH = FOPEN_NOFLUSH;
     }
 }
 
@@ -199,6 +231,30 @@ const HANDLE_KILLPRIV_V2: u32 = 0x1000_0000;
 // This flag indicates whether the guest kernel enable per-file dax
 const PERFILE_DAX: u32 = 0x4000_0000;
 
+/// Extended flags are carried by `InitInExt::flags2` and `InitOut::flags2`.
+///
+/// Kernels since 7.36 use the bit of `PERFILE_DAX` for it, and carry per-file DAX by the
+/// `HAS_INODE_DAX` bit of the extended flags instead.
+pub const INIT_EXT: u32 = 0x4000_0000;
+
+// Flags carried by flags2 of the INIT request/reply, see `FsOptionsExt`.
+
+// Add security context to create, mkdir, symlink, and mknod requests.
+const SECURITY_CTX: u32 = 0x1;
+
+// Per-file DAX, the upstream counterpart of `PERFILE_DAX`, exposed as `FsOptions::PERFILE_DAX`.
+pub(crate) const HAS_INODE_DAX: u32 = 0x2;
+
+// Add supplementary group info to create, mkdir, symlink and mknod requests.
+const CREATE_SUPP_GROUP: u32 = 0x4;
+
+// The file system may be stacked on top of other file systems, up to `InitOut::max_stack_depth`.
+const PASSTHROUGH: u32 = 0x20;
+
+/// Maximum depth of stacked file systems supported by the kernel, e.g. an overlayfs on top of a
+/// FUSE file system backed by another FUSE mount.
+pub const FILESYSTEM_MAX_STACK_DEPTH: u32 = 2;
+
 /**
  *
  * fuse_attr flags
@@ -431,6 +487,9 @@ bitflags! {
         const MAP_ALIGNMENT = MAP_ALIGNMENT;
 
         /// Kernel supports the ATTR_SUBMOUNT flag.
+        ///
+        /// It's only supported by the virtiofs driver of the kernel, since Linux 5.10 and protocol
+        /// version 7.32.
         const SUBMOUNTS = SUBMOUNTS;
 
         /// Filesystem responsible for clearing security.capability xattr and setuid/setgid bits.
@@ -448,10 +507,51 @@ bitflags! {
         ///
         /// If this feature is enabled, filesystem will notify guest kernel whether file
         /// enable DAX by EntryOut.Attr.flags of inode when lookup
+        ///
+        /// Kernels since 7.36 negotiate it by the `HAS_INODE_DAX` extended flag instead, the bit
+        /// of this option being `INIT_EXT` there.
         const PERFILE_DAX = PERFILE_DAX;
     }
 }
 
+bitflags! {
+    /// Extended options carried by the `flags2` field of the INIT request and reply, negotiated
+    /// by the `init_ext` method of the `FileSystem` trait after [FsOptions].
+    ///
+    /// The kernel only sends them since protocol 7.36, with `INIT_EXT` set.
+    pub struct FsOptionsExt: u32 {
+        /// Indicates that the kernel sends the security context of the new node with create,
+        /// mkdir, mknod and symlink requests, which the file system should apply to the node.
+        ///
+        /// The security context is passed to `FileSystem::init_security_context()` once the node
+        /// has been created.
+        ///
+        /// This feature is disabled by default.
+        const SECURITY_CTX = SECURITY_CTX;
+
+        /// Indicates that the kernel sends the supplementary groups of the caller with create,
+        /// mkdir, mknod and symlink requests, as an extension of the requests.
+        ///
+        /// The groups are available by `Extensions::supp_groups()` of the request context.
+        ///
+        /// This feature is disabled by default.
+        const CREATE_SUPP_GROUP = CREATE_SUPP_GROUP;
+
+        /// Indicates that the file system may be stacked on top of other stacking file systems,
+        /// e.g. when serving a directory on another FUSE or overlayfs mount.
+        ///
+        /// The kernel limits the depth of stacked file systems to `FILESYSTEM_MAX_STACK_DEPTH`
+        /// to bound the recursion of operations through the layers, which could otherwise
+        /// overflow the kernel stack. With this option the FUSE mount reports the depth returned
+        /// by `FileSystem::max_stack_depth()`, so the kernel refuses to stack it deeper than
+        /// that, e.g. a depth of 2 doesn't allow an overlayfs on top of the FUSE mount, while a
+        /// depth of 1 does. The kernel ignores the option if `WRITEBACK_CACHE` is enabled.
+        ///
+        /// This feature is disabled by default.
+        const PASSTHROUGH = PASSTHROUGH;
+    }
+}
+
 // Release flags.
 pub const RELEASE_FLUSH: u32 = 1;
 pub const RELEASE_FLOCK_UNLOCK: u32 = 2;
@@ -536,6 +636,17 @@ pub const POLL_SCHEDULE_NOTIFY: u32 = 1;
 /// Sync data only, not metadata
 pub const FSYNC_FDATASYNC: u32 = 1;
 
+/// Opcode of the CUSE_INIT request, which starts a CUSE connection instead of FUSE_INIT.
+pub const CUSE_INIT: u32 = 4096;
+
+/// CUSE_INIT flags
+///
+/// Allow unrestricted ioctls on the character device.
+pub const CUSE_UNRESTRICTED_IOCTL: u32 = 1;
+
+/// Maximum length of the device information following `CuseInitOut`.
+pub const CUSE_INIT_INFO_MAX: usize = 4096;
+
 /// The read buffer is required to be at least 8k, but may be much larger.
 pub const FUSE_MIN_READ_BUFFER: u32 = 8192;
 
@@ -732,7 +843,13 @@ pub enum Opcode {
     CopyFileRange = 47,
     SetupMapping = 48,
     RemoveMapping = 49,
-    MaxOpcode = 50,
+    Syncfs = 50,
+    Tmpfile = 51,
+    Statx = 52,
+    MaxOpcode = 53,
+
+    /* Android specific opcodes, out of the range of upstream opcodes */
+    CanonicalPath = 2016,
 
     /* Reserved opcodes: helpful to detect structure endian-ness in case of e.g. virtiofs */
     CuseInitBswapReserved = 1_048_576, /* CUSE_INIT << 8 */
@@ -773,6 +890,27 @@ pub struct EntryOut {
 }
 unsafe impl ByteValued for EntryOut {}
 
+impl EntryOut {
+    /// Build the reply for an entry, with both cache timeouts split into seconds and nanoseconds.
+    pub fn new(
+        nodeid: u64,
+        generation: u64,
+        attr: Attr,
+        attr_timeout: Duration,
+        entry_timeout: Duration,
+    ) -> EntryOut {
+        EntryOut {
+            nodeid,
+            generation,
+            entry_valid: entry_timeout.as_secs(),
+            attr_valid: attr_timeout.as_secs(),
+            entry_valid_nsec: entry_timeout.subsec_nanos(),
+            attr_valid_nsec: attr_timeout.subsec_nanos(),
+            attr,
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone)]
 pub struct ForgetIn {
@@ -805,6 +943,117 @@ pub struct GetattrIn {
 }
 unsafe impl ByteValued for GetattrIn {}
 
+/// A timestamp of [Statx].
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct SxTime {
+    pub tv_sec: i64,
+    pub tv_nsec: u32,
+    pub reserved: i32,
+}
+unsafe impl ByteValued for SxTime {}
+
+/// Extended attributes of a file, as returned by `statx(2)`.
+///
+/// `mask` tells the `STATX_*` fields filled, and `attributes_mask` the `STATX_ATTR_*` flags of
+/// `attributes` supported.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Statx {
+    pub mask: u32,
+    pub blksize: u32,
+    pub attributes: u64,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u16,
+    pub spare0: u16,
+    pub ino: u64,
+    pub size: u64,
+    pub blocks: u64,
+    pub attributes_mask: u64,
+    pub atime: SxTime,
+    pub btime: SxTime,
+    pub ctime: SxTime,
+    pub mtime: SxTime,
+    pub rdev_major: u32,
+    pub rdev_minor: u32,
+    pub dev_major: u32,
+    pub dev_minor: u32,
+    pub spare2: [u64; 14],
+}
+unsafe impl ByteValued for Statx {}
+
+// Fields of `Statx` filled from a `stat64`, i.e. `STATX_BASIC_STATS`.
+pub const STATX_BASIC_STATS: u32 = 0x7ff;
+
+impl From<stat64> for Statx {
+    fn from(st: stat64) -> Statx {
+        // Device numbers are encoded the same way as glibc does.
+        let dev_major = |dev: u64| (((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff)) as u32;
+        let dev_minor = |dev: u64| ((dev & 0xff) | ((dev >> 12) & !0xff)) as u32;
+        let time = |sec: i64, nsec: i64| SxTime {
+            tv_sec: sec,
+            tv_nsec: nsec as u32,
+            reserved: 0,
+        };
+
+        Statx {
+            mask: STATX_BASIC_STATS,
+            blksize: st.st_blksize as u32,
+            nlink: st.st_nlink as u32,
+            uid: st.st_uid,
+            gid: st.st_gid,
+            mode: st.st_mode as u16,
+            ino: st.st_ino,
+            size: st.st_size as u64,
+            blocks: st.st_blocks as u64,
+            atime: time(st.st_atime, st.st_atime_nsec),
+            ctime: time(st.st_ctime, st.st_ctime_nsec),
+            mtime: time(st.st_mtime, st.st_mtime_nsec),
+            rdev_major: dev_major(st.st_rdev),
+            rdev_minor: dev_minor(st.st_rdev),
+            dev_major: dev_major(st.st_dev),
+            dev_minor: dev_minor(st.st_dev),
+            ..Default::default()
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct StatxIn {
+    pub getattr_flags: u32,
+    pub reserved: u32,
+    pub fh: u64,
+    pub sx_flags: u32,
+    pub sx_mask: u32,
+}
+unsafe impl ByteValued for StatxIn {}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct StatxOut {
+    pub attr_valid: u64, /* Cache timeout for the attributes */
+    pub attr_valid_nsec: u32,
+    pub flags: u32,
+    pub spare: [u64; 2],
+    pub stat: Statx,
+}
+unsafe impl ByteValued for StatxOut {}
+
+impl StatxOut {
+    /// Build the reply for the attributes `stat`, which may be cached for `timeout`.
+    pub fn new(stat: Statx, timeout: Duration) -> StatxOut {
+        StatxOut {
+            attr_valid: timeout.as_secs(),
+            attr_valid_nsec: timeout.subsec_nanos(),
+            stat,
+            ..Default::default()
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone)]
 pub struct AttrOut {
@@ -815,6 +1064,23 @@ pub struct AttrOut {
 }
 unsafe impl ByteValued for AttrOut {}
 
+impl AttrOut {
+    /// Build the reply for the attributes `attr`, which may be cached for `timeout`.
+    pub fn new(attr: Attr, timeout: Duration) -> AttrOut {
+        AttrOut {
+            attr_valid: timeout.as_secs(),
+            attr_valid_nsec: timeout.subsec_nanos(),
+            dummy: 0,
+            attr,
+        }
+    }
+
+    /// Build the reply from the result of `stat(2)`.
+    pub fn from_stat(st: &stat64, timeout: Duration) -> AttrOut {
+        AttrOut::new(Attr::from(*st), timeout)
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone)]
 pub struct MknodIn {
@@ -928,6 +1194,17 @@ pub struct OpenOut {
 }
 unsafe impl ByteValued for OpenOut {}
 
+impl OpenOut {
+    /// Build the reply for an open handle, `fh` is zero if the file system doesn't use handles.
+    pub fn new(fh: u64, opts: OpenOptions) -> OpenOut {
+        OpenOut {
+            fh,
+            open_flags: opts.bits(),
+            padding: 0,
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone)]
 pub struct ReleaseIn {
@@ -982,6 +1259,13 @@ pub struct WriteOut {
 }
 unsafe impl ByteValued for WriteOut {}
 
+impl WriteOut {
+    /// Build the reply for `size` bytes written.
+    pub fn new(size: u32) -> WriteOut {
+        WriteOut { size, padding: 0 }
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone)]
 pub struct StatfsOut {
@@ -1062,6 +1346,15 @@ pub struct InitIn {
 }
 unsafe impl ByteValued for InitIn {}
 
+/// Extension of the INIT request, following `InitIn` if `INIT_EXT` is set by the kernel.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct InitInExt {
+    pub flags2: u32,
+    pub unused: [u32; 11],
+}
+unsafe impl ByteValued for InitInExt {}
+
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone)]
 pub struct InitOut {
@@ -1075,10 +1368,82 @@ pub struct InitOut {
     pub time_gran: u32,
     pub max_pages: u16,
     pub map_alignment: u16,
-    pub unused: [u32; 8],
+    pub flags2: u32,
+    pub max_stack_depth: u32,
+    pub unused: [u32; 6],
 }
 unsafe impl ByteValued for InitOut {}
 
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct CuseInitIn {
+    pub major: u32,
+    pub minor: u32,
+    pub unused: u32,
+    pub flags: u32,
+}
+unsafe impl ByteValued for CuseInitIn {}
+
+/// Reply of the CUSE_INIT request, followed by the device information.
+///
+/// The device information is a list of nul terminated `KEY=VALUE` strings, such as
+/// `DEVNAME=ttyS9`.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct CuseInitOut {
+    pub major: u32,
+    pub minor: u32,
+    pub unused: u32,
+    pub flags: u32,
+    pub max_read: u32,
+    pub max_write: u32,
+    pub dev_major: u32,
+    pub dev_minor: u32,
+    pub spare: [u32; 10],
+}
+unsafe impl ByteValued for CuseInitOut {}
+
+/// Header of the security contexts following create, mkdir, mknod and symlink requests.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SecctxHeader {
+    pub size: u32,
+    pub nr_secctx: u32,
+}
+unsafe impl ByteValued for SecctxHeader {}
+
+// Extension types of requests. Types 0..31 are reserved for `SecctxHeader`, whose `nr_secctx`
+// field is at the place of `ExtHeader::type_`.
+pub const FUSE_MAX_NR_SECCTX: u32 = 31;
+pub const FUSE_EXT_GROUPS: u32 = 32;
+
+/// Header of an extension appended to a request, `InHeader::total_extlen` counts the length of
+/// all extensions in units of 8 bytes.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct ExtHeader {
+    pub size: u32,
+    pub type_: u32,
+}
+unsafe impl ByteValued for ExtHeader {}
+
+/// Supplementary groups extension, followed by `nr_groups` group IDs.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SuppGroups {
+    pub nr_groups: u32,
+}
+unsafe impl ByteValued for SuppGroups {}
+
+/// A security context, followed by its nul terminated name and `size` bytes of value.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Secctx {
+    pub size: u32,
+    pub padding: u32,
+}
+unsafe impl ByteValued for Secctx {}
+
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone)]
 pub struct InterruptIn {
@@ -1178,7 +1543,8 @@ pub struct InHeader {
     pub uid: u32,
     pub gid: u32,
     pub pid: u32,
-    pub padding: u32,
+    pub total_extlen: u16,
+    pub padding: u16,
 }
 unsafe impl ByteValued for InHeader {}
 
@@ -1303,6 +1669,13 @@ pub struct CopyFileRangeIn {
 }
 unsafe impl ByteValued for CopyFileRangeIn {}
 
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SyncfsIn {
+    pub padding: u64,
+}
+unsafe impl ByteValued for SyncfsIn {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1327,6 +1700,9 @@ mod tests {
         assert_eq!(std::mem::size_of::<AttrOut>(), 104);
         #[cfg(target_os = "macos")]
         assert_eq!(std::mem::size_of::<AttrOut>(), 120);
+        assert_eq!(std::mem::size_of::<Statx>(), 256);
+        assert_eq!(std::mem::size_of::<StatxIn>(), 24);
+        assert_eq!(std::mem::size_of::<StatxOut>(), 288);
         assert_eq!(std::mem::size_of::<MknodIn>(), 16);
         assert_eq!(std::mem::size_of::<MkdirIn>(), 8);
         assert_eq!(std::mem::size_of::<InHeader>(), 40);
@@ -1353,4 +1729,46 @@ mod tests {
         assert_eq!(buf[8], 0x5u8);
         assert_eq!(buf[9], 0x6u8);
     }
+
+    #[test]
+    fn test_reply_constructors() {
+        let mut st: stat64 = unsafe { mem::zeroed() };
+        st.st_ino = 5;
+        st.st_mode = libc::S_IFREG | 0o644;
+        st.st_blksize = 4096;
+        st.st_mtime = 10;
+        st.st_mtime_nsec = 20;
+
+        let timeout = Duration::new(3, 500);
+        let out = EntryOut::new(
+            5,
+            7,
+            Attr::with_flags(st, 1),
+            timeout,
+            Duration::from_secs(2),
+        );
+        assert_eq!((out.nodeid, out.generation), (5, 7));
+        assert_eq!((out.attr_valid, out.attr_valid_nsec), (3, 500));
+        assert_eq!((out.entry_valid, out.entry_valid_nsec), (2, 0));
+        assert_eq!(
+            (out.attr.ino, out.attr.blksize, out.attr.flags),
+            (5, 4096, 1)
+        );
+
+        let out = AttrOut::from_stat(&st, timeout);
+        assert_eq!(
+            (out.attr_valid, out.attr_valid_nsec, out.dummy),
+            (3, 500, 0)
+        );
+        assert_eq!(out.attr.mode, libc::S_IFREG | 0o644);
+        assert_eq!((out.attr.mtime, out.attr.mtimensec), (10, 20));
+
+        let out = OpenOut::new(9, OpenOptions::DIRECT_IO | OpenOptions::KEEP_CACHE);
+        assert_eq!(out.fh, 9);
+        assert_eq!(
+            out.open_flags,
+            (OpenOptions::DIRECT_IO | OpenOptions::KEEP_CACHE).bits()
+        );
+        assert_eq!(WriteOut::new(4096).size, 4096);
+    }
 }
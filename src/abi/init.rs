@@ -0,0 +1,275 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Decoding and encoding of the FUSE_INIT exchange.
+//!
+//! The [Server](crate::api::server::Server) uses these helpers to negotiate a session, and they are
+//! public so that gateways relaying FUSE traffic can inspect or rewrite the negotiation without a
+//! server, e.g. to strip the options they can't relay. The 64 bits of flags split between
+//! `InitIn::flags` and `InitInExt::flags2`, or `InitOut::flags` and `InitOut::flags2`, are
+//! decoded into [FsOptions] and [FsOptionsExt].
+
+use std::mem::size_of;
+
+use vm_memory::ByteValued;
+
+pub use super::fuse_abi::{
+    FsOptions, FsOptionsExt, INIT_EXT, KERNEL_MINOR_VERSION, KERNEL_MINOR_VERSION_INIT_EXT,
+    KERNEL_VERSION,
+};
+use super::fuse_abi::{
+    InitIn, InitInExt, InitOut, FUSE_COMPAT_22_INIT_OUT_SIZE, FUSE_COMPAT_INIT_OUT_SIZE,
+    HAS_INODE_DAX, KERNEL_MINOR_VERSION_INIT_22_OUT_SIZE, KERNEL_MINOR_VERSION_INIT_OUT_SIZE,
+};
+use crate::{DecodeError, Error, Result};
+
+/// A FUSE_INIT request sent by the kernel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FuseInitIn {
+    /// Major version of the protocol spoken by the kernel.
+    pub major: u32,
+    /// Minor version of the protocol spoken by the kernel.
+    pub minor: u32,
+    /// Maximum readahead of the kernel, in bytes.
+    pub max_readahead: u32,
+    /// Options supported by the kernel. Bits unknown to this crate are dropped.
+    pub flags: FsOptions,
+    /// Extended options supported by the kernel. Bits unknown to this crate are dropped.
+    pub flags2: FsOptionsExt,
+}
+
+/// A reply to the FUSE_INIT request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FuseInitOut {
+    /// Minor version of the protocol spoken by the kernel, i.e. `FuseInitIn::minor`. Kernels
+    /// before 7.23 expect a shorter reply, so it's truncated to the size they know.
+    pub kernel_minor: u32,
+    /// Major version of the protocol spoken by the file system.
+    pub major: u32,
+    /// Minor version of the protocol spoken by the file system.
+    pub minor: u32,
+    /// Maximum readahead, in bytes.
+    pub max_readahead: u32,
+    /// Options enabled for the session.
+    pub flags: FsOptions,
+    /// Extended options enabled for the session, only sent to kernels since 7.36.
+    pub flags2: FsOptionsExt,
+    /// Maximum number of pending background requests.
+    pub max_background: u16,
+    /// Number of pending background requests to mark the connection as congested.
+    pub congestion_threshold: u16,
+    /// Maximum size of write requests, in bytes.
+    pub max_write: u32,
+    /// Granularity of timestamps, in nanoseconds.
+    pub time_gran: u32,
+    /// Maximum number of pages of requests, if `FsOptions::MAX_PAGES` is enabled.
+    pub max_pages: u16,
+    /// Alignment of DAX mappings, as a power of two, if `FsOptions::MAP_ALIGNMENT` is enabled.
+    pub map_alignment: u16,
+    /// Depth of stacked file systems, if `FsOptionsExt::PASSTHROUGH` is enabled.
+    pub max_stack_depth: u32,
+}
+
+/// Parse the body of a FUSE_INIT request, following the `InHeader`.
+///
+/// It fails with `DecodeError::Truncated` if the body is shorter than `InitIn`, and with
+/// `DecodeError::UnsupportedVersion` if the kernel speaks a major version older than
+/// [KERNEL_VERSION]. A newer major version is accepted, the reply should then just carry the
+/// version of the file system, so the kernel retries with an older one. The extended flags are
+/// only read if the kernel sets [INIT_EXT] and the body is long enough to hold them.
+///
+/// Since 7.36 the kernel uses the bit of `FsOptions::PERFILE_DAX` for [INIT_EXT], and supports
+/// per-file DAX by the `HAS_INODE_DAX` extended flag, which is decoded as
+/// `FsOptions::PERFILE_DAX` instead. Older kernels never send extended flags.
+pub fn parse_init_in(body: &[u8]) -> Result<FuseInitIn> {
+    let (init, ext) = body.split_at(std::cmp::min(body.len(), size_of::<InitIn>()));
+    let init = InitIn::from_slice(init).ok_or(Error::InvalidMessage(DecodeError::Truncated {
+        needed: size_of::<InitIn>(),
+        got: body.len(),
+    }))?;
+    if init.major < KERNEL_VERSION {
+        return Err(Error::InvalidMessage(DecodeError::UnsupportedVersion {
+            major: init.major,
+            minor: init.minor,
+        }));
+    }
+
+    let mut flags = FsOptions::from_bits_truncate(init.flags);
+    let mut flags2 = 0;
+    if init.minor >= KERNEL_MINOR_VERSION_INIT_EXT {
+        if init.flags & INIT_EXT != 0 && ext.len() >= size_of::<InitInExt>() {
+            flags2 = InitInExt::from_slice(&ext[..size_of::<InitInExt>()])
+                .unwrap()
+                .flags2;
+        }
+        flags.set(FsOptions::PERFILE_DAX, flags2 & HAS_INODE_DAX != 0);
+    }
+
+    Ok(FuseInitIn {
+        major: init.major,
+        minor: init.minor,
+        max_readahead: init.max_readahead,
+        flags,
+        flags2: FsOptionsExt::from_bits_truncate(flags2),
+    })
+}
+
+/// Encode the body of a FUSE_INIT reply, to follow the `OutHeader`.
+///
+/// For kernels since 7.36, [INIT_EXT] is set if any extended flag is set, and
+/// `FsOptions::PERFILE_DAX` is encoded as the `HAS_INODE_DAX` extended flag, see
+/// [parse_init_in()]. The extended options are dropped for older kernels.
+pub fn encode_init_out(out: &FuseInitOut) -> Vec<u8> {
+    let mut init = InitOut {
+        major: out.major,
+        minor: out.minor,
+        max_readahead: out.max_readahead,
+        flags: out.flags.bits(),
+        max_background: out.max_background,
+        congestion_threshold: out.congestion_threshold,
+        max_write: out.max_write,
+        time_gran: out.time_gran,
+        max_pages: out.max_pages,
+        map_alignment: out.map_alignment,
+        max_stack_depth: out.max_stack_depth,
+        ..Default::default()
+    };
+    if out.kernel_minor >= KERNEL_MINOR_VERSION_INIT_EXT {
+        init.flags &= !FsOptions::PERFILE_DAX.bits();
+        init.flags2 = out.flags2.bits();
+        if out.flags.contains(FsOptions::PERFILE_DAX) {
+            init.flags2 |= HAS_INODE_DAX;
+        }
+        if init.flags2 != 0 {
+            init.flags |= INIT_EXT;
+        }
+    }
+
+    let size = if out.kernel_minor < KERNEL_MINOR_VERSION_INIT_OUT_SIZE {
+        FUSE_COMPAT_INIT_OUT_SIZE
+    } else if out.kernel_minor < KERNEL_MINOR_VERSION_INIT_22_OUT_SIZE {
+        FUSE_COMPAT_22_INIT_OUT_SIZE
+    } else {
+        size_of::<InitOut>()
+    };
+    init.as_slice()[..size].to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn init_in(flags: u32, flags2: Option<u32>) -> Vec<u8> {
+        let init = InitIn {
+            major: KERNEL_VERSION,
+            minor: 36,
+            max_readahead: 0x20000,
+            flags,
+        };
+        let mut body = init.as_slice().to_vec();
+        if let Some(flags2) = flags2 {
+            let ext = InitInExt {
+                flags2,
+                ..Default::default()
+            };
+            body.extend_from_slice(ext.as_slice());
+        }
+        body
+    }
+
+    #[test]
+    fn test_parse_init_in() {
+        let ctx = FsOptionsExt::SECURITY_CTX.bits();
+        let init = parse_init_in(&init_in(
+            FsOptions::ASYNC_READ.bits() | INIT_EXT,
+            Some(ctx | HAS_INODE_DAX),
+        ))
+        .unwrap();
+        assert_eq!(init.major, KERNEL_VERSION);
+        assert_eq!(init.minor, 36);
+        assert_eq!(init.max_readahead, 0x20000);
+        // INIT_EXT isn't taken as PERFILE_DAX, which is carried by HAS_INODE_DAX instead.
+        assert_eq!(init.flags, FsOptions::ASYNC_READ | FsOptions::PERFILE_DAX);
+        assert_eq!(init.flags2, FsOptionsExt::SECURITY_CTX);
+
+        // The extended flags are ignored without INIT_EXT, or if they are missing.
+        let init = parse_init_in(&init_in(FsOptions::ASYNC_READ.bits(), Some(ctx))).unwrap();
+        assert_eq!(init.flags, FsOptions::ASYNC_READ);
+        assert!(init.flags2.is_empty());
+        let init = parse_init_in(&init_in(INIT_EXT, None)).unwrap();
+        assert!(init.flags.is_empty() && init.flags2.is_empty());
+
+        // Kernels before 7.36 use the bit of INIT_EXT for PERFILE_DAX.
+        let mut body = init_in(FsOptions::PERFILE_DAX.bits(), Some(ctx));
+        body[4] = 35;
+        let init = parse_init_in(&body).unwrap();
+        assert_eq!(init.flags, FsOptions::PERFILE_DAX);
+        assert!(init.flags2.is_empty());
+
+        match parse_init_in(&init_in(0, None)[..8]) {
+            Err(Error::InvalidMessage(DecodeError::Truncated { needed: 16, got: 8 })) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        let mut body = init_in(0, None);
+        body[0] = 6;
+        match parse_init_in(&body) {
+            Err(Error::InvalidMessage(DecodeError::UnsupportedVersion { major: 6, .. })) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_encode_init_out() {
+        let mut out = FuseInitOut {
+            kernel_minor: 36,
+            major: KERNEL_VERSION,
+            minor: KERNEL_MINOR_VERSION,
+            max_readahead: 0x20000,
+            flags: FsOptions::ASYNC_READ | FsOptions::MAX_PAGES,
+            flags2: FsOptionsExt::empty(),
+            max_background: 12,
+            congestion_threshold: 9,
+            max_write: 0x10_0000,
+            time_gran: 1,
+            max_pages: 256,
+            map_alignment: 0,
+            max_stack_depth: 0,
+        };
+        let body = encode_init_out(&out);
+        assert_eq!(body.len(), size_of::<InitOut>());
+        let init = InitOut::from_slice(&body).unwrap();
+        assert_eq!(init.flags, out.flags.bits());
+        assert_eq!(init.flags2, 0);
+        assert_eq!((init.max_write, init.max_pages), (0x10_0000, 256));
+
+        // Extended options need INIT_EXT.
+        out.flags2 = FsOptionsExt::SECURITY_CTX;
+        let body = encode_init_out(&out);
+        let init = InitOut::from_slice(&body).unwrap();
+        assert_eq!(init.flags, out.flags.bits() | INIT_EXT);
+        assert_eq!(init.flags2, FsOptionsExt::SECURITY_CTX.bits());
+
+        // PERFILE_DAX is sent as HAS_INODE_DAX since 7.36, and as is before.
+        out.flags2 = FsOptionsExt::empty();
+        out.flags |= FsOptions::PERFILE_DAX;
+        let init = *InitOut::from_slice(&encode_init_out(&out)).unwrap();
+        assert_eq!(
+            init.flags,
+            (FsOptions::ASYNC_READ | FsOptions::MAX_PAGES).bits() | INIT_EXT
+        );
+        assert_eq!(init.flags2, HAS_INODE_DAX);
+        out.kernel_minor = 35;
+        out.flags2 = FsOptionsExt::SECURITY_CTX;
+        let init = *InitOut::from_slice(&encode_init_out(&out)).unwrap();
+        assert_eq!(init.flags, out.flags.bits());
+        assert_eq!(init.flags2, 0);
+        out.flags.remove(FsOptions::PERFILE_DAX);
+        out.flags2 = FsOptionsExt::empty();
+
+        // Old kernels get a truncated reply.
+        out.kernel_minor = 22;
+        assert_eq!(encode_init_out(&out).len(), FUSE_COMPAT_22_INIT_OUT_SIZE);
+        out.kernel_minor = 4;
+        assert_eq!(encode_init_out(&out), body[..FUSE_COMPAT_INIT_OUT_SIZE]);
+    }
+}
@@ -7,5 +7,9 @@
 #[cfg(any(target_os = "macos", target_os = "linux"))]
 pub mod fuse_abi;
 
+/// Decoding and encoding of the FUSE_INIT exchange.
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+pub mod init;
+
 #[cfg(feature = "virtiofs")]
 pub mod virtio_fs;
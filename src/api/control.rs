@@ -0,0 +1,363 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Control socket to introspect the live state of a Fuse server.
+//!
+//! The [ControlServer](struct.ControlServer.html) listens on a unix domain socket and serves a
+//! simple line based protocol. Each request is a single line and each reply is a single line of
+//! JSON:
+//! - `stats`: per opcode request counters of the Fuse server and summary counters of the file
+//!   system, e.g. `{"opcodes":{"1":12,"15":3},"fs":{"inodes":10,"handles":2}}`.
+//! - `handles`: open handles of the file system, e.g. `[{"handle":1,"inode":2,"fd":7}]`.
+//! - `inodes <n>`: at most `n` entries of the inode table of the file system, e.g.
+//!   `[{"inode":1,"refcount":2,"mode":16877,"path":"/"}]`.
+//! - `capabilities`: the protocol version and the bits of the options negotiated with the kernel
+//!   and of the operations supported by the file system, see
+//!   [Server::capabilities()](../server/struct.Server.html#method.capabilities), e.g.
+//!   `{"version":"7.38","options":4097,"ops":1}`.
+//!
+//! Errors are replied as `{"error":"<message>"}`.
+
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use super::filesystem::FileSystem;
+use super::server::Server;
+use crate::async_util::AsyncDrive;
+
+// Don't let an idle client block the control server forever.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Information about an open handle of a file system.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HandleInfo {
+    /// The handle returned to the kernel.
+    pub handle: u64,
+    /// The inode the handle was opened for.
+    pub inode: u64,
+    /// The file descriptor backing the handle, or -1 if there's none.
+    pub fd: i32,
+}
+
+/// Information about an inode tracked by a file system.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct InodeInfo {
+    /// The inode number returned to the kernel.
+    pub inode: u64,
+    /// The lookup count of the inode.
+    pub refcount: u64,
+    /// The file type and mode of the inode.
+    pub mode: u32,
+    /// The path of the inode, if available.
+    pub path: Option<String>,
+}
+
+/// Trait for file systems to expose their internal state to a [ControlServer].
+pub trait Introspect {
+    /// Get summary counters of the file system as `(name, value)` pairs, such as the number of
+    /// inodes and handles.
+    fn stats(&self) -> Vec<(String, u64)>;
+
+    /// Get all open handles of the file system.
+    fn handles(&self) -> Vec<HandleInfo>;
+
+    /// Get at most `limit` entries of the inode table of the file system.
+    fn inodes(&self, limit: usize) -> Vec<InodeInfo>;
+}
+
+/// A server to introspect the live state of a Fuse server over a unix domain socket.
+///
+/// Clients are served one by one on the thread calling [serve()](ControlServer::serve), so it's
+/// expected to run on a dedicated thread.
+pub struct ControlServer {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl ControlServer {
+    /// Create a control server listening on the unix domain socket at `path`.
+    pub fn new<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let listener = UnixListener::bind(&path)?;
+
+        Ok(ControlServer { listener, path })
+    }
+
+    /// Get the path of the unix domain socket.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Accept and serve clients until accepting a connection fails.
+    pub fn serve<F: FileSystem + Sync, D: AsyncDrive>(
+        &self,
+        server: &Server<F, D>,
+        fs: &dyn Introspect,
+    ) -> io::Result<()> {
+        loop {
+            let (stream, _) = self.listener.accept()?;
+            if let Err(e) = Self::handle_client(server, fs, stream) {
+                warn!("fuse: control socket client error, {}", e);
+            }
+        }
+    }
+
+    /// Serve requests from a connected client until it closes the connection.
+    pub fn handle_client<F: FileSystem + Sync, D: AsyncDrive>(
+        server: &Server<F, D>,
+        fs: &dyn Introspect,
+        stream: UnixStream,
+    ) -> io::Result<()> {
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+        stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+        let mut writer = stream.try_clone()?;
+
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let mut reply = Self::handle_command(server, fs, &line);
+            reply.push('\n');
+            writer.write_all(reply.as_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    /// Execute a single command and return the JSON reply.
+    pub fn handle_command<F: FileSystem + Sync, D: AsyncDrive>(
+        server: &Server<F, D>,
+        fs: &dyn Introspect,
+        line: &str,
+    ) -> String {
+        let mut args = line.split_whitespace();
+
+        match (args.next(), args.next(), args.next()) {
+            (Some("stats"), None, _) => Self::reply_stats(server, fs),
+            (Some("handles"), None, _) => Self::reply_handles(fs),
+            (Some("capabilities"), None, _) => Self::reply_capabilities(server),
+            (Some("inodes"), Some(n), None) => match n.parse::<usize>() {
+                Ok(limit) => Self::reply_inodes(fs, limit),
+                Err(_) => reply_error("invalid inode count"),
+            },
+            _ => reply_error("unknown command"),
+        }
+    }
+
+    fn reply_stats<F: FileSystem + Sync, D: AsyncDrive>(
+        server: &Server<F, D>,
+        fs: &dyn Introspect,
+    ) -> String {
+        let opcodes = server
+            .opcode_counters()
+            .iter()
+            .map(|(op, count)| format!("\"{}\":{}", op, count))
+            .collect::<Vec<_>>()
+            .join(",");
+        let stats = fs
+            .stats()
+            .iter()
+            .map(|(name, value)| format!("{}:{}", json_string(name), value))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        format!("{{\"opcodes\":{{{}}},\"fs\":{{{}}}}}", opcodes, stats)
+    }
+
+    fn reply_capabilities<F: FileSystem + Sync, D: AsyncDrive>(server: &Server<F, D>) -> String {
+        let caps = server.capabilities();
+
+        format!(
+            "{{\"version\":\"{}.{}\",\"options\":{},\"ops\":{}}}",
+            caps.major,
+            caps.minor,
+            caps.options.bits(),
+            caps.ops.bits()
+        )
+    }
+
+    fn reply_handles(fs: &dyn Introspect) -> String {
+        let handles = fs
+            .handles()
+            .iter()
+            .map(|h| {
+                format!(
+                    "{{\"handle\":{},\"inode\":{},\"fd\":{}}}",
+                    h.handle, h.inode, h.fd
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+
+        format!("[{}]", handles)
+    }
+
+    fn reply_inodes(fs: &dyn Introspect, limit: usize) -> String {
+        let inodes = fs
+            .inodes(limit)
+            .iter()
+            .take(limit)
+            .map(|i| {
+                let path = match &i.path {
+                    Some(p) => json_string(p),
+                    None => "null".to_string(),
+                };
+                format!(
+                    "{{\"inode\":{},\"refcount\":{},\"mode\":{},\"path\":{}}}",
+                    i.inode, i.refcount, i.mode, path
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+
+        format!("[{}]", inodes)
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn reply_error(msg: &str) -> String {
+    format!("{{\"error\":{}}}", json_string(msg))
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::Vfs;
+    use std::io::Read;
+    use std::net::Shutdown;
+
+    struct DummyFs;
+
+    impl Introspect for DummyFs {
+        fn stats(&self) -> Vec<(String, u64)> {
+            vec![("inodes".to_string(), 2), ("handles".to_string(), 1)]
+        }
+
+        fn handles(&self) -> Vec<HandleInfo> {
+            vec![HandleInfo {
+                handle: 1,
+                inode: 2,
+                fd: 7,
+            }]
+        }
+
+        fn inodes(&self, _limit: usize) -> Vec<InodeInfo> {
+            vec![
+                InodeInfo {
+                    inode: 1,
+                    refcount: 2,
+                    mode: libc::S_IFDIR | 0o755,
+                    path: Some("/".to_string()),
+                },
+                InodeInfo {
+                    inode: 2,
+                    refcount: 1,
+                    mode: libc::S_IFREG | 0o644,
+                    path: Some("/a \"b\"".to_string()),
+                },
+            ]
+        }
+    }
+
+    #[test]
+    fn test_handle_command() {
+        let server: Server<Vfs> = Server::new(Vfs::default());
+        let fs = DummyFs;
+
+        assert_eq!(
+            ControlServer::handle_command(&server, &fs, "stats"),
+            "{\"opcodes\":{},\"fs\":{\"inodes\":2,\"handles\":1}}"
+        );
+        assert_eq!(
+            ControlServer::handle_command(&server, &fs, "handles"),
+            "[{\"handle\":1,\"inode\":2,\"fd\":7}]"
+        );
+        assert_eq!(
+            ControlServer::handle_command(&server, &fs, "inodes 1"),
+            "[{\"inode\":1,\"refcount\":2,\"mode\":16877,\"path\":\"/\"}]"
+        );
+        assert_eq!(
+            ControlServer::handle_command(&server, &fs, "inodes 5"),
+            "[{\"inode\":1,\"refcount\":2,\"mode\":16877,\"path\":\"/\"},\
+             {\"inode\":2,\"refcount\":1,\"mode\":33188,\"path\":\"/a \\\"b\\\"\"}]"
+        );
+        assert_eq!(
+            ControlServer::handle_command(&server, &fs, "capabilities"),
+            format!(
+                "{{\"version\":\"{}.{}\",\"options\":0,\"ops\":0}}",
+                crate::abi::fuse_abi::KERNEL_VERSION,
+                crate::abi::fuse_abi::KERNEL_MINOR_VERSION
+            )
+        );
+        assert_eq!(
+            ControlServer::handle_command(&server, &fs, "inodes x"),
+            "{\"error\":\"invalid inode count\"}"
+        );
+        assert_eq!(
+            ControlServer::handle_command(&server, &fs, "foo"),
+            "{\"error\":\"unknown command\"}"
+        );
+    }
+
+    #[test]
+    fn test_handle_client() {
+        let server: Server<Vfs> = Server::new(Vfs::default());
+        let fs = DummyFs;
+        let (mut client, stream) = UnixStream::pair().unwrap();
+
+        client.write_all(b"handles\n\nfoo\n").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        ControlServer::handle_client(&server, &fs, stream).unwrap();
+
+        let mut reply = String::new();
+        client.read_to_string(&mut reply).unwrap();
+        assert_eq!(
+            reply,
+            "[{\"handle\":1,\"inode\":2,\"fd\":7}]\n{\"error\":\"unknown command\"}\n"
+        );
+    }
+
+    #[test]
+    fn test_control_server() {
+        let dir = vmm_sys_util::tempdir::TempDir::new().unwrap();
+        let path = dir.as_path().join("control.sock");
+
+        let control = ControlServer::new(&path).unwrap();
+        assert_eq!(control.path(), path.as_path());
+        assert!(path.exists());
+        drop(control);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,428 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Cache of directory entries for file systems which are slow to list directories.
+//!
+//! A [DirEntryCache] is meant to be used by a [FileSystem] wrapper: its `readdir()` and
+//! `readdirplus()` methods forward to [DirEntryCache::readdir()] and
+//! [DirEntryCache::readdirplus()], which list the directory from the wrapped file system on a
+//! cache miss and serve following requests from the cached entries until the entries expire or
+//! are invalidated.
+//!
+//! The cache only knows about changes made through the wrapper, so the wrapper should call
+//! [DirEntryCache::invalidate()] for the parent directories of all mutating operations, e.g.
+//! `create`, `mkdir`, `unlink` and `rename`. If a directory is changed behind the back of the
+//! wrapper, the wrapper should invalidate the directory here before invalidating the entries
+//! cached by the kernel, otherwise the kernel may list the stale entries again.
+
+use std::collections::HashMap;
+use std::io;
+use std::mem::size_of;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::abi::fuse_abi::Dirent;
+use crate::api::filesystem::{Context, DirEntry, DirEntryBuf, Entry, FileSystem};
+use crate::api::{CURRENT_DIR_CSTR, PARENT_DIR_CSTR};
+
+// Size of the buffer used to list directories from the file system on a cache miss.
+const DIR_CACHE_FILL_SIZE: u32 = 64 * 1024;
+
+struct CachedEntry {
+    entry: DirEntryBuf,
+    // The offset reported by the file system for the entry.
+    offset: u64,
+}
+
+struct CachedDir {
+    entries: Vec<CachedEntry>,
+    filled: Instant,
+}
+
+impl CachedDir {
+    // Get the index of the first entry to return for `offset`, or None if there's no entry at
+    // `offset`.
+    fn position(&self, offset: u64) -> Option<usize> {
+        if offset == 0 {
+            return Some(0);
+        }
+        self.entries
+            .iter()
+            .position(|e| e.offset == offset)
+            .map(|idx| idx + 1)
+    }
+}
+
+/// A cache of directory entries keyed by directory inode, with a time to live.
+pub struct DirEntryCache {
+    ttl: Duration,
+    dirs: Mutex<HashMap<u64, Arc<CachedDir>>>,
+    // Bumped on invalidation, so a listing racing with invalidation won't be cached.
+    generation: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl DirEntryCache {
+    /// Create a cache whose entries expire `ttl` after the directory has been listed.
+    pub fn new(ttl: Duration) -> Self {
+        DirEntryCache {
+            ttl,
+            dirs: Mutex::new(HashMap::new()),
+            generation: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Drop the cached entries of directory `inode`.
+    pub fn invalidate(&self, inode: u64) {
+        let mut dirs = self.dirs.lock().unwrap();
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        dirs.remove(&inode);
+    }
+
+    /// Drop the cached entries of all directories.
+    pub fn invalidate_all(&self) {
+        let mut dirs = self.dirs.lock().unwrap();
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        dirs.clear();
+    }
+
+    /// Get the number of requests served from the cache.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Get the number of requests which missed the cache.
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// Read directory `inode` from the cache, or from `fs` on a cache miss.
+    ///
+    /// The arguments are the same as [FileSystem::readdir()], and the offsets of the entries are
+    /// the ones reported by `fs`, so a listing may continue with `fs` if the cached entries have
+    /// been dropped in between.
+    #[allow(clippy::too_many_arguments)]
+    pub fn readdir<F: FileSystem>(
+        &self,
+        fs: &F,
+        ctx: &Context,
+        inode: F::Inode,
+        handle: F::Handle,
+        size: u32,
+        offset: u64,
+        add_entry: &mut dyn FnMut(DirEntry) -> io::Result<usize>,
+    ) -> io::Result<()> {
+        if size == 0 {
+            return Ok(());
+        }
+
+        let ino: u64 = inode.into();
+        let (dir, start) = match self.get(ino, offset) {
+            Some(v) => v,
+            None if offset == 0 => {
+                let handle: u64 = handle.into();
+                let dir = self.fill(fs, ctx, ino, handle)?;
+                (dir, 0)
+            }
+            // Continue the listing with the file system.
+            None => return fs.readdir(ctx, ino.into(), handle, size, offset, add_entry),
+        };
+
+        for e in dir.entries[start..].iter() {
+            let dirent = DirEntry {
+                ino: e.entry.ino,
+                offset: e.offset,
+                type_: e.entry.type_,
+                name: &e.entry.name,
+            };
+            if add_entry(dirent)? == 0 {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Read directory `inode` with entry attributes from the cache, or from `fs` on a cache miss.
+    ///
+    /// The attributes are never cached: each entry served from the cache is looked up by `fs`,
+    /// which also takes the lookup count required by `readdirplus`. The "." and ".." entries are
+    /// skipped as they don't need a lookup.
+    #[allow(clippy::too_many_arguments)]
+    pub fn readdirplus<F: FileSystem>(
+        &self,
+        fs: &F,
+        ctx: &Context,
+        inode: F::Inode,
+        handle: F::Handle,
+        size: u32,
+        offset: u64,
+        add_entry: &mut dyn FnMut(DirEntry, Entry) -> io::Result<usize>,
+    ) -> io::Result<()> {
+        if size == 0 {
+            return Ok(());
+        }
+
+        let ino: u64 = inode.into();
+        let (dir, start) = match self.get(ino, offset) {
+            Some(v) => v,
+            None if offset == 0 => {
+                let handle: u64 = handle.into();
+                let dir = self.fill(fs, ctx, ino, handle)?;
+                (dir, 0)
+            }
+            None => return fs.readdirplus(ctx, ino.into(), handle, size, offset, add_entry),
+        };
+
+        for e in dir.entries[start..].iter() {
+            let name = &e.entry.name;
+            if is_dot_or_dotdot(name) {
+                continue;
+            }
+            let mut cname = name.clone();
+            cname.push(0);
+            let cname = std::ffi::CStr::from_bytes_with_nul(&cname)
+                .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+            let entry = match fs.lookup(ctx, ino.into(), cname) {
+                Ok(entry) => entry,
+                // The entry has been removed since the directory was listed.
+                Err(e) if e.raw_os_error() == Some(libc::ENOENT) => continue,
+                Err(e) => return Err(e),
+            };
+            let child = entry.inode;
+            let dirent = DirEntry {
+                ino: entry.attr.st_ino,
+                offset: e.offset,
+                type_: e.entry.type_,
+                name,
+            };
+            if add_entry(dirent, entry)? == 0 {
+                // The entry isn't returned to the kernel, so drop the lookup count taken above.
+                fs.forget(ctx, child.into(), 1);
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Get the cached entries of `inode` and the index of the entry at `offset`, and account the
+    // request as a hit or a miss.
+    fn get(&self, inode: u64, offset: u64) -> Option<(Arc<CachedDir>, usize)> {
+        let mut dirs = self.dirs.lock().unwrap();
+        let result = match dirs.get(&inode) {
+            Some(dir) if dir.filled.elapsed() >= self.ttl => {
+                dirs.remove(&inode);
+                None
+            }
+            Some(dir) => dir.position(offset).map(|start| (dir.clone(), start)),
+            None => None,
+        };
+
+        match result {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        result
+    }
+
+    // List the whole directory from the file system and cache the entries.
+    fn fill<F: FileSystem>(
+        &self,
+        fs: &F,
+        ctx: &Context,
+        inode: u64,
+        handle: u64,
+    ) -> io::Result<Arc<CachedDir>> {
+        let generation = self.generation.load(Ordering::Acquire);
+        let filled = Instant::now();
+        let mut entries = Vec::new();
+        let mut offset = 0;
+
+        loop {
+            let count = entries.len();
+            fs.readdir(
+                ctx,
+                inode.into(),
+                handle.into(),
+                DIR_CACHE_FILL_SIZE,
+                offset,
+                &mut |d| {
+                    entries.push(CachedEntry {
+                        entry: DirEntryBuf {
+                            ino: d.ino,
+                            type_: d.type_,
+                            name: d.name.to_vec(),
+                        },
+                        offset: d.offset,
+                    });
+                    Ok((size_of::<Dirent>() + d.name.len() + 7) & !7)
+                },
+            )?;
+            if entries.len() == count {
+                break;
+            }
+            offset = entries[entries.len() - 1].offset;
+        }
+
+        let dir = Arc::new(CachedDir { entries, filled });
+        let mut dirs = self.dirs.lock().unwrap();
+        if self.generation.load(Ordering::Acquire) == generation {
+            dirs.insert(inode, dir.clone());
+        }
+
+        Ok(dir)
+    }
+}
+
+fn is_dot_or_dotdot(name: &[u8]) -> bool {
+    let dot = &CURRENT_DIR_CSTR[..CURRENT_DIR_CSTR.len() - 1];
+    let dotdot = &PARENT_DIR_CSTR[..PARENT_DIR_CSTR.len() - 1];
+    name == dot || name == dotdot
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+
+    // A directory of `count` files, named by their inode numbers.
+    #[derive(Default)]
+    struct ListFs {
+        count: Mutex<u64>,
+        readdirs: AtomicU64,
+        lookups: AtomicU64,
+    }
+
+    impl FileSystem for ListFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn lookup(&self, _ctx: &Context, _parent: u64, name: &CStr) -> io::Result<Entry> {
+            self.lookups.fetch_add(1, Ordering::Relaxed);
+            let ino: u64 = name
+                .to_str()
+                .unwrap()
+                .parse()
+                .map_err(|_| io::Error::from_raw_os_error(libc::ENOENT))?;
+            if ino > *self.count.lock().unwrap() {
+                return Err(io::Error::from_raw_os_error(libc::ENOENT));
+            }
+            let mut entry = Entry {
+                inode: ino + 100,
+                ..Default::default()
+            };
+            entry.attr.st_ino = ino;
+            Ok(entry)
+        }
+
+        fn readdir(
+            &self,
+            _ctx: &Context,
+            _inode: u64,
+            _handle: u64,
+            _size: u32,
+            offset: u64,
+            add_entry: &mut dyn FnMut(DirEntry) -> io::Result<usize>,
+        ) -> io::Result<()> {
+            self.readdirs.fetch_add(1, Ordering::Relaxed);
+            let count = *self.count.lock().unwrap();
+            // Return at most 2 entries per call to test filling in several rounds.
+            for ino in (offset + 1..=count).take(2) {
+                let name = if ino == 1 {
+                    b".".to_vec()
+                } else {
+                    ino.to_string().into_bytes()
+                };
+                let dirent = DirEntry {
+                    ino,
+                    offset: ino,
+                    type_: libc::DT_REG as u32,
+                    name: &name,
+                };
+                if add_entry(dirent)? == 0 {
+                    break;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn list(cache: &DirEntryCache, fs: &ListFs, offset: u64, limit: usize) -> Vec<u64> {
+        let mut inos = Vec::new();
+        cache
+            .readdir(fs, &Context::default(), 1, 0, 4096, offset, &mut |d| {
+                if inos.len() == limit {
+                    return Ok(0);
+                }
+                inos.push(d.offset);
+                Ok(1)
+            })
+            .unwrap();
+        inos
+    }
+
+    #[test]
+    fn test_dir_entry_cache() {
+        let fs = ListFs::default();
+        *fs.count.lock().unwrap() = 5;
+        let cache = DirEntryCache::new(Duration::from_secs(60));
+
+        assert_eq!(list(&cache, &fs, 0, 10), vec![1, 2, 3, 4, 5]);
+        // Filled by 3 rounds of 2 entries plus a final empty round.
+        assert_eq!(fs.readdirs.load(Ordering::Relaxed), 4);
+        assert_eq!((cache.hits(), cache.misses()), (0, 1));
+
+        assert_eq!(list(&cache, &fs, 0, 2), vec![1, 2]);
+        assert_eq!(list(&cache, &fs, 2, 10), vec![3, 4, 5]);
+        assert_eq!(fs.readdirs.load(Ordering::Relaxed), 4);
+        assert_eq!((cache.hits(), cache.misses()), (2, 1));
+
+        // New entries show up after invalidation.
+        *fs.count.lock().unwrap() = 6;
+        assert_eq!(list(&cache, &fs, 0, 10), vec![1, 2, 3, 4, 5]);
+        cache.invalidate(1);
+        // A listing in progress continues with the file system.
+        assert_eq!(list(&cache, &fs, 4, 10), vec![5, 6]);
+        assert_eq!(fs.readdirs.load(Ordering::Relaxed), 5);
+        assert_eq!(list(&cache, &fs, 0, 10), vec![1, 2, 3, 4, 5, 6]);
+        assert_eq!((cache.hits(), cache.misses()), (3, 3));
+    }
+
+    #[test]
+    fn test_dir_entry_cache_ttl() {
+        let fs = ListFs::default();
+        *fs.count.lock().unwrap() = 3;
+        let cache = DirEntryCache::new(Duration::from_secs(0));
+
+        assert_eq!(list(&cache, &fs, 0, 10), vec![1, 2, 3]);
+        assert_eq!(list(&cache, &fs, 0, 10), vec![1, 2, 3]);
+        assert_eq!((cache.hits(), cache.misses()), (0, 2));
+        assert_eq!(fs.readdirs.load(Ordering::Relaxed), 6);
+    }
+
+    #[test]
+    fn test_dir_entry_cache_readdirplus() {
+        let fs = ListFs::default();
+        *fs.count.lock().unwrap() = 4;
+        let cache = DirEntryCache::new(Duration::from_secs(60));
+        assert_eq!(list(&cache, &fs, 0, 10), vec![1, 2, 3, 4]);
+
+        // The entry "4" has been removed behind the back of the cache.
+        *fs.count.lock().unwrap() = 3;
+        let mut entries = Vec::new();
+        cache
+            .readdirplus(&fs, &Context::default(), 1, 0, 4096, 0, &mut |d, e| {
+                entries.push((d.ino, e.inode));
+                Ok(1)
+            })
+            .unwrap();
+        assert_eq!(entries, vec![(2, 102), (3, 103)]);
+        assert_eq!(fs.lookups.load(Ordering::Relaxed), 3);
+        assert_eq!((cache.hits(), cache.misses()), (1, 1));
+    }
+}
@@ -14,8 +14,12 @@ use async_trait::async_trait;
 
 use super::{Context, Entry, FileSystem, ZeroCopyReader, ZeroCopyWriter};
 use crate::abi::fuse_abi::{stat64, OpenOptions, SetattrValid};
+#[cfg(feature = "virtiofs")]
+use crate::abi::virtio_fs::RemovemappingOne;
 use crate::api::CreateIn;
 use crate::async_util::{AsyncDrive, AsyncDriver};
+#[cfg(feature = "virtiofs")]
+use crate::transport::AsyncFsCacheReqHandler;
 
 /// A trait for directly copying data from the fuse transport into a `File` without first storing it
 /// in an intermediate buffer in asynchronous mode.
@@ -713,6 +717,40 @@ pub trait AsyncFileSystem<D: AsyncDrive = AsyncDriver>: FileSystem {
         handle: Self::Handle,
     ) -> io::Result<()>;
 
+    /// Setup a mapping so that guest can access files in DAX style.
+    ///
+    /// This is the asynchronous version of [FileSystem::setupmapping], which issues the mapping
+    /// request through an [AsyncFsCacheReqHandler] instead of blocking the worker.
+    #[cfg(feature = "virtiofs")]
+    #[allow(clippy::too_many_arguments)]
+    async fn async_setupmapping(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        foffset: u64,
+        len: u64,
+        flags: u64,
+        moffset: u64,
+        vu_req: &mut dyn AsyncFsCacheReqHandler,
+    ) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Teardown a mapping which was setup for guest DAX style access.
+    ///
+    /// This is the asynchronous version of [FileSystem::removemapping].
+    #[cfg(feature = "virtiofs")]
+    async fn async_removemapping(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        requests: Vec<RemovemappingOne>,
+        vu_req: &mut dyn AsyncFsCacheReqHandler,
+    ) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
     /*
     /// Release an open directory.
     ///
@@ -1023,4 +1061,44 @@ impl<FS: AsyncFileSystem<D>, D: AsyncDrive> AsyncFileSystem<D> for Arc<FS> {
     {
         self.deref().async_fsyncdir(ctx, inode, datasync, handle)
     }
+
+    #[cfg(feature = "virtiofs")]
+    fn async_setupmapping<'a, 'b, 'c, 'async_trait>(
+        &'a self,
+        ctx: &'b Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        foffset: u64,
+        len: u64,
+        flags: u64,
+        moffset: u64,
+        vu_req: &'c mut dyn AsyncFsCacheReqHandler,
+    ) -> Pin<Box<dyn Future<Output = io::Result<()>> + Send + 'async_trait>>
+    where
+        'a: 'async_trait,
+        'b: 'async_trait,
+        'c: 'async_trait,
+        Self: 'async_trait,
+    {
+        self.deref()
+            .async_setupmapping(ctx, inode, handle, foffset, len, flags, moffset, vu_req)
+    }
+
+    #[cfg(feature = "virtiofs")]
+    fn async_removemapping<'a, 'b, 'c, 'async_trait>(
+        &'a self,
+        ctx: &'b Context,
+        inode: Self::Inode,
+        requests: Vec<RemovemappingOne>,
+        vu_req: &'c mut dyn AsyncFsCacheReqHandler,
+    ) -> Pin<Box<dyn Future<Output = io::Result<()>> + Send + 'async_trait>>
+    where
+        'a: 'async_trait,
+        'b: 'async_trait,
+        'c: 'async_trait,
+        Self: 'async_trait,
+    {
+        self.deref()
+            .async_removemapping(ctx, inode, requests, vu_req)
+    }
 }
@@ -9,13 +9,17 @@
 //! and the backend filesystem server. Other structs are used to pass information from the
 
 use std::convert::TryInto;
+use std::ffi::CString;
 use std::io;
 use std::time::Duration;
 
+use bitflags::bitflags;
+
 use crate::abi::fuse_abi as fuse;
 use crate::transport::FileReadWriteVolatile;
 
 pub use fuse::FsOptions;
+pub use fuse::FsOptionsExt;
 pub use fuse::OpenOptions;
 pub use fuse::SetattrValid;
 pub use fuse::ROOT_ID;
@@ -52,6 +56,11 @@ pub struct Entry {
     pub attr: stat64,
 
     /// Flags for 'fuse::Attr.flags'
+    ///
+    /// Setting `fuse::ATTR_SUBMOUNT` on a directory makes the kernel mount it as a separate file
+    /// system, if `FsOptions::SUBMOUNTS` has been negotiated. Only the kernel's virtiofs driver
+    /// supports it, since Linux 5.10, and it's only honored in entry replies, e.g. to `lookup`
+    /// and `readdirplus`, not in the replies to `getattr`.
     pub attr_flags: u32,
 
     /// How long the values in `attr` should be considered valid. If the attributes of the `Entry`
@@ -66,15 +75,13 @@ pub struct Entry {
 
 impl From<Entry> for fuse::EntryOut {
     fn from(entry: Entry) -> fuse::EntryOut {
-        fuse::EntryOut {
-            nodeid: entry.inode,
-            generation: entry.generation,
-            entry_valid: entry.entry_timeout.as_secs(),
-            attr_valid: entry.attr_timeout.as_secs(),
-            entry_valid_nsec: entry.entry_timeout.subsec_nanos(),
-            attr_valid_nsec: entry.attr_timeout.subsec_nanos(),
-            attr: fuse::Attr::with_flags(entry.attr, entry.attr_flags),
-        }
+        fuse::EntryOut::new(
+            entry.inode,
+            entry.generation,
+            fuse::Attr::with_flags(entry.attr, entry.attr_flags),
+            entry.attr_timeout,
+            entry.entry_timeout,
+        )
     }
 }
 
@@ -113,6 +120,90 @@ pub struct DirEntry<'a> {
     pub name: &'a [u8],
 }
 
+/// Represents an entry in a directory returned by
+/// [FileSystem::readdir_cursor()](trait.FileSystem.html#method.readdir_cursor).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DirEntryBuf {
+    /// The inode number for this entry, with the same requirements as `DirEntry::ino`.
+    pub ino: ino64_t,
+
+    /// The type of this directory entry. Valid values are any of the `libc::DT_*` constants.
+    pub type_: u32,
+
+    /// The name of this directory entry.
+    pub name: Vec<u8>,
+}
+
+/// Security context of a new node, sent by the kernel with create, mkdir, mknod and symlink
+/// requests once `FsOptionsExt::SECURITY_CTX` has been negotiated.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SecContext {
+    /// Name of the extended attribute holding the context, e.g. `security.selinux`.
+    pub name: CString,
+
+    /// Value of the security context.
+    pub value: Vec<u8>,
+}
+
+/// An extent of a file, as reported by `ioctl(FS_IOC_FIEMAP)`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FiemapExtent {
+    /// Offset of the extent in the file.
+    pub logical: u64,
+    /// Offset of the extent on the backing device, or 0 for holes.
+    pub physical: u64,
+    /// Length of the extent in bytes.
+    pub length: u64,
+    /// `FiemapExtent::*` flags of the extent.
+    pub flags: u32,
+}
+
+impl FiemapExtent {
+    /// The last extent of the file.
+    pub const LAST: u32 = 0x1;
+    /// The location of the extent is unknown.
+    pub const UNKNOWN: u32 = 0x2;
+    /// The extent has been allocated but not written, or is a hole.
+    pub const UNWRITTEN: u32 = 0x800;
+    /// The extent is shared with other files.
+    pub const SHARED: u32 = 0x2000;
+}
+
+bitflags! {
+    /// Optional operations implemented by a file system, see
+    /// [FileSystem::supported_ops()](trait.FileSystem.html#method.supported_ops).
+    ///
+    /// A file system not implementing an operation fails it with `ENOSYS`, or `EOPNOTSUPP`.
+    pub struct SupportedOps: u64 {
+        /// `lseek()` with `SEEK_DATA` and `SEEK_HOLE`.
+        const LSEEK = 0x1;
+        /// `clone_range()`, used to serve `copy_file_range()` by reflink.
+        const CLONE_RANGE = 0x2;
+        /// `fallocate()`.
+        const FALLOCATE = 0x4;
+        /// `ioctl()`.
+        const IOCTL = 0x8;
+        /// `poll()`.
+        const POLL = 0x10;
+        /// `bmap()`.
+        const BMAP = 0x20;
+        /// POSIX record locks by `getlk()`, `setlk()` and `setlkw()`.
+        const POSIX_LOCKS = 0x40;
+        /// Extended attributes.
+        const XATTR = 0x80;
+        /// `tmpfile()`.
+        const TMPFILE = 0x100;
+        /// `statx()`.
+        const STATX = 0x200;
+        /// `enable_verity()` and `measure_verity()`.
+        const VERITY = 0x400;
+        /// `fiemap()`.
+        const FIEMAP = 0x800;
+        /// DAX mappings by `setupmapping()` and `removemapping()`.
+        const DAX_MAPPING = 0x1000;
+    }
+}
+
 /// Represents a fuse lock
 #[derive(Copy, Clone)]
 pub struct FileLock {
@@ -372,9 +463,61 @@ pub trait ZeroCopyWriter: io::Write {
     }
 }
 
+/// An extension appended by the kernel to a request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Extension {
+    /// Security contexts of the node to create, see `FsOptionsExt::SECURITY_CTX`.
+    SecCtx(Vec<SecContext>),
+    /// Supplementary groups of the calling process, see `FsOptionsExt::CREATE_SUPP_GROUP`.
+    SuppGroups(Vec<libc::gid_t>),
+    /// An extension unknown to this crate, as its type and payload following the extension
+    /// header.
+    Unknown(u32, Vec<u8>),
+}
+
+/// Extensions appended by the kernel to a request, in the order they have been sent.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Extensions(Vec<Extension>);
+
+impl Extensions {
+    /// Create extensions from a list of extensions.
+    pub fn new(extensions: Vec<Extension>) -> Self {
+        Extensions(extensions)
+    }
+
+    /// Check whether there's no extension.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Get an iterator over all extensions.
+    pub fn iter(&self) -> std::slice::Iter<'_, Extension> {
+        self.0.iter()
+    }
+
+    /// Get the security contexts, if sent with the request.
+    pub fn secctx(&self) -> Option<&[SecContext]> {
+        self.0.iter().find_map(|e| match e {
+            Extension::SecCtx(v) => Some(v.as_slice()),
+            _ => None,
+        })
+    }
+
+    /// Get the supplementary groups of the calling process, if sent with the request.
+    pub fn supp_groups(&self) -> Option<&[libc::gid_t]> {
+        self.0.iter().find_map(|e| match e {
+            Extension::SuppGroups(v) => Some(v.as_slice()),
+            _ => None,
+        })
+    }
+}
+
+// Extensions of requests which haven't been sent with any.
+static NO_EXTENSIONS: Extensions = Extensions(Vec::new());
+
 /// Additional context associated with requests.
-#[derive(Default, Clone, Copy, Debug)]
-pub struct Context {
+#[derive(Clone, Copy, Debug)]
+pub struct Context<'a> {
     /// The user ID of the calling process.
     pub uid: libc::uid_t,
 
@@ -384,24 +527,54 @@ pub struct Context {
     /// The thread group ID of the calling process.
     pub pid: libc::pid_t,
 
+    /// Extensions appended by the kernel to the request, which are owned by the server and only
+    /// live as long as the request is being handled.
+    pub extensions: &'a Extensions,
+
+    /// The raw header of the request, which is only available if the file system asks for it by
+    /// [FileSystem::wants_raw_header()](trait.FileSystem.html#method.wants_raw_header).
+    pub raw_header: Option<fuse::InHeader>,
+
     #[cfg(feature = "async-io")]
     /// Asynchronous event drive
     pub drive: usize,
 }
 
-impl Context {
+impl Default for Context<'_> {
+    fn default() -> Self {
+        Context::from(&fuse::InHeader::default())
+    }
+}
+
+impl Context<'_> {
     /// Create a new 'Context' object.
     pub fn new() -> Self {
         Self::default()
     }
+
+    /// Get a copy of the context without the extensions of the request, which may be kept after
+    /// the request has been handled.
+    pub fn without_extensions(&self) -> Context<'static> {
+        Context {
+            uid: self.uid,
+            gid: self.gid,
+            pid: self.pid,
+            extensions: &NO_EXTENSIONS,
+            raw_header: self.raw_header,
+            #[cfg(feature = "async-io")]
+            drive: self.drive,
+        }
+    }
 }
 
-impl From<&fuse::InHeader> for Context {
+impl From<&fuse::InHeader> for Context<'_> {
     fn from(source: &fuse::InHeader) -> Self {
         Context {
             uid: source.uid,
             gid: source.gid,
             pid: source.pid as i32,
+            extensions: &NO_EXTENSIONS,
+            raw_header: None,
             #[cfg(feature = "async-io")]
             drive: 0,
         }
@@ -411,7 +584,7 @@ impl From<&fuse::InHeader> for Context {
 // The design is very ugly, but it helps to avoid adding a generic type parameter "D: AsyncDrive"
 // to the FileSystem trait.
 #[cfg(feature = "async-io")]
-impl Context {
+impl Context<'_> {
     /// Set the asynchronous event drive.
     ///
     /// ## Safety
@@ -453,6 +626,7 @@ mod tests {
             uid: 3,
             gid: 4,
             pid: 5,
+            total_extlen: 0,
             padding: 0,
         };
         let header: Context = fuse_header.into();
@@ -462,6 +636,24 @@ mod tests {
         assert_eq!(header.pid, 5);
     }
 
+    #[test]
+    fn test_context_extensions() {
+        let extensions = Extensions::new(vec![Extension::SuppGroups(vec![10])]);
+        let ctx = Context {
+            uid: 3,
+            extensions: &extensions,
+            ..Default::default()
+        };
+        let copy = ctx;
+        assert_eq!(ctx.extensions.supp_groups(), Some(&[10][..]));
+        assert_eq!(copy.extensions, &extensions);
+
+        let detached = ctx.without_extensions();
+        assert_eq!(detached.uid, 3);
+        assert!(detached.extensions.is_empty());
+        assert!(Context::default().extensions.is_empty());
+    }
+
     #[test]
     fn test_into_fuse_entry() {
         let attr = Attr {
@@ -4,15 +4,17 @@
 // found in the LICENSE-BSD-3-Clause file.
 
 use super::{
-    Context, DirEntry, Entry, FileLock, GetxattrReply, IoctlData, ListxattrReply, ZeroCopyReader,
-    ZeroCopyWriter,
+    Context, DirEntry, DirEntryBuf, Entry, FiemapExtent, FileLock, GetxattrReply, IoctlData,
+    ListxattrReply, SecContext, SupportedOps, ZeroCopyReader, ZeroCopyWriter,
+};
+use crate::abi::fuse_abi::{
+    stat64, statvfs64, CreateIn, FsOptions, FsOptionsExt, OpenOptions, SetattrValid, Statx,
 };
-use crate::abi::fuse_abi::{stat64, statvfs64, CreateIn, FsOptions, OpenOptions, SetattrValid};
 #[cfg(feature = "virtiofs")]
 pub use crate::abi::virtio_fs::RemovemappingOne;
 #[cfg(feature = "virtiofs")]
 use crate::transport::virtiofs::FsCacheReqHandler;
-use std::ffi::CStr;
+use std::ffi::{CStr, CString};
 use std::io;
 use std::mem;
 use std::ops::Deref;
@@ -59,14 +61,57 @@ pub trait FileSystem {
         Ok(FsOptions::empty())
     }
 
+    /// Negotiate the extended options, carried by the `flags2` field of FUSE_INIT.
+    ///
+    /// It's called right after a successful `init` with the extended options supported by the
+    /// kernel, which are only sent by kernels since 7.36, and returns the extended options that
+    /// the file system supports. Any options not in `capable` are silently dropped.
+    fn init_ext(&self, capable: FsOptionsExt) -> io::Result<FsOptionsExt> {
+        Ok(FsOptionsExt::empty())
+    }
+
     /// Clean up the file system.
     ///
     /// Called when the filesystem exits. All open `Handle`s should be closed and the lookup count
     /// for all open `Inode`s implicitly goes to zero. At this point the connection to the FUSE
     /// kernel module may already be gone so implementations should not rely on being able to
     /// communicate with the kernel.
+    ///
+    /// [Server](crate::api::server::Server) calls it once for each successful `init`, either on
+    /// FUSE_DESTROY or when the connection is aborted, see
+    /// [Server::shutdown()](crate::api::server::Server::shutdown).
     fn destroy(&self) {}
 
+    /// Whether the file system needs the raw header of requests.
+    ///
+    /// If it returns true, [Context::raw_header](struct.Context.html#structfield.raw_header) is set
+    /// for all requests passed to the file system, e.g. to get the `unique` id of a request for
+    /// tracing, or the node id as sent by the kernel. It's called for every request, so the result
+    /// should be cheap to compute.
+    fn wants_raw_header(&self) -> bool {
+        false
+    }
+
+    /// Get the depth of stacked file systems below this one, including itself.
+    ///
+    /// It's reported to the kernel on FUSE_INIT if `FsOptionsExt::PASSTHROUGH` is enabled, and must
+    /// be between 1 and `FILESYSTEM_MAX_STACK_DEPTH`, e.g. 2 for a file system serving a
+    /// directory on another FUSE mount.
+    fn max_stack_depth(&self) -> u32 {
+        1
+    }
+
+    /// Get the optional operations implemented by the file system.
+    ///
+    /// It's called once FUSE_INIT has been handled, and only reported by
+    /// [Server::capabilities()](crate::api::server::Server::capabilities), e.g. to tell operators
+    /// why `copy_file_range()` doesn't use reflink on a mount. Requests are dispatched no matter
+    /// what it returns. The default is an empty set, so file systems should set the bits of the
+    /// operations they override, and have enabled by their configuration.
+    fn supported_ops(&self) -> SupportedOps {
+        SupportedOps::empty()
+    }
+
     /// Look up a directory entry by name and get its attributes.
     ///
     /// If this call is successful then the lookup count of the `Inode` associated with the returned
@@ -115,6 +160,47 @@ pub trait FileSystem {
         Err(io::Error::from_raw_os_error(libc::ENOSYS))
     }
 
+    /// Get attributes for multiple files / directories.
+    ///
+    /// Called by the server to serve GETATTR requests without a file handle which have been
+    /// coalesced, see [Server::set_getattr_coalescing()](crate::api::server::Server::set_getattr_coalescing).
+    /// The requests may come from different callers, `ctx` is the context of the first one, so
+    /// file systems checking the credentials of the caller in `getattr` shouldn't override it.
+    ///
+    /// It must return one result for each inode, in the same order. The default implementation
+    /// calls `getattr` for each inode in turn, file systems which can fetch the attributes of many
+    /// inodes at once cheaper, e.g. from a remote server, may override it.
+    fn getattr_batch(
+        &self,
+        ctx: &Context,
+        inodes: Vec<Self::Inode>,
+    ) -> Vec<io::Result<(stat64, Duration)>> {
+        inodes
+            .into_iter()
+            .map(|inode| self.getattr(ctx, inode, None))
+            .collect()
+    }
+
+    /// Get extended attributes for a file / directory, as `statx(2)`.
+    ///
+    /// `handle` has the same meaning as for `getattr`. `flags` are the `AT_STATX_*`
+    /// synchronization flags of the caller, and `mask` is the set of `STATX_*` fields wanted by
+    /// the caller. Fields not asked for may be omitted, and `Statx::mask` should tell the fields
+    /// actually filled.
+    ///
+    /// The kernel falls back to `getattr` if this method returns an `ENOSYS` error, which can't
+    /// report fields such as the birth time of files.
+    fn statx(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Option<Self::Handle>,
+        flags: u32,
+        mask: u32,
+    ) -> io::Result<(Statx, Duration)> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
     /// Set attributes for a file / directory.
     ///
     /// If `handle` is not `None`, then it contains the handle previously returned by the
@@ -148,6 +234,17 @@ pub trait FileSystem {
         Err(io::Error::from_raw_os_error(libc::ENOSYS))
     }
 
+    /// Get the canonical path of an inode.
+    ///
+    /// It's used to serve `FUSE_CANONICAL_PATH`, which is issued by some clients, such as Android,
+    /// to map an inode back to the path of the file. The path is absolute from the root of the
+    /// file system, e.g. `/dir/file`, so the layout of the backing storage isn't disclosed to
+    /// the client. The file system should return `ENOENT` if the inode has been unlinked and has
+    /// no path anymore, and `EXDEV` if it isn't reachable from the root.
+    fn canonical_path(&self, ctx: &Context, inode: Self::Inode) -> io::Result<CString> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
     /// Create a symbolic link.
     ///
     /// The file system must create a symbolic link named `name` in the directory represented by
@@ -208,6 +305,29 @@ pub trait FileSystem {
         Err(io::Error::from_raw_os_error(libc::ENOSYS))
     }
 
+    /// Apply the security context to a newly created node.
+    ///
+    /// It's called by the server after `create`, `mkdir`, `mknod` or `symlink` has successfully
+    /// created the node `name` represented by `inode` in the directory `parent`, if the kernel
+    /// has sent a security context with the request. The security context is only sent when the
+    /// `FsOptionsExt::SECURITY_CTX` feature has been negotiated. If this call fails, the server
+    /// releases the lookup count of `inode` taken by the creation and replies the error to the
+    /// kernel.
+    ///
+    /// The default implementation stores the context as an extended attribute by `setxattr`,
+    /// which follows symlinks on most file systems. File systems supporting symlinks should
+    /// override it to label the link itself.
+    fn init_security_context(
+        &self,
+        ctx: &Context,
+        parent: Self::Inode,
+        name: &CStr,
+        inode: Self::Inode,
+        secctx: &SecContext,
+    ) -> io::Result<()> {
+        self.setxattr(ctx, inode, &secctx.name, &secctx.value, 0)
+    }
+
     /// Remove a file.
     ///
     /// If the file's inode lookup count is non-zero, then the file system is expected to delay
@@ -341,6 +461,41 @@ pub trait FileSystem {
         Err(io::Error::from_raw_os_error(libc::ENOSYS))
     }
 
+    /// Create and open an unnamed temporary file.
+    ///
+    /// This is called for `open()` with the `O_TMPFILE` flag. The file is created in the directory
+    /// `parent` but it has no name, so it's deleted once the last reference goes away unless it's
+    /// linked into the file system by `link`. `mode` and `umask` are the same as in `create`, and
+    /// `flags` contains the flags used to open the file, including `O_TMPFILE`.
+    ///
+    /// Like `create`, the file system must return an `Entry` for the unnamed file in addition to
+    /// the optional `Handle` and the `OpenOptions`, which increases the lookup count for the
+    /// `Inode` by 1.
+    ///
+    /// The default implementation forwards the request to `create` with the `O_TMPFILE` flag and
+    /// the name "/" used by the kernel for unnamed files. If the file system returns an `ENOSYS`
+    /// error, then the kernel will fail all future `O_TMPFILE` opens with `EOPNOTSUPP` without
+    /// forwarding them to the file system.
+    fn tmpfile(
+        &self,
+        ctx: &Context,
+        parent: Self::Inode,
+        mode: u32,
+        umask: u32,
+        flags: u32,
+    ) -> io::Result<(Entry, Option<Self::Handle>, OpenOptions)> {
+        // Safe because this is a constant value and a valid C string.
+        let name = unsafe { CStr::from_bytes_with_nul_unchecked(b"/\0") };
+        let args = CreateIn {
+            flags: flags | libc::O_TMPFILE as u32,
+            mode,
+            umask,
+            fuse_flags: 0,
+        };
+
+        self.create(ctx, parent, name, args)
+    }
+
     /// Read data from a file.
     ///
     /// Returns `size` bytes of data starting from offset `off` from the file associated with
@@ -356,6 +511,14 @@ pub trait FileSystem {
     /// zeroes. An exception to this rule is if the file was opened with the "direct I/O" option
     /// (`libc::O_DIRECT`), in which case the kernel will forward the return code from this method
     /// to the userspace application that made the system call.
+    ///
+    /// The same applies to files for which `open` returned `OpenOptions::DIRECT_IO`. Pipe-like
+    /// files producing data over time should be opened with `OpenOptions::DIRECT_IO`,
+    /// `OpenOptions::NONSEEKABLE` and `OpenOptions::STREAM`, and may return whatever data is
+    /// available instead of waiting for `size` bytes. The reply carries exactly the returned
+    /// number of bytes, the server never retries to fill up the request. For stream-like files
+    /// `offset` is meaningless and the file system keeps track of the position itself. Returning
+    /// zero bytes is seen as EOF by the reader, so block until some data is available instead.
     #[allow(clippy::too_many_arguments)]
     fn read(
         &self,
@@ -530,6 +693,15 @@ pub trait FileSystem {
         Ok(st)
     }
 
+    /// Synchronize the whole file system to stable storage, like `syncfs(2)`.
+    ///
+    /// The kernel sends it on `syncfs(2)` and `sync(2)`, since protocol 7.34 (Linux 5.14), and
+    /// only for virtio-fs mounts. If this method returns an `ENOSYS` error then the kernel will
+    /// treat it as success and won't forward it to the file system anymore.
+    fn syncfs(&self, ctx: &Context) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
     /// Set an extended attribute.
     ///
     /// If this method fails with an `ENOSYS` error, then the kernel will treat that as a permanent
@@ -701,6 +873,36 @@ pub trait FileSystem {
         Err(io::Error::from_raw_os_error(libc::ENOSYS))
     }
 
+    /// Read a directory page by page with opaque continuation cursors.
+    ///
+    /// This is an alternative to `readdir` for file systems which can't map a position in the
+    /// directory entry stream to a numeric offset, such as listings of a remote object store
+    /// paginated by continuation tokens.
+    ///
+    /// `cursor` is `None` to read from the beginning of the directory, otherwise it's a cursor
+    /// previously returned by this method for the same handle. `size` indicates the maximum number
+    /// of bytes the caller is able to return to the kernel, so it's a hint of how many entries
+    /// are wanted.
+    ///
+    /// Returns the entries following `cursor` and the cursor to read the entries after them, or
+    /// `None` if the end of the directory has been reached. Entries may be returned beyond the
+    /// space available, and a page may be read again with the same cursor if not all of its
+    /// entries have been consumed, so cursors must stay valid until the handle is released.
+    ///
+    /// The server keeps the mapping between the numeric offsets seen by the kernel and the
+    /// cursors, and `readdir` is used if this method returns an `ENOSYS` error. Names only are
+    /// returned for `READDIRPLUS` requests when this method is implemented.
+    fn readdir_cursor(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        size: u32,
+        cursor: Option<&[u8]>,
+    ) -> io::Result<(Vec<DirEntryBuf>, Option<Vec<u8>>)> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
     /// Synchronize the contents of a directory.
     ///
     /// File systems must ensure that the directory contents have been flushed to disk before
@@ -811,6 +1013,70 @@ pub trait FileSystem {
         Err(io::Error::from_raw_os_error(libc::ENOSYS))
     }
 
+    /// Clone a range of data from one file into another file.
+    ///
+    /// Instead of copying data, the destination range shares the same data extents with the
+    /// source range, like `ioctl(FICLONERANGE)` does. It's used to serve `copy_file_range()`,
+    /// so copying files on reflink capable file systems completes instantly.
+    ///
+    /// `src_handle` and `dst_handle` are the `Handle`s returned by the file system from the
+    /// `open` method, if any. A `len` of zero means cloning to the end of the source file.
+    /// On success, the number of bytes cloned is returned.
+    ///
+    /// The file system should return `EXDEV` if the two files are not on the same backing file
+    /// system, and `EOPNOTSUPP` if cloning isn't supported. In both cases the kernel falls back
+    /// to copying data with read and write requests.
+    #[allow(clippy::too_many_arguments)]
+    fn clone_range(
+        &self,
+        ctx: &Context,
+        src_inode: Self::Inode,
+        src_handle: Self::Handle,
+        src_offset: u64,
+        dst_inode: Self::Inode,
+        dst_handle: Self::Handle,
+        dst_offset: u64,
+        len: u64,
+    ) -> io::Result<usize> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Enable fs-verity on a file.
+    ///
+    /// Once enabled, the file becomes immutable and every read of its contents is verified against
+    /// a Merkle tree built over the file data, so any tampering with the backing storage is
+    /// detected. It mirrors `ioctl(FS_IOC_ENABLE_VERITY)` and only applies to regular files.
+    ///
+    /// The file system should return `EOPNOTSUPP` if fs-verity isn't supported.
+    fn enable_verity(&self, ctx: &Context, inode: Self::Inode) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Get the fs-verity digest of a file.
+    ///
+    /// On success, the raw digest of the file is returned, which may be compared against a known
+    /// good digest to authenticate the file contents. It mirrors `ioctl(FS_IOC_MEASURE_VERITY)`,
+    /// and the file system should return `ENODATA` if fs-verity isn't enabled on the file.
+    fn measure_verity(&self, ctx: &Context, inode: Self::Inode) -> io::Result<Vec<u8>> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Get the extents of a file in the range from `start` of `len` bytes.
+    ///
+    /// It mirrors `ioctl(FS_IOC_FIEMAP)`, except that holes in the range are also reported, as
+    /// extents with the `FiemapExtent::UNWRITTEN` flag and no physical location. Extents shared
+    /// with other files, e.g. by reflinks, have the `FiemapExtent::SHARED` flag, and their
+    /// physical locations may be compared to find the shared ranges.
+    fn fiemap(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        start: u64,
+        len: u64,
+    ) -> io::Result<Vec<FiemapExtent>> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
     /// Query file lock status
     fn getlk(
         &self,
@@ -905,10 +1171,26 @@ impl<FS: FileSystem> FileSystem for Arc<FS> {
         self.deref().init(capable)
     }
 
+    fn init_ext(&self, capable: FsOptionsExt) -> io::Result<FsOptionsExt> {
+        self.deref().init_ext(capable)
+    }
+
     fn destroy(&self) {
         self.deref().destroy()
     }
 
+    fn wants_raw_header(&self) -> bool {
+        self.deref().wants_raw_header()
+    }
+
+    fn max_stack_depth(&self) -> u32 {
+        self.deref().max_stack_depth()
+    }
+
+    fn supported_ops(&self) -> SupportedOps {
+        self.deref().supported_ops()
+    }
+
     fn lookup(&self, ctx: &Context, parent: Self::Inode, name: &CStr) -> io::Result<Entry> {
         self.deref().lookup(ctx, parent, name)
     }
@@ -930,6 +1212,25 @@ impl<FS: FileSystem> FileSystem for Arc<FS> {
         self.deref().getattr(ctx, inode, handle)
     }
 
+    fn getattr_batch(
+        &self,
+        ctx: &Context,
+        inodes: Vec<Self::Inode>,
+    ) -> Vec<io::Result<(stat64, Duration)>> {
+        self.deref().getattr_batch(ctx, inodes)
+    }
+
+    fn statx(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Option<Self::Handle>,
+        flags: u32,
+        mask: u32,
+    ) -> io::Result<(Statx, Duration)> {
+        self.deref().statx(ctx, inode, handle, flags, mask)
+    }
+
     fn setattr(
         &self,
         ctx: &Context,
@@ -945,6 +1246,10 @@ impl<FS: FileSystem> FileSystem for Arc<FS> {
         self.deref().readlink(ctx, inode)
     }
 
+    fn canonical_path(&self, ctx: &Context, inode: Self::Inode) -> io::Result<CString> {
+        self.deref().canonical_path(ctx, inode)
+    }
+
     fn symlink(
         &self,
         ctx: &Context,
@@ -978,6 +1283,18 @@ impl<FS: FileSystem> FileSystem for Arc<FS> {
         self.deref().mkdir(ctx, parent, name, mode, umask)
     }
 
+    fn init_security_context(
+        &self,
+        ctx: &Context,
+        parent: Self::Inode,
+        name: &CStr,
+        inode: Self::Inode,
+        secctx: &SecContext,
+    ) -> io::Result<()> {
+        self.deref()
+            .init_security_context(ctx, parent, name, inode, secctx)
+    }
+
     fn unlink(&self, ctx: &Context, parent: Self::Inode, name: &CStr) -> io::Result<()> {
         self.deref().unlink(ctx, parent, name)
     }
@@ -1029,6 +1346,17 @@ impl<FS: FileSystem> FileSystem for Arc<FS> {
         self.deref().create(ctx, parent, name, args)
     }
 
+    fn tmpfile(
+        &self,
+        ctx: &Context,
+        parent: Self::Inode,
+        mode: u32,
+        umask: u32,
+        flags: u32,
+    ) -> io::Result<(Entry, Option<Self::Handle>, OpenOptions)> {
+        self.deref().tmpfile(ctx, parent, mode, umask, flags)
+    }
+
     fn read(
         &self,
         ctx: &Context,
@@ -1124,6 +1452,10 @@ impl<FS: FileSystem> FileSystem for Arc<FS> {
         self.deref().statfs(ctx, inode)
     }
 
+    fn syncfs(&self, ctx: &Context) -> io::Result<()> {
+        self.deref().syncfs(ctx)
+    }
+
     fn setxattr(
         &self,
         ctx: &Context,
@@ -1193,6 +1525,18 @@ impl<FS: FileSystem> FileSystem for Arc<FS> {
             .readdirplus(ctx, inode, handle, size, offset, add_entry)
     }
 
+    fn readdir_cursor(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        size: u32,
+        cursor: Option<&[u8]>,
+    ) -> io::Result<(Vec<DirEntryBuf>, Option<Vec<u8>>)> {
+        self.deref()
+            .readdir_cursor(ctx, inode, handle, size, cursor)
+    }
+
     fn fsyncdir(
         &self,
         ctx: &Context,
@@ -1256,6 +1600,41 @@ impl<FS: FileSystem> FileSystem for Arc<FS> {
         self.deref().lseek(ctx, inode, handle, offset, whence)
     }
 
+    #[allow(clippy::too_many_arguments)]
+    fn clone_range(
+        &self,
+        ctx: &Context,
+        src_inode: Self::Inode,
+        src_handle: Self::Handle,
+        src_offset: u64,
+        dst_inode: Self::Inode,
+        dst_handle: Self::Handle,
+        dst_offset: u64,
+        len: u64,
+    ) -> io::Result<usize> {
+        self.deref().clone_range(
+            ctx, src_inode, src_handle, src_offset, dst_inode, dst_handle, dst_offset, len,
+        )
+    }
+
+    fn enable_verity(&self, ctx: &Context, inode: Self::Inode) -> io::Result<()> {
+        self.deref().enable_verity(ctx, inode)
+    }
+
+    fn measure_verity(&self, ctx: &Context, inode: Self::Inode) -> io::Result<Vec<u8>> {
+        self.deref().measure_verity(ctx, inode)
+    }
+
+    fn fiemap(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        start: u64,
+        len: u64,
+    ) -> io::Result<Vec<FiemapExtent>> {
+        self.deref().fiemap(ctx, inode, start, len)
+    }
+
     /// Query file lock status
     fn getlk(
         &self,
@@ -0,0 +1,1121 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A read-only file system served from a prebuilt image.
+//!
+//! The [ImageFs] serves an immutable directory tree packed into a single blob, such as a
+//! container base layer distributed as one artifact, without any backing directory. Images are
+//! created by the [ImageBuilder], and may be served from memory or from a file. All mutating
+//! requests fail with `EROFS`.
+//!
+//! The image layout is simple, all integers are little endian:
+//!
+//! ```text
+//! superblock, 32 bytes at offset 0:
+//!     magic       [u8; 8]     b"FBRSIMG\0"
+//!     version     u32         1
+//!     inodes      u32         number of inodes
+//!     table       u64         offset of the inode table
+//!     reserved    u64
+//!
+//! inode table, `inodes` records of 72 bytes, the record at index `i` is inode `i + 1`, and
+//! inode 1 is the root directory:
+//!     mode        u32         file type and permission bits, as `st_mode`
+//!     uid         u32
+//!     gid         u32
+//!     nlink       u32
+//!     rdev        u32
+//!     mtime_nsec  u32
+//!     mtime       i64         seconds since the epoch, also used as atime and ctime
+//!     parent      u64         parent directory, the root is its own parent
+//!     data_off    u64         offset and length of the data
+//!     data_len    u64
+//!     xattr_off   u64         offset and length of the extended attributes
+//!     xattr_len   u64
+//! ```
+//!
+//! The data of a regular file is its content, the data of a symlink is its target, and the data
+//! of a directory is a list of entries sorted by name, each of them being the inode number as a
+//! `u64`, followed by the length of the name as a `u16` and the name itself. The extended
+//! attributes are a list of the length of the name as a `u16`, the length of the value as a
+//! `u32`, followed by the name and the value.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{self, Error, Result, Write};
+use std::mem;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::abi::fuse_abi::{stat64, statvfs64, CreateIn, OpenOptions, SetattrValid};
+use crate::api::filesystem::*;
+
+type Inode = u64;
+type Handle = u64;
+
+const IMAGEFS_MAGIC: &[u8; 8] = b"FBRSIMG\0";
+const IMAGEFS_VERSION: u32 = 1;
+const IMAGEFS_SUPERBLOCK_SIZE: usize = 32;
+const IMAGEFS_INODE_SIZE: usize = 72;
+const IMAGEFS_BLOCK_SIZE: u64 = 4096;
+// The image never changes, so the kernel may cache everything forever.
+const IMAGEFS_DEFAULT_TIMEOUT: u64 = 1 << 32;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct ImageInode {
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    nlink: u32,
+    rdev: u32,
+    mtime_nsec: u32,
+    mtime: i64,
+    parent: u64,
+    data_off: u64,
+    data_len: u64,
+    xattr_off: u64,
+    xattr_len: u64,
+}
+
+impl ImageInode {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        for v in [
+            self.mode,
+            self.uid,
+            self.gid,
+            self.nlink,
+            self.rdev,
+            self.mtime_nsec,
+        ] {
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        buf.extend_from_slice(&self.mtime.to_le_bytes());
+        for v in [
+            self.parent,
+            self.data_off,
+            self.data_len,
+            self.xattr_off,
+            self.xattr_len,
+        ] {
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+    }
+
+    fn decode(buf: &[u8]) -> Self {
+        let u32_at = |i: usize| u32::from_le_bytes(buf[i..i + 4].try_into().unwrap());
+        let u64_at = |i: usize| u64::from_le_bytes(buf[i..i + 8].try_into().unwrap());
+
+        ImageInode {
+            mode: u32_at(0),
+            uid: u32_at(4),
+            gid: u32_at(8),
+            nlink: u32_at(12),
+            rdev: u32_at(16),
+            mtime_nsec: u32_at(20),
+            mtime: u64_at(24) as i64,
+            parent: u64_at(32),
+            data_off: u64_at(40),
+            data_len: u64_at(48),
+            xattr_off: u64_at(56),
+            xattr_len: u64_at(64),
+        }
+    }
+
+    fn file_type(&self) -> u32 {
+        self.mode & libc::S_IFMT
+    }
+
+    fn is_dir(&self) -> bool {
+        self.file_type() == libc::S_IFDIR
+    }
+}
+
+fn invalid_image(msg: &str) -> Error {
+    Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid image: {}", msg),
+    )
+}
+
+// Number of blocks of `block_size` bytes needed to hold `size` bytes.
+fn blocks(size: u64, block_size: u64) -> u64 {
+    size.saturating_add(block_size - 1) / block_size
+}
+
+fn erofs() -> Error {
+    Error::from_raw_os_error(libc::EROFS)
+}
+
+// Iterate over `(inode, name)` pairs of a directory.
+struct DirIter<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Iterator for DirIter<'a> {
+    type Item = Result<(u64, &'a [u8])>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buf.is_empty() {
+            return None;
+        }
+        if self.buf.len() < 10 {
+            self.buf = &[];
+            return Some(Err(invalid_image("truncated directory entry")));
+        }
+        let ino = u64::from_le_bytes(self.buf[0..8].try_into().unwrap());
+        let len = u16::from_le_bytes(self.buf[8..10].try_into().unwrap()) as usize;
+        if self.buf.len() < 10 + len {
+            self.buf = &[];
+            return Some(Err(invalid_image("truncated directory entry")));
+        }
+        let name = &self.buf[10..10 + len];
+        self.buf = &self.buf[10 + len..];
+
+        Some(Ok((ino, name)))
+    }
+}
+
+// Iterate over `(name, value)` pairs of extended attributes.
+struct XattrIter<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Iterator for XattrIter<'a> {
+    type Item = Result<(&'a [u8], &'a [u8])>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buf.is_empty() {
+            return None;
+        }
+        if self.buf.len() < 6 {
+            self.buf = &[];
+            return Some(Err(invalid_image("truncated extended attribute")));
+        }
+        let name_len = u16::from_le_bytes(self.buf[0..2].try_into().unwrap()) as usize;
+        let value_len = u32::from_le_bytes(self.buf[2..6].try_into().unwrap()) as usize;
+        if self.buf.len() - 6 < name_len + value_len {
+            self.buf = &[];
+            return Some(Err(invalid_image("truncated extended attribute")));
+        }
+        let name = &self.buf[6..6 + name_len];
+        let value = &self.buf[6 + name_len..6 + name_len + value_len];
+        self.buf = &self.buf[6 + name_len + value_len..];
+
+        Some(Ok((name, value)))
+    }
+}
+
+enum ImageSource {
+    Memory(Vec<u8>),
+    File(File),
+}
+
+/// A read-only file system serving an image created by [ImageBuilder].
+///
+/// Inodes are static, so the file system keeps no per-lookup state and `forget` is a no-op. Files
+/// and directories are opened without handles.
+pub struct ImageFs {
+    source: ImageSource,
+    size: u64,
+    inodes: Vec<ImageInode>,
+}
+
+impl ImageFs {
+    /// Serve an image held in memory.
+    pub fn from_bytes(buf: Vec<u8>) -> Result<Self> {
+        Self::load(ImageSource::Memory(buf))
+    }
+
+    /// Serve an image file, which is read on demand.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::load(ImageSource::File(File::open(path)?))
+    }
+
+    fn load(source: ImageSource) -> Result<Self> {
+        let size = match &source {
+            ImageSource::Memory(buf) => buf.len() as u64,
+            ImageSource::File(file) => file.metadata()?.len(),
+        };
+        let mut fs = ImageFs {
+            source,
+            size,
+            inodes: Vec::new(),
+        };
+
+        let sb = fs.read_range(0, IMAGEFS_SUPERBLOCK_SIZE as u64)?;
+        if &sb[0..8] != IMAGEFS_MAGIC {
+            return Err(invalid_image("bad magic"));
+        }
+        let version = u32::from_le_bytes(sb[8..12].try_into().unwrap());
+        if version != IMAGEFS_VERSION {
+            return Err(invalid_image(&format!("unsupported version {}", version)));
+        }
+        let count = u32::from_le_bytes(sb[12..16].try_into().unwrap()) as u64;
+        let table = u64::from_le_bytes(sb[16..24].try_into().unwrap());
+        if count == 0 {
+            return Err(invalid_image("no root directory"));
+        }
+
+        let buf = fs.read_range(table, count * IMAGEFS_INODE_SIZE as u64)?;
+        let inodes: Vec<ImageInode> = buf
+            .chunks_exact(IMAGEFS_INODE_SIZE)
+            .map(ImageInode::decode)
+            .collect();
+        for inode in inodes.iter() {
+            fs.check_range(inode.data_off, inode.data_len)?;
+            fs.check_range(inode.xattr_off, inode.xattr_len)?;
+            if inode.parent == 0 || inode.parent > count {
+                return Err(invalid_image("bad parent inode"));
+            }
+        }
+        if !inodes[0].is_dir() {
+            return Err(invalid_image("root is not a directory"));
+        }
+        fs.inodes = inodes;
+
+        Ok(fs)
+    }
+
+    fn check_range(&self, off: u64, len: u64) -> Result<()> {
+        match off.checked_add(len) {
+            Some(end) if end <= self.size => Ok(()),
+            _ => Err(invalid_image("range out of the image")),
+        }
+    }
+
+    // Get `len` bytes at `off` of the image, which has been validated against the image size.
+    fn read_range(&self, off: u64, len: u64) -> Result<Cow<'_, [u8]>> {
+        self.check_range(off, len)?;
+        match &self.source {
+            ImageSource::Memory(buf) => Ok(Cow::Borrowed(&buf[off as usize..(off + len) as usize])),
+            ImageSource::File(file) => {
+                let mut buf = vec![0u8; len as usize];
+                file.read_exact_at(&mut buf, off)?;
+                Ok(Cow::Owned(buf))
+            }
+        }
+    }
+
+    fn get_inode(&self, inode: Inode) -> Result<&ImageInode> {
+        inode
+            .checked_sub(1)
+            .and_then(|idx| self.inodes.get(idx as usize))
+            .ok_or_else(|| Error::from_raw_os_error(libc::ENOENT))
+    }
+
+    fn get_dir(&self, inode: Inode) -> Result<&ImageInode> {
+        let data = self.get_inode(inode)?;
+        if !data.is_dir() {
+            return Err(Error::from_raw_os_error(libc::ENOTDIR));
+        }
+        Ok(data)
+    }
+
+    fn stat(&self, inode: Inode, data: &ImageInode) -> stat64 {
+        // Safe because stat64 is a plain old data structure.
+        let mut st: stat64 = unsafe { mem::zeroed() };
+        st.st_ino = inode;
+        st.st_mode = data.mode as _;
+        st.st_nlink = data.nlink as _;
+        st.st_uid = data.uid;
+        st.st_gid = data.gid;
+        st.st_rdev = data.rdev as _;
+        st.st_size = data.data_len as i64;
+        st.st_blksize = IMAGEFS_BLOCK_SIZE as _;
+        st.st_blocks = blocks(data.data_len, 512) as i64;
+        st.st_atime = data.mtime as _;
+        st.st_atime_nsec = data.mtime_nsec as _;
+        st.st_mtime = data.mtime as _;
+        st.st_mtime_nsec = data.mtime_nsec as _;
+        st.st_ctime = data.mtime as _;
+        st.st_ctime_nsec = data.mtime_nsec as _;
+        st
+    }
+
+    fn entry(&self, inode: Inode) -> Result<Entry> {
+        let data = self.get_inode(inode)?;
+        Ok(Entry {
+            inode,
+            generation: 0,
+            attr: self.stat(inode, data),
+            attr_flags: 0,
+            attr_timeout: Duration::from_secs(IMAGEFS_DEFAULT_TIMEOUT),
+            entry_timeout: Duration::from_secs(IMAGEFS_DEFAULT_TIMEOUT),
+        })
+    }
+
+    fn do_readdir(
+        &self,
+        inode: Inode,
+        size: u32,
+        offset: u64,
+        add_entry: &mut dyn FnMut(DirEntry, Inode) -> Result<usize>,
+    ) -> Result<()> {
+        let dir = self.get_dir(inode)?;
+        if size == 0 {
+            return Ok(());
+        }
+
+        let buf = self.read_range(dir.data_off, dir.data_len)?;
+        let dots = [(inode, &b"."[..]), (dir.parent, &b".."[..])];
+        let entries = dots
+            .iter()
+            .map(|v| Ok(*v))
+            .chain(DirIter { buf: &buf[..] })
+            .enumerate()
+            .skip(offset as usize);
+        for (idx, entry) in entries {
+            let (ino, name) = entry?;
+            let child = self.get_inode(ino)?;
+            let dirent = DirEntry {
+                ino,
+                offset: idx as u64 + 1,
+                type_: child.file_type() >> 12,
+                name,
+            };
+            if add_entry(dirent, ino)? == 0 {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl FileSystem for ImageFs {
+    type Inode = Inode;
+    type Handle = Handle;
+
+    fn lookup(&self, _ctx: &Context, parent: Inode, name: &CStr) -> Result<Entry> {
+        let dir = self.get_dir(parent)?;
+        let name = name.to_bytes();
+        if name == b"." {
+            return self.entry(parent);
+        } else if name == b".." {
+            return self.entry(dir.parent);
+        }
+
+        let buf = self.read_range(dir.data_off, dir.data_len)?;
+        for entry in (DirIter { buf: &buf[..] }) {
+            let (ino, n) = entry?;
+            if n == name {
+                return self.entry(ino);
+            }
+        }
+
+        Err(Error::from_raw_os_error(libc::ENOENT))
+    }
+
+    fn getattr(
+        &self,
+        _ctx: &Context,
+        inode: Inode,
+        _handle: Option<Handle>,
+    ) -> Result<(stat64, Duration)> {
+        let data = self.get_inode(inode)?;
+        Ok((
+            self.stat(inode, data),
+            Duration::from_secs(IMAGEFS_DEFAULT_TIMEOUT),
+        ))
+    }
+
+    fn setattr(
+        &self,
+        _ctx: &Context,
+        _inode: Inode,
+        _attr: stat64,
+        _handle: Option<Handle>,
+        _valid: SetattrValid,
+    ) -> Result<(stat64, Duration)> {
+        Err(erofs())
+    }
+
+    fn readlink(&self, _ctx: &Context, inode: Inode) -> Result<Vec<u8>> {
+        let data = self.get_inode(inode)?;
+        if data.file_type() != libc::S_IFLNK {
+            return Err(Error::from_raw_os_error(libc::EINVAL));
+        }
+        Ok(self.read_range(data.data_off, data.data_len)?.into_owned())
+    }
+
+    fn symlink(
+        &self,
+        _ctx: &Context,
+        _linkname: &CStr,
+        _parent: Inode,
+        _name: &CStr,
+    ) -> Result<Entry> {
+        Err(erofs())
+    }
+
+    fn mknod(
+        &self,
+        _ctx: &Context,
+        _parent: Inode,
+        _name: &CStr,
+        _mode: u32,
+        _rdev: u32,
+        _umask: u32,
+    ) -> Result<Entry> {
+        Err(erofs())
+    }
+
+    fn mkdir(
+        &self,
+        _ctx: &Context,
+        _parent: Inode,
+        _name: &CStr,
+        _mode: u32,
+        _umask: u32,
+    ) -> Result<Entry> {
+        Err(erofs())
+    }
+
+    fn unlink(&self, _ctx: &Context, _parent: Inode, _name: &CStr) -> Result<()> {
+        Err(erofs())
+    }
+
+    fn rmdir(&self, _ctx: &Context, _parent: Inode, _name: &CStr) -> Result<()> {
+        Err(erofs())
+    }
+
+    fn rename(
+        &self,
+        _ctx: &Context,
+        _olddir: Inode,
+        _oldname: &CStr,
+        _newdir: Inode,
+        _newname: &CStr,
+        _flags: u32,
+    ) -> Result<()> {
+        Err(erofs())
+    }
+
+    fn link(
+        &self,
+        _ctx: &Context,
+        _inode: Inode,
+        _newparent: Inode,
+        _newname: &CStr,
+    ) -> Result<Entry> {
+        Err(erofs())
+    }
+
+    fn open(
+        &self,
+        _ctx: &Context,
+        inode: Inode,
+        flags: u32,
+        _fuse_flags: u32,
+    ) -> Result<(Option<Handle>, OpenOptions)> {
+        let flags = flags as i32;
+        if flags & libc::O_ACCMODE != libc::O_RDONLY || flags & libc::O_TRUNC != 0 {
+            return Err(erofs());
+        }
+        if self.get_inode(inode)?.is_dir() {
+            return Err(Error::from_raw_os_error(libc::EISDIR));
+        }
+
+        Ok((None, OpenOptions::KEEP_CACHE))
+    }
+
+    fn create(
+        &self,
+        _ctx: &Context,
+        _parent: Inode,
+        _name: &CStr,
+        _args: CreateIn,
+    ) -> Result<(Entry, Option<Handle>, OpenOptions)> {
+        Err(erofs())
+    }
+
+    fn tmpfile(
+        &self,
+        _ctx: &Context,
+        _parent: Inode,
+        _mode: u32,
+        _umask: u32,
+        _flags: u32,
+    ) -> Result<(Entry, Option<Handle>, OpenOptions)> {
+        Err(erofs())
+    }
+
+    fn read(
+        &self,
+        _ctx: &Context,
+        inode: Inode,
+        _handle: Handle,
+        w: &mut dyn ZeroCopyWriter,
+        size: u32,
+        offset: u64,
+        _lock_owner: Option<u64>,
+        _flags: u32,
+    ) -> Result<usize> {
+        let data = self.get_inode(inode)?;
+        if data.file_type() != libc::S_IFREG {
+            return Err(Error::from_raw_os_error(libc::EINVAL));
+        }
+        if offset >= data.data_len {
+            return Ok(0);
+        }
+
+        let len = std::cmp::min(size as u64, data.data_len - offset);
+        let buf = self.read_range(data.data_off + offset, len)?;
+        w.write_all(&buf)?;
+
+        Ok(buf.len())
+    }
+
+    fn write(
+        &self,
+        _ctx: &Context,
+        _inode: Inode,
+        _handle: Handle,
+        _r: &mut dyn ZeroCopyReader,
+        _size: u32,
+        _offset: u64,
+        _lock_owner: Option<u64>,
+        _delayed_write: bool,
+        _flags: u32,
+        _fuse_flags: u32,
+    ) -> Result<usize> {
+        Err(erofs())
+    }
+
+    fn fallocate(
+        &self,
+        _ctx: &Context,
+        _inode: Inode,
+        _handle: Handle,
+        _mode: u32,
+        _offset: u64,
+        _length: u64,
+    ) -> Result<()> {
+        Err(erofs())
+    }
+
+    fn release(
+        &self,
+        _ctx: &Context,
+        _inode: Inode,
+        _flags: u32,
+        _handle: Handle,
+        _flush: bool,
+        _flock_release: bool,
+        _lock_owner: Option<u64>,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn statfs(&self, _ctx: &Context, _inode: Inode) -> Result<statvfs64> {
+        // Safe because statvfs64 is a plain old data structure.
+        let mut st: statvfs64 = unsafe { mem::zeroed() };
+        st.f_bsize = IMAGEFS_BLOCK_SIZE as _;
+        st.f_frsize = IMAGEFS_BLOCK_SIZE as _;
+        st.f_blocks = blocks(self.size, IMAGEFS_BLOCK_SIZE) as _;
+        st.f_files = self.inodes.len() as _;
+        st.f_namemax = 255;
+        st.f_flag = libc::ST_RDONLY as _;
+        Ok(st)
+    }
+
+    fn setxattr(
+        &self,
+        _ctx: &Context,
+        _inode: Inode,
+        _name: &CStr,
+        _value: &[u8],
+        _flags: u32,
+    ) -> Result<()> {
+        Err(erofs())
+    }
+
+    fn getxattr(
+        &self,
+        _ctx: &Context,
+        inode: Inode,
+        name: &CStr,
+        size: u32,
+    ) -> Result<GetxattrReply> {
+        let data = self.get_inode(inode)?;
+        let buf = self.read_range(data.xattr_off, data.xattr_len)?;
+        for xattr in (XattrIter { buf: &buf[..] }) {
+            let (n, value) = xattr?;
+            if n != name.to_bytes() {
+                continue;
+            }
+            return if size == 0 {
+                Ok(GetxattrReply::Count(value.len() as u32))
+            } else if (size as usize) < value.len() {
+                Err(Error::from_raw_os_error(libc::ERANGE))
+            } else {
+                Ok(GetxattrReply::Value(value.to_vec()))
+            };
+        }
+
+        Err(Error::from_raw_os_error(libc::ENODATA))
+    }
+
+    fn listxattr(&self, _ctx: &Context, inode: Inode, size: u32) -> Result<ListxattrReply> {
+        let data = self.get_inode(inode)?;
+        let buf = self.read_range(data.xattr_off, data.xattr_len)?;
+        let mut names = Vec::new();
+        for xattr in (XattrIter { buf: &buf[..] }) {
+            names.extend_from_slice(xattr?.0);
+            names.push(0);
+        }
+
+        if size == 0 {
+            Ok(ListxattrReply::Count(names.len() as u32))
+        } else if (size as usize) < names.len() {
+            Err(Error::from_raw_os_error(libc::ERANGE))
+        } else {
+            Ok(ListxattrReply::Names(names))
+        }
+    }
+
+    fn removexattr(&self, _ctx: &Context, _inode: Inode, _name: &CStr) -> Result<()> {
+        Err(erofs())
+    }
+
+    fn opendir(
+        &self,
+        _ctx: &Context,
+        inode: Inode,
+        _flags: u32,
+    ) -> Result<(Option<Handle>, OpenOptions)> {
+        self.get_dir(inode)?;
+        Ok((None, OpenOptions::KEEP_CACHE | OpenOptions::CACHE_DIR))
+    }
+
+    fn readdir(
+        &self,
+        _ctx: &Context,
+        inode: Inode,
+        _handle: Handle,
+        size: u32,
+        offset: u64,
+        add_entry: &mut dyn FnMut(DirEntry) -> Result<usize>,
+    ) -> Result<()> {
+        self.do_readdir(inode, size, offset, &mut |dirent, _| add_entry(dirent))
+    }
+
+    fn readdirplus(
+        &self,
+        _ctx: &Context,
+        inode: Inode,
+        _handle: Handle,
+        size: u32,
+        offset: u64,
+        add_entry: &mut dyn FnMut(DirEntry, Entry) -> Result<usize>,
+    ) -> Result<()> {
+        self.do_readdir(inode, size, offset, &mut |dirent, ino| {
+            let entry = self.entry(ino)?;
+            add_entry(dirent, entry)
+        })
+    }
+
+    fn releasedir(
+        &self,
+        _ctx: &Context,
+        _inode: Inode,
+        _flags: u32,
+        _handle: Handle,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn access(&self, _ctx: &Context, inode: Inode, mask: u32) -> Result<()> {
+        self.get_inode(inode)?;
+        if mask & libc::W_OK as u32 != 0 {
+            return Err(erofs());
+        }
+        Ok(())
+    }
+
+    fn clone_range(
+        &self,
+        _ctx: &Context,
+        _src_inode: Inode,
+        _src_handle: Handle,
+        _src_offset: u64,
+        _dst_inode: Inode,
+        _dst_handle: Handle,
+        _dst_offset: u64,
+        _len: u64,
+    ) -> Result<usize> {
+        Err(erofs())
+    }
+}
+
+struct BuilderNode {
+    inode: ImageInode,
+    data: Vec<u8>,
+    children: BTreeMap<Vec<u8>, u64>,
+    xattrs: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+/// Builder of images to be served by [ImageFs].
+///
+/// Nodes are referred to by their inode numbers in the image, and the root directory is
+/// [ROOT_ID]. New nodes are owned by root with a zero timestamp, which may be changed by
+/// [set_owner()](ImageBuilder::set_owner) and [set_mtime()](ImageBuilder::set_mtime).
+pub struct ImageBuilder {
+    nodes: Vec<BuilderNode>,
+}
+
+impl Default for ImageBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ImageBuilder {
+    /// Create a builder with an empty root directory.
+    pub fn new() -> Self {
+        let mut builder = ImageBuilder { nodes: Vec::new() };
+        builder.push_node(ROOT_ID, libc::S_IFDIR | 0o755, Vec::new());
+        builder
+    }
+
+    /// Add a directory with permission bits `mode` into the directory `parent`.
+    pub fn add_dir(&mut self, parent: u64, name: &str, mode: u32) -> Result<u64> {
+        self.add_node(parent, name, libc::S_IFDIR | (mode & 0o7777), Vec::new())
+    }
+
+    /// Add a regular file with permission bits `mode` and content `data` into the directory
+    /// `parent`.
+    pub fn add_file(&mut self, parent: u64, name: &str, mode: u32, data: &[u8]) -> Result<u64> {
+        self.add_node(parent, name, libc::S_IFREG | (mode & 0o7777), data.to_vec())
+    }
+
+    /// Add a symlink pointing to `target` into the directory `parent`.
+    pub fn add_symlink(&mut self, parent: u64, name: &str, target: &str) -> Result<u64> {
+        self.add_node(
+            parent,
+            name,
+            libc::S_IFLNK | 0o777,
+            target.as_bytes().to_vec(),
+        )
+    }
+
+    /// Set the owner of the node `inode`.
+    pub fn set_owner(&mut self, inode: u64, uid: u32, gid: u32) -> Result<()> {
+        let node = self.get_node(inode)?;
+        node.inode.uid = uid;
+        node.inode.gid = gid;
+        Ok(())
+    }
+
+    /// Set the modification time of the node `inode`, which is also reported as access and
+    /// change time.
+    pub fn set_mtime(&mut self, inode: u64, secs: i64, nsecs: u32) -> Result<()> {
+        let node = self.get_node(inode)?;
+        node.inode.mtime = secs;
+        node.inode.mtime_nsec = nsecs;
+        Ok(())
+    }
+
+    /// Set the extended attribute `name` of the node `inode`.
+    pub fn set_xattr(&mut self, inode: u64, name: &str, value: &[u8]) -> Result<()> {
+        if name.is_empty() || name.len() > u16::MAX as usize || value.len() > u32::MAX as usize {
+            return Err(Error::from_raw_os_error(libc::EINVAL));
+        }
+        let node = self.get_node(inode)?;
+        node.xattrs.insert(name.as_bytes().to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    /// Write the image to `w`, and return the size of the image.
+    pub fn write_to<W: Write + ?Sized>(&self, w: &mut W) -> Result<u64> {
+        let mut datas = Vec::with_capacity(self.nodes.len());
+        for node in self.nodes.iter() {
+            let mut data = Vec::new();
+            if node.inode.is_dir() {
+                for (name, ino) in node.children.iter() {
+                    data.extend_from_slice(&ino.to_le_bytes());
+                    data.extend_from_slice(&(name.len() as u16).to_le_bytes());
+                    data.extend_from_slice(name);
+                }
+            }
+            let mut xattrs = Vec::new();
+            for (name, value) in node.xattrs.iter() {
+                xattrs.extend_from_slice(&(name.len() as u16).to_le_bytes());
+                xattrs.extend_from_slice(&(value.len() as u32).to_le_bytes());
+                xattrs.extend_from_slice(name);
+                xattrs.extend_from_slice(value);
+            }
+            datas.push((data, xattrs));
+        }
+
+        let table = IMAGEFS_SUPERBLOCK_SIZE as u64;
+        let mut off = table + (self.nodes.len() * IMAGEFS_INODE_SIZE) as u64;
+        let mut buf = Vec::with_capacity(off as usize);
+        buf.extend_from_slice(IMAGEFS_MAGIC);
+        buf.extend_from_slice(&IMAGEFS_VERSION.to_le_bytes());
+        buf.extend_from_slice(&(self.nodes.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&table.to_le_bytes());
+        buf.extend_from_slice(&0u64.to_le_bytes());
+        for (node, (dir, xattrs)) in self.nodes.iter().zip(datas.iter()) {
+            let data = if node.inode.is_dir() { dir } else { &node.data };
+            let mut inode = node.inode;
+            inode.data_off = off;
+            inode.data_len = data.len() as u64;
+            inode.xattr_off = off + inode.data_len;
+            inode.xattr_len = xattrs.len() as u64;
+            off = inode.xattr_off + inode.xattr_len;
+            inode.encode(&mut buf);
+        }
+        w.write_all(&buf)?;
+
+        for (node, (dir, xattrs)) in self.nodes.iter().zip(datas.iter()) {
+            let data = if node.inode.is_dir() { dir } else { &node.data };
+            w.write_all(data)?;
+            w.write_all(xattrs)?;
+        }
+
+        Ok(off)
+    }
+
+    /// Build the image in memory.
+    pub fn build(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        // Writing to a `Vec` never fails.
+        self.write_to(&mut buf).unwrap();
+        buf
+    }
+
+    fn get_node(&mut self, inode: u64) -> Result<&mut BuilderNode> {
+        inode
+            .checked_sub(1)
+            .and_then(move |idx| self.nodes.get_mut(idx as usize))
+            .ok_or_else(|| Error::from_raw_os_error(libc::ENOENT))
+    }
+
+    fn push_node(&mut self, parent: u64, mode: u32, data: Vec<u8>) -> u64 {
+        let is_dir = mode & libc::S_IFMT == libc::S_IFDIR;
+        self.nodes.push(BuilderNode {
+            inode: ImageInode {
+                mode,
+                nlink: if is_dir { 2 } else { 1 },
+                parent,
+                ..Default::default()
+            },
+            data,
+            children: BTreeMap::new(),
+            xattrs: BTreeMap::new(),
+        });
+        self.nodes.len() as u64
+    }
+
+    fn add_node(&mut self, parent: u64, name: &str, mode: u32, data: Vec<u8>) -> Result<u64> {
+        if name.is_empty()
+            || name == "."
+            || name == ".."
+            || name.contains('/')
+            || name.contains('\0')
+            || name.len() > u16::MAX as usize
+        {
+            return Err(Error::from_raw_os_error(libc::EINVAL));
+        }
+        let dir = self.get_node(parent)?;
+        if !dir.inode.is_dir() {
+            return Err(Error::from_raw_os_error(libc::ENOTDIR));
+        }
+        if dir.children.contains_key(name.as_bytes()) {
+            return Err(Error::from_raw_os_error(libc::EEXIST));
+        }
+
+        let inode = self.push_node(parent, mode, data);
+        let dir = self.get_node(parent)?;
+        dir.children.insert(name.as_bytes().to_vec(), inode);
+        if mode & libc::S_IFMT == libc::S_IFDIR {
+            dir.inode.nlink += 1;
+        }
+
+        Ok(inode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::FileReadWriteVolatile;
+    use std::ffi::CString;
+    use vmm_sys_util::tempfile::TempFile;
+
+    struct VecWriter(Vec<u8>);
+
+    impl Write for VecWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl ZeroCopyWriter for VecWriter {
+        fn write_from(
+            &mut self,
+            _f: &mut dyn FileReadWriteVolatile,
+            _count: usize,
+            _off: u64,
+        ) -> io::Result<usize> {
+            Err(Error::from_raw_os_error(libc::ENOSYS))
+        }
+    }
+
+    fn build_image() -> Vec<u8> {
+        let mut builder = ImageBuilder::new();
+        let etc = builder.add_dir(ROOT_ID, "etc", 0o755).unwrap();
+        let hostname = builder
+            .add_file(etc, "hostname", 0o644, b"image\n")
+            .unwrap();
+        builder.set_owner(hostname, 1000, 100).unwrap();
+        builder.set_mtime(hostname, 1_600_000_000, 7).unwrap();
+        builder.set_xattr(hostname, "user.a", b"1").unwrap();
+        builder.set_xattr(hostname, "user.bb", b"22").unwrap();
+        builder
+            .add_file(ROOT_ID, "big", 0o600, &vec![0x5a; 10000])
+            .unwrap();
+        builder
+            .add_symlink(ROOT_ID, "link", "etc/hostname")
+            .unwrap();
+        assert_eq!(
+            builder
+                .add_file(etc, "hostname", 0o644, b"")
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::EEXIST)
+        );
+        assert_eq!(
+            builder
+                .add_file(hostname, "x", 0o644, b"")
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::ENOTDIR)
+        );
+        builder.build()
+    }
+
+    fn check_image(fs: &ImageFs) {
+        let ctx = Context::default();
+        let lookup = |parent, name: &str| fs.lookup(&ctx, parent, &CString::new(name).unwrap());
+
+        let etc = lookup(ROOT_ID, "etc").unwrap();
+        assert_eq!(etc.attr.st_mode, libc::S_IFDIR | 0o755);
+        assert_eq!(lookup(etc.inode, "..").unwrap().inode, ROOT_ID);
+        assert_eq!(
+            lookup(ROOT_ID, "none").err().unwrap().raw_os_error(),
+            Some(libc::ENOENT)
+        );
+        let hostname = lookup(etc.inode, "hostname").unwrap();
+        let (st, _) = fs.getattr(&ctx, hostname.inode, None).unwrap();
+        assert_eq!(st.st_mode, libc::S_IFREG | 0o644);
+        assert_eq!(st.st_size, 6);
+        assert_eq!((st.st_uid, st.st_gid), (1000, 100));
+        assert_eq!((st.st_mtime, st.st_mtime_nsec), (1_600_000_000, 7));
+        assert_eq!(fs.getattr(&ctx, ROOT_ID, None).unwrap().0.st_nlink, 3);
+
+        let read = |inode, size, offset| {
+            let mut w = VecWriter(Vec::new());
+            fs.open(&ctx, inode, libc::O_RDONLY as u32, 0).unwrap();
+            fs.read(&ctx, inode, 0, &mut w, size, offset, None, 0)
+                .unwrap();
+            w.0
+        };
+        assert_eq!(read(hostname.inode, 4096, 0), b"image\n");
+        assert_eq!(read(hostname.inode, 3, 2), b"age");
+        assert!(read(hostname.inode, 4096, 6).is_empty());
+        let big = lookup(ROOT_ID, "big").unwrap();
+        assert_eq!(read(big.inode, 8192, 4096), vec![0x5a; 5904]);
+
+        let link = lookup(ROOT_ID, "link").unwrap();
+        assert_eq!(fs.readlink(&ctx, link.inode).unwrap(), b"etc/hostname");
+        assert!(fs.readlink(&ctx, hostname.inode).is_err());
+
+        let mut names = Vec::new();
+        fs.readdir(&ctx, ROOT_ID, 0, 4096, 0, &mut |e| {
+            names.push((e.name.to_vec(), e.offset, e.type_));
+            Ok(1)
+        })
+        .unwrap();
+        assert_eq!(
+            names,
+            vec![
+                (b".".to_vec(), 1, libc::DT_DIR as u32),
+                (b"..".to_vec(), 2, libc::DT_DIR as u32),
+                (b"big".to_vec(), 3, libc::DT_REG as u32),
+                (b"etc".to_vec(), 4, libc::DT_DIR as u32),
+                (b"link".to_vec(), 5, libc::DT_LNK as u32),
+            ]
+        );
+        let mut names = Vec::new();
+        fs.readdirplus(&ctx, ROOT_ID, 0, 4096, 3, &mut |e, entry| {
+            assert_eq!(e.ino, entry.inode);
+            names.push(e.name.to_vec());
+            Ok(1)
+        })
+        .unwrap();
+        assert_eq!(names, vec![b"etc".to_vec(), b"link".to_vec()]);
+
+        let name = CString::new("user.bb").unwrap();
+        match fs.getxattr(&ctx, hostname.inode, &name, 0).unwrap() {
+            GetxattrReply::Count(n) => assert_eq!(n, 2),
+            _ => panic!("unexpected getxattr reply"),
+        }
+        match fs.getxattr(&ctx, hostname.inode, &name, 2).unwrap() {
+            GetxattrReply::Value(v) => assert_eq!(v, b"22"),
+            _ => panic!("unexpected getxattr reply"),
+        }
+        assert!(fs.getxattr(&ctx, hostname.inode, &name, 1).is_err());
+        match fs.listxattr(&ctx, hostname.inode, 4096).unwrap() {
+            ListxattrReply::Names(v) => assert_eq!(v, b"user.a\0user.bb\0"),
+            _ => panic!("unexpected listxattr reply"),
+        }
+
+        // Mutating requests are rejected.
+        let e = fs
+            .open(&ctx, hostname.inode, libc::O_RDWR as u32, 0)
+            .unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EROFS));
+        let e = fs
+            .unlink(&ctx, etc.inode, &CString::new("hostname").unwrap())
+            .unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EROFS));
+        let e = fs
+            .mkdir(&ctx, ROOT_ID, &CString::new("new").unwrap(), 0o755, 0)
+            .err()
+            .unwrap();
+        assert_eq!(e.raw_os_error(), Some(libc::EROFS));
+        let e = fs
+            .access(&ctx, hostname.inode, libc::W_OK as u32)
+            .unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EROFS));
+    }
+
+    #[test]
+    fn test_image_round_trip() {
+        let image = build_image();
+        check_image(&ImageFs::from_bytes(image.clone()).unwrap());
+
+        let file = TempFile::new().unwrap();
+        file.as_file().write_all(&image).unwrap();
+        check_image(&ImageFs::open(file.as_path()).unwrap());
+
+        // Damaged images are rejected.
+        let mut bad = image.clone();
+        bad[0] = b'X';
+        assert!(ImageFs::from_bytes(bad).is_err());
+        let mut bad = image.clone();
+        bad.truncate(image.len() - 1);
+        assert!(ImageFs::from_bytes(bad).is_err());
+        assert!(ImageFs::from_bytes(Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,197 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Inode numbers of file systems stacking other file systems, such as UnionFs and OverlayFs.
+//!
+//! Each inode is backed by inodes of the stacked file systems, and is keyed by its first backing
+//! inode, so looking up the same node again gets the same inode. Inode numbers are recycled once
+//! the inodes are no longer referenced, so the map doesn't grow with the number of nodes ever
+//! looked up.
+
+use std::collections::HashMap;
+
+use crate::api::filesystem::ROOT_ID;
+
+type Inode = u64;
+
+pub(crate) struct LayerInode<T> {
+    // Backing inodes as `(layer index, inode)` pairs in the order of precedence.
+    pub(crate) layers: Vec<(usize, Inode)>,
+    // Lookup count of the kernel, plus references taken by the stacking file system itself.
+    pub(crate) refcount: u64,
+    pub(crate) generation: u64,
+    // Data of the inode specific to the stacking file system.
+    pub(crate) extra: T,
+}
+
+pub(crate) struct LayerInodes<T> {
+    inodes: HashMap<Inode, LayerInode<T>>,
+    // Map the first backing inode to the inode.
+    keys: HashMap<(usize, Inode), Inode>,
+    // Inode numbers no longer referenced, which may be handed out again.
+    free: Vec<Inode>,
+    next_inode: Inode,
+    // Bumped whenever an inode number is recycled, so `(inode, generation)` stays unique.
+    generation: u64,
+}
+
+impl<T> LayerInodes<T> {
+    /// Create the inode map with the root inode backed by `layers`, which is never recycled.
+    pub(crate) fn new(layers: Vec<(usize, Inode)>, extra: T) -> Self {
+        let mut inodes = HashMap::new();
+        inodes.insert(
+            ROOT_ID,
+            LayerInode {
+                layers,
+                refcount: 1,
+                generation: 0,
+                extra,
+            },
+        );
+
+        LayerInodes {
+            inodes,
+            keys: HashMap::new(),
+            free: Vec::new(),
+            next_inode: ROOT_ID + 1,
+            generation: 0,
+        }
+    }
+
+    pub(crate) fn get(&self, ino: Inode) -> Option<&LayerInode<T>> {
+        self.inodes.get(&ino)
+    }
+
+    pub(crate) fn get_mut(&mut self, ino: Inode) -> Option<&mut LayerInode<T>> {
+        self.inodes.get_mut(&ino)
+    }
+
+    /// Get the number of inodes, including the root inode.
+    pub(crate) fn len(&self) -> usize {
+        self.inodes.len()
+    }
+
+    /// Take a reference to the inode whose first backing inode is `key`, if there's one.
+    pub(crate) fn get_ref(&mut self, key: (usize, Inode)) -> Option<(Inode, u64)> {
+        let ino = self.keys.get(&key).copied()?;
+        let data = self.inodes.get_mut(&ino).unwrap();
+        data.refcount += 1;
+        Some((ino, data.generation))
+    }
+
+    /// Allocate an inode backed by `layers` holding one reference, which must not be empty.
+    pub(crate) fn alloc(&mut self, layers: Vec<(usize, Inode)>, extra: T) -> (Inode, u64) {
+        let ino = match self.free.pop() {
+            Some(ino) => {
+                self.generation += 1;
+                ino
+            }
+            None => {
+                let ino = self.next_inode;
+                self.next_inode += 1;
+                ino
+            }
+        };
+
+        self.keys.insert(layers[0], ino);
+        self.inodes.insert(
+            ino,
+            LayerInode {
+                layers,
+                refcount: 1,
+                generation: self.generation,
+                extra,
+            },
+        );
+
+        (ino, self.generation)
+    }
+
+    /// Drop `count` references to the inode. Once it's not referenced any more, the inode is
+    /// removed and returned, and its inode number may be recycled. The root inode is never
+    /// removed.
+    pub(crate) fn unref(&mut self, ino: Inode, count: u64) -> Option<LayerInode<T>> {
+        let data = self.inodes.get_mut(&ino)?;
+        data.refcount = data.refcount.saturating_sub(count);
+        if data.refcount > 0 || ino == ROOT_ID {
+            return None;
+        }
+
+        let data = self.inodes.remove(&ino).unwrap();
+        if self.keys.get(&data.layers[0]) == Some(&ino) {
+            self.keys.remove(&data.layers[0]);
+        }
+        self.free.push(ino);
+        Some(data)
+    }
+
+    /// Add `layer` as the first backing inode of the inode, which becomes the key of the inode.
+    pub(crate) fn push_front(&mut self, ino: Inode, layer: (usize, Inode)) -> bool {
+        let data = match self.inodes.get_mut(&ino) {
+            Some(data) => data,
+            None => return false,
+        };
+        let old = data.layers[0];
+        data.layers.insert(0, layer);
+
+        if self.keys.get(&old) == Some(&ino) {
+            self.keys.remove(&old);
+        }
+        self.keys.insert(layer, ino);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layer_inodes_recycle() {
+        let mut inodes = LayerInodes::new(vec![(0, ROOT_ID)], ());
+
+        // An inode still referenced is never recycled.
+        let (a, _) = inodes.alloc(vec![(0, 10)], ());
+        assert_eq!(inodes.get_ref((0, 10)), Some((a, 0)));
+        assert!(inodes.unref(a, 1).is_none());
+        let (b, _) = inodes.alloc(vec![(0, 11)], ());
+        assert_ne!(a, b);
+        assert!(inodes.unref(a, 1).is_some());
+        assert!(inodes.get_ref((0, 10)).is_none());
+        assert!(inodes.unref(b, 1).is_some());
+
+        let mut generation = 0;
+        for i in 0..1000 {
+            let (ino, gen) = inodes.alloc(vec![(i % 2, 100 + i as u64)], ());
+            assert!(ino == a || ino == b);
+            assert!(gen > generation);
+            generation = gen;
+            assert_eq!(
+                inodes.unref(ino, 1).unwrap().layers,
+                vec![(i % 2, 100 + i as u64)]
+            );
+        }
+
+        // The root inode is never removed.
+        assert!(inodes.unref(ROOT_ID, 1).is_none());
+        assert_eq!(inodes.len(), 1);
+        assert!(inodes.keys.is_empty());
+        assert_eq!(inodes.free.len(), 2);
+        assert_eq!(inodes.next_inode, ROOT_ID + 3);
+    }
+
+    #[test]
+    fn test_layer_inodes_push_front() {
+        let mut inodes = LayerInodes::new(vec![(0, ROOT_ID)], ());
+        let (ino, _) = inodes.alloc(vec![(1, 10)], ());
+
+        assert!(inodes.push_front(ino, (0, 20)));
+        assert_eq!(inodes.get(ino).unwrap().layers, vec![(0, 20), (1, 10)]);
+        assert!(inodes.get_ref((1, 10)).is_none());
+        assert_eq!(inodes.get_ref((0, 20)), Some((ino, 0)));
+        assert!(!inodes.push_front(ino + 1, (0, 30)));
+
+        assert!(inodes.unref(ino, 2).is_some());
+        assert!(inodes.keys.is_empty());
+    }
+}
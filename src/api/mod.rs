@@ -14,9 +14,11 @@
 
 pub use super::abi::fuse_abi::CreateIn;
 
+mod layer_inodes;
 mod pseudo_fs;
 
 pub mod vfs;
+pub(crate) use vfs::is_dot_or_dotdot;
 pub use vfs::{
     validate_path_component, BackFileSystem, BackendFileSystem, Vfs, VfsIndex, VfsOptions,
     CURRENT_DIR_CSTR, EMPTY_CSTR, PARENT_DIR_CSTR, PROC_SELF_FD_CSTR, SLASH_ASCII, VFS_MAX_INO,
@@ -24,3 +26,27 @@ pub use vfs::{
 
 pub mod filesystem;
 pub mod server;
+
+pub mod dir_cache;
+pub use dir_cache::DirEntryCache;
+
+pub mod reply_buf;
+pub use reply_buf::ReplyBuf;
+
+pub mod union_fs;
+pub use union_fs::{UnionFs, UnionLayer};
+
+pub mod overlay_fs;
+pub use overlay_fs::{OverlayFs, OverlayLayer};
+
+pub mod quota_fs;
+pub use quota_fs::{QuotaFs, QuotaLimits, QuotaUsage};
+
+pub mod image_fs;
+pub use image_fs::{ImageBuilder, ImageFs};
+
+pub mod write_coalescer;
+pub use write_coalescer::{CoalesceLimits, WriteCoalescer};
+
+#[cfg(feature = "control-socket")]
+pub mod control;
@@ -0,0 +1,1560 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! An overlay of a writable file system on top of read-only file systems.
+//!
+//! The OverlayFs follows the semantics of the Linux overlayfs:
+//! - A name is resolved by the upper file system first, then by the lower file systems in order.
+//!   Directories of the same name are merged, and a non-directory entry hides all later entries.
+//! - A whiteout, which is a character device with device number 0/0, hides the entries of the
+//!   same name in later file systems. An opaque directory, with the `user.overlay.opaque`
+//!   extended attribute being "y", hides the directories of the same name in later file systems.
+//! - The lower file systems are never modified. A node is copied up to the upper file system,
+//!   along with its parent directories, before being modified.
+//! - Renaming a directory which isn't only in the upper file system fails with `EXDEV`, and
+//!   userspace falls back to copying, as the kernel overlayfs does without `redirect_dir`.
+//!
+//! The upper file system may be backed by a tmpfs for ephemeral writes, e.g. by containers.
+//! Inode numbers of the overlay are recycled once the kernel has forgotten them, so the inode map
+//! of a long running overlay doesn't grow with the number of files created and removed.
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::{CStr, CString};
+use std::io::{self, Error, Result};
+use std::mem;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::abi::fuse_abi::{stat64, statvfs64, CreateIn, FsOptions, OpenOptions, SetattrValid};
+use crate::api::filesystem::*;
+use crate::api::layer_inodes::LayerInodes;
+use crate::api::write_coalescer::BufferReader;
+use crate::transport::{FileReadWriteVolatile, FileVolatileSlice};
+
+type Inode = u64;
+type Handle = u64;
+
+/// A file system stacked by [OverlayFs](struct.OverlayFs.html).
+pub type OverlayLayer = Arc<dyn FileSystem<Inode = u64, Handle = u64> + Send + Sync>;
+
+// Index of the upper file system in the layers of the overlay.
+const UPPER: usize = 0;
+
+// Size of the buffer used to list directories of layers.
+const OVERLAYFS_READDIR_SIZE: u32 = 64 * 1024;
+
+// Size of each read and write request to copy up a regular file.
+const OVERLAYFS_COPY_SIZE: u32 = 128 * 1024;
+
+// Extended attribute marking a directory of the upper file system as opaque.
+const OPAQUE_XATTR: &[u8] = b"user.overlay.opaque\0";
+
+// Where an overlay inode has been looked up, to copy up the inode. The inode holds a reference to
+// the parent, which is released along with the inode.
+struct OverlayLink {
+    parent: Inode,
+    name: CString,
+}
+
+struct OverlayDirEntry {
+    ino: u64,
+    type_: u32,
+    name: Vec<u8>,
+}
+
+enum OverlayHandle {
+    File {
+        layer: usize,
+        inode: Inode,
+        handle: Option<Handle>,
+    },
+    // Directories are listed at `opendir()` time, so the merged result is stable for the handle.
+    Dir(Vec<OverlayDirEntry>),
+}
+
+// Collects data read from a layer to copy up a regular file.
+struct CopyBuffer {
+    data: Vec<u8>,
+}
+
+impl io::Write for CopyBuffer {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl ZeroCopyWriter for CopyBuffer {
+    fn write_from(
+        &mut self,
+        f: &mut dyn FileReadWriteVolatile,
+        count: usize,
+        off: u64,
+    ) -> Result<usize> {
+        let start = self.data.len();
+        self.data.resize(start + count, 0);
+        // Safe because the buffer outlives the slice.
+        let slice = unsafe { FileVolatileSlice::new(self.data[start..].as_mut_ptr(), count) };
+        let res = f.read_at_volatile(slice, off);
+        self.data.truncate(start + *res.as_ref().unwrap_or(&0));
+        res
+    }
+}
+
+fn is_dir(st: &stat64) -> bool {
+    st.st_mode & libc::S_IFMT == libc::S_IFDIR
+}
+
+fn is_whiteout(st: &stat64) -> bool {
+    st.st_mode & libc::S_IFMT == libc::S_IFCHR && st.st_rdev == 0
+}
+
+fn enoent() -> Error {
+    Error::from_raw_os_error(libc::ENOENT)
+}
+
+/// An overlay of a writable upper file system on top of read-only lower file systems.
+///
+/// All file systems in the overlay should have been initialized with their root inode being
+/// `ROOT_ID`. The lower file systems are only read, and may be shared by several overlays.
+pub struct OverlayFs {
+    // The upper file system, followed by the lower file systems in the order of precedence.
+    layers: Vec<OverlayLayer>,
+    // Backing inodes of overlay inodes in the order of precedence, the upper one first once it
+    // exists. The first one serves all requests except for listing directories, which merges all
+    // of them.
+    inodes: Mutex<LayerInodes<OverlayLink>>,
+    handles: Mutex<HashMap<Handle, Arc<OverlayHandle>>>,
+    next_handle: AtomicU64,
+    // Serialize copy-ups, so a node is copied up only once.
+    copy_up_lock: Mutex<()>,
+}
+
+impl OverlayFs {
+    /// Create an OverlayFs from the upper file system and lower file systems in the order of
+    /// precedence.
+    pub fn new(upper: OverlayLayer, lowers: Vec<OverlayLayer>) -> Self {
+        let mut layers = vec![upper];
+        layers.extend(lowers);
+
+        let root = (0..layers.len()).map(|idx| (idx, ROOT_ID)).collect();
+        let inodes = LayerInodes::new(
+            root,
+            OverlayLink {
+                parent: ROOT_ID,
+                name: CString::default(),
+            },
+        );
+
+        OverlayFs {
+            layers,
+            inodes: Mutex::new(inodes),
+            handles: Mutex::new(HashMap::new()),
+            next_handle: AtomicU64::new(1),
+            copy_up_lock: Mutex::new(()),
+        }
+    }
+
+    /// Get the number of inodes referenced by the kernel, including the root inode.
+    pub fn inode_count(&self) -> usize {
+        self.inodes.lock().unwrap().len()
+    }
+
+    fn upper(&self) -> &OverlayLayer {
+        &self.layers[UPPER]
+    }
+
+    fn backing(&self, inode: Inode) -> Result<Vec<(usize, Inode)>> {
+        self.inodes
+            .lock()
+            .unwrap()
+            .get(inode)
+            .map(|i| i.layers.clone())
+            .ok_or_else(enoent)
+    }
+
+    fn primary(&self, inode: Inode) -> Result<(&OverlayLayer, Inode)> {
+        let (idx, ino) = self.primary_idx(inode)?;
+        Ok((&self.layers[idx], ino))
+    }
+
+    fn primary_idx(&self, inode: Inode) -> Result<(usize, Inode)> {
+        self.inodes
+            .lock()
+            .unwrap()
+            .get(inode)
+            .and_then(|i| i.layers.first().copied())
+            .ok_or_else(enoent)
+    }
+
+    fn get_handle(&self, handle: Handle) -> Result<Arc<OverlayHandle>> {
+        self.handles
+            .lock()
+            .unwrap()
+            .get(&handle)
+            .cloned()
+            .ok_or_else(|| Error::from_raw_os_error(libc::EBADF))
+    }
+
+    // Get the backing handle to pass to the layer, for a handle of an overlay inode.
+    fn layer_handle(&self, handle: Handle) -> Result<(usize, Inode, Handle)> {
+        match self.get_handle(handle)?.as_ref() {
+            OverlayHandle::File {
+                layer,
+                inode,
+                handle,
+            } => Ok((*layer, *inode, handle.unwrap_or(0))),
+            OverlayHandle::Dir(_) => Err(Error::from_raw_os_error(libc::EISDIR)),
+        }
+    }
+
+    // Get the backing handle of the upper file system to modify the file by. Handles of lower
+    // file systems have been opened read-only.
+    fn upper_handle(&self, handle: Handle) -> Result<(Inode, Handle)> {
+        match self.layer_handle(handle)? {
+            (UPPER, inode, handle) => Ok((inode, handle)),
+            _ => Err(Error::from_raw_os_error(libc::EBADF)),
+        }
+    }
+
+    fn insert_handle(&self, data: OverlayHandle) -> Handle {
+        let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
+        self.handles.lock().unwrap().insert(handle, Arc::new(data));
+        handle
+    }
+
+    fn forget_layers(&self, ctx: &Context, layers: &[(usize, Inode)]) {
+        for (idx, ino) in layers {
+            self.layers[*idx].forget(ctx, *ino, 1);
+        }
+    }
+
+    fn forget_entries(&self, ctx: &Context, entries: &[(usize, Entry)]) {
+        for (idx, entry) in entries {
+            self.layers[*idx].forget(ctx, entry.inode, 1);
+        }
+    }
+
+    fn is_opaque(&self, ctx: &Context, idx: usize, inode: Inode) -> bool {
+        let name = CStr::from_bytes_with_nul(OPAQUE_XATTR).unwrap();
+        matches!(
+            self.layers[idx].getxattr(ctx, inode, name, 2),
+            Ok(GetxattrReply::Value(v)) if v == b"y"
+        )
+    }
+
+    // Look up `name` in the backing directories of `parent`. Return the backing entries of the
+    // merged result in the order of precedence, each of them holds a lookup count of its layer.
+    fn lookup_layers(
+        &self,
+        ctx: &Context,
+        parent: Inode,
+        name: &CStr,
+    ) -> Result<Vec<(usize, Entry)>> {
+        let mut found: Vec<(usize, Entry)> = Vec::new();
+
+        for (idx, ino) in self.backing(parent)? {
+            let layer = &self.layers[idx];
+            let entry = match layer.lookup(ctx, ino, name) {
+                // Negative entry.
+                Ok(e) if e.inode == 0 => continue,
+                Ok(e) => e,
+                Err(e) if e.raw_os_error() == Some(libc::ENOENT) => continue,
+                Err(e) => {
+                    self.forget_entries(ctx, &found);
+                    return Err(e);
+                }
+            };
+
+            if is_whiteout(&entry.attr) || (!found.is_empty() && !is_dir(&entry.attr)) {
+                // Hides the entries of later layers.
+                layer.forget(ctx, entry.inode, 1);
+                break;
+            }
+            let merge = is_dir(&entry.attr) && !self.is_opaque(ctx, idx, entry.inode);
+            found.push((idx, entry));
+            if !merge {
+                break;
+            }
+        }
+
+        Ok(found)
+    }
+
+    // Check whether `name` in `parent` is visible from the lower file systems, so it must be
+    // hidden by a whiteout once removed from the upper file system.
+    fn in_lower(&self, ctx: &Context, parent: Inode, name: &CStr) -> Result<bool> {
+        for (idx, ino) in self.backing(parent)? {
+            if idx == UPPER {
+                continue;
+            }
+            match self.layers[idx].lookup(ctx, ino, name) {
+                Ok(e) if e.inode == 0 => continue,
+                Ok(e) => {
+                    self.layers[idx].forget(ctx, e.inode, 1);
+                    return Ok(!is_whiteout(&e.attr));
+                }
+                Err(e) if e.raw_os_error() == Some(libc::ENOENT) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(false)
+    }
+
+    // Get the overlay inode of backing inodes just looked up as `name` in `parent`, taking a
+    // lookup count of the overlay inode.
+    fn insert(
+        &self,
+        ctx: &Context,
+        parent: Inode,
+        name: &CStr,
+        layers: Vec<(usize, Inode)>,
+    ) -> (Inode, u64) {
+        let mut inodes = self.inodes.lock().unwrap();
+
+        if let Some(found) = inodes.get_ref(layers[0]) {
+            // The overlay inode already holds a reference to each of its backing inodes.
+            drop(inodes);
+            self.forget_layers(ctx, &layers);
+            return found;
+        }
+
+        // The parent must stay around to copy up the inode.
+        if let Some(data) = inodes.get_mut(parent) {
+            data.refcount += 1;
+        }
+        let link = OverlayLink {
+            parent,
+            name: name.to_owned(),
+        };
+        inodes.alloc(layers, link)
+    }
+
+    // Drop `count` references to `inode`, and release the inode once it's not referenced any
+    // more, which in turn drops its reference to the parent.
+    fn unref(&self, ctx: &Context, mut inode: Inode, mut count: u64) {
+        // Release the lock before forgetting the backing inodes.
+        while let Some(data) = self.inodes.lock().unwrap().unref(inode, count) {
+            self.forget_layers(ctx, &data.layers);
+            inode = data.extra.parent;
+            count = 1;
+        }
+    }
+
+    // Copy up `inode` to the upper file system along with its parent directories, and return
+    // the backing inode in the upper file system.
+    fn copy_up(&self, ctx: &Context, inode: Inode) -> Result<Inode> {
+        let _guard = self.copy_up_lock.lock().unwrap();
+
+        // Find the closest ancestor in the upper file system, the root directory always is.
+        let mut pending = Vec::new();
+        let mut cur = inode;
+        let mut upper_parent = loop {
+            let inodes = self.inodes.lock().unwrap();
+            let data = inodes.get(cur).ok_or_else(enoent)?;
+            let (idx, ino) = data.layers[0];
+            if idx == UPPER {
+                break ino;
+            }
+            pending.push(cur);
+            cur = data.extra.parent;
+        };
+
+        for ino in pending.into_iter().rev() {
+            upper_parent = self.copy_up_one(ctx, ino, upper_parent)?;
+        }
+
+        Ok(upper_parent)
+    }
+
+    // Copy up `inode` into the directory `upper_parent` of the upper file system.
+    fn copy_up_one(&self, ctx: &Context, inode: Inode, upper_parent: Inode) -> Result<Inode> {
+        let ((idx, ino), name) = {
+            let inodes = self.inodes.lock().unwrap();
+            let data = inodes.get(inode).ok_or_else(enoent)?;
+            (data.layers[0], data.extra.name.clone())
+        };
+        let layer = &self.layers[idx];
+        let upper = self.upper();
+        let (st, _) = layer.getattr(ctx, ino, None)?;
+
+        let entry = match st.st_mode & libc::S_IFMT {
+            libc::S_IFDIR => upper.mkdir(ctx, upper_parent, &name, st.st_mode & 0o7777, 0)?,
+            libc::S_IFLNK => {
+                let target = CString::new(layer.readlink(ctx, ino)?)
+                    .map_err(|_| Error::from_raw_os_error(libc::EINVAL))?;
+                upper.symlink(ctx, &target, upper_parent, &name)?
+            }
+            libc::S_IFREG => self.copy_up_file(ctx, layer, ino, upper_parent, &name, &st)?,
+            _ => upper.mknod(ctx, upper_parent, &name, st.st_mode, st.st_rdev as u32, 0)?,
+        };
+
+        // Symlinks have no mode, and their timestamps are left alone.
+        if st.st_mode & libc::S_IFMT != libc::S_IFLNK {
+            let valid = SetattrValid::MODE
+                | SetattrValid::UID
+                | SetattrValid::GID
+                | SetattrValid::ATIME
+                | SetattrValid::MTIME;
+            if let Err(e) = upper.setattr(ctx, entry.inode, st, None, valid) {
+                self.remove_upper(ctx, upper_parent, &name, &entry);
+                return Err(e);
+            }
+        }
+
+        if !self
+            .inodes
+            .lock()
+            .unwrap()
+            .push_front(inode, (UPPER, entry.inode))
+        {
+            return Err(enoent());
+        }
+
+        Ok(entry.inode)
+    }
+
+    fn copy_up_file(
+        &self,
+        ctx: &Context,
+        layer: &OverlayLayer,
+        inode: Inode,
+        upper_parent: Inode,
+        name: &CStr,
+        st: &stat64,
+    ) -> Result<Entry> {
+        let upper = self.upper();
+        let args = CreateIn {
+            flags: (libc::O_WRONLY | libc::O_EXCL) as u32,
+            mode: st.st_mode & 0o7777,
+            umask: 0,
+            fuse_flags: 0,
+        };
+        let (entry, upper_handle, _) = upper.create(ctx, upper_parent, name, args)?;
+        let upper_handle = upper_handle.unwrap_or(0);
+
+        let res = layer
+            .open(ctx, inode, libc::O_RDONLY as u32, 0)
+            .and_then(|(handle, _)| {
+                let handle = handle.unwrap_or(0);
+                let res = self.copy_data(ctx, layer, inode, handle, entry.inode, upper_handle);
+                let _ = layer.release(ctx, inode, 0, handle, false, false, None);
+                res
+            });
+        let _ = upper.release(ctx, entry.inode, 0, upper_handle, true, false, None);
+        if let Err(e) = res {
+            self.remove_upper(ctx, upper_parent, name, &entry);
+            return Err(e);
+        }
+
+        Ok(entry)
+    }
+
+    fn copy_data(
+        &self,
+        ctx: &Context,
+        layer: &OverlayLayer,
+        inode: Inode,
+        handle: Handle,
+        upper_inode: Inode,
+        upper_handle: Handle,
+    ) -> Result<()> {
+        let mut buf = CopyBuffer { data: Vec::new() };
+        let mut offset = 0;
+
+        loop {
+            buf.data.clear();
+            let n = layer.read(
+                ctx,
+                inode,
+                handle,
+                &mut buf,
+                OVERLAYFS_COPY_SIZE,
+                offset,
+                None,
+                0,
+            )?;
+            if n == 0 {
+                return Ok(());
+            }
+
+            let mut r = BufferReader { data: &buf.data };
+            while !r.data.is_empty() {
+                let done = buf.data.len() - r.data.len();
+                let size = r.data.len() as u32;
+                let n = self.upper().write(
+                    ctx,
+                    upper_inode,
+                    upper_handle,
+                    &mut r,
+                    size,
+                    offset + done as u64,
+                    None,
+                    false,
+                    0,
+                    0,
+                )?;
+                if n == 0 {
+                    return Err(Error::from(io::ErrorKind::WriteZero));
+                }
+            }
+            offset += buf.data.len() as u64;
+        }
+    }
+
+    // Remove a node just created in the upper file system, when it can't be completed.
+    fn remove_upper(&self, ctx: &Context, upper_parent: Inode, name: &CStr, entry: &Entry) {
+        let upper = self.upper();
+        let _ = if is_dir(&entry.attr) {
+            upper.rmdir(ctx, upper_parent, name)
+        } else {
+            upper.unlink(ctx, upper_parent, name)
+        };
+        upper.forget(ctx, entry.inode, 1);
+    }
+
+    fn create_whiteout(&self, ctx: &Context, upper_parent: Inode, name: &CStr) -> Result<()> {
+        let entry = self
+            .upper()
+            .mknod(ctx, upper_parent, name, libc::S_IFCHR, 0, 0)?;
+        self.upper().forget(ctx, entry.inode, 1);
+        Ok(())
+    }
+
+    // Remove the whiteout of `name` in `upper_parent` if there's one, so a new node could be
+    // created by the name. Return whether a whiteout has been removed, in which case the lower
+    // file systems have an entry of the name.
+    fn remove_whiteout(&self, ctx: &Context, upper_parent: Inode, name: &CStr) -> Result<bool> {
+        let upper = self.upper();
+        let entry = match upper.lookup(ctx, upper_parent, name) {
+            Ok(e) if e.inode == 0 => return Ok(false),
+            Ok(e) => e,
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        upper.forget(ctx, entry.inode, 1);
+        if !is_whiteout(&entry.attr) {
+            return Err(Error::from_raw_os_error(libc::EEXIST));
+        }
+
+        upper.unlink(ctx, upper_parent, name)?;
+        Ok(true)
+    }
+
+    // Prepare to create `name` in `parent`, which must not exist yet. Return the directory in the
+    // upper file system to create the node in, and whether the node must be an opaque directory.
+    fn prepare_create(&self, ctx: &Context, parent: Inode, name: &CStr) -> Result<(Inode, bool)> {
+        let found = self.lookup_layers(ctx, parent, name)?;
+        if !found.is_empty() {
+            self.forget_entries(ctx, &found);
+            return Err(Error::from_raw_os_error(libc::EEXIST));
+        }
+
+        let upper_parent = self.copy_up(ctx, parent)?;
+        let opaque = self.remove_whiteout(ctx, upper_parent, name)?;
+
+        Ok((upper_parent, opaque))
+    }
+
+    // Add the node just created in the upper file system to the overlay.
+    fn insert_created(&self, ctx: &Context, parent: Inode, name: &CStr, mut entry: Entry) -> Entry {
+        let (ino, generation) = self.insert(ctx, parent, name, vec![(UPPER, entry.inode)]);
+        entry.inode = ino;
+        entry.generation = generation;
+        entry
+    }
+
+    fn list_layer(
+        &self,
+        ctx: &Context,
+        idx: usize,
+        inode: Inode,
+        entries: &mut Vec<OverlayDirEntry>,
+        seen: &mut HashSet<Vec<u8>>,
+    ) -> Result<()> {
+        let layer = &self.layers[idx];
+        let (handle, _) = layer.opendir(ctx, inode, libc::O_RDONLY as u32)?;
+        let handle = handle.unwrap_or(0);
+        let mut listed = Vec::new();
+        let mut offset = 0;
+
+        let res = loop {
+            let mut added = 0;
+            let res = layer.readdir(
+                ctx,
+                inode,
+                handle,
+                OVERLAYFS_READDIR_SIZE,
+                offset,
+                &mut |dirent| {
+                    listed.push(OverlayDirEntry {
+                        ino: dirent.ino,
+                        type_: dirent.type_,
+                        name: dirent.name.to_vec(),
+                    });
+                    offset = dirent.offset;
+                    added += 1;
+                    Ok(mem::size_of::<DirEntry>() + dirent.name.len())
+                },
+            );
+            if res.is_err() || added == 0 {
+                break res;
+            }
+        };
+        let _ = layer.releasedir(ctx, inode, libc::O_RDONLY as u32, handle);
+        res?;
+
+        for e in listed {
+            if seen.contains(&e.name) {
+                continue;
+            }
+            seen.insert(e.name.clone());
+            // A whiteout hides the entries of later layers, and isn't listed itself.
+            if e.type_ == libc::DT_CHR as u32 {
+                let name = CString::new(e.name.clone())
+                    .map_err(|_| Error::from_raw_os_error(libc::EINVAL))?;
+                if let Ok(entry) = layer.lookup(ctx, inode, &name) {
+                    layer.forget(ctx, entry.inode, 1);
+                    if is_whiteout(&entry.attr) {
+                        continue;
+                    }
+                }
+            }
+            entries.push(e);
+        }
+
+        Ok(())
+    }
+
+    // List the merged directory backed by `layers`.
+    fn list_dir(&self, ctx: &Context, layers: &[(usize, Inode)]) -> Result<Vec<OverlayDirEntry>> {
+        let mut entries = Vec::new();
+        let mut seen = HashSet::new();
+
+        for (idx, ino) in layers {
+            self.list_layer(ctx, *idx, *ino, &mut entries, &mut seen)?;
+        }
+
+        Ok(entries)
+    }
+
+    // Check that the merged directory backed by `layers` has no entry but "." and "..".
+    fn check_empty(&self, ctx: &Context, layers: &[(usize, Inode)]) -> Result<()> {
+        let entries = self.list_dir(ctx, layers)?;
+        if entries.iter().any(|e| e.name != b"." && e.name != b"..") {
+            return Err(Error::from_raw_os_error(libc::ENOTEMPTY));
+        }
+        Ok(())
+    }
+
+    // Remove the whiteouts left in a directory of the upper file system, so it could be removed.
+    fn clear_whiteouts(&self, ctx: &Context, upper_dir: Inode) -> Result<()> {
+        let upper = self.upper();
+        let mut entries = Vec::new();
+        let mut seen = HashSet::new();
+        self.list_layer(ctx, UPPER, upper_dir, &mut entries, &mut seen)?;
+
+        for name in seen {
+            if entries.iter().any(|e| e.name == name) {
+                continue;
+            }
+            let name = CString::new(name).map_err(|_| Error::from_raw_os_error(libc::EINVAL))?;
+            upper.unlink(ctx, upper_dir, &name)?;
+        }
+
+        Ok(())
+    }
+
+    fn remove(&self, ctx: &Context, parent: Inode, name: &CStr, dir: bool) -> Result<()> {
+        let found = self.lookup_layers(ctx, parent, name)?;
+        if found.is_empty() {
+            return Err(enoent());
+        }
+        let layers: Vec<(usize, Inode)> = found.iter().map(|(idx, e)| (*idx, e.inode)).collect();
+        let res = match (dir, is_dir(&found[0].1.attr)) {
+            (true, false) => Err(Error::from_raw_os_error(libc::ENOTDIR)),
+            (false, true) => Err(Error::from_raw_os_error(libc::EISDIR)),
+            (true, true) => self.check_empty(ctx, &layers).and_then(|_| {
+                if layers[0].0 == UPPER {
+                    self.clear_whiteouts(ctx, layers[0].1)
+                } else {
+                    Ok(())
+                }
+            }),
+            (false, false) => Ok(()),
+        };
+        self.forget_entries(ctx, &found);
+        res?;
+
+        let upper_parent = self.copy_up(ctx, parent)?;
+        let whiteout = self.in_lower(ctx, parent, name)?;
+        if layers[0].0 == UPPER {
+            if dir {
+                self.upper().rmdir(ctx, upper_parent, name)?;
+            } else {
+                self.upper().unlink(ctx, upper_parent, name)?;
+            }
+        }
+        if whiteout {
+            self.create_whiteout(ctx, upper_parent, name)?;
+        }
+
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn do_rename(
+        &self,
+        ctx: &Context,
+        src: Inode,
+        olddir: Inode,
+        oldname: &CStr,
+        newdir: Inode,
+        newname: &CStr,
+        flags: u32,
+    ) -> Result<()> {
+        let src_layers = self.backing(src)?;
+        let (_, src_ino) = self.primary(src)?;
+        let (st, _) = self.primary(src)?.0.getattr(ctx, src_ino, None)?;
+        let src_dir = is_dir(&st);
+        // Directories with entries in the lower file systems can't be moved without redirects.
+        if src_dir && (src_layers.len() > 1 || src_layers[0].0 != UPPER) {
+            return Err(Error::from_raw_os_error(libc::EXDEV));
+        }
+
+        let found = self.lookup_layers(ctx, newdir, newname)?;
+        let target: Vec<(usize, Inode)> = found.iter().map(|(idx, e)| (*idx, e.inode)).collect();
+        let res = match found.first() {
+            None => Ok(()),
+            Some(_) if flags & libc::RENAME_NOREPLACE != 0 => {
+                Err(Error::from_raw_os_error(libc::EEXIST))
+            }
+            Some((_, e)) if is_dir(&e.attr) && !src_dir => {
+                Err(Error::from_raw_os_error(libc::EISDIR))
+            }
+            Some((_, e)) if !is_dir(&e.attr) && src_dir => {
+                Err(Error::from_raw_os_error(libc::ENOTDIR))
+            }
+            Some((_, e)) if is_dir(&e.attr) => {
+                if target.len() > 1 || target[0].0 != UPPER {
+                    Err(Error::from_raw_os_error(libc::EXDEV))
+                } else {
+                    self.check_empty(ctx, &target)
+                        .and_then(|_| self.clear_whiteouts(ctx, target[0].1))
+                }
+            }
+            Some(_) => Ok(()),
+        };
+        self.forget_entries(ctx, &found);
+        res?;
+
+        let upper_src = self.copy_up(ctx, src)?;
+        let upper_old = self.copy_up(ctx, olddir)?;
+        let upper_new = self.copy_up(ctx, newdir)?;
+        let whiteout = self.in_lower(ctx, olddir, oldname)?;
+        // A directory moved over a whiteout must hide the directory of the lower file systems.
+        let opaque = src_dir && self.in_lower(ctx, newdir, newname)?;
+
+        self.upper()
+            .rename(ctx, upper_old, oldname, upper_new, newname, flags)?;
+        if opaque {
+            self.set_opaque(ctx, upper_src)?;
+        }
+        if whiteout {
+            self.create_whiteout(ctx, upper_old, oldname)?;
+        }
+
+        let moved = {
+            let mut inodes = self.inodes.lock().unwrap();
+            match inodes.get_mut(src).map(|data| &mut data.extra) {
+                Some(link) if link.parent != newdir => {
+                    let old = mem::replace(&mut link.parent, newdir);
+                    link.name = newname.to_owned();
+                    if let Some(data) = inodes.get_mut(newdir) {
+                        data.refcount += 1;
+                    }
+                    Some(old)
+                }
+                Some(link) => {
+                    link.name = newname.to_owned();
+                    None
+                }
+                None => None,
+            }
+        };
+        if let Some(old) = moved {
+            self.unref(ctx, old, 1);
+        }
+
+        Ok(())
+    }
+
+    fn set_opaque(&self, ctx: &Context, upper_dir: Inode) -> Result<()> {
+        let name = CStr::from_bytes_with_nul(OPAQUE_XATTR).unwrap();
+        self.upper().setxattr(ctx, upper_dir, name, b"y", 0)
+    }
+}
+
+impl FileSystem for OverlayFs {
+    type Inode = Inode;
+    type Handle = Handle;
+
+    fn init(&self, capable: FsOptions) -> Result<FsOptions> {
+        // Handles are needed to find the backing file, and a file is copied up on open.
+        let unsupported = FsOptions::DO_READDIRPLUS
+            | FsOptions::READDIRPLUS_AUTO
+            | FsOptions::WRITEBACK_CACHE
+            | FsOptions::ATOMIC_O_TRUNC
+            | FsOptions::ZERO_MESSAGE_OPEN
+            | FsOptions::ZERO_MESSAGE_OPENDIR;
+        let capable = capable - unsupported;
+        let mut opts = capable;
+
+        for layer in self.layers.iter() {
+            opts &= layer.init(capable)?;
+        }
+
+        Ok(opts)
+    }
+
+    fn init_ext(&self, capable: FsOptionsExt) -> Result<FsOptionsExt> {
+        let mut opts = capable;
+
+        for layer in self.layers.iter() {
+            opts &= layer.init_ext(capable)?;
+        }
+
+        Ok(opts)
+    }
+
+    fn destroy(&self) {
+        for layer in self.layers.iter() {
+            layer.destroy();
+        }
+    }
+
+    fn wants_raw_header(&self) -> bool {
+        self.layers.iter().any(|layer| layer.wants_raw_header())
+    }
+
+    fn max_stack_depth(&self) -> u32 {
+        self.layers
+            .iter()
+            .map(|layer| layer.max_stack_depth())
+            .max()
+            .unwrap_or(1)
+    }
+
+    fn supported_ops(&self) -> SupportedOps {
+        // Extended attributes are read from all layers, others are only done by the upper one.
+        let ops = self.upper().supported_ops()
+            & (SupportedOps::XATTR | SupportedOps::FALLOCATE | SupportedOps::TMPFILE);
+        self.layers[1..].iter().fold(ops, |ops, layer| {
+            ops & (layer.supported_ops() | !SupportedOps::XATTR)
+        })
+    }
+
+    fn lookup(&self, ctx: &Context, parent: Inode, name: &CStr) -> Result<Entry> {
+        let mut found = self.lookup_layers(ctx, parent, name)?;
+        if found.is_empty() {
+            return Err(enoent());
+        }
+
+        let layers: Vec<(usize, Inode)> = found.iter().map(|(idx, e)| (*idx, e.inode)).collect();
+        let (_, mut entry) = found.swap_remove(0);
+        let (ino, generation) = self.insert(ctx, parent, name, layers);
+        entry.inode = ino;
+        entry.generation = generation;
+
+        Ok(entry)
+    }
+
+    fn forget(&self, ctx: &Context, inode: Inode, count: u64) {
+        self.unref(ctx, inode, count);
+    }
+
+    fn batch_forget(&self, ctx: &Context, requests: Vec<(Inode, u64)>) {
+        for (inode, count) in requests {
+            self.forget(ctx, inode, count);
+        }
+    }
+
+    fn getattr(
+        &self,
+        ctx: &Context,
+        inode: Inode,
+        handle: Option<Handle>,
+    ) -> Result<(stat64, Duration)> {
+        if let Some(h) = handle {
+            if let Ok((idx, ino, h)) = self.layer_handle(h) {
+                return self.layers[idx].getattr(ctx, ino, Some(h));
+            }
+        }
+
+        let (layer, ino) = self.primary(inode)?;
+        layer.getattr(ctx, ino, None)
+    }
+
+    fn setattr(
+        &self,
+        ctx: &Context,
+        inode: Inode,
+        attr: stat64,
+        handle: Option<Handle>,
+        valid: SetattrValid,
+    ) -> Result<(stat64, Duration)> {
+        let upper = self.copy_up(ctx, inode)?;
+        let handle = handle
+            .and_then(|h| self.upper_handle(h).ok())
+            .map(|(_, h)| h);
+
+        self.upper().setattr(ctx, upper, attr, handle, valid)
+    }
+
+    fn readlink(&self, ctx: &Context, inode: Inode) -> Result<Vec<u8>> {
+        let (layer, ino) = self.primary(inode)?;
+        layer.readlink(ctx, ino)
+    }
+
+    fn symlink(&self, ctx: &Context, linkname: &CStr, parent: Inode, name: &CStr) -> Result<Entry> {
+        let (upper_parent, _) = self.prepare_create(ctx, parent, name)?;
+        let entry = self.upper().symlink(ctx, linkname, upper_parent, name)?;
+        Ok(self.insert_created(ctx, parent, name, entry))
+    }
+
+    fn mknod(
+        &self,
+        ctx: &Context,
+        parent: Inode,
+        name: &CStr,
+        mode: u32,
+        rdev: u32,
+        umask: u32,
+    ) -> Result<Entry> {
+        let (upper_parent, _) = self.prepare_create(ctx, parent, name)?;
+        let entry = self
+            .upper()
+            .mknod(ctx, upper_parent, name, mode, rdev, umask)?;
+        Ok(self.insert_created(ctx, parent, name, entry))
+    }
+
+    fn mkdir(
+        &self,
+        ctx: &Context,
+        parent: Inode,
+        name: &CStr,
+        mode: u32,
+        umask: u32,
+    ) -> Result<Entry> {
+        let (upper_parent, opaque) = self.prepare_create(ctx, parent, name)?;
+        let entry = self.upper().mkdir(ctx, upper_parent, name, mode, umask)?;
+        // The new directory replaces a removed one, whose lower entries must stay hidden.
+        if opaque {
+            if let Err(e) = self.set_opaque(ctx, entry.inode) {
+                self.remove_upper(ctx, upper_parent, name, &entry);
+                return Err(e);
+            }
+        }
+        Ok(self.insert_created(ctx, parent, name, entry))
+    }
+
+    fn init_security_context(
+        &self,
+        ctx: &Context,
+        parent: Inode,
+        name: &CStr,
+        inode: Inode,
+        secctx: &SecContext,
+    ) -> Result<()> {
+        // The node has just been created in the upper file system.
+        let upper_parent = self.copy_up(ctx, parent)?;
+        let upper = self.copy_up(ctx, inode)?;
+        self.upper()
+            .init_security_context(ctx, upper_parent, name, upper, secctx)
+    }
+
+    fn unlink(&self, ctx: &Context, parent: Inode, name: &CStr) -> Result<()> {
+        self.remove(ctx, parent, name, false)
+    }
+
+    fn rmdir(&self, ctx: &Context, parent: Inode, name: &CStr) -> Result<()> {
+        self.remove(ctx, parent, name, true)
+    }
+
+    fn rename(
+        &self,
+        ctx: &Context,
+        olddir: Inode,
+        oldname: &CStr,
+        newdir: Inode,
+        newname: &CStr,
+        flags: u32,
+    ) -> Result<()> {
+        if flags & !libc::RENAME_NOREPLACE != 0 {
+            return Err(Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        // Hold a reference to the source while moving it.
+        let src = self.lookup(ctx, olddir, oldname)?.inode;
+        let res = self.do_rename(ctx, src, olddir, oldname, newdir, newname, flags);
+        self.unref(ctx, src, 1);
+
+        res
+    }
+
+    fn link(&self, ctx: &Context, inode: Inode, newparent: Inode, newname: &CStr) -> Result<Entry> {
+        let upper = self.copy_up(ctx, inode)?;
+        let (upper_parent, _) = self.prepare_create(ctx, newparent, newname)?;
+        let entry = self.upper().link(ctx, upper, upper_parent, newname)?;
+        Ok(self.insert_created(ctx, newparent, newname, entry))
+    }
+
+    fn open(
+        &self,
+        ctx: &Context,
+        inode: Inode,
+        flags: u32,
+        fuse_flags: u32,
+    ) -> Result<(Option<Handle>, OpenOptions)> {
+        let flags_i = flags as i32;
+        if flags_i & libc::O_ACCMODE != libc::O_RDONLY || flags_i & libc::O_TRUNC != 0 {
+            self.copy_up(ctx, inode)?;
+        }
+
+        let (idx, ino) = self.primary_idx(inode)?;
+        let (handle, opts) = self.layers[idx].open(ctx, ino, flags, fuse_flags)?;
+        let handle = self.insert_handle(OverlayHandle::File {
+            layer: idx,
+            inode: ino,
+            handle,
+        });
+
+        Ok((Some(handle), opts))
+    }
+
+    fn create(
+        &self,
+        ctx: &Context,
+        parent: Inode,
+        name: &CStr,
+        args: CreateIn,
+    ) -> Result<(Entry, Option<Handle>, OpenOptions)> {
+        let (upper_parent, _) = self.prepare_create(ctx, parent, name)?;
+        let (entry, handle, opts) = self.upper().create(ctx, upper_parent, name, args)?;
+        let handle = self.insert_handle(OverlayHandle::File {
+            layer: UPPER,
+            inode: entry.inode,
+            handle,
+        });
+
+        Ok((
+            self.insert_created(ctx, parent, name, entry),
+            Some(handle),
+            opts,
+        ))
+    }
+
+    fn tmpfile(
+        &self,
+        ctx: &Context,
+        parent: Inode,
+        mode: u32,
+        umask: u32,
+        flags: u32,
+    ) -> Result<(Entry, Option<Handle>, OpenOptions)> {
+        let upper_parent = self.copy_up(ctx, parent)?;
+        let (entry, handle, opts) = self
+            .upper()
+            .tmpfile(ctx, upper_parent, mode, umask, flags)?;
+        let handle = self.insert_handle(OverlayHandle::File {
+            layer: UPPER,
+            inode: entry.inode,
+            handle,
+        });
+
+        Ok((
+            self.insert_created(ctx, parent, &CString::default(), entry),
+            Some(handle),
+            opts,
+        ))
+    }
+
+    fn read(
+        &self,
+        ctx: &Context,
+        _inode: Inode,
+        handle: Handle,
+        w: &mut dyn ZeroCopyWriter,
+        size: u32,
+        offset: u64,
+        lock_owner: Option<u64>,
+        flags: u32,
+    ) -> Result<usize> {
+        let (idx, ino, handle) = self.layer_handle(handle)?;
+        self.layers[idx].read(ctx, ino, handle, w, size, offset, lock_owner, flags)
+    }
+
+    fn write(
+        &self,
+        ctx: &Context,
+        _inode: Inode,
+        handle: Handle,
+        r: &mut dyn ZeroCopyReader,
+        size: u32,
+        offset: u64,
+        lock_owner: Option<u64>,
+        delayed_write: bool,
+        flags: u32,
+        fuse_flags: u32,
+    ) -> Result<usize> {
+        let (ino, handle) = self.upper_handle(handle)?;
+        self.upper().write(
+            ctx,
+            ino,
+            handle,
+            r,
+            size,
+            offset,
+            lock_owner,
+            delayed_write,
+            flags,
+            fuse_flags,
+        )
+    }
+
+    fn fallocate(
+        &self,
+        ctx: &Context,
+        _inode: Inode,
+        handle: Handle,
+        mode: u32,
+        offset: u64,
+        length: u64,
+    ) -> Result<()> {
+        let (ino, handle) = self.upper_handle(handle)?;
+        self.upper()
+            .fallocate(ctx, ino, handle, mode, offset, length)
+    }
+
+    fn flush(&self, ctx: &Context, _inode: Inode, handle: Handle, lock_owner: u64) -> Result<()> {
+        let (idx, ino, handle) = self.layer_handle(handle)?;
+        self.layers[idx].flush(ctx, ino, handle, lock_owner)
+    }
+
+    fn fsync(&self, ctx: &Context, _inode: Inode, datasync: bool, handle: Handle) -> Result<()> {
+        match self.layer_handle(handle)? {
+            (UPPER, ino, handle) => self.upper().fsync(ctx, ino, datasync, handle),
+            _ => Ok(()),
+        }
+    }
+
+    fn release(
+        &self,
+        ctx: &Context,
+        _inode: Inode,
+        flags: u32,
+        handle: Handle,
+        flush: bool,
+        flock_release: bool,
+        lock_owner: Option<u64>,
+    ) -> Result<()> {
+        let data = self
+            .handles
+            .lock()
+            .unwrap()
+            .remove(&handle)
+            .ok_or_else(|| Error::from_raw_os_error(libc::EBADF))?;
+
+        match data.as_ref() {
+            OverlayHandle::File {
+                layer,
+                inode,
+                handle: Some(h),
+            } => self.layers[*layer].release(
+                ctx,
+                *inode,
+                flags,
+                *h,
+                flush,
+                flock_release,
+                lock_owner,
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    fn statfs(&self, ctx: &Context, _inode: Inode) -> Result<statvfs64> {
+        self.upper().statfs(ctx, ROOT_ID)
+    }
+
+    fn setxattr(
+        &self,
+        ctx: &Context,
+        inode: Inode,
+        name: &CStr,
+        value: &[u8],
+        flags: u32,
+    ) -> Result<()> {
+        let upper = self.copy_up(ctx, inode)?;
+        self.upper().setxattr(ctx, upper, name, value, flags)
+    }
+
+    fn getxattr(
+        &self,
+        ctx: &Context,
+        inode: Inode,
+        name: &CStr,
+        size: u32,
+    ) -> Result<GetxattrReply> {
+        let (layer, ino) = self.primary(inode)?;
+        layer.getxattr(ctx, ino, name, size)
+    }
+
+    fn listxattr(&self, ctx: &Context, inode: Inode, size: u32) -> Result<ListxattrReply> {
+        let (layer, ino) = self.primary(inode)?;
+        layer.listxattr(ctx, ino, size)
+    }
+
+    fn removexattr(&self, ctx: &Context, inode: Inode, name: &CStr) -> Result<()> {
+        let upper = self.copy_up(ctx, inode)?;
+        self.upper().removexattr(ctx, upper, name)
+    }
+
+    fn opendir(
+        &self,
+        ctx: &Context,
+        inode: Inode,
+        _flags: u32,
+    ) -> Result<(Option<Handle>, OpenOptions)> {
+        let entries = self.list_dir(ctx, &self.backing(inode)?)?;
+        let handle = self.insert_handle(OverlayHandle::Dir(entries));
+
+        Ok((Some(handle), OpenOptions::empty()))
+    }
+
+    fn readdir(
+        &self,
+        _ctx: &Context,
+        _inode: Inode,
+        handle: Handle,
+        size: u32,
+        offset: u64,
+        add_entry: &mut dyn FnMut(DirEntry) -> Result<usize>,
+    ) -> Result<()> {
+        if size == 0 {
+            return Ok(());
+        }
+
+        let data = self.get_handle(handle)?;
+        let entries = match data.as_ref() {
+            OverlayHandle::Dir(entries) => entries,
+            OverlayHandle::File { .. } => return Err(Error::from_raw_os_error(libc::ENOTDIR)),
+        };
+
+        for (i, e) in entries.iter().enumerate().skip(offset as usize) {
+            let dirent = DirEntry {
+                ino: e.ino,
+                offset: i as u64 + 1,
+                type_: e.type_,
+                name: &e.name,
+            };
+            if add_entry(dirent)? == 0 {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn releasedir(&self, _ctx: &Context, _inode: Inode, _flags: u32, handle: Handle) -> Result<()> {
+        self.handles
+            .lock()
+            .unwrap()
+            .remove(&handle)
+            .map(|_| ())
+            .ok_or_else(|| Error::from_raw_os_error(libc::EBADF))
+    }
+
+    fn fsyncdir(&self, ctx: &Context, inode: Inode, datasync: bool, _handle: Handle) -> Result<()> {
+        match self.primary_idx(inode)? {
+            (UPPER, ino) => self.upper().fsyncdir(ctx, ino, datasync, 0),
+            _ => Ok(()),
+        }
+    }
+
+    fn access(&self, ctx: &Context, inode: Inode, mask: u32) -> Result<()> {
+        let (layer, ino) = self.primary(inode)?;
+        layer.access(ctx, ino, mask)
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::passthrough::{Config, PassthroughFs};
+    use vmm_sys_util::tempdir::TempDir;
+
+    struct VecWriter(Vec<u8>);
+
+    impl io::Write for VecWriter {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.0.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    impl ZeroCopyWriter for VecWriter {
+        fn write_from(
+            &mut self,
+            f: &mut dyn FileReadWriteVolatile,
+            count: usize,
+            off: u64,
+        ) -> Result<usize> {
+            let mut buf = CopyBuffer { data: Vec::new() };
+            let n = buf.write_from(f, count, off)?;
+            self.0.extend_from_slice(&buf.data);
+            Ok(n)
+        }
+    }
+
+    fn passthrough(dir: &TempDir) -> OverlayLayer {
+        let cfg = Config {
+            root_dir: dir.as_path().to_str().unwrap().to_string(),
+            xattr: true,
+            ..Default::default()
+        };
+        let fs: PassthroughFs = PassthroughFs::new(cfg).unwrap();
+        fs.import().unwrap();
+        Arc::new(fs)
+    }
+
+    fn name(name: &str) -> CString {
+        CString::new(name).unwrap()
+    }
+
+    fn read_file(fs: &OverlayFs, inode: Inode) -> Vec<u8> {
+        let ctx = Context::default();
+        let (handle, _) = fs.open(&ctx, inode, libc::O_RDONLY as u32, 0).unwrap();
+        let mut w = VecWriter(Vec::new());
+        fs.read(&ctx, inode, handle.unwrap(), &mut w, 4096, 0, None, 0)
+            .unwrap();
+        fs.release(&ctx, inode, 0, handle.unwrap(), false, false, None)
+            .unwrap();
+        w.0
+    }
+
+    fn write_file(fs: &OverlayFs, inode: Inode, data: &[u8]) {
+        let ctx = Context::default();
+        let (handle, _) = fs
+            .open(&ctx, inode, (libc::O_WRONLY | libc::O_TRUNC) as u32, 0)
+            .unwrap();
+        let mut r = BufferReader { data };
+        fs.write(
+            &ctx,
+            inode,
+            handle.unwrap(),
+            &mut r,
+            data.len() as u32,
+            0,
+            None,
+            false,
+            0,
+            0,
+        )
+        .unwrap();
+        fs.release(&ctx, inode, 0, handle.unwrap(), true, false, None)
+            .unwrap();
+    }
+
+    fn list(fs: &OverlayFs, inode: Inode) -> Vec<String> {
+        let ctx = Context::default();
+        let (handle, _) = fs.opendir(&ctx, inode, 0).unwrap();
+        let mut names = Vec::new();
+        fs.readdir(&ctx, inode, handle.unwrap(), 4096, 0, &mut |e| {
+            if e.name != b"." && e.name != b".." {
+                names.push(String::from_utf8(e.name.to_vec()).unwrap());
+            }
+            Ok(1)
+        })
+        .unwrap();
+        fs.releasedir(&ctx, inode, 0, handle.unwrap()).unwrap();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_overlay_copy_up() {
+        let upper_dir = TempDir::new().unwrap();
+        let lower_dir = TempDir::new().unwrap();
+        std::fs::create_dir(lower_dir.as_path().join("dir")).unwrap();
+        std::fs::write(lower_dir.as_path().join("dir/a"), b"lower a").unwrap();
+        std::fs::write(lower_dir.as_path().join("b"), b"lower b").unwrap();
+        let fs = OverlayFs::new(passthrough(&upper_dir), vec![passthrough(&lower_dir)]);
+        let ctx = Context::default();
+
+        let dir = fs.lookup(&ctx, ROOT_ID, &name("dir")).unwrap();
+        let a = fs.lookup(&ctx, dir.inode, &name("a")).unwrap();
+        assert_eq!(read_file(&fs, a.inode), b"lower a");
+        assert!(!upper_dir.as_path().join("dir").exists());
+
+        // Writing to the file copies it up along with its parent directory.
+        write_file(&fs, a.inode, b"upper a");
+        assert_eq!(read_file(&fs, a.inode), b"upper a");
+        assert_eq!(
+            std::fs::read(upper_dir.as_path().join("dir/a")).unwrap(),
+            b"upper a"
+        );
+        assert_eq!(
+            std::fs::read(lower_dir.as_path().join("dir/a")).unwrap(),
+            b"lower a"
+        );
+
+        // Files are copied up with their data.
+        let b = fs.lookup(&ctx, ROOT_ID, &name("b")).unwrap();
+        fs.setxattr(&ctx, b.inode, &name("user.test"), b"1", 0)
+            .unwrap();
+        assert_eq!(
+            std::fs::read(upper_dir.as_path().join("b")).unwrap(),
+            b"lower b"
+        );
+
+        // New files are created in the upper directory, merged with the lower entries.
+        let args = CreateIn {
+            flags: libc::O_RDWR as u32,
+            mode: 0o644,
+            umask: 0,
+            fuse_flags: 0,
+        };
+        let (c, handle, _) = fs.create(&ctx, dir.inode, &name("c"), args).unwrap();
+        fs.release(&ctx, c.inode, 0, handle.unwrap(), false, false, None)
+            .unwrap();
+        assert!(upper_dir.as_path().join("dir/c").exists());
+        assert_eq!(list(&fs, dir.inode), vec!["a", "c"]);
+        let e = fs
+            .create(&ctx, ROOT_ID, &name("b"), args)
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EEXIST));
+    }
+
+    #[test]
+    fn test_overlay_whiteout() {
+        let upper_dir = TempDir::new().unwrap();
+        let lower_dir = TempDir::new().unwrap();
+        std::fs::create_dir(lower_dir.as_path().join("dir")).unwrap();
+        std::fs::write(lower_dir.as_path().join("dir/a"), b"a").unwrap();
+        std::fs::write(lower_dir.as_path().join("b"), b"b").unwrap();
+        let fs = OverlayFs::new(passthrough(&upper_dir), vec![passthrough(&lower_dir)]);
+        let ctx = Context::default();
+
+        // Removed lower entries are hidden by whiteouts.
+        fs.unlink(&ctx, ROOT_ID, &name("b")).unwrap();
+        let e = fs
+            .lookup(&ctx, ROOT_ID, &name("b"))
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ENOENT));
+        assert!(lower_dir.as_path().join("b").exists());
+        assert_eq!(list(&fs, ROOT_ID), vec!["dir"]);
+
+        let e = fs.rmdir(&ctx, ROOT_ID, &name("dir")).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ENOTEMPTY));
+        let dir = fs.lookup(&ctx, ROOT_ID, &name("dir")).unwrap();
+        fs.unlink(&ctx, dir.inode, &name("a")).unwrap();
+        assert!(list(&fs, dir.inode).is_empty());
+        fs.rmdir(&ctx, ROOT_ID, &name("dir")).unwrap();
+        fs.forget(&ctx, dir.inode, 1);
+        assert!(list(&fs, ROOT_ID).is_empty());
+
+        // A directory created over a whiteout doesn't expose the lower entries.
+        let dir = fs.mkdir(&ctx, ROOT_ID, &name("dir"), 0o755, 0).unwrap();
+        assert!(list(&fs, dir.inode).is_empty());
+        let e = fs
+            .lookup(&ctx, dir.inode, &name("a"))
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ENOENT));
+        assert_eq!(list(&fs, ROOT_ID), vec!["dir"]);
+    }
+
+    #[test]
+    fn test_overlay_rename() {
+        let upper_dir = TempDir::new().unwrap();
+        let lower_dir = TempDir::new().unwrap();
+        std::fs::create_dir(lower_dir.as_path().join("dir")).unwrap();
+        std::fs::write(lower_dir.as_path().join("a"), b"a").unwrap();
+        let fs = OverlayFs::new(passthrough(&upper_dir), vec![passthrough(&lower_dir)]);
+        let ctx = Context::default();
+
+        let a = fs.lookup(&ctx, ROOT_ID, &name("a")).unwrap();
+        fs.rename(&ctx, ROOT_ID, &name("a"), ROOT_ID, &name("b"), 0)
+            .unwrap();
+        assert_eq!(list(&fs, ROOT_ID), vec!["b", "dir"]);
+        let b = fs.lookup(&ctx, ROOT_ID, &name("b")).unwrap();
+        assert_eq!(a.inode, b.inode);
+        assert_eq!(read_file(&fs, b.inode), b"a");
+        assert!(lower_dir.as_path().join("a").exists());
+
+        // Lower directories can't be moved.
+        let e = fs
+            .rename(&ctx, ROOT_ID, &name("dir"), ROOT_ID, &name("dir2"), 0)
+            .unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EXDEV));
+    }
+
+    #[test]
+    fn test_overlay_inode_recycle() {
+        // The upper file system is on a tmpfs if available.
+        let upper_dir = TempDir::new_in(std::path::Path::new("/dev/shm"))
+            .or_else(|_| TempDir::new())
+            .unwrap();
+        let lower_dir = TempDir::new().unwrap();
+        std::fs::write(lower_dir.as_path().join("a"), b"a").unwrap();
+        let fs = OverlayFs::new(passthrough(&upper_dir), vec![passthrough(&lower_dir)]);
+        let ctx = Context::default();
+        let args = CreateIn {
+            flags: libc::O_RDWR as u32,
+            mode: 0o644,
+            umask: 0,
+            fuse_flags: 0,
+        };
+
+        // An inode still referenced by the kernel is never recycled.
+        let a = fs.lookup(&ctx, ROOT_ID, &name("a")).unwrap();
+        fs.unlink(&ctx, ROOT_ID, &name("a")).unwrap();
+        let (f, handle, _) = fs.create(&ctx, ROOT_ID, &name("f"), args).unwrap();
+        fs.release(&ctx, f.inode, 0, handle.unwrap(), false, false, None)
+            .unwrap();
+        assert_ne!(f.inode, a.inode);
+        fs.unlink(&ctx, ROOT_ID, &name("f")).unwrap();
+        fs.forget(&ctx, f.inode, 1);
+        assert_eq!(read_file(&fs, a.inode), b"a");
+        fs.forget(&ctx, a.inode, 1);
+
+        // Inode numbers of files created and removed are recycled, so the inode map is bounded.
+        let mut generation = 0;
+        for i in 0..5000 {
+            let name = name(&format!("f{}", i % 16));
+            let (e, handle, _) = fs.create(&ctx, ROOT_ID, &name, args).unwrap();
+            fs.release(&ctx, e.inode, 0, handle.unwrap(), false, false, None)
+                .unwrap();
+            assert!(e.inode == a.inode || e.inode == f.inode);
+            assert!(e.generation > generation);
+            generation = e.generation;
+            fs.unlink(&ctx, ROOT_ID, &name).unwrap();
+            fs.forget(&ctx, e.inode, 1);
+        }
+
+        assert_eq!(fs.inode_count(), 1);
+    }
+}
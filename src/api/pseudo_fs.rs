@@ -398,7 +398,7 @@ mod tests {
     use super::*;
     use std::ffi::CString;
 
-    fn create_fuse_context() -> Context {
+    fn create_fuse_context() -> Context<'static> {
         Context::new()
     }
 
@@ -0,0 +1,1091 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A file system wrapper enforcing per-user quotas.
+//!
+//! The [QuotaFs] forwards all requests to the wrapped file system, and keeps track of two
+//! counters for each user:
+//! - `bytes`: the amount of data written by `write`, `fallocate` and `clone_range` requests.
+//!   It is the total amount of data written, not the space currently in use, so it never
+//!   decreases unless the usage is reset by [QuotaFs::restore_usage()].
+//! - `inodes`: the number of inodes created by `create`, `mkdir`, `mknod`, `symlink` and
+//!   `tmpfile` requests, minus the number of inodes removed by `unlink`, `rmdir` and `rename`.
+//!
+//! Requests which would exceed the limits of the user fail with `EDQUOT` before reaching the
+//! wrapped file system, so a write is never partially applied because of the quota.
+//!
+//! Data written through a handle is accounted to the user who opened the handle, because the
+//! kernel sends cached writes with the credentials of its own flusher threads. Removed inodes are
+//! accounted to their owner.
+
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::abi::fuse_abi::{
+    stat64, statvfs64, CreateIn, FsOptions, OpenOptions, SetattrValid, Statx,
+};
+#[cfg(feature = "virtiofs")]
+use crate::abi::virtio_fs::RemovemappingOne;
+use crate::api::filesystem::*;
+#[cfg(feature = "virtiofs")]
+use crate::transport::FsCacheReqHandler;
+
+/// Limits of a user enforced by [QuotaFs]. `None` means unlimited.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QuotaLimits {
+    /// Maximum amount of data written by the user, in bytes.
+    pub bytes: Option<u64>,
+    /// Maximum number of inodes owned by the user.
+    pub inodes: Option<u64>,
+}
+
+/// Usage of a user tracked by [QuotaFs].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QuotaUsage {
+    /// Amount of data written by the user, in bytes.
+    pub bytes: u64,
+    /// Number of inodes owned by the user.
+    pub inodes: u64,
+}
+
+#[derive(Default)]
+struct QuotaState {
+    default_limits: QuotaLimits,
+    limits: HashMap<u32, QuotaLimits>,
+    usage: HashMap<u32, QuotaUsage>,
+    // Users who opened the handles, to account data written through them.
+    handles: HashMap<u64, u32>,
+}
+
+impl QuotaState {
+    fn limits(&self, uid: u32) -> QuotaLimits {
+        self.limits
+            .get(&uid)
+            .copied()
+            .unwrap_or(self.default_limits)
+    }
+}
+
+/// A file system wrapper enforcing per-user limits on written data and inode count.
+pub struct QuotaFs<F> {
+    inner: F,
+    state: Mutex<QuotaState>,
+}
+
+impl<F: FileSystem> QuotaFs<F> {
+    /// Wrap `inner`, applying `limits` to all users without limits of their own.
+    pub fn new(inner: F, limits: QuotaLimits) -> Self {
+        QuotaFs {
+            inner,
+            state: Mutex::new(QuotaState {
+                default_limits: limits,
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Get the wrapped file system.
+    pub fn inner(&self) -> &F {
+        &self.inner
+    }
+
+    /// Set the limits of the user `uid`, replacing the default limits.
+    ///
+    /// Lowering the limits below the current usage doesn't change anything already written, but
+    /// further requests charged to the user fail with `EDQUOT`.
+    pub fn set_limits(&self, uid: u32, limits: QuotaLimits) {
+        self.state.lock().unwrap().limits.insert(uid, limits);
+    }
+
+    /// Get the usage of the user `uid`.
+    pub fn usage(&self, uid: u32) -> QuotaUsage {
+        self.state
+            .lock()
+            .unwrap()
+            .usage
+            .get(&uid)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Get the usage of all users, sorted by uid.
+    pub fn all_usage(&self) -> Vec<(u32, QuotaUsage)> {
+        let mut usage: Vec<_> = self
+            .state
+            .lock()
+            .unwrap()
+            .usage
+            .iter()
+            .map(|(uid, usage)| (*uid, *usage))
+            .collect();
+        usage.sort_unstable_by_key(|(uid, _)| *uid);
+        usage
+    }
+
+    /// Replace the usage of all users, e.g. with the usage saved by a previous instance.
+    pub fn restore_usage(&self, usage: &[(u32, QuotaUsage)]) {
+        self.state.lock().unwrap().usage = usage.iter().copied().collect();
+    }
+
+    /// Save the usage of all users to `w`, one `<uid> <bytes> <inodes>` line per user.
+    pub fn save_usage<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        for (uid, usage) in self.all_usage() {
+            writeln!(w, "{} {} {}", uid, usage.bytes, usage.inodes)?;
+        }
+        Ok(())
+    }
+
+    /// Restore the usage of all users from `r`, in the format written by
+    /// [QuotaFs::save_usage()].
+    pub fn load_usage<R: Read>(&self, r: R) -> io::Result<()> {
+        let mut usage = Vec::new();
+        for line in BufReader::new(r).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let fields = line
+                .split_whitespace()
+                .map(|v| v.parse::<u64>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            match fields[..] {
+                [uid, bytes, inodes] if uid <= u32::MAX as u64 => {
+                    usage.push((uid as u32, QuotaUsage { bytes, inodes }))
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("invalid quota usage line: {}", line),
+                    ))
+                }
+            }
+        }
+        self.restore_usage(&usage);
+        Ok(())
+    }
+
+    // Charge `bytes` and `inodes` to the user, failing with `EDQUOT` if it exceeds any limit.
+    fn charge(&self, uid: u32, bytes: u64, inodes: u64) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let limits = state.limits(uid);
+        let usage = state.usage.entry(uid).or_default();
+        let exceeds = |used: u64, delta: u64, limit: Option<u64>| match limit {
+            Some(limit) => delta > 0 && used.saturating_add(delta) > limit,
+            None => false,
+        };
+        if exceeds(usage.bytes, bytes, limits.bytes) || exceeds(usage.inodes, inodes, limits.inodes)
+        {
+            return Err(io::Error::from_raw_os_error(libc::EDQUOT));
+        }
+        usage.bytes = usage.bytes.saturating_add(bytes);
+        usage.inodes = usage.inodes.saturating_add(inodes);
+        Ok(())
+    }
+
+    fn refund(&self, uid: u32, bytes: u64, inodes: u64) {
+        if let Some(usage) = self.state.lock().unwrap().usage.get_mut(&uid) {
+            usage.bytes = usage.bytes.saturating_sub(bytes);
+            usage.inodes = usage.inodes.saturating_sub(inodes);
+        }
+    }
+
+    // Charge an inode for a creating request, and refund it if the request fails.
+    fn create_inode<T>(&self, ctx: &Context, f: impl FnOnce() -> io::Result<T>) -> io::Result<T> {
+        self.charge(ctx.uid, 0, 1)?;
+        f().map_err(|e| {
+            self.refund(ctx.uid, 0, 1);
+            e
+        })
+    }
+
+    // Charge data written through `handle`, and refund what hasn't been written.
+    fn write_data(
+        &self,
+        ctx: &Context,
+        handle: u64,
+        size: u64,
+        f: impl FnOnce() -> io::Result<u64>,
+    ) -> io::Result<u64> {
+        let uid = self.handle_owner(ctx, handle);
+        self.charge(uid, size, 0)?;
+        let res = f();
+        let written = *res.as_ref().unwrap_or(&0);
+        if written < size {
+            self.refund(uid, size - written, 0);
+        }
+        res
+    }
+
+    fn handle_owner(&self, ctx: &Context, handle: u64) -> u32 {
+        self.state
+            .lock()
+            .unwrap()
+            .handles
+            .get(&handle)
+            .copied()
+            .unwrap_or(ctx.uid)
+    }
+
+    fn open_handle(&self, ctx: &Context, handle: Option<F::Handle>) -> Option<F::Handle> {
+        handle.map(|h| {
+            let h: u64 = h.into();
+            self.state.lock().unwrap().handles.insert(h, ctx.uid);
+            F::Handle::from(h)
+        })
+    }
+
+    // Get the owner of the inode `name` in `parent` if removing the name frees the inode.
+    fn removed_owner(&self, ctx: &Context, parent: u64, name: &CStr) -> Option<u32> {
+        let entry = self.inner.lookup(ctx, parent.into(), name).ok()?;
+        self.inner.forget(ctx, entry.inode.into(), 1);
+        let is_dir = entry.attr.st_mode & libc::S_IFMT == libc::S_IFDIR;
+        if is_dir || entry.attr.st_nlink <= 1 {
+            Some(entry.attr.st_uid)
+        } else {
+            None
+        }
+    }
+
+    fn remove_inode(
+        &self,
+        ctx: &Context,
+        parent: u64,
+        name: &CStr,
+        f: impl FnOnce() -> io::Result<()>,
+    ) -> io::Result<()> {
+        let owner = self.removed_owner(ctx, parent, name);
+        f()?;
+        if let Some(uid) = owner {
+            self.refund(uid, 0, 1);
+        }
+        Ok(())
+    }
+}
+
+impl<F: FileSystem> FileSystem for QuotaFs<F> {
+    type Inode = F::Inode;
+    type Handle = F::Handle;
+
+    fn init(&self, capable: FsOptions) -> io::Result<FsOptions> {
+        self.inner.init(capable)
+    }
+
+    fn init_ext(&self, capable: FsOptionsExt) -> io::Result<FsOptionsExt> {
+        self.inner.init_ext(capable)
+    }
+
+    fn destroy(&self) {
+        self.inner.destroy()
+    }
+
+    fn wants_raw_header(&self) -> bool {
+        self.inner.wants_raw_header()
+    }
+
+    fn max_stack_depth(&self) -> u32 {
+        self.inner.max_stack_depth()
+    }
+
+    fn supported_ops(&self) -> SupportedOps {
+        self.inner.supported_ops()
+    }
+
+    fn lookup(&self, ctx: &Context, parent: Self::Inode, name: &CStr) -> io::Result<Entry> {
+        self.inner.lookup(ctx, parent, name)
+    }
+
+    fn forget(&self, ctx: &Context, inode: Self::Inode, count: u64) {
+        self.inner.forget(ctx, inode, count)
+    }
+
+    fn batch_forget(&self, ctx: &Context, requests: Vec<(Self::Inode, u64)>) {
+        self.inner.batch_forget(ctx, requests)
+    }
+
+    fn getattr(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Option<Self::Handle>,
+    ) -> io::Result<(stat64, Duration)> {
+        self.inner.getattr(ctx, inode, handle)
+    }
+
+    fn getattr_batch(
+        &self,
+        ctx: &Context,
+        inodes: Vec<Self::Inode>,
+    ) -> Vec<io::Result<(stat64, Duration)>> {
+        self.inner.getattr_batch(ctx, inodes)
+    }
+
+    fn statx(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Option<Self::Handle>,
+        flags: u32,
+        mask: u32,
+    ) -> io::Result<(Statx, Duration)> {
+        self.inner.statx(ctx, inode, handle, flags, mask)
+    }
+
+    fn setattr(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        attr: stat64,
+        handle: Option<Self::Handle>,
+        valid: SetattrValid,
+    ) -> io::Result<(stat64, Duration)> {
+        self.inner.setattr(ctx, inode, attr, handle, valid)
+    }
+
+    fn readlink(&self, ctx: &Context, inode: Self::Inode) -> io::Result<Vec<u8>> {
+        self.inner.readlink(ctx, inode)
+    }
+
+    fn canonical_path(&self, ctx: &Context, inode: Self::Inode) -> io::Result<CString> {
+        self.inner.canonical_path(ctx, inode)
+    }
+
+    fn symlink(
+        &self,
+        ctx: &Context,
+        linkname: &CStr,
+        parent: Self::Inode,
+        name: &CStr,
+    ) -> io::Result<Entry> {
+        self.create_inode(ctx, || self.inner.symlink(ctx, linkname, parent, name))
+    }
+
+    fn mknod(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        name: &CStr,
+        mode: u32,
+        rdev: u32,
+        umask: u32,
+    ) -> io::Result<Entry> {
+        self.create_inode(ctx, || {
+            self.inner.mknod(ctx, inode, name, mode, rdev, umask)
+        })
+    }
+
+    fn mkdir(
+        &self,
+        ctx: &Context,
+        parent: Self::Inode,
+        name: &CStr,
+        mode: u32,
+        umask: u32,
+    ) -> io::Result<Entry> {
+        self.create_inode(ctx, || self.inner.mkdir(ctx, parent, name, mode, umask))
+    }
+
+    fn init_security_context(
+        &self,
+        ctx: &Context,
+        parent: Self::Inode,
+        name: &CStr,
+        inode: Self::Inode,
+        secctx: &SecContext,
+    ) -> io::Result<()> {
+        self.inner
+            .init_security_context(ctx, parent, name, inode, secctx)
+    }
+
+    fn unlink(&self, ctx: &Context, parent: Self::Inode, name: &CStr) -> io::Result<()> {
+        let parent = parent.into();
+        self.remove_inode(ctx, parent, name, || {
+            self.inner.unlink(ctx, parent.into(), name)
+        })
+    }
+
+    fn rmdir(&self, ctx: &Context, parent: Self::Inode, name: &CStr) -> io::Result<()> {
+        let parent = parent.into();
+        self.remove_inode(ctx, parent, name, || {
+            self.inner.rmdir(ctx, parent.into(), name)
+        })
+    }
+
+    fn rename(
+        &self,
+        ctx: &Context,
+        olddir: Self::Inode,
+        oldname: &CStr,
+        newdir: Self::Inode,
+        newname: &CStr,
+        flags: u32,
+    ) -> io::Result<()> {
+        if flags & libc::RENAME_EXCHANGE != 0 {
+            return self
+                .inner
+                .rename(ctx, olddir, oldname, newdir, newname, flags);
+        }
+        // The inode replaced by the rename, if any, is freed.
+        let newdir = newdir.into();
+        self.remove_inode(ctx, newdir, newname, || {
+            self.inner
+                .rename(ctx, olddir, oldname, newdir.into(), newname, flags)
+        })
+    }
+
+    fn link(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        newparent: Self::Inode,
+        newname: &CStr,
+    ) -> io::Result<Entry> {
+        self.inner.link(ctx, inode, newparent, newname)
+    }
+
+    fn open(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        flags: u32,
+        fuse_flags: u32,
+    ) -> io::Result<(Option<Self::Handle>, OpenOptions)> {
+        let (handle, opts) = self.inner.open(ctx, inode, flags, fuse_flags)?;
+        Ok((self.open_handle(ctx, handle), opts))
+    }
+
+    fn create(
+        &self,
+        ctx: &Context,
+        parent: Self::Inode,
+        name: &CStr,
+        args: CreateIn,
+    ) -> io::Result<(Entry, Option<Self::Handle>, OpenOptions)> {
+        let (entry, handle, opts) =
+            self.create_inode(ctx, || self.inner.create(ctx, parent, name, args))?;
+        Ok((entry, self.open_handle(ctx, handle), opts))
+    }
+
+    fn tmpfile(
+        &self,
+        ctx: &Context,
+        parent: Self::Inode,
+        mode: u32,
+        umask: u32,
+        flags: u32,
+    ) -> io::Result<(Entry, Option<Self::Handle>, OpenOptions)> {
+        let (entry, handle, opts) =
+            self.create_inode(ctx, || self.inner.tmpfile(ctx, parent, mode, umask, flags))?;
+        Ok((entry, self.open_handle(ctx, handle), opts))
+    }
+
+    fn read(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        w: &mut dyn ZeroCopyWriter,
+        size: u32,
+        offset: u64,
+        lock_owner: Option<u64>,
+        flags: u32,
+    ) -> io::Result<usize> {
+        self.inner
+            .read(ctx, inode, handle, w, size, offset, lock_owner, flags)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn write(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        r: &mut dyn ZeroCopyReader,
+        size: u32,
+        offset: u64,
+        lock_owner: Option<u64>,
+        delayed_write: bool,
+        flags: u32,
+        fuse_flags: u32,
+    ) -> io::Result<usize> {
+        let handle = handle.into();
+        self.write_data(ctx, handle, size as u64, || {
+            self.inner
+                .write(
+                    ctx,
+                    inode,
+                    handle.into(),
+                    r,
+                    size,
+                    offset,
+                    lock_owner,
+                    delayed_write,
+                    flags,
+                    fuse_flags,
+                )
+                .map(|n| n as u64)
+        })
+        .map(|n| n as usize)
+    }
+
+    fn flush(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        lock_owner: u64,
+    ) -> io::Result<()> {
+        self.inner.flush(ctx, inode, handle, lock_owner)
+    }
+
+    fn fsync(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        datasync: bool,
+        handle: Self::Handle,
+    ) -> io::Result<()> {
+        self.inner.fsync(ctx, inode, datasync, handle)
+    }
+
+    fn fallocate(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        mode: u32,
+        offset: u64,
+        length: u64,
+    ) -> io::Result<()> {
+        // Deallocating modes don't write any data.
+        if mode & (libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_COLLAPSE_RANGE) as u32 != 0 {
+            return self
+                .inner
+                .fallocate(ctx, inode, handle, mode, offset, length);
+        }
+        let handle = handle.into();
+        self.write_data(ctx, handle, length, || {
+            self.inner
+                .fallocate(ctx, inode, handle.into(), mode, offset, length)
+                .map(|_| length)
+        })
+        .map(|_| ())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn release(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        flags: u32,
+        handle: Self::Handle,
+        flush: bool,
+        flock_release: bool,
+        lock_owner: Option<u64>,
+    ) -> io::Result<()> {
+        let handle = handle.into();
+        self.state.lock().unwrap().handles.remove(&handle);
+        self.inner.release(
+            ctx,
+            inode,
+            flags,
+            handle.into(),
+            flush,
+            flock_release,
+            lock_owner,
+        )
+    }
+
+    fn statfs(&self, ctx: &Context, inode: Self::Inode) -> io::Result<statvfs64> {
+        self.inner.statfs(ctx, inode)
+    }
+
+    fn syncfs(&self, ctx: &Context) -> io::Result<()> {
+        self.inner.syncfs(ctx)
+    }
+
+    fn setxattr(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        name: &CStr,
+        value: &[u8],
+        flags: u32,
+    ) -> io::Result<()> {
+        self.inner.setxattr(ctx, inode, name, value, flags)
+    }
+
+    fn getxattr(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        name: &CStr,
+        size: u32,
+    ) -> io::Result<GetxattrReply> {
+        self.inner.getxattr(ctx, inode, name, size)
+    }
+
+    fn listxattr(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        size: u32,
+    ) -> io::Result<ListxattrReply> {
+        self.inner.listxattr(ctx, inode, size)
+    }
+
+    fn removexattr(&self, ctx: &Context, inode: Self::Inode, name: &CStr) -> io::Result<()> {
+        self.inner.removexattr(ctx, inode, name)
+    }
+
+    fn opendir(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        flags: u32,
+    ) -> io::Result<(Option<Self::Handle>, OpenOptions)> {
+        self.inner.opendir(ctx, inode, flags)
+    }
+
+    fn readdir(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        size: u32,
+        offset: u64,
+        add_entry: &mut dyn FnMut(DirEntry) -> io::Result<usize>,
+    ) -> io::Result<()> {
+        self.inner
+            .readdir(ctx, inode, handle, size, offset, add_entry)
+    }
+
+    fn readdirplus(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        size: u32,
+        offset: u64,
+        add_entry: &mut dyn FnMut(DirEntry, Entry) -> io::Result<usize>,
+    ) -> io::Result<()> {
+        self.inner
+            .readdirplus(ctx, inode, handle, size, offset, add_entry)
+    }
+
+    fn readdir_cursor(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        size: u32,
+        cursor: Option<&[u8]>,
+    ) -> io::Result<(Vec<DirEntryBuf>, Option<Vec<u8>>)> {
+        self.inner.readdir_cursor(ctx, inode, handle, size, cursor)
+    }
+
+    fn fsyncdir(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        datasync: bool,
+        handle: Self::Handle,
+    ) -> io::Result<()> {
+        self.inner.fsyncdir(ctx, inode, datasync, handle)
+    }
+
+    fn releasedir(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        flags: u32,
+        handle: Self::Handle,
+    ) -> io::Result<()> {
+        self.inner.releasedir(ctx, inode, flags, handle)
+    }
+
+    #[cfg(feature = "virtiofs")]
+    #[allow(clippy::too_many_arguments)]
+    fn setupmapping(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        foffset: u64,
+        len: u64,
+        flags: u64,
+        moffset: u64,
+        vu_req: &mut dyn FsCacheReqHandler,
+    ) -> io::Result<()> {
+        self.inner
+            .setupmapping(ctx, inode, handle, foffset, len, flags, moffset, vu_req)
+    }
+
+    #[cfg(feature = "virtiofs")]
+    fn removemapping(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        requests: Vec<RemovemappingOne>,
+        vu_req: &mut dyn FsCacheReqHandler,
+    ) -> io::Result<()> {
+        self.inner.removemapping(ctx, inode, requests, vu_req)
+    }
+
+    fn access(&self, ctx: &Context, inode: Self::Inode, mask: u32) -> io::Result<()> {
+        self.inner.access(ctx, inode, mask)
+    }
+
+    fn lseek(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        offset: u64,
+        whence: u32,
+    ) -> io::Result<u64> {
+        self.inner.lseek(ctx, inode, handle, offset, whence)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn clone_range(
+        &self,
+        ctx: &Context,
+        src_inode: Self::Inode,
+        src_handle: Self::Handle,
+        src_offset: u64,
+        dst_inode: Self::Inode,
+        dst_handle: Self::Handle,
+        dst_offset: u64,
+        len: u64,
+    ) -> io::Result<usize> {
+        let (src_inode, src_handle): (u64, u64) = (src_inode.into(), src_handle.into());
+        // A zero length clones up to the end of the source file.
+        let size = if len == 0 {
+            let (st, _) = self
+                .inner
+                .getattr(ctx, src_inode.into(), Some(src_handle.into()))?;
+            (st.st_size as u64).saturating_sub(src_offset)
+        } else {
+            len
+        };
+        let dst_handle = dst_handle.into();
+        self.write_data(ctx, dst_handle, size, || {
+            self.inner
+                .clone_range(
+                    ctx,
+                    src_inode.into(),
+                    src_handle.into(),
+                    src_offset,
+                    dst_inode,
+                    dst_handle.into(),
+                    dst_offset,
+                    len,
+                )
+                .map(|n| n as u64)
+        })
+        .map(|n| n as usize)
+    }
+
+    fn enable_verity(&self, ctx: &Context, inode: Self::Inode) -> io::Result<()> {
+        self.inner.enable_verity(ctx, inode)
+    }
+
+    fn measure_verity(&self, ctx: &Context, inode: Self::Inode) -> io::Result<Vec<u8>> {
+        self.inner.measure_verity(ctx, inode)
+    }
+
+    fn fiemap(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        start: u64,
+        len: u64,
+    ) -> io::Result<Vec<FiemapExtent>> {
+        self.inner.fiemap(ctx, inode, start, len)
+    }
+
+    /// Query file lock status
+    fn getlk(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<FileLock> {
+        self.inner.getlk(ctx, inode, handle, owner, lock, flags)
+    }
+
+    /// Grab a file read lock
+    fn setlk(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<()> {
+        self.inner.setlk(ctx, inode, handle, owner, lock, flags)
+    }
+
+    /// Grab a file write lock
+    fn setlkw(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<()> {
+        self.inner.setlkw(ctx, inode, handle, owner, lock, flags)
+    }
+
+    /// send ioctl to the file
+    #[allow(clippy::too_many_arguments)]
+    fn ioctl(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        flags: u32,
+        cmd: u32,
+        data: IoctlData,
+        out_size: u32,
+    ) -> io::Result<IoctlData<'_>> {
+        self.inner
+            .ioctl(ctx, inode, handle, flags, cmd, data, out_size)
+    }
+
+    /// Query a file's block mapping info
+    fn bmap(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        block: u64,
+        blocksize: u32,
+    ) -> io::Result<u64> {
+        self.inner.bmap(ctx, inode, block, blocksize)
+    }
+
+    /// Poll a file's events
+    fn poll(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        khandle: Self::Handle,
+        flags: u32,
+        events: u32,
+    ) -> io::Result<u32> {
+        self.inner.poll(ctx, inode, handle, khandle, flags, events)
+    }
+
+    /// TODO: support this
+    fn notify_reply(&self) -> io::Result<()> {
+        self.inner.notify_reply()
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::passthrough::{Config, PassthroughFs};
+    use crate::transport::{FileReadWriteVolatile, FileVolatileSlice};
+    use vmm_sys_util::tempdir::TempDir;
+
+    struct VecReader(Vec<u8>);
+
+    impl io::Read for VecReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = std::cmp::min(buf.len(), self.0.len());
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0.drain(..len);
+            Ok(len)
+        }
+    }
+
+    impl ZeroCopyReader for VecReader {
+        fn read_to(
+            &mut self,
+            f: &mut dyn FileReadWriteVolatile,
+            count: usize,
+            off: u64,
+        ) -> io::Result<usize> {
+            let len = std::cmp::min(count, self.0.len());
+            // Safe because the slice is valid during the call.
+            let slice = unsafe { FileVolatileSlice::new(self.0.as_mut_ptr(), len) };
+            let len = f.write_at_volatile(slice, off)?;
+            self.0.drain(..len);
+            Ok(len)
+        }
+    }
+
+    fn create(fs: &QuotaFs<PassthroughFs>, name: &str) -> io::Result<(Entry, u64)> {
+        let args = CreateIn {
+            flags: libc::O_RDWR as u32,
+            mode: 0o644,
+            umask: 0,
+            fuse_flags: 0,
+        };
+        let (entry, handle, _) = fs.create(
+            &Context::default(),
+            ROOT_ID,
+            &CString::new(name).unwrap(),
+            args,
+        )?;
+        Ok((entry, handle.unwrap()))
+    }
+
+    fn write(
+        fs: &QuotaFs<PassthroughFs>,
+        entry: &Entry,
+        handle: u64,
+        data: &[u8],
+    ) -> io::Result<usize> {
+        let mut r = VecReader(data.to_vec());
+        fs.write(
+            &Context::default(),
+            entry.inode,
+            handle,
+            &mut r,
+            data.len() as u32,
+            0,
+            None,
+            false,
+            0,
+            0,
+        )
+    }
+
+    #[test]
+    fn test_quota_fs() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let fs_cfg = Config {
+            root_dir: source
+                .as_path()
+                .to_str()
+                .expect("source path to string")
+                .to_string(),
+            ..Default::default()
+        };
+        let passthrough = PassthroughFs::new(fs_cfg).unwrap();
+        passthrough.import().unwrap();
+        let fs = QuotaFs::new(passthrough, QuotaLimits::default());
+        let uid = Context::default().uid;
+        fs.set_limits(
+            uid,
+            QuotaLimits {
+                bytes: Some(10),
+                inodes: Some(2),
+            },
+        );
+
+        let (a, handle) = create(&fs, "a").unwrap();
+        assert_eq!(write(&fs, &a, handle, b"12345678").unwrap(), 8);
+        // A write which doesn't fit is rejected as a whole.
+        let e = write(&fs, &a, handle, b"1234").unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EDQUOT));
+        assert_eq!(
+            std::fs::metadata(source.as_path().join("a")).unwrap().len(),
+            8
+        );
+        assert_eq!(write(&fs, &a, handle, b"12").unwrap(), 2);
+        assert_eq!(
+            fs.usage(uid),
+            QuotaUsage {
+                bytes: 10,
+                inodes: 1
+            }
+        );
+
+        let ctx = Context::default();
+        fs.mkdir(&ctx, ROOT_ID, &CString::new("d").unwrap(), 0o755, 0)
+            .unwrap();
+        let e = create(&fs, "b").err().unwrap();
+        assert_eq!(e.raw_os_error(), Some(libc::EDQUOT));
+        assert!(!source.as_path().join("b").exists());
+
+        // Removing an inode releases its quota.
+        fs.release(&ctx, a.inode, 0, handle, false, false, None)
+            .unwrap();
+        fs.unlink(&ctx, ROOT_ID, &CString::new("a").unwrap())
+            .unwrap();
+        assert_eq!(fs.usage(uid).inodes, 1);
+        create(&fs, "b").unwrap();
+        assert_eq!(fs.usage(uid).inodes, 2);
+
+        // Usage can be saved and restored.
+        let mut saved = Vec::new();
+        fs.save_usage(&mut saved).unwrap();
+        assert_eq!(saved, format!("{} 10 2\n", uid).into_bytes());
+        let fs2 = QuotaFs::new(
+            PassthroughFs::<()>::new(Config::default()).unwrap(),
+            QuotaLimits::default(),
+        );
+        fs2.load_usage(&saved[..]).unwrap();
+        assert_eq!(fs2.all_usage(), fs.all_usage());
+        assert!(fs2.load_usage(&b"1 2\n"[..]).is_err());
+    }
+
+    #[test]
+    fn test_quota_clone_range() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::write(source.as_path().join("src"), vec![0x5au8; 8192]).unwrap();
+        let fs_cfg = Config {
+            root_dir: source
+                .as_path()
+                .to_str()
+                .expect("source path to string")
+                .to_string(),
+            ..Default::default()
+        };
+        let passthrough = PassthroughFs::new(fs_cfg).unwrap();
+        passthrough.import().unwrap();
+        let fs = QuotaFs::new(passthrough, QuotaLimits::default());
+        let ctx = Context::default();
+        fs.set_limits(
+            ctx.uid,
+            QuotaLimits {
+                bytes: Some(4096),
+                inodes: None,
+            },
+        );
+
+        let src = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("src").unwrap())
+            .unwrap();
+        let (src_handle, _) = fs.open(&ctx, src.inode, libc::O_RDONLY as u32, 0).unwrap();
+        let (dst, dst_handle) = create(&fs, "dst").unwrap();
+        let clone = |offset: u64| {
+            fs.clone_range(
+                &ctx,
+                src.inode,
+                src_handle.unwrap(),
+                offset,
+                dst.inode,
+                dst_handle,
+                offset,
+                0,
+            )
+        };
+
+        // A zero length is charged for the rest of the source file.
+        let e = clone(0).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EDQUOT));
+        match clone(4096) {
+            Ok(count) => assert_eq!(fs.usage(ctx.uid).bytes, count as u64),
+            // The backing file system doesn't support reflink, and the charge is refunded.
+            Err(e) => {
+                assert_eq!(e.raw_os_error(), Some(libc::EOPNOTSUPP));
+                assert_eq!(fs.usage(ctx.uid).bytes, 0);
+            }
+        }
+    }
+}
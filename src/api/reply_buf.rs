@@ -0,0 +1,157 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Reusable buffers to assemble variable-length replies.
+//!
+//! Replies such as directory listings and extended attribute lists are collected into a buffer
+//! before they are sent to the kernel. Allocating a fresh `Vec` for each of them, and growing it
+//! while entries are appended, causes a lot of allocator churn on busy file systems. A
+//! [ReplyBuf] borrows a buffer from a small per-thread pool instead and puts it back into the pool
+//! once it has been committed or dropped.
+//!
+//! The pooled buffers are pre-sized to [ReplyBuf::capacity()], which the server sets on
+//! `FUSE_INIT` to the largest reply allowed by the negotiated `max_write` and `max_pages`.
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::api::server::{BUFFER_HEADER_SIZE, MAX_BUFFER_SIZE, MIN_READ_BUFFER};
+
+// Maximum number of idle buffers kept by each thread.
+const REPLY_BUF_POOL_DEPTH: usize = 4;
+
+static REPLY_BUF_CAPACITY: AtomicUsize =
+    AtomicUsize::new((MIN_READ_BUFFER - BUFFER_HEADER_SIZE) as usize);
+
+thread_local! {
+    static REPLY_BUF_POOL: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A buffer borrowed from the per-thread reply buffer pool.
+///
+/// The buffer dereferences to a `Vec<u8>`, which is empty when acquired. It goes back to the pool
+/// when it's dropped, unless it has been detached by [ReplyBuf::into_inner()].
+pub struct ReplyBuf {
+    buf: Vec<u8>,
+}
+
+impl ReplyBuf {
+    /// Borrow a buffer with at least [ReplyBuf::capacity()] bytes of capacity.
+    pub fn acquire() -> Self {
+        Self::with_capacity(Self::capacity())
+    }
+
+    /// Borrow a buffer with at least `size` bytes of capacity.
+    pub fn with_capacity(size: usize) -> Self {
+        let size = std::cmp::max(size, Self::capacity());
+        let buf = REPLY_BUF_POOL
+            .with(|pool| pool.borrow_mut().pop())
+            .unwrap_or_default();
+        let mut buf = ReplyBuf { buf };
+        buf.buf.reserve(size);
+        buf
+    }
+
+    /// Get the capacity of the pooled buffers.
+    pub fn capacity() -> usize {
+        REPLY_BUF_CAPACITY.load(Ordering::Relaxed)
+    }
+
+    /// Set the capacity of the pooled buffers, usually to the negotiated `max_write`.
+    ///
+    /// The setting is process wide. Buffers smaller than the new capacity are grown when they are
+    /// acquired next time.
+    pub fn set_capacity(size: usize) {
+        REPLY_BUF_CAPACITY.store(size, Ordering::Relaxed);
+    }
+
+    /// Write the content of the buffer to `w` and return the buffer to the pool.
+    pub fn commit_to<W: Write + ?Sized>(self, w: &mut W) -> io::Result<usize> {
+        w.write_all(&self.buf)?;
+        Ok(self.buf.len())
+    }
+
+    /// Detach the underlying `Vec` from the pool.
+    ///
+    /// The `Vec` may be handed back to the pool later by converting it into a [ReplyBuf] again.
+    pub fn into_inner(mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buf)
+    }
+}
+
+impl From<Vec<u8>> for ReplyBuf {
+    fn from(buf: Vec<u8>) -> Self {
+        ReplyBuf { buf }
+    }
+}
+
+impl Deref for ReplyBuf {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.buf
+    }
+}
+
+impl DerefMut for ReplyBuf {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.buf
+    }
+}
+
+impl Drop for ReplyBuf {
+    fn drop(&mut self) {
+        let mut buf = std::mem::take(&mut self.buf);
+        // Don't keep buffers which have been grown larger than any reply the kernel may ask for.
+        if buf.capacity() > std::cmp::max(Self::capacity(), MAX_BUFFER_SIZE as usize) {
+            return;
+        }
+        buf.clear();
+        // The pool may already be gone if the thread is exiting.
+        let _ = REPLY_BUF_POOL.try_with(|pool| {
+            let mut pool = pool.borrow_mut();
+            if pool.len() < REPLY_BUF_POOL_DEPTH {
+                pool.push(buf);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reply_buf_reuse() {
+        let mut buf = ReplyBuf::with_capacity(MAX_BUFFER_SIZE as usize);
+        assert!(buf.is_empty());
+        assert!(buf.capacity() >= MAX_BUFFER_SIZE as usize);
+        buf.extend_from_slice(b"hello");
+        let ptr = buf.as_ptr();
+        let mut out = Vec::new();
+        assert_eq!(buf.commit_to(&mut out).unwrap(), 5);
+        assert_eq!(out, b"hello");
+
+        // The committed buffer is handed out again, empty.
+        let buf = ReplyBuf::acquire();
+        assert_eq!(buf.as_ptr(), ptr);
+        assert!(buf.is_empty());
+
+        // Detached buffers can be returned to the pool.
+        let vec = buf.into_inner();
+        assert_eq!(vec.as_ptr(), ptr);
+        drop(ReplyBuf::from(vec));
+        assert_eq!(ReplyBuf::acquire().as_ptr(), ptr);
+
+        // Oversized buffers are not kept.
+        let buf = ReplyBuf::with_capacity(
+            std::cmp::max(ReplyBuf::capacity(), MAX_BUFFER_SIZE as usize) + 1,
+        );
+        let ptr = buf.as_ptr();
+        drop(buf);
+        let buf = ReplyBuf::acquire();
+        assert_ne!(buf.as_ptr(), ptr);
+    }
+}
@@ -9,15 +9,19 @@ use std::time::Duration;
 use async_trait::async_trait;
 use vm_memory::ByteValued;
 
-use super::{MetricsHook, Server, ServerUtil, SrvContext, BUFFER_HEADER_SIZE};
+use super::{
+    check_out_header, MetricsHook, Retryable, Server, ServerUtil, SrvContext, BUFFER_HEADER_SIZE,
+};
 use crate::abi::fuse_abi::*;
+#[cfg(feature = "virtiofs")]
+use crate::abi::virtio_fs::{RemovemappingIn, RemovemappingOne, SetupmappingIn};
 use crate::api::filesystem::{
     AsyncFileSystem, AsyncZeroCopyReader, AsyncZeroCopyWriter, ZeroCopyReader, ZeroCopyWriter,
 };
 use crate::api::server::MAX_BUFFER_SIZE;
 use crate::api::CreateIn;
 use crate::async_util::AsyncDrive;
-use crate::transport::{FileReadWriteVolatile, FsCacheReqHandler, Reader, Writer};
+use crate::transport::{AsyncFsCacheReqHandler, FileReadWriteVolatile, Reader, Writer};
 use crate::{bytes_to_cstr, encode_io_error_kind, BitmapSlice, Error, Result};
 
 struct AsyncZcReader<'a, S: BitmapSlice = ()>(Reader<'a, S>);
@@ -116,11 +120,14 @@ impl<F: AsyncFileSystem<D> + Sync, D: AsyncDrive> Server<F, D> {
         drive: D,
         mut r: Reader<'_, S>,
         w: Writer<'_, S>,
-        vu_req: Option<&mut dyn FsCacheReqHandler>,
+        vu_req: Option<&mut dyn AsyncFsCacheReqHandler>,
         hook: Option<&dyn MetricsHook>,
     ) -> Result<usize> {
-        let in_header = r.read_obj().map_err(Error::DecodeMessage)?;
-        let mut ctx = SrvContext::<F, D, S>::with_drive(in_header, r, w, drive);
+        let in_header: InHeader = ServerUtil::read_obj(&mut r)?;
+        // The extensions must outlive the context borrowing them.
+        let mut header = in_header;
+        let extensions = ServerUtil::take_extensions(&mut header, &mut r);
+        let mut ctx = SrvContext::<F, D, S>::with_drive(header, r, w, drive);
         if ctx.in_header.len > (MAX_BUFFER_SIZE + BUFFER_HEADER_SIZE)
             || ctx.w.available_bytes() < size_of::<OutHeader>()
         {
@@ -128,6 +135,21 @@ impl<F: AsyncFileSystem<D> + Sync, D: AsyncDrive> Server<F, D> {
                 .async_do_reply_error(io::Error::from_raw_os_error(libc::ENOMEM), true)
                 .await;
         }
+        match &extensions {
+            Ok(extensions) => ctx.context.extensions = extensions,
+            Err(e) => {
+                let errno = match e {
+                    Error::InvalidMessage(e) => e.errno(),
+                    _ => libc::EINVAL,
+                };
+                return ctx
+                    .async_do_reply_error(io::Error::from_raw_os_error(errno), true)
+                    .await;
+            }
+        }
+        if self.fs.wants_raw_header() {
+            ctx.context.raw_header = Some(in_header);
+        }
         let in_header = &ctx.in_header;
 
         trace!(
@@ -135,7 +157,17 @@ impl<F: AsyncFileSystem<D> + Sync, D: AsyncDrive> Server<F, D> {
             Opcode::from(in_header.opcode),
             in_header
         );
+        if !self.allowed_opcodes.load().allows(in_header.opcode) {
+            debug!("fuse: opcode {} is not allowed", in_header.opcode);
+            return ctx
+                .async_reply_error(io::Error::from_raw_os_error(libc::ENOSYS))
+                .await;
+        }
+        if let Err(e) = self.check_path_jail(&ctx) {
+            return ctx.async_reply_error(e).await;
+        }
         hook.map_or((), |h| h.collect(&in_header));
+        self.counters.inc(in_header.opcode);
 
         let res = match in_header.opcode {
             x if x == Opcode::Lookup as u32 => self.async_lookup(ctx).await,
@@ -181,9 +213,9 @@ impl<F: AsyncFileSystem<D> + Sync, D: AsyncDrive> Server<F, D> {
             x if x == Opcode::Rename2 as u32 => self.rename2(ctx),
             x if x == Opcode::Lseek as u32 => self.lseek(ctx),
             #[cfg(feature = "virtiofs")]
-            x if x == Opcode::SetupMapping as u32 => self.setupmapping(ctx, vu_req),
+            x if x == Opcode::SetupMapping as u32 => self.async_setupmapping(ctx, vu_req).await,
             #[cfg(feature = "virtiofs")]
-            x if x == Opcode::RemoveMapping as u32 => self.removemapping(ctx, vu_req),
+            x if x == Opcode::RemoveMapping as u32 => self.async_removemapping(ctx, vu_req).await,
             // Group reqeusts don't need reply together
             x => match x {
                 x if x == Opcode::Interrupt as u32 => {
@@ -191,7 +223,7 @@ impl<F: AsyncFileSystem<D> + Sync, D: AsyncDrive> Server<F, D> {
                     Ok(0)
                 }
                 x if x == Opcode::Destroy as u32 => {
-                    self.destroy();
+                    self.shutdown();
                     Ok(0)
                 }
                 _ => {
@@ -213,7 +245,7 @@ impl<F: AsyncFileSystem<D> + Sync, D: AsyncDrive> Server<F, D> {
         &self,
         mut ctx: SrvContext<'_, F, D, S>,
     ) -> Result<usize> {
-        let buf = ServerUtil::get_message_body(&mut ctx.r, &ctx.in_header, 0)?;
+        let buf = ctx.message_body(0)?;
         let name = bytes_to_cstr(buf.as_ref())?;
         let result = self
             .fs
@@ -244,7 +276,7 @@ impl<F: AsyncFileSystem<D> + Sync, D: AsyncDrive> Server<F, D> {
         &self,
         mut ctx: SrvContext<'_, F, D, S>,
     ) -> Result<usize> {
-        let GetattrIn { flags, fh, .. } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        let GetattrIn { flags, fh, .. } = ctx.read_obj()?;
         let handle = if (flags & GETATTR_FH) != 0 {
             Some(fh.into())
         } else {
@@ -262,7 +294,7 @@ impl<F: AsyncFileSystem<D> + Sync, D: AsyncDrive> Server<F, D> {
         &self,
         mut ctx: SrvContext<'_, F, D, S>,
     ) -> Result<usize> {
-        let setattr_in: SetattrIn = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        let setattr_in: SetattrIn = ctx.read_obj()?;
         let handle = if setattr_in.valid & FATTR_FH != 0 {
             Some(setattr_in.fh.into())
         } else {
@@ -279,7 +311,7 @@ impl<F: AsyncFileSystem<D> + Sync, D: AsyncDrive> Server<F, D> {
     }
 
     async fn async_open<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, D, S>) -> Result<usize> {
-        let OpenIn { flags, fuse_flags } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        let OpenIn { flags, fuse_flags } = ctx.read_obj()?;
         let result = self
             .fs
             .async_open(ctx.context(), ctx.nodeid(), flags, fuse_flags)
@@ -287,11 +319,7 @@ impl<F: AsyncFileSystem<D> + Sync, D: AsyncDrive> Server<F, D> {
 
         match result {
             Ok((handle, opts)) => {
-                let out = OpenOut {
-                    fh: handle.map(Into::into).unwrap_or(0),
-                    open_flags: opts.bits(),
-                    ..Default::default()
-                };
+                let out = OpenOut::new(handle.map(Into::into).unwrap_or(0), opts);
                 ctx.async_reply_ok(Some(out), None).await
             }
             Err(e) => ctx.async_reply_error(e).await,
@@ -307,7 +335,7 @@ impl<F: AsyncFileSystem<D> + Sync, D: AsyncDrive> Server<F, D> {
             lock_owner,
             flags,
             ..
-        } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        } = ctx.read_obj()?;
 
         if size > MAX_BUFFER_SIZE {
             return ctx
@@ -355,6 +383,7 @@ impl<F: AsyncFileSystem<D> + Sync, D: AsyncDrive> Server<F, D> {
                     .async_write_all(ctx.drive(), out.as_slice())
                     .await
                     .map_err(Error::EncodeMessage)?;
+                check_out_header(&out, ctx.w.bytes_written() + data_writer.0.bytes_written());
                 ctx.w
                     .async_commit(ctx.drive(), Some(&data_writer.0))
                     .await
@@ -374,7 +403,7 @@ impl<F: AsyncFileSystem<D> + Sync, D: AsyncDrive> Server<F, D> {
             lock_owner,
             flags,
             ..
-        } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        } = ctx.read_obj()?;
 
         if size > MAX_BUFFER_SIZE {
             return ctx
@@ -407,12 +436,8 @@ impl<F: AsyncFileSystem<D> + Sync, D: AsyncDrive> Server<F, D> {
 
         match result {
             Ok(count) => {
-                let out = WriteOut {
-                    size: count as u32,
-                    ..Default::default()
-                };
-
-                ctx.async_reply_ok(Some(out), None).await
+                ctx.async_reply_ok(Some(WriteOut::new(count as u32)), None)
+                    .await
             }
             Err(e) => ctx.async_reply_error(e).await,
         }
@@ -421,7 +446,7 @@ impl<F: AsyncFileSystem<D> + Sync, D: AsyncDrive> Server<F, D> {
     async fn async_fsync<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, D, S>) -> Result<usize> {
         let FsyncIn {
             fh, fsync_flags, ..
-        } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        } = ctx.read_obj()?;
         let datasync = fsync_flags & 0x1 != 0;
 
         match self
@@ -440,7 +465,7 @@ impl<F: AsyncFileSystem<D> + Sync, D: AsyncDrive> Server<F, D> {
     ) -> Result<usize> {
         let FsyncIn {
             fh, fsync_flags, ..
-        } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        } = ctx.read_obj()?;
         let datasync = fsync_flags & 0x1 != 0;
         let result = self
             .fs
@@ -453,12 +478,91 @@ impl<F: AsyncFileSystem<D> + Sync, D: AsyncDrive> Server<F, D> {
         }
     }
 
+    #[cfg(feature = "virtiofs")]
+    async fn async_setupmapping<S: BitmapSlice>(
+        &self,
+        mut ctx: SrvContext<'_, F, D, S>,
+        vu_req: Option<&mut dyn AsyncFsCacheReqHandler>,
+    ) -> Result<usize> {
+        if let Some(req) = vu_req {
+            let SetupmappingIn {
+                fh,
+                foffset,
+                len,
+                flags,
+                moffset,
+            } = ctx.read_obj()?;
+            let result = self
+                .fs
+                .async_setupmapping(
+                    ctx.context(),
+                    ctx.nodeid(),
+                    fh.into(),
+                    foffset,
+                    len,
+                    flags,
+                    moffset,
+                    req,
+                )
+                .await;
+
+            match result {
+                Ok(()) => ctx.async_reply_ok(None::<u8>, None).await,
+                Err(e) => ctx.async_reply_error(e).await,
+            }
+        } else {
+            ctx.async_do_reply_error(io::Error::from_raw_os_error(libc::EINVAL), true)
+                .await
+        }
+    }
+
+    #[cfg(feature = "virtiofs")]
+    async fn async_removemapping<S: BitmapSlice>(
+        &self,
+        mut ctx: SrvContext<'_, F, D, S>,
+        vu_req: Option<&mut dyn AsyncFsCacheReqHandler>,
+    ) -> Result<usize> {
+        if let Some(req) = vu_req {
+            let RemovemappingIn { count } = ctx.read_obj()?;
+
+            if let Some(size) = (count as usize).checked_mul(size_of::<RemovemappingOne>()) {
+                if size > MAX_BUFFER_SIZE as usize {
+                    return ctx
+                        .async_do_reply_error(io::Error::from_raw_os_error(libc::ENOMEM), true)
+                        .await;
+                }
+            } else {
+                return ctx
+                    .async_do_reply_error(io::Error::from_raw_os_error(libc::EOVERFLOW), true)
+                    .await;
+            }
+
+            let mut requests = Vec::with_capacity(count as usize);
+            for _ in 0..count {
+                requests.push(ctx.read_obj::<RemovemappingOne>()?);
+            }
+
+            let result = self
+                .fs
+                .async_removemapping(ctx.context(), ctx.nodeid(), requests, req)
+                .await;
+
+            match result {
+                Ok(()) => ctx.async_reply_ok(None::<u8>, None).await,
+                Err(e) => ctx.async_reply_error(e).await,
+            }
+        } else {
+            ctx.async_do_reply_error(io::Error::from_raw_os_error(libc::EINVAL), true)
+                .await
+        }
+    }
+
     async fn async_create<S: BitmapSlice>(
         &self,
         mut ctx: SrvContext<'_, F, D, S>,
     ) -> Result<usize> {
-        let args: CreateIn = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
-        let buf = ServerUtil::get_message_body(&mut ctx.r, &ctx.in_header, size_of::<CreateIn>())?;
+        let args: CreateIn = ctx.read_obj()?;
+        let buf = ctx.message_body(size_of::<CreateIn>())?;
         let name = bytes_to_cstr(&buf)?;
         let result = self
             .fs
@@ -467,20 +571,8 @@ impl<F: AsyncFileSystem<D> + Sync, D: AsyncDrive> Server<F, D> {
 
         match result {
             Ok((entry, handle, opts)) => {
-                let entry_out = EntryOut {
-                    nodeid: entry.inode,
-                    generation: entry.generation,
-                    entry_valid: entry.entry_timeout.as_secs(),
-                    attr_valid: entry.attr_timeout.as_secs(),
-                    entry_valid_nsec: entry.entry_timeout.subsec_nanos(),
-                    attr_valid_nsec: entry.attr_timeout.subsec_nanos(),
-                    attr: entry.attr.into(),
-                };
-                let open_out = OpenOut {
-                    fh: handle.map(Into::into).unwrap_or(0),
-                    open_flags: opts.bits(),
-                    ..Default::default()
-                };
+                let entry_out = EntryOut::from(entry);
+                let open_out = OpenOut::new(handle.map(Into::into).unwrap_or(0), opts);
 
                 // Kind of a hack to write both structs.
                 ctx.async_reply_ok(Some(entry_out), Some(open_out.as_slice()))
@@ -500,7 +592,7 @@ impl<F: AsyncFileSystem<D> + Sync, D: AsyncDrive> Server<F, D> {
             length,
             mode,
             ..
-        } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        } = ctx.read_obj()?;
         let result = self
             .fs
             .async_fallocate(ctx.context(), ctx.nodeid(), fh.into(), mode, offset, length)
@@ -569,6 +661,7 @@ impl<'a, F: AsyncFileSystem<D>, D: AsyncDrive, S: BitmapSlice> SrvContext<'a, F,
     }
 
     async fn async_do_reply_error(&mut self, err: io::Error, internal_err: bool) -> Result<usize> {
+        let err = Retryable::into_inner(err);
         let header = OutHeader {
             len: size_of::<OutHeader>() as u32,
             error: -err
@@ -609,13 +702,8 @@ impl<'a, F: AsyncFileSystem<D>, D: AsyncDrive, S: BitmapSlice> SrvContext<'a, F,
     ) -> Result<usize> {
         match result {
             Ok((st, timeout)) => {
-                let out = AttrOut {
-                    attr_valid: timeout.as_secs(),
-                    attr_valid_nsec: timeout.subsec_nanos(),
-                    dummy: 0,
-                    attr: st.into(),
-                };
-                self.async_reply_ok(Some(out), None).await
+                self.async_reply_ok(Some(AttrOut::from_stat(&st, timeout)), None)
+                    .await
             }
             Err(e) => self.async_reply_error(e).await,
         }
@@ -0,0 +1,584 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Character devices in userspace (CUSE).
+//!
+//! CUSE is a variant of the FUSE protocol to implement a character device instead of a file
+//! system. A CUSE connection is set up by opening `/dev/cuse` rather than mounting a file system,
+//! and it starts with a `CUSE_INIT` request, whose reply tells the kernel the name and device
+//! number of the character device to create. Later requests are a subset of FUSE requests on an
+//! open file: `OPEN`, `READ`, `WRITE`, `IOCTL`, `POLL`, `FLUSH`, `FSYNC` and `RELEASE`.
+//!
+//! A [CuseServer] serves these requests with a [CharDevice] implementation, and it reuses the
+//! transport layer [Reader] and [Writer] of FUSE servers.
+
+use std::io;
+use std::mem::size_of;
+
+use super::{Server, SrvContext};
+use crate::abi::fuse_abi::*;
+use crate::api::filesystem::{
+    Context, FileSystem, IoctlData, OpenOptions, ZeroCopyReader, ZeroCopyWriter,
+};
+use crate::async_util::AsyncDriver;
+use crate::transport::{pagesize, Reader, Writer};
+use crate::{BitmapSlice, Error, Result};
+
+// The kernel doesn't negotiate `max_pages` for CUSE, so requests are limited to 32 pages.
+const CUSE_MAX_PAGES: usize = 32;
+
+/// Information about the character device created by a CUSE connection.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CuseDeviceInfo {
+    /// Name of the device node created under `/dev`.
+    pub name: String,
+    /// Major number of the device, 0 to let the kernel allocate one.
+    pub dev_major: u32,
+    /// Minor number of the device.
+    pub dev_minor: u32,
+    /// Pass all ioctl requests to the device, instead of only those with encoded data sizes.
+    pub unrestricted_ioctl: bool,
+}
+
+impl CuseDeviceInfo {
+    // Encode the device information following the CUSE_INIT reply.
+    fn encode(&self) -> io::Result<Vec<u8>> {
+        let info = format!("DEVNAME={}\0", self.name).into_bytes();
+        if self.name.is_empty()
+            || self.name.contains('\0')
+            || self.name.contains('/')
+            || info.len() > CUSE_INIT_INFO_MAX
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid cuse device name {:?}", self.name),
+            ));
+        }
+
+        Ok(info)
+    }
+}
+
+/// The character device interface to be implemented by CUSE drivers.
+///
+/// Requests on the device are not bound to any inode, they are identified by the handle returned
+/// by `open` instead. The methods have the same semantics as their counterparts of
+/// [FileSystem](../filesystem/trait.FileSystem.html).
+#[allow(unused_variables)]
+pub trait CharDevice {
+    /// Open the device, returning a handle to identify the opened file in later requests.
+    fn open(&self, ctx: &Context, flags: u32) -> io::Result<(u64, OpenOptions)> {
+        Ok((0, OpenOptions::empty()))
+    }
+
+    /// Release an open file of the device, once all references to it have been dropped.
+    fn release(&self, ctx: &Context, handle: u64, flags: u32) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Read at most `size` bytes from the device into `w`.
+    fn read(
+        &self,
+        ctx: &Context,
+        handle: u64,
+        w: &mut dyn ZeroCopyWriter,
+        size: u32,
+        offset: u64,
+        flags: u32,
+    ) -> io::Result<usize> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Write `size` bytes from `r` to the device.
+    fn write(
+        &self,
+        ctx: &Context,
+        handle: u64,
+        r: &mut dyn ZeroCopyReader,
+        size: u32,
+        offset: u64,
+        flags: u32,
+    ) -> io::Result<usize> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Handle a device specific ioctl.
+    fn ioctl(
+        &self,
+        ctx: &Context,
+        handle: u64,
+        flags: u32,
+        cmd: u32,
+        data: IoctlData,
+        out_size: u32,
+    ) -> io::Result<IoctlData<'_>> {
+        Err(io::Error::from_raw_os_error(libc::ENOTTY))
+    }
+
+    /// Poll the device for `events`, returning the events ready.
+    ///
+    /// `khandle` identifies the poll request to be woken up by a `FUSE_NOTIFY_POLL`
+    /// notification if `POLL_SCHEDULE_NOTIFY` is set in `flags`.
+    fn poll(
+        &self,
+        ctx: &Context,
+        handle: u64,
+        khandle: u64,
+        flags: u32,
+        events: u32,
+    ) -> io::Result<u32> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Flush an open file of the device, called on each `close()` of a file descriptor.
+    fn flush(&self, ctx: &Context, handle: u64, lock_owner: u64) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    /// Synchronize pending writes of the device.
+    fn fsync(&self, ctx: &Context, handle: u64, datasync: bool) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+}
+
+// Serve the file requests of a CUSE connection by the FUSE server.
+struct CuseFs<D>(D);
+
+impl<D: CharDevice> FileSystem for CuseFs<D> {
+    type Inode = u64;
+    type Handle = u64;
+
+    fn open(
+        &self,
+        ctx: &Context,
+        _inode: u64,
+        flags: u32,
+        _fuse_flags: u32,
+    ) -> io::Result<(Option<u64>, OpenOptions)> {
+        self.0
+            .open(ctx, flags)
+            .map(|(handle, opts)| (Some(handle), opts))
+    }
+
+    fn release(
+        &self,
+        ctx: &Context,
+        _inode: u64,
+        flags: u32,
+        handle: u64,
+        _flush: bool,
+        _flock_release: bool,
+        _lock_owner: Option<u64>,
+    ) -> io::Result<()> {
+        self.0.release(ctx, handle, flags)
+    }
+
+    fn read(
+        &self,
+        ctx: &Context,
+        _inode: u64,
+        handle: u64,
+        w: &mut dyn ZeroCopyWriter,
+        size: u32,
+        offset: u64,
+        _lock_owner: Option<u64>,
+        flags: u32,
+    ) -> io::Result<usize> {
+        self.0.read(ctx, handle, w, size, offset, flags)
+    }
+
+    fn write(
+        &self,
+        ctx: &Context,
+        _inode: u64,
+        handle: u64,
+        r: &mut dyn ZeroCopyReader,
+        size: u32,
+        offset: u64,
+        _lock_owner: Option<u64>,
+        _delayed_write: bool,
+        flags: u32,
+        _fuse_flags: u32,
+    ) -> io::Result<usize> {
+        self.0.write(ctx, handle, r, size, offset, flags)
+    }
+
+    fn ioctl(
+        &self,
+        ctx: &Context,
+        _inode: u64,
+        handle: u64,
+        flags: u32,
+        cmd: u32,
+        data: IoctlData,
+        out_size: u32,
+    ) -> io::Result<IoctlData<'_>> {
+        self.0.ioctl(ctx, handle, flags, cmd, data, out_size)
+    }
+
+    fn poll(
+        &self,
+        ctx: &Context,
+        _inode: u64,
+        handle: u64,
+        khandle: u64,
+        flags: u32,
+        events: u32,
+    ) -> io::Result<u32> {
+        self.0.poll(ctx, handle, khandle, flags, events)
+    }
+
+    fn flush(&self, ctx: &Context, _inode: u64, handle: u64, lock_owner: u64) -> io::Result<()> {
+        self.0.flush(ctx, handle, lock_owner)
+    }
+
+    fn fsync(&self, ctx: &Context, _inode: u64, datasync: bool, handle: u64) -> io::Result<()> {
+        self.0.fsync(ctx, handle, datasync)
+    }
+}
+
+/// Server to serve a CUSE connection by a [CharDevice].
+///
+/// Requests could be received from a channel opened by
+/// [FuseChannel::open_cuse()](crate::transport::fusedev::FuseChannel::open_cuse), and the character
+/// device is created once the `CUSE_INIT` request has been replied.
+pub struct CuseServer<D: CharDevice + Sync> {
+    server: Server<CuseFs<D>, AsyncDriver>,
+    info: CuseDeviceInfo,
+    devinfo: Vec<u8>,
+}
+
+impl<D: CharDevice + Sync> CuseServer<D> {
+    /// Create a CUSE server to serve the character device `dev` described by `info`.
+    pub fn new(dev: D, info: CuseDeviceInfo) -> io::Result<Self> {
+        let devinfo = info.encode()?;
+
+        Ok(CuseServer {
+            server: Server::new(CuseFs(dev)),
+            info,
+            devinfo,
+        })
+    }
+
+    /// Get the character device served.
+    pub fn device(&self) -> &D {
+        &self.server.fs.0
+    }
+
+    /// Get the information of the character device.
+    pub fn device_info(&self) -> &CuseDeviceInfo {
+        &self.info
+    }
+
+    /// Handle a CUSE request from the transport layer.
+    pub fn handle_message<S: BitmapSlice>(
+        &self,
+        r: Reader<'_, S>,
+        w: Writer<'_, S>,
+    ) -> Result<usize> {
+        let in_header: InHeader = r.clone().read_obj().map_err(Error::DecodeMessage)?;
+        if in_header.opcode != CUSE_INIT {
+            return self.server.handle_message(r, w, None, None);
+        }
+
+        let mut ctx = SrvContext::<CuseFs<D>, AsyncDriver, S>::new(in_header, r, w);
+        // Skip the header, which has been peeked above.
+        ctx.r.read_obj::<InHeader>().map_err(Error::DecodeMessage)?;
+        let CuseInitIn { major, minor, .. } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+
+        let init = InitIn {
+            major,
+            minor,
+            ..Default::default()
+        };
+        if let Err(e) = self.server.resume(&init) {
+            return ctx.reply_error_explicit(e);
+        }
+        info!(
+            "CUSE INIT major {} minor {}, device {:?}",
+            major, minor, self.info
+        );
+
+        let max_io = (CUSE_MAX_PAGES * pagesize()) as u32;
+        let out = CuseInitOut {
+            major: KERNEL_VERSION,
+            minor: KERNEL_MINOR_VERSION,
+            flags: if self.info.unrestricted_ioctl {
+                CUSE_UNRESTRICTED_IOCTL
+            } else {
+                0
+            },
+            max_read: max_io,
+            max_write: max_io,
+            dev_major: self.info.dev_major,
+            dev_minor: self.info.dev_minor,
+            ..Default::default()
+        };
+        debug_assert!(size_of::<CuseInitOut>() + self.devinfo.len() <= pagesize());
+
+        ctx.reply_ok(Some(out), Some(&self.devinfo))
+    }
+}
+
+#[cfg(all(test, not(feature = "virtiofs")))]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use vm_memory::ByteValued;
+
+    // A pipe-like device, reads drain the data written so far.
+    #[derive(Default)]
+    struct EchoDev {
+        data: Mutex<Vec<u8>>,
+        released: Mutex<Vec<u64>>,
+    }
+
+    impl CharDevice for EchoDev {
+        fn open(&self, _ctx: &Context, _flags: u32) -> io::Result<(u64, OpenOptions)> {
+            Ok((7, OpenOptions::empty()))
+        }
+
+        fn release(&self, _ctx: &Context, handle: u64, _flags: u32) -> io::Result<()> {
+            self.released.lock().unwrap().push(handle);
+            Ok(())
+        }
+
+        fn read(
+            &self,
+            _ctx: &Context,
+            handle: u64,
+            w: &mut dyn ZeroCopyWriter,
+            size: u32,
+            _offset: u64,
+            _flags: u32,
+        ) -> io::Result<usize> {
+            assert_eq!(handle, 7);
+            let mut data = self.data.lock().unwrap();
+            let count = std::cmp::min(size as usize, data.len());
+            w.write_all(&data[..count])?;
+            data.drain(..count);
+            Ok(count)
+        }
+
+        fn write(
+            &self,
+            _ctx: &Context,
+            handle: u64,
+            r: &mut dyn ZeroCopyReader,
+            size: u32,
+            _offset: u64,
+            _flags: u32,
+        ) -> io::Result<usize> {
+            assert_eq!(handle, 7);
+            let mut buf = vec![0u8; size as usize];
+            r.read_exact(&mut buf)?;
+            self.data.lock().unwrap().extend_from_slice(&buf);
+            Ok(buf.len())
+        }
+
+        fn ioctl(
+            &self,
+            _ctx: &Context,
+            _handle: u64,
+            _flags: u32,
+            cmd: u32,
+            _data: IoctlData,
+            _out_size: u32,
+        ) -> io::Result<IoctlData<'_>> {
+            if cmd != libc::FIONREAD as u32 {
+                return Err(io::Error::from_raw_os_error(libc::ENOTTY));
+            }
+            Ok(IoctlData {
+                result: self.data.lock().unwrap().len() as i32,
+                data: None,
+            })
+        }
+
+        fn poll(
+            &self,
+            _ctx: &Context,
+            _handle: u64,
+            _khandle: u64,
+            _flags: u32,
+            events: u32,
+        ) -> io::Result<u32> {
+            let mut revents = libc::POLLOUT as u32;
+            if !self.data.lock().unwrap().is_empty() {
+                revents |= libc::POLLIN as u32;
+            }
+            Ok(revents & events)
+        }
+    }
+
+    // Send a request to the server and return the error and the body of the reply.
+    fn request(
+        server: &CuseServer<EchoDev>,
+        opcode: u32,
+        arg: &[u8],
+        data: &[u8],
+    ) -> (i32, Vec<u8>) {
+        let header = InHeader {
+            len: (size_of::<InHeader>() + arg.len() + data.len()) as u32,
+            opcode,
+            unique: 9,
+            ..Default::default()
+        };
+        let mut req = header.as_slice().to_vec();
+        req.extend_from_slice(arg);
+        req.extend_from_slice(data);
+
+        let mut owned = Writer::<()>::new_owned(0x1000);
+        server
+            .handle_message(Reader::from_vec(req), owned.writer())
+            .unwrap();
+        let mut reply = owned.into_inner();
+        let out = OutHeader::from_slice(&reply[..size_of::<OutHeader>()]).unwrap();
+        assert_eq!(out.len as usize, reply.len());
+        assert_eq!(out.unique, 9);
+        (out.error, reply.split_off(size_of::<OutHeader>()))
+    }
+
+    #[test]
+    fn test_cuse_device_info() {
+        for name in ["", "a/b", "a\0b"] {
+            let info = CuseDeviceInfo {
+                name: name.to_string(),
+                ..Default::default()
+            };
+            assert!(CuseServer::new(EchoDev::default(), info).is_err());
+        }
+        let info = CuseDeviceInfo {
+            name: "x".repeat(CUSE_INIT_INFO_MAX),
+            ..Default::default()
+        };
+        assert!(CuseServer::new(EchoDev::default(), info).is_err());
+    }
+
+    #[test]
+    fn test_cuse_server() {
+        let info = CuseDeviceInfo {
+            name: "ttyECHO".to_string(),
+            dev_major: 240,
+            dev_minor: 3,
+            unrestricted_ioctl: false,
+        };
+        let server = CuseServer::new(EchoDev::default(), info.clone()).unwrap();
+        assert_eq!(server.device_info(), &info);
+
+        // The CUSE_INIT reply is followed by the device information.
+        let init = CuseInitIn {
+            major: KERNEL_VERSION,
+            minor: KERNEL_MINOR_VERSION,
+            ..Default::default()
+        };
+        let (error, reply) = request(&server, CUSE_INIT, init.as_slice(), &[]);
+        assert_eq!(error, 0);
+        let (out, devinfo) = reply.split_at(size_of::<CuseInitOut>());
+        let out = CuseInitOut::from_slice(out).unwrap();
+        assert_eq!(out.major, KERNEL_VERSION);
+        assert_eq!(out.flags, 0);
+        assert_eq!((out.dev_major, out.dev_minor), (240, 3));
+        assert!(out.max_read >= 4096 && out.max_write >= 4096);
+        assert_eq!(devinfo, b"DEVNAME=ttyECHO\0");
+
+        let (error, reply) = request(
+            &server,
+            Opcode::Open as u32,
+            OpenIn::default().as_slice(),
+            &[],
+        );
+        assert_eq!(error, 0);
+        let fh = OpenOut::from_slice(&reply).unwrap().fh;
+        assert_eq!(fh, 7);
+
+        let write = WriteIn {
+            fh,
+            size: 5,
+            ..Default::default()
+        };
+        let (error, reply) = request(&server, Opcode::Write as u32, write.as_slice(), b"hello");
+        assert_eq!(error, 0);
+        assert_eq!(WriteOut::from_slice(&reply).unwrap().size, 5);
+
+        let ioctl = IoctlIn {
+            fh,
+            cmd: libc::FIONREAD as u32,
+            ..Default::default()
+        };
+        let (error, reply) = request(&server, Opcode::Ioctl as u32, ioctl.as_slice(), &[]);
+        assert_eq!(error, 0);
+        assert_eq!(IoctlOut::from_slice(&reply).unwrap().result, 5);
+        let ioctl = IoctlIn {
+            fh,
+            cmd: libc::TCGETS as u32,
+            ..Default::default()
+        };
+        let (error, _) = request(&server, Opcode::Ioctl as u32, ioctl.as_slice(), &[]);
+        assert_eq!(error, -libc::ENOTTY);
+
+        let poll = PollIn {
+            fh,
+            events: (libc::POLLIN | libc::POLLOUT) as u32,
+            ..Default::default()
+        };
+        let (error, reply) = request(&server, Opcode::Poll as u32, poll.as_slice(), &[]);
+        assert_eq!(error, 0);
+        assert_eq!(
+            PollOut::from_slice(&reply).unwrap().revents,
+            (libc::POLLIN | libc::POLLOUT) as u32
+        );
+
+        let read = ReadIn {
+            fh,
+            size: 16,
+            ..Default::default()
+        };
+        let (error, reply) = request(&server, Opcode::Read as u32, read.as_slice(), &[]);
+        assert_eq!(error, 0);
+        assert_eq!(reply, b"hello");
+
+        let release = ReleaseIn {
+            fh,
+            ..Default::default()
+        };
+        let (error, _) = request(&server, Opcode::Release as u32, release.as_slice(), &[]);
+        assert_eq!(error, 0);
+        assert_eq!(*server.device().released.lock().unwrap(), vec![7]);
+
+        // Requests not supported by the device.
+        let (error, _) = request(
+            &server,
+            Opcode::Fsync as u32,
+            FsyncIn::default().as_slice(),
+            &[],
+        );
+        assert_eq!(error, -libc::ENOSYS);
+    }
+
+    #[test]
+    fn test_cuse_init_version() {
+        let info = CuseDeviceInfo {
+            name: "ttyECHO".to_string(),
+            unrestricted_ioctl: true,
+            ..Default::default()
+        };
+        let server = CuseServer::new(EchoDev::default(), info).unwrap();
+
+        let init = CuseInitIn {
+            major: KERNEL_VERSION + 1,
+            ..Default::default()
+        };
+        let (error, _) = request(&server, CUSE_INIT, init.as_slice(), &[]);
+        assert_eq!(error, -libc::EPROTO);
+
+        let init = CuseInitIn {
+            major: KERNEL_VERSION,
+            minor: KERNEL_MINOR_VERSION,
+            ..Default::default()
+        };
+        let (error, reply) = request(&server, CUSE_INIT, init.as_slice(), &[]);
+        assert_eq!(error, 0);
+        let out = CuseInitOut::from_slice(&reply[..size_of::<CuseInitOut>()]).unwrap();
+        assert_eq!(out.flags, CUSE_UNRESTRICTED_IOCTL);
+    }
+}
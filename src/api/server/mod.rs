@@ -16,22 +16,39 @@
 //! The Fuse API server is performance critical, so it's designed to support multi-threading by
 //! adopting interior-mutability. And the arcswap crate is used to implement interior-mutability.
 
-use std::ffi::CStr;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::ffi::{CStr, OsStr};
+use std::future::Future;
 use std::io::{self, Read};
 use std::marker::PhantomData;
 use std::mem::size_of;
-use std::sync::Arc;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context as TaskContext, Poll, Waker};
+use std::time::{Duration, Instant};
 
-use arc_swap::ArcSwap;
+use arc_swap::{ArcSwap, ArcSwapOption};
 
-use super::filesystem::{Context, FileSystem, ZeroCopyReader, ZeroCopyWriter};
+use super::filesystem::{
+    Context, Extension, Extensions, FileSystem, SecContext, SupportedOps, ZeroCopyReader,
+    ZeroCopyWriter,
+};
 use crate::abi::fuse_abi::*;
 use crate::async_util::{AsyncDrive, AsyncDriver};
 use crate::transport::{FileReadWriteVolatile, Reader, Writer};
-use crate::{bytes_to_cstr, BitmapSlice, Error, Result};
+use crate::{bytes_to_cstr, BitmapSlice, DecodeError, Error, Result};
+use vm_memory::ByteValued;
 
 #[cfg(feature = "async-io")]
 mod async_io;
+mod cuse;
+pub use cuse::{CharDevice, CuseDeviceInfo, CuseServer};
+#[cfg(all(target_os = "linux", feature = "fusedev", not(feature = "virtiofs")))]
+mod sessions;
 mod sync_io;
 
 /// Maximum buffer size of FUSE requests.
@@ -40,17 +57,78 @@ pub const MAX_BUFFER_SIZE: u32 = 1 << 20;
 /// Maximum buffer size of FUSE requests.
 #[cfg(target_os = "macos")]
 pub const MAX_BUFFER_SIZE: u32 = 1 << 25;
-const MIN_READ_BUFFER: u32 = 8192;
-const BUFFER_HEADER_SIZE: u32 = 0x1000;
+pub(crate) const MIN_READ_BUFFER: u32 = 8192;
+pub(crate) const BUFFER_HEADER_SIZE: u32 = 0x1000;
 const DIRENT_PADDING: [u8; 8] = [0; 8];
+const DEFAULT_MAX_BACKGROUND: u16 = u16::MAX;
+const DEFAULT_CONGESTION_THRESHOLD: u16 = (u16::MAX / 4) * 3;
 
 /// Maximum number of pages required for FUSE requests.
 pub const MAX_REQ_PAGES: u16 = 256; // 1MB
+                                    // Maximum number of pages per request if FUSE_MAX_PAGES isn't negotiated, following the kernel.
+pub(crate) const FUSE_DEFAULT_MAX_PAGES_PER_REQ: u16 = 32;
+
+// Get `max_write` and `max_pages` negotiated with the kernel once `enabled` options are in effect.
+pub(crate) fn negotiate_max_write(enabled: FsOptions) -> (u32, u16) {
+    if enabled.contains(FsOptions::MAX_PAGES) {
+        (
+            MAX_REQ_PAGES as u32 * crate::transport::pagesize() as u32,
+            MAX_REQ_PAGES,
+        )
+    } else {
+        (MIN_READ_BUFFER - BUFFER_HEADER_SIZE, 0)
+    }
+}
+
+// Get the size of the largest payload of requests and replies with the negotiated `max_write` and
+// `max_pages`. Besides writes, the kernel sends and expects other payloads, e.g. extended
+// attributes and reads, of up to `max_pages` pages, or its default without FUSE_MAX_PAGES.
+pub(crate) fn max_payload_size(max_write: u32, max_pages: u16) -> usize {
+    let pages = if max_pages == 0 {
+        FUSE_DEFAULT_MAX_PAGES_PER_REQ
+    } else {
+        max_pages
+    };
+    std::cmp::max(
+        max_write as usize,
+        pages as usize * crate::transport::pagesize(),
+    )
+}
 
 /// Fuse Server to handle requests from the Fuse client and vhost user master.
+///
+/// With the `panic-guard` feature, a panic raised by the filesystem driver while serving a
+/// request is caught by the synchronous request handlers. The panic is logged together with the
+/// opcode of the request, which is replied with EIO, and the server keeps serving other requests.
+/// The filesystem driver must keep its data structures consistent across a caught panic, e.g. it
+/// must not leave a half updated state behind a `&mut` reference or a lock guard, since it's
+/// accessed again by later requests. Panics can't be caught if the binary is built with
+/// `panic = "abort"`.
 pub struct Server<F: FileSystem + Sync, D: AsyncDrive = AsyncDriver> {
     fs: F,
     vers: ArcSwap<ServerVersion>,
+    opts: ArcSwap<FsOptions>,
+    opts_ext: ArcSwap<FsOptionsExt>,
+    time_gran: AtomicU32,
+    max_stack_depth: AtomicU32,
+    supported_ops: AtomicU64,
+    max_background: AtomicU16,
+    congestion_threshold: AtomicU16,
+    // Whether the filesystem driver has been initialized and not destroyed yet.
+    alive: AtomicBool,
+    rdplus: ReaddirplusAuto,
+    rdcursors: ReaddirCursors,
+    inflight: InflightRequests,
+    getattr_batch: GetattrCoalescer,
+    counters: OpcodeCounters,
+    middlewares: ArcSwap<Vec<Arc<dyn ServerMiddleware>>>,
+    retrieves: RetrieveTable,
+    retry: ArcSwap<RetryPolicy>,
+    allowed_opcodes: ArcSwap<OpcodeMask>,
+    path_jail: ArcSwapOption<PathBuf>,
+    congestion: Mutex<CongestionControl>,
+    #[cfg(all(target_os = "linux", feature = "fusedev", not(feature = "virtiofs")))]
+    sessions: sessions::MountSessions<F, D>,
     phantom: PhantomData<D>,
 }
 
@@ -63,9 +141,550 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
                 major: KERNEL_VERSION,
                 minor: KERNEL_MINOR_VERSION,
             })),
+            opts: ArcSwap::new(Arc::new(FsOptions::empty())),
+            opts_ext: ArcSwap::new(Arc::new(FsOptionsExt::empty())),
+            time_gran: AtomicU32::new(1),
+            max_stack_depth: AtomicU32::new(0),
+            supported_ops: AtomicU64::new(0),
+            max_background: AtomicU16::new(DEFAULT_MAX_BACKGROUND),
+            congestion_threshold: AtomicU16::new(DEFAULT_CONGESTION_THRESHOLD),
+            alive: AtomicBool::new(false),
+            rdplus: ReaddirplusAuto::default(),
+            rdcursors: ReaddirCursors::default(),
+            inflight: InflightRequests::default(),
+            getattr_batch: GetattrCoalescer::default(),
+            counters: OpcodeCounters::default(),
+            middlewares: ArcSwap::new(Arc::new(Vec::new())),
+            retrieves: RetrieveTable::default(),
+            retry: ArcSwap::new(Arc::new(RetryPolicy::default())),
+            allowed_opcodes: ArcSwap::new(Arc::new(OpcodeMask::all())),
+            path_jail: ArcSwapOption::empty(),
+            congestion: Mutex::new(CongestionControl::default()),
+            #[cfg(all(target_os = "linux", feature = "fusedev", not(feature = "virtiofs")))]
+            sessions: sessions::MountSessions::default(),
             phantom: PhantomData,
         }
     }
+
+    /// Resume serving a connection initialized by another Server instance.
+    ///
+    /// The kernel only sends the INIT request once per connection, so a daemon taking over the
+    /// connection from another process calls it with the INIT request received by the previous
+    /// daemon. The filesystem driver is initialized by the capabilities from `init`, and the
+    /// negotiated options are returned. Nothing is sent to the kernel, so the driver should
+    /// agree on the same options as the previous daemon did. The extended options are carried
+    /// by the extension of the INIT request, so none of them is enabled for a resumed connection.
+    pub fn resume(&self, init: &InitIn) -> io::Result<FsOptions> {
+        if init.major != KERNEL_VERSION {
+            error!(
+                "Unsupported fuse protocol version: {}.{}",
+                init.major, init.minor
+            );
+            return Err(io::Error::from_raw_os_error(libc::EPROTO));
+        }
+
+        let capable = FsOptions::from_bits_truncate(init.flags);
+        let enabled = capable & self.fs.init(capable)?;
+        self.vers.store(Arc::new(ServerVersion {
+            major: init.major,
+            minor: init.minor,
+        }));
+        self.opts.store(Arc::new(enabled));
+        self.opts_ext.store(Arc::new(FsOptionsExt::empty()));
+        self.supported_ops
+            .store(self.fs.supported_ops().bits(), Ordering::Relaxed);
+        self.alive.store(true, Ordering::Release);
+
+        Ok(enabled)
+    }
+
+    /// Tear down the filesystem driver once the connection has gone away.
+    ///
+    /// The kernel sends FUSE_DESTROY when the filesystem is unmounted cleanly, but nothing when
+    /// the connection is aborted, e.g. through `/sys/fs/fuse/connections/<N>/abort`. So the
+    /// transport loop should call it when the fuse device reports the session closed.
+    /// [FileSystem::destroy()] is called only once for each FUSE_INIT, no matter whether it's
+    /// triggered by FUSE_DESTROY or by this method.
+    pub fn shutdown(&self) {
+        if self.alive.swap(false, Ordering::AcqRel) {
+            self.fs.destroy();
+        }
+    }
+
+    /// Get the options negotiated with the kernel by the FUSE_INIT request.
+    ///
+    /// An empty set is returned before the session has been initialized.
+    pub fn negotiated_options(&self) -> FsOptions {
+        **self.opts.load()
+    }
+
+    /// Get the extended options negotiated with the kernel by the FUSE_INIT request.
+    ///
+    /// An empty set is returned before the session has been initialized, or if the kernel is
+    /// older than 7.36.
+    pub fn negotiated_options_ext(&self) -> FsOptionsExt {
+        **self.opts_ext.load()
+    }
+
+    /// Coalesce GETATTR requests arriving within `window` into a single call of
+    /// `FileSystem::getattr_batch()`.
+    ///
+    /// The first GETATTR request without a file handle waits for `window` while other worker
+    /// threads add their GETATTR requests to the batch, up to 256 requests, then all of them are
+    /// served by one call to the filesystem driver. Only requests from the same process and user
+    /// are coalesced, since the batch is fetched with the credentials of its first request. It
+    /// trades the latency of single requests for
+    /// less round trips to backends fetching metadata in bulk cheaper, e.g. when stat(2) is
+    /// called on many files without listing their directories. It only helps if the session is
+    /// served by multiple worker threads. A zero `window` disables coalescing, which is the
+    /// default.
+    pub fn set_getattr_coalescing(&self, window: Duration) {
+        self.getattr_batch.set_window(window);
+    }
+
+    /// Get the depth of stacked file systems negotiated by the FUSE_INIT request.
+    ///
+    /// It's the value returned by `FileSystem::max_stack_depth()` if `FsOptionsExt::PASSTHROUGH` has
+    /// been negotiated, otherwise 0.
+    pub fn max_stack_depth(&self) -> u32 {
+        self.max_stack_depth.load(Ordering::Relaxed)
+    }
+
+    /// Get the features of the session, as negotiated by the FUSE_INIT request and reported by
+    /// `FileSystem::supported_ops()`.
+    ///
+    /// The options and operations are empty before the session has been initialized.
+    pub fn capabilities(&self) -> FsCapabilities {
+        let vers = self.vers.load();
+        FsCapabilities {
+            major: vers.major,
+            minor: vers.minor,
+            options: **self.opts.load(),
+            options_ext: **self.opts_ext.load(),
+            ops: SupportedOps::from_bits_truncate(self.supported_ops.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// Set the granularity of timestamps in nanoseconds to be advertised by the FUSE_INIT reply.
+    ///
+    /// The kernel truncates timestamps to the granularity, so a filesystem which only keeps
+    /// timestamps in seconds should set it to 1_000_000_000. It must be a power of ten between 1
+    /// and 1_000_000_000, and takes effect on the next FUSE_INIT request. The default is 1.
+    pub fn set_time_gran(&self, time_gran: u32) -> io::Result<()> {
+        if !(0..=9).any(|exp| 10u32.pow(exp) == time_gran) {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        self.time_gran.store(time_gran, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Set the maximum number of background requests to be advertised by the FUSE_INIT reply.
+    ///
+    /// Background requests are those the kernel doesn't wait for synchronously, such as
+    /// readahead, writeback and asynchronous direct IO. The kernel queues further background
+    /// requests once the limit has been reached, so it should be at least the number of worker
+    /// threads serving the session to keep them all busy under load. The value is clamped to
+    /// `1..=65535`, the kernel may lower it further for unprivileged mounts according to the
+    /// `max_user_bgreq` sysctl. It takes effect on the next FUSE_INIT request, and the default is
+    /// 65535.
+    pub fn set_max_background(&self, max_background: u32) {
+        let max_background = max_background.clamp(1, u16::MAX as u32) as u16;
+        self.max_background.store(max_background, Ordering::Relaxed);
+    }
+
+    /// Set the congestion threshold to be advertised by the FUSE_INIT reply.
+    ///
+    /// Once the number of pending background requests reaches the threshold, the kernel marks
+    /// the connection congested and throttles readahead and writeback, so it should be close to
+    /// `max_background` to avoid premature throttling. The value is clamped to `1..=65535`, and
+    /// to `max_background` when replying the FUSE_INIT request. It takes effect on the next
+    /// FUSE_INIT request, and the default is 3/4 of the default `max_background`.
+    pub fn set_congestion_threshold(&self, threshold: u32) {
+        let threshold = threshold.clamp(1, u16::MAX as u32) as u16;
+        self.congestion_threshold
+            .store(threshold, Ordering::Relaxed);
+    }
+
+    /// Set the timeout for requests being handled by the filesystem driver.
+    ///
+    /// Requests exceeding the timeout are replied with EIO by
+    /// [`reply_timed_out_requests()`](Self::reply_timed_out_requests), so a hung backend
+    /// only fails the affected requests instead of blocking the whole mount. The filesystem
+    /// handler keeps running, and its late reply is dropped, so each request is replied once.
+    /// Entries and handles returned by the late reply are forgotten and released on behalf of
+    /// the kernel, so the filesystem stays balanced with the kernel. A zero timeout disables
+    /// the feature, which is the default.
+    pub fn set_request_timeout(&self, timeout: Duration) {
+        self.inflight.set_timeout(timeout);
+    }
+
+    /// Set the policy to retry idempotent requests failed with a [Retryable] error.
+    ///
+    /// Only LOOKUP, GETATTR and READ are retried, the filesystem driver is invoked again after
+    /// `backoff` until it succeeds, fails with a non-retryable error, or `max_attempts` have
+    /// been made. The last error is then replied to the kernel. Other requests are never retried,
+    /// and [Retryable] errors returned by them are replied as is. The default policy makes a
+    /// single attempt.
+    pub fn set_retry_policy(&self, policy: RetryPolicy) {
+        self.retry.store(Arc::new(policy));
+    }
+
+    /// Limit the requests to be dispatched to the filesystem driver.
+    ///
+    /// Requests with an opcode not in `mask` are replied with ENOSYS without reaching the
+    /// filesystem driver, and the kernel stops sending many of them once it has seen ENOSYS.
+    /// FUSE_INIT, FUSE_DESTROY and the requests without reply, such as FUSE_FORGET, are always
+    /// dispatched to keep the session working. All opcodes are allowed by default.
+    pub fn set_allowed_opcodes(&self, mask: OpcodeMask) {
+        self.allowed_opcodes.store(Arc::new(mask));
+    }
+
+    /// Refuse requests modifying files outside of the directory `jail`.
+    ///
+    /// Before a request creating, removing, renaming, truncating or writing files, or changing
+    /// their attributes, is dispatched, the inodes it modifies are resolved to paths by
+    /// `FileSystem::canonical_path()`, and the request is replied with EACCES if any of them is
+    /// outside of `jail`. For requests changing directory entries, such as FUSE_MKDIR and
+    /// FUSE_RENAME, the directories are checked. FUSE_OPEN is checked when it truncates the
+    /// file, and FUSE_SETUPMAPPING when it maps the file writable. It doesn't replace the
+    /// confinement done by the filesystem driver, but keeps a driver mapping an inode to the
+    /// wrong file from modifying anything outside of the exported tree.
+    ///
+    /// `jail` is a path in the namespace of the file system, as returned by `canonical_path()`,
+    /// e.g. `/` for the whole file system or `/data` for one of its directories. The check fails
+    /// closed: the request is refused if the path can't be resolved, including when the driver
+    /// doesn't implement `canonical_path()` or the inode isn't reachable from the root. The only
+    /// exception is ENOENT, which `canonical_path()` returns for an unlinked inode, for requests
+    /// modifying the file itself, e.g. writing to a file still open, or linking an `O_TMPFILE`
+    /// file: it has no name left anywhere, so modifying it can't change anything visible outside
+    /// of the jail. Requests modifying entries of an unlinked directory are refused.
+    /// Each checked request costs one or two extra `canonical_path()` calls.
+    pub fn set_path_jail(&self, jail: PathBuf) {
+        self.path_jail.store(Some(Arc::new(jail)));
+    }
+
+    /// Set the directory of the connection in the fuse control file system, usually
+    /// `/sys/fs/fuse/connections/<N>` as returned by `FuseSession::connection_dir()`.
+    ///
+    /// It's needed by [`set_congested()`](Self::set_congested), and resets the congestion state.
+    pub fn set_connection_dir(&self, dir: PathBuf) {
+        let mut congestion = self.congestion.lock().unwrap();
+        congestion.dir = Some(dir);
+        congestion.saved_threshold = None;
+    }
+
+    /// Mark the connection congested, or not congested any more, so the kernel throttles
+    /// writeback while the backend can't keep up.
+    ///
+    /// There is no request or notification to set the congestion state, the kernel deems the
+    /// connection congested while the number of pending background requests reaches the
+    /// congestion threshold. So the threshold is set to 0 through the `congestion_threshold`
+    /// file in the directory set by [`set_connection_dir()`](Self::set_connection_dir), and the
+    /// previous value is restored once not congested. While congested, the kernel skips
+    /// background writeback and asynchronous readahead, so dirty pages accumulate until
+    /// `balance_dirty_pages()` throttles the writers. It has some limitations:
+    /// - writeback for `sync(2)`, `fsync(2)` and memory reclaim is still sent, and writes without
+    ///   writeback caching aren't throttled at all;
+    /// - the fuse control file system must be mounted, and the file is only writable by the
+    ///   owner of the mount and by root;
+    /// - the threshold is also changed for everybody else, and a new value advertised by the
+    ///   next FUSE_INIT request, e.g. after reconnecting, overrides it.
+    ///
+    /// Fails with ENOTCONN if the connection directory hasn't been set.
+    pub fn set_congested(&self, congested: bool) -> io::Result<()> {
+        let mut congestion = self.congestion.lock().unwrap();
+        let path = match congestion.dir.as_ref() {
+            Some(dir) => dir.join("congestion_threshold"),
+            None => return Err(io::Error::from_raw_os_error(libc::ENOTCONN)),
+        };
+        if congested && congestion.saved_threshold.is_none() {
+            let threshold = std::fs::read_to_string(&path)?;
+            std::fs::write(&path, "0")?;
+            congestion.saved_threshold = Some(threshold.trim().to_string());
+        } else if !congested {
+            if let Some(threshold) = congestion.saved_threshold.as_ref() {
+                std::fs::write(&path, threshold)?;
+                congestion.saved_threshold = None;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the connection has been marked congested by
+    /// [`set_congested()`](Self::set_congested).
+    pub fn congested(&self) -> bool {
+        self.congestion.lock().unwrap().saved_threshold.is_some()
+    }
+
+    /// Reply EIO to the kernel for all requests exceeding the request timeout.
+    ///
+    /// It's expected to be called periodically by a watchdog thread, with `w` being a writer
+    /// to the transport channel, such as the fuse device file. A failure to reply a request is
+    /// logged and doesn't stop replying the others. Returns the number of requests which have
+    /// been replied.
+    pub fn reply_timed_out_requests(&self, w: &mut dyn io::Write) -> usize {
+        let expired = self.inflight.expire(Instant::now());
+        let mut replied = 0;
+
+        for unique in expired.iter() {
+            let header = OutHeader {
+                len: size_of::<OutHeader>() as u32,
+                error: -libc::EIO,
+                unique: *unique,
+            };
+            warn!("fuse: request {} timed out, reply EIO", unique);
+            match w.write_all(header.as_slice()) {
+                Ok(()) => replied += 1,
+                Err(e) => error!("fuse: failed to reply timed out request {}: {}", unique, e),
+            }
+        }
+
+        replied
+    }
+
+    /// Notify the kernel that the entry `name` of directory `parent`, which refers to `child`,
+    /// has been deleted.
+    ///
+    /// Unlike FUSE_NOTIFY_INVAL_ENTRY, which only drops the dentry from the kernel cache, the
+    /// kernel handles FUSE_NOTIFY_DELETE as if the entry has been unlinked locally if the cached
+    /// dentry still refers to `child`, so inotify/fanotify watchers get IN_DELETE events. The
+    /// notification is written to `w`, such as the fuse device file, and the number of bytes
+    /// written is returned.
+    pub fn notify_delete(
+        &self,
+        w: &mut dyn io::Write,
+        parent: u64,
+        child: u64,
+        name: &CStr,
+    ) -> io::Result<usize> {
+        if self.vers.load().minor < KERNEL_MINOR_VERSION_NOTIFY_DELETE {
+            return Err(io::Error::from_raw_os_error(libc::ENOSYS));
+        }
+        let name = notify_entry_name(name)?;
+        let out = NotifyDeleteOut {
+            parent,
+            child,
+            namelen: (name.len() - 1) as u32,
+            padding: 0,
+        };
+        let buf = encode_notify(NotifyOpcode::Delete, &[out.as_slice(), name]);
+
+        // Notifications must be written to the fuse device in one shot.
+        w.write_all(&buf)?;
+
+        Ok(buf.len())
+    }
+
+    /// Ask the kernel for the cached data of `nodeid` in the range of `size` bytes at `offset`.
+    ///
+    /// The FUSE_NOTIFY_RETRIEVE notification is written to `w`, such as the fuse device file.
+    /// The kernel sends the cached data back by a FUSE_NOTIFY_REPLY request, which is routed to
+    /// the returned [RetrieveReply] by [`handle_message()`](Self::handle_message) instead of the
+    /// filesystem driver. Only the leading pages present in the kernel page cache are returned,
+    /// so the data may be shorter than `size`.
+    pub fn notify_retrieve(
+        &self,
+        w: &mut dyn io::Write,
+        nodeid: u64,
+        offset: u64,
+        size: u32,
+    ) -> io::Result<RetrieveReply> {
+        if self.vers.load().minor < KERNEL_MINOR_VERSION_NOTIFY_RETRIEVE {
+            return Err(io::Error::from_raw_os_error(libc::ENOSYS));
+        }
+
+        let (notify_unique, reply) = self.retrieves.register();
+        let out = Notify_Retrieve_Out {
+            notify_unique,
+            nodeid,
+            offset,
+            size,
+            padding: 0,
+        };
+        let buf = encode_notify(NotifyOpcode::Retrieve, &[out.as_slice()]);
+
+        // The pending retrieve is dropped along with `reply` if failed to send the notification.
+        w.write_all(&buf)?;
+
+        Ok(reply)
+    }
+
+    /// Get the number of requests received for each opcode, as `(opcode, count)` pairs.
+    ///
+    /// Opcodes which have never been received are skipped.
+    pub fn opcode_counters(&self) -> Vec<(u32, u64)> {
+        self.counters.snapshot()
+    }
+
+    /// Append a middleware to the chain wrapping the dispatch of requests.
+    ///
+    /// The `before()` hooks are invoked in the order of registration, and the `after()` hooks
+    /// in the reverse order. Replies are only intercepted by the fusedev transport.
+    pub fn add_middleware(&self, middleware: Arc<dyn ServerMiddleware>) {
+        self.middlewares.rcu(|chain| {
+            let mut chain = Vec::clone(chain);
+            chain.push(middleware.clone());
+            chain
+        });
+    }
+
+    // Invoke `op` for an idempotent request, retrying transient failures according to the
+    // retry policy.
+    fn with_retry<T>(&self, mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+        let policy = **self.retry.load();
+        let mut attempts = 1;
+        loop {
+            match op() {
+                Err(e) if Retryable::is_retryable(&e) && attempts < policy.max_attempts => {
+                    debug!(
+                        "fuse: transient error {:?}, retry attempt {}/{}",
+                        e,
+                        attempts + 1,
+                        policy.max_attempts
+                    );
+                    attempts += 1;
+                    if !policy.backoff.is_zero() {
+                        std::thread::sleep(policy.backoff);
+                    }
+                }
+                res => return res,
+            }
+        }
+    }
+
+    // Run the handler of a request. With the `panic-guard` feature, a panic in the filesystem
+    // driver is caught and logged, and the request is replied with EIO by the `Drop` handler of
+    // `SrvContext` while unwinding.
+    #[cfg(feature = "panic-guard")]
+    fn guard_panic(
+        &self,
+        in_header: &InHeader,
+        handler: impl FnOnce() -> Result<usize>,
+    ) -> Result<usize> {
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(handler)).unwrap_or_else(|payload| {
+            let msg = payload
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("unknown panic payload");
+            error!(
+                "fuse: filesystem panicked serving {:?} request {}: {}",
+                Opcode::from(in_header.opcode),
+                in_header.unique,
+                msg
+            );
+            Ok(0)
+        })
+    }
+
+    #[cfg(not(feature = "panic-guard"))]
+    #[inline]
+    fn guard_panic(
+        &self,
+        _in_header: &InHeader,
+        handler: impl FnOnce() -> Result<usize>,
+    ) -> Result<usize> {
+        handler()
+    }
+
+    // Check that the inodes modified by the request resolve to paths inside of the path jail.
+    fn check_path_jail<S: BitmapSlice>(&self, ctx: &SrvContext<'_, F, D, S>) -> io::Result<()> {
+        let jail = match self.path_jail.load_full() {
+            Some(jail) => jail,
+            None => return Ok(()),
+        };
+        let nodeid = ctx.in_header.nodeid;
+        let mut r = ctx.r.clone();
+        // The inodes to check, and whether the request may modify them once unlinked.
+        let inodes = match ctx.in_header.opcode {
+            x if [
+                Opcode::Setattr as u32,
+                Opcode::Write as u32,
+                Opcode::Setxattr as u32,
+                Opcode::Removexattr as u32,
+                Opcode::Fallocate as u32,
+            ]
+            .contains(&x) =>
+            {
+                vec![(nodeid, true)]
+            }
+            // Entries are modified in the directory.
+            x if [
+                Opcode::Symlink as u32,
+                Opcode::Mknod as u32,
+                Opcode::Mkdir as u32,
+                Opcode::Unlink as u32,
+                Opcode::Rmdir as u32,
+                Opcode::Create as u32,
+                Opcode::Tmpfile as u32,
+            ]
+            .contains(&x) =>
+            {
+                vec![(nodeid, false)]
+            }
+            // The new directory comes first in the request body.
+            x if x == Opcode::Rename as u32 || x == Opcode::Rename2 as u32 => {
+                vec![(nodeid, false), (r.read_obj::<u64>()?, false)]
+            }
+            // So does the inode being linked, which is unlinked for `O_TMPFILE` files.
+            x if x == Opcode::Link as u32 => {
+                vec![(nodeid, false), (r.read_obj::<u64>()?, true)]
+            }
+            x if x == Opcode::CopyFileRange as u32 => {
+                vec![(r.read_obj::<CopyFileRangeIn>()?.nodeid_out, true)]
+            }
+            x if x == Opcode::Open as u32 => {
+                if r.read_obj::<OpenIn>()?.flags & libc::O_TRUNC as u32 == 0 {
+                    return Ok(());
+                }
+                vec![(nodeid, true)]
+            }
+            #[cfg(feature = "virtiofs")]
+            x if x == Opcode::SetupMapping as u32 => {
+                use crate::abi::virtio_fs::{SetupmappingFlags, SetupmappingIn};
+                let flags = r.read_obj::<SetupmappingIn>()?.flags;
+                if !SetupmappingFlags::from_bits_truncate(flags).contains(SetupmappingFlags::WRITE)
+                {
+                    return Ok(());
+                }
+                vec![(nodeid, true)]
+            }
+            _ => return Ok(()),
+        };
+
+        for (inode, unlinked_ok) in inodes {
+            let path = match self.fs.canonical_path(ctx.context(), inode.into()) {
+                Ok(path) => path,
+                // The inode has been unlinked and has no path left to escape through.
+                Err(e) if unlinked_ok && e.raw_os_error() == Some(libc::ENOENT) => continue,
+                Err(e) => {
+                    warn!("fuse: failed to resolve path of inode {}: {}", inode, e);
+                    return Err(io::Error::from_raw_os_error(libc::EACCES));
+                }
+            };
+            let path = Path::new(OsStr::from_bytes(path.as_bytes()));
+            if !path.starts_with(jail.as_path()) {
+                error!(
+                    "fuse: {:?} request {} modifies {:?} outside of {:?}",
+                    Opcode::from(ctx.in_header.opcode),
+                    ctx.in_header.unique,
+                    path,
+                    jail
+                );
+                return Err(io::Error::from_raw_os_error(libc::EACCES));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Server side READDIRPLUS_AUTO heuristic is only enabled when the kernel has agreed on it.
+    fn readdirplus_auto(&self) -> bool {
+        self.opts
+            .load()
+            .contains(FsOptions::DO_READDIRPLUS | FsOptions::READDIRPLUS_AUTO)
+    }
 }
 
 struct ZcReader<'a, S: BitmapSlice = ()>(Reader<'a, S>);
@@ -110,144 +729,3135 @@ impl<'a, S: BitmapSlice> io::Write for ZcWriter<'a, S> {
     }
 }
 
+/// Features of a session, see [Server::capabilities()].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FsCapabilities {
+    /// Major version of the FUSE protocol used by the kernel.
+    pub major: u32,
+    /// Minor version of the FUSE protocol used by the kernel.
+    pub minor: u32,
+    /// Options negotiated with the kernel.
+    pub options: FsOptions,
+    /// Extended options negotiated with the kernel.
+    pub options_ext: FsOptionsExt,
+    /// Optional operations implemented by the file system.
+    pub ops: SupportedOps,
+}
+
 #[allow(dead_code)]
 struct ServerVersion {
     major: u32,
     minor: u32,
 }
 
-struct ServerUtil();
+/// Number of requests received for each opcode.
+struct OpcodeCounters {
+    counters: Vec<AtomicU64>,
+}
 
-impl ServerUtil {
-    fn get_message_body<S: BitmapSlice>(
-        r: &mut Reader<'_, S>,
-        in_header: &InHeader,
-        sub_hdr_sz: usize,
-    ) -> Result<Vec<u8>> {
-        let len = (in_header.len as usize)
-            .checked_sub(size_of::<InHeader>())
-            .and_then(|l| l.checked_sub(sub_hdr_sz))
-            .ok_or(Error::InvalidHeaderLength)?;
+impl Default for OpcodeCounters {
+    fn default() -> Self {
+        OpcodeCounters {
+            counters: (0..Opcode::MaxOpcode as u32)
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+        }
+    }
+}
 
-        // Allocate buffer without zeroing out the content for performance.
-        let mut buf = Vec::<u8>::with_capacity(len);
-        // It's safe because read_exact() is called to fill all the allocated buffer.
-        #[allow(clippy::uninit_vec)]
-        unsafe {
-            buf.set_len(len)
-        };
-        r.read_exact(&mut buf).map_err(Error::DecodeMessage)?;
+impl OpcodeCounters {
+    fn inc(&self, opcode: u32) {
+        if let Some(c) = self.counters.get(opcode as usize) {
+            c.fetch_add(1, Ordering::Relaxed);
+        }
+    }
 
-        Ok(buf)
+    fn snapshot(&self) -> Vec<(u32, u64)> {
+        self.counters
+            .iter()
+            .enumerate()
+            .map(|(op, c)| (op as u32, c.load(Ordering::Relaxed)))
+            .filter(|(_, count)| *count != 0)
+            .collect()
     }
+}
 
-    fn extract_two_cstrs(buf: &[u8]) -> Result<(&CStr, &CStr)> {
-        if let Some(mut pos) = buf.iter().position(|x| *x == 0) {
-            let first = CStr::from_bytes_with_nul(&buf[0..=pos]).map_err(Error::InvalidCString)?;
-            pos += 1;
-            if pos < buf.len() {
-                return Ok((first, bytes_to_cstr(&buf[pos..])?));
+#[derive(Default)]
+struct ReaddirplusState {
+    // Whether the current listing of the directory carries entry attributes.
+    plus: bool,
+    // Whether any child of the directory has been looked up since the last listing.
+    looked_up: bool,
+}
+
+/// Per directory state to implement the READDIRPLUS_AUTO heuristic.
+///
+/// Following the kernel convention, the first listing of a directory is served with full
+/// attributes. Later listings only carry attributes if children of the directory have been
+/// looked up after the previous listing, which means the client is interested in them
+/// (`ls -l`) instead of names only (`ls`). Once the kernel has cached the children, `ls -l` only
+/// revalidates their attributes, so getattr requests of the children count as lookups too.
+#[derive(Default)]
+struct ReaddirplusAuto {
+    dirs: Mutex<HashMap<u64, ReaddirplusState>>,
+    // The directory each inode known to the kernel has been looked up or listed in.
+    parents: Mutex<HashMap<u64, u64>>,
+}
+
+impl ReaddirplusAuto {
+    fn want_plus(&self, dir: u64, offset: u64) -> bool {
+        let mut dirs = self.dirs.lock().unwrap();
+
+        if offset == 0 {
+            let plus = match dirs.get(&dir) {
+                Some(state) => state.looked_up,
+                None => true,
+            };
+            dirs.insert(
+                dir,
+                ReaddirplusState {
+                    plus,
+                    looked_up: false,
+                },
+            );
+            plus
+        } else {
+            match dirs.get(&dir) {
+                Some(state) => state.plus,
+                None => true,
             }
         }
+    }
 
-        Err(Error::DecodeMessage(std::io::Error::from_raw_os_error(
-            libc::EINVAL,
-        )))
+    fn note_lookup(&self, parent: u64) {
+        if let Some(state) = self.dirs.lock().unwrap().get_mut(&parent) {
+            state.looked_up = true;
+        }
+    }
+
+    fn note_child(&self, parent: u64, child: u64) {
+        self.parents.lock().unwrap().insert(child, parent);
+    }
+
+    fn note_getattr(&self, inode: u64) {
+        let parent = self.parents.lock().unwrap().get(&inode).copied();
+        if let Some(parent) = parent {
+            self.note_lookup(parent);
+        }
+    }
+
+    fn forget(&self, inode: u64) {
+        self.dirs.lock().unwrap().remove(&inode);
+        self.parents.lock().unwrap().remove(&inode);
     }
 }
 
-/// Provide concrete backend filesystem a way to catch information/metrics from fuse.
-pub trait MetricsHook {
-    /// `collect()` will be invoked before the real request is processed
-    fn collect(&self, ih: &InHeader);
-    /// `release()` will be invoked after the real request is processed
-    fn release(&self, oh: Option<&OutHeader>);
+/// Map numeric directory offsets seen by the kernel to cursors of `FileSystem::readdir_cursor()`.
+///
+/// Every page returned by the file system is recorded with the cursor used to fetch it, and the
+/// entries of a page are assigned consecutive offsets following the offsets of previous pages.
+/// So listing can be resumed from the middle of a page by fetching it again and skipping the
+/// entries already returned.
+#[derive(Default)]
+struct ReaddirCursors {
+    // Keyed by (nodeid, handle) of the directory.
+    dirs: Mutex<HashMap<(u64, u64), CursorPages>>,
 }
 
-struct SrvContext<'a, F, D: AsyncDrive = AsyncDriver, S: BitmapSlice = ()> {
-    #[allow(dead_code)]
-    drive: Option<D>,
-    in_header: InHeader,
-    context: Context,
-    r: Reader<'a, S>,
-    w: Writer<'a, S>,
-    phantom: PhantomData<F>,
-    phantom2: PhantomData<S>,
+#[derive(Default)]
+struct CursorPages {
+    // Offset before the first entry of the next page.
+    next_offset: u64,
+    // Keyed by offset before the first entry of the page.
+    pages: BTreeMap<u64, CursorPage>,
 }
 
-impl<'a, F: FileSystem, D: AsyncDrive, S: BitmapSlice> SrvContext<'a, F, D, S> {
-    fn new(in_header: InHeader, r: Reader<'a, S>, w: Writer<'a, S>) -> Self {
-        let context = Context::from(&in_header);
+struct CursorPage {
+    cursor: Option<Vec<u8>>,
+    count: u64,
+    next: Option<Vec<u8>>,
+}
 
-        SrvContext {
-            drive: None,
-            in_header,
-            context,
-            r,
-            w,
-            phantom: PhantomData,
-            phantom2: PhantomData,
+/// Position to resume a cursor based directory listing from.
+#[derive(Debug, PartialEq, Eq)]
+enum CursorPosition {
+    /// Fetch the page at `cursor` and skip its first `skip` entries.
+    Page { cursor: Option<Vec<u8>>, skip: u64 },
+    /// The end of the directory has been reached.
+    End,
+}
+
+impl ReaddirCursors {
+    fn position(&self, dir: (u64, u64), offset: u64) -> io::Result<CursorPosition> {
+        let mut dirs = self.dirs.lock().unwrap();
+
+        if offset == 0 {
+            // Listing restarts from the beginning, forget about all the pages.
+            dirs.remove(&dir);
+            return Ok(CursorPosition::Page {
+                cursor: None,
+                skip: 0,
+            });
+        }
+
+        // The directory isn't listed by cursors.
+        let pages = dirs
+            .get(&dir)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOSYS))?;
+        let page = pages
+            .pages
+            .range(..offset)
+            .next_back()
+            .filter(|(base, page)| offset - *base <= page.count);
+        match page {
+            Some((base, page)) if offset - base == page.count => match page.next.as_ref() {
+                Some(next) => Ok(CursorPosition::Page {
+                    cursor: Some(next.clone()),
+                    skip: 0,
+                }),
+                None => Ok(CursorPosition::End),
+            },
+            Some((base, page)) => Ok(CursorPosition::Page {
+                cursor: page.cursor.clone(),
+                skip: offset - base,
+            }),
+            None => Err(io::Error::from_raw_os_error(libc::EINVAL)),
         }
     }
 
-    fn context(&self) -> &Context {
-        &self.context
+    /// Record a page fetched with `cursor` and return the offset before its first entry.
+    fn add_page(
+        &self,
+        dir: (u64, u64),
+        cursor: Option<Vec<u8>>,
+        count: u64,
+        next: Option<Vec<u8>>,
+    ) -> u64 {
+        let mut dirs = self.dirs.lock().unwrap();
+        let pages = dirs.entry(dir).or_default();
+        let base = pages.next_offset;
+
+        // No offset refers to an empty page, the end of the previous page leads to its cursor.
+        if count == 0 {
+            return base;
+        }
+
+        pages.next_offset += count;
+        pages.pages.insert(
+            base,
+            CursorPage {
+                cursor,
+                count,
+                next,
+            },
+        );
+        base
     }
 
-    fn unique(&self) -> u64 {
-        self.in_header.unique
+    fn forget(&self, dir: (u64, u64)) {
+        self.dirs.lock().unwrap().remove(&dir);
     }
+}
 
-    fn nodeid(&self) -> F::Inode {
-        self.in_header.nodeid.into()
+/// Congestion state of the connection, set through the fuse control file system.
+#[derive(Default)]
+struct CongestionControl {
+    // Directory of the connection in the fuse control file system.
+    dir: Option<PathBuf>,
+    // The congestion threshold replaced while congested, to be restored afterwards.
+    saved_threshold: Option<String>,
+}
+
+/// Book keeping of requests being handled, to support request timeout.
+#[derive(Default)]
+struct InflightRequests {
+    // Request timeout in nanoseconds, zero means no timeout.
+    timeout: AtomicU64,
+    // Start time of requests being handled, `None` once replied due to timeout.
+    requests: Mutex<HashMap<u64, Option<Instant>>>,
+}
+
+impl InflightRequests {
+    fn set_timeout(&self, timeout: Duration) {
+        let nanos = u64::try_from(timeout.as_nanos()).unwrap_or(u64::MAX);
+        self.timeout.store(nanos, Ordering::Release);
     }
 
-    fn take_reader(&mut self) -> Reader<'a, S> {
-        let mut reader = Reader::default();
+    fn enabled(&self) -> bool {
+        self.timeout.load(Ordering::Acquire) != 0
+    }
 
-        std::mem::swap(&mut self.r, &mut reader);
+    fn begin(&self, unique: u64) {
+        self.requests
+            .lock()
+            .unwrap()
+            .insert(unique, Some(Instant::now()));
+    }
 
-        reader
+    // Returns false if the request has already been replied due to timeout. Once ended, the
+    // request may be replied without racing with `expire()`.
+    fn end(&self, unique: u64) -> bool {
+        !matches!(self.requests.lock().unwrap().remove(&unique), Some(None))
+    }
+
+    fn expire(&self, now: Instant) -> Vec<u64> {
+        let timeout = Duration::from_nanos(self.timeout.load(Ordering::Acquire));
+        if timeout.is_zero() {
+            return Vec::new();
+        }
+
+        let mut expired = Vec::new();
+        for (unique, start) in self.requests.lock().unwrap().iter_mut() {
+            if let Some(t) = start {
+                if now.saturating_duration_since(*t) >= timeout {
+                    expired.push(*unique);
+                    *start = None;
+                }
+            }
+        }
+
+        expired
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+// Maximum number of GETATTR requests coalesced into a batch.
+const GETATTR_BATCH_MAX: usize = 256;
 
-    #[test]
-    fn test_extract_cstrs() {
-        assert_eq!(
-            ServerUtil::extract_two_cstrs(&[0x1u8, 0x2u8, 0x0, 0x3, 0x0]).unwrap(),
-            (
-                CStr::from_bytes_with_nul(&[0x1u8, 0x2u8, 0x0]).unwrap(),
-                CStr::from_bytes_with_nul(&[0x3u8, 0x0]).unwrap(),
-            )
-        );
-        assert_eq!(
-            ServerUtil::extract_two_cstrs(&[0x1u8, 0x2u8, 0x0, 0x3, 0x0, 0x0]).unwrap(),
-            (
-                CStr::from_bytes_with_nul(&[0x1u8, 0x2u8, 0x0]).unwrap(),
-                CStr::from_bytes_with_nul(&[0x3u8, 0x0]).unwrap(),
-            )
-        );
-        assert_eq!(
-            ServerUtil::extract_two_cstrs(&[0x1u8, 0x2u8, 0x0, 0x3, 0x0, 0x4]).unwrap(),
-            (
-                CStr::from_bytes_with_nul(&[0x1u8, 0x2u8, 0x0]).unwrap(),
-                CStr::from_bytes_with_nul(&[0x3u8, 0x0]).unwrap(),
-            )
-        );
-        assert_eq!(
-            ServerUtil::extract_two_cstrs(&[0x1u8, 0x2u8, 0x0, 0x0, 0x4]).unwrap(),
-            (
-                CStr::from_bytes_with_nul(&[0x1u8, 0x2u8, 0x0]).unwrap(),
-                CStr::from_bytes_with_nul(&[0x0]).unwrap(),
-            )
-        );
+type AttrResult = io::Result<(stat64, Duration)>;
 
-        ServerUtil::extract_two_cstrs(&[0x1u8, 0x2u8, 0x0, 0x3]).unwrap_err();
-        ServerUtil::extract_two_cstrs(&[0x1u8, 0x2u8, 0x0]).unwrap_err();
-        ServerUtil::extract_two_cstrs(&[0x1u8, 0x2u8]).unwrap_err();
+#[derive(Default)]
+struct GetattrBatchState {
+    inodes: Vec<u64>,
+    results: Vec<Option<AttrResult>>,
+    complete: bool,
+}
+
+/// GETATTR requests served by a single `FileSystem::getattr_batch()` call.
+#[derive(Default)]
+struct GetattrBatch {
+    state: Mutex<GetattrBatchState>,
+    complete: Condvar,
+}
+
+impl GetattrBatch {
+    // Wake up all requests of the batch, requests without a result get EIO.
+    fn complete(&self, results: Vec<AttrResult>) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.results = results.into_iter().map(Some).collect();
+        state.complete = true;
+        self.complete.notify_all();
+    }
+
+    fn wait(&self, index: usize) -> AttrResult {
+        let mut state = self.state.lock().unwrap();
+        while !state.complete {
+            state = self.complete.wait(state).unwrap();
+        }
+        state
+            .results
+            .get_mut(index)
+            .and_then(Option::take)
+            .unwrap_or_else(|| Err(io::Error::from_raw_os_error(libc::EIO)))
+    }
+}
+
+// Completes the batch even if the filesystem driver panics, so the other requests don't wait
+// forever.
+struct GetattrBatchGuard<'a>(&'a GetattrBatch);
+
+impl Drop for GetattrBatchGuard<'_> {
+    fn drop(&mut self) {
+        let complete = self
+            .0
+            .state
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .complete;
+        if !complete {
+            self.0.complete(Vec::new());
+        }
+    }
+}
+
+/// Coalescing of GETATTR requests arriving within a time window.
+#[derive(Default)]
+struct GetattrCoalescer {
+    // Coalescing window in nanoseconds, zero means no coalescing.
+    window: AtomicU64,
+    // The batches accepting new requests, by the uid, gid and pid of the requests, as the whole
+    // batch is fetched with the `Context` of its first request.
+    pending: Mutex<HashMap<(u32, u32, i32), Arc<GetattrBatch>>>,
+}
+
+impl GetattrCoalescer {
+    fn set_window(&self, window: Duration) {
+        let nanos = u64::try_from(window.as_nanos()).unwrap_or(u64::MAX);
+        self.window.store(nanos, Ordering::Release);
+    }
+
+    fn enabled(&self) -> bool {
+        self.window.load(Ordering::Acquire) != 0
+    }
+
+    // Get the attributes of `inode` by joining the pending batch of requests from the same
+    // process and user as `ctx`, or by starting a new batch and fetching the attributes of the
+    // whole batch with `fetch` once the window has passed.
+    fn getattr(
+        &self,
+        ctx: &Context,
+        inode: u64,
+        fetch: impl FnOnce(Vec<u64>) -> Vec<AttrResult>,
+    ) -> AttrResult {
+        let key = (ctx.uid, ctx.gid, ctx.pid);
+        let (batch, index) = {
+            let mut pending = self.pending.lock().unwrap();
+            match pending.get(&key) {
+                Some(batch) => {
+                    let batch = batch.clone();
+                    let mut state = batch.state.lock().unwrap();
+                    state.inodes.push(inode);
+                    let index = state.inodes.len() - 1;
+                    // Let following requests start a new batch once it's full.
+                    if state.inodes.len() >= GETATTR_BATCH_MAX {
+                        pending.remove(&key);
+                    }
+                    drop(state);
+                    (batch, index)
+                }
+                None => {
+                    let batch = Arc::new(GetattrBatch::default());
+                    batch.state.lock().unwrap().inodes.push(inode);
+                    pending.insert(key, batch.clone());
+                    (batch, 0)
+                }
+            }
+        };
+
+        if index == 0 {
+            std::thread::sleep(Duration::from_nanos(self.window.load(Ordering::Acquire)));
+            {
+                let mut pending = self.pending.lock().unwrap();
+                if matches!(pending.get(&key), Some(b) if Arc::ptr_eq(b, &batch)) {
+                    pending.remove(&key);
+                }
+            }
+            // No more requests join the batch once it's not pending any more.
+            let inodes = std::mem::take(&mut batch.state.lock().unwrap().inodes);
+            let _guard = GetattrBatchGuard(&batch);
+            batch.complete(fetch(inodes));
+        }
+
+        batch.wait(index)
+    }
+}
+
+// Number of 64-bit words to cover all opcodes, up to CUSE_INIT.
+const OPCODE_MASK_WORDS: usize = 4096 / 64 + 1;
+
+/// A set of request opcodes, used by [`Server::set_allowed_opcodes()`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OpcodeMask {
+    bits: [u64; OPCODE_MASK_WORDS],
+}
+
+impl OpcodeMask {
+    /// Create a mask containing all opcodes.
+    pub fn all() -> Self {
+        OpcodeMask {
+            bits: [u64::MAX; OPCODE_MASK_WORDS],
+        }
+    }
+
+    /// Create a mask containing no opcode.
+    pub fn empty() -> Self {
+        OpcodeMask {
+            bits: [0; OPCODE_MASK_WORDS],
+        }
+    }
+
+    /// Add `opcode` to the mask.
+    pub fn allow(mut self, opcode: Opcode) -> Self {
+        let op = opcode as usize;
+        if let Some(word) = self.bits.get_mut(op / 64) {
+            *word |= 1 << (op % 64);
+        }
+        self
+    }
+
+    /// Remove `opcode` from the mask.
+    pub fn deny(mut self, opcode: Opcode) -> Self {
+        let op = opcode as usize;
+        if let Some(word) = self.bits.get_mut(op / 64) {
+            *word &= !(1 << (op % 64));
+        }
+        self
+    }
+
+    /// Check whether the raw `opcode` of a request is in the mask.
+    pub fn contains(&self, opcode: u32) -> bool {
+        let op = opcode as usize;
+        self.bits
+            .get(op / 64)
+            .map(|word| word & (1 << (op % 64)) != 0)
+            .unwrap_or(false)
+    }
+
+    // INIT and DESTROY manage the session, and requests without reply can't be answered.
+    fn allows(&self, opcode: u32) -> bool {
+        self.contains(opcode)
+            || [
+                Opcode::Init as u32,
+                Opcode::Destroy as u32,
+                Opcode::Forget as u32,
+                Opcode::BatchForget as u32,
+                Opcode::Interrupt as u32,
+                Opcode::NotifyReply as u32,
+            ]
+            .contains(&opcode)
+    }
+}
+
+impl Default for OpcodeMask {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+/// Policy to retry idempotent requests failed with a [Retryable] error.
+///
+/// It's installed by [`Server::set_retry_policy()`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Maximum number of times the filesystem driver is invoked for a request, including the
+    /// first one. Zero and one disable retrying.
+    pub max_attempts: u32,
+    /// Delay before each retry.
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            backoff: Duration::ZERO,
+        }
+    }
+}
+
+/// Marker of transient errors, which may go away if the request is retried.
+///
+/// Filesystem drivers return `Retryable::new(err)` to ask the server to retry idempotent requests
+/// according to the [RetryPolicy]. The wrapped error is replied to the kernel once the retries
+/// have been exhausted.
+#[derive(Debug)]
+pub struct Retryable(io::Error);
+
+impl Retryable {
+    /// Wrap `err` into an `io::Error` marked as retryable.
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(err: io::Error) -> io::Error {
+        io::Error::new(err.kind(), Retryable(err))
+    }
+
+    /// Check whether `err` has been marked as retryable.
+    pub fn is_retryable(err: &io::Error) -> bool {
+        err.get_ref().map(|e| e.is::<Retryable>()).unwrap_or(false)
+    }
+
+    /// Strip the retryable mark off `err`, if any.
+    pub fn into_inner(err: io::Error) -> io::Error {
+        if !Self::is_retryable(&err) {
+            return err;
+        }
+        // The downcast can't fail, the error has been checked above.
+        err.into_inner()
+            .and_then(|e| e.downcast::<Retryable>().ok())
+            .map(|e| e.0)
+            .unwrap()
+    }
+}
+
+impl std::fmt::Display for Retryable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "transient error: {}", self.0)
+    }
+}
+
+impl std::error::Error for Retryable {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+struct ServerUtil();
+
+impl ServerUtil {
+    // Split the extensions appended by the kernel off the request, so the request body could be
+    // decoded as usual. The caller keeps the extensions for `Context::extensions` of the request.
+    fn take_extensions<S: BitmapSlice>(
+        in_header: &mut InHeader,
+        r: &mut Reader<'_, S>,
+    ) -> Result<Extensions> {
+        let ext_len = in_header.total_extlen as usize * 8;
+        if ext_len == 0 {
+            return Ok(Extensions::default());
+        }
+
+        let body_len = (in_header.len as usize)
+            .checked_sub(size_of::<InHeader>())
+            .and_then(|l| l.checked_sub(ext_len))
+            .ok_or_else(|| {
+                ServerUtil::invalid_field("total_extlen", in_header.total_extlen as u64)
+            })?;
+        Self::check_available(r, body_len + ext_len)?;
+        let mut r = r
+            .split_at(body_len)
+            .map_err(|_| Error::InvalidHeaderLength)?;
+        let mut buf = vec![0u8; ext_len];
+        r.read_exact(&mut buf).map_err(Error::DecodeMessage)?;
+
+        let extensions = Self::extract_extensions(&buf)?;
+        in_header.len -= ext_len as u32;
+
+        Ok(extensions)
+    }
+
+    fn read_obj<T: ByteValued, S: BitmapSlice>(r: &mut Reader<'_, S>) -> Result<T> {
+        Self::check_available(r, size_of::<T>())?;
+        r.read_obj().map_err(Error::DecodeMessage)
+    }
+
+    fn check_available<S: BitmapSlice>(r: &Reader<'_, S>, needed: usize) -> Result<()> {
+        let got = r.available_bytes();
+        if got < needed {
+            return Err(Error::InvalidMessage(DecodeError::Truncated {
+                needed,
+                got,
+            }));
+        }
+        Ok(())
+    }
+
+    fn get_message_body<S: BitmapSlice>(
+        r: &mut Reader<'_, S>,
+        in_header: &InHeader,
+        sub_hdr_sz: usize,
+    ) -> Result<Vec<u8>> {
+        let len = (in_header.len as usize)
+            .checked_sub(size_of::<InHeader>())
+            .and_then(|l| l.checked_sub(sub_hdr_sz))
+            .ok_or(Error::InvalidMessage(DecodeError::InvalidField {
+                name: "len",
+                value: in_header.len as u64,
+            }))?;
+        Self::check_available(r, len)?;
+
+        // Allocate buffer without zeroing out the content for performance.
+        let mut buf = Vec::<u8>::with_capacity(len);
+        // It's safe because read_exact() is called to fill all the allocated buffer.
+        #[allow(clippy::uninit_vec)]
+        unsafe {
+            buf.set_len(len)
+        };
+        r.read_exact(&mut buf).map_err(Error::DecodeMessage)?;
+
+        Ok(buf)
+    }
+
+    fn extract_two_cstrs(buf: &[u8]) -> Result<(&CStr, &CStr)> {
+        if let Some(mut pos) = buf.iter().position(|x| *x == 0) {
+            let first = CStr::from_bytes_with_nul(&buf[0..=pos]).map_err(Error::InvalidCString)?;
+            pos += 1;
+            if pos < buf.len() {
+                return Ok((first, bytes_to_cstr(&buf[pos..])?));
+            }
+        }
+
+        Err(Error::DecodeMessage(std::io::Error::from_raw_os_error(
+            libc::EINVAL,
+        )))
+    }
+
+    // Decode the security contexts following the name(s) of create, mkdir, mknod and symlink
+    // requests. Each context is aligned to 8 bytes.
+    fn extract_secctx(buf: &[u8]) -> Result<Vec<SecContext>> {
+        let header: SecctxHeader = Self::read_unaligned(buf, 0)?;
+        let size = header.size as usize;
+        if size < size_of::<SecctxHeader>() || size > buf.len() {
+            return Err(Self::invalid_field("fuse_secctx_header.size", size as u64));
+        }
+
+        let buf = &buf[..size];
+        let mut pos = size_of::<SecctxHeader>();
+        let mut contexts = Vec::with_capacity(header.nr_secctx as usize);
+        for _ in 0..header.nr_secctx {
+            let start = pos;
+            let secctx: Secctx = Self::read_unaligned(buf, pos)?;
+            pos += size_of::<Secctx>();
+            let name = bytes_to_cstr(&buf[pos..])?;
+            pos += name.to_bytes_with_nul().len();
+            let value = buf
+                .get(pos..pos + secctx.size as usize)
+                .ok_or_else(|| Self::invalid_field("fuse_secctx.size", secctx.size as u64))?
+                .to_vec();
+            pos = start + ((pos + value.len() - start + 7) & !7);
+
+            contexts.push(SecContext {
+                name: name.to_owned(),
+                value,
+            });
+        }
+
+        Ok(contexts)
+    }
+
+    // Decode the extensions appended to a request, each of them starts with an `ExtHeader`
+    // and is aligned to 8 bytes.
+    fn extract_extensions(buf: &[u8]) -> Result<Extensions> {
+        let mut extensions = Vec::new();
+        let mut pos = 0;
+
+        while pos < buf.len() {
+            let header: ExtHeader = Self::read_unaligned(buf, pos)?;
+            let size = header.size as usize;
+            if size < size_of::<ExtHeader>() || size > buf.len() - pos {
+                return Err(Self::invalid_field("fuse_ext_header.size", size as u64));
+            }
+            if size & 7 != 0 {
+                return Err(Error::InvalidMessage(DecodeError::BadAlignment {
+                    size,
+                    align: 8,
+                }));
+            }
+            let ext = &buf[pos..pos + size];
+            let payload = &ext[size_of::<ExtHeader>()..];
+
+            let extension = match header.type_ {
+                // The extension header is the header of the security contexts.
+                t if t <= FUSE_MAX_NR_SECCTX => Extension::SecCtx(Self::extract_secctx(ext)?),
+                FUSE_EXT_GROUPS => {
+                    let groups: SuppGroups = Self::read_unaligned(payload, 0)?;
+                    let len = groups.nr_groups as usize * size_of::<u32>();
+                    let ids = payload
+                        .get(size_of::<SuppGroups>()..size_of::<SuppGroups>() + len)
+                        .ok_or_else(|| Self::invalid_field("nr_groups", groups.nr_groups as u64))?;
+                    Extension::SuppGroups(
+                        ids.chunks_exact(size_of::<u32>())
+                            .map(|id| u32::from_ne_bytes([id[0], id[1], id[2], id[3]]))
+                            .collect(),
+                    )
+                }
+                t => Extension::Unknown(t, payload.to_vec()),
+            };
+            extensions.push(extension);
+            pos += size;
+        }
+
+        Ok(Extensions::new(extensions))
+    }
+
+    fn invalid_field(name: &'static str, value: u64) -> Error {
+        Error::InvalidMessage(DecodeError::InvalidField { name, value })
+    }
+
+    // Check that `buf` holds at least `needed` bytes.
+    fn check_len(buf: &[u8], needed: usize) -> Result<()> {
+        if buf.len() < needed {
+            return Err(Error::InvalidMessage(DecodeError::Truncated {
+                needed,
+                got: buf.len(),
+            }));
+        }
+        Ok(())
+    }
+
+    // The buffer may not be aligned for `T`.
+    fn read_unaligned<T: ByteValued + Default>(buf: &[u8], pos: usize) -> Result<T> {
+        let mut obj = T::default();
+        Self::check_len(buf, pos + size_of::<T>())?;
+        obj.as_mut_slice()
+            .copy_from_slice(&buf[pos..pos + size_of::<T>()]);
+        Ok(obj)
+    }
+}
+
+struct RetrieveState {
+    result: Option<io::Result<Vec<u8>>>,
+    waker: Option<Waker>,
+}
+
+struct RetrieveSlot {
+    state: Mutex<RetrieveState>,
+    cond: Condvar,
+}
+
+/// The data to be sent back by the kernel for a FUSE_NOTIFY_RETRIEVE notification.
+///
+/// It's a future resolved once the FUSE_NOTIFY_REPLY request has been handled by the server,
+/// and [wait()](RetrieveReply::wait) blocks the calling thread for the data instead.
+pub struct RetrieveReply {
+    unique: u64,
+    slot: Arc<RetrieveSlot>,
+    pending: Arc<Mutex<HashMap<u64, Arc<RetrieveSlot>>>>,
+}
+
+impl RetrieveReply {
+    /// Block until the data has been received, or `timeout` has expired.
+    ///
+    /// Returns `ETIMEDOUT` if no reply has been received in time.
+    pub fn wait(self, timeout: Duration) -> io::Result<Vec<u8>> {
+        let state = self.slot.state.lock().unwrap();
+        let (mut state, _) = self
+            .slot
+            .cond
+            .wait_timeout_while(state, timeout, |s| s.result.is_none())
+            .unwrap();
+
+        state
+            .result
+            .take()
+            .unwrap_or_else(|| Err(io::Error::from_raw_os_error(libc::ETIMEDOUT)))
+    }
+}
+
+impl Drop for RetrieveReply {
+    fn drop(&mut self) {
+        // Nobody is interested in the reply anymore, let it be handled as an unknown one.
+        self.pending.lock().unwrap().remove(&self.unique);
+    }
+}
+
+impl Future for RetrieveReply {
+    type Output = io::Result<Vec<u8>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Self::Output> {
+        let mut state = self.slot.state.lock().unwrap();
+
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+// Pending FUSE_NOTIFY_RETRIEVE notifications indexed by their `notify_unique`.
+struct RetrieveTable {
+    pending: Arc<Mutex<HashMap<u64, Arc<RetrieveSlot>>>>,
+    next_unique: AtomicU64,
+}
+
+impl Default for RetrieveTable {
+    fn default() -> Self {
+        RetrieveTable {
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            // Zero is reserved for notifications which don't expect a reply.
+            next_unique: AtomicU64::new(1),
+        }
+    }
+}
+
+impl RetrieveTable {
+    fn register(&self) -> (u64, RetrieveReply) {
+        let unique = self.next_unique.fetch_add(1, Ordering::Relaxed);
+        let slot = Arc::new(RetrieveSlot {
+            state: Mutex::new(RetrieveState {
+                result: None,
+                waker: None,
+            }),
+            cond: Condvar::new(),
+        });
+        self.pending.lock().unwrap().insert(unique, slot.clone());
+
+        let reply = RetrieveReply {
+            unique,
+            slot,
+            pending: self.pending.clone(),
+        };
+
+        (unique, reply)
+    }
+
+    fn is_pending(&self, unique: u64) -> bool {
+        self.pending.lock().unwrap().contains_key(&unique)
+    }
+
+    // Returns false if there's no pending retrieve for `unique`.
+    fn complete(&self, unique: u64, result: io::Result<Vec<u8>>) -> bool {
+        let slot = match self.pending.lock().unwrap().remove(&unique) {
+            Some(slot) => slot,
+            None => return false,
+        };
+
+        let mut state = slot.state.lock().unwrap();
+        state.result = Some(result);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+        slot.cond.notify_all();
+
+        true
+    }
+}
+
+/// Provide concrete backend filesystem a way to catch information/metrics from fuse.
+pub trait MetricsHook {
+    /// `collect()` will be invoked before the real request is processed
+    fn collect(&self, ih: &InHeader);
+    /// `release()` will be invoked after the real request is processed
+    fn release(&self, oh: Option<&OutHeader>);
+}
+
+/// Hooks to inspect and alter requests and replies around the dispatch of a Fuse server.
+///
+/// Middlewares are registered by [`Server::add_middleware()`].
+pub trait ServerMiddleware: Send + Sync {
+    /// Invoked before a request is dispatched to the filesystem driver.
+    ///
+    /// Returning an error replies the error to the kernel without dispatching the request, and
+    /// the `after()` hooks are skipped.
+    fn before(&self, _in_header: &InHeader) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Invoked with the complete reply, starting with the `OutHeader`, before it's sent.
+    ///
+    /// The reply may be changed in place or resized, the `len` field of the `OutHeader` is
+    /// updated to match the final reply. Requests without reply, such as FORGET, never reach
+    /// the hook.
+    fn after(&self, _in_header: &InHeader, _reply: &mut Vec<u8>) {}
+}
+
+// Whether the kernel expects a reply for the request.
+fn expects_reply(opcode: u32) -> bool {
+    ![
+        Opcode::Forget as u32,
+        Opcode::BatchForget as u32,
+        Opcode::Interrupt as u32,
+        Opcode::Destroy as u32,
+        Opcode::NotifyReply as u32,
+    ]
+    .contains(&opcode)
+}
+
+/// Encode a notification of `code` to the kernel, made of an `OutHeader` with zero `unique`
+/// followed by `args`.
+///
+/// The kernel handles each write to the fuse device as a whole message, so the notification
+/// is meant to be written in one shot.
+pub(crate) fn encode_notify(code: NotifyOpcode, args: &[&[u8]]) -> Vec<u8> {
+    let len = args
+        .iter()
+        .fold(size_of::<OutHeader>(), |acc, arg| acc + arg.len());
+    let header = OutHeader {
+        len: len as u32,
+        error: code as i32,
+        unique: 0,
+    };
+    let mut buf = Vec::with_capacity(len);
+    buf.extend_from_slice(header.as_slice());
+    for arg in args {
+        buf.extend_from_slice(arg);
+    }
+    buf
+}
+
+/// Get the bytes of `name`, including the trailing nul, to be sent by an entry notification.
+///
+/// The kernel bounds the name by FUSE_NAME_MAX, not by the negotiated `max_write`, and rejects
+/// longer ones with `ENAMETOOLONG`, so they fail the same way without being sent.
+pub(crate) fn notify_entry_name(name: &CStr) -> io::Result<&[u8]> {
+    let name = name.to_bytes_with_nul();
+    if name.len() > FUSE_NAME_MAX + 1 {
+        return Err(io::Error::from_raw_os_error(libc::ENAMETOOLONG));
+    }
+    Ok(name)
+}
+
+// Check that the `len` field of the header of a reply gathered from several parts matches the
+// `total` size of the parts. The kernel fails the request with EINVAL on mismatch, which is hard
+// to trace back to the reply, e.g. a `ZeroCopyWriter` reporting a wrong number of bytes.
+#[cfg(all(debug_assertions, feature = "check-out-header"))]
+fn check_out_header(header: &OutHeader, total: usize) {
+    if header.len as usize != total {
+        panic!(
+            "fuse: out_header.len {} doesn't match the {} bytes of the reply",
+            header.len, total
+        );
+    }
+}
+
+#[cfg(not(all(debug_assertions, feature = "check-out-header")))]
+#[inline]
+fn check_out_header(_header: &OutHeader, _total: usize) {}
+
+struct SrvContext<'a, F, D: AsyncDrive = AsyncDriver, S: BitmapSlice = ()> {
+    #[allow(dead_code)]
+    drive: Option<D>,
+    in_header: InHeader,
+    context: Context<'a>,
+    r: Reader<'a, S>,
+    w: Writer<'a, S>,
+    // Set if the request is tracked for timeout, until its reply has been claimed.
+    inflight: Option<&'a InflightRequests>,
+    phantom: PhantomData<F>,
+    phantom2: PhantomData<S>,
+}
+
+impl<'a, F: FileSystem, D: AsyncDrive, S: BitmapSlice> SrvContext<'a, F, D, S> {
+    fn new(in_header: InHeader, r: Reader<'a, S>, w: Writer<'a, S>) -> Self {
+        let context = Context::from(&in_header);
+
+        SrvContext {
+            drive: None,
+            in_header,
+            context,
+            r,
+            w,
+            inflight: None,
+            phantom: PhantomData,
+            phantom2: PhantomData,
+        }
+    }
+
+    fn context(&self) -> &Context<'a> {
+        &self.context
+    }
+
+    fn read_obj<T: ByteValued>(&mut self) -> Result<T> {
+        let res = ServerUtil::read_obj(&mut self.r);
+        self.decoded(res)
+    }
+
+    fn message_body(&mut self, sub_hdr_sz: usize) -> Result<Vec<u8>> {
+        let res = ServerUtil::get_message_body(&mut self.r, &self.in_header, sub_hdr_sz);
+        self.decoded(res)
+    }
+
+    // Reply the error of a request which failed to decode, so the kernel doesn't wait for it.
+    fn decoded<T>(&mut self, res: Result<T>) -> Result<T> {
+        match res {
+            Err(Error::InvalidMessage(e)) => Err(self.decode_failed(e)),
+            res => res,
+        }
+    }
+
+    fn decode_failed(&mut self, e: DecodeError) -> Error {
+        error!(
+            "fuse: failed to decode {:?} request {}: {}",
+            Opcode::from(self.in_header.opcode),
+            self.in_header.unique,
+            e
+        );
+        if expects_reply(self.in_header.opcode) && self.w.bytes_written() == 0 {
+            if let Err(err) = self.reply_error_explicit(io::Error::from_raw_os_error(e.errno())) {
+                return err;
+            }
+        }
+        Error::InvalidMessage(e)
+    }
+
+    fn unique(&self) -> u64 {
+        self.in_header.unique
+    }
+
+    fn nodeid(&self) -> F::Inode {
+        self.in_header.nodeid.into()
+    }
+
+    fn take_reader(&mut self) -> Reader<'a, S> {
+        let mut reader = Reader::default();
+
+        std::mem::swap(&mut self.r, &mut reader);
+
+        reader
+    }
+}
+
+impl<'a, F, D: AsyncDrive, S: BitmapSlice> SrvContext<'a, F, D, S> {
+    // Claim the reply of a request tracked for timeout, so it's not replied by
+    // `Server::reply_timed_out_requests()` too. Returns false if the request has already been
+    // replied due to timeout, then the reply must be dropped and its side effects undone.
+    fn claim_reply(&mut self) -> bool {
+        match self.inflight.take() {
+            Some(inflight) => inflight.end(self.in_header.unique),
+            None => true,
+        }
+    }
+}
+
+// Reply EIO if the handler of a request panics before replying, so the kernel doesn't wait for
+// the request forever.
+#[cfg(feature = "panic-guard")]
+impl<'a, F, D: AsyncDrive, S: BitmapSlice> Drop for SrvContext<'a, F, D, S> {
+    fn drop(&mut self) {
+        if !std::thread::panicking()
+            || self.w.bytes_written() != 0
+            || !expects_reply(self.in_header.opcode)
+            || !self.claim_reply()
+        {
+            return;
+        }
+
+        let header = OutHeader {
+            len: size_of::<OutHeader>() as u32,
+            error: -libc::EIO,
+            unique: self.in_header.unique,
+        };
+        if let Err(e) =
+            io::Write::write_all(&mut self.w, header.as_slice()).and_then(|_| self.w.commit(None))
+        {
+            error!("fuse: failed to reply EIO for panicked request: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::filesystem::Entry;
+    use std::ffi::CString;
+
+    #[test]
+    fn test_extract_cstrs() {
+        assert_eq!(
+            ServerUtil::extract_two_cstrs(&[0x1u8, 0x2u8, 0x0, 0x3, 0x0]).unwrap(),
+            (
+                CStr::from_bytes_with_nul(&[0x1u8, 0x2u8, 0x0]).unwrap(),
+                CStr::from_bytes_with_nul(&[0x3u8, 0x0]).unwrap(),
+            )
+        );
+        assert_eq!(
+            ServerUtil::extract_two_cstrs(&[0x1u8, 0x2u8, 0x0, 0x3, 0x0, 0x0]).unwrap(),
+            (
+                CStr::from_bytes_with_nul(&[0x1u8, 0x2u8, 0x0]).unwrap(),
+                CStr::from_bytes_with_nul(&[0x3u8, 0x0]).unwrap(),
+            )
+        );
+        assert_eq!(
+            ServerUtil::extract_two_cstrs(&[0x1u8, 0x2u8, 0x0, 0x3, 0x0, 0x4]).unwrap(),
+            (
+                CStr::from_bytes_with_nul(&[0x1u8, 0x2u8, 0x0]).unwrap(),
+                CStr::from_bytes_with_nul(&[0x3u8, 0x0]).unwrap(),
+            )
+        );
+        assert_eq!(
+            ServerUtil::extract_two_cstrs(&[0x1u8, 0x2u8, 0x0, 0x0, 0x4]).unwrap(),
+            (
+                CStr::from_bytes_with_nul(&[0x1u8, 0x2u8, 0x0]).unwrap(),
+                CStr::from_bytes_with_nul(&[0x0]).unwrap(),
+            )
+        );
+
+        ServerUtil::extract_two_cstrs(&[0x1u8, 0x2u8, 0x0, 0x3]).unwrap_err();
+        ServerUtil::extract_two_cstrs(&[0x1u8, 0x2u8, 0x0]).unwrap_err();
+        ServerUtil::extract_two_cstrs(&[0x1u8, 0x2u8]).unwrap_err();
+    }
+
+    #[test]
+    fn test_readdirplus_auto() {
+        let rdplus = ReaddirplusAuto::default();
+
+        // The first listing always carries attributes, and continuations follow it.
+        assert!(rdplus.want_plus(1, 0));
+        assert!(rdplus.want_plus(1, 10));
+        // No lookup happened, so fall back to names only.
+        assert!(!rdplus.want_plus(1, 0));
+        assert!(!rdplus.want_plus(1, 10));
+        // A lookup of a child turns attributes back on for the next listing.
+        rdplus.note_lookup(1);
+        rdplus.note_lookup(2);
+        assert!(rdplus.want_plus(1, 0));
+        assert!(rdplus.want_plus(2, 0));
+
+        rdplus.forget(1);
+        assert!(rdplus.want_plus(1, 0));
+
+        // So does a getattr of a child listed or looked up before.
+        assert!(!rdplus.want_plus(1, 0));
+        rdplus.note_getattr(3);
+        assert!(!rdplus.want_plus(1, 0));
+        rdplus.note_child(1, 3);
+        rdplus.note_getattr(3);
+        assert!(rdplus.want_plus(1, 0));
+        rdplus.forget(3);
+        rdplus.note_getattr(3);
+        assert!(!rdplus.want_plus(1, 0));
+    }
+
+    #[test]
+    fn test_reply_timed_out_requests() {
+        let server: Server<crate::api::Vfs> = Server::new(crate::api::Vfs::default());
+        let mut buf = Vec::new();
+
+        // Nothing is tracked without a timeout.
+        server.inflight.begin(1);
+        assert_eq!(server.reply_timed_out_requests(&mut buf), 0);
+        assert!(server.inflight.end(1));
+
+        server.set_request_timeout(Duration::from_millis(1));
+        server.inflight.begin(2);
+        std::thread::sleep(Duration::from_millis(2));
+        assert_eq!(server.reply_timed_out_requests(&mut buf), 1);
+        // Replied requests are not replied again.
+        assert_eq!(server.reply_timed_out_requests(&mut buf), 0);
+        assert!(!server.inflight.end(2));
+        assert!(server.inflight.end(2));
+
+        let header = OutHeader::from_slice(&buf).unwrap();
+        assert_eq!(header.len as usize, size_of::<OutHeader>());
+        assert_eq!(header.error, -libc::EIO);
+        assert_eq!(header.unique, 2);
+
+        // A failed reply doesn't stop replying other requests.
+        struct FailingWriter(usize);
+        impl io::Write for FailingWriter {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0 += 1;
+                if self.0 == 1 {
+                    return Err(io::Error::from_raw_os_error(libc::EIO));
+                }
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        server.inflight.begin(3);
+        server.inflight.begin(4);
+        std::thread::sleep(Duration::from_millis(2));
+        let mut w = FailingWriter(0);
+        assert_eq!(server.reply_timed_out_requests(&mut w), 1);
+        assert_eq!(w.0, 2);
+    }
+
+    struct SlowFs {
+        forgotten: Mutex<Vec<(u64, u64)>>,
+        released: Mutex<Vec<u64>>,
+    }
+
+    impl FileSystem for SlowFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn lookup(&self, _ctx: &Context, _parent: u64, _name: &CStr) -> io::Result<Entry> {
+            std::thread::sleep(Duration::from_millis(50));
+            Ok(Entry {
+                inode: 5,
+                ..Default::default()
+            })
+        }
+
+        fn forget(&self, _ctx: &Context, inode: u64, count: u64) {
+            self.forgotten.lock().unwrap().push((inode, count));
+        }
+
+        fn create(
+            &self,
+            _ctx: &Context,
+            _parent: u64,
+            _name: &CStr,
+            _args: CreateIn,
+        ) -> io::Result<(Entry, Option<u64>, OpenOptions)> {
+            std::thread::sleep(Duration::from_millis(50));
+            let entry = Entry {
+                inode: 6,
+                ..Default::default()
+            };
+            Ok((entry, Some(9), OpenOptions::empty()))
+        }
+
+        fn release(
+            &self,
+            _ctx: &Context,
+            _inode: u64,
+            _flags: u32,
+            handle: u64,
+            _flush: bool,
+            _flock_release: bool,
+            _lock_owner: Option<u64>,
+        ) -> io::Result<()> {
+            self.released.lock().unwrap().push(handle);
+            Ok(())
+        }
+    }
+
+    #[cfg(not(feature = "virtiofs"))]
+    fn timed_out_request(server: &Arc<Server<SlowFs>>, opcode: Opcode, body: &[u8]) {
+        let watchdog = server.clone();
+        let handle = std::thread::spawn(move || {
+            let mut buf = Vec::new();
+            while watchdog.reply_timed_out_requests(&mut buf) == 0 {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            buf
+        });
+
+        let in_header = InHeader {
+            len: (size_of::<InHeader>() + body.len()) as u32,
+            opcode: opcode as u32,
+            unique: 7,
+            nodeid: ROOT_ID,
+            ..Default::default()
+        };
+        let mut req = in_header.as_slice().to_vec();
+        req.extend_from_slice(body);
+        let mut owned = Writer::<()>::new_owned(0x1000);
+        server
+            .handle_message(Reader::from_vec(req), owned.writer(), None, None)
+            .unwrap();
+
+        // The late reply is dropped, only the watchdog replies.
+        assert!(owned.into_inner().is_empty());
+        let buf = handle.join().unwrap();
+        assert_eq!(buf.len(), size_of::<OutHeader>());
+        let header = OutHeader::from_slice(&buf).unwrap();
+        assert_eq!((header.error, header.unique), (-libc::EIO, 7));
+    }
+
+    #[cfg(not(feature = "virtiofs"))]
+    #[test]
+    fn test_timed_out_requests() {
+        let server = Arc::new(Server::<SlowFs>::new(SlowFs {
+            forgotten: Mutex::new(Vec::new()),
+            released: Mutex::new(Vec::new()),
+        }));
+        server.set_request_timeout(Duration::from_millis(1));
+
+        // The entry of a late LOOKUP reply is forgotten.
+        timed_out_request(&server, Opcode::Lookup, b"foo\0");
+        assert_eq!(*server.fs.forgotten.lock().unwrap(), vec![(5, 1)]);
+
+        // The entry and handle of a late CREATE reply are forgotten and released.
+        let mut body = CreateIn::default().as_slice().to_vec();
+        body.extend_from_slice(b"bar\0");
+        timed_out_request(&server, Opcode::Create, &body);
+        assert_eq!(*server.fs.forgotten.lock().unwrap(), vec![(5, 1), (6, 1)]);
+        assert_eq!(*server.fs.released.lock().unwrap(), vec![9]);
+        assert!(server.inflight.requests.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_opcode_counters() {
+        let server: Server<crate::api::Vfs> = Server::new(crate::api::Vfs::default());
+        assert!(server.opcode_counters().is_empty());
+
+        server.counters.inc(Opcode::Lookup as u32);
+        server.counters.inc(Opcode::Lookup as u32);
+        server.counters.inc(Opcode::Read as u32);
+        // Unknown opcodes are ignored.
+        server.counters.inc(Opcode::MaxOpcode as u32);
+        assert_eq!(
+            server.opcode_counters(),
+            vec![(Opcode::Lookup as u32, 2), (Opcode::Read as u32, 1)]
+        );
+    }
+
+    #[test]
+    fn test_resume() {
+        let server: Server<crate::api::Vfs> = Server::new(crate::api::Vfs::default());
+        let init = InitIn {
+            major: 8,
+            minor: 0,
+            max_readahead: 0,
+            flags: FsOptions::ASYNC_READ.bits(),
+        };
+        assert!(server.resume(&init).is_err());
+
+        let init = InitIn {
+            major: KERNEL_VERSION,
+            minor: 31,
+            ..init
+        };
+        let opts = server.resume(&init).unwrap();
+        assert!(opts.contains(FsOptions::ASYNC_READ));
+        assert_eq!(server.negotiated_options(), opts);
+        assert_eq!(server.vers.load().minor, 31);
+    }
+
+    struct OpsFs;
+
+    impl FileSystem for OpsFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn init(&self, _capable: FsOptions) -> io::Result<FsOptions> {
+            Ok(FsOptions::ASYNC_READ | FsOptions::WRITEBACK_CACHE)
+        }
+
+        fn supported_ops(&self) -> SupportedOps {
+            SupportedOps::LSEEK | SupportedOps::CLONE_RANGE
+        }
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let server: Server<OpsFs> = Server::new(OpsFs);
+        let caps = server.capabilities();
+        assert!(caps.options.is_empty());
+        assert!(caps.ops.is_empty());
+
+        let init = InitIn {
+            major: KERNEL_VERSION,
+            minor: 31,
+            max_readahead: 0,
+            flags: (FsOptions::ASYNC_READ | FsOptions::ATOMIC_O_TRUNC).bits(),
+        };
+        server.resume(&init).unwrap();
+        assert_eq!(
+            server.capabilities(),
+            FsCapabilities {
+                major: KERNEL_VERSION,
+                minor: 31,
+                options: FsOptions::ASYNC_READ,
+                options_ext: FsOptionsExt::empty(),
+                ops: SupportedOps::LSEEK | SupportedOps::CLONE_RANGE,
+            }
+        );
+    }
+
+    #[cfg(not(feature = "virtiofs"))]
+    #[test]
+    fn test_time_gran() {
+        use crate::transport::FuseBuf;
+        use std::io::{Seek, SeekFrom};
+        use std::os::unix::io::AsRawFd;
+        use vmm_sys_util::tempfile::TempFile;
+
+        let server: Server<crate::api::Vfs> = Server::new(crate::api::Vfs::default());
+        for gran in [0, 2, 20, 1_000_000_001, 10_000_000_000u64 as u32] {
+            assert!(server.set_time_gran(gran).is_err());
+        }
+        for gran in [1, 10, 1000, 1_000_000_000] {
+            server.set_time_gran(gran).unwrap();
+        }
+
+        let header = InHeader {
+            len: (size_of::<InHeader>() + size_of::<InitIn>()) as u32,
+            opcode: Opcode::Init as u32,
+            unique: 1,
+            ..Default::default()
+        };
+        let arg = InitIn {
+            major: KERNEL_VERSION,
+            minor: KERNEL_MINOR_VERSION,
+            ..Default::default()
+        };
+        let mut req = header.as_slice().to_vec();
+        req.extend_from_slice(arg.as_slice());
+        let mut file = TempFile::new().unwrap().into_file();
+        let mut buf = vec![0u8; 0x1000];
+        let r = Reader::<()>::new(FuseBuf::new(&mut req)).unwrap();
+        let w = Writer::<()>::new(file.as_raw_fd(), &mut buf).unwrap();
+        server.handle_message(r, w, None, None).unwrap();
+
+        let mut reply = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut reply).unwrap();
+        assert_eq!(reply.len(), size_of::<OutHeader>() + size_of::<InitOut>());
+        let mut out = InitOut::default();
+        out.as_mut_slice()
+            .copy_from_slice(&reply[size_of::<OutHeader>()..]);
+        assert_eq!(out.time_gran, 1_000_000_000);
+    }
+
+    #[cfg(not(feature = "virtiofs"))]
+    #[test]
+    fn test_serve_transport() {
+        use crate::transport::{FuseTransport, Reply, Request};
+        use std::collections::VecDeque;
+
+        #[derive(Default)]
+        struct VecTransport {
+            requests: Mutex<VecDeque<Vec<u8>>>,
+            replies: Mutex<Vec<Vec<u8>>>,
+        }
+
+        impl FuseTransport for VecTransport {
+            fn recv(&self) -> io::Result<Option<Request>> {
+                Ok(self.requests.lock().unwrap().pop_front().map(Request::new))
+            }
+
+            fn send(&self, reply: Reply) -> io::Result<()> {
+                self.replies.lock().unwrap().push(reply.into_inner());
+                Ok(())
+            }
+        }
+
+        let request = |opcode: Opcode, unique: u64, body: &[u8]| {
+            let header = InHeader {
+                len: (size_of::<InHeader>() + body.len()) as u32,
+                opcode: opcode as u32,
+                unique,
+                nodeid: 1,
+                ..Default::default()
+            };
+            let mut req = header.as_slice().to_vec();
+            req.extend_from_slice(body);
+            req
+        };
+        let init = InitIn {
+            major: KERNEL_VERSION,
+            minor: KERNEL_MINOR_VERSION,
+            ..Default::default()
+        };
+        let transport = VecTransport::default();
+        transport.requests.lock().unwrap().extend([
+            request(Opcode::Init, 1, init.as_slice()),
+            // FORGET has no reply.
+            request(Opcode::Forget, 2, ForgetIn { nlookup: 1 }.as_slice()),
+            request(Opcode::Getattr, 3, GetattrIn::default().as_slice()),
+        ]);
+
+        let server: Server<CursorFs> = Server::new(CursorFs);
+        server.serve_transport(&transport).unwrap();
+        let replies = transport.replies.into_inner().unwrap();
+        assert_eq!(replies.len(), 2);
+        let out = OutHeader::from_slice(&replies[0][..size_of::<OutHeader>()]).unwrap();
+        assert_eq!((out.unique, out.error), (1, 0));
+        assert_eq!(
+            replies[0].len(),
+            size_of::<OutHeader>() + size_of::<InitOut>()
+        );
+        let out = OutHeader::from_slice(&replies[1][..size_of::<OutHeader>()]).unwrap();
+        assert_eq!((out.unique, out.error), (3, -libc::ENOSYS));
+        assert_eq!(out.len as usize, replies[1].len());
+    }
+
+    #[cfg(not(feature = "virtiofs"))]
+    #[test]
+    fn test_init_background() {
+        // Vfs can't be initialized more than once, use a file system accepting FUSE_INIT again.
+        let server: Server<CursorFs> = Server::new(CursorFs);
+        let init = |server: &Server<CursorFs>| -> InitOut {
+            let header = InHeader {
+                len: (size_of::<InHeader>() + size_of::<InitIn>()) as u32,
+                opcode: Opcode::Init as u32,
+                unique: 1,
+                ..Default::default()
+            };
+            let arg = InitIn {
+                major: KERNEL_VERSION,
+                minor: KERNEL_MINOR_VERSION,
+                ..Default::default()
+            };
+            let mut req = header.as_slice().to_vec();
+            req.extend_from_slice(arg.as_slice());
+            let mut owned = Writer::<()>::new_owned(0x1000);
+            server
+                .handle_message(Reader::from_vec(req), owned.writer(), None, None)
+                .unwrap();
+            let reply = owned.into_inner();
+            *InitOut::from_slice(&reply[size_of::<OutHeader>()..]).unwrap()
+        };
+
+        let out = init(&server);
+        assert_eq!(out.max_background, DEFAULT_MAX_BACKGROUND);
+        assert_eq!(out.congestion_threshold, DEFAULT_CONGESTION_THRESHOLD);
+        // The kernel doesn't offer FUSE_MAX_PAGES, so replies may still take its default pages.
+        assert_eq!((out.max_write, out.max_pages), (4096, 0));
+        assert_eq!(
+            max_payload_size(out.max_write, out.max_pages),
+            FUSE_DEFAULT_MAX_PAGES_PER_REQ as usize * crate::transport::pagesize()
+        );
+        assert_eq!(
+            negotiate_max_write(FsOptions::MAX_PAGES),
+            (
+                MAX_REQ_PAGES as u32 * crate::transport::pagesize() as u32,
+                MAX_REQ_PAGES
+            )
+        );
+
+        server.set_max_background(64);
+        server.set_congestion_threshold(48);
+        let out = init(&server);
+        assert_eq!(out.max_background, 64);
+        assert_eq!(out.congestion_threshold, 48);
+
+        // Values are clamped to the protocol limits.
+        server.set_congestion_threshold(100);
+        assert_eq!(init(&server).congestion_threshold, 64);
+        server.set_max_background(0);
+        server.set_congestion_threshold(0);
+        let out = init(&server);
+        assert_eq!(out.max_background, 1);
+        assert_eq!(out.congestion_threshold, 1);
+        server.set_max_background(1 << 20);
+        assert_eq!(init(&server).max_background, u16::MAX);
+    }
+
+    // Records the uid and the inodes of each getattr_batch() call.
+    #[derive(Default)]
+    struct BatchFs {
+        batches: Mutex<Vec<(u32, Vec<u64>)>>,
+    }
+
+    impl FileSystem for BatchFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn getattr(
+            &self,
+            _ctx: &Context,
+            inode: u64,
+            _handle: Option<u64>,
+        ) -> io::Result<(stat64, Duration)> {
+            let mut st: stat64 = unsafe { std::mem::zeroed() };
+            st.st_ino = inode;
+            Ok((st, Duration::ZERO))
+        }
+
+        fn getattr_batch(
+            &self,
+            ctx: &Context,
+            inodes: Vec<u64>,
+        ) -> Vec<io::Result<(stat64, Duration)>> {
+            self.batches.lock().unwrap().push((ctx.uid, inodes.clone()));
+            inodes
+                .into_iter()
+                .map(|inode| match inode {
+                    13 => Err(io::Error::from_raw_os_error(libc::ENOENT)),
+                    _ => self.getattr(ctx, inode, None),
+                })
+                .collect()
+        }
+    }
+
+    #[cfg(not(feature = "virtiofs"))]
+    #[test]
+    fn test_getattr_coalescing() {
+        // Requests for odd inodes come from uid 1, the others from uid 0.
+        let getattr = |server: &Server<BatchFs>, nodeid: u64, flags: u32| -> Vec<u8> {
+            let in_header = InHeader {
+                len: (size_of::<InHeader>() + size_of::<GetattrIn>()) as u32,
+                opcode: Opcode::Getattr as u32,
+                unique: nodeid,
+                nodeid,
+                uid: (nodeid % 2) as u32,
+                ..Default::default()
+            };
+            let arg = GetattrIn {
+                flags,
+                fh: 1,
+                ..Default::default()
+            };
+            let mut req = in_header.as_slice().to_vec();
+            req.extend_from_slice(arg.as_slice());
+            let mut owned = Writer::<()>::new_owned(0x1000);
+            server
+                .handle_message(Reader::from_vec(req), owned.writer(), None, None)
+                .unwrap();
+            owned.into_inner()
+        };
+
+        let server = Arc::new(Server::new(BatchFs::default()));
+        getattr(&server, 1, 0);
+        assert!(server.fs.batches.lock().unwrap().is_empty());
+
+        server.set_getattr_coalescing(Duration::from_millis(200));
+        let threads: Vec<_> = (10..16)
+            .map(|nodeid| {
+                let server = server.clone();
+                std::thread::spawn(move || (nodeid, getattr(&server, nodeid, 0)))
+            })
+            .collect();
+        for t in threads {
+            let (nodeid, reply) = t.join().unwrap();
+            let out = OutHeader::from_slice(&reply[..size_of::<OutHeader>()]).unwrap();
+            assert_eq!(out.unique, nodeid);
+            if nodeid == 13 {
+                assert_eq!(out.error, -libc::ENOENT);
+            } else {
+                assert_eq!(out.error, 0);
+                let attr = AttrOut::from_slice(&reply[size_of::<OutHeader>()..]).unwrap();
+                assert_eq!(attr.attr.ino, nodeid);
+            }
+        }
+        let batches = std::mem::take(&mut *server.fs.batches.lock().unwrap());
+        // All requests are served, most likely by one batch for each user.
+        assert!(batches.len() < 6);
+        let mut inodes = Vec::new();
+        for (uid, batch) in batches {
+            // Requests from different users are never coalesced.
+            assert!(batch.iter().all(|inode| (inode % 2) as u32 == uid));
+            inodes.extend(batch);
+        }
+        inodes.sort_unstable();
+        assert_eq!(inodes, (10..16).collect::<Vec<u64>>());
+
+        // Requests with a file handle are never coalesced.
+        getattr(&server, 2, GETATTR_FH);
+        assert!(server.fs.batches.lock().unwrap().is_empty());
+    }
+
+    struct StackFs {
+        depth: AtomicU32,
+    }
+
+    impl FileSystem for StackFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn init_ext(&self, _capable: FsOptionsExt) -> io::Result<FsOptionsExt> {
+            Ok(FsOptionsExt::PASSTHROUGH)
+        }
+
+        fn max_stack_depth(&self) -> u32 {
+            self.depth.load(Ordering::Relaxed)
+        }
+    }
+
+    #[cfg(not(feature = "virtiofs"))]
+    #[test]
+    fn test_init_stack_depth() {
+        let init = |server: &Server<StackFs>, flags2: u32| -> InitOut {
+            let header = InHeader {
+                len: (size_of::<InHeader>() + size_of::<InitIn>() + size_of::<InitInExt>()) as u32,
+                opcode: Opcode::Init as u32,
+                unique: 1,
+                ..Default::default()
+            };
+            let arg = InitIn {
+                major: KERNEL_VERSION,
+                minor: KERNEL_MINOR_VERSION_INIT_EXT,
+                flags: INIT_EXT,
+                ..Default::default()
+            };
+            let ext = InitInExt {
+                flags2,
+                ..Default::default()
+            };
+            let mut req = header.as_slice().to_vec();
+            req.extend_from_slice(arg.as_slice());
+            req.extend_from_slice(ext.as_slice());
+            let mut owned = Writer::<()>::new_owned(0x1000);
+            server
+                .handle_message(Reader::from_vec(req), owned.writer(), None, None)
+                .unwrap();
+            let reply = owned.into_inner();
+            *InitOut::from_slice(&reply[size_of::<OutHeader>()..]).unwrap()
+        };
+        let passthrough = FsOptionsExt::PASSTHROUGH.bits();
+
+        let server = Server::new(StackFs {
+            depth: AtomicU32::new(2),
+        });
+        assert_eq!(server.max_stack_depth(), 0);
+        let out = init(&server, passthrough);
+        assert_eq!(out.flags2 & passthrough, passthrough);
+        assert_eq!(out.max_stack_depth, 2);
+        assert_eq!(server.max_stack_depth(), 2);
+
+        // Not negotiated if the kernel doesn't support it.
+        let out = init(&server, 0);
+        assert_eq!(out.flags2 & passthrough, 0);
+        assert_eq!(out.max_stack_depth, 0);
+        assert_eq!(server.max_stack_depth(), 0);
+
+        // The kernel refuses depths beyond its limit.
+        server
+            .fs
+            .depth
+            .store(FILESYSTEM_MAX_STACK_DEPTH + 1, Ordering::Relaxed);
+        let out = init(&server, passthrough);
+        assert_eq!(out.flags2 & passthrough, 0);
+        assert_eq!(out.max_stack_depth, 0);
+        assert!(!server
+            .negotiated_options_ext()
+            .contains(FsOptionsExt::PASSTHROUGH));
+    }
+
+    struct DestroyFs {
+        destroyed: AtomicU32,
+    }
+
+    impl FileSystem for DestroyFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn init(&self, _capable: FsOptions) -> io::Result<FsOptions> {
+            Ok(FsOptions::empty())
+        }
+
+        fn destroy(&self) {
+            self.destroyed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[cfg(not(feature = "virtiofs"))]
+    #[test]
+    fn test_destroy_once() {
+        let server = Server::<DestroyFs>::new(DestroyFs {
+            destroyed: AtomicU32::new(0),
+        });
+        let send = |opcode: Opcode, arg: &[u8]| {
+            let header = InHeader {
+                len: (size_of::<InHeader>() + arg.len()) as u32,
+                opcode: opcode as u32,
+                unique: 1,
+                ..Default::default()
+            };
+            let mut req = header.as_slice().to_vec();
+            req.extend_from_slice(arg);
+            let mut owned = Writer::<()>::new_owned(0x1000);
+            server
+                .handle_message(Reader::from_vec(req), owned.writer(), None, None)
+                .unwrap();
+        };
+        let init = InitIn {
+            major: KERNEL_VERSION,
+            minor: KERNEL_MINOR_VERSION,
+            ..Default::default()
+        };
+        let destroyed = || server.fs.destroyed.load(Ordering::Relaxed);
+
+        // Nothing to tear down before FUSE_INIT.
+        server.shutdown();
+        assert_eq!(destroyed(), 0);
+
+        // Clean unmount, the session is closed afterwards.
+        send(Opcode::Init, init.as_slice());
+        send(Opcode::Destroy, &[]);
+        assert_eq!(destroyed(), 1);
+        server.shutdown();
+        assert_eq!(destroyed(), 1);
+
+        // Aborted connection.
+        send(Opcode::Init, init.as_slice());
+        server.shutdown();
+        server.shutdown();
+        assert_eq!(destroyed(), 2);
+    }
+
+    struct SyncFs {
+        synced: AtomicU32,
+    }
+
+    impl FileSystem for SyncFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn syncfs(&self, _ctx: &Context) -> io::Result<()> {
+            self.synced.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    #[cfg(not(feature = "virtiofs"))]
+    #[test]
+    fn test_syncfs() {
+        let syncfs = |server: &dyn Fn(Reader, Writer) -> Result<usize>| {
+            let header = InHeader {
+                len: (size_of::<InHeader>() + size_of::<SyncfsIn>()) as u32,
+                opcode: Opcode::Syncfs as u32,
+                unique: 1,
+                nodeid: ROOT_ID,
+                ..Default::default()
+            };
+            let mut req = header.as_slice().to_vec();
+            req.extend_from_slice(SyncfsIn::default().as_slice());
+            let mut owned = Writer::<()>::new_owned(0x1000);
+            server(Reader::from_vec(req), owned.writer()).unwrap();
+            let reply = owned.into_inner();
+            *OutHeader::from_slice(&reply).unwrap()
+        };
+
+        let server = Server::<SyncFs>::new(SyncFs {
+            synced: AtomicU32::new(0),
+        });
+        let out = syncfs(&|r, w| server.handle_message(r, w, None, None));
+        assert_eq!((out.len, out.error, out.unique), (16, 0, 1));
+        assert_eq!(server.fs.synced.load(Ordering::Relaxed), 1);
+
+        // File systems not implementing it fail with ENOSYS, so the kernel stops sending it.
+        let server = Server::<DestroyFs>::new(DestroyFs {
+            destroyed: AtomicU32::new(0),
+        });
+        let out = syncfs(&|r, w| server.handle_message(r, w, None, None));
+        assert_eq!((out.len, out.error), (16, -libc::ENOSYS));
+    }
+
+    #[test]
+    fn test_notify_delete() {
+        let server: Server<crate::api::Vfs> = Server::new(crate::api::Vfs::default());
+        let name = CString::new("file").unwrap();
+        let mut buf = Vec::new();
+
+        let len = server.notify_delete(&mut buf, 1, 5, &name).unwrap();
+        assert_eq!(len, buf.len());
+        assert_eq!(
+            len,
+            size_of::<OutHeader>() + size_of::<NotifyDeleteOut>() + 5
+        );
+        let header = OutHeader::from_slice(&buf[..size_of::<OutHeader>()]).unwrap();
+        assert_eq!(header.len as usize, len);
+        assert_eq!(header.error, NotifyOpcode::Delete as i32);
+        assert_eq!(header.unique, 0);
+        let out = NotifyDeleteOut::from_slice(
+            &buf[size_of::<OutHeader>()..size_of::<OutHeader>() + size_of::<NotifyDeleteOut>()],
+        )
+        .unwrap();
+        assert_eq!((out.parent, out.child, out.namelen), (1, 5, 4));
+        assert_eq!(&buf[len - 5..], b"file\0");
+
+        let long = CString::new(vec![b'a'; FUSE_NAME_MAX + 1]).unwrap();
+        let e = server.notify_delete(&mut buf, 1, 5, &long).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ENAMETOOLONG));
+
+        server.vers.store(Arc::new(ServerVersion {
+            major: KERNEL_VERSION,
+            minor: KERNEL_MINOR_VERSION_NOTIFY_DELETE - 1,
+        }));
+        let e = server.notify_delete(&mut buf, 1, 5, &name).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ENOSYS));
+    }
+
+    #[cfg(not(feature = "virtiofs"))]
+    #[test]
+    fn test_notify_retrieve() {
+        use crate::transport::FuseBuf;
+        use std::os::unix::io::AsRawFd;
+        use std::task::Wake;
+        use vmm_sys_util::tempfile::TempFile;
+
+        struct NoopWaker;
+        impl Wake for NoopWaker {
+            fn wake(self: Arc<Self>) {}
+        }
+
+        let server: Server<crate::api::Vfs> = Server::new(crate::api::Vfs::default());
+        let file = TempFile::new().unwrap().into_file();
+        let reply_to = |unique: u64, data: &[u8]| {
+            let arg = NotifyRetrieveIn {
+                offset: 4096,
+                size: data.len() as u32,
+                ..Default::default()
+            };
+            let in_header = InHeader {
+                len: (size_of::<InHeader>() + size_of::<NotifyRetrieveIn>() + data.len()) as u32,
+                opcode: Opcode::NotifyReply as u32,
+                unique,
+                nodeid: 2,
+                ..Default::default()
+            };
+            let mut req = in_header.as_slice().to_vec();
+            req.extend_from_slice(arg.as_slice());
+            req.extend_from_slice(data);
+            let mut buf = vec![0u8; 0x1000];
+            let r = Reader::<()>::new(FuseBuf::new(&mut req)).unwrap();
+            let w = Writer::<()>::new(file.as_raw_fd(), &mut buf).unwrap();
+            server.handle_message(r, w, None, None).unwrap();
+        };
+
+        let mut buf = Vec::new();
+        let reply = server.notify_retrieve(&mut buf, 2, 4096, 8).unwrap();
+        assert_eq!(
+            buf.len(),
+            size_of::<OutHeader>() + size_of::<Notify_Retrieve_Out>()
+        );
+        let header = OutHeader::from_slice(&buf[..size_of::<OutHeader>()]).unwrap();
+        assert_eq!(header.len as usize, buf.len());
+        assert_eq!(header.error, NotifyOpcode::Retrieve as i32);
+        let out = Notify_Retrieve_Out::from_slice(&buf[size_of::<OutHeader>()..]).unwrap();
+        assert_ne!(out.notify_unique, 0);
+        assert_eq!((out.nodeid, out.offset, out.size), (2, 4096, 8));
+
+        reply_to(out.notify_unique, b"abcdefgh");
+        assert_eq!(reply.wait(Duration::from_secs(1)).unwrap(), b"abcdefgh");
+        assert!(!server.retrieves.is_pending(out.notify_unique));
+
+        // The future is woken up once the reply arrives.
+        buf.clear();
+        let mut reply = server.notify_retrieve(&mut buf, 2, 0, 8).unwrap();
+        let out = Notify_Retrieve_Out::from_slice(&buf[size_of::<OutHeader>()..]).unwrap();
+        let waker = Waker::from(Arc::new(NoopWaker));
+        let mut cx = TaskContext::from_waker(&waker);
+        assert!(Pin::new(&mut reply).poll(&mut cx).is_pending());
+        reply_to(out.notify_unique, b"abc");
+        match Pin::new(&mut reply).poll(&mut cx) {
+            Poll::Ready(data) => assert_eq!(data.unwrap(), b"abc"),
+            Poll::Pending => panic!("retrieve reply is not ready"),
+        }
+
+        let reply = server.notify_retrieve(&mut buf, 2, 0, 8).unwrap();
+        let out =
+            Notify_Retrieve_Out::from_slice(&buf[buf.len() - size_of::<Notify_Retrieve_Out>()..])
+                .unwrap();
+        let e = reply.wait(Duration::from_millis(10)).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ETIMEDOUT));
+        assert!(!server.retrieves.is_pending(out.notify_unique));
+
+        server.vers.store(Arc::new(ServerVersion {
+            major: KERNEL_VERSION,
+            minor: KERNEL_MINOR_VERSION_NOTIFY_RETRIEVE - 1,
+        }));
+        assert!(server.notify_retrieve(&mut buf, 2, 0, 8).is_err());
+    }
+
+    // Lists "a", "b", "c", an empty page and then "d", "e".
+    struct CursorFs;
+
+    impl FileSystem for CursorFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn readdir_cursor(
+            &self,
+            _ctx: &Context,
+            _inode: u64,
+            _handle: u64,
+            _size: u32,
+            cursor: Option<&[u8]>,
+        ) -> io::Result<(Vec<crate::api::filesystem::DirEntryBuf>, Option<Vec<u8>>)> {
+            let (names, next): (&[&[u8]], Option<&[u8]>) = match cursor {
+                None => (&[b"a", b"b", b"c"], Some(b"p1")),
+                Some(b"p1") => (&[], Some(b"p2")),
+                Some(b"p2") => (&[b"d", b"e"], None),
+                Some(_) => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+            };
+            let entries = names
+                .iter()
+                .map(|name| crate::api::filesystem::DirEntryBuf {
+                    ino: 10 + name[0] as u64,
+                    type_: libc::DT_REG as u32,
+                    name: name.to_vec(),
+                })
+                .collect();
+            Ok((entries, next.map(|n| n.to_vec())))
+        }
+    }
+
+    #[cfg(not(feature = "virtiofs"))]
+    #[test]
+    fn test_readdir_cursor() {
+        use crate::transport::FuseBuf;
+        use std::io::{Seek, SeekFrom};
+        use std::os::unix::io::AsRawFd;
+        use vmm_sys_util::tempfile::TempFile;
+
+        let server: Server<CursorFs> = Server::new(CursorFs);
+        let mut file = TempFile::new().unwrap().into_file();
+
+        // Each entry with a single character name takes 32 bytes, plus the size of EntryOut for
+        // READDIRPLUS, so two entries fit in a reply.
+        let mut readdir = |opcode: Opcode, offset: u64| -> (i32, Vec<(u64, u64, Vec<u8>)>) {
+            let arg = ReadIn {
+                fh: 1,
+                offset,
+                size: match opcode {
+                    Opcode::Readdirplus => 2 * (32 + size_of::<EntryOut>() as u32),
+                    _ => 64,
+                },
+                ..Default::default()
+            };
+            let in_header = InHeader {
+                len: (size_of::<InHeader>() + size_of::<ReadIn>()) as u32,
+                opcode: opcode as u32,
+                unique: 3,
+                nodeid: 1,
+                ..Default::default()
+            };
+            let mut req = in_header.as_slice().to_vec();
+            req.extend_from_slice(arg.as_slice());
+            let mut buf = vec![0u8; 0x1000];
+            let r = Reader::<()>::new(FuseBuf::new(&mut req)).unwrap();
+            let w = Writer::<()>::new(file.as_raw_fd(), &mut buf).unwrap();
+            server.handle_message(r, w, None, None).unwrap();
+
+            let mut reply = Vec::new();
+            file.seek(SeekFrom::Start(0)).unwrap();
+            file.read_to_end(&mut reply).unwrap();
+            file.set_len(0).unwrap();
+            file.seek(SeekFrom::Start(0)).unwrap();
+
+            let header = OutHeader::from_slice(&reply[..size_of::<OutHeader>()]).unwrap();
+            assert_eq!(header.len as usize, reply.len());
+            let mut entries = Vec::new();
+            let mut pos = size_of::<OutHeader>();
+            while pos < reply.len() {
+                if matches!(opcode, Opcode::Readdirplus) {
+                    let out = EntryOut::from_slice(&reply[pos..pos + size_of::<EntryOut>()]);
+                    assert_eq!(out.unwrap().nodeid, 0);
+                    pos += size_of::<EntryOut>();
+                }
+                let d = Dirent::from_slice(&reply[pos..pos + size_of::<Dirent>()]).unwrap();
+                let name = &reply[pos + size_of::<Dirent>()..][..d.namelen as usize];
+                entries.push((d.ino, d.off, name.to_vec()));
+                pos += (size_of::<Dirent>() + d.namelen as usize + 7) & !7;
+            }
+            (header.error, entries)
+        };
+
+        let (error, entries) = readdir(Opcode::Readdir, 0);
+        assert_eq!(error, 0);
+        assert_eq!(
+            entries,
+            vec![(107, 1, b"a".to_vec()), (108, 2, b"b".to_vec())]
+        );
+
+        // Resume from the middle of the first page, then skip over the empty page.
+        let (_, entries) = readdir(Opcode::Readdir, 2);
+        assert_eq!(
+            entries,
+            vec![(109, 6, b"c".to_vec()), (110, 7, b"d".to_vec())]
+        );
+        let (_, entries) = readdir(Opcode::Readdir, 7);
+        assert_eq!(entries, vec![(111, 10, b"e".to_vec())]);
+        let (error, entries) = readdir(Opcode::Readdir, 10);
+        assert_eq!(error, 0);
+        assert!(entries.is_empty());
+
+        // Earlier offsets stay valid until the directory is rewound.
+        let (_, entries) = readdir(Opcode::Readdirplus, 6);
+        let names: Vec<Vec<u8>> = entries.into_iter().map(|e| e.2).collect();
+        assert_eq!(names, vec![b"d".to_vec(), b"e".to_vec()]);
+        let (error, _) = readdir(Opcode::Readdir, 100);
+        assert_eq!(error, -libc::EINVAL);
+        let (_, entries) = readdir(Opcode::Readdir, 0);
+        assert_eq!(entries[0].1, 1);
+        let (error, _) = readdir(Opcode::Readdir, 6);
+        assert_eq!(error, -libc::EINVAL);
+
+        // Offsets are forgotten once the directory is released.
+        server.rdcursors.forget((1, 1));
+        let (error, _) = readdir(Opcode::Readdir, 2);
+        assert_eq!(error, -libc::ENOSYS);
+    }
+
+    // A pipe-like file, reads return the data produced so far.
+    #[derive(Default)]
+    struct StreamFs {
+        data: Mutex<Vec<u8>>,
+    }
+
+    impl FileSystem for StreamFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn open(
+            &self,
+            _ctx: &Context,
+            _inode: u64,
+            _flags: u32,
+            _fuse_flags: u32,
+        ) -> io::Result<(Option<u64>, OpenOptions)> {
+            let opts = OpenOptions::DIRECT_IO | OpenOptions::NONSEEKABLE | OpenOptions::STREAM;
+            Ok((Some(1), opts))
+        }
+
+        fn read(
+            &self,
+            _ctx: &Context,
+            _inode: u64,
+            _handle: u64,
+            w: &mut dyn ZeroCopyWriter,
+            size: u32,
+            _offset: u64,
+            _lock_owner: Option<u64>,
+            _flags: u32,
+        ) -> io::Result<usize> {
+            let mut data = self.data.lock().unwrap();
+            let count = std::cmp::min(size as usize, data.len());
+            w.write_all(&data[..count])?;
+            data.drain(..count);
+            Ok(count)
+        }
+    }
+
+    #[cfg(not(feature = "virtiofs"))]
+    #[test]
+    fn test_stream_read() {
+        use crate::transport::FuseBuf;
+        use std::io::{Seek, SeekFrom};
+        use std::os::unix::io::AsRawFd;
+        use vmm_sys_util::tempfile::TempFile;
+
+        let server: Server<StreamFs> = Server::new(StreamFs::default());
+        let mut file = TempFile::new().unwrap().into_file();
+        let mut handle = |opcode: Opcode, arg: &[u8]| -> Vec<u8> {
+            let in_header = InHeader {
+                len: (size_of::<InHeader>() + arg.len()) as u32,
+                opcode: opcode as u32,
+                unique: 3,
+                nodeid: 2,
+                ..Default::default()
+            };
+            let mut req = in_header.as_slice().to_vec();
+            req.extend_from_slice(arg);
+            let mut buf = vec![0u8; 0x2000];
+            let r = Reader::<()>::new(FuseBuf::new(&mut req)).unwrap();
+            let w = Writer::<()>::new(file.as_raw_fd(), &mut buf).unwrap();
+            server.handle_message(r, w, None, None).unwrap();
+
+            let mut reply = Vec::new();
+            file.seek(SeekFrom::Start(0)).unwrap();
+            file.read_to_end(&mut reply).unwrap();
+            file.set_len(0).unwrap();
+            file.seek(SeekFrom::Start(0)).unwrap();
+            let header = OutHeader::from_slice(&reply[..size_of::<OutHeader>()]).unwrap();
+            assert_eq!(header.len as usize, reply.len());
+            assert_eq!(header.error, 0);
+            reply.split_off(size_of::<OutHeader>())
+        };
+
+        let reply = handle(Opcode::Open, OpenIn::default().as_slice());
+        let out = OpenOut::from_slice(&reply).unwrap();
+        assert_eq!(
+            OpenOptions::from_bits_truncate(out.open_flags),
+            OpenOptions::DIRECT_IO | OpenOptions::NONSEEKABLE | OpenOptions::STREAM
+        );
+
+        // Each read returns the data available so far, even if less than requested.
+        let read = ReadIn {
+            fh: out.fh,
+            size: 4096,
+            ..Default::default()
+        };
+        server.fs.data.lock().unwrap().extend_from_slice(b"abc");
+        assert_eq!(handle(Opcode::Read, read.as_slice()), b"abc");
+        server.fs.data.lock().unwrap().extend_from_slice(b"de");
+        assert_eq!(handle(Opcode::Read, read.as_slice()), b"de");
+    }
+
+    // Records the security contexts applied to new nodes, labeling nodes named "bad" fails.
+    #[derive(Default)]
+    struct SecctxFs {
+        labels: Mutex<Vec<(u64, CString, SecContext)>>,
+        forgotten: Mutex<Vec<u64>>,
+    }
+
+    impl FileSystem for SecctxFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn forget(&self, _ctx: &Context, inode: u64, count: u64) {
+            assert_eq!(count, 1);
+            self.forgotten.lock().unwrap().push(inode);
+        }
+
+        fn symlink(
+            &self,
+            _ctx: &Context,
+            _linkname: &CStr,
+            _parent: u64,
+            _name: &CStr,
+        ) -> io::Result<Entry> {
+            Ok(Entry {
+                inode: 10,
+                ..Default::default()
+            })
+        }
+
+        fn mknod(
+            &self,
+            _ctx: &Context,
+            _inode: u64,
+            _name: &CStr,
+            _mode: u32,
+            _rdev: u32,
+            _umask: u32,
+        ) -> io::Result<Entry> {
+            Ok(Entry {
+                inode: 11,
+                ..Default::default()
+            })
+        }
+
+        fn init_security_context(
+            &self,
+            _ctx: &Context,
+            parent: u64,
+            name: &CStr,
+            inode: u64,
+            secctx: &SecContext,
+        ) -> io::Result<()> {
+            assert_eq!(parent, 1);
+            if name.to_bytes() == b"bad" {
+                return Err(io::Error::from_raw_os_error(libc::EPERM));
+            }
+            self.labels
+                .lock()
+                .unwrap()
+                .push((inode, name.to_owned(), secctx.clone()));
+            Ok(())
+        }
+    }
+
+    fn encode_secctx(name: &[u8], value: &[u8]) -> Vec<u8> {
+        let mut entry = Secctx {
+            size: value.len() as u32,
+            ..Default::default()
+        }
+        .as_slice()
+        .to_vec();
+        entry.extend_from_slice(name);
+        entry.push(0);
+        entry.extend_from_slice(value);
+        entry.resize((entry.len() + 7) & !7, 0);
+
+        let header = SecctxHeader {
+            size: (size_of::<SecctxHeader>() + entry.len()) as u32,
+            nr_secctx: 1,
+        };
+        let mut buf = header.as_slice().to_vec();
+        buf.extend_from_slice(&entry);
+        buf
+    }
+
+    #[test]
+    fn test_extract_secctx() {
+        let buf = encode_secctx(b"security.selinux", b"system_u:object_r:tmp_t:s0\0");
+        assert_eq!(
+            ServerUtil::extract_secctx(&buf).unwrap(),
+            vec![SecContext {
+                name: CString::new("security.selinux").unwrap(),
+                value: b"system_u:object_r:tmp_t:s0\0".to_vec(),
+            }]
+        );
+
+        let header = SecctxHeader {
+            size: size_of::<SecctxHeader>() as u32,
+            nr_secctx: 0,
+        };
+        assert!(ServerUtil::extract_secctx(header.as_slice())
+            .unwrap()
+            .is_empty());
+
+        ServerUtil::extract_secctx(&buf[..4]).unwrap_err();
+        ServerUtil::extract_secctx(&buf[..buf.len() - 8]).unwrap_err();
+        let mut truncated = buf.clone();
+        truncated[0] = 16;
+        ServerUtil::extract_secctx(&truncated).unwrap_err();
+    }
+
+    #[derive(Default)]
+    struct ExtFs {
+        extensions: Mutex<Vec<Extensions>>,
+    }
+
+    impl FileSystem for ExtFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn mknod(
+            &self,
+            ctx: &Context,
+            _inode: u64,
+            name: &CStr,
+            _mode: u32,
+            _rdev: u32,
+            _umask: u32,
+        ) -> io::Result<Entry> {
+            assert_eq!(name.to_bytes(), b"dev");
+            self.extensions.lock().unwrap().push(ctx.extensions.clone());
+            Ok(Entry {
+                inode: 11,
+                ..Default::default()
+            })
+        }
+    }
+
+    #[derive(Default)]
+    struct RawHeaderFs {
+        wants: bool,
+        headers: Mutex<Vec<Option<InHeader>>>,
+    }
+
+    impl FileSystem for RawHeaderFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn wants_raw_header(&self) -> bool {
+            self.wants
+        }
+
+        fn getattr(
+            &self,
+            ctx: &Context,
+            _inode: u64,
+            _handle: Option<u64>,
+        ) -> io::Result<(stat64, Duration)> {
+            self.headers.lock().unwrap().push(ctx.raw_header);
+            Err(io::Error::from_raw_os_error(libc::ENOENT))
+        }
+    }
+
+    #[cfg(not(feature = "virtiofs"))]
+    #[test]
+    fn test_raw_header() {
+        let getattr = |server: &Server<RawHeaderFs>| {
+            let in_header = InHeader {
+                len: (size_of::<InHeader>() + size_of::<GetattrIn>()) as u32,
+                opcode: Opcode::Getattr as u32,
+                unique: 42,
+                nodeid: 7,
+                padding: 3,
+                ..Default::default()
+            };
+            let mut req = in_header.as_slice().to_vec();
+            req.extend_from_slice(GetattrIn::default().as_slice());
+            let mut owned = Writer::<()>::new_owned(0x1000);
+            server
+                .handle_message(Reader::from_vec(req), owned.writer(), None, None)
+                .unwrap();
+        };
+
+        let server = Server::new(RawHeaderFs::default());
+        getattr(&server);
+        assert!(server.fs.headers.lock().unwrap()[0].is_none());
+
+        let server = Server::new(RawHeaderFs {
+            wants: true,
+            ..Default::default()
+        });
+        getattr(&server);
+        let header = server.fs.headers.lock().unwrap()[0].unwrap();
+        assert_eq!(header.unique, 42);
+        assert_eq!(header.nodeid, 7);
+        assert_eq!(header.padding, 3);
+        assert_eq!(
+            header.len as usize,
+            size_of::<InHeader>() + size_of::<GetattrIn>()
+        );
+    }
+
+    // Fails the first `failures` GETATTR and all UNLINK requests with a transient error.
+    #[derive(Default)]
+    struct FlakyFs {
+        failures: AtomicU32,
+        getattrs: AtomicU32,
+        unlinks: AtomicU32,
+    }
+
+    impl FileSystem for FlakyFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn getattr(
+            &self,
+            _ctx: &Context,
+            _inode: u64,
+            _handle: Option<u64>,
+        ) -> io::Result<(stat64, Duration)> {
+            self.getattrs.fetch_add(1, Ordering::Relaxed);
+            if self
+                .failures
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| v.checked_sub(1))
+                .is_ok()
+            {
+                return Err(Retryable::new(io::Error::from_raw_os_error(libc::EAGAIN)));
+            }
+            let mut st: stat64 = unsafe { std::mem::zeroed() };
+            st.st_ino = 1;
+            Ok((st, Duration::ZERO))
+        }
+
+        fn unlink(&self, _ctx: &Context, _parent: u64, _name: &CStr) -> io::Result<()> {
+            self.unlinks.fetch_add(1, Ordering::Relaxed);
+            Err(Retryable::new(io::Error::from_raw_os_error(libc::EAGAIN)))
+        }
+    }
+
+    #[cfg(not(feature = "virtiofs"))]
+    #[test]
+    fn test_retry_policy() {
+        let send = |server: &Server<FlakyFs>, opcode: Opcode, body: &[u8]| -> OutHeader {
+            let in_header = InHeader {
+                len: (size_of::<InHeader>() + body.len()) as u32,
+                opcode: opcode as u32,
+                unique: 1,
+                nodeid: 1,
+                ..Default::default()
+            };
+            let mut req = in_header.as_slice().to_vec();
+            req.extend_from_slice(body);
+            let mut owned = Writer::<()>::new_owned(0x1000);
+            server
+                .handle_message(Reader::from_vec(req), owned.writer(), None, None)
+                .unwrap();
+            let reply = owned.into_inner();
+            *OutHeader::from_slice(&reply[..size_of::<OutHeader>()]).unwrap()
+        };
+        let getattr = GetattrIn::default();
+
+        // Transient errors are replied as is without a retry policy.
+        let server = Server::new(FlakyFs::default());
+        server.fs.failures.store(1, Ordering::Relaxed);
+        assert_eq!(
+            send(&server, Opcode::Getattr, getattr.as_slice()).error,
+            -libc::EAGAIN
+        );
+        assert_eq!(server.fs.getattrs.load(Ordering::Relaxed), 1);
+
+        server.set_retry_policy(RetryPolicy {
+            max_attempts: 3,
+            backoff: Duration::from_millis(1),
+        });
+        server.fs.failures.store(2, Ordering::Relaxed);
+        assert_eq!(send(&server, Opcode::Getattr, getattr.as_slice()).error, 0);
+        assert_eq!(server.fs.getattrs.load(Ordering::Relaxed), 4);
+
+        // The last error is replied once the attempts have been exhausted.
+        server.fs.failures.store(5, Ordering::Relaxed);
+        assert_eq!(
+            send(&server, Opcode::Getattr, getattr.as_slice()).error,
+            -libc::EAGAIN
+        );
+        assert_eq!(server.fs.getattrs.load(Ordering::Relaxed), 7);
+
+        // Non-idempotent requests are never retried.
+        assert_eq!(
+            send(&server, Opcode::Unlink, b"name\0").error,
+            -libc::EAGAIN
+        );
+        assert_eq!(server.fs.unlinks.load(Ordering::Relaxed), 1);
+    }
+
+    #[cfg(not(feature = "virtiofs"))]
+    #[test]
+    fn test_allowed_opcodes() {
+        let send = |server: &Server<FlakyFs>, opcode: Opcode, body: &[u8]| -> OutHeader {
+            let in_header = InHeader {
+                len: (size_of::<InHeader>() + body.len()) as u32,
+                opcode: opcode as u32,
+                unique: 1,
+                nodeid: 1,
+                ..Default::default()
+            };
+            let mut req = in_header.as_slice().to_vec();
+            req.extend_from_slice(body);
+            let mut owned = Writer::<()>::new_owned(0x1000);
+            server
+                .handle_message(Reader::from_vec(req), owned.writer(), None, None)
+                .unwrap();
+            let reply = owned.into_inner();
+            *OutHeader::from_slice(&reply[..size_of::<OutHeader>()]).unwrap()
+        };
+        let getattr = GetattrIn::default();
+
+        let mask = OpcodeMask::all().deny(Opcode::Getattr);
+        assert!(!mask.contains(Opcode::Getattr as u32));
+        assert!(mask.contains(Opcode::Unlink as u32));
+        assert!(mask.contains(Opcode::CanonicalPath as u32));
+        assert!(!OpcodeMask::empty().contains(Opcode::Lookup as u32));
+        assert!(OpcodeMask::empty()
+            .allow(Opcode::Lookup)
+            .contains(Opcode::Lookup as u32));
+
+        let server = Server::new(FlakyFs::default());
+        server.set_allowed_opcodes(mask);
+        assert_eq!(
+            send(&server, Opcode::Getattr, getattr.as_slice()).error,
+            -libc::ENOSYS
+        );
+        assert_eq!(server.fs.getattrs.load(Ordering::Relaxed), 0);
+
+        // Other requests still reach the filesystem.
+        assert_eq!(
+            send(&server, Opcode::Unlink, b"name\0").error,
+            -libc::EAGAIN
+        );
+        assert_eq!(server.fs.unlinks.load(Ordering::Relaxed), 1);
+
+        server.set_allowed_opcodes(OpcodeMask::default());
+        assert_eq!(send(&server, Opcode::Getattr, getattr.as_slice()).error, 0);
+        assert_eq!(server.fs.getattrs.load(Ordering::Relaxed), 1);
+    }
+
+    #[cfg(all(debug_assertions, feature = "check-out-header"))]
+    #[test]
+    #[should_panic(expected = "doesn't match")]
+    fn test_check_out_header() {
+        let header = OutHeader {
+            len: 24,
+            ..Default::default()
+        };
+        check_out_header(&header, 24);
+        check_out_header(&header, 16);
+    }
+
+    #[test]
+    fn test_set_congested() {
+        let dir = vmm_sys_util::tempdir::TempDir::new().unwrap();
+        let path = dir.as_path().join("congestion_threshold");
+        std::fs::write(&path, "12\n").unwrap();
+        let server: Server<FlakyFs> = Server::new(FlakyFs::default());
+        let e = server.set_congested(true).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ENOTCONN));
+
+        server.set_connection_dir(dir.as_path().to_path_buf());
+        assert!(!server.congested());
+        server.set_congested(true).unwrap();
+        assert!(server.congested());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "0");
+        // Setting the same state again doesn't lose the saved threshold.
+        server.set_congested(true).unwrap();
+        server.set_congested(false).unwrap();
+        assert!(!server.congested());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "12");
+        server.set_congested(false).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "12");
+    }
+
+    // Inode 2 is inside of `/jail`, inode 3 is outside, inode 4 has been unlinked and inode 5
+    // can't be reached from the root.
+    #[derive(Default)]
+    struct JailFs {
+        unlinks: AtomicU32,
+    }
+
+    impl FileSystem for JailFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn canonical_path(&self, _ctx: &Context, inode: u64) -> io::Result<CString> {
+            match inode {
+                1 => Ok(CString::new("/jail").unwrap()),
+                2 => Ok(CString::new("/jail/dir").unwrap()),
+                3 => Ok(CString::new("/jailbreak").unwrap()),
+                4 => Err(io::Error::from_raw_os_error(libc::ENOENT)),
+                _ => Err(io::Error::from_raw_os_error(libc::EXDEV)),
+            }
+        }
+
+        fn unlink(&self, _ctx: &Context, _parent: u64, _name: &CStr) -> io::Result<()> {
+            self.unlinks.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+
+        fn rename(
+            &self,
+            _ctx: &Context,
+            _olddir: u64,
+            _oldname: &CStr,
+            _newdir: u64,
+            _newname: &CStr,
+            _flags: u32,
+        ) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[cfg(not(feature = "virtiofs"))]
+    #[test]
+    fn test_path_jail() {
+        let send = |server: &Server<JailFs>, opcode: Opcode, nodeid: u64, body: &[u8]| {
+            let in_header = InHeader {
+                len: (size_of::<InHeader>() + body.len()) as u32,
+                opcode: opcode as u32,
+                unique: 1,
+                nodeid,
+                ..Default::default()
+            };
+            let mut req = in_header.as_slice().to_vec();
+            req.extend_from_slice(body);
+            let mut owned = Writer::<()>::new_owned(0x1000);
+            server
+                .handle_message(Reader::from_vec(req), owned.writer(), None, None)
+                .unwrap();
+            let reply = owned.into_inner();
+            OutHeader::from_slice(&reply[..size_of::<OutHeader>()])
+                .unwrap()
+                .error
+        };
+        let rename = |newdir: u64| {
+            let mut body = RenameIn { newdir }.as_slice().to_vec();
+            body.extend_from_slice(b"a\0b\0");
+            body
+        };
+
+        let server = Server::new(JailFs::default());
+        // Nothing is checked without a jail.
+        assert_eq!(send(&server, Opcode::Unlink, 3, b"name\0"), 0);
+
+        server.set_path_jail(PathBuf::from("/jail"));
+        assert_eq!(send(&server, Opcode::Unlink, 2, b"name\0"), 0);
+        assert_eq!(send(&server, Opcode::Unlink, 3, b"name\0"), -libc::EACCES);
+        // Fail closed if the path can't be resolved.
+        assert_eq!(send(&server, Opcode::Unlink, 5, b"name\0"), -libc::EACCES);
+        assert_eq!(server.fs.unlinks.load(Ordering::Relaxed), 2);
+
+        // Unlinked files may still be modified, but not entries of unlinked directories.
+        assert_eq!(send(&server, Opcode::Unlink, 4, b"name\0"), -libc::EACCES);
+        assert_eq!(
+            send(&server, Opcode::Setattr, 4, SetattrIn::default().as_slice()),
+            -libc::ENOSYS
+        );
+        let link = |oldnodeid: u64| {
+            let mut body = LinkIn { oldnodeid }.as_slice().to_vec();
+            body.extend_from_slice(b"name\0");
+            body
+        };
+        assert_eq!(send(&server, Opcode::Link, 2, &link(4)), -libc::ENOSYS);
+        assert_eq!(send(&server, Opcode::Link, 4, &link(2)), -libc::EACCES);
+
+        // Only opens truncating the file are checked.
+        let open = |flags: i32| {
+            OpenIn {
+                flags: flags as u32,
+                fuse_flags: 0,
+            }
+            .as_slice()
+            .to_vec()
+        };
+        assert_eq!(send(&server, Opcode::Open, 3, &open(libc::O_RDWR)), 0);
+        assert_eq!(
+            send(
+                &server,
+                Opcode::Open,
+                3,
+                &open(libc::O_RDWR | libc::O_TRUNC)
+            ),
+            -libc::EACCES
+        );
+        assert_eq!(
+            send(
+                &server,
+                Opcode::Open,
+                2,
+                &open(libc::O_RDWR | libc::O_TRUNC)
+            ),
+            0
+        );
+
+        // Both directories of a rename must be inside of the jail.
+        assert_eq!(send(&server, Opcode::Rename, 1, &rename(2)), 0);
+        assert_eq!(send(&server, Opcode::Rename, 2, &rename(3)), -libc::EACCES);
+        assert_eq!(send(&server, Opcode::Rename, 3, &rename(2)), -libc::EACCES);
+
+        // Requests not modifying anything are not checked.
+        assert_eq!(
+            send(&server, Opcode::Getattr, 3, GetattrIn::default().as_slice()),
+            -libc::ENOSYS
+        );
+    }
+
+    #[cfg(feature = "panic-guard")]
+    struct PanicFs;
+
+    #[cfg(feature = "panic-guard")]
+    impl FileSystem for PanicFs {
+        type Inode = u64;
+        type Handle = u64;
+
+        fn getattr(
+            &self,
+            _ctx: &Context,
+            inode: u64,
+            _handle: Option<u64>,
+        ) -> io::Result<(stat64, Duration)> {
+            assert_ne!(inode, 2, "bad inode");
+            let mut st: stat64 = unsafe { std::mem::zeroed() };
+            st.st_ino = inode;
+            Ok((st, Duration::ZERO))
+        }
+    }
+
+    #[cfg(all(feature = "panic-guard", not(feature = "virtiofs")))]
+    #[test]
+    fn test_panic_guard() {
+        let send = |server: &Server<PanicFs>, nodeid: u64| -> OutHeader {
+            let in_header = InHeader {
+                len: (size_of::<InHeader>() + size_of::<GetattrIn>()) as u32,
+                opcode: Opcode::Getattr as u32,
+                unique: nodeid,
+                nodeid,
+                ..Default::default()
+            };
+            let mut req = in_header.as_slice().to_vec();
+            req.extend_from_slice(GetattrIn::default().as_slice());
+            let mut owned = Writer::<()>::new_owned(0x1000);
+            server
+                .handle_message(Reader::from_vec(req), owned.writer(), None, None)
+                .unwrap();
+            let reply = owned.into_inner();
+            let out = *OutHeader::from_slice(&reply[..size_of::<OutHeader>()]).unwrap();
+            assert_eq!(reply.len(), out.len as usize);
+            out
+        };
+
+        let server = Server::new(PanicFs);
+        let out = send(&server, 2);
+        assert_eq!((out.error, out.unique), (-libc::EIO, 2));
+        assert_eq!(out.len as usize, size_of::<OutHeader>());
+
+        // The server keeps serving other requests.
+        let out = send(&server, 3);
+        assert_eq!((out.error, out.unique), (0, 3));
+    }
+
+    fn encode_extension(type_: u32, payload: &[u8]) -> Vec<u8> {
+        let size = (size_of::<ExtHeader>() + payload.len() + 7) & !7;
+        let header = ExtHeader {
+            size: size as u32,
+            type_,
+        };
+        let mut buf = header.as_slice().to_vec();
+        buf.extend_from_slice(payload);
+        buf.resize(size, 0);
+        buf
+    }
+
+    #[test]
+    fn test_extract_extensions() {
+        let label = b"system_u:object_r:tmp_t:s0\0";
+        let mut groups = SuppGroups { nr_groups: 2 }.as_slice().to_vec();
+        groups.extend_from_slice(&10u32.to_ne_bytes());
+        groups.extend_from_slice(&20u32.to_ne_bytes());
+        let mut buf = encode_secctx(b"security.selinux", label);
+        buf.extend_from_slice(&encode_extension(FUSE_EXT_GROUPS, &groups));
+        buf.extend_from_slice(&encode_extension(100, b"future"));
+
+        let extensions = ServerUtil::extract_extensions(&buf).unwrap();
+        let secctx = SecContext {
+            name: CString::new("security.selinux").unwrap(),
+            value: label.to_vec(),
+        };
+        assert_eq!(
+            extensions.iter().cloned().collect::<Vec<_>>(),
+            vec![
+                Extension::SecCtx(vec![secctx.clone()]),
+                Extension::SuppGroups(vec![10, 20]),
+                Extension::Unknown(100, b"future\0\0".to_vec()),
+            ]
+        );
+        assert_eq!(extensions.secctx(), Some(&[secctx][..]));
+        assert_eq!(extensions.supp_groups(), Some(&[10, 20][..]));
+        assert!(ServerUtil::extract_extensions(&[]).unwrap().is_empty());
+
+        // Extensions must be aligned to 8 bytes and fit in the buffer.
+        ServerUtil::extract_extensions(&buf[..4]).unwrap_err();
+        ServerUtil::extract_extensions(&encode_extension(100, b"future")[..12]).unwrap_err();
+        let mut misaligned = encode_extension(100, b"future");
+        misaligned[0] = 12;
+        ServerUtil::extract_extensions(&misaligned).unwrap_err();
+        // Too many groups for the extension.
+        let mut groups = SuppGroups { nr_groups: 3 }.as_slice().to_vec();
+        groups.extend_from_slice(&10u32.to_ne_bytes());
+        ServerUtil::extract_extensions(&encode_extension(FUSE_EXT_GROUPS, &groups)).unwrap_err();
+    }
+
+    #[cfg(not(feature = "virtiofs"))]
+    #[test]
+    fn test_request_extensions() {
+        let server: Server<ExtFs> = Server::new(ExtFs::default());
+        let handle = |arg: &[u8], ext: &[u8]| -> OutHeader {
+            let in_header = InHeader {
+                len: (size_of::<InHeader>() + arg.len() + ext.len()) as u32,
+                opcode: Opcode::Mknod as u32,
+                unique: 7,
+                nodeid: 1,
+                total_extlen: (ext.len() / 8) as u16,
+                ..Default::default()
+            };
+            let mut req = in_header.as_slice().to_vec();
+            req.extend_from_slice(arg);
+            req.extend_from_slice(ext);
+            let mut owned = Writer::<()>::new_owned(0x1000);
+            let res = server.handle_message(Reader::from_vec(req), owned.writer(), None, None);
+            let reply = owned.into_inner();
+            let header = *OutHeader::from_slice(&reply[..size_of::<OutHeader>()]).unwrap();
+            if header.error == 0 {
+                res.unwrap();
+            }
+            header
+        };
+
+        let mut mknod = MknodIn {
+            mode: libc::S_IFCHR | 0o600,
+            ..Default::default()
+        }
+        .as_slice()
+        .to_vec();
+        mknod.extend_from_slice(b"dev\0");
+        let mut groups = SuppGroups { nr_groups: 1 }.as_slice().to_vec();
+        groups.extend_from_slice(&10u32.to_ne_bytes());
+        let mut ext = encode_extension(FUSE_EXT_GROUPS, &groups);
+        ext.extend_from_slice(&encode_extension(100, &[1u8; 8]));
+
+        let header = handle(&mknod, &ext);
+        assert_eq!(header.error, 0);
+        // Requests without extensions are handled as before.
+        let header = handle(&mknod, &[]);
+        assert_eq!(header.error, 0);
+        // Malformed extensions are rejected.
+        let mut bad = encode_extension(100, &[1u8; 8]);
+        bad[0] = 24;
+        let header = handle(&mknod, &bad);
+        assert_eq!(header.error, -libc::EINVAL);
+
+        let extensions = server.fs.extensions.lock().unwrap().clone();
+        assert_eq!(
+            extensions,
+            vec![
+                Extensions::new(vec![
+                    Extension::SuppGroups(vec![10]),
+                    Extension::Unknown(100, vec![1u8; 8]),
+                ]),
+                Extensions::default(),
+            ]
+        );
+    }
+
+    #[cfg(not(feature = "virtiofs"))]
+    #[test]
+    fn test_security_context() {
+        use crate::transport::FuseBuf;
+        use std::io::{Seek, SeekFrom};
+        use std::os::unix::io::AsRawFd;
+        use vmm_sys_util::tempfile::TempFile;
+
+        let server: Server<SecctxFs> = Server::new(SecctxFs::default());
+        let mut file = TempFile::new().unwrap().into_file();
+        let mut handle = |opcode: Opcode, nodeid: u64, arg: &[u8]| -> (i32, Vec<u8>) {
+            let in_header = InHeader {
+                len: (size_of::<InHeader>() + arg.len()) as u32,
+                opcode: opcode as u32,
+                unique: 5,
+                nodeid,
+                ..Default::default()
+            };
+            let mut req = in_header.as_slice().to_vec();
+            req.extend_from_slice(arg);
+            let mut buf = vec![0u8; 0x1000];
+            let r = Reader::<()>::new(FuseBuf::new(&mut req)).unwrap();
+            let w = Writer::<()>::new(file.as_raw_fd(), &mut buf).unwrap();
+            server.handle_message(r, w, None, None).unwrap();
+
+            let mut reply = Vec::new();
+            file.seek(SeekFrom::Start(0)).unwrap();
+            file.read_to_end(&mut reply).unwrap();
+            file.set_len(0).unwrap();
+            file.seek(SeekFrom::Start(0)).unwrap();
+            let header = OutHeader::from_slice(&reply[..size_of::<OutHeader>()]).unwrap();
+            assert_eq!(header.len as usize, reply.len());
+            (header.error, reply.split_off(size_of::<OutHeader>()))
+        };
+
+        // The security context is negotiated by the extended flags of the INIT request.
+        let mut init = InitIn {
+            major: KERNEL_VERSION,
+            minor: KERNEL_MINOR_VERSION_INIT_EXT,
+            flags: INIT_EXT,
+            ..Default::default()
+        }
+        .as_slice()
+        .to_vec();
+        init.extend_from_slice(
+            InitInExt {
+                flags2: FsOptionsExt::SECURITY_CTX.bits(),
+                ..Default::default()
+            }
+            .as_slice(),
+        );
+        let (error, reply) = handle(Opcode::Init, 0, &init);
+        assert_eq!(error, 0);
+        let out = InitOut::from_slice(&reply).unwrap();
+        // The file system hasn't asked for it.
+        assert_eq!(out.flags & INIT_EXT, 0);
+        assert_eq!(out.flags2, 0);
+        assert!(!server
+            .negotiated_options_ext()
+            .contains(FsOptionsExt::SECURITY_CTX));
+        server.opts_ext.store(Arc::new(FsOptionsExt::SECURITY_CTX));
+
+        let label = b"system_u:object_r:tmp_t:s0\0";
+        let mut symlink = b"link\0target\0".to_vec();
+        symlink.extend_from_slice(&encode_secctx(b"security.selinux", label));
+        let (error, reply) = handle(Opcode::Symlink, 1, &symlink);
+        assert_eq!(error, 0);
+        assert_eq!(EntryOut::from_slice(&reply).unwrap().nodeid, 10);
+
+        let mut mknod = MknodIn {
+            mode: libc::S_IFCHR | 0o600,
+            ..Default::default()
+        }
+        .as_slice()
+        .to_vec();
+        mknod.extend_from_slice(b"dev\0");
+        mknod.extend_from_slice(&encode_secctx(b"security.selinux", label));
+        let (error, reply) = handle(Opcode::Mknod, 1, &mknod);
+        assert_eq!(error, 0);
+        assert_eq!(EntryOut::from_slice(&reply).unwrap().nodeid, 11);
+
+        let labels = server.fs.labels.lock().unwrap().clone();
+        assert_eq!(labels.len(), 2);
+        for ((inode, name, secctx), (ino, n)) in labels.iter().zip([(10, "link"), (11, "dev")]) {
+            assert_eq!(*inode, ino);
+            assert_eq!(name.to_str().unwrap(), n);
+            assert_eq!(secctx.name.to_str().unwrap(), "security.selinux");
+            assert_eq!(secctx.value, label);
+        }
+        assert!(server.fs.forgotten.lock().unwrap().is_empty());
+
+        // The new node is forgotten if it can't be labeled, or the context is malformed.
+        let mut symlink = b"bad\0target\0".to_vec();
+        symlink.extend_from_slice(&encode_secctx(b"security.selinux", label));
+        let (error, _) = handle(Opcode::Symlink, 1, &symlink);
+        assert_eq!(error, -libc::EPERM);
+        let mut mknod = MknodIn::default().as_slice().to_vec();
+        mknod.extend_from_slice(b"dev\0\x10\0\0\0");
+        let (error, _) = handle(Opcode::Mknod, 1, &mknod);
+        assert_eq!(error, -libc::EINVAL);
+        assert_eq!(*server.fs.forgotten.lock().unwrap(), vec![10, 11]);
+        assert_eq!(server.fs.labels.lock().unwrap().len(), 2);
+    }
+
+    struct TestMiddleware {
+        tag: u8,
+        order: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl ServerMiddleware for TestMiddleware {
+        fn before(&self, in_header: &InHeader) -> io::Result<()> {
+            self.order.lock().unwrap().push(self.tag);
+            if in_header.opcode == Opcode::Getattr as u32 {
+                return Err(io::Error::from_raw_os_error(libc::EPERM));
+            }
+            Ok(())
+        }
+
+        fn after(&self, _in_header: &InHeader, reply: &mut Vec<u8>) {
+            self.order.lock().unwrap().push(self.tag);
+            reply.push(self.tag);
+        }
+    }
+
+    #[cfg(not(feature = "virtiofs"))]
+    #[test]
+    fn test_middleware() {
+        use crate::transport::FuseBuf;
+        use std::io::{Seek, SeekFrom};
+        use std::os::unix::io::AsRawFd;
+        use vmm_sys_util::tempfile::TempFile;
+
+        let server: Server<crate::api::Vfs> = Server::new(crate::api::Vfs::default());
+        let order = Arc::new(Mutex::new(Vec::new()));
+        for tag in 1..=2 {
+            server.add_middleware(Arc::new(TestMiddleware {
+                tag,
+                order: order.clone(),
+            }));
+        }
+        let mut file = TempFile::new().unwrap().into_file();
+
+        let mut handle = |opcode: u32| -> Vec<u8> {
+            let in_header = InHeader {
+                len: size_of::<InHeader>() as u32,
+                opcode,
+                unique: 3,
+                ..Default::default()
+            };
+            let mut req = in_header.as_slice().to_vec();
+            let mut buf = vec![0u8; 0x1000];
+            let r = Reader::<()>::new(FuseBuf::new(&mut req)).unwrap();
+            let w = Writer::<()>::new(file.as_raw_fd(), &mut buf).unwrap();
+            server.handle_message(r, w, None, None).unwrap();
+
+            let mut reply = Vec::new();
+            file.seek(SeekFrom::Start(0)).unwrap();
+            file.read_to_end(&mut reply).unwrap();
+            file.set_len(0).unwrap();
+            file.seek(SeekFrom::Start(0)).unwrap();
+            reply
+        };
+
+        // The reply passes through the `after()` hooks in reverse order.
+        let reply = handle(Opcode::MaxOpcode as u32);
+        let header = OutHeader::from_slice(&reply[..size_of::<OutHeader>()]).unwrap();
+        assert_eq!(header.len as usize, size_of::<OutHeader>() + 2);
+        assert_eq!(header.error, -libc::ENOSYS);
+        assert_eq!(header.unique, 3);
+        assert_eq!(&reply[size_of::<OutHeader>()..], &[2, 1]);
+        assert_eq!(*order.lock().unwrap(), vec![1, 2, 2, 1]);
+
+        // A request rejected by `before()` skips the remaining hooks.
+        order.lock().unwrap().clear();
+        let reply = handle(Opcode::Getattr as u32);
+        let header = OutHeader::from_slice(&reply).unwrap();
+        assert_eq!(reply.len(), size_of::<OutHeader>());
+        assert_eq!(header.error, -libc::EPERM);
+        assert_eq!(*order.lock().unwrap(), vec![1]);
+    }
+
+    #[cfg(not(feature = "virtiofs"))]
+    #[test]
+    fn test_decode_errors() {
+        let server: Server<FlakyFs> = Server::new(FlakyFs::default());
+        let send = |opcode: Opcode, len: usize, body: &[u8]| {
+            let in_header = InHeader {
+                len: len as u32,
+                opcode: opcode as u32,
+                unique: 1,
+                nodeid: 1,
+                ..Default::default()
+            };
+            let mut req = in_header.as_slice().to_vec();
+            req.extend_from_slice(body);
+            let mut owned = Writer::<()>::new_owned(0x1000);
+            let err = match server.handle_message(Reader::from_vec(req), owned.writer(), None, None)
+            {
+                Err(Error::InvalidMessage(e)) => e,
+                res => panic!("unexpected result {:?}", res),
+            };
+            let reply = owned.into_inner();
+            let header = OutHeader::from_slice(&reply[..size_of::<OutHeader>()]).unwrap();
+            assert_eq!(header.error, -err.errno());
+            err
+        };
+
+        // The body is shorter than `GetattrIn`.
+        let err = send(Opcode::Getattr, size_of::<InHeader>() + 16, &[0u8; 4]);
+        assert_eq!(err, DecodeError::Truncated { needed: 16, got: 4 });
+        assert_eq!(err.errno(), libc::EIO);
+
+        // The length of the request is shorter than its header.
+        let err = send(Opcode::Lookup, 10, b"name\0");
+        assert_eq!(
+            err,
+            DecodeError::InvalidField {
+                name: "len",
+                value: 10
+            }
+        );
+        assert_eq!(err.errno(), libc::EINVAL);
+
+        let init = InitIn {
+            major: 6,
+            minor: 31,
+            ..Default::default()
+        };
+        let err = send(
+            Opcode::Init,
+            size_of::<InHeader>() + size_of::<InitIn>(),
+            init.as_slice(),
+        );
+        assert_eq!(
+            err,
+            DecodeError::UnsupportedVersion {
+                major: 6,
+                minor: 31
+            }
+        );
+        assert_eq!(err.errno(), libc::EPROTO);
+
+        // An extension whose size isn't a multiple of 8 bytes.
+        let mut ext = ExtHeader {
+            size: 12,
+            type_: 100,
+        }
+        .as_slice()
+        .to_vec();
+        ext.extend_from_slice(&[0u8; 8]);
+        match ServerUtil::extract_extensions(&ext) {
+            Err(Error::InvalidMessage(e)) => {
+                assert_eq!(e, DecodeError::BadAlignment { size: 12, align: 8 });
+                assert_eq!(e.errno(), libc::EINVAL);
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
     }
 }
@@ -0,0 +1,282 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Serve multiple fuse mountpoints by a single thread.
+//!
+//! Each session added by [Server::add_session()](super::Server::add_session) is served by a
+//! dedicated [Server](super::Server) instance, so every mountpoint has its own filesystem driver
+//! and negotiates its own INIT parameters with the kernel. The fuse device files of all sessions
+//! are multiplexed by [Server::run()](super::Server::run) with `poll(2)`.
+
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+
+use super::Server;
+use crate::api::filesystem::FileSystem;
+use crate::async_util::AsyncDrive;
+use crate::transport::fusedev::{Error, FuseChannel, FuseSession, Result};
+
+struct MountSession<F: FileSystem + Sync, D: AsyncDrive> {
+    session: FuseSession,
+    channel: Mutex<FuseChannel>,
+    server: Server<F, D>,
+}
+
+/// Fuse sessions served by [Server::run()](super::Server::run).
+pub(super) struct MountSessions<F: FileSystem + Sync, D: AsyncDrive> {
+    sessions: Mutex<Vec<Arc<MountSession<F, D>>>>,
+    // Wake up `run()` to pick up changes of the session list.
+    event: Mutex<Option<Arc<EventFd>>>,
+    stopping: AtomicBool,
+}
+
+impl<F: FileSystem + Sync, D: AsyncDrive> Default for MountSessions<F, D> {
+    fn default() -> Self {
+        MountSessions {
+            sessions: Mutex::new(Vec::new()),
+            event: Mutex::new(None),
+            stopping: AtomicBool::new(false),
+        }
+    }
+}
+
+impl<F: FileSystem + Sync, D: AsyncDrive> MountSessions<F, D> {
+    fn notify(&self) -> Result<()> {
+        if let Some(event) = self.event.lock().unwrap().as_ref() {
+            event.write(1).map_err(Error::IoError)?;
+        }
+        Ok(())
+    }
+
+    fn remove(&self, session: &Arc<MountSession<F, D>>) {
+        self.sessions
+            .lock()
+            .unwrap()
+            .retain(|s| !Arc::ptr_eq(s, session));
+    }
+}
+
+impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
+    /// Add a connected fuse session to be served by [run()](Self::run) with the filesystem `fs`.
+    ///
+    /// The session is served by a dedicated `Server` instance, so the options negotiated by the
+    /// FUSE_INIT request, middlewares and other settings of `self` don't apply to it. The session
+    /// is umounted when dropped, that is after the kernel has closed the connection or the
+    /// session has been stopped by [stop_sessions()](Self::stop_sessions).
+    pub fn add_session(&self, session: FuseSession, fs: F) -> Result<()> {
+        let channel = session.new_channel()?;
+        let mount = Arc::new(MountSession {
+            session,
+            channel: Mutex::new(channel),
+            server: Server::new(fs),
+        });
+
+        self.sessions.sessions.lock().unwrap().push(mount);
+        self.sessions.notify()
+    }
+
+    /// Get the number of sessions added by [add_session()](Self::add_session) and still being
+    /// served.
+    pub fn session_count(&self) -> usize {
+        self.sessions.sessions.lock().unwrap().len()
+    }
+
+    /// Serve requests from all sessions added by [add_session()](Self::add_session) on the
+    /// current thread.
+    ///
+    /// It returns once all sessions have been closed, or [stop_sessions()](Self::stop_sessions)
+    /// has been called.
+    pub fn run(&self) -> Result<()> {
+        let event = Arc::new(EventFd::new(EFD_NONBLOCK).map_err(Error::IoError)?);
+        *self.sessions.event.lock().unwrap() = Some(event.clone());
+
+        let result = self.run_sessions(&event);
+        *self.sessions.event.lock().unwrap() = None;
+
+        result
+    }
+
+    /// Stop [run()](Self::run) and all sessions being served.
+    pub fn stop_sessions(&self) -> Result<()> {
+        self.sessions.stopping.store(true, Ordering::Release);
+        for mount in self.sessions.sessions.lock().unwrap().iter() {
+            mount.session.wake()?;
+        }
+        self.sessions.notify()
+    }
+
+    fn run_sessions(&self, event: &EventFd) -> Result<()> {
+        loop {
+            if self.sessions.stopping.load(Ordering::Acquire) {
+                self.sessions.sessions.lock().unwrap().clear();
+                return Ok(());
+            }
+
+            let sessions = self.sessions.sessions.lock().unwrap().clone();
+            if sessions.is_empty() {
+                return Ok(());
+            }
+
+            let mut fds = Vec::with_capacity(sessions.len() + 1);
+            fds.push(libc::pollfd {
+                fd: event.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            });
+            for mount in sessions.iter() {
+                fds.push(libc::pollfd {
+                    fd: mount.channel.lock().unwrap().poll_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                });
+            }
+
+            // Safe because the pollfd array is valid during the call.
+            let ret = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+            if ret < 0 {
+                let e = std::io::Error::last_os_error();
+                if e.kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(Error::IoError(e));
+            }
+
+            if fds[0].revents != 0 {
+                let _ = event.read();
+            }
+            for (mount, fd) in sessions.iter().zip(fds[1..].iter()) {
+                if fd.revents != 0 {
+                    self.serve_session(mount);
+                }
+            }
+        }
+    }
+
+    // Handle one request from the session, the session is dropped once it has been closed.
+    fn serve_session(&self, mount: &Arc<MountSession<F, D>>) {
+        let mut channel = mount.channel.lock().unwrap();
+
+        match channel.get_request() {
+            Ok(Some((reader, writer))) => {
+                if let Err(e) = mount.server.handle_message(reader, writer, None, None) {
+                    error!(
+                        "fuse: failed to handle request for {:?}, {}",
+                        mount.session.mountpoint(),
+                        e
+                    );
+                }
+            }
+            Ok(None) => {
+                info!("fuse: session {:?} stopped", mount.session.mountpoint());
+                self.sessions.remove(mount);
+            }
+            Err(Error::SessionClosed) => {
+                info!("fuse: session {:?} closed", mount.session.mountpoint());
+                mount.server.shutdown();
+                self.sessions.remove(mount);
+            }
+            Err(e) => {
+                error!(
+                    "fuse: failed to get request for {:?}, {}",
+                    mount.session.mountpoint(),
+                    e
+                );
+                mount.server.shutdown();
+                self.sessions.remove(mount);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abi::fuse_abi::{InHeader, InitIn, InitOut, Opcode, OutHeader};
+    use crate::api::Vfs;
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::mem::size_of;
+    use std::os::unix::io::FromRawFd;
+    use std::os::unix::net::UnixStream;
+    use vm_memory::ByteValued;
+    use vmm_sys_util::tempdir::TempDir;
+
+    // Connect a session to a seqpacket socket, the peer acts as the fuse driver.
+    fn connect(dir: &TempDir) -> (FuseSession, UnixStream) {
+        let mut fds = [0; 2];
+        let ret =
+            unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_SEQPACKET, 0, fds.as_mut_ptr()) };
+        assert_eq!(ret, 0);
+
+        let mut session = FuseSession::builder(dir.as_path())
+            .fsname("test")
+            .build()
+            .unwrap();
+        session.set_fuse_file(unsafe { File::from_raw_fd(fds[0]) });
+        (session, unsafe { UnixStream::from_raw_fd(fds[1]) })
+    }
+
+    fn init(kernel: &mut UnixStream, minor: u32) {
+        let header = InHeader {
+            len: (size_of::<InHeader>() + size_of::<InitIn>()) as u32,
+            opcode: Opcode::Init as u32,
+            unique: 1,
+            ..Default::default()
+        };
+        let arg = InitIn {
+            major: 7,
+            minor,
+            ..Default::default()
+        };
+        let mut req = header.as_slice().to_vec();
+        req.extend_from_slice(arg.as_slice());
+        kernel.write_all(&req).unwrap();
+
+        let mut reply = vec![0u8; 0x1000];
+        let len = kernel.read(&mut reply).unwrap();
+        let header = OutHeader::from_slice(&reply[..size_of::<OutHeader>()]).unwrap();
+        assert_eq!(header.len as usize, len);
+        assert_eq!(header.error, 0);
+        assert_eq!(header.unique, 1);
+        assert_eq!(len, size_of::<OutHeader>() + size_of::<InitOut>());
+    }
+
+    #[test]
+    fn test_run_sessions() {
+        let dir = TempDir::new().unwrap();
+        let server = Arc::new(Server::<Vfs>::new(Vfs::default()));
+        let (se1, mut kernel1) = connect(&dir);
+        let (se2, mut kernel2) = connect(&dir);
+        server.add_session(se1, Vfs::default()).unwrap();
+        assert_eq!(server.session_count(), 1);
+
+        let srv = server.clone();
+        let handle = std::thread::spawn(move || srv.run());
+
+        // Sessions added after `run()` has started are served too.
+        server.add_session(se2, Vfs::default()).unwrap();
+        assert_eq!(server.session_count(), 2);
+
+        // Each session negotiates its own protocol version.
+        init(&mut kernel1, 31);
+        init(&mut kernel2, 27);
+        let minors: Vec<u32> = server
+            .sessions
+            .sessions
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|s| s.server.vers.load().minor)
+            .collect();
+        assert_eq!(minors, vec![31, 27]);
+
+        server.stop_sessions().unwrap();
+        handle.join().unwrap().unwrap();
+        assert_eq!(server.session_count(), 0);
+        drop(kernel1);
+        drop(kernel2);
+    }
+}
@@ -3,24 +3,31 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE-BSD-3-Clause file.
 
+use std::ffi::CStr;
 use std::io::{self, IoSlice, Read, Write};
 use std::mem::size_of;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::time::Duration;
 use vm_memory::ByteValued;
 
 use super::{
-    MetricsHook, Server, ServerUtil, ServerVersion, SrvContext, ZcReader, ZcWriter,
-    BUFFER_HEADER_SIZE, DIRENT_PADDING, MAX_BUFFER_SIZE, MAX_REQ_PAGES, MIN_READ_BUFFER,
+    check_out_header, expects_reply, max_payload_size, negotiate_max_write, CursorPosition,
+    MetricsHook, Retryable, Server, ServerMiddleware, ServerUtil, ServerVersion, SrvContext,
+    ZcReader, ZcWriter, BUFFER_HEADER_SIZE, DIRENT_PADDING, MAX_BUFFER_SIZE,
 };
 use crate::abi::fuse_abi::*;
+use crate::abi::init::{encode_init_out, parse_init_in, FuseInitIn, FuseInitOut};
 #[cfg(feature = "virtiofs")]
 use crate::abi::virtio_fs::{RemovemappingIn, RemovemappingOne, SetupmappingIn};
 use crate::api::filesystem::{
     DirEntry, Entry, FileSystem, GetxattrReply, IoctlData, ListxattrReply,
 };
+use crate::api::reply_buf::ReplyBuf;
 use crate::async_util::AsyncDrive;
 use crate::transport::{pagesize, FsCacheReqHandler, Reader, Writer};
+#[cfg(all(feature = "fusedev", not(feature = "virtiofs")))]
+use crate::transport::{FuseTransport, Reply};
 use crate::{bytes_to_cstr, encode_io_error_kind, BitmapSlice, Error, Result};
 
 impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
@@ -29,19 +36,120 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
     /// It receives Fuse requests from transport layers, parses the request according to Fuse ABI,
     /// invokes filesystem drivers to server the requests, and eventually send back the result to
     /// the transport layer.
-    #[allow(unused_variables)]
     pub fn handle_message<S: BitmapSlice>(
+        &self,
+        r: Reader<'_, S>,
+        w: Writer<'_, S>,
+        vu_req: Option<&mut dyn FsCacheReqHandler>,
+        hook: Option<&dyn MetricsHook>,
+    ) -> Result<usize> {
+        let middlewares = self.middlewares.load();
+        if middlewares.is_empty() {
+            self.dispatch_message(r, w, vu_req, hook)
+        } else {
+            self.handle_message_with_middlewares(&middlewares, r, w, vu_req, hook)
+        }
+    }
+
+    /// Serve requests received from a custom transport on the current thread.
+    ///
+    /// Each request is handled by [handle_message()](Self::handle_message) and its reply, if
+    /// any, is sent back through the transport. Errors handling a request are logged and don't
+    /// stop the loop, which returns once the transport has no more requests or fails.
+    #[cfg(all(feature = "fusedev", not(feature = "virtiofs")))]
+    pub fn serve_transport(&self, transport: &dyn FuseTransport) -> Result<()> {
+        while let Some(req) = transport.recv().map_err(Error::DecodeMessage)? {
+            let mut out = Writer::<()>::new_owned(transport.max_reply_size());
+            let res =
+                self.handle_message(Reader::from_vec(req.into_inner()), out.writer(), None, None);
+            if let Err(e) = res {
+                error!("fuse: failed to handle request from transport, {}", e);
+            }
+            let reply = out.into_inner();
+            if !reply.is_empty() {
+                transport
+                    .send(Reply::new(reply))
+                    .map_err(Error::EncodeMessage)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_message_with_middlewares<S: BitmapSlice>(
+        &self,
+        chain: &[Arc<dyn ServerMiddleware>],
+        r: Reader<'_, S>,
+        #[allow(unused_mut)] mut w: Writer<'_, S>,
+        vu_req: Option<&mut dyn FsCacheReqHandler>,
+        hook: Option<&dyn MetricsHook>,
+    ) -> Result<usize> {
+        let in_header: InHeader = ServerUtil::read_obj(&mut r.clone())?;
+        for middleware in chain.iter() {
+            if let Err(e) = middleware.before(&in_header) {
+                let mut ctx = SrvContext::<F, D, S>::new(in_header, r, w);
+                return ctx.reply_error(e);
+            }
+        }
+
+        // The virtio-fs transport writes replies into guest memory directly, so they can't be
+        // intercepted.
+        #[cfg(feature = "virtiofs")]
+        return self.dispatch_message(r, w, vu_req, hook);
+
+        #[cfg(not(feature = "virtiofs"))]
+        {
+            let mut buf = vec![0u8; w.available_bytes()];
+            let res = self.dispatch_message(r, w.capture(&mut buf), vu_req, hook)?;
+            let len = OutHeader::from_slice(&buf[..size_of::<OutHeader>()])
+                .map(|h| h.len as usize)
+                .unwrap_or(0);
+            if len < size_of::<OutHeader>() || len > buf.len() {
+                // No reply for the request.
+                return Ok(res);
+            }
+
+            buf.truncate(len);
+            for middleware in chain.iter().rev() {
+                middleware.after(&in_header, &mut buf);
+            }
+            if buf.len() < size_of::<OutHeader>() || buf.len() > u32::MAX as usize {
+                let mut ctx = SrvContext::<F, D, S>::new(in_header, Reader::default(), w);
+                return ctx.reply_error_explicit(io::Error::from_raw_os_error(libc::EIO));
+            }
+            let mut header = *OutHeader::from_slice(&buf[..size_of::<OutHeader>()]).unwrap();
+            header.len = buf.len() as u32;
+            buf[..size_of::<OutHeader>()].copy_from_slice(header.as_slice());
+
+            w.write_all(&buf).map_err(Error::EncodeMessage)?;
+            w.commit(None).map_err(Error::EncodeMessage)?;
+            Ok(buf.len())
+        }
+    }
+
+    #[allow(unused_variables)]
+    fn dispatch_message<S: BitmapSlice>(
         &self,
         mut r: Reader<'_, S>,
         w: Writer<'_, S>,
         vu_req: Option<&mut dyn FsCacheReqHandler>,
         hook: Option<&dyn MetricsHook>,
     ) -> Result<usize> {
-        let in_header: InHeader = r.read_obj().map_err(Error::DecodeMessage)?;
-        let mut ctx = SrvContext::<F, D, S>::new(in_header, r, w);
+        let in_header: InHeader = ServerUtil::read_obj(&mut r)?;
+        // The extensions must outlive the context borrowing them.
+        let mut header = in_header;
+        let extensions = ServerUtil::take_extensions(&mut header, &mut r);
+        let mut ctx = SrvContext::<F, D, S>::new(header, r, w);
         if ctx.in_header.len > (MAX_BUFFER_SIZE + BUFFER_HEADER_SIZE) {
             return ctx.reply_error_explicit(io::Error::from_raw_os_error(libc::ENOMEM));
         }
+        match &extensions {
+            Ok(extensions) => ctx.context.extensions = extensions,
+            Err(Error::InvalidMessage(e)) => return Err(ctx.decode_failed(*e)),
+            Err(_) => return ctx.reply_error_explicit(io::Error::from_raw_os_error(libc::EINVAL)),
+        }
+        if self.fs.wants_raw_header() {
+            ctx.context.raw_header = Some(in_header);
+        }
 
         trace!(
             "fuse: new req {:?}: {:?}",
@@ -49,9 +157,25 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
             in_header
         );
 
+        if !self.allowed_opcodes.load().allows(in_header.opcode) {
+            debug!("fuse: opcode {} is not allowed", in_header.opcode);
+            return ctx.reply_error(io::Error::from_raw_os_error(libc::ENOSYS));
+        }
+        if let Err(e) = self.check_path_jail(&ctx) {
+            return ctx.reply_error(e);
+        }
+
         hook.map_or((), |h| h.collect(&in_header));
+        self.counters.inc(in_header.opcode);
 
-        let res = match in_header.opcode {
+        // Requests without reply don't need to be tracked for timeout.
+        let tracked = self.inflight.enabled() && expects_reply(in_header.opcode);
+        if tracked {
+            self.inflight.begin(in_header.unique);
+            ctx.inflight = Some(&self.inflight);
+        }
+
+        let res = self.guard_panic(&in_header, || match in_header.opcode {
             x if x == Opcode::Lookup as u32 => self.lookup(ctx),
             x if x == Opcode::Forget as u32 => self.forget(ctx), // No reply.
             x if x == Opcode::Getattr as u32 => self.getattr(ctx),
@@ -68,6 +192,7 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
             x if x == Opcode::Read as u32 => self.read(ctx),
             x if x == Opcode::Write as u32 => self.write(ctx),
             x if x == Opcode::Statfs as u32 => self.statfs(ctx),
+            x if x == Opcode::Syncfs as u32 => self.syncfs(ctx),
             x if x == Opcode::Release as u32 => self.release(ctx),
             x if x == Opcode::Fsync as u32 => self.fsync(ctx),
             x if x == Opcode::Setxattr as u32 => self.setxattr(ctx),
@@ -94,6 +219,10 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
             x if x == Opcode::Readdirplus as u32 => self.readdirplus(ctx),
             x if x == Opcode::Rename2 as u32 => self.rename2(ctx),
             x if x == Opcode::Lseek as u32 => self.lseek(ctx),
+            x if x == Opcode::CopyFileRange as u32 => self.copyfilerange(ctx),
+            x if x == Opcode::Tmpfile as u32 => self.tmpfile(ctx),
+            x if x == Opcode::Statx as u32 => self.statx(ctx),
+            x if x == Opcode::CanonicalPath as u32 => self.canonical_path(ctx),
             #[cfg(feature = "virtiofs")]
             x if x == Opcode::SetupMapping as u32 => self.setupmapping(ctx, vu_req),
             #[cfg(feature = "virtiofs")]
@@ -110,21 +239,29 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
                 }
                 _ => ctx.reply_error(io::Error::from_raw_os_error(libc::ENOSYS)),
             },
-        };
+        });
 
         // Pass `None` because current API handler's design does not allow us to catch
         // the `out_header`. Hopefully, we can reach to `out_header` after some
         // refactoring work someday.
         hook.map_or((), |h| h.release(None));
 
+        // Stop tracking requests failed without a reply.
+        if tracked {
+            self.inflight.end(in_header.unique);
+        }
+
         res
     }
 
     fn lookup<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, D, S>) -> Result<usize> {
-        let buf = ServerUtil::get_message_body(&mut ctx.r, &ctx.in_header, 0)?;
+        let buf = ctx.message_body(0)?;
         let name = bytes_to_cstr(buf.as_ref())?;
         let version = self.vers.load();
-        let result = self.fs.lookup(ctx.context(), ctx.nodeid(), name);
+        if self.readdirplus_auto() {
+            self.rdplus.note_lookup(ctx.in_header.nodeid);
+        }
+        let result = self.with_retry(|| self.fs.lookup(ctx.context(), ctx.nodeid(), name));
 
         match result {
             // before ABI 7.4 inode == 0 was invalid, only ENOENT means negative dentry
@@ -135,6 +272,12 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
                 ctx.reply_error(io::Error::from_raw_os_error(libc::ENOENT))
             }
             Ok(entry) => {
+                if !self.claim_entry_reply(&mut ctx, &entry) {
+                    return Ok(0);
+                }
+                if self.readdirplus_auto() && entry.inode != 0 {
+                    self.rdplus.note_child(ctx.in_header.nodeid, entry.inode);
+                }
                 let out = EntryOut::from(entry);
 
                 ctx.reply_ok(Some(out), None)
@@ -144,8 +287,11 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
     }
 
     pub(super) fn forget<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, D, S>) -> Result<usize> {
-        let ForgetIn { nlookup } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        let ForgetIn { nlookup } = ctx.read_obj()?;
 
+        if self.readdirplus_auto() {
+            self.rdplus.forget(ctx.in_header.nodeid);
+        }
         self.fs.forget(ctx.context(), ctx.nodeid(), nlookup);
 
         // There is no reply for forget messages.
@@ -153,19 +299,56 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
     }
 
     fn getattr<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, D, S>) -> Result<usize> {
-        let GetattrIn { flags, fh, .. } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
-        let handle = if (flags & GETATTR_FH) != 0 {
-            Some(fh.into())
+        let GetattrIn { flags, fh, .. } = ctx.read_obj()?;
+        let fh = if (flags & GETATTR_FH) != 0 {
+            Some(fh)
         } else {
             None
         };
-        let result = self.fs.getattr(ctx.context(), ctx.nodeid(), handle);
+        if self.readdirplus_auto() {
+            self.rdplus.note_getattr(ctx.in_header.nodeid);
+        }
+        let result = self.with_retry(|| match fh {
+            None if self.getattr_batch.enabled() => {
+                self.getattr_batch
+                    .getattr(ctx.context(), ctx.in_header.nodeid, |inodes| {
+                        let inodes = inodes.into_iter().map(Into::into).collect();
+                        self.fs.getattr_batch(ctx.context(), inodes)
+                    })
+            }
+            _ => self
+                .fs
+                .getattr(ctx.context(), ctx.nodeid(), fh.map(Into::into)),
+        });
 
         ctx.handle_attr_result(result)
     }
 
+    fn statx<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, D, S>) -> Result<usize> {
+        let StatxIn {
+            getattr_flags,
+            fh,
+            sx_flags,
+            sx_mask,
+            ..
+        } = ctx.read_obj()?;
+        let handle = if (getattr_flags & GETATTR_FH) != 0 {
+            Some(fh.into())
+        } else {
+            None
+        };
+
+        match self
+            .fs
+            .statx(ctx.context(), ctx.nodeid(), handle, sx_flags, sx_mask)
+        {
+            Ok((stat, timeout)) => ctx.reply_ok(Some(StatxOut::new(stat, timeout)), None),
+            Err(e) => ctx.reply_error(e),
+        }
+    }
+
     fn setattr<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, D, S>) -> Result<usize> {
-        let setattr_in: SetattrIn = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        let setattr_in: SetattrIn = ctx.read_obj()?;
         let handle = if setattr_in.valid & FATTR_FH != 0 {
             Some(setattr_in.fh.into())
         } else {
@@ -193,15 +376,29 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
         }
     }
 
+    fn canonical_path<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, D, S>) -> Result<usize> {
+        match self.fs.canonical_path(ctx.context(), ctx.nodeid()) {
+            // The path is replied as a NUL-terminated string.
+            Ok(path) => ctx.reply_ok(None::<u8>, Some(path.as_bytes_with_nul())),
+            Err(e) => ctx.reply_error(e),
+        }
+    }
+
     pub(super) fn symlink<S: BitmapSlice>(
         &self,
         mut ctx: SrvContext<'_, F, D, S>,
     ) -> Result<usize> {
-        let buf = ServerUtil::get_message_body(&mut ctx.r, &ctx.in_header, 0)?;
+        let buf = ctx.message_body(0)?;
         // The name and linkname are encoded one after another and separated by a nul character.
         let (name, linkname) = ServerUtil::extract_two_cstrs(&buf)?;
+        let secctx = &buf[name.to_bytes_with_nul().len() + linkname.to_bytes_with_nul().len()..];
 
-        match self.fs.symlink(ctx.context(), linkname, ctx.nodeid(), name) {
+        match self
+            .fs
+            .symlink(ctx.context(), linkname, ctx.nodeid(), name)
+            .and_then(|entry| self.init_secctx(&ctx, name, &entry, secctx).map(|_| entry))
+        {
+            Ok(entry) if !self.claim_entry_reply(&mut ctx, &entry) => Ok(0),
             Ok(entry) => ctx.reply_ok(Some(EntryOut::from(entry)), None),
             Err(e) => ctx.reply_error(e),
         }
@@ -210,35 +407,41 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
     pub(super) fn mknod<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, D, S>) -> Result<usize> {
         let MknodIn {
             mode, rdev, umask, ..
-        } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
-        let buf = ServerUtil::get_message_body(&mut ctx.r, &ctx.in_header, size_of::<MknodIn>())?;
+        } = ctx.read_obj()?;
+        let buf = ctx.message_body(size_of::<MknodIn>())?;
         let name = bytes_to_cstr(buf.as_ref())?;
+        let secctx = &buf[name.to_bytes_with_nul().len()..];
 
         match self
             .fs
             .mknod(ctx.context(), ctx.nodeid(), name, mode, rdev, umask)
+            .and_then(|entry| self.init_secctx(&ctx, name, &entry, secctx).map(|_| entry))
         {
+            Ok(entry) if !self.claim_entry_reply(&mut ctx, &entry) => Ok(0),
             Ok(entry) => ctx.reply_ok(Some(EntryOut::from(entry)), None),
             Err(e) => ctx.reply_error(e),
         }
     }
 
     pub(super) fn mkdir<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, D, S>) -> Result<usize> {
-        let MkdirIn { mode, umask } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
-        let buf = ServerUtil::get_message_body(&mut ctx.r, &ctx.in_header, size_of::<MkdirIn>())?;
+        let MkdirIn { mode, umask } = ctx.read_obj()?;
+        let buf = ctx.message_body(size_of::<MkdirIn>())?;
         let name = bytes_to_cstr(buf.as_ref())?;
+        let secctx = &buf[name.to_bytes_with_nul().len()..];
 
         match self
             .fs
             .mkdir(ctx.context(), ctx.nodeid(), name, mode, umask)
+            .and_then(|entry| self.init_secctx(&ctx, name, &entry, secctx).map(|_| entry))
         {
+            Ok(entry) if !self.claim_entry_reply(&mut ctx, &entry) => Ok(0),
             Ok(entry) => ctx.reply_ok(Some(EntryOut::from(entry)), None),
             Err(e) => ctx.reply_error(e),
         }
     }
 
     pub(super) fn unlink<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, D, S>) -> Result<usize> {
-        let buf = ServerUtil::get_message_body(&mut ctx.r, &ctx.in_header, 0)?;
+        let buf = ctx.message_body(0)?;
         let name = bytes_to_cstr(buf.as_ref())?;
 
         match self.fs.unlink(ctx.context(), ctx.nodeid(), name) {
@@ -248,7 +451,7 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
     }
 
     pub(super) fn rmdir<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, D, S>) -> Result<usize> {
-        let buf = ServerUtil::get_message_body(&mut ctx.r, &ctx.in_header, 0)?;
+        let buf = ctx.message_body(0)?;
         let name = bytes_to_cstr(buf.as_ref())?;
 
         match self.fs.rmdir(ctx.context(), ctx.nodeid(), name) {
@@ -264,7 +467,7 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
         newdir: u64,
         flags: u32,
     ) -> Result<usize> {
-        let buf = ServerUtil::get_message_body(&mut ctx.r, &ctx.in_header, msg_size)?;
+        let buf = ctx.message_body(msg_size)?;
         let (oldname, newname) = ServerUtil::extract_two_cstrs(&buf)?;
 
         match self.fs.rename(
@@ -281,7 +484,7 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
     }
 
     pub(super) fn rename<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, D, S>) -> Result<usize> {
-        let RenameIn { newdir, .. } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        let RenameIn { newdir, .. } = ctx.read_obj()?;
 
         self.do_rename(ctx, size_of::<RenameIn>(), newdir, 0)
     }
@@ -290,7 +493,7 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
         &self,
         mut ctx: SrvContext<'_, F, D, S>,
     ) -> Result<usize> {
-        let Rename2In { newdir, flags, .. } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        let Rename2In { newdir, flags, .. } = ctx.read_obj()?;
 
         #[cfg(target_os = "linux")]
         let flags =
@@ -303,29 +506,41 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
     }
 
     pub(super) fn link<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, D, S>) -> Result<usize> {
-        let LinkIn { oldnodeid } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
-        let buf = ServerUtil::get_message_body(&mut ctx.r, &ctx.in_header, size_of::<LinkIn>())?;
+        let LinkIn { oldnodeid } = ctx.read_obj()?;
+        let buf = ctx.message_body(size_of::<LinkIn>())?;
         let name = bytes_to_cstr(buf.as_ref())?;
 
         match self
             .fs
             .link(ctx.context(), oldnodeid.into(), ctx.nodeid(), name)
         {
+            Ok(entry) if !self.claim_entry_reply(&mut ctx, &entry) => Ok(0),
             Ok(entry) => ctx.reply_ok(Some(EntryOut::from(entry)), None),
             Err(e) => ctx.reply_error(e),
         }
     }
 
     fn open<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, D, S>) -> Result<usize> {
-        let OpenIn { flags, fuse_flags } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        let OpenIn { flags, fuse_flags } = ctx.read_obj()?;
 
         match self.fs.open(ctx.context(), ctx.nodeid(), flags, fuse_flags) {
             Ok((handle, opts)) => {
-                let out = OpenOut {
-                    fh: handle.map(Into::into).unwrap_or(0),
-                    open_flags: opts.bits(),
-                    ..Default::default()
-                };
+                if !ctx.claim_reply() {
+                    warn!("fuse: drop late reply for timed out open {}", ctx.unique());
+                    if let Some(handle) = handle {
+                        let _ = self.fs.release(
+                            ctx.context(),
+                            ctx.nodeid(),
+                            flags,
+                            handle,
+                            false,
+                            false,
+                            None,
+                        );
+                    }
+                    return Ok(0);
+                }
+                let out = OpenOut::new(handle.map(Into::into).unwrap_or(0), opts);
 
                 ctx.reply_ok(Some(out), None)
             }
@@ -342,7 +557,7 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
             lock_owner,
             flags,
             ..
-        } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        } = ctx.read_obj()?;
 
         if size > MAX_BUFFER_SIZE {
             return ctx.reply_error_explicit(io::Error::from_raw_os_error(libc::ENOMEM));
@@ -360,17 +575,33 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
             Err(_e) => return Err(Error::InvalidHeaderLength),
         };
         let mut data_writer = ZcWriter(w2);
+        let result = self.with_retry(|| {
+            self.fs
+                .read(
+                    ctx.context(),
+                    ctx.nodeid(),
+                    fh.into(),
+                    &mut data_writer,
+                    size,
+                    offset,
+                    owner,
+                    flags,
+                )
+                // Data already written can't be taken back, so a partial read is not retried.
+                .map_err(|e| {
+                    if data_writer.0.bytes_written() > 0 {
+                        Retryable::into_inner(e)
+                    } else {
+                        e
+                    }
+                })
+        });
 
-        match self.fs.read(
-            ctx.context(),
-            ctx.nodeid(),
-            fh.into(),
-            &mut data_writer,
-            size,
-            offset,
-            owner,
-            flags,
-        ) {
+        match result {
+            Ok(_) if !ctx.claim_reply() => {
+                warn!("fuse: drop late reply for timed out read {}", ctx.unique());
+                Ok(0)
+            }
             Ok(count) => {
                 // Don't use `reply_ok` because we need to set a custom size length for the
                 // header.
@@ -383,6 +614,7 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
                 ctx.w
                     .write_all(out.as_slice())
                     .map_err(Error::EncodeMessage)?;
+                check_out_header(&out, ctx.w.bytes_written() + data_writer.0.bytes_written());
                 ctx.w
                     .commit(Some(&data_writer.0))
                     .map_err(Error::EncodeMessage)?;
@@ -401,7 +633,7 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
             lock_owner,
             flags,
             ..
-        } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        } = ctx.read_obj()?;
 
         if size > MAX_BUFFER_SIZE {
             return ctx.reply_error_explicit(io::Error::from_raw_os_error(libc::ENOMEM));
@@ -429,14 +661,7 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
             flags,
             fuse_flags,
         ) {
-            Ok(count) => {
-                let out = WriteOut {
-                    size: count as u32,
-                    ..Default::default()
-                };
-
-                ctx.reply_ok(Some(out), None)
-            }
+            Ok(count) => ctx.reply_ok(Some(WriteOut::new(count as u32)), None),
             Err(e) => ctx.reply_error_explicit(e),
         }
     }
@@ -448,6 +673,15 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
         }
     }
 
+    pub(super) fn syncfs<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, D, S>) -> Result<usize> {
+        let SyncfsIn { .. } = ctx.read_obj()?;
+
+        match self.fs.syncfs(ctx.context()) {
+            Ok(()) => ctx.reply_ok(None::<u8>, None),
+            Err(e) => ctx.reply_error(e),
+        }
+    }
+
     pub(super) fn release<S: BitmapSlice>(
         &self,
         mut ctx: SrvContext<'_, F, D, S>,
@@ -457,7 +691,7 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
             flags,
             release_flags,
             lock_owner,
-        } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        } = ctx.read_obj()?;
 
         let flush = release_flags & RELEASE_FLUSH != 0;
         let flock_release = release_flags & RELEASE_FLOCK_UNLOCK != 0;
@@ -484,7 +718,7 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
     fn fsync<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, D, S>) -> Result<usize> {
         let FsyncIn {
             fh, fsync_flags, ..
-        } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        } = ctx.read_obj()?;
         let datasync = fsync_flags & 0x1 != 0;
 
         match self
@@ -500,9 +734,8 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
         &self,
         mut ctx: SrvContext<'_, F, D, S>,
     ) -> Result<usize> {
-        let SetxattrIn { size, flags } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
-        let buf =
-            ServerUtil::get_message_body(&mut ctx.r, &ctx.in_header, size_of::<SetxattrIn>())?;
+        let SetxattrIn { size, flags } = ctx.read_obj()?;
+        let buf = ctx.message_body(size_of::<SetxattrIn>())?;
 
         // The name and value and encoded one after another and separated by a '\0' character.
         let split_pos = buf
@@ -532,17 +765,18 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
         &self,
         mut ctx: SrvContext<'_, F, D, S>,
     ) -> Result<usize> {
-        let GetxattrIn { size, .. } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        let GetxattrIn { size, .. } = ctx.read_obj()?;
         if size > MAX_BUFFER_SIZE {
             return ctx.reply_error_explicit(io::Error::from_raw_os_error(libc::ENOMEM));
         }
 
-        let buf =
-            ServerUtil::get_message_body(&mut ctx.r, &ctx.in_header, size_of::<GetxattrIn>())?;
+        let buf = ctx.message_body(size_of::<GetxattrIn>())?;
         let name = bytes_to_cstr(buf.as_ref())?;
 
         match self.fs.getxattr(ctx.context(), ctx.nodeid(), name, size) {
-            Ok(GetxattrReply::Value(val)) => ctx.reply_ok(None::<u8>, Some(&val)),
+            Ok(GetxattrReply::Value(val)) => {
+                ctx.reply_ok(None::<u8>, Some(&ReplyBuf::from(val)[..]))
+            }
             Ok(GetxattrReply::Count(count)) => {
                 let out = GetxattrOut {
                     size: count,
@@ -559,14 +793,16 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
         &self,
         mut ctx: SrvContext<'_, F, D, S>,
     ) -> Result<usize> {
-        let GetxattrIn { size, .. } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        let GetxattrIn { size, .. } = ctx.read_obj()?;
 
         if size > MAX_BUFFER_SIZE {
             return ctx.reply_error_explicit(io::Error::from_raw_os_error(libc::ENOMEM));
         }
 
         match self.fs.listxattr(ctx.context(), ctx.nodeid(), size) {
-            Ok(ListxattrReply::Names(val)) => ctx.reply_ok(None::<u8>, Some(&val)),
+            Ok(ListxattrReply::Names(val)) => {
+                ctx.reply_ok(None::<u8>, Some(&ReplyBuf::from(val)[..]))
+            }
             Ok(ListxattrReply::Count(count)) => {
                 let out = GetxattrOut {
                     size: count,
@@ -583,7 +819,7 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
         &self,
         mut ctx: SrvContext<'_, F, D, S>,
     ) -> Result<usize> {
-        let buf = ServerUtil::get_message_body(&mut ctx.r, &ctx.in_header, 0)?;
+        let buf = ctx.message_body(0)?;
         let name = bytes_to_cstr(&buf)?;
 
         match self.fs.removexattr(ctx.context(), ctx.nodeid(), name) {
@@ -593,7 +829,7 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
     }
 
     pub(super) fn flush<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, D, S>) -> Result<usize> {
-        let FlushIn { fh, lock_owner, .. } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        let FlushIn { fh, lock_owner, .. } = ctx.read_obj()?;
 
         match self
             .fs
@@ -605,17 +841,19 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
     }
 
     pub(super) fn init<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, D, S>) -> Result<usize> {
-        let InitIn {
+        let len = std::cmp::min(
+            ctx.r.available_bytes(),
+            size_of::<InitIn>() + size_of::<InitInExt>(),
+        );
+        let mut body = vec![0u8; len];
+        ctx.r.read_exact(&mut body).map_err(Error::DecodeMessage)?;
+        let FuseInitIn {
             major,
             minor,
             max_readahead,
-            flags,
-        } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
-
-        if major < KERNEL_VERSION {
-            error!("Unsupported fuse protocol version: {}.{}", major, minor);
-            return ctx.reply_error_explicit(io::Error::from_raw_os_error(libc::EPROTO));
-        }
+            flags: capable,
+            flags2: capable_ext,
+        } = ctx.decoded(parse_init_in(&body))?;
 
         if major > KERNEL_VERSION {
             // Wait for the kernel to reply back with a 7.X version.
@@ -628,14 +866,29 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
             return ctx.reply_ok(Some(out), None);
         }
 
-        let capable = FsOptions::from_bits_truncate(flags);
-
-        match self.fs.init(capable) {
-            Ok(want) => {
+        match self
+            .fs
+            .init(capable)
+            .and_then(|want| Ok((want, self.fs.init_ext(capable_ext)?)))
+        {
+            Ok((want, want_ext)) => {
                 let enabled = capable & want;
+                let mut enabled_ext = capable_ext & want_ext;
+                let mut max_stack_depth = 0;
+                if enabled_ext.contains(FsOptionsExt::PASSTHROUGH) {
+                    max_stack_depth = self.fs.max_stack_depth();
+                    if max_stack_depth == 0 || max_stack_depth > FILESYSTEM_MAX_STACK_DEPTH {
+                        warn!(
+                            "fuse: invalid max_stack_depth {}, disable PASSTHROUGH",
+                            max_stack_depth
+                        );
+                        enabled_ext.remove(FsOptionsExt::PASSTHROUGH);
+                        max_stack_depth = 0;
+                    }
+                }
                 info!(
-                    "FUSE INIT major {} minor {}\n in_opts: {:?}\nout_opts: {:?}",
-                    major, minor, capable, enabled
+                    "FUSE INIT major {} minor {}\n in_opts: {:?} {:?}\nout_opts: {:?} {:?}",
+                    major, minor, capable, capable_ext, enabled, enabled_ext
                 );
 
                 let readahead = if cfg!(target_os = "macos") {
@@ -644,46 +897,38 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
                     max_readahead
                 };
 
-                let mut out = InitOut {
+                let max_background = self.max_background.load(Ordering::Relaxed);
+                let (max_write, max_pages) = negotiate_max_write(enabled);
+                let out = FuseInitOut {
+                    kernel_minor: minor,
                     major: KERNEL_VERSION,
                     minor: KERNEL_MINOR_VERSION,
                     max_readahead: readahead,
-                    flags: enabled.bits(),
-                    max_background: ::std::u16::MAX,
-                    congestion_threshold: (::std::u16::MAX / 4) * 3,
-                    max_write: MIN_READ_BUFFER - BUFFER_HEADER_SIZE,
-                    time_gran: 1, // nanoseconds
-                    ..Default::default()
+                    flags: enabled,
+                    flags2: enabled_ext,
+                    max_background,
+                    congestion_threshold: std::cmp::min(
+                        self.congestion_threshold.load(Ordering::Relaxed),
+                        max_background,
+                    ),
+                    max_write,
+                    time_gran: self.time_gran.load(Ordering::Relaxed),
+                    max_pages,
+                    map_alignment: 0,
+                    max_stack_depth,
                 };
-                if enabled.contains(FsOptions::MAX_PAGES) {
-                    out.max_pages = MAX_REQ_PAGES;
-                    out.max_write = MAX_REQ_PAGES as u32 * pagesize() as u32; // 1MB
-                }
+                // Size the pooled reply buffers for the largest reply the kernel may ask for.
+                ReplyBuf::set_capacity(max_payload_size(max_write, max_pages));
                 let vers = ServerVersion { major, minor };
                 self.vers.store(Arc::new(vers));
-                if minor < KERNEL_MINOR_VERSION_INIT_OUT_SIZE {
-                    ctx.reply_ok(
-                        Some(
-                            *<[u8; FUSE_COMPAT_INIT_OUT_SIZE]>::from_slice(
-                                out.as_slice().split_at(FUSE_COMPAT_INIT_OUT_SIZE).0,
-                            )
-                            .unwrap(),
-                        ),
-                        None,
-                    )
-                } else if minor < KERNEL_MINOR_VERSION_INIT_22_OUT_SIZE {
-                    ctx.reply_ok(
-                        Some(
-                            *<[u8; FUSE_COMPAT_22_INIT_OUT_SIZE]>::from_slice(
-                                out.as_slice().split_at(FUSE_COMPAT_22_INIT_OUT_SIZE).0,
-                            )
-                            .unwrap(),
-                        ),
-                        None,
-                    )
-                } else {
-                    ctx.reply_ok(Some(out), None)
-                }
+                self.opts.store(Arc::new(enabled));
+                self.opts_ext.store(Arc::new(enabled_ext));
+                self.max_stack_depth
+                    .store(max_stack_depth, Ordering::Relaxed);
+                self.supported_ops
+                    .store(self.fs.supported_ops().bits(), Ordering::Relaxed);
+                self.alive.store(true, Ordering::Release);
+                ctx.reply_ok(None::<u8>, Some(&encode_init_out(&out)))
             }
             Err(e) => ctx.reply_error(e),
         }
@@ -693,15 +938,23 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
         &self,
         mut ctx: SrvContext<'_, F, D, S>,
     ) -> Result<usize> {
-        let OpenIn { flags, .. } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        let OpenIn { flags, .. } = ctx.read_obj()?;
 
         match self.fs.opendir(ctx.context(), ctx.nodeid(), flags) {
             Ok((handle, opts)) => {
-                let out = OpenOut {
-                    fh: handle.map(Into::into).unwrap_or(0),
-                    open_flags: opts.bits(),
-                    ..Default::default()
-                };
+                if !ctx.claim_reply() {
+                    warn!(
+                        "fuse: drop late reply for timed out opendir {}",
+                        ctx.unique()
+                    );
+                    if let Some(handle) = handle {
+                        let _ = self
+                            .fs
+                            .releasedir(ctx.context(), ctx.nodeid(), flags, handle);
+                    }
+                    return Ok(0);
+                }
+                let out = OpenOut::new(handle.map(Into::into).unwrap_or(0), opts);
 
                 ctx.reply_ok(Some(out), None)
             }
@@ -716,7 +969,7 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
     ) -> Result<usize> {
         let ReadIn {
             fh, offset, size, ..
-        } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        } = ctx.read_obj()?;
 
         if size > MAX_BUFFER_SIZE {
             return ctx.reply_error_explicit(io::Error::from_raw_os_error(libc::ENOMEM));
@@ -733,29 +986,32 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
             Err(_e) => return Err(Error::InvalidHeaderLength),
         };
 
-        let res = if plus {
-            self.fs.readdirplus(
-                ctx.context(),
-                ctx.nodeid(),
-                fh.into(),
-                size,
-                offset,
-                &mut |d, e| add_dirent(&mut cursor, size, d, Some(e)),
-            )
-        } else {
-            self.fs.readdir(
-                ctx.context(),
-                ctx.nodeid(),
-                fh.into(),
-                size,
-                offset,
-                &mut |d| add_dirent(&mut cursor, size, d, None),
-            )
+        // Inodes of the entries replied by READDIRPLUS, which hold a lookup count.
+        let mut entries = Vec::new();
+        let res = match self.readdir_by_cursor(&ctx, fh, offset, size, plus, &mut cursor) {
+            Err(e) if e.raw_os_error() == Some(libc::ENOSYS) => {
+                self.readdir_by_offset(&ctx, fh, offset, size, plus, &mut cursor, &mut entries)
+            }
+            res => res,
         };
 
         if let Err(e) = res {
             ctx.reply_error_explicit(e)
+        } else if !ctx.claim_reply() {
+            warn!(
+                "fuse: drop late reply for timed out readdir {}",
+                ctx.unique()
+            );
+            for inode in entries {
+                self.fs.forget(ctx.context(), inode.into(), 1);
+            }
+            Ok(0)
         } else {
+            if self.readdirplus_auto() {
+                for inode in entries {
+                    self.rdplus.note_child(ctx.in_header.nodeid, inode);
+                }
+            }
             // Don't use `reply_ok` because we need to set a custom size length for the
             // header.
             let out = OutHeader {
@@ -767,11 +1023,119 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
             ctx.w
                 .write_all(out.as_slice())
                 .map_err(Error::EncodeMessage)?;
+            check_out_header(&out, ctx.w.bytes_written() + cursor.bytes_written());
             ctx.w.commit(Some(&cursor)).map_err(Error::EncodeMessage)?;
             Ok(out.len as usize)
         }
     }
 
+    // Serve READDIR and READDIRPLUS requests by `FileSystem::readdir_cursor()`, it fails with
+    // `ENOSYS` if the directory isn't listed by cursors.
+    fn readdir_by_cursor<S: BitmapSlice>(
+        &self,
+        ctx: &SrvContext<'_, F, D, S>,
+        fh: u64,
+        offset: u64,
+        size: u32,
+        plus: bool,
+        cursor: &mut Writer<'_, S>,
+    ) -> io::Result<()> {
+        let dir = (ctx.in_header.nodeid, fh);
+        let (mut page, mut skip) = match self.rdcursors.position(dir, offset)? {
+            CursorPosition::Page { cursor, skip } => (cursor, skip),
+            CursorPosition::End => return Ok(()),
+        };
+
+        loop {
+            let avail = size.saturating_sub(cursor.bytes_written() as u32);
+            let (entries, next) = self.fs.readdir_cursor(
+                ctx.context(),
+                ctx.nodeid(),
+                fh.into(),
+                avail,
+                page.as_deref(),
+            )?;
+            let base = self
+                .rdcursors
+                .add_page(dir, page, entries.len() as u64, next.clone());
+
+            for (idx, e) in entries.iter().enumerate().skip(skip as usize) {
+                let d = DirEntry {
+                    ino: e.ino,
+                    offset: base + idx as u64 + 1,
+                    type_: e.type_,
+                    name: &e.name,
+                };
+                // Attributes are not available, so return names only for READDIRPLUS.
+                let entry = if plus { Some(Entry::default()) } else { None };
+                if add_dirent(cursor, size, d, entry)? == 0 {
+                    return Ok(());
+                }
+            }
+
+            match next {
+                Some(next) => {
+                    page = Some(next);
+                    skip = 0;
+                }
+                None => return Ok(()),
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn readdir_by_offset<S: BitmapSlice>(
+        &self,
+        ctx: &SrvContext<'_, F, D, S>,
+        fh: u64,
+        offset: u64,
+        size: u32,
+        plus: bool,
+        cursor: &mut Writer<'_, S>,
+        entries: &mut Vec<u64>,
+    ) -> io::Result<()> {
+        // With READDIRPLUS_AUTO, skip fetching attributes if the client doesn't seem to care
+        // about them. Entries with zero nodeid are accepted by the kernel as names only.
+        let lite =
+            plus && self.readdirplus_auto() && !self.rdplus.want_plus(ctx.in_header.nodeid, offset);
+
+        if lite {
+            self.fs.readdir(
+                ctx.context(),
+                ctx.nodeid(),
+                fh.into(),
+                size,
+                offset,
+                &mut |d| add_dirent(cursor, size, d, Some(Entry::default())),
+            )
+        } else if plus {
+            self.fs.readdirplus(
+                ctx.context(),
+                ctx.nodeid(),
+                fh.into(),
+                size,
+                offset,
+                &mut |d, e| {
+                    let inode = e.inode;
+                    let res = add_dirent(cursor, size, d, Some(e));
+                    if inode != 0 && matches!(res, Ok(len) if len > 0) {
+                        entries.push(inode);
+                    }
+                    res
+                },
+            )
+        } else {
+            self.fs.readdir(
+                ctx.context(),
+                ctx.nodeid(),
+                fh.into(),
+                size,
+                offset,
+                &mut |d| add_dirent(cursor, size, d, None),
+            )
+        }
+    }
+
     pub(super) fn readdir<S: BitmapSlice>(&self, ctx: SrvContext<'_, F, D, S>) -> Result<usize> {
         self.do_readdir(ctx, false)
     }
@@ -787,7 +1151,8 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
         &self,
         mut ctx: SrvContext<'_, F, D, S>,
     ) -> Result<usize> {
-        let ReleaseIn { fh, flags, .. } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        let ReleaseIn { fh, flags, .. } = ctx.read_obj()?;
+        self.rdcursors.forget((ctx.in_header.nodeid, fh));
 
         match self
             .fs
@@ -801,7 +1166,7 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
     fn fsyncdir<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, D, S>) -> Result<usize> {
         let FsyncIn {
             fh, fsync_flags, ..
-        } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        } = ctx.read_obj()?;
         let datasync = fsync_flags & 0x1 != 0;
 
         match self
@@ -820,7 +1185,7 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
             lk,
             lk_flags,
             ..
-        } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        } = ctx.read_obj()?;
         match self.fs.getlk(
             ctx.context(),
             ctx.nodeid(),
@@ -841,7 +1206,7 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
             lk,
             lk_flags,
             ..
-        } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        } = ctx.read_obj()?;
         match self.fs.setlk(
             ctx.context(),
             ctx.nodeid(),
@@ -862,8 +1227,8 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
             lk,
             lk_flags,
             ..
-        } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
-        match self.fs.setlk(
+        } = ctx.read_obj()?;
+        match self.fs.setlkw(
             ctx.context(),
             ctx.nodeid(),
             fh.into(),
@@ -877,7 +1242,7 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
     }
 
     pub(super) fn access<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, D, S>) -> Result<usize> {
-        let AccessIn { mask, .. } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        let AccessIn { mask, .. } = ctx.read_obj()?;
 
         match self.fs.access(ctx.context(), ctx.nodeid(), mask) {
             Ok(()) => ctx.reply_ok(None::<u8>, None),
@@ -886,28 +1251,145 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
     }
 
     fn create<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, D, S>) -> Result<usize> {
-        let args: CreateIn = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
-        let buf = ServerUtil::get_message_body(&mut ctx.r, &ctx.in_header, size_of::<CreateIn>())?;
+        let args: CreateIn = ctx.read_obj()?;
+        let buf = ctx.message_body(size_of::<CreateIn>())?;
         let name = bytes_to_cstr(&buf)?;
+        let secctx = &buf[name.to_bytes_with_nul().len()..];
 
         match self.fs.create(ctx.context(), ctx.nodeid(), name, args) {
             Ok((entry, handle, opts)) => {
-                let entry_out = EntryOut {
-                    nodeid: entry.inode,
-                    generation: entry.generation,
-                    entry_valid: entry.entry_timeout.as_secs(),
-                    attr_valid: entry.attr_timeout.as_secs(),
-                    entry_valid_nsec: entry.entry_timeout.subsec_nanos(),
-                    attr_valid_nsec: entry.attr_timeout.subsec_nanos(),
-                    attr: entry.attr.into(),
-                };
+                if let Err(e) = self.init_secctx(&ctx, name, &entry, secctx) {
+                    if let Some(handle) = handle {
+                        let _ = self.fs.release(
+                            ctx.context(),
+                            entry.inode.into(),
+                            args.flags,
+                            handle,
+                            false,
+                            false,
+                            None,
+                        );
+                    }
+                    return ctx.reply_error(e);
+                }
+
+                if !self.claim_entry_reply(&mut ctx, &entry) {
+                    if let Some(handle) = handle {
+                        let _ = self.fs.release(
+                            ctx.context(),
+                            entry.inode.into(),
+                            args.flags,
+                            handle,
+                            false,
+                            false,
+                            None,
+                        );
+                    }
+                    return Ok(0);
+                }
+                let entry_out = EntryOut::from(entry);
+                let open_out = OpenOut::new(handle.map(Into::into).unwrap_or(0), opts);
+
+                // Kind of a hack to write both structs.
+                ctx.reply_ok(Some(entry_out), Some(open_out.as_slice()))
+            }
+            Err(e) => ctx.reply_error(e),
+        }
+    }
+
+    // Claim the reply of a request returning `entry`. If the request has already been replied
+    // due to timeout, the kernel never knows the entry, so forget it on behalf of the kernel.
+    fn claim_entry_reply<S: BitmapSlice>(
+        &self,
+        ctx: &mut SrvContext<'_, F, D, S>,
+        entry: &Entry,
+    ) -> bool {
+        if ctx.claim_reply() {
+            return true;
+        }
+
+        warn!(
+            "fuse: drop late reply for timed out {:?} request {}",
+            Opcode::from(ctx.in_header.opcode),
+            ctx.unique()
+        );
+        if entry.inode != 0 {
+            self.fs.forget(ctx.context(), entry.inode.into(), 1);
+        }
+        false
+    }
+
+    // Apply the security contexts of the request to the node just created. The contexts are sent
+    // as an extension of the request, or following the name(s) of the request by kernels without
+    // support of request extensions. The lookup count taken by the creation is dropped on failure.
+    fn init_secctx<S: BitmapSlice>(
+        &self,
+        ctx: &SrvContext<'_, F, D, S>,
+        name: &CStr,
+        entry: &Entry,
+        buf: &[u8],
+    ) -> io::Result<()> {
+        if !self.opts_ext.load().contains(FsOptionsExt::SECURITY_CTX) {
+            return Ok(());
+        }
+        let contexts = match ctx.context().extensions.secctx() {
+            Some(contexts) => Ok(contexts.to_vec()),
+            None if buf.is_empty() => return Ok(()),
+            None => ServerUtil::extract_secctx(buf)
+                .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL)),
+        };
+
+        let res = contexts.and_then(|contexts| {
+            contexts.iter().try_for_each(|secctx| {
+                self.fs.init_security_context(
+                    ctx.context(),
+                    ctx.nodeid(),
+                    name,
+                    entry.inode.into(),
+                    secctx,
+                )
+            })
+        });
+        if res.is_err() {
+            self.fs.forget(ctx.context(), entry.inode.into(), 1);
+        }
+
+        res
+    }
+
+    fn tmpfile<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, D, S>) -> Result<usize> {
+        let CreateIn {
+            flags, mode, umask, ..
+        } = ctx.read_obj()?;
+        // The request carries a dummy name "/" for the unnamed file, just skip it.
+        ctx.message_body(size_of::<CreateIn>())?;
+
+        match self
+            .fs
+            .tmpfile(ctx.context(), ctx.nodeid(), mode, umask, flags)
+        {
+            Ok((entry, handle, opts)) => {
+                if !self.claim_entry_reply(&mut ctx, &entry) {
+                    if let Some(handle) = handle {
+                        let _ = self.fs.release(
+                            ctx.context(),
+                            entry.inode.into(),
+                            flags,
+                            handle,
+                            false,
+                            false,
+                            None,
+                        );
+                    }
+                    return Ok(0);
+                }
+                let entry_out = EntryOut::from(entry);
                 let open_out = OpenOut {
                     fh: handle.map(Into::into).unwrap_or(0),
                     open_flags: opts.bits(),
                     ..Default::default()
                 };
 
-                // Kind of a hack to write both structs.
                 ctx.reply_ok(Some(entry_out), Some(open_out.as_slice()))
             }
             Err(e) => ctx.reply_error(e),
@@ -919,7 +1401,7 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
     pub(super) fn bmap<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, D, S>) -> Result<usize> {
         let BmapIn {
             block, blocksize, ..
-        } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        } = ctx.read_obj()?;
 
         match self.fs.bmap(ctx.context(), ctx.nodeid(), block, blocksize) {
             Ok(block) => ctx.reply_ok(Some(BmapOut { block }), None),
@@ -928,7 +1410,7 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
     }
 
     pub(super) fn destroy<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, D, S>) {
-        self.fs.destroy();
+        self.shutdown();
         if let Err(e) = ctx.reply_ok(None::<u8>, None) {
             warn!("fuse channel reply destroy failed {:?}", e);
         }
@@ -942,7 +1424,7 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
             arg: _,
             in_size,
             out_size,
-        } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        } = ctx.read_obj()?;
         // TODO: check fs capability of FUSE_CAP_IOCTL_DIR and return ENOTTY if unsupported.
         let mut buf = IoctlData {
             ..Default::default()
@@ -985,7 +1467,7 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
             kh,
             flags,
             events,
-        } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        } = ctx.read_obj()?;
 
         match self.fs.poll(
             ctx.context(),
@@ -1010,6 +1492,21 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
         &self,
         mut ctx: SrvContext<'_, F, D, S>,
     ) -> Result<usize> {
+        // Replies to FUSE_NOTIFY_RETRIEVE notifications are consumed by the server itself.
+        if self.retrieves.is_pending(ctx.unique()) {
+            let result = ctx.r.read_obj::<NotifyRetrieveIn>().and_then(|arg| {
+                let size = std::cmp::min(arg.size as usize, ctx.r.available_bytes());
+                let mut data = vec![0u8; size];
+                ctx.r.read_exact(&mut data)?;
+                Ok(data)
+            });
+            if let Err(e) = &result {
+                error!("fuse: failed to decode retrieve reply: {}", e);
+            }
+            self.retrieves.complete(ctx.unique(), result);
+            return Ok(0);
+        }
+
         if let Err(e) = self.fs.notify_reply() {
             ctx.reply_error(e)
         } else {
@@ -1021,7 +1518,7 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
         &self,
         mut ctx: SrvContext<'_, F, D, S>,
     ) -> Result<usize> {
-        let BatchForgetIn { count, .. } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        let BatchForgetIn { count, .. } = ctx.read_obj()?;
 
         if let Some(size) = (count as usize).checked_mul(size_of::<ForgetOne>()) {
             if size > MAX_BUFFER_SIZE as usize {
@@ -1031,14 +1528,14 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
             return ctx.reply_error_explicit(io::Error::from_raw_os_error(libc::EOVERFLOW));
         }
 
+        let rdplus_auto = self.readdirplus_auto();
         let mut requests = Vec::with_capacity(count as usize);
         for _ in 0..count {
-            requests.push(
-                ctx.r
-                    .read_obj::<ForgetOne>()
-                    .map(|f| (f.nodeid.into(), f.nlookup))
-                    .map_err(Error::DecodeMessage)?,
-            );
+            let f = ctx.read_obj::<ForgetOne>()?;
+            if rdplus_auto {
+                self.rdplus.forget(f.nodeid);
+            }
+            requests.push((f.nodeid.into(), f.nlookup));
         }
 
         self.fs.batch_forget(ctx.context(), requests);
@@ -1054,7 +1551,7 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
             length,
             mode,
             ..
-        } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        } = ctx.read_obj()?;
 
         match self
             .fs
@@ -1068,7 +1565,7 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
     pub(super) fn lseek<S: BitmapSlice>(&self, mut ctx: SrvContext<'_, F, D, S>) -> Result<usize> {
         let LseekIn {
             fh, offset, whence, ..
-        } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+        } = ctx.read_obj()?;
 
         match self
             .fs
@@ -1082,6 +1579,44 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
             Err(e) => ctx.reply_error(e),
         }
     }
+
+    pub(super) fn copyfilerange<S: BitmapSlice>(
+        &self,
+        mut ctx: SrvContext<'_, F, D, S>,
+    ) -> Result<usize> {
+        let CopyFileRangeIn {
+            fh_in,
+            offset_in,
+            nodeid_out,
+            fh_out,
+            offset_out,
+            len,
+            flags,
+        } = ctx.read_obj()?;
+
+        // No flag is defined for copy_file_range() yet.
+        if flags != 0 {
+            return ctx.reply_error(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        // The count of copied bytes is replied in u32, so limit the range to a page aligned
+        // length fitting in it.
+        let len = len.min(u32::MAX as u64 & !(pagesize() as u64 - 1));
+
+        match self.fs.clone_range(
+            ctx.context(),
+            ctx.nodeid(),
+            fh_in.into(),
+            offset_in,
+            nodeid_out.into(),
+            fh_out.into(),
+            offset_out,
+            len,
+        ) {
+            Ok(count) => ctx.reply_ok(Some(WriteOut::new(count as u32)), None),
+            Err(e) => ctx.reply_error(e),
+        }
+    }
 }
 
 #[cfg(feature = "virtiofs")]
@@ -1098,7 +1633,7 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
                 len,
                 flags,
                 moffset,
-            } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+            } = ctx.read_obj()?;
 
             match self.fs.setupmapping(
                 ctx.context(),
@@ -1124,7 +1659,7 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
         vu_req: Option<&mut dyn FsCacheReqHandler>,
     ) -> Result<usize> {
         if let Some(req) = vu_req {
-            let RemovemappingIn { count } = ctx.r.read_obj().map_err(Error::DecodeMessage)?;
+            let RemovemappingIn { count } = ctx.read_obj()?;
 
             if let Some(size) = (count as usize).checked_mul(size_of::<RemovemappingOne>()) {
                 if size > MAX_BUFFER_SIZE as usize {
@@ -1136,11 +1671,7 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
 
             let mut requests = Vec::with_capacity(count as usize);
             for _ in 0..count {
-                requests.push(
-                    ctx.r
-                        .read_obj::<RemovemappingOne>()
-                        .map_err(Error::DecodeMessage)?,
-                );
+                requests.push(ctx.read_obj::<RemovemappingOne>()?);
             }
 
             match self
@@ -1157,7 +1688,19 @@ impl<F: FileSystem + Sync, D: AsyncDrive> Server<F, D> {
 }
 
 impl<'a, F: FileSystem, D: AsyncDrive, S: BitmapSlice> SrvContext<'a, F, D, S> {
-    fn reply_ok<T: ByteValued>(&mut self, out: Option<T>, data: Option<&[u8]>) -> Result<usize> {
+    pub(super) fn reply_ok<T: ByteValued>(
+        &mut self,
+        out: Option<T>,
+        data: Option<&[u8]>,
+    ) -> Result<usize> {
+        if !self.claim_reply() {
+            warn!(
+                "fuse: drop late reply for timed out request {}",
+                self.unique()
+            );
+            return Ok(0);
+        }
+
         let data2 = out.as_ref().map(|v| v.as_slice()).unwrap_or(&[]);
         let data3 = data.unwrap_or(&[]);
         let len = size_of::<OutHeader>() + data2.len() + data3.len();
@@ -1196,6 +1739,16 @@ impl<'a, F: FileSystem, D: AsyncDrive, S: BitmapSlice> SrvContext<'a, F, D, S> {
     }
 
     fn do_reply_error(&mut self, err: io::Error, explicit: bool) -> Result<usize> {
+        let err = Retryable::into_inner(err);
+        if !self.claim_reply() {
+            warn!(
+                "fuse: drop late error reply for timed out request {}: {}",
+                self.unique(),
+                err
+            );
+            return Ok(0);
+        }
+
         let header = OutHeader {
             len: size_of::<OutHeader>() as u32,
             error: -err
@@ -1225,25 +1778,17 @@ impl<'a, F: FileSystem, D: AsyncDrive, S: BitmapSlice> SrvContext<'a, F, D, S> {
 
     // reply operation error back to fuse client, don't print error message, as they are not server's
     // internal error, and client could deal with them.
-    fn reply_error(&mut self, err: io::Error) -> Result<usize> {
+    pub(super) fn reply_error(&mut self, err: io::Error) -> Result<usize> {
         self.do_reply_error(err, false)
     }
 
-    fn reply_error_explicit(&mut self, err: io::Error) -> Result<usize> {
+    pub(super) fn reply_error_explicit(&mut self, err: io::Error) -> Result<usize> {
         self.do_reply_error(err, true)
     }
 
     fn handle_attr_result(&mut self, result: io::Result<(stat64, Duration)>) -> Result<usize> {
         match result {
-            Ok((st, timeout)) => {
-                let out = AttrOut {
-                    attr_valid: timeout.as_secs(),
-                    attr_valid_nsec: timeout.subsec_nanos(),
-                    dummy: 0,
-                    attr: st.into(),
-                };
-                self.reply_ok(Some(out), None)
-            }
+            Ok((st, timeout)) => self.reply_ok(Some(AttrOut::from_stat(&st, timeout)), None),
             Err(e) => self.reply_error(e),
         }
     }
@@ -0,0 +1,958 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A read-only union of several file systems.
+//!
+//! The UnionFs stacks an ordered list of file systems, and a name is resolved by the first file
+//! system containing it. It's much simpler than overlayfs:
+//! - All file systems are read-only from the UnionFs' view, there's no copy-up and mutating
+//!   requests fail with `EROFS`.
+//! - There's no whiteout, an entry can't hide entries of the same name in later file systems.
+//! - Directories of the same name from different file systems are merged, and the first entry
+//!   wins when listing the merged directory. A non-directory entry hides all later entries.
+
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::io::{Error, Result};
+use std::mem;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::abi::fuse_abi::{stat64, statvfs64, CreateIn, FsOptions, OpenOptions, SetattrValid};
+use crate::api::filesystem::*;
+use crate::api::layer_inodes::LayerInodes;
+
+type Inode = u64;
+type Handle = u64;
+
+/// A file system stacked by [UnionFs](struct.UnionFs.html).
+pub type UnionLayer = Arc<dyn FileSystem<Inode = u64, Handle = u64> + Send + Sync>;
+
+// Size of the buffer used to list directories of layers.
+const UNIONFS_READDIR_SIZE: u32 = 64 * 1024;
+
+struct UnionDirEntry {
+    ino: u64,
+    type_: u32,
+    name: Vec<u8>,
+}
+
+enum UnionHandle {
+    File {
+        layer: usize,
+        inode: Inode,
+        handle: Option<Handle>,
+    },
+    // Directories are listed at `opendir()` time, so the merged result is stable for the handle.
+    Dir(Vec<UnionDirEntry>),
+}
+
+/// A read-only union of several file systems where the first one resolving a name wins.
+///
+/// All file systems in the union should have been initialized with their root inode being
+/// `ROOT_ID`.
+pub struct UnionFs {
+    layers: Vec<UnionLayer>,
+    inodes: Mutex<LayerInodes<()>>,
+    handles: Mutex<HashMap<Handle, Arc<UnionHandle>>>,
+    next_handle: AtomicU64,
+}
+
+impl UnionFs {
+    /// Create a UnionFs from file systems in the order of precedence.
+    pub fn new(layers: Vec<UnionLayer>) -> Self {
+        let root = (0..layers.len()).map(|idx| (idx, ROOT_ID)).collect();
+        let inodes = LayerInodes::new(root, ());
+
+        UnionFs {
+            layers,
+            inodes: Mutex::new(inodes),
+            handles: Mutex::new(HashMap::new()),
+            next_handle: AtomicU64::new(1),
+        }
+    }
+
+    fn backing(&self, inode: Inode) -> Result<Vec<(usize, Inode)>> {
+        self.inodes
+            .lock()
+            .unwrap()
+            .get(inode)
+            .map(|i| i.layers.clone())
+            .ok_or_else(|| Error::from_raw_os_error(libc::ENOENT))
+    }
+
+    fn primary(&self, inode: Inode) -> Result<(&UnionLayer, Inode)> {
+        let (idx, ino) = self.primary_idx(inode)?;
+        Ok((&self.layers[idx], ino))
+    }
+
+    fn primary_idx(&self, inode: Inode) -> Result<(usize, Inode)> {
+        self.inodes
+            .lock()
+            .unwrap()
+            .get(inode)
+            .and_then(|i| i.layers.first().copied())
+            .ok_or_else(|| Error::from_raw_os_error(libc::ENOENT))
+    }
+
+    fn get_handle(&self, handle: Handle) -> Result<Arc<UnionHandle>> {
+        self.handles
+            .lock()
+            .unwrap()
+            .get(&handle)
+            .cloned()
+            .ok_or_else(|| Error::from_raw_os_error(libc::EBADF))
+    }
+
+    // Get the backing handle to pass to the layer, for a handle of a union inode.
+    fn layer_handle(&self, handle: Handle) -> Result<(usize, Inode, Handle)> {
+        match self.get_handle(handle)?.as_ref() {
+            UnionHandle::File {
+                layer,
+                inode,
+                handle,
+            } => Ok((*layer, *inode, handle.unwrap_or(0))),
+            UnionHandle::Dir(_) => Err(Error::from_raw_os_error(libc::EISDIR)),
+        }
+    }
+
+    fn insert_handle(&self, data: UnionHandle) -> Handle {
+        let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
+        self.handles.lock().unwrap().insert(handle, Arc::new(data));
+        handle
+    }
+
+    fn forget_layers(&self, ctx: &Context, layers: &[(usize, Inode)]) {
+        for (idx, ino) in layers {
+            self.layers[*idx].forget(ctx, *ino, 1);
+        }
+    }
+
+    fn list_layer(
+        &self,
+        ctx: &Context,
+        layer: &UnionLayer,
+        inode: Inode,
+        entries: &mut Vec<UnionDirEntry>,
+    ) -> Result<()> {
+        let (handle, _) = layer.opendir(ctx, inode, libc::O_RDONLY as u32)?;
+        let handle = handle.unwrap_or(0);
+        let mut offset = 0;
+
+        let res = loop {
+            let mut added = 0;
+            let res = layer.readdir(
+                ctx,
+                inode,
+                handle,
+                UNIONFS_READDIR_SIZE,
+                offset,
+                &mut |dirent| {
+                    let len = mem::size_of::<DirEntry>() + dirent.name.len();
+                    if entries.iter().all(|e| e.name != dirent.name) {
+                        entries.push(UnionDirEntry {
+                            ino: dirent.ino,
+                            type_: dirent.type_,
+                            name: dirent.name.to_vec(),
+                        });
+                    }
+                    offset = dirent.offset;
+                    added += 1;
+                    Ok(len)
+                },
+            );
+            if res.is_err() || added == 0 {
+                break res;
+            }
+        };
+        let _ = layer.releasedir(ctx, inode, libc::O_RDONLY as u32, handle);
+
+        res
+    }
+}
+
+fn erofs() -> Error {
+    Error::from_raw_os_error(libc::EROFS)
+}
+
+impl FileSystem for UnionFs {
+    type Inode = Inode;
+    type Handle = Handle;
+
+    fn init(&self, capable: FsOptions) -> Result<FsOptions> {
+        // Handles are needed to find the backing file, and the union is read-only.
+        let unsupported = FsOptions::DO_READDIRPLUS
+            | FsOptions::READDIRPLUS_AUTO
+            | FsOptions::WRITEBACK_CACHE
+            | FsOptions::ATOMIC_O_TRUNC
+            | FsOptions::ZERO_MESSAGE_OPEN
+            | FsOptions::ZERO_MESSAGE_OPENDIR;
+        let capable = capable - unsupported;
+        let mut opts = capable;
+
+        for layer in self.layers.iter() {
+            opts &= layer.init(capable)?;
+        }
+
+        Ok(opts)
+    }
+
+    fn init_ext(&self, capable: FsOptionsExt) -> Result<FsOptionsExt> {
+        let mut opts = capable;
+
+        for layer in self.layers.iter() {
+            opts &= layer.init_ext(capable)?;
+        }
+
+        Ok(opts)
+    }
+
+    fn destroy(&self) {
+        for layer in self.layers.iter() {
+            layer.destroy();
+        }
+    }
+
+    fn wants_raw_header(&self) -> bool {
+        self.layers.iter().any(|layer| layer.wants_raw_header())
+    }
+
+    fn max_stack_depth(&self) -> u32 {
+        self.layers
+            .iter()
+            .map(|layer| layer.max_stack_depth())
+            .max()
+            .unwrap_or(1)
+    }
+
+    fn supported_ops(&self) -> SupportedOps {
+        // Only extended attributes are read from the layers, the union doesn't modify data.
+        self.layers.iter().fold(SupportedOps::XATTR, |ops, layer| {
+            ops & layer.supported_ops()
+        })
+    }
+
+    fn lookup(&self, ctx: &Context, parent: Inode, name: &CStr) -> Result<Entry> {
+        let mut found: Vec<(usize, Entry)> = Vec::new();
+
+        for (idx, ino) in self.backing(parent)? {
+            let entry = match self.layers[idx].lookup(ctx, ino, name) {
+                // Negative entry.
+                Ok(e) if e.inode == 0 => continue,
+                Ok(e) => e,
+                Err(e) if e.raw_os_error() == Some(libc::ENOENT) => continue,
+                Err(e) => {
+                    // Don't expose entries of later file systems if an earlier one fails.
+                    if found.is_empty() {
+                        return Err(e);
+                    }
+                    continue;
+                }
+            };
+
+            let is_dir = entry.attr.st_mode & libc::S_IFMT == libc::S_IFDIR;
+            if found.is_empty() {
+                found.push((idx, entry));
+                if !is_dir {
+                    break;
+                }
+            } else if is_dir {
+                found.push((idx, entry));
+            } else {
+                // Only directories are merged.
+                self.layers[idx].forget(ctx, entry.inode, 1);
+            }
+        }
+
+        if found.is_empty() {
+            return Err(Error::from_raw_os_error(libc::ENOENT));
+        }
+
+        let layers: Vec<(usize, Inode)> = found.iter().map(|(idx, e)| (*idx, e.inode)).collect();
+        let (_, mut entry) = found.swap_remove(0);
+        let mut inodes = self.inodes.lock().unwrap();
+
+        let (ino, generation) = match inodes.get_ref(layers[0]) {
+            Some(found) => {
+                // The union inode already holds a reference to each of its backing inodes.
+                drop(inodes);
+                self.forget_layers(ctx, &layers);
+                found
+            }
+            None => inodes.alloc(layers, ()),
+        };
+        entry.inode = ino;
+        entry.generation = generation;
+
+        Ok(entry)
+    }
+
+    fn forget(&self, ctx: &Context, inode: Inode, count: u64) {
+        // Release the lock before forgetting the backing inodes.
+        let removed = self.inodes.lock().unwrap().unref(inode, count);
+        if let Some(data) = removed {
+            self.forget_layers(ctx, &data.layers);
+        }
+    }
+
+    fn batch_forget(&self, ctx: &Context, requests: Vec<(Inode, u64)>) {
+        for (inode, count) in requests {
+            self.forget(ctx, inode, count);
+        }
+    }
+
+    fn getattr(
+        &self,
+        ctx: &Context,
+        inode: Inode,
+        handle: Option<Handle>,
+    ) -> Result<(stat64, Duration)> {
+        let (layer, ino) = self.primary(inode)?;
+        let handle = match handle {
+            Some(h) => match self.get_handle(h)?.as_ref() {
+                UnionHandle::File { handle, .. } => *handle,
+                UnionHandle::Dir(_) => None,
+            },
+            None => None,
+        };
+
+        layer.getattr(ctx, ino, handle)
+    }
+
+    fn setattr(
+        &self,
+        _ctx: &Context,
+        _inode: Inode,
+        _attr: stat64,
+        _handle: Option<Handle>,
+        _valid: SetattrValid,
+    ) -> Result<(stat64, Duration)> {
+        Err(erofs())
+    }
+
+    fn readlink(&self, ctx: &Context, inode: Inode) -> Result<Vec<u8>> {
+        let (layer, ino) = self.primary(inode)?;
+        layer.readlink(ctx, ino)
+    }
+
+    fn symlink(
+        &self,
+        _ctx: &Context,
+        _linkname: &CStr,
+        _parent: Inode,
+        _name: &CStr,
+    ) -> Result<Entry> {
+        Err(erofs())
+    }
+
+    fn mknod(
+        &self,
+        _ctx: &Context,
+        _parent: Inode,
+        _name: &CStr,
+        _mode: u32,
+        _rdev: u32,
+        _umask: u32,
+    ) -> Result<Entry> {
+        Err(erofs())
+    }
+
+    fn mkdir(
+        &self,
+        _ctx: &Context,
+        _parent: Inode,
+        _name: &CStr,
+        _mode: u32,
+        _umask: u32,
+    ) -> Result<Entry> {
+        Err(erofs())
+    }
+
+    fn unlink(&self, _ctx: &Context, _parent: Inode, _name: &CStr) -> Result<()> {
+        Err(erofs())
+    }
+
+    fn rmdir(&self, _ctx: &Context, _parent: Inode, _name: &CStr) -> Result<()> {
+        Err(erofs())
+    }
+
+    fn rename(
+        &self,
+        _ctx: &Context,
+        _olddir: Inode,
+        _oldname: &CStr,
+        _newdir: Inode,
+        _newname: &CStr,
+        _flags: u32,
+    ) -> Result<()> {
+        Err(erofs())
+    }
+
+    fn link(
+        &self,
+        _ctx: &Context,
+        _inode: Inode,
+        _newparent: Inode,
+        _newname: &CStr,
+    ) -> Result<Entry> {
+        Err(erofs())
+    }
+
+    fn open(
+        &self,
+        ctx: &Context,
+        inode: Inode,
+        flags: u32,
+        fuse_flags: u32,
+    ) -> Result<(Option<Handle>, OpenOptions)> {
+        let flags_i = flags as i32;
+        if flags_i & libc::O_ACCMODE != libc::O_RDONLY || flags_i & libc::O_TRUNC != 0 {
+            return Err(erofs());
+        }
+
+        let (idx, ino) = self.primary_idx(inode)?;
+        let (handle, opts) = self.layers[idx].open(ctx, ino, flags, fuse_flags)?;
+        let handle = self.insert_handle(UnionHandle::File {
+            layer: idx,
+            inode: ino,
+            handle,
+        });
+
+        Ok((Some(handle), opts))
+    }
+
+    fn create(
+        &self,
+        _ctx: &Context,
+        _parent: Inode,
+        _name: &CStr,
+        _args: CreateIn,
+    ) -> Result<(Entry, Option<Handle>, OpenOptions)> {
+        Err(erofs())
+    }
+
+    fn tmpfile(
+        &self,
+        _ctx: &Context,
+        _parent: Inode,
+        _mode: u32,
+        _umask: u32,
+        _flags: u32,
+    ) -> Result<(Entry, Option<Handle>, OpenOptions)> {
+        Err(erofs())
+    }
+
+    fn read(
+        &self,
+        ctx: &Context,
+        _inode: Inode,
+        handle: Handle,
+        w: &mut dyn ZeroCopyWriter,
+        size: u32,
+        offset: u64,
+        lock_owner: Option<u64>,
+        flags: u32,
+    ) -> Result<usize> {
+        let (idx, ino, handle) = self.layer_handle(handle)?;
+        self.layers[idx].read(ctx, ino, handle, w, size, offset, lock_owner, flags)
+    }
+
+    fn write(
+        &self,
+        _ctx: &Context,
+        _inode: Inode,
+        _handle: Handle,
+        _r: &mut dyn ZeroCopyReader,
+        _size: u32,
+        _offset: u64,
+        _lock_owner: Option<u64>,
+        _delayed_write: bool,
+        _flags: u32,
+        _fuse_flags: u32,
+    ) -> Result<usize> {
+        Err(erofs())
+    }
+
+    fn fallocate(
+        &self,
+        _ctx: &Context,
+        _inode: Inode,
+        _handle: Handle,
+        _mode: u32,
+        _offset: u64,
+        _length: u64,
+    ) -> Result<()> {
+        Err(erofs())
+    }
+
+    fn release(
+        &self,
+        ctx: &Context,
+        _inode: Inode,
+        flags: u32,
+        handle: Handle,
+        flush: bool,
+        flock_release: bool,
+        lock_owner: Option<u64>,
+    ) -> Result<()> {
+        let data = self
+            .handles
+            .lock()
+            .unwrap()
+            .remove(&handle)
+            .ok_or_else(|| Error::from_raw_os_error(libc::EBADF))?;
+
+        match data.as_ref() {
+            UnionHandle::File {
+                layer,
+                inode,
+                handle: Some(h),
+            } => self.layers[*layer].release(
+                ctx,
+                *inode,
+                flags,
+                *h,
+                flush,
+                flock_release,
+                lock_owner,
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    fn statfs(&self, ctx: &Context, inode: Inode) -> Result<statvfs64> {
+        let (layer, ino) = self.primary(inode)?;
+        layer.statfs(ctx, ino)
+    }
+
+    fn setxattr(
+        &self,
+        _ctx: &Context,
+        _inode: Inode,
+        _name: &CStr,
+        _value: &[u8],
+        _flags: u32,
+    ) -> Result<()> {
+        Err(erofs())
+    }
+
+    fn getxattr(
+        &self,
+        ctx: &Context,
+        inode: Inode,
+        name: &CStr,
+        size: u32,
+    ) -> Result<GetxattrReply> {
+        let (layer, ino) = self.primary(inode)?;
+        layer.getxattr(ctx, ino, name, size)
+    }
+
+    fn listxattr(&self, ctx: &Context, inode: Inode, size: u32) -> Result<ListxattrReply> {
+        let (layer, ino) = self.primary(inode)?;
+        layer.listxattr(ctx, ino, size)
+    }
+
+    fn removexattr(&self, _ctx: &Context, _inode: Inode, _name: &CStr) -> Result<()> {
+        Err(erofs())
+    }
+
+    fn opendir(
+        &self,
+        ctx: &Context,
+        inode: Inode,
+        _flags: u32,
+    ) -> Result<(Option<Handle>, OpenOptions)> {
+        let mut entries = Vec::new();
+
+        for (idx, ino) in self.backing(inode)? {
+            self.list_layer(ctx, &self.layers[idx], ino, &mut entries)?;
+        }
+        let handle = self.insert_handle(UnionHandle::Dir(entries));
+
+        Ok((Some(handle), OpenOptions::empty()))
+    }
+
+    fn readdir(
+        &self,
+        _ctx: &Context,
+        _inode: Inode,
+        handle: Handle,
+        size: u32,
+        offset: u64,
+        add_entry: &mut dyn FnMut(DirEntry) -> Result<usize>,
+    ) -> Result<()> {
+        if size == 0 {
+            return Ok(());
+        }
+
+        let data = self.get_handle(handle)?;
+        let entries = match data.as_ref() {
+            UnionHandle::Dir(entries) => entries,
+            UnionHandle::File { .. } => return Err(Error::from_raw_os_error(libc::ENOTDIR)),
+        };
+
+        for (i, e) in entries.iter().enumerate().skip(offset as usize) {
+            let dirent = DirEntry {
+                ino: e.ino,
+                offset: i as u64 + 1,
+                type_: e.type_,
+                name: &e.name,
+            };
+            if add_entry(dirent)? == 0 {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn releasedir(&self, _ctx: &Context, _inode: Inode, _flags: u32, handle: Handle) -> Result<()> {
+        self.handles
+            .lock()
+            .unwrap()
+            .remove(&handle)
+            .map(|_| ())
+            .ok_or_else(|| Error::from_raw_os_error(libc::EBADF))
+    }
+
+    fn access(&self, ctx: &Context, inode: Inode, mask: u32) -> Result<()> {
+        if mask & libc::W_OK as u32 != 0 {
+            return Err(erofs());
+        }
+
+        let (layer, ino) = self.primary(inode)?;
+        layer.access(ctx, ino, mask)
+    }
+
+    fn clone_range(
+        &self,
+        _ctx: &Context,
+        _src_inode: Inode,
+        _src_handle: Handle,
+        _src_offset: u64,
+        _dst_inode: Inode,
+        _dst_handle: Handle,
+        _dst_offset: u64,
+        _len: u64,
+    ) -> Result<usize> {
+        Err(erofs())
+    }
+
+    fn enable_verity(&self, _ctx: &Context, _inode: Inode) -> Result<()> {
+        Err(erofs())
+    }
+
+    fn measure_verity(&self, ctx: &Context, inode: Inode) -> Result<Vec<u8>> {
+        let (layer, ino) = self.primary(inode)?;
+        layer.measure_verity(ctx, ino)
+    }
+
+    fn fiemap(
+        &self,
+        ctx: &Context,
+        inode: Inode,
+        start: u64,
+        len: u64,
+    ) -> Result<Vec<FiemapExtent>> {
+        let (layer, ino) = self.primary(inode)?;
+        layer.fiemap(ctx, ino, start, len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::FileReadWriteVolatile;
+    use std::ffi::CString;
+    use std::io::{self, Write};
+
+    // A flat file system with regular files and empty directories in the root directory.
+    struct TestFs {
+        // (name, content), a `None` content means a directory.
+        entries: Vec<(&'static str, Option<&'static [u8]>)>,
+        lookups: AtomicU64,
+    }
+
+    impl TestFs {
+        fn new(entries: Vec<(&'static str, Option<&'static [u8]>)>) -> Arc<TestFs> {
+            Arc::new(TestFs {
+                entries,
+                lookups: AtomicU64::new(0),
+            })
+        }
+
+        fn attr(&self, inode: Inode) -> stat64 {
+            let mut st: stat64 = unsafe { mem::zeroed() };
+            st.st_ino = inode;
+            if inode == ROOT_ID {
+                st.st_mode = libc::S_IFDIR | 0o755;
+            } else {
+                match self.entries[inode as usize - 2].1 {
+                    Some(data) => {
+                        st.st_mode = libc::S_IFREG | 0o644;
+                        st.st_size = data.len() as i64;
+                    }
+                    None => st.st_mode = libc::S_IFDIR | 0o755,
+                }
+            }
+            st
+        }
+    }
+
+    impl FileSystem for TestFs {
+        type Inode = Inode;
+        type Handle = Handle;
+
+        fn lookup(&self, _ctx: &Context, parent: Inode, name: &CStr) -> Result<Entry> {
+            let idx = self
+                .entries
+                .iter()
+                .position(|(n, _)| parent == ROOT_ID && n.as_bytes() == name.to_bytes())
+                .ok_or_else(|| Error::from_raw_os_error(libc::ENOENT))?;
+            self.lookups.fetch_add(1, Ordering::Relaxed);
+
+            let inode = idx as u64 + 2;
+            Ok(Entry {
+                inode,
+                generation: 0,
+                attr: self.attr(inode),
+                attr_flags: 0,
+                attr_timeout: Duration::from_secs(1),
+                entry_timeout: Duration::from_secs(1),
+            })
+        }
+
+        fn forget(&self, _ctx: &Context, _inode: Inode, count: u64) {
+            self.lookups.fetch_sub(count, Ordering::Relaxed);
+        }
+
+        fn getattr(
+            &self,
+            _ctx: &Context,
+            inode: Inode,
+            _handle: Option<Handle>,
+        ) -> Result<(stat64, Duration)> {
+            Ok((self.attr(inode), Duration::from_secs(1)))
+        }
+
+        fn open(
+            &self,
+            _ctx: &Context,
+            _inode: Inode,
+            _flags: u32,
+            _fuse_flags: u32,
+        ) -> Result<(Option<Handle>, OpenOptions)> {
+            Ok((None, OpenOptions::empty()))
+        }
+
+        fn read(
+            &self,
+            _ctx: &Context,
+            inode: Inode,
+            _handle: Handle,
+            w: &mut dyn ZeroCopyWriter,
+            _size: u32,
+            _offset: u64,
+            _lock_owner: Option<u64>,
+            _flags: u32,
+        ) -> Result<usize> {
+            let data = self.entries[inode as usize - 2].1.unwrap();
+            w.write_all(data)?;
+            Ok(data.len())
+        }
+
+        fn readdir(
+            &self,
+            _ctx: &Context,
+            inode: Inode,
+            _handle: Handle,
+            _size: u32,
+            offset: u64,
+            add_entry: &mut dyn FnMut(DirEntry) -> Result<usize>,
+        ) -> Result<()> {
+            if inode != ROOT_ID {
+                return Ok(());
+            }
+            for (i, (name, data)) in self.entries.iter().enumerate().skip(offset as usize) {
+                let dirent = DirEntry {
+                    ino: i as u64 + 2,
+                    offset: i as u64 + 1,
+                    type_: if data.is_some() {
+                        libc::DT_REG as u32
+                    } else {
+                        libc::DT_DIR as u32
+                    },
+                    name: name.as_bytes(),
+                };
+                if add_entry(dirent)? == 0 {
+                    break;
+                }
+            }
+            Ok(())
+        }
+
+        fn releasedir(
+            &self,
+            _ctx: &Context,
+            _inode: Inode,
+            _flags: u32,
+            _handle: Handle,
+        ) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    struct VecWriter(Vec<u8>);
+
+    impl Write for VecWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl ZeroCopyWriter for VecWriter {
+        fn write_from(
+            &mut self,
+            _f: &mut dyn FileReadWriteVolatile,
+            _count: usize,
+            _off: u64,
+        ) -> io::Result<usize> {
+            Err(Error::from_raw_os_error(libc::ENOSYS))
+        }
+    }
+
+    fn read_file(fs: &UnionFs, inode: Inode) -> Vec<u8> {
+        let ctx = Context::default();
+        let (handle, _) = fs.open(&ctx, inode, libc::O_RDONLY as u32, 0).unwrap();
+        let mut w = VecWriter(Vec::new());
+        fs.read(&ctx, inode, handle.unwrap(), &mut w, 4096, 0, None, 0)
+            .unwrap();
+        fs.release(&ctx, inode, 0, handle.unwrap(), false, false, None)
+            .unwrap();
+        w.0
+    }
+
+    #[test]
+    fn test_union_lookup_precedence() {
+        let upper = TestFs::new(vec![("a", Some(b"upper a")), ("dir", None)]);
+        let lower = TestFs::new(vec![
+            ("a", Some(b"lower a")),
+            ("b", Some(b"lower b")),
+            ("dir", None),
+        ]);
+        let fs = UnionFs::new(vec![upper.clone(), lower.clone()]);
+        let ctx = Context::default();
+
+        let a = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("a").unwrap())
+            .unwrap();
+        assert_eq!(read_file(&fs, a.inode), b"upper a");
+        let b = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("b").unwrap())
+            .unwrap();
+        assert_eq!(read_file(&fs, b.inode), b"lower b");
+        assert_eq!(fs.getattr(&ctx, b.inode, None).unwrap().0.st_size, 7);
+        assert!(fs
+            .lookup(&ctx, ROOT_ID, &CString::new("c").unwrap())
+            .is_err());
+
+        // The same name resolves to the same inode.
+        let a2 = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("a").unwrap())
+            .unwrap();
+        assert_eq!(a.inode, a2.inode);
+        assert_ne!(a.inode, b.inode);
+
+        // Mutating requests are rejected.
+        let e = fs.open(&ctx, a.inode, libc::O_RDWR as u32, 0).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EROFS));
+        let e = fs
+            .unlink(&ctx, ROOT_ID, &CString::new("a").unwrap())
+            .unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EROFS));
+
+        // Merged directories reference both layers until forgotten.
+        let dir = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("dir").unwrap())
+            .unwrap();
+        assert_eq!(fs.backing(dir.inode).unwrap().len(), 2);
+        fs.forget(&ctx, a.inode, 2);
+        fs.forget(&ctx, b.inode, 1);
+        fs.forget(&ctx, dir.inode, 1);
+        fs.backing(a.inode).unwrap_err();
+        assert_eq!(upper.lookups.load(Ordering::Relaxed), 0);
+        assert_eq!(lower.lookups.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_union_readdir_merge() {
+        let upper = TestFs::new(vec![("b", Some(b"upper b")), ("c", None)]);
+        let lower = TestFs::new(vec![("a", Some(b"lower a")), ("b", Some(b"lower b"))]);
+        let fs = UnionFs::new(vec![upper, lower]);
+        let ctx = Context::default();
+
+        let (handle, _) = fs.opendir(&ctx, ROOT_ID, 0).unwrap();
+        let handle = handle.unwrap();
+        let mut entries = Vec::new();
+        fs.readdir(&ctx, ROOT_ID, handle, 4096, 0, &mut |e| {
+            entries.push((String::from_utf8(e.name.to_vec()).unwrap(), e.ino, e.offset));
+            Ok(1)
+        })
+        .unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                ("b".to_string(), 2, 1),
+                ("c".to_string(), 3, 2),
+                ("a".to_string(), 2, 3)
+            ]
+        );
+
+        // Resume from an offset.
+        let mut names = Vec::new();
+        fs.readdir(&ctx, ROOT_ID, handle, 4096, 2, &mut |e| {
+            names.push(e.name.to_vec());
+            Ok(1)
+        })
+        .unwrap();
+        assert_eq!(names, vec![b"a".to_vec()]);
+        fs.releasedir(&ctx, ROOT_ID, 0, handle).unwrap();
+    }
+
+    #[test]
+    fn test_union_inode_recycle() {
+        let layer = TestFs::new(vec![("a", Some(b"a")), ("b", Some(b"b"))]);
+        let fs = UnionFs::new(vec![layer.clone()]);
+        let ctx = Context::default();
+        let a = CString::new("a").unwrap();
+        let b = CString::new("b").unwrap();
+
+        // An inode still referenced by the kernel is never recycled.
+        let e1 = fs.lookup(&ctx, ROOT_ID, &a).unwrap();
+        let e2 = fs.lookup(&ctx, ROOT_ID, &a).unwrap();
+        assert_eq!(e1.inode, e2.inode);
+        fs.forget(&ctx, e1.inode, 1);
+        let eb = fs.lookup(&ctx, ROOT_ID, &b).unwrap();
+        assert_ne!(eb.inode, e1.inode);
+        fs.forget(&ctx, e1.inode, 1);
+        fs.forget(&ctx, eb.inode, 1);
+
+        let mut generation = 0;
+        for i in 0..100_000 {
+            let name = if i % 2 == 0 { &a } else { &b };
+            let e = fs.lookup(&ctx, ROOT_ID, name).unwrap();
+            assert!(e.inode == e1.inode || e.inode == eb.inode);
+            assert!(e.generation > generation);
+            generation = e.generation;
+            fs.forget(&ctx, e.inode, 1);
+        }
+
+        assert_eq!(fs.inodes.lock().unwrap().len(), 1);
+        assert_eq!(layer.lookups.load(Ordering::Relaxed), 0);
+    }
+}
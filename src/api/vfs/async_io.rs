@@ -222,6 +222,7 @@ mod tests {
             gid: 0,
             pid: 0,
             drive: 0,
+            ..Default::default()
         };
         let executor = futures::executor::ThreadPool::new().unwrap();
 
@@ -17,7 +17,7 @@
 
 use std::any::Any;
 use std::collections::HashMap;
-use std::ffi::CStr;
+use std::ffi::{CStr, CString};
 use std::io;
 use std::io::{Error, ErrorKind, Result};
 use std::marker::PhantomData;
@@ -95,7 +95,7 @@ pub enum VfsError {
 pub type VfsResult<T> = std::result::Result<T, VfsError>;
 
 #[inline]
-fn is_dot_or_dotdot(name: &CStr) -> bool {
+pub(crate) fn is_dot_or_dotdot(name: &CStr) -> bool {
     let bytes = name.to_bytes_with_nul();
     bytes.starts_with(CURRENT_DIR_CSTR) || bytes.starts_with(PARENT_DIR_CSTR)
 }
@@ -220,6 +220,11 @@ pub struct VfsOptions {
     /// to remove security.capability xattr and setuid/setgid bits. See details in
     /// comments for HANDLE_KILLPRIV_V2
     pub killpriv_v2: bool,
+    /// Report the root of each mounted backend file system as a submount, so the kernel creates a
+    /// separate mount for it, with its own `st_dev` and entry in `/proc/mounts`. It needs the
+    /// kernel to support `FsOptions::SUBMOUNTS`, i.e. virtiofs on Linux 5.10 or later. Fusedev
+    /// connections never support it.
+    pub submounts: bool,
     /// File system options passed in from client
     pub in_opts: FsOptions,
     /// File system options returned to client
@@ -240,6 +245,7 @@ impl Default for VfsOptions {
             no_writeback: false,
             no_readdir: false,
             killpriv_v2: false,
+            submounts: false,
             in_opts: FsOptions::empty(),
             out_opts: FsOptions::ASYNC_READ
                 | FsOptions::PARALLEL_DIROPS
@@ -519,6 +525,15 @@ impl<D: AsyncDrive> Vfs<D> {
         }
     }
 
+    // Get the entry of the root of a mounted file system, for lookups crossing the mountpoint.
+    fn mount_root_entry(&self, mnt: &MountPointData) -> Entry {
+        let mut entry = mnt.root_entry;
+        if self.opts.load().out_opts.contains(FsOptions::SUBMOUNTS) {
+            entry.attr_flags |= ATTR_SUBMOUNT;
+        }
+        entry
+    }
+
     fn lookup_pseudo(
         &self,
         fs: &PseudoFs,
@@ -532,7 +547,7 @@ impl<D: AsyncDrive> Vfs<D> {
         match self.mountpoints.load().get(&entry.inode) {
             Some(mnt) => {
                 // cross mountpoint, return mount root entry
-                entry = mnt.root_entry;
+                entry = self.mount_root_entry(mnt);
                 entry.inode = self.convert_inode(mnt.fs_idx, mnt.ino)?;
                 trace!(
                     "vfs lookup cross mountpoint, return new mount fs_idx {} inode {} fuse inode {}",
@@ -1015,6 +1030,39 @@ mod tests {
         assert_eq!(entry3.inode, 0);
     }
 
+    #[test]
+    fn test_vfs_submounts() {
+        let ctx = Context::new();
+        let lookup = |vfs: &Vfs, parent: u64, name: &str| {
+            vfs.lookup(&ctx, parent.into(), &CString::new(name).unwrap())
+                .unwrap()
+        };
+
+        for (submounts, capable) in [(true, true), (true, false), (false, true)] {
+            let vfs = Vfs::new(VfsOptions {
+                submounts,
+                ..Default::default()
+            });
+            vfs.mount(Box::new(FakeFileSystemOne {}), "/x/y").unwrap();
+            let in_opts = if capable {
+                FsOptions::ASYNC_READ | FsOptions::SUBMOUNTS
+            } else {
+                FsOptions::ASYNC_READ
+            };
+            let out_opts = vfs.init(in_opts).unwrap();
+            let enabled = submounts && capable;
+            assert_eq!(out_opts.contains(FsOptions::SUBMOUNTS), enabled);
+
+            // Only the root of the mounted file system is a submount.
+            let x = lookup(&vfs, ROOT_ID, "x");
+            assert_eq!(x.attr_flags & ATTR_SUBMOUNT, 0);
+            let y = lookup(&vfs, x.inode, "y");
+            assert_eq!(y.attr_flags & ATTR_SUBMOUNT != 0, enabled);
+            let z = lookup(&vfs, y.inode, "z");
+            assert_eq!(z.attr_flags & ATTR_SUBMOUNT, 0);
+        }
+    }
+
     #[test]
     fn test_mount_different_fs_types() {
         let vfs = Vfs::new(VfsOptions::default());
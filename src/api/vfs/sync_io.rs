@@ -2,7 +2,7 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use super::*;
-use crate::abi::fuse_abi::{stat64, statvfs64};
+use crate::abi::fuse_abi::{stat64, statvfs64, Statx};
 #[cfg(any(feature = "vhost-user-fs", feature = "virtiofs"))]
 use crate::abi::virtio_fs;
 use crate::async_util::AsyncDrive;
@@ -33,6 +33,7 @@ impl<D: AsyncDrive> FileSystem for Vfs<D> {
         if !n_opts.killpriv_v2 {
             n_opts.out_opts.remove(FsOptions::HANDLE_KILLPRIV_V2);
         }
+        n_opts.out_opts.set(FsOptions::SUBMOUNTS, n_opts.submounts);
         n_opts.in_opts = opts;
 
         n_opts.out_opts &= opts;
@@ -64,6 +65,40 @@ impl<D: AsyncDrive> FileSystem for Vfs<D> {
         }
     }
 
+    fn wants_raw_header(&self) -> bool {
+        // File systems may be mounted at any time, and copying the header is cheap.
+        true
+    }
+
+    fn max_stack_depth(&self) -> u32 {
+        // Only the file systems mounted before FUSE_INIT are taken into account.
+        self.superblocks
+            .load()
+            .iter()
+            .flatten()
+            .map(|fs| fs.max_stack_depth())
+            .max()
+            .unwrap_or(1)
+    }
+
+    fn supported_ops(&self) -> SupportedOps {
+        // Operations forwarded to the mounted file systems, supported if any of them does.
+        let forwarded = SupportedOps::CLONE_RANGE
+            | SupportedOps::FALLOCATE
+            | SupportedOps::XATTR
+            | SupportedOps::TMPFILE
+            | SupportedOps::STATX
+            | SupportedOps::VERITY
+            | SupportedOps::FIEMAP
+            | SupportedOps::DAX_MAPPING;
+        self.superblocks
+            .load()
+            .iter()
+            .flatten()
+            .fold(SupportedOps::empty(), |ops, fs| ops | fs.supported_ops())
+            & forwarded
+    }
+
     fn lookup(&self, ctx: &Context, parent: VfsInode, name: &CStr) -> Result<Entry> {
         // Don't use is_safe_path_component(), allow "." and ".." for NFS export support
         if name.to_bytes_with_nul().contains(&SLASH_ASCII) {
@@ -106,6 +141,24 @@ impl<D: AsyncDrive> FileSystem for Vfs<D> {
         }
     }
 
+    fn statx(
+        &self,
+        ctx: &Context,
+        inode: VfsInode,
+        handle: Option<VfsHandle>,
+        flags: u32,
+        mask: u32,
+    ) -> Result<(Statx, Duration)> {
+        match self.get_real_rootfs(inode)? {
+            // The kernel stops sending FUSE_STATX once it fails with ENOSYS, so build the reply
+            // from basic attributes for the pseudo file system.
+            (Left(fs), idata) => fs
+                .getattr(ctx, idata.ino(), handle)
+                .map(|(st, timeout)| (Statx::from(st), timeout)),
+            (Right(fs), idata) => fs.statx(ctx, idata.ino(), handle, flags, mask),
+        }
+    }
+
     fn setattr(
         &self,
         ctx: &Context,
@@ -127,6 +180,13 @@ impl<D: AsyncDrive> FileSystem for Vfs<D> {
         }
     }
 
+    fn canonical_path(&self, ctx: &Context, inode: VfsInode) -> Result<CString> {
+        match self.get_real_rootfs(inode)? {
+            (Left(fs), idata) => fs.canonical_path(ctx, idata.ino()),
+            (Right(fs), idata) => fs.canonical_path(ctx, idata.ino()),
+        }
+    }
+
     fn symlink(
         &self,
         ctx: &Context,
@@ -187,6 +247,26 @@ impl<D: AsyncDrive> FileSystem for Vfs<D> {
         }
     }
 
+    fn init_security_context(
+        &self,
+        ctx: &Context,
+        parent: VfsInode,
+        name: &CStr,
+        inode: VfsInode,
+        secctx: &SecContext,
+    ) -> Result<()> {
+        let (_, node) = self.get_real_rootfs(inode)?;
+
+        match self.get_real_rootfs(parent)? {
+            (Left(fs), idata) => {
+                fs.init_security_context(ctx, idata.ino(), name, node.ino(), secctx)
+            }
+            (Right(fs), idata) => {
+                fs.init_security_context(ctx, idata.ino(), name, node.ino(), secctx)
+            }
+        }
+    }
+
     fn unlink(&self, ctx: &Context, parent: VfsInode, name: &CStr) -> Result<()> {
         validate_path_component(name)?;
 
@@ -311,6 +391,26 @@ impl<D: AsyncDrive> FileSystem for Vfs<D> {
         }
     }
 
+    fn tmpfile(
+        &self,
+        ctx: &Context,
+        parent: VfsInode,
+        mode: u32,
+        umask: u32,
+        flags: u32,
+    ) -> Result<(Entry, Option<u64>, OpenOptions)> {
+        match self.get_real_rootfs(parent)? {
+            (Left(fs), idata) => fs.tmpfile(ctx, idata.ino(), mode, umask, flags),
+            (Right(fs), idata) => {
+                fs.tmpfile(ctx, idata.ino(), mode, umask, flags)
+                    .map(|(mut a, b, c)| {
+                        a.inode = self.convert_inode(idata.fs_idx(), a.inode)?;
+                        Ok((a, b, c))
+                    })?
+            }
+        }
+    }
+
     fn read(
         &self,
         ctx: &Context,
@@ -441,6 +541,18 @@ impl<D: AsyncDrive> FileSystem for Vfs<D> {
         }
     }
 
+    fn syncfs(&self, ctx: &Context) -> Result<()> {
+        // Sync all mounted file systems even if one fails, and skip those not supporting it.
+        let mut res = Ok(());
+        for fs in self.superblocks.load().iter().flatten() {
+            match fs.syncfs(ctx) {
+                Err(e) if e.raw_os_error() != Some(libc::ENOSYS) && res.is_ok() => res = Err(e),
+                _ => {}
+            }
+        }
+        res
+    }
+
     fn setxattr(
         &self,
         ctx: &Context,
@@ -564,7 +676,7 @@ impl<D: AsyncDrive> FileSystem for Vfs<D> {
                         Some(mnt) => {
                             // cross mountpoint, return mount root entry
                             dir_entry.ino = self.convert_inode(mnt.fs_idx, mnt.ino)?;
-                            entry = mnt.root_entry;
+                            entry = self.mount_root_entry(mnt);
                         }
                         None => {
                             dir_entry.ino = self.convert_inode(idata.fs_idx(), dir_entry.ino)?;
@@ -590,6 +702,33 @@ impl<D: AsyncDrive> FileSystem for Vfs<D> {
         }
     }
 
+    fn readdir_cursor(
+        &self,
+        ctx: &Context,
+        inode: VfsInode,
+        handle: u64,
+        size: u32,
+        cursor: Option<&[u8]>,
+    ) -> Result<(Vec<DirEntryBuf>, Option<Vec<u8>>)> {
+        match self.get_real_rootfs(inode)? {
+            (Left(fs), idata) => {
+                let (mut entries, next) =
+                    fs.readdir_cursor(ctx, idata.ino(), handle, size, cursor)?;
+                let mountpoints = self.mountpoints.load();
+                for entry in entries.iter_mut() {
+                    entry.ino = match mountpoints.get(&entry.ino) {
+                        // cross mountpoint, return mount root entry
+                        Some(mnt) => self.convert_inode(mnt.fs_idx, mnt.ino)?,
+                        None => self.convert_inode(idata.fs_idx(), entry.ino)?,
+                    };
+                }
+                Ok((entries, next))
+            }
+
+            (Right(fs), idata) => fs.readdir_cursor(ctx, idata.ino(), handle, size, cursor),
+        }
+    }
+
     fn fsyncdir(&self, ctx: &Context, inode: VfsInode, datasync: bool, handle: u64) -> Result<()> {
         match self.get_real_rootfs(inode)? {
             (Left(fs), idata) => fs.fsyncdir(ctx, idata.ino(), datasync, handle),
@@ -611,6 +750,76 @@ impl<D: AsyncDrive> FileSystem for Vfs<D> {
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
+    fn clone_range(
+        &self,
+        ctx: &Context,
+        src_inode: VfsInode,
+        src_handle: u64,
+        src_offset: u64,
+        dst_inode: VfsInode,
+        dst_handle: u64,
+        dst_offset: u64,
+        len: u64,
+    ) -> Result<usize> {
+        let (root, idata_src) = self.get_real_rootfs(src_inode)?;
+        let (_, idata_dst) = self.get_real_rootfs(dst_inode)?;
+
+        if idata_src.fs_idx() != idata_dst.fs_idx() {
+            return Err(Error::from_raw_os_error(libc::EXDEV));
+        }
+
+        match root {
+            Left(fs) => fs.clone_range(
+                ctx,
+                idata_src.ino(),
+                src_handle,
+                src_offset,
+                idata_dst.ino(),
+                dst_handle,
+                dst_offset,
+                len,
+            ),
+            Right(fs) => fs.clone_range(
+                ctx,
+                idata_src.ino(),
+                src_handle,
+                src_offset,
+                idata_dst.ino(),
+                dst_handle,
+                dst_offset,
+                len,
+            ),
+        }
+    }
+
+    fn enable_verity(&self, ctx: &Context, inode: VfsInode) -> Result<()> {
+        match self.get_real_rootfs(inode)? {
+            (Left(fs), idata) => fs.enable_verity(ctx, idata.ino()),
+            (Right(fs), idata) => fs.enable_verity(ctx, idata.ino()),
+        }
+    }
+
+    fn measure_verity(&self, ctx: &Context, inode: VfsInode) -> Result<Vec<u8>> {
+        match self.get_real_rootfs(inode)? {
+            (Left(fs), idata) => fs.measure_verity(ctx, idata.ino()),
+            (Right(fs), idata) => fs.measure_verity(ctx, idata.ino()),
+        }
+    }
+
+    fn fiemap(
+        &self,
+        ctx: &Context,
+        inode: VfsInode,
+        start: u64,
+        len: u64,
+    ) -> Result<Vec<FiemapExtent>> {
+        match self.get_real_rootfs(inode)? {
+            (Left(fs), idata) => fs.fiemap(ctx, idata.ino(), start, len),
+            (Right(fs), idata) => fs.fiemap(ctx, idata.ino(), start, len),
+        }
+    }
+
     #[cfg(any(feature = "vhost-user-fs", feature = "virtiofs"))]
     fn setupmapping(
         &self,
@@ -0,0 +1,1107 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A file system wrapper coalescing small sequential writes.
+//!
+//! Guests often issue long runs of small writes, each contiguous with the previous one, e.g. when
+//! the page cache is disabled. Forwarding each of them to the backend costs a syscall, or a round
+//! trip for remote backends, per write. The [WriteCoalescer] keeps a buffer per file handle and
+//! appends contiguous writes to it, until the buffer reaches [CoalesceLimits::max_bytes] or its
+//! oldest data is older than [CoalesceLimits::max_delay]. The buffer is then written to the
+//! wrapped file system as a single request.
+//!
+//! Buffered data is written back before any request which may observe it: a write which isn't
+//! contiguous with the buffer, `flush`, `fsync` and `release` on the handle, and requests reading
+//! data or attributes of the inode, such as `read`, `getattr`, `setattr` and `lseek`.
+//!
+//! A buffered write is acknowledged before it reaches the backend, so an error writing it back is
+//! reported by the next `write`, `flush` or `fsync` request on the handle, like the kernel does
+//! for errors writing back dirty pages. Nothing checks the buffer on a timer: the owner of the
+//! wrapper should call [WriteCoalescer::flush_expired()] periodically to bound the time data
+//! stays in the buffer of an idle handle.
+
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::abi::fuse_abi::{
+    stat64, statvfs64, CreateIn, FsOptions, OpenOptions, SetattrValid, Statx,
+};
+#[cfg(feature = "virtiofs")]
+use crate::abi::virtio_fs::RemovemappingOne;
+use crate::api::filesystem::*;
+#[cfg(feature = "virtiofs")]
+use crate::transport::FsCacheReqHandler;
+use crate::transport::{FileReadWriteVolatile, FileVolatileSlice};
+
+/// Limits on the data buffered by [WriteCoalescer] for each file handle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CoalesceLimits {
+    /// Maximum amount of buffered data, in bytes. Writes at least this large are not buffered.
+    pub max_bytes: usize,
+    /// Maximum time data stays in the buffer.
+    pub max_delay: Duration,
+}
+
+impl Default for CoalesceLimits {
+    fn default() -> Self {
+        CoalesceLimits {
+            max_bytes: 0x10_0000,
+            max_delay: Duration::from_millis(10),
+        }
+    }
+}
+
+// Contiguous data written through a handle, waiting to be written to the wrapped file system.
+struct PendingWrite {
+    offset: u64,
+    data: Vec<u8>,
+    since: Instant,
+    ctx: Context<'static>,
+    lock_owner: Option<u64>,
+    flags: u32,
+    fuse_flags: u32,
+}
+
+impl PendingWrite {
+    fn end(&self) -> u64 {
+        self.offset + self.data.len() as u64
+    }
+}
+
+#[derive(Default)]
+struct HandleState {
+    pending: Option<PendingWrite>,
+    // Error writing back buffered data, reported by the next request on the handle.
+    error: Option<io::Error>,
+}
+
+struct HandleSlot {
+    inode: u64,
+    state: Mutex<HandleState>,
+}
+
+// Feeds buffered data to the write method of the wrapped file system.
+pub(crate) struct BufferReader<'a> {
+    pub(crate) data: &'a [u8],
+}
+
+impl io::Read for BufferReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.data.read(buf)
+    }
+}
+
+impl ZeroCopyReader for BufferReader<'_> {
+    fn read_to(
+        &mut self,
+        f: &mut dyn FileReadWriteVolatile,
+        count: usize,
+        off: u64,
+    ) -> io::Result<usize> {
+        let len = std::cmp::min(count, self.data.len());
+        // Safe because the buffer outlives the slice, which is only read from.
+        let slice = unsafe { FileVolatileSlice::new(self.data.as_ptr() as *mut u8, len) };
+        let len = f.write_at_volatile(slice, off)?;
+        self.data = &self.data[len..];
+        Ok(len)
+    }
+}
+
+/// A file system wrapper buffering small sequential writes, to forward them to the wrapped file
+/// system in larger requests.
+pub struct WriteCoalescer<F> {
+    inner: F,
+    limits: CoalesceLimits,
+    handles: Mutex<HashMap<u64, Arc<HandleSlot>>>,
+}
+
+impl<F: FileSystem> WriteCoalescer<F> {
+    /// Wrap `inner`, buffering writes within `limits`.
+    pub fn new(inner: F, limits: CoalesceLimits) -> Self {
+        WriteCoalescer {
+            inner,
+            limits,
+            handles: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Get the wrapped file system.
+    pub fn inner(&self) -> &F {
+        &self.inner
+    }
+
+    /// Write back the buffers holding data older than [CoalesceLimits::max_delay].
+    ///
+    /// Errors are kept and reported by the next request on the handle.
+    pub fn flush_expired(&self) {
+        for (handle, slot) in self.slots(|_| true) {
+            let mut state = slot.state.lock().unwrap();
+            let expired = match state.pending.as_ref() {
+                Some(p) => p.since.elapsed() >= self.limits.max_delay,
+                None => false,
+            };
+            if expired {
+                if let Err(e) = self.write_back(slot.inode, handle, &mut state) {
+                    state.error = Some(e);
+                }
+            }
+        }
+    }
+
+    fn slots(&self, filter: impl Fn(&HandleSlot) -> bool) -> Vec<(u64, Arc<HandleSlot>)> {
+        self.handles
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, slot)| filter(slot))
+            .map(|(handle, slot)| (*handle, slot.clone()))
+            .collect()
+    }
+
+    fn slot(&self, inode: u64, handle: u64) -> Arc<HandleSlot> {
+        self.handles
+            .lock()
+            .unwrap()
+            .entry(handle)
+            .or_insert_with(|| {
+                Arc::new(HandleSlot {
+                    inode,
+                    state: Mutex::new(HandleState::default()),
+                })
+            })
+            .clone()
+    }
+
+    // Write the buffered data of `handle` to the wrapped file system.
+    fn write_back(&self, inode: u64, handle: u64, state: &mut HandleState) -> io::Result<()> {
+        let p = match state.pending.take() {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        let mut r = BufferReader { data: &p.data };
+        while !r.data.is_empty() {
+            let done = p.data.len() - r.data.len();
+            let size = r.data.len() as u32;
+            let n = self.inner.write(
+                &p.ctx,
+                inode.into(),
+                handle.into(),
+                &mut r,
+                size,
+                p.offset + done as u64,
+                p.lock_owner,
+                false,
+                p.flags,
+                p.fuse_flags,
+            )?;
+            if n == 0 {
+                return Err(io::Error::from(io::ErrorKind::WriteZero));
+            }
+        }
+        Ok(())
+    }
+
+    // Write back the buffer of `handle` and take any error left by an earlier write back.
+    fn flush_handle(&self, handle: u64) -> io::Result<()> {
+        let slot = match self.handles.lock().unwrap().get(&handle) {
+            Some(slot) => slot.clone(),
+            None => return Ok(()),
+        };
+        let mut state = slot.state.lock().unwrap();
+        self.write_back(slot.inode, handle, &mut state)?;
+        state.error.take().map_or(Ok(()), Err)
+    }
+
+    // Write back the buffers of all handles of `inode`, before its data or attributes are read.
+    fn flush_inode(&self, inode: u64) -> io::Result<()> {
+        for (handle, slot) in self.slots(|slot| slot.inode == inode) {
+            let mut state = slot.state.lock().unwrap();
+            self.write_back(inode, handle, &mut state)?;
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn buffer_write(
+        &self,
+        ctx: &Context,
+        inode: u64,
+        handle: u64,
+        r: &mut dyn ZeroCopyReader,
+        size: u32,
+        offset: u64,
+        lock_owner: Option<u64>,
+        flags: u32,
+        fuse_flags: u32,
+    ) -> io::Result<usize> {
+        let slot = self.slot(inode, handle);
+        let mut state = slot.state.lock().unwrap();
+        if let Some(e) = state.error.take() {
+            return Err(e);
+        }
+        let size = size as usize;
+        let contiguous = match state.pending.as_ref() {
+            Some(p) => {
+                p.end() == offset
+                    && p.lock_owner == lock_owner
+                    && p.flags == flags
+                    && p.fuse_flags == fuse_flags
+                    && p.data.len() + size <= self.limits.max_bytes
+            }
+            None => true,
+        };
+        if !contiguous {
+            self.write_back(inode, handle, &mut state)?;
+        }
+
+        let p = state.pending.get_or_insert_with(|| PendingWrite {
+            offset,
+            data: Vec::new(),
+            since: Instant::now(),
+            ctx: ctx.without_extensions(),
+            lock_owner,
+            flags,
+            fuse_flags,
+        });
+        let start = p.data.len();
+        p.data.resize(start + size, 0);
+        if let Err(e) = r.read_exact(&mut p.data[start..]) {
+            p.data.truncate(start);
+            if start == 0 {
+                state.pending = None;
+            }
+            return Err(e);
+        }
+        if p.data.len() >= self.limits.max_bytes || p.since.elapsed() >= self.limits.max_delay {
+            self.write_back(inode, handle, &mut state)?;
+        }
+        Ok(size)
+    }
+}
+
+impl<F: FileSystem> FileSystem for WriteCoalescer<F> {
+    type Inode = F::Inode;
+    type Handle = F::Handle;
+
+    fn init(&self, capable: FsOptions) -> io::Result<FsOptions> {
+        self.inner.init(capable)
+    }
+
+    fn init_ext(&self, capable: FsOptionsExt) -> io::Result<FsOptionsExt> {
+        self.inner.init_ext(capable)
+    }
+
+    fn destroy(&self) {
+        for (handle, slot) in self.slots(|_| true) {
+            let mut state = slot.state.lock().unwrap();
+            if let Err(e) = self.write_back(slot.inode, handle, &mut state) {
+                warn!("fuse: failed to write back buffered data: {}", e);
+            }
+        }
+        self.inner.destroy()
+    }
+
+    fn wants_raw_header(&self) -> bool {
+        self.inner.wants_raw_header()
+    }
+
+    fn max_stack_depth(&self) -> u32 {
+        self.inner.max_stack_depth()
+    }
+
+    fn supported_ops(&self) -> SupportedOps {
+        self.inner.supported_ops()
+    }
+
+    fn lookup(&self, ctx: &Context, parent: Self::Inode, name: &CStr) -> io::Result<Entry> {
+        self.inner.lookup(ctx, parent, name)
+    }
+
+    fn forget(&self, ctx: &Context, inode: Self::Inode, count: u64) {
+        self.inner.forget(ctx, inode, count)
+    }
+
+    fn batch_forget(&self, ctx: &Context, requests: Vec<(Self::Inode, u64)>) {
+        self.inner.batch_forget(ctx, requests)
+    }
+
+    fn getattr(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Option<Self::Handle>,
+    ) -> io::Result<(stat64, Duration)> {
+        let inode = inode.into();
+        self.flush_inode(inode)?;
+        self.inner.getattr(ctx, inode.into(), handle)
+    }
+
+    fn getattr_batch(
+        &self,
+        ctx: &Context,
+        inodes: Vec<Self::Inode>,
+    ) -> Vec<io::Result<(stat64, Duration)>> {
+        let inodes: Vec<u64> = inodes.into_iter().map(|i| i.into()).collect();
+        for inode in inodes.iter() {
+            if let Err(e) = self.flush_inode(*inode) {
+                return inodes
+                    .iter()
+                    .map(|_| {
+                        Err(io::Error::from_raw_os_error(
+                            e.raw_os_error().unwrap_or(libc::EIO),
+                        ))
+                    })
+                    .collect();
+            }
+        }
+        self.inner
+            .getattr_batch(ctx, inodes.into_iter().map(|i| i.into()).collect())
+    }
+
+    fn statx(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Option<Self::Handle>,
+        flags: u32,
+        mask: u32,
+    ) -> io::Result<(Statx, Duration)> {
+        let inode = inode.into();
+        self.flush_inode(inode)?;
+        self.inner.statx(ctx, inode.into(), handle, flags, mask)
+    }
+
+    fn setattr(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        attr: stat64,
+        handle: Option<Self::Handle>,
+        valid: SetattrValid,
+    ) -> io::Result<(stat64, Duration)> {
+        let inode = inode.into();
+        self.flush_inode(inode)?;
+        self.inner.setattr(ctx, inode.into(), attr, handle, valid)
+    }
+
+    fn readlink(&self, ctx: &Context, inode: Self::Inode) -> io::Result<Vec<u8>> {
+        self.inner.readlink(ctx, inode)
+    }
+
+    fn canonical_path(&self, ctx: &Context, inode: Self::Inode) -> io::Result<CString> {
+        self.inner.canonical_path(ctx, inode)
+    }
+
+    fn symlink(
+        &self,
+        ctx: &Context,
+        linkname: &CStr,
+        parent: Self::Inode,
+        name: &CStr,
+    ) -> io::Result<Entry> {
+        self.inner.symlink(ctx, linkname, parent, name)
+    }
+
+    fn mknod(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        name: &CStr,
+        mode: u32,
+        rdev: u32,
+        umask: u32,
+    ) -> io::Result<Entry> {
+        self.inner.mknod(ctx, inode, name, mode, rdev, umask)
+    }
+
+    fn mkdir(
+        &self,
+        ctx: &Context,
+        parent: Self::Inode,
+        name: &CStr,
+        mode: u32,
+        umask: u32,
+    ) -> io::Result<Entry> {
+        self.inner.mkdir(ctx, parent, name, mode, umask)
+    }
+
+    fn init_security_context(
+        &self,
+        ctx: &Context,
+        parent: Self::Inode,
+        name: &CStr,
+        inode: Self::Inode,
+        secctx: &SecContext,
+    ) -> io::Result<()> {
+        self.inner
+            .init_security_context(ctx, parent, name, inode, secctx)
+    }
+
+    fn unlink(&self, ctx: &Context, parent: Self::Inode, name: &CStr) -> io::Result<()> {
+        self.inner.unlink(ctx, parent, name)
+    }
+
+    fn rmdir(&self, ctx: &Context, parent: Self::Inode, name: &CStr) -> io::Result<()> {
+        self.inner.rmdir(ctx, parent, name)
+    }
+
+    fn rename(
+        &self,
+        ctx: &Context,
+        olddir: Self::Inode,
+        oldname: &CStr,
+        newdir: Self::Inode,
+        newname: &CStr,
+        flags: u32,
+    ) -> io::Result<()> {
+        self.inner
+            .rename(ctx, olddir, oldname, newdir, newname, flags)
+    }
+
+    fn link(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        newparent: Self::Inode,
+        newname: &CStr,
+    ) -> io::Result<Entry> {
+        self.inner.link(ctx, inode, newparent, newname)
+    }
+
+    fn open(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        flags: u32,
+        fuse_flags: u32,
+    ) -> io::Result<(Option<Self::Handle>, OpenOptions)> {
+        let inode = inode.into();
+        // Buffered data written back after truncating the file would reappear in it.
+        if flags & libc::O_TRUNC as u32 != 0 {
+            self.flush_inode(inode)?;
+        }
+        self.inner.open(ctx, inode.into(), flags, fuse_flags)
+    }
+
+    fn create(
+        &self,
+        ctx: &Context,
+        parent: Self::Inode,
+        name: &CStr,
+        args: CreateIn,
+    ) -> io::Result<(Entry, Option<Self::Handle>, OpenOptions)> {
+        let parent = parent.into();
+        // Creating an existing file truncates it unless O_EXCL fails the request.
+        let flags = args.flags as i32;
+        if flags & libc::O_TRUNC != 0 && flags & libc::O_EXCL == 0 {
+            if let Ok(entry) = self.inner.lookup(ctx, parent.into(), name) {
+                let res = self.flush_inode(entry.inode);
+                self.inner.forget(ctx, entry.inode.into(), 1);
+                res?;
+            }
+        }
+        self.inner.create(ctx, parent.into(), name, args)
+    }
+
+    fn tmpfile(
+        &self,
+        ctx: &Context,
+        parent: Self::Inode,
+        mode: u32,
+        umask: u32,
+        flags: u32,
+    ) -> io::Result<(Entry, Option<Self::Handle>, OpenOptions)> {
+        self.inner.tmpfile(ctx, parent, mode, umask, flags)
+    }
+
+    fn read(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        w: &mut dyn ZeroCopyWriter,
+        size: u32,
+        offset: u64,
+        lock_owner: Option<u64>,
+        flags: u32,
+    ) -> io::Result<usize> {
+        let inode = inode.into();
+        self.flush_inode(inode)?;
+        self.inner.read(
+            ctx,
+            inode.into(),
+            handle,
+            w,
+            size,
+            offset,
+            lock_owner,
+            flags,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn write(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        r: &mut dyn ZeroCopyReader,
+        size: u32,
+        offset: u64,
+        lock_owner: Option<u64>,
+        delayed_write: bool,
+        flags: u32,
+        fuse_flags: u32,
+    ) -> io::Result<usize> {
+        let handle = handle.into();
+        if delayed_write || size as usize >= self.limits.max_bytes {
+            self.flush_handle(handle)?;
+            return self.inner.write(
+                ctx,
+                inode,
+                handle.into(),
+                r,
+                size,
+                offset,
+                lock_owner,
+                delayed_write,
+                flags,
+                fuse_flags,
+            );
+        }
+        self.buffer_write(
+            ctx,
+            inode.into(),
+            handle,
+            r,
+            size,
+            offset,
+            lock_owner,
+            flags,
+            fuse_flags,
+        )
+    }
+
+    fn flush(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        lock_owner: u64,
+    ) -> io::Result<()> {
+        let handle = handle.into();
+        self.flush_handle(handle)?;
+        self.inner.flush(ctx, inode, handle.into(), lock_owner)
+    }
+
+    fn fsync(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        datasync: bool,
+        handle: Self::Handle,
+    ) -> io::Result<()> {
+        let handle = handle.into();
+        self.flush_handle(handle)?;
+        self.inner.fsync(ctx, inode, datasync, handle.into())
+    }
+
+    fn fallocate(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        mode: u32,
+        offset: u64,
+        length: u64,
+    ) -> io::Result<()> {
+        let inode = inode.into();
+        self.flush_inode(inode)?;
+        self.inner
+            .fallocate(ctx, inode.into(), handle, mode, offset, length)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn release(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        flags: u32,
+        handle: Self::Handle,
+        flush: bool,
+        flock_release: bool,
+        lock_owner: Option<u64>,
+    ) -> io::Result<()> {
+        let handle = handle.into();
+        let res = self.flush_handle(handle);
+        self.handles.lock().unwrap().remove(&handle);
+        // Release the handle even if the buffered data couldn't be written.
+        self.inner
+            .release(
+                ctx,
+                inode,
+                flags,
+                handle.into(),
+                flush,
+                flock_release,
+                lock_owner,
+            )
+            .and(res)
+    }
+
+    fn statfs(&self, ctx: &Context, inode: Self::Inode) -> io::Result<statvfs64> {
+        self.inner.statfs(ctx, inode)
+    }
+
+    fn syncfs(&self, ctx: &Context) -> io::Result<()> {
+        for (handle, slot) in self.slots(|_| true) {
+            let mut state = slot.state.lock().unwrap();
+            self.write_back(slot.inode, handle, &mut state)?;
+        }
+        self.inner.syncfs(ctx)
+    }
+
+    fn setxattr(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        name: &CStr,
+        value: &[u8],
+        flags: u32,
+    ) -> io::Result<()> {
+        self.inner.setxattr(ctx, inode, name, value, flags)
+    }
+
+    fn getxattr(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        name: &CStr,
+        size: u32,
+    ) -> io::Result<GetxattrReply> {
+        self.inner.getxattr(ctx, inode, name, size)
+    }
+
+    fn listxattr(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        size: u32,
+    ) -> io::Result<ListxattrReply> {
+        self.inner.listxattr(ctx, inode, size)
+    }
+
+    fn removexattr(&self, ctx: &Context, inode: Self::Inode, name: &CStr) -> io::Result<()> {
+        self.inner.removexattr(ctx, inode, name)
+    }
+
+    fn opendir(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        flags: u32,
+    ) -> io::Result<(Option<Self::Handle>, OpenOptions)> {
+        self.inner.opendir(ctx, inode, flags)
+    }
+
+    fn readdir(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        size: u32,
+        offset: u64,
+        add_entry: &mut dyn FnMut(DirEntry) -> io::Result<usize>,
+    ) -> io::Result<()> {
+        self.inner
+            .readdir(ctx, inode, handle, size, offset, add_entry)
+    }
+
+    fn readdirplus(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        size: u32,
+        offset: u64,
+        add_entry: &mut dyn FnMut(DirEntry, Entry) -> io::Result<usize>,
+    ) -> io::Result<()> {
+        self.inner
+            .readdirplus(ctx, inode, handle, size, offset, add_entry)
+    }
+
+    fn readdir_cursor(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        size: u32,
+        cursor: Option<&[u8]>,
+    ) -> io::Result<(Vec<DirEntryBuf>, Option<Vec<u8>>)> {
+        self.inner.readdir_cursor(ctx, inode, handle, size, cursor)
+    }
+
+    fn fsyncdir(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        datasync: bool,
+        handle: Self::Handle,
+    ) -> io::Result<()> {
+        self.inner.fsyncdir(ctx, inode, datasync, handle)
+    }
+
+    fn releasedir(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        flags: u32,
+        handle: Self::Handle,
+    ) -> io::Result<()> {
+        self.inner.releasedir(ctx, inode, flags, handle)
+    }
+
+    #[cfg(feature = "virtiofs")]
+    #[allow(clippy::too_many_arguments)]
+    fn setupmapping(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        foffset: u64,
+        len: u64,
+        flags: u64,
+        moffset: u64,
+        vu_req: &mut dyn FsCacheReqHandler,
+    ) -> io::Result<()> {
+        let inode = inode.into();
+        self.flush_inode(inode)?;
+        self.inner.setupmapping(
+            ctx,
+            inode.into(),
+            handle,
+            foffset,
+            len,
+            flags,
+            moffset,
+            vu_req,
+        )
+    }
+
+    #[cfg(feature = "virtiofs")]
+    fn removemapping(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        requests: Vec<RemovemappingOne>,
+        vu_req: &mut dyn FsCacheReqHandler,
+    ) -> io::Result<()> {
+        self.inner.removemapping(ctx, inode, requests, vu_req)
+    }
+
+    fn access(&self, ctx: &Context, inode: Self::Inode, mask: u32) -> io::Result<()> {
+        self.inner.access(ctx, inode, mask)
+    }
+
+    fn lseek(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        offset: u64,
+        whence: u32,
+    ) -> io::Result<u64> {
+        let inode = inode.into();
+        self.flush_inode(inode)?;
+        self.inner.lseek(ctx, inode.into(), handle, offset, whence)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn clone_range(
+        &self,
+        ctx: &Context,
+        src_inode: Self::Inode,
+        src_handle: Self::Handle,
+        src_offset: u64,
+        dst_inode: Self::Inode,
+        dst_handle: Self::Handle,
+        dst_offset: u64,
+        len: u64,
+    ) -> io::Result<usize> {
+        let (src_inode, dst_inode) = (src_inode.into(), dst_inode.into());
+        self.flush_inode(src_inode)?;
+        self.flush_inode(dst_inode)?;
+        self.inner.clone_range(
+            ctx,
+            src_inode.into(),
+            src_handle,
+            src_offset,
+            dst_inode.into(),
+            dst_handle,
+            dst_offset,
+            len,
+        )
+    }
+
+    fn enable_verity(&self, ctx: &Context, inode: Self::Inode) -> io::Result<()> {
+        self.inner.enable_verity(ctx, inode)
+    }
+
+    fn measure_verity(&self, ctx: &Context, inode: Self::Inode) -> io::Result<Vec<u8>> {
+        self.inner.measure_verity(ctx, inode)
+    }
+
+    fn fiemap(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        start: u64,
+        len: u64,
+    ) -> io::Result<Vec<FiemapExtent>> {
+        let inode = inode.into();
+        self.flush_inode(inode)?;
+        self.inner.fiemap(ctx, inode.into(), start, len)
+    }
+
+    /// Query file lock status
+    fn getlk(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<FileLock> {
+        self.inner.getlk(ctx, inode, handle, owner, lock, flags)
+    }
+
+    /// Grab a file read lock
+    fn setlk(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<()> {
+        self.inner.setlk(ctx, inode, handle, owner, lock, flags)
+    }
+
+    /// Grab a file write lock
+    fn setlkw(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<()> {
+        self.inner.setlkw(ctx, inode, handle, owner, lock, flags)
+    }
+
+    /// send ioctl to the file
+    #[allow(clippy::too_many_arguments)]
+    fn ioctl(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        flags: u32,
+        cmd: u32,
+        data: IoctlData,
+        out_size: u32,
+    ) -> io::Result<IoctlData<'_>> {
+        self.inner
+            .ioctl(ctx, inode, handle, flags, cmd, data, out_size)
+    }
+
+    /// Query a file's block mapping info
+    fn bmap(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        block: u64,
+        blocksize: u32,
+    ) -> io::Result<u64> {
+        let inode = inode.into();
+        self.flush_inode(inode)?;
+        self.inner.bmap(ctx, inode.into(), block, blocksize)
+    }
+
+    /// Poll a file's events
+    fn poll(
+        &self,
+        ctx: &Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        khandle: Self::Handle,
+        flags: u32,
+        events: u32,
+    ) -> io::Result<u32> {
+        self.inner.poll(ctx, inode, handle, khandle, flags, events)
+    }
+
+    /// TODO: support this
+    fn notify_reply(&self) -> io::Result<()> {
+        self.inner.notify_reply()
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::passthrough::{Config, PassthroughFs};
+    use vmm_sys_util::tempdir::TempDir;
+
+    fn write(
+        fs: &WriteCoalescer<PassthroughFs>,
+        entry: &Entry,
+        handle: u64,
+        data: &[u8],
+        offset: u64,
+    ) -> io::Result<usize> {
+        let mut r = BufferReader { data };
+        fs.write(
+            &Context::default(),
+            entry.inode,
+            handle,
+            &mut r,
+            data.len() as u32,
+            offset,
+            None,
+            false,
+            0,
+            0,
+        )
+    }
+
+    #[test]
+    fn test_write_coalescer() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let fs_cfg = Config {
+            root_dir: source
+                .as_path()
+                .to_str()
+                .expect("source path to string")
+                .to_string(),
+            ..Default::default()
+        };
+        let passthrough = PassthroughFs::new(fs_cfg).unwrap();
+        passthrough.import().unwrap();
+        let limits = CoalesceLimits {
+            max_bytes: 0x10000,
+            max_delay: Duration::from_secs(3600),
+        };
+        let fs = WriteCoalescer::new(passthrough, limits);
+        let ctx = Context::default();
+        let args = CreateIn {
+            flags: libc::O_RDWR as u32,
+            mode: 0o644,
+            umask: 0,
+            fuse_flags: 0,
+        };
+        let (entry, handle, _) = fs
+            .create(&ctx, ROOT_ID, &CString::new("a").unwrap(), args)
+            .unwrap();
+        let handle = handle.unwrap();
+        let path = source.as_path().join("a");
+        let backend_len = || std::fs::metadata(&path).unwrap().len();
+        let mut expected = vec![0u8; 0x31000];
+        let mut write_expected = |data: &[u8], offset: u64| {
+            let offset = offset as usize;
+            expected[offset..offset + data.len()].copy_from_slice(data);
+            write(&fs, &entry, handle, data, offset as u64).unwrap()
+        };
+
+        // Sequential small writes are buffered.
+        for i in 0..8u8 {
+            let chunk = [i + 1; 0x1000];
+            assert_eq!(write_expected(&chunk, i as u64 * 0x1000), 0x1000);
+        }
+        assert_eq!(backend_len(), 0);
+
+        // A write elsewhere pushes the buffered data to the backend first.
+        assert_eq!(write_expected(&[0xaa; 100], 0x10000), 100);
+        assert_eq!(backend_len(), 0x8000);
+
+        // Large writes bypass the buffer.
+        assert_eq!(write_expected(&[0xbb; 0x10000], 0x10064), 0x10000);
+        assert_eq!(backend_len(), 0x20064);
+
+        // Reads observe the buffered data.
+        assert_eq!(write_expected(&[0xcc; 0x800], 0x20064), 0x800);
+        let (st, _) = fs.getattr(&ctx, entry.inode, None).unwrap();
+        assert_eq!(st.st_size, 0x20864);
+
+        // Data buffered up to the limit is written back without waiting.
+        for i in 0..16u64 {
+            write_expected(&[i as u8; 0x1000], 0x21000 + i * 0x1000);
+        }
+        assert_eq!(backend_len(), 0x31000);
+
+        write_expected(b"tail", 0x31000 - 4);
+        fs.flush(&ctx, entry.inode, handle, 0).unwrap();
+        fs.release(&ctx, entry.inode, 0, handle, false, false, None)
+            .unwrap();
+        let content = std::fs::read(&path).unwrap();
+        assert_eq!(content.len(), expected.len());
+        assert!(content == expected);
+    }
+
+    #[test]
+    fn test_write_coalescer_truncate() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let fs_cfg = Config {
+            root_dir: source
+                .as_path()
+                .to_str()
+                .expect("source path to string")
+                .to_string(),
+            ..Default::default()
+        };
+        let passthrough = PassthroughFs::new(fs_cfg).unwrap();
+        passthrough.import().unwrap();
+        let limits = CoalesceLimits {
+            max_bytes: 0x10000,
+            max_delay: Duration::from_secs(3600),
+        };
+        let fs = WriteCoalescer::new(passthrough, limits);
+        let ctx = Context::default();
+        let name = CString::new("a").unwrap();
+        let path = source.as_path().join("a");
+        let args = CreateIn {
+            flags: (libc::O_RDWR | libc::O_TRUNC) as u32,
+            mode: 0o644,
+            umask: 0,
+            fuse_flags: 0,
+        };
+
+        // Data buffered before opening the file with O_TRUNC doesn't survive the truncation.
+        let (entry, handle, _) = fs.create(&ctx, ROOT_ID, &name, args).unwrap();
+        let handle = handle.unwrap();
+        assert_eq!(write(&fs, &entry, handle, b"stale", 0).unwrap(), 5);
+        let (handle2, _) = fs
+            .open(&ctx, entry.inode, (libc::O_RDWR | libc::O_TRUNC) as u32, 0)
+            .unwrap();
+        fs.release(&ctx, entry.inode, 0, handle, false, false, None)
+            .unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+
+        // So does data buffered before creating the file again.
+        let handle2 = handle2.unwrap();
+        assert_eq!(write(&fs, &entry, handle2, b"stale", 0).unwrap(), 5);
+        let (entry3, handle3, _) = fs.create(&ctx, ROOT_ID, &name, args).unwrap();
+        assert_eq!(entry3.inode, entry.inode);
+        fs.release(&ctx, entry.inode, 0, handle2, false, false, None)
+            .unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+        fs.release(&ctx, entry.inode, 0, handle3.unwrap(), false, false, None)
+            .unwrap();
+    }
+}
@@ -300,6 +300,30 @@ impl<D: AsyncDrive> AsyncUtil<D> {
     }
 }
 
+/// Future returned by [yield_now()], which is pending once so other tasks may run.
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.yielded {
+            Poll::Ready(())
+        } else {
+            self.yielded = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+/// Yield to other tasks of the executor once.
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
 thread_local! {
     static ASYNC_EXECUTOR: RefCell<Option<AsyncDriver>> = RefCell::new(None);
 }
@@ -74,6 +74,8 @@ pub enum Error {
     /// The `size` field of the `SetxattrIn` message does not match the length
     /// of the decoded value.
     InvalidXattrSize((u32, usize)),
+    /// A protocol message is truncated or malformed.
+    InvalidMessage(DecodeError),
 }
 
 impl error::Error for Error {}
@@ -93,6 +95,78 @@ impl fmt::Display for Error {
                  decoded value: size = {}, value.len() = {}",
                 size, len
             ),
+            InvalidMessage(err) => write!(f, "invalid fuse message: {}", err),
+        }
+    }
+}
+
+/// Reasons for rejecting a protocol message while decoding it.
+///
+/// A truncated message usually points to a transport issue, such as a request split over
+/// buffers which are too small, while the other errors point to a buggy or malicious client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The message is shorter than the structures it should hold.
+    Truncated {
+        /// Number of bytes needed to decode the message.
+        needed: usize,
+        /// Number of bytes available.
+        got: usize,
+    },
+    /// A field of the message has an invalid value.
+    InvalidField {
+        /// Name of the field.
+        name: &'static str,
+        /// Value of the field.
+        value: u64,
+    },
+    /// The client speaks an unsupported version of the protocol.
+    UnsupportedVersion {
+        /// Major version of the client.
+        major: u32,
+        /// Minor version of the client.
+        minor: u32,
+    },
+    /// A variable-length part of the message isn't aligned as the protocol requires.
+    BadAlignment {
+        /// Size of the part.
+        size: usize,
+        /// Required alignment.
+        align: usize,
+    },
+}
+
+impl DecodeError {
+    /// Get the error number replied to the client for the error.
+    ///
+    /// Truncated messages are replied with `EIO` and unsupported versions with `EPROTO`, other
+    /// malformed messages are replied with `EINVAL`.
+    pub fn errno(&self) -> i32 {
+        match self {
+            DecodeError::Truncated { .. } => libc::EIO,
+            DecodeError::UnsupportedVersion { .. } => libc::EPROTO,
+            DecodeError::InvalidField { .. } | DecodeError::BadAlignment { .. } => libc::EINVAL,
+        }
+    }
+}
+
+impl error::Error for DecodeError {}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Truncated { needed, got } => {
+                write!(f, "truncated message: needed {} bytes, got {}", needed, got)
+            }
+            DecodeError::InvalidField { name, value } => {
+                write!(f, "invalid value {:#x} of field `{}`", value, name)
+            }
+            DecodeError::UnsupportedVersion { major, minor } => {
+                write!(f, "unsupported protocol version {}.{}", major, minor)
+            }
+            DecodeError::BadAlignment { size, align } => {
+                write!(f, "size {} is not aligned to {} bytes", size, align)
+            }
         }
     }
 }
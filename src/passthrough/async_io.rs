@@ -12,11 +12,12 @@ use crate::api::filesystem::{
     AsyncFileSystem, AsyncZeroCopyReader, AsyncZeroCopyWriter, Context, FileSystem,
 };
 use crate::api::CreateIn;
-use crate::async_util::{AsyncDrive, AsyncUtil};
+use crate::async_util::{self, AsyncDrive, AsyncUtil};
 
 impl<D: AsyncDrive + Sync> BackendFileSystem<D> for PassthroughFs<D> {
     fn mount(&self) -> io::Result<(Entry, u64)> {
-        let entry = self.do_lookup(fuse::ROOT_ID, &CString::new(".").unwrap())?;
+        let cfg = self.cfg.load();
+        let entry = self.do_lookup(&cfg, fuse::ROOT_ID, &CString::new(".").unwrap())?;
         Ok((entry, VFS_MAX_INO))
     }
 
@@ -80,6 +81,7 @@ impl<D: AsyncDrive> PassthroughFs<D> {
     /// Create a File or File Handle for `name` under directory `dir_fd` to support `lookup()`.
     async fn async_open_file_or_handle<F>(
         &self,
+        cfg: &Config,
         ctx: &Context,
         dir_fd: RawFd,
         name: &CStr,
@@ -88,7 +90,7 @@ impl<D: AsyncDrive> PassthroughFs<D> {
     where
         F: FnOnce(RawFd, libc::c_int, u32) -> io::Result<File>,
     {
-        let handle = if self.cfg.inode_file_handles {
+        let handle = if cfg.inode_file_handles {
             FileHandle::from_name_at_with_mount_fds(dir_fd, name, &self.mount_fds, reopen_dir)
         } else {
             Err(io::Error::from_raw_os_error(libc::ENOTSUP))
@@ -143,38 +145,27 @@ impl<D: AsyncDrive> PassthroughFs<D> {
 
     async fn async_open_inode(
         &self,
+        cfg: &Config,
         ctx: &Context,
         inode: Inode,
-        mut flags: i32,
+        flags: i32,
     ) -> io::Result<File> {
-        // When writeback caching is enabled, the kernel may send read requests even if the
-        // userspace program opened the file write-only. So we need to ensure that we have opened
-        // the file for reading as well as writing.
-        let writeback = self.writeback.load(Ordering::Relaxed);
-        if writeback && flags & libc::O_ACCMODE == libc::O_WRONLY {
-            flags &= !libc::O_ACCMODE;
-            flags |= libc::O_RDWR;
-        }
-
-        // When writeback caching is enabled the kernel is responsible for handling `O_APPEND`.
-        // However, this breaks atomicity as the file may have changed on disk, invalidating the
-        // cached copy of the data in the kernel and the offset that the kernel thinks is the end of
-        // the file. Just allow this for now as it is the user's responsibility to enable writeback
-        // caching only for directories that are not shared. It also means that we need to clear the
-        // `O_APPEND` flag.
-        if writeback && flags & libc::O_APPEND != 0 {
-            flags &= !libc::O_APPEND;
-        }
-
         let data = self.inode_map.get(inode)?;
+        self.check_open_mode(cfg, data.mode, flags)?;
+        let flags = self.backing_open_flags(flags & !FMODE_EXEC);
         let file = data.async_get_file(&self.mount_fds).await?;
 
-        self.async_open_proc_file(ctx, file.as_raw_fd(), flags, data.mode)
-            .await
+        let file = self
+            .async_open_proc_file(ctx, file.as_raw_fd(), flags, data.mode)
+            .await?;
+        self.check_verity(cfg, &file, data.mode)?;
+
+        Ok(file)
     }
 
     async fn async_do_open(
         &self,
+        cfg: &Config,
         ctx: &Context,
         inode: Inode,
         flags: u32,
@@ -187,7 +178,16 @@ impl<D: AsyncDrive> PassthroughFs<D> {
         } else {
             None
         };
-        let file = self.async_open_inode(ctx, inode, flags as i32).await?;
+        let direct = flags & (libc::O_DIRECT as u32) != 0;
+        let file = match self.async_open_inode(cfg, ctx, inode, flags as i32).await {
+            // The backing file system doesn't support direct I/O, use buffered I/O on the host
+            // while the guest still bypasses its page cache.
+            Err(e) if direct && e.raw_os_error() == Some(libc::EINVAL) => {
+                self.async_open_inode(cfg, ctx, inode, flags as i32 & !libc::O_DIRECT)
+                    .await?
+            }
+            res => res?,
+        };
         drop(killpriv);
 
         let data = HandleData::new(inode, file);
@@ -195,7 +195,7 @@ impl<D: AsyncDrive> PassthroughFs<D> {
         let mut opts = OpenOptions::empty();
 
         self.handle_map.insert(handle, data);
-        match self.cfg.cache_policy {
+        match cfg.cache_policy {
             // We only set the direct I/O option on files.
             CachePolicy::Never => opts.set(
                 OpenOptions::DIRECT_IO,
@@ -204,12 +204,23 @@ impl<D: AsyncDrive> PassthroughFs<D> {
             CachePolicy::Always => opts |= OpenOptions::KEEP_CACHE,
             _ => {}
         };
+        if direct {
+            opts.remove(OpenOptions::KEEP_CACHE);
+            opts |= OpenOptions::DIRECT_IO;
+        }
+        if self.no_flush.load(Ordering::Relaxed)
+            && flags & (libc::O_DIRECTORY as u32) == 0
+            && flags & (libc::O_ACCMODE as u32) == libc::O_RDONLY as u32
+        {
+            opts |= OpenOptions::NOFLUSH;
+        }
 
         Ok((Some(handle), opts))
     }
 
     async fn async_do_getattr(
         &self,
+        cfg: &Config,
         ctx: &Context,
         inode: Inode,
         handle: Option<<Self as FileSystem>::Handle>,
@@ -247,7 +258,9 @@ impl<D: AsyncDrive> PassthroughFs<D> {
             e
         })?;
 
-        Ok((st, self.cfg.attr_timeout))
+        let mut st = self.guest_stat(cfg, st);
+        st.st_blksize = self.guest_blksize(cfg, inode, st.st_blksize as u32) as _;
+        Ok((st, cfg.attr_timeout))
     }
 
     async fn async_stat(
@@ -289,6 +302,7 @@ impl<D: AsyncDrive> PassthroughFs<D> {
 
     async fn async_get_data(
         &self,
+        cfg: &Config,
         ctx: &Context,
         handle: Handle,
         inode: Inode,
@@ -298,7 +312,7 @@ impl<D: AsyncDrive> PassthroughFs<D> {
         if !no_open {
             self.handle_map.get(handle, inode)
         } else {
-            let file = self.async_open_inode(ctx, inode, flags as i32).await?;
+            let file = self.async_open_inode(cfg, ctx, inode, flags as i32).await?;
             Ok(Arc::new(HandleData::new(inode, file)))
         }
     }
@@ -312,6 +326,7 @@ impl<D: AsyncDrive + Sync> AsyncFileSystem<D> for PassthroughFs<D> {
         parent: <Self as FileSystem>::Inode,
         name: &CStr,
     ) -> io::Result<Entry> {
+        let cfg = self.cfg.load_full();
         // Don't use is_safe_path_component(), allow "." and ".." for NFS export support
         if name.to_bytes_with_nul().contains(&SLASH_ASCII) {
             return Err(io::Error::from_raw_os_error(libc::EINVAL));
@@ -320,13 +335,13 @@ impl<D: AsyncDrive + Sync> AsyncFileSystem<D> for PassthroughFs<D> {
         let dir = self.inode_map.get(parent)?;
         let dir_file = dir.async_get_file(&self.mount_fds).await?;
         let (file_or_handle, st, ids_altkey, handle_altkey) = self
-            .async_open_file_or_handle(&ctx, dir_file.as_raw_fd(), name, |fd, flags, mode| {
+            .async_open_file_or_handle(&cfg, &ctx, dir_file.as_raw_fd(), name, |fd, flags, mode| {
                 Self::open_proc_file(&self.proc_self_fd, fd, flags, mode)
             })
             .await?;
 
         let mut attr_flags: u32 = 0;
-        if let Some(dax_file_size) = self.cfg.dax_file_size {
+        if let Some(dax_file_size) = cfg.dax_file_size {
             // st.stat.st_size is i64
             if self.perfile_dax.load(Ordering::Relaxed)
                 && st.stat.st_size >= 0x0
@@ -357,15 +372,15 @@ impl<D: AsyncDrive + Sync> AsyncFileSystem<D> for PassthroughFs<D> {
                         .compare_exchange(curr, new, Ordering::AcqRel, Ordering::Acquire)
                         .is_ok()
                     {
-                        found = Some(data.inode);
+                        found = Some((data.inode, data.generation));
                         break;
                     }
                 }
             }
         }
 
-        let inode = if let Some(v) = found {
-            v
+        let (inode, generation, created) = if let Some((inode, generation)) = found {
+            (inode, generation, false)
         } else {
             // Write guard get_alt_locked() and insert_lock() to avoid race conditions.
             let mut inodes = self.inode_map.get_map_mut();
@@ -382,7 +397,7 @@ impl<D: AsyncDrive + Sync> AsyncFileSystem<D> for PassthroughFs<D> {
                         ids_altkey
                     );
                     data.refcount.fetch_add(1, Ordering::Relaxed);
-                    data.inode
+                    (data.inode, data.generation, false)
                 }
                 None => {
                     let inode = self.next_inode.fetch_add(1, Ordering::Relaxed);
@@ -400,25 +415,41 @@ impl<D: AsyncDrive + Sync> AsyncFileSystem<D> for PassthroughFs<D> {
                         handle_altkey
                     );
 
+                    let generation = self
+                        .inode_map
+                        .next_generation_locked(&ids_altkey, handle_altkey.as_ref());
                     InodeMap::insert_locked(
                         inodes.deref_mut(),
                         inode,
-                        InodeData::new(inode, file_or_handle, 1, ids_altkey, st.get_stat().st_mode),
+                        InodeData::new(
+                            inode,
+                            file_or_handle,
+                            1,
+                            ids_altkey,
+                            st.get_stat().st_mode,
+                            generation,
+                        ),
                         ids_altkey,
                         handle_altkey,
                     );
-                    inode
+                    (inode, generation, true)
                 }
             }
         };
 
+        if created {
+            if let Some(hooks) = cfg.inode_hooks.as_ref() {
+                hooks.on_create(inode, &st.get_stat());
+            }
+        }
+
         Ok(Entry {
             inode,
-            generation: 0,
-            attr: st.get_stat(),
+            generation,
+            attr: self.guest_stat(&cfg, st.get_stat()),
             attr_flags,
-            attr_timeout: self.cfg.attr_timeout,
-            entry_timeout: self.cfg.entry_timeout,
+            attr_timeout: cfg.attr_timeout,
+            entry_timeout: cfg.entry_timeout,
         })
     }
 
@@ -428,7 +459,8 @@ impl<D: AsyncDrive + Sync> AsyncFileSystem<D> for PassthroughFs<D> {
         inode: <Self as FileSystem>::Inode,
         handle: Option<<Self as FileSystem>::Handle>,
     ) -> io::Result<(libc::stat64, Duration)> {
-        self.async_do_getattr(&ctx, inode, handle).await
+        let cfg = self.cfg.load_full();
+        self.async_do_getattr(&cfg, &ctx, inode, handle).await
     }
 
     async fn async_setattr(
@@ -439,6 +471,8 @@ impl<D: AsyncDrive + Sync> AsyncFileSystem<D> for PassthroughFs<D> {
         handle: Option<<Self as FileSystem>::Handle>,
         valid: SetattrValid,
     ) -> io::Result<(libc::stat64, Duration)> {
+        let cfg = self.cfg.load_full();
+        cfg.write_policy.check_setattr(valid)?;
         enum Data {
             Handle(Arc<HandleData>, RawFd),
             ProcPath(CString),
@@ -463,7 +497,8 @@ impl<D: AsyncDrive + Sync> AsyncFileSystem<D> for PassthroughFs<D> {
             }
         };
 
-        if valid.contains(SetattrValid::MODE) {
+        // Mode changes of symlinks are ignored, see the sync version.
+        if valid.contains(SetattrValid::MODE) && inode_data.mode & libc::S_IFMT != libc::S_IFLNK {
             // Safe because this doesn't modify any memory and we check the return value.
             let res = unsafe {
                 match data {
@@ -479,14 +514,15 @@ impl<D: AsyncDrive + Sync> AsyncFileSystem<D> for PassthroughFs<D> {
         }
 
         if valid.intersects(SetattrValid::UID | SetattrValid::GID) {
+            let ids = cfg.id_offset;
             let uid = if valid.contains(SetattrValid::UID) {
-                attr.st_uid
+                ids.map_or(attr.st_uid, |ids| ids.host_uid(attr.st_uid))
             } else {
                 // Cannot use -1 here because these are unsigned values.
                 ::std::u32::MAX
             };
             let gid = if valid.contains(SetattrValid::GID) {
-                attr.st_gid
+                ids.map_or(attr.st_gid, |ids| ids.host_gid(attr.st_gid))
             } else {
                 // Cannot use -1 here because these are unsigned values.
                 ::std::u32::MAX
@@ -526,7 +562,7 @@ impl<D: AsyncDrive + Sync> AsyncFileSystem<D> for PassthroughFs<D> {
                 Data::ProcPath(_) => {
                     // There is no `ftruncateat` so we need to get a new fd and truncate it.
                     let f = self
-                        .async_open_inode(&ctx, inode, libc::O_NONBLOCK | libc::O_RDWR)
+                        .async_open_inode(&cfg, &ctx, inode, libc::O_NONBLOCK | libc::O_RDWR)
                         .await?;
                     unsafe { libc::ftruncate(f.as_raw_fd(), attr.st_size) }
                 }
@@ -536,7 +572,14 @@ impl<D: AsyncDrive + Sync> AsyncFileSystem<D> for PassthroughFs<D> {
             }
         }
 
-        if valid.intersects(SetattrValid::ATIME | SetattrValid::MTIME) {
+        // Timestamps not being set are passed as `UTIME_OMIT`, so the nanoseconds of the others
+        // are kept as is.
+        if valid.intersects(
+            SetattrValid::ATIME
+                | SetattrValid::MTIME
+                | SetattrValid::ATIME_NOW
+                | SetattrValid::MTIME_NOW,
+        ) {
             let mut tvs = [
                 libc::timespec {
                     tv_sec: 0,
@@ -574,7 +617,7 @@ impl<D: AsyncDrive + Sync> AsyncFileSystem<D> for PassthroughFs<D> {
             }
         }
 
-        self.async_do_getattr(&ctx, inode, handle).await
+        self.async_do_getattr(&cfg, &ctx, inode, handle).await
     }
 
     async fn async_open(
@@ -584,11 +627,14 @@ impl<D: AsyncDrive + Sync> AsyncFileSystem<D> for PassthroughFs<D> {
         flags: u32,
         fuse_flags: u32,
     ) -> io::Result<(Option<<Self as FileSystem>::Handle>, OpenOptions)> {
+        let cfg = self.cfg.load_full();
+        cfg.write_policy.check_open(flags)?;
         if self.no_open.load(Ordering::Relaxed) {
             info!("fuse: open is not supported.");
             Err(io::Error::from_raw_os_error(libc::ENOSYS))
         } else {
-            self.async_do_open(&ctx, inode, flags, fuse_flags).await
+            self.async_do_open(&cfg, &ctx, inode, flags, fuse_flags)
+                .await
         }
     }
 
@@ -599,13 +645,22 @@ impl<D: AsyncDrive + Sync> AsyncFileSystem<D> for PassthroughFs<D> {
         name: &CStr,
         args: CreateIn,
     ) -> io::Result<(Entry, Option<<Self as FileSystem>::Handle>, OpenOptions)> {
-        self.validate_path_component(name)?;
+        let cfg = self.cfg.load_full();
+        cfg.write_policy.check_namespace()?;
+        self.validate_path_component(&cfg, name)?;
 
         let dir = self.inode_map.get(parent)?;
         let dir_file = dir.async_get_file(&self.mount_fds).await?;
 
-        let new_file = {
-            let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
+        // Files created now can't have fs-verity enabled, so with `require_verity` only existing
+        // files may be opened by create requests, which `async_open_inode()` checks.
+        let new_file = if cfg.require_verity {
+            if args.flags as i32 & libc::O_EXCL != 0 {
+                return Err(io::Error::from_raw_os_error(libc::EPERM));
+            }
+            None
+        } else {
+            let (_uid, _gid) = self.set_creds(&cfg, &ctx)?;
 
             Self::create_file_excl(
                 dir_file.as_raw_fd(),
@@ -615,7 +670,12 @@ impl<D: AsyncDrive + Sync> AsyncFileSystem<D> for PassthroughFs<D> {
             )?
         };
 
-        let entry = self.async_lookup(ctx, parent, name).await?;
+        let entry = match self.async_lookup(ctx, parent, name).await {
+            Err(e) if cfg.require_verity && e.raw_os_error() == Some(libc::ENOENT) => {
+                return Err(io::Error::from_raw_os_error(libc::EPERM));
+            }
+            res => res?,
+        };
         let file = match new_file {
             // File didn't exist, now created by create_file_excl()
             Some(f) => f,
@@ -631,8 +691,8 @@ impl<D: AsyncDrive + Sync> AsyncFileSystem<D> for PassthroughFs<D> {
                     None
                 };
 
-                let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
-                self.async_open_inode(ctx, entry.inode, args.flags as i32)
+                let (_uid, _gid) = self.set_creds(&cfg, &ctx)?;
+                self.async_open_inode(&cfg, ctx, entry.inode, args.flags as i32)
                     .await?
             }
         };
@@ -648,7 +708,7 @@ impl<D: AsyncDrive + Sync> AsyncFileSystem<D> for PassthroughFs<D> {
         };
 
         let mut opts = OpenOptions::empty();
-        match self.cfg.cache_policy {
+        match cfg.cache_policy {
             CachePolicy::Never => opts |= OpenOptions::DIRECT_IO,
             CachePolicy::Always => opts |= OpenOptions::KEEP_CACHE,
             _ => {}
@@ -666,18 +726,60 @@ impl<D: AsyncDrive + Sync> AsyncFileSystem<D> for PassthroughFs<D> {
         w: &mut (dyn AsyncZeroCopyWriter<D> + Send),
         size: u32,
         offset: u64,
-        _lock_owner: Option<u64>,
+        lock_owner: Option<u64>,
         _flags: u32,
     ) -> io::Result<usize> {
+        let cfg = self.cfg.load_full();
         let data = self
-            .async_get_data(&ctx, handle, inode, libc::O_RDONLY)
+            .async_get_data(&cfg, &ctx, handle, inode, libc::O_RDONLY)
             .await?;
+        self.check_mandatory_lock(
+            inode,
+            data.get_handle_raw_fd(),
+            lock_owner,
+            offset,
+            size,
+            false,
+        )?;
         let drive = ctx
             .get_drive::<D>()
             .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
 
-        w.async_write_from(drive, data.get_handle_raw_fd(), size as usize, offset)
-            .await
+        let size = size as usize;
+        let interval = cfg.io_yield_interval;
+        if interval == 0 || size <= interval {
+            return w
+                .async_write_from(drive, data.get_handle_raw_fd(), size, offset)
+                .await;
+        }
+
+        // Yield between the chunks of large reads so other requests may be served meanwhile.
+        let mut done = 0;
+        while done < size {
+            if done > 0 {
+                async_util::yield_now().await;
+            }
+            let len = std::cmp::min(interval, size - done);
+            let n = match w
+                .async_write_from(
+                    drive.clone(),
+                    data.get_handle_raw_fd(),
+                    len,
+                    offset + done as u64,
+                )
+                .await
+            {
+                Ok(n) => n,
+                Err(e) if done == 0 => return Err(e),
+                Err(_) => break,
+            };
+            done += n;
+            if n < len {
+                break;
+            }
+        }
+
+        Ok(done)
     }
 
     #[allow(clippy::too_many_arguments)]
@@ -689,14 +791,24 @@ impl<D: AsyncDrive + Sync> AsyncFileSystem<D> for PassthroughFs<D> {
         r: &mut (dyn AsyncZeroCopyReader<D> + Send),
         size: u32,
         offset: u64,
-        _lock_owner: Option<u64>,
+        lock_owner: Option<u64>,
         _delayed_write: bool,
         _flags: u32,
         fuse_flags: u32,
     ) -> io::Result<usize> {
+        let cfg = self.cfg.load_full();
+        cfg.write_policy.check_data()?;
         let data = self
-            .async_get_data(&ctx, handle, inode, libc::O_RDWR)
+            .async_get_data(&cfg, &ctx, handle, inode, libc::O_RDWR)
             .await?;
+        self.check_mandatory_lock(
+            inode,
+            data.get_handle_raw_fd(),
+            lock_owner,
+            offset,
+            size,
+            true,
+        )?;
 
         // Fallback to sync io if KILLPRIV_V2 is enabled to work around a limitation of io_uring.
         if self.killpriv_v2.load(Ordering::Relaxed) && (fuse_flags & WRITE_KILL_PRIV != 0) {
@@ -714,8 +826,40 @@ impl<D: AsyncDrive + Sync> AsyncFileSystem<D> for PassthroughFs<D> {
                 .get_drive::<D>()
                 .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
 
-            r.async_read_to(drive, data.get_handle_raw_fd(), size as usize, offset)
-                .await
+            let size = size as usize;
+            let interval = cfg.io_yield_interval;
+            if interval == 0 || size <= interval {
+                return r
+                    .async_read_to(drive, data.get_handle_raw_fd(), size, offset)
+                    .await;
+            }
+
+            let mut done = 0;
+            while done < size {
+                if done > 0 {
+                    async_util::yield_now().await;
+                }
+                let len = std::cmp::min(interval, size - done);
+                let n = match r
+                    .async_read_to(
+                        drive.clone(),
+                        data.get_handle_raw_fd(),
+                        len,
+                        offset + done as u64,
+                    )
+                    .await
+                {
+                    Ok(n) => n,
+                    Err(e) if done == 0 => return Err(e),
+                    Err(_) => break,
+                };
+                done += n;
+                if n < len {
+                    break;
+                }
+            }
+
+            Ok(done)
         }
     }
 
@@ -726,8 +870,9 @@ impl<D: AsyncDrive + Sync> AsyncFileSystem<D> for PassthroughFs<D> {
         datasync: bool,
         handle: <Self as FileSystem>::Handle,
     ) -> io::Result<()> {
+        let cfg = self.cfg.load_full();
         let data = self
-            .async_get_data(&ctx, handle, inode, libc::O_RDONLY)
+            .async_get_data(&cfg, &ctx, handle, inode, libc::O_RDONLY)
             .await?;
         let drive = ctx
             .get_drive::<D>()
@@ -745,9 +890,11 @@ impl<D: AsyncDrive + Sync> AsyncFileSystem<D> for PassthroughFs<D> {
         offset: u64,
         length: u64,
     ) -> io::Result<()> {
+        let cfg = self.cfg.load_full();
+        cfg.write_policy.check_data()?;
         // Let the Arc<HandleData> in scope, otherwise fd may get invalid.
         let data = self
-            .async_get_data(&ctx, handle, inode, libc::O_RDWR)
+            .async_get_data(&cfg, &ctx, handle, inode, libc::O_RDWR)
             .await?;
         let drive = ctx
             .get_drive::<D>()
@@ -763,6 +910,12 @@ impl<D: AsyncDrive + Sync> AsyncFileSystem<D> for PassthroughFs<D> {
         datasync: bool,
         handle: <Self as FileSystem>::Handle,
     ) -> io::Result<()> {
-        self.async_fsync(ctx, inode, datasync, handle).await
+        let cfg = self.cfg.load_full();
+        let data = self.get_dirdata(&cfg, handle, inode, libc::O_RDONLY)?;
+        let drive = ctx
+            .get_drive::<D>()
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
+
+        AsyncUtil::fsync(drive, data.get_handle_raw_fd(), datasync).await
     }
 }
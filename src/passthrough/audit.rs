@@ -0,0 +1,139 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Auditing of the content served by passthrough.
+//!
+//! Each read request of an audited file produces one [AuditRecord], with the digest of exactly the
+//! bytes put into the reply. Reads are not merged or deduplicated: overlapping or repeated reads
+//! of the same range produce one record each, so the records describe what has been served and
+//! how many times, not which parts of a file have been served. A digest is computed from the
+//! reply buffer once the data has been read into it, so it matches the data sent to the client
+//! even if the backing file is modified concurrently.
+//!
+//! Only `read` requests are audited. Data served from the page cache of the client, or mapped by
+//! DAX, never reaches passthrough, so a cache policy of `CachePolicy::Never` is needed for the
+//! records to cover every access. A record is reported before the reply is sent, so it may cover
+//! a reply the client never got, e.g. because the request has been interrupted.
+
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use super::Inode;
+use crate::transport::{FileReadWriteVolatile, FileVolatileSlice};
+
+/// A hasher computing the digest of data served to the client.
+pub trait AuditHasher {
+    /// Feed the next bytes of the data.
+    fn update(&mut self, data: &[u8]);
+
+    /// Get the digest of all data fed so far.
+    fn finish(self: Box<Self>) -> Vec<u8>;
+}
+
+/// The digest of data served by a read request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuditRecord {
+    /// Inode of the file.
+    pub inode: Inode,
+    /// Offset of the data in the file.
+    pub offset: u64,
+    /// Number of bytes served, which may be less than requested at the end of the file.
+    pub len: usize,
+    /// Digest computed by the hasher of the sink.
+    pub digest: Vec<u8>,
+}
+
+/// Receiver of the [AuditRecord]s of reads from audited files.
+pub trait AuditSink: Send + Sync {
+    /// Whether reads of the file at `path`, relative to the root directory, are audited. It's
+    /// checked on the first read of each open file.
+    fn audited(&self, path: &Path) -> bool;
+
+    /// Create a hasher for the data served by a read request.
+    fn hasher(&self) -> Box<dyn AuditHasher>;
+
+    /// Record the digest of data served by a read request.
+    fn record(&self, record: AuditRecord);
+}
+
+impl fmt::Debug for dyn AuditSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AuditSink({:p})", self)
+    }
+}
+
+// Sinks are compared by identity.
+impl PartialEq for dyn AuditSink {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(
+            self as *const Self as *const u8,
+            other as *const Self as *const u8,
+        )
+    }
+}
+
+/// A backing file feeding the data read from it to a hasher, straight from the buffers it has
+/// been read into.
+pub(super) struct AuditedFile<'a> {
+    file: &'a mut File,
+    hasher: &'a mut Option<Box<dyn AuditHasher>>,
+}
+
+impl<'a> AuditedFile<'a> {
+    pub fn new(file: &'a mut File, hasher: &'a mut Option<Box<dyn AuditHasher>>) -> Self {
+        AuditedFile { file, hasher }
+    }
+
+    fn hash(&mut self, bufs: &[FileVolatileSlice], mut len: usize) {
+        let hasher = match self.hasher.as_mut() {
+            Some(hasher) => hasher,
+            None => return,
+        };
+        for buf in bufs {
+            if len == 0 {
+                break;
+            }
+            let n = std::cmp::min(len, buf.len());
+            // Safe because the first `len` bytes of the buffers have just been filled by the
+            // read, and the buffers outlive this call.
+            hasher.update(unsafe { std::slice::from_raw_parts(buf.as_ptr(), n) });
+            len -= n;
+        }
+    }
+}
+
+impl FileReadWriteVolatile for AuditedFile<'_> {
+    fn read_volatile(&mut self, slice: FileVolatileSlice) -> io::Result<usize> {
+        self.read_vectored_volatile(&[slice])
+    }
+
+    fn read_vectored_volatile(&mut self, bufs: &[FileVolatileSlice]) -> io::Result<usize> {
+        let n = self.file.read_vectored_volatile(bufs)?;
+        self.hash(bufs, n);
+        Ok(n)
+    }
+
+    fn write_volatile(&mut self, slice: FileVolatileSlice) -> io::Result<usize> {
+        self.file.write_volatile(slice)
+    }
+
+    fn read_at_volatile(&mut self, slice: FileVolatileSlice, offset: u64) -> io::Result<usize> {
+        self.read_vectored_at_volatile(&[slice], offset)
+    }
+
+    fn read_vectored_at_volatile(
+        &mut self,
+        bufs: &[FileVolatileSlice],
+        offset: u64,
+    ) -> io::Result<usize> {
+        let n = self.file.read_vectored_at_volatile(bufs, offset)?;
+        self.hash(bufs, n);
+        Ok(n)
+    }
+
+    fn write_at_volatile(&mut self, slice: FileVolatileSlice, offset: u64) -> io::Result<usize> {
+        self.file.write_at_volatile(slice, offset)
+    }
+}
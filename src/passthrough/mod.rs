@@ -12,8 +12,9 @@
 //! with heavy modification/enhancements from Alibaba Cloud OS team.
 
 use std::any::Any;
-use std::collections::{btree_map, BTreeMap};
+use std::collections::{btree_map, BTreeMap, HashMap, VecDeque};
 use std::ffi::{CStr, CString, OsString};
+use std::fmt;
 use std::fs::File;
 use std::io;
 use std::marker::PhantomData;
@@ -21,16 +22,17 @@ use std::mem::MaybeUninit;
 use std::ops::{Deref, DerefMut};
 use std::os::unix::ffi::OsStringExt;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 use std::str::FromStr;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockWriteGuard};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockWriteGuard, Weak};
 use std::time::Duration;
 
+use arc_swap::ArcSwap;
 use vm_memory::ByteValued;
 
 use crate::abi::fuse_abi as fuse;
-use crate::api::filesystem::Entry;
+use crate::api::filesystem::{Context, DirEntry, Entry};
 use crate::api::{
     validate_path_component, BackendFileSystem, CURRENT_DIR_CSTR, EMPTY_CSTR, PARENT_DIR_CSTR,
     PROC_SELF_FD_CSTR, SLASH_ASCII, VFS_MAX_INO,
@@ -39,19 +41,100 @@ use crate::BitmapSlice;
 
 #[cfg(feature = "async-io")]
 mod async_io;
+mod audit;
 mod file_handle;
 mod multikey;
+mod posix_locks;
 mod sync_io;
+mod synthetic;
 
+use audit::AuditedFile;
+pub use audit::{AuditHasher, AuditRecord, AuditSink};
 use file_handle::{FileHandle, MountFds};
 use multikey::MultikeyBTreeMap;
+use posix_locks::PosixLocks;
+pub use synthetic::SyntheticFile;
+use synthetic::{SyntheticFiles, SYNTHETIC_OFFSET};
 
+#[cfg(feature = "control-socket")]
+use crate::api::control::{HandleInfo, InodeInfo, Introspect};
 use crate::async_util::{AsyncDrive, AsyncDriver};
 
 type Inode = u64;
 type Handle = u64;
 type MultiKeyMap = MultikeyBTreeMap<Inode, InodeAltKey, Arc<InodeData>>;
 
+// Maximum number of symlinks followed when resolving a path, following the kernel.
+const MAX_SYMLINK_HOPS: u32 = 40;
+
+// Initial buffer size to read symlink targets, most of which are short.
+const READLINK_INIT_SIZE: usize = 256;
+
+// fs-verity definitions from linux/fsverity.h and linux/fs.h, not exported by libc yet.
+const FS_VERITY_FL: libc::c_int = 0x0010_0000;
+const FS_VERITY_HASH_ALG_SHA256: u32 = 1;
+const FS_VERITY_BLOCK_SIZE: u32 = 4096;
+// The largest digest size of supported hash algorithms, which is SHA512.
+const FS_VERITY_MAX_DIGEST_SIZE: usize = 64;
+// _IOW('f', 133, struct fsverity_enable_arg)
+const FS_IOC_ENABLE_VERITY: libc::c_ulong = 0x4080_6685;
+// _IOWR('f', 134, struct fsverity_digest)
+const FS_IOC_MEASURE_VERITY: libc::c_ulong = 0xc004_6686;
+
+#[repr(C)]
+#[derive(Default)]
+struct FsverityEnableArg {
+    version: u32,
+    hash_algorithm: u32,
+    block_size: u32,
+    salt_size: u32,
+    salt_ptr: u64,
+    sig_size: u32,
+    reserved1: u32,
+    sig_ptr: u64,
+    reserved2: [u64; 11],
+}
+
+#[repr(C)]
+struct FsverityDigest {
+    digest_algorithm: u16,
+    digest_size: u16,
+    digest: [u8; FS_VERITY_MAX_DIGEST_SIZE],
+}
+
+// FIEMAP definitions from linux/fiemap.h and linux/fs.h, not exported by libc yet.
+// _IOWR('f', 11, struct fiemap)
+// Set in the open flags by the kernel when a file is opened for execution.
+const FMODE_EXEC: i32 = 0x20;
+
+const FS_IOC_FIEMAP: libc::c_ulong = 0xc020_660b;
+const FIEMAP_FLAG_SYNC: u32 = 0x1;
+// Number of extents queried by each FS_IOC_FIEMAP call.
+const FIEMAP_BATCH_SIZE: usize = 32;
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct FiemapRawExtent {
+    fe_logical: u64,
+    fe_physical: u64,
+    fe_length: u64,
+    fe_reserved64: [u64; 2],
+    fe_flags: u32,
+    fe_reserved: [u32; 3],
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct FiemapArg {
+    fm_start: u64,
+    fm_length: u64,
+    fm_flags: u32,
+    fm_mapped_extents: u32,
+    fm_extent_count: u32,
+    fm_reserved: u32,
+    fm_extents: [FiemapRawExtent; FIEMAP_BATCH_SIZE],
+}
+
 #[derive(Clone, Copy)]
 struct InodeStat {
     stat: libc::stat64,
@@ -129,6 +212,10 @@ struct InodeData {
     refcount: AtomicU64,
     // File type and mode, not used for now
     mode: u32,
+    // Generation number of the inode, see `InodeMap::next_generation_locked()`.
+    generation: u64,
+    // Backing file serving reads without open requests, kept until the inode is forgotten.
+    read_file: Mutex<Option<Arc<File>>>,
 }
 
 // Returns true if it's safe to open this inode without O_PATH.
@@ -139,13 +226,22 @@ fn is_safe_inode(mode: u32) -> bool {
 }
 
 impl<'a> InodeData {
-    fn new(inode: Inode, f: FileOrHandle, refcount: u64, altkey: InodeAltKey, mode: u32) -> Self {
+    fn new(
+        inode: Inode,
+        f: FileOrHandle,
+        refcount: u64,
+        altkey: InodeAltKey,
+        mode: u32,
+        generation: u64,
+    ) -> Self {
         InodeData {
             inode,
             file_or_handle: f,
             altkey,
             refcount: AtomicU64::new(refcount),
             mode,
+            generation,
+            read_file: Mutex::new(None),
         }
     }
 
@@ -160,21 +256,78 @@ impl<'a> InodeData {
     }
 }
 
+// Maximum number of `InodeAltKey::Ids` keys whose generation number is remembered.
+const MAX_GENERATIONS: usize = 65536;
+
+#[derive(Default)]
+struct Generations {
+    // Last generation number and file handle assigned to each `InodeAltKey::Ids` key.
+    keys: BTreeMap<InodeAltKey, (u64, Option<FileHandle>)>,
+    // Generation number of keys not in `keys`, above any generation number dropped from it.
+    base: u64,
+}
+
 /// Data structures to manage accessed inodes.
 struct InodeMap {
     inodes: RwLock<MultiKeyMap>,
+    generations: Mutex<Generations>,
 }
 
 impl InodeMap {
     fn new() -> Self {
         InodeMap {
             inodes: RwLock::new(MultikeyBTreeMap::new()),
+            generations: Mutex::new(Generations::default()),
         }
     }
 
     fn clear(&self) {
         // Do not expect poisoned lock here, so safe to unwrap().
         self.inodes.write().unwrap().clear();
+        self.generations.lock().unwrap().keys.clear();
+    }
+
+    // Get the generation number for a new inode identified by `ids_altkey`.
+    //
+    // The `(dev, ino)` pair of a deleted file may be reused by the underlying file system for a
+    // new file, so the generation number gets bumped whenever a new inode is allocated for an ids
+    // key seen before, unless the file handles prove it's still the same file. The caller must
+    // hold the write lock of the inode map.
+    //
+    // At most `MAX_GENERATIONS` keys are remembered. Once full, all of them are dropped and keys
+    // seen from then on start above the largest generation number dropped, so a reused
+    // `(dev, ino)` still never gets the generation number of the previous file.
+    fn next_generation_locked(
+        &self,
+        ids_altkey: &InodeAltKey,
+        handle_altkey: Option<&InodeAltKey>,
+    ) -> u64 {
+        let handle = match handle_altkey {
+            Some(InodeAltKey::Handle(h)) => Some(*h),
+            _ => None,
+        };
+        let mut generations = self.generations.lock().unwrap();
+
+        if generations.keys.len() >= MAX_GENERATIONS && !generations.keys.contains_key(ids_altkey) {
+            let last = generations.keys.values().map(|(g, _)| *g).max();
+            generations.base = last.map_or(generations.base, |g| g + 1);
+            generations.keys.clear();
+        }
+
+        let base = generations.base;
+        match generations.keys.get_mut(ids_altkey) {
+            None => {
+                generations.keys.insert(*ids_altkey, (base, handle));
+                base
+            }
+            Some((generation, last)) => {
+                if handle.is_none() || *last != handle {
+                    *generation += 1;
+                    *last = handle;
+                }
+                *generation
+            }
+        }
     }
 
     fn get(&self, inode: Inode) -> io::Result<Arc<InodeData>> {
@@ -253,18 +406,28 @@ impl InodeMap {
     }
 }
 
+// An open backing file. Requests on the handle always go to this file and never resolve the path
+// again, so a file unlinked or renamed over keeps being served to the holders of its handles, as
+// POSIX does for open file descriptions.
 struct HandleData {
     inode: Inode,
-    file: File,
+    file: Arc<File>,
     lock: Mutex<()>,
+    // Whether reads through the handle are audited, from `cfg.audit_hash`, once known.
+    audited: Mutex<Option<bool>>,
 }
 
 impl HandleData {
     fn new(inode: Inode, file: File) -> Self {
+        Self::with_shared_file(inode, Arc::new(file))
+    }
+
+    fn with_shared_file(inode: Inode, file: Arc<File>) -> Self {
         HandleData {
             inode,
             file,
             lock: Mutex::new(()),
+            audited: Mutex::new(None),
         }
     }
 
@@ -330,6 +493,19 @@ impl HandleMap {
     }
 }
 
+// Backing files shared by handles of an inode, see `Config::shared_fd`.
+#[derive(Default)]
+struct SharedFiles {
+    rdonly: Weak<File>,
+    rdwr: Weak<File>,
+}
+
+impl SharedFiles {
+    fn is_empty(&self) -> bool {
+        self.rdonly.strong_count() == 0 && self.rdwr.strong_count() == 0
+    }
+}
+
 #[repr(C, packed)]
 #[derive(Clone, Copy, Debug, Default)]
 struct LinuxDirent64 {
@@ -380,6 +556,338 @@ impl Default for CachePolicy {
     }
 }
 
+/// The readahead policy used to advise the backing file when a file is opened.
+///
+/// FUSE has no per-file readahead hint in the open reply, the kernel side readahead window is
+/// negotiated once by `max_readahead` in INIT. So the policy is applied to the backing file by
+/// `posix_fadvise()`, which controls the readahead behavior of the host page cache.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReadaheadPolicy {
+    /// Don't give any advice, the host kernel uses the default readahead window.
+    Normal,
+
+    /// Data is expected to be accessed sequentially, the host kernel doubles the readahead window.
+    Sequential,
+
+    /// Data is expected to be accessed in random order, which disables readahead. It suits
+    /// database files and other workloads with small random reads.
+    Random,
+}
+
+impl Default for ReadaheadPolicy {
+    fn default() -> Self {
+        ReadaheadPolicy::Normal
+    }
+}
+
+impl ReadaheadPolicy {
+    fn advice(&self) -> libc::c_int {
+        match self {
+            ReadaheadPolicy::Normal => libc::POSIX_FADV_NORMAL,
+            ReadaheadPolicy::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+            ReadaheadPolicy::Random => libc::POSIX_FADV_RANDOM,
+        }
+    }
+}
+
+impl FromStr for ReadaheadPolicy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "normal" | "Normal" | "NORMAL" => Ok(ReadaheadPolicy::Normal),
+            "sequential" | "Sequential" | "SEQUENTIAL" => Ok(ReadaheadPolicy::Sequential),
+            "random" | "Random" | "RANDOM" => Ok(ReadaheadPolicy::Random),
+            _ => Err("invalid readahead policy"),
+        }
+    }
+}
+
+/// Kinds of modifications allowed by the passthrough file system.
+///
+/// Each kind of modification may be allowed or denied independently, requests making a denied
+/// modification fail with `EROFS` before reaching the backing file system. For example, file
+/// contents and the directory tree could be kept immutable while user xattrs are still writable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WritePolicy {
+    /// Modify file contents, by writing, truncating, allocating space or cloning ranges.
+    pub data: bool,
+    /// Change the mode, ownership or timestamps of files.
+    pub metadata: bool,
+    /// Create, remove, link and rename files.
+    pub namespace: bool,
+    /// Set and remove extended attributes.
+    pub xattr: bool,
+}
+
+impl WritePolicy {
+    /// Get a policy denying all kinds of modifications.
+    pub fn read_only() -> Self {
+        WritePolicy {
+            data: false,
+            metadata: false,
+            namespace: false,
+            xattr: false,
+        }
+    }
+
+    fn check(allowed: bool) -> io::Result<()> {
+        if allowed {
+            Ok(())
+        } else {
+            Err(io::Error::from_raw_os_error(libc::EROFS))
+        }
+    }
+
+    fn check_data(&self) -> io::Result<()> {
+        Self::check(self.data)
+    }
+
+    fn check_namespace(&self) -> io::Result<()> {
+        Self::check(self.namespace)
+    }
+
+    fn check_xattr(&self) -> io::Result<()> {
+        Self::check(self.xattr)
+    }
+
+    // Opening with `O_TRUNC` modifies file contents.
+    fn check_open(&self, flags: u32) -> io::Result<()> {
+        Self::check(self.data || flags & libc::O_TRUNC as u32 == 0)
+    }
+
+    fn check_setattr(&self, valid: fuse::SetattrValid) -> io::Result<()> {
+        let metadata = fuse::SetattrValid::MODE
+            | fuse::SetattrValid::UID
+            | fuse::SetattrValid::GID
+            | fuse::SetattrValid::ATIME
+            | fuse::SetattrValid::MTIME
+            | fuse::SetattrValid::ATIME_NOW
+            | fuse::SetattrValid::MTIME_NOW
+            | fuse::SetattrValid::CTIME;
+        Self::check(self.data || !valid.contains(fuse::SetattrValid::SIZE))?;
+        Self::check(self.metadata || !valid.intersects(metadata))
+    }
+}
+
+impl Default for WritePolicy {
+    fn default() -> Self {
+        WritePolicy {
+            data: true,
+            metadata: true,
+            namespace: true,
+            xattr: true,
+        }
+    }
+}
+
+/// How to handle failures to set extended attributes in privileged namespaces, i.e. `security.`,
+/// `system.` and `trusted.`, which an unprivileged daemon or the backing file system may not
+/// support.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum XattrErrorPolicy {
+    /// Fail the request with the error from the backing file system.
+    Fail,
+
+    /// Log the error and reply success, as if the attribute has been set.
+    Ignore,
+
+    /// Store the attribute in the `user.` namespace instead, e.g. `security.capability` is
+    /// stored as `user.security.capability`. Getting or removing the original name falls back to
+    /// the remapped one if the backing file system doesn't have it, and listing reports the
+    /// original name. The client can't access the remapped names directly, otherwise it could
+    /// forge a privileged attribute by setting an unprivileged one.
+    Remap,
+}
+
+impl Default for XattrErrorPolicy {
+    fn default() -> Self {
+        XattrErrorPolicy::Fail
+    }
+}
+
+impl XattrErrorPolicy {
+    // Whether the failure to set xattr `name` with `err` is handled by the policy.
+    fn applies(&self, name: &CStr, err: &io::Error) -> bool {
+        *self != XattrErrorPolicy::Fail
+            && is_privileged_xattr(name.to_bytes())
+            && matches!(
+                err.raw_os_error(),
+                Some(libc::EPERM) | Some(libc::EACCES) | Some(libc::EOPNOTSUPP)
+            )
+    }
+}
+
+impl FromStr for XattrErrorPolicy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" | "Fail" | "FAIL" => Ok(XattrErrorPolicy::Fail),
+            "ignore" | "Ignore" | "IGNORE" => Ok(XattrErrorPolicy::Ignore),
+            "remap" | "Remap" | "REMAP" => Ok(XattrErrorPolicy::Remap),
+            _ => Err("invalid xattr error policy"),
+        }
+    }
+}
+
+fn is_privileged_xattr(name: &[u8]) -> bool {
+    name.starts_with(b"security.") || name.starts_with(b"system.") || name.starts_with(b"trusted.")
+}
+
+// Name of the `user.` xattr storing a remapped privileged xattr.
+fn remapped_xattr_name(name: &CStr) -> CString {
+    let mut remapped = b"user.".to_vec();
+    remapped.extend_from_slice(name.to_bytes());
+    // Safe because `name` doesn't contain a NUL byte.
+    unsafe { CString::from_vec_unchecked(remapped) }
+}
+
+// Get the NUL separated list of all xattr names of `pathname`.
+fn list_all_xattrs(pathname: &CStr) -> io::Result<Vec<u8>> {
+    loop {
+        // Safe because this doesn't modify any memory and we check the return value.
+        let size = unsafe { libc::listxattr(pathname.as_ptr(), std::ptr::null_mut(), 0) };
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buf = vec![0u8; size as usize];
+        // Safe because this will only modify the contents of `buf`.
+        let res = unsafe {
+            libc::listxattr(
+                pathname.as_ptr(),
+                buf.as_mut_ptr() as *mut libc::c_char,
+                buf.len(),
+            )
+        };
+        if res >= 0 {
+            buf.truncate(res as usize);
+            return Ok(buf);
+        }
+        // Retry if an xattr has been added in between.
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::ERANGE) {
+            return Err(e);
+        }
+    }
+}
+
+// Whether `name` is the `user.` xattr storing a remapped privileged xattr.
+fn is_remapped_xattr(name: &[u8]) -> bool {
+    name.strip_prefix(b"user.")
+        .map_or(false, is_privileged_xattr)
+}
+
+// Convert a NUL separated list of xattr names from the backing file system to the names seen by
+// the client, by dropping the `user.` prefix of remapped xattrs. A name stored both ways is only
+// reported once.
+fn unmap_xattr_list(list: &[u8]) -> Vec<u8> {
+    let mut names: Vec<&[u8]> = Vec::new();
+    for name in list.split(|&b| b == 0).filter(|n| !n.is_empty()) {
+        let name = if is_remapped_xattr(name) {
+            &name[b"user.".len()..]
+        } else {
+            name
+        };
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+
+    let mut out = Vec::with_capacity(list.len());
+    for name in names {
+        out.extend_from_slice(name);
+        out.push(0);
+    }
+    out
+}
+
+// The overflow uid/gid reported for ids without a mapping, as the kernel does.
+const NOBODY_ID: u32 = 65534;
+
+/// Shift of user and group ids between the FUSE client and the backing file system, like an
+/// idmapped mount.
+///
+/// Ids `0..range` of the client map to `uid_base..uid_base + range` and
+/// `gid_base..gid_base + range` on the host, so the root of the client may own files as an
+/// unprivileged host user. Ids outside of the range map to nobody (65534) in both directions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdOffset {
+    /// The host uid the uid 0 of the client maps to.
+    pub uid_base: u32,
+    /// The host gid the gid 0 of the client maps to.
+    pub gid_base: u32,
+    /// Number of ids mapped.
+    pub range: u32,
+}
+
+impl IdOffset {
+    fn to_host(id: u32, base: u32, range: u32) -> u32 {
+        if id < range {
+            base.checked_add(id).unwrap_or(NOBODY_ID)
+        } else {
+            NOBODY_ID
+        }
+    }
+
+    fn to_guest(id: u32, base: u32, range: u32) -> u32 {
+        match id.checked_sub(base) {
+            Some(id) if id < range => id,
+            _ => NOBODY_ID,
+        }
+    }
+
+    /// Map a uid of the client to the host.
+    pub fn host_uid(&self, uid: u32) -> u32 {
+        Self::to_host(uid, self.uid_base, self.range)
+    }
+
+    /// Map a gid of the client to the host.
+    pub fn host_gid(&self, gid: u32) -> u32 {
+        Self::to_host(gid, self.gid_base, self.range)
+    }
+
+    /// Map a host uid to the client.
+    pub fn guest_uid(&self, uid: u32) -> u32 {
+        Self::to_guest(uid, self.uid_base, self.range)
+    }
+
+    /// Map a host gid to the client.
+    pub fn guest_gid(&self, gid: u32) -> u32 {
+        Self::to_guest(gid, self.gid_base, self.range)
+    }
+}
+
+/// Observer of the lifecycle of inodes of the passthrough file system.
+///
+/// Callbacks are invoked without holding the lock of the inode map, so they may call back into
+/// the file system.
+pub trait InodeObserver: Send + Sync {
+    /// Invoked when a new inode has been added to the inode map, with the attributes of the
+    /// backing file.
+    fn on_create(&self, inode: Inode, st: &libc::stat64);
+
+    /// Invoked when an inode has been removed from the inode map because its lookup count
+    /// dropped to zero, `nlookup` is the count forgotten by the last request.
+    fn on_forget(&self, inode: Inode, nlookup: u64);
+}
+
+impl fmt::Debug for dyn InodeObserver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "InodeObserver({:p})", self)
+    }
+}
+
+// Observers are compared by identity.
+impl PartialEq for dyn InodeObserver {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(
+            self as *const Self as *const u8,
+            other as *const Self as *const u8,
+        )
+    }
+}
+
 /// Options that configure the behavior of the passthrough fuse file system.
 #[derive(Debug, Clone, PartialEq)]
 pub struct Config {
@@ -413,6 +921,9 @@ pub struct Config {
     /// system has exclusive access to the directory and 2) the file system has read permissions for
     /// all files in that directory.
     ///
+    /// With writeback caching, the FUSE client computes the offsets of `O_APPEND` writes from its
+    /// cached file size, so appends are only atomic among the users of the same FUSE client.
+    ///
     /// The default value for this option is `false`.
     pub writeback: bool,
 
@@ -436,6 +947,11 @@ pub struct Config {
 
     /// Control whether no_open is allowed.
     ///
+    /// Once `FsOptions::ZERO_MESSAGE_OPEN` is negotiated, the kernel sends requests with a handle
+    /// of 0 instead of opening files. Reads of a regular file are then served by a backing file
+    /// opened on the first read and kept open until the inode is forgotten, other requests open
+    /// the backing file for each request.
+    ///
     /// The default value for this option is `false`.
     pub no_open: bool,
 
@@ -471,6 +987,188 @@ pub struct Config {
     /// * If dax_file_size == N, DAX will enable only when the file size is greater than or equal
     /// to N Bytes.
     pub dax_file_size: Option<u64>,
+
+    /// The readahead policy applied to files when they are opened. See the documentation of
+    /// `ReadaheadPolicy` for more details.
+    ///
+    /// The default value for this option is `ReadaheadPolicy::Normal`.
+    pub readahead: ReadaheadPolicy,
+
+    /// Per path readahead rules overriding `readahead`. Each rule is a path relative to the root
+    /// directory and the policy applied to it, a rule applies to the path itself and everything
+    /// beneath it. The first matching rule wins.
+    ///
+    /// The default value for this option is empty.
+    pub readahead_rules: Vec<(PathBuf, ReadaheadPolicy)>,
+
+    /// Per path block sizes reported in the attributes of files, instead of the preferred IO size
+    /// of the backing file system. The kernel sizes buffered IO by it, so it's worth raising for
+    /// backends serving large chunks better, e.g. an object store. Rules match like
+    /// `readahead_rules`.
+    ///
+    /// The default value for this option is empty.
+    pub blksize_rules: Vec<(PathBuf, u32)>,
+
+    /// Only allow opening regular files with fs-verity enabled, opening other regular files
+    /// fails with `EPERM`. Creating regular files by create and tmpfile requests fails with `EPERM`
+    /// too, as new files don't have fs-verity enabled. It guarantees that file contents are verified by the host kernel
+    /// against the fs-verity digest on every read, so tampering with backing files gets detected.
+    ///
+    /// The default value for this option is `false`.
+    pub require_verity: bool,
+
+    /// How to handle failures to set extended attributes in privileged namespaces. See the
+    /// documentation of `XattrErrorPolicy` for more details.
+    ///
+    /// The default value for this option is `XattrErrorPolicy::Fail`.
+    pub xattr_on_error: XattrErrorPolicy,
+
+    /// Observer to be notified when inodes are created and forgotten.
+    ///
+    /// The default value for this option is `None`.
+    pub inode_hooks: Option<Arc<dyn InodeObserver>>,
+
+    /// Maximum length of a name in bytes. Requests with longer names fail with `ENAMETOOLONG`
+    /// before reaching the backing file system.
+    ///
+    /// The default value for this option is 255.
+    pub max_name_len: usize,
+
+    /// Share backing files among handles of the same inode. Handles opened with the same access
+    /// mode share a single backing file, and a read-only backing file is replaced by a read-write
+    /// one once the inode is opened for writing, so at most two backing files are open for an
+    /// inode. The backing file is closed once all handles sharing it have been released.
+    ///
+    /// It saves file descriptors when the same files are opened many times. Files opened with
+    /// flags affecting the open file description, such as `O_APPEND` and `O_DIRECT`, always get
+    /// a dedicated backing file. Note that `flock(2)` locks are shared by handles sharing a backing
+    /// file.
+    ///
+    /// The default value for this option is `false`.
+    pub shared_fd: bool,
+
+    /// Kinds of modifications allowed by the file system. See the documentation of `WritePolicy`
+    /// for more details.
+    ///
+    /// The default value for this option allows all modifications.
+    pub write_policy: WritePolicy,
+
+    /// Let the kernel issue lookup and readdir requests for the same directory concurrently, by
+    /// negotiating `FUSE_PARALLEL_DIROPS`. Otherwise the kernel serializes them per directory.
+    ///
+    /// Concurrent requests on a directory may observe each other's effects in any order, e.g. a
+    /// lookup may race with the creation or removal of the same name, so the backing file system
+    /// must keep a directory consistent under concurrent access. The passthrough file system
+    /// itself is safe for this since every request is resolved by the backing file system.
+    ///
+    /// The default value for this option is `false`.
+    pub parallel_dirops: bool,
+
+    /// Let the kernel issue several read requests for the same file concurrently, by negotiating
+    /// `FUSE_ASYNC_READ`, e.g. to fetch readahead pages in the background while serving a read.
+    ///
+    /// The read requests may then be served by different worker threads and complete in any
+    /// order. The passthrough file system always reads at the offset given by the request, so this
+    /// is safe as long as the content of the backing files doesn't depend on the order in which
+    /// they are read. Disable it for backing files like pipes or character devices which return a
+    /// stream of data, otherwise readers may see chunks of the stream out of order. When mounted
+    /// by `Vfs`, the kernel negotiates with the `Vfs` instead, so `FsOptions::ASYNC_READ` must also
+    /// be removed from `VfsOptions::out_opts`.
+    ///
+    /// The default value for this option is `true`.
+    pub async_read: bool,
+
+    /// Split reads and writes larger than this many bytes into chunks, and yield between the
+    /// chunks, so a huge transfer doesn't monopolize the worker serving it. The async io path
+    /// yields to the executor, while the sync io path calls the hook set by
+    /// `PassthroughFs::set_io_yield_hook()`, if any. A value of 0 disables the splitting.
+    ///
+    /// The default value for this option is 1MiB, which is the largest request size negotiated by
+    /// the server, so requests are not split by default.
+    pub io_yield_interval: usize,
+
+    /// Shift the user and group ids between the client and the backing file system. The ids of
+    /// the requester are mapped to the host when creating files or changing their ownership, and
+    /// the owners of files are mapped back when reporting attributes. See the documentation of
+    /// `IdOffset` for more details.
+    ///
+    /// The default value for this option is `None`, ids are passed through unchanged.
+    pub id_offset: Option<IdOffset>,
+
+    /// Depth of stacked file systems to report to the kernel, including the FUSE mount itself,
+    /// e.g. 2 when `root_dir` is on another FUSE mount. It's negotiated by
+    /// `FsOptionsExt::PASSTHROUGH`, so the kernel knows how deep the mount has been stacked and
+    /// refuses to stack more file systems on top of it than `FILESYSTEM_MAX_STACK_DEPTH` allows.
+    /// The limit keeps operations recursing through the layers from overflowing the kernel
+    /// stack. Note that a depth of 2 doesn't allow stacking an overlayfs on the mount any more.
+    /// It's not negotiated if writeback caching is enabled, which the kernel doesn't support
+    /// together with stacking.
+    ///
+    /// The default value for this option is 0, which doesn't negotiate the stacking depth.
+    pub max_stack_depth: u32,
+
+    /// Enforce the restricted deletion flag (`S_ISVTX`) of directories for the requester, as
+    /// the kernel does for local file systems. An entry of a sticky directory, e.g. `/tmp`, may
+    /// only be removed or renamed by the owner of the entry or of the directory. The backing file
+    /// system only checks the rule against the credentials of the daemon, which usually owns
+    /// everything or is privileged, so users of the client could otherwise remove or rename the
+    /// files of each other. Violations fail with `EPERM`.
+    ///
+    /// The default value for this option is `false`.
+    pub enforce_sticky: bool,
+
+    /// Uids of requesters exempt from the rule enforced by `enforce_sticky`, like processes with
+    /// `CAP_FOWNER`. The ids are those of the client, before `id_offset` is applied.
+    ///
+    /// The default value for this option is `[0]`.
+    pub fowner_uids: Vec<u32>,
+
+    /// Keep POSIX record locks taken by the client in the daemon, and negotiate
+    /// `FsOptions::POSIX_LOCKS` so the kernel forwards `fcntl()` locking to the file system.
+    ///
+    /// Locks on files with mandatory locking enabled, i.e. setgid but not group executable, are
+    /// also enforced on reads and writes of other lock owners, which fail with `EAGAIN` instead
+    /// of waiting for the lock. IO without a lock owner, such as writeback of cached data,
+    /// conflicts with the locks of all owners. Waiting for a lock, e.g. `F_SETLKW`, isn't
+    /// supported and fails with `EAGAIN` if the lock is held. The locks are only visible to the
+    /// client, not to other users of the backing files.
+    ///
+    /// The default value for this option is `false`.
+    pub posix_locks: bool,
+
+    /// Hide the setuid and setgid bits of files from the client, like the `nosuid` mount
+    /// option. The setgid bit of directories, and of files without group execute permission,
+    /// which marks mandatory locking, is kept since it doesn't grant any privilege.
+    ///
+    /// The default value for this option is `false`.
+    pub nosuid: bool,
+
+    /// Refuse to open device nodes with `EACCES`, like the `nodev` mount option.
+    ///
+    /// The default value for this option is `false`.
+    pub nodev: bool,
+
+    /// Hide the execute permission of regular files from the client, and refuse to open them
+    /// for execution with `EACCES`, like the `noexec` mount option. Directories are still
+    /// searchable.
+    ///
+    /// The default value for this option is `false`.
+    pub noexec: bool,
+
+    /// Files generated by the daemon to serve alongside the backing files, each at a path
+    /// relative to the root directory. The parent directory of each path must exist in the
+    /// backing tree, and a backing file at the same path is shadowed by the synthetic file.
+    /// Synthetic directories list their entries by `SyntheticFile::readdir()`. Synthetic files
+    /// can't be modified, requests to do so fail with `EROFS`.
+    ///
+    /// The default value for this option is empty.
+    pub synthetic_entries: Vec<(PathBuf, Arc<dyn SyntheticFile>)>,
+
+    /// Sink of the digests of data read from the audited files it selects. Each read request of
+    /// an audited file is reported with the digest of the bytes served, see `AuditRecord`.
+    ///
+    /// The default value for this option is `None`.
+    pub audit_hash: Option<Arc<dyn AuditSink>>,
 }
 
 impl Default for Config {
@@ -489,6 +1187,28 @@ impl Default for Config {
             inode_file_handles: false,
             no_readdir: false,
             dax_file_size: None,
+            readahead: ReadaheadPolicy::Normal,
+            readahead_rules: Vec::new(),
+            blksize_rules: Vec::new(),
+            require_verity: false,
+            xattr_on_error: XattrErrorPolicy::Fail,
+            inode_hooks: None,
+            max_name_len: 255,
+            shared_fd: false,
+            write_policy: WritePolicy::default(),
+            parallel_dirops: false,
+            async_read: true,
+            io_yield_interval: 1 << 20,
+            id_offset: None,
+            max_stack_depth: 0,
+            enforce_sticky: false,
+            fowner_uids: vec![0],
+            posix_locks: false,
+            nosuid: false,
+            nodev: false,
+            noexec: false,
+            synthetic_entries: Vec::new(),
+            audit_hash: None,
         }
     }
 }
@@ -512,6 +1232,8 @@ pub struct PassthroughFs<D: AsyncDrive = AsyncDriver, S: BitmapSlice + Send + Sy
     // used for reading and writing data.
     handle_map: HandleMap,
     next_handle: AtomicU64,
+    // Backing files shared by handles when `cfg.shared_fd` is enabled.
+    shared_files: Mutex<HashMap<Inode, SharedFiles>>,
 
     // Maps mount IDs to an open FD on the respective ID for the purpose of open_by_handle_at().
     mount_fds: MountFds,
@@ -535,14 +1257,32 @@ pub struct PassthroughFs<D: AsyncDrive = AsyncDriver, S: BitmapSlice + Send + Sy
     // Whether kill_priv_v2 is enabled.
     killpriv_v2: AtomicBool,
 
+    // Whether the daemon has CAP_FSETID to write files without clearing the setuid/setgid bits.
+    cap_fsetid: AtomicBool,
+
     // Whether no_readdir is enabled.
     no_readdir: AtomicBool,
 
+    // Whether files opened read-only skip the flush on close. Only enabled by `init` when POSIX
+    // locks are not, as they are released by the flush request of their owner.
+    no_flush: AtomicBool,
+
     // Whether per-file DAX feature is enabled.
     // Init from guest kernel Init cmd of fuse fs.
     perfile_dax: AtomicBool,
 
-    cfg: Config,
+    // POSIX record locks taken by the client.
+    posix_locks: PosixLocks,
+
+    // Called between the chunks of large reads and writes in the sync io path.
+    io_yield_hook: Mutex<Option<Arc<dyn Fn() + Send + Sync>>>,
+
+    // Files generated by the daemon, from `cfg.synthetic_entries`.
+    synthetic: SyntheticFiles,
+
+    // Swapped by `reconfigure()`. Each request loads it once and passes it down, so it sees either
+    // the old or the new configuration.
+    cfg: ArcSwap<Config>,
 
     phantom: PhantomData<D>,
     phantom2: PhantomData<S>,
@@ -566,6 +1306,7 @@ impl<D: AsyncDrive, S: BitmapSlice + Send + Sync> PassthroughFs<D, S> {
 
             handle_map: HandleMap::new(),
             next_handle: AtomicU64::new(1),
+            shared_files: Mutex::new(HashMap::new()),
             mount_fds: MountFds::new(),
 
             proc_self_fd,
@@ -574,23 +1315,94 @@ impl<D: AsyncDrive, S: BitmapSlice + Send + Sync> PassthroughFs<D, S> {
             no_open: AtomicBool::new(false),
             no_opendir: AtomicBool::new(false),
             killpriv_v2: AtomicBool::new(false),
+            cap_fsetid: AtomicBool::new(true),
             no_readdir: AtomicBool::new(cfg.no_readdir),
+            no_flush: AtomicBool::new(false),
             perfile_dax: AtomicBool::new(false),
-            cfg,
+            posix_locks: PosixLocks::default(),
+            io_yield_hook: Mutex::new(None),
+            synthetic: SyntheticFiles::new(&cfg.synthetic_entries)?,
+            cfg: ArcSwap::new(Arc::new(cfg)),
 
             phantom: PhantomData,
             phantom2: PhantomData,
         })
     }
 
+    /// Replace the configuration of a running file system.
+    ///
+    /// The new configuration applies to the requests received from now on, open handles and
+    /// looked up inodes are kept. Only these options may change:
+    /// - `entry_timeout`, `attr_timeout` and `cache_policy`, for replies sent from now on. The
+    ///   kernel keeps what it has cached until it expires;
+    /// - `write_policy`, `nosuid`, `nodev`, `noexec`, `enforce_sticky` and `fowner_uids`, for
+    ///   requests received from now on, including writes through handles opened before;
+    /// - `readahead`, `readahead_rules` and `require_verity`, for files opened from now on;
+    /// - `blksize_rules`, for attributes sent from now on;
+    /// - `xattr_on_error`, `max_name_len`, `io_yield_interval`, `inode_hooks` and `audit_hash`.
+    ///
+    /// The other options are used to set up the file system or are negotiated with the kernel by
+    /// FUSE_INIT, so changing them needs to mount the file system again, and fails with `EINVAL`.
+    pub fn reconfigure(&self, new: Config) -> io::Result<()> {
+        let old = self.cfg.load();
+        let fixed = Config {
+            entry_timeout: old.entry_timeout,
+            attr_timeout: old.attr_timeout,
+            cache_policy: old.cache_policy.clone(),
+            write_policy: old.write_policy,
+            nosuid: old.nosuid,
+            nodev: old.nodev,
+            noexec: old.noexec,
+            enforce_sticky: old.enforce_sticky,
+            fowner_uids: old.fowner_uids.clone(),
+            readahead: old.readahead,
+            readahead_rules: old.readahead_rules.clone(),
+            blksize_rules: old.blksize_rules.clone(),
+            require_verity: old.require_verity,
+            xattr_on_error: old.xattr_on_error,
+            max_name_len: old.max_name_len,
+            io_yield_interval: old.io_yield_interval,
+            inode_hooks: old.inode_hooks.clone(),
+            audit_hash: old.audit_hash.clone(),
+            ..new.clone()
+        };
+        if fixed != **old {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        self.cfg.store(Arc::new(new));
+        Ok(())
+    }
+
     /// Initialize the Passthrough file system.
     pub fn import(&self) -> io::Result<()> {
-        let root = CString::new(self.cfg.root_dir.as_str()).expect("CString::new failed");
+        let root = CString::new(self.cfg.load().root_dir.as_str()).expect("CString::new failed");
+        self.import_root(libc::AT_FDCWD, &root)
+    }
+
+    /// Initialize the Passthrough file system with an open file of the root directory, instead of
+    /// opening `Config::root_dir`.
+    ///
+    /// The file may be opened with `O_PATH`. All backing files are reached relative to it, so the
+    /// daemon doesn't need access to the parent directories of the root, e.g. in a sandbox which
+    /// only inherits the root. File handles need the mount points of the root to be accessible,
+    /// so `O_PATH` files are used for inodes instead if `Config::inode_file_handles` can't be
+    /// honored.
+    pub fn import_from_fd(&self, root: File) -> io::Result<()> {
+        let st = Self::stat(&root, None)?;
+        if st.st_mode & libc::S_IFMT != libc::S_IFDIR {
+            return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+        }
+        // Safe because this is a constant value and a valid C string.
+        let dot = unsafe { CStr::from_bytes_with_nul_unchecked(CURRENT_DIR_CSTR) };
+        self.import_root(root.as_raw_fd(), dot)
+    }
 
+    fn import_root(&self, dir_fd: RawFd, root: &CStr) -> io::Result<()> {
+        let cfg = self.cfg.load();
         let (file_or_handle, st, ids_altkey, handle_altkey) = Self::open_file_or_handle(
-            self.cfg.inode_file_handles,
-            libc::AT_FDCWD,
-            &root,
+            cfg.inode_file_handles,
+            dir_fd,
+            root,
             &self.mount_fds,
             |fd, flags, _mode| {
                 let pathname = CString::new(format!("{}", fd))
@@ -617,37 +1429,145 @@ impl<D: AsyncDrive, S: BitmapSlice + Send + Sync> PassthroughFs<D, S> {
                 2,
                 ids_altkey,
                 st.get_stat().st_mode,
+                0,
             ),
             ids_altkey,
             handle_altkey,
         );
+        if let Some(hooks) = cfg.inode_hooks.as_ref() {
+            hooks.on_create(fuse::ROOT_ID, &st.get_stat());
+        }
 
         Ok(())
     }
 
+    /// Set the hook called between the chunks of large reads and writes in the sync io path,
+    /// e.g. to let other work of a cooperative scheduler run. See `Config::io_yield_interval`.
+    pub fn set_io_yield_hook<H: Fn() + Send + Sync + 'static>(&self, hook: H) {
+        *self.io_yield_hook.lock().unwrap() = Some(Arc::new(hook));
+    }
+
     /// Get the list of file descriptors which should be reserved across live upgrade.
     pub fn keep_fds(&self) -> Vec<RawFd> {
         vec![self.proc_self_fd.as_raw_fd()]
     }
 
-    fn readlinkat(dfd: i32, pathname: &CStr) -> io::Result<PathBuf> {
-        let mut buf = Vec::with_capacity(libc::PATH_MAX as usize);
+    /// Look up the inodes of `paths` in advance, so the first requests to them don't pay for it.
+    ///
+    /// Each path is relative to the root directory of the file system and resolved component by
+    /// component as the kernel would do by lookup requests, but nothing is sent to the kernel.
+    /// Symlinks are followed except for the final component, and resolving fails with `ELOOP`
+    /// after following 40 symlinks. Absolute symlink targets are resolved from the root directory
+    /// of the file system.
+    /// Every inode along the paths gets one extra lookup reference, which is never forgotten by
+    /// the kernel, so it stays cached until the file system is destroyed.
+    ///
+    /// All paths are tried even if some of them fail, and an error listing the failed paths is
+    /// returned in that case.
+    pub fn prime(&self, paths: &[&Path]) -> io::Result<()> {
+        let cfg = self.cfg.load();
+        let mut failed = Vec::new();
+        let mut kind = io::ErrorKind::Other;
+
+        for path in paths {
+            if let Err(e) = self.prime_path(&cfg, path) {
+                warn!("fuse: failed to prime {:?}, {}", path, e);
+                kind = e.kind();
+                failed.push(format!("{}: {}", path.display(), e));
+            }
+        }
 
-        // Safe because the kernel will only write data to buf and we check the return value
-        let buf_read = unsafe {
-            libc::readlinkat(
-                dfd,
-                pathname.as_ptr(),
-                buf.as_mut_ptr() as *mut libc::c_char,
-                buf.capacity(),
-            )
-        };
-        if buf_read < 0 {
-            error!("fuse: readlinkat error");
-            return Err(io::Error::last_os_error());
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                kind,
+                format!("failed to prime paths: {}", failed.join(", ")),
+            ))
         }
+    }
 
-        // Safe because we know buf len
+    fn prime_path(&self, cfg: &Config, path: &Path) -> io::Result<Inode> {
+        let mut pending = VecDeque::new();
+        for component in path.components() {
+            match component {
+                Component::RootDir | Component::CurDir => {}
+                Component::Normal(name) => pending.push_back(name.to_os_string()),
+                // Don't let `..` escape from the root directory.
+                Component::ParentDir | Component::Prefix(_) => {
+                    return Err(io::Error::from_raw_os_error(libc::EINVAL))
+                }
+            }
+        }
+
+        // Inodes from the root to the current one.
+        let mut dirs = vec![fuse::ROOT_ID];
+        let mut is_dir = true;
+        let mut hops = 0;
+        while let Some(name) = pending.pop_front() {
+            if name == ".." {
+                if !is_dir {
+                    return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+                }
+                if dirs.len() > 1 {
+                    dirs.pop();
+                }
+                continue;
+            }
+
+            let name = CString::new(name.into_vec())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            self.validate_name_len(cfg, &name)?;
+            let entry = self.do_lookup(cfg, *dirs.last().unwrap(), &name)?;
+
+            // Like the kernel, follow symlinks in the middle of the path but not the final one.
+            if entry.attr.st_mode & libc::S_IFMT == libc::S_IFLNK && !pending.is_empty() {
+                hops += 1;
+                if hops > MAX_SYMLINK_HOPS {
+                    return Err(io::Error::from_raw_os_error(libc::ELOOP));
+                }
+                let data = self.inode_map.get(entry.inode)?;
+                let file = data.get_file(&self.mount_fds)?;
+                // Safe because this is a constant value and a valid C string.
+                let empty = unsafe { CStr::from_bytes_with_nul_unchecked(EMPTY_CSTR) };
+                let target = Self::readlinkat(file.as_raw_fd(), empty)?;
+
+                // Absolute targets are resolved from the root directory of the file system.
+                for component in target.components().rev() {
+                    match component {
+                        Component::RootDir => dirs.truncate(1),
+                        Component::Normal(name) => pending.push_front(name.to_os_string()),
+                        Component::ParentDir => pending.push_front(OsString::from("..")),
+                        Component::CurDir | Component::Prefix(_) => {}
+                    }
+                }
+            } else {
+                is_dir = entry.attr.st_mode & libc::S_IFMT == libc::S_IFDIR;
+                dirs.push(entry.inode);
+            }
+        }
+
+        Ok(*dirs.last().unwrap())
+    }
+
+    fn readlinkat(dfd: i32, pathname: &CStr) -> io::Result<PathBuf> {
+        let mut buf = Vec::with_capacity(libc::PATH_MAX as usize);
+
+        // Safe because the kernel will only write data to buf and we check the return value
+        let buf_read = unsafe {
+            libc::readlinkat(
+                dfd,
+                pathname.as_ptr(),
+                buf.as_mut_ptr() as *mut libc::c_char,
+                buf.capacity(),
+            )
+        };
+        if buf_read < 0 {
+            error!("fuse: readlinkat error");
+            return Err(io::Error::last_os_error());
+        }
+
+        // Safe because we know buf len
         unsafe {
             buf.set_len(buf_read as usize);
         }
@@ -677,6 +1597,113 @@ impl<D: AsyncDrive, S: BitmapSlice + Send + Sync> PassthroughFs<D, S> {
         Self::readlinkat(self.proc_self_fd.as_raw_fd(), &pathname)
     }
 
+    // Get the path of the inode relative to the root directory.
+    fn relative_path(&self, inode: Inode) -> io::Result<PathBuf> {
+        let path = self.readlinkat_proc_file(inode)?;
+        // The root may have been imported from a file, so match against its actual path.
+        let root = self.readlinkat_proc_file(fuse::ROOT_ID)?;
+        path.strip_prefix(&root)
+            .map(Path::to_path_buf)
+            .map_err(|_| io::Error::from_raw_os_error(libc::ENOENT))
+    }
+
+    // Get a hasher for the data read through `data`, if the file is audited.
+    fn audit_hasher(&self, cfg: &Config, data: &HandleData) -> Option<Box<dyn AuditHasher>> {
+        let sink = cfg.audit_hash.as_ref()?;
+        let mut cached = data.audited.lock().unwrap();
+        let audited = *cached.get_or_insert_with(|| match self.relative_path(data.inode) {
+            Ok(path) => sink.audited(&path),
+            // Rather report too much than miss a file, e.g. one which has been unlinked.
+            Err(e) => {
+                warn!(
+                    "fuse: failed to get path of inode {} to audit, {:?}",
+                    data.inode, e
+                );
+                true
+            }
+        });
+        drop(cached);
+        if audited {
+            Some(sink.hasher())
+        } else {
+            None
+        }
+    }
+
+    fn readahead_policy(&self, cfg: &Config, inode: Inode) -> ReadaheadPolicy {
+        if cfg.readahead_rules.is_empty() {
+            return cfg.readahead;
+        }
+
+        let path = match self.relative_path(inode) {
+            Ok(p) => p,
+            Err(e) => {
+                debug!("fuse: failed to get path of inode {}, {:?}", inode, e);
+                return cfg.readahead;
+            }
+        };
+
+        cfg.readahead_rules
+            .iter()
+            .find(|(prefix, _)| path.starts_with(prefix))
+            .map(|(_, policy)| *policy)
+            .unwrap_or(cfg.readahead)
+    }
+
+    // Get the block size reported for the inode, `blksize` being the one of the backing file.
+    fn guest_blksize(&self, cfg: &Config, inode: Inode, blksize: u32) -> u32 {
+        if cfg.blksize_rules.is_empty() {
+            return blksize;
+        }
+
+        match self.relative_path(inode) {
+            Ok(path) => cfg
+                .blksize_rules
+                .iter()
+                .find(|(prefix, _)| path.starts_with(prefix))
+                .map_or(blksize, |(_, size)| *size),
+            Err(e) => {
+                debug!("fuse: failed to get path of inode {}, {:?}", inode, e);
+                blksize
+            }
+        }
+    }
+
+    fn advise_readahead(&self, cfg: &Config, inode: Inode, file: &File) {
+        let policy = self.readahead_policy(cfg, inode);
+        if policy == ReadaheadPolicy::Normal {
+            return;
+        }
+
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res = unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, policy.advice()) };
+        if res != 0 {
+            // It's only an advice, so don't fail the open request.
+            warn!(
+                "fuse: failed to advise readahead {:?} for inode {}, {}",
+                policy,
+                inode,
+                io::Error::from_raw_os_error(res)
+            );
+        }
+    }
+
+    fn check_verity(&self, cfg: &Config, file: &File, mode: u32) -> io::Result<()> {
+        if !cfg.require_verity || mode & libc::S_IFMT != libc::S_IFREG {
+            return Ok(());
+        }
+
+        let mut flags: libc::c_int = 0;
+        // Safe because the kernel only writes an int into `flags` and we check the return value.
+        let res = unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags) };
+        // File systems without inode flags support can't have fs-verity enabled either.
+        if res < 0 || flags & FS_VERITY_FL == 0 {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+
+        Ok(())
+    }
+
     fn stat(dir: &impl AsRawFd, path: Option<&CStr>) -> io::Result<libc::stat64> {
         Self::stat_fd(dir.as_raw_fd(), path)
     }
@@ -704,6 +1731,34 @@ impl<D: AsyncDrive, S: BitmapSlice + Send + Sync> PassthroughFs<D, S> {
         }
     }
 
+    // Get the setuid/setgid bits of the file which may be cleared by writing to it.
+    fn privileged_mode(fd: RawFd) -> io::Result<Option<u32>> {
+        let st = Self::stat_fd(fd, None)?;
+        if st.st_mode & (libc::S_ISUID | libc::S_ISGID) != 0 {
+            Ok(Some(st.st_mode & 0o7777))
+        } else {
+            Ok(None)
+        }
+    }
+
+    // Restore the setuid/setgid bits cleared by the backing file system on write.
+    fn restore_privileged_mode(fd: RawFd, mode: u32) {
+        match Self::stat_fd(fd, None) {
+            Ok(st) if st.st_mode & 0o7777 != mode => {
+                // Safe because this doesn't modify any memory and we check the return value.
+                if unsafe { libc::fchmod(fd, mode) } < 0 {
+                    warn!(
+                        "fuse: failed to restore mode {:o} after write: {}",
+                        mode,
+                        io::Error::last_os_error()
+                    );
+                }
+            }
+            Ok(_) => {}
+            Err(e) => warn!("fuse: failed to stat file after write: {}", e),
+        }
+    }
+
     fn create_file_excl(
         dfd: i32,
         pathname: &CStr,
@@ -737,7 +1792,9 @@ impl<D: AsyncDrive, S: BitmapSlice + Send + Sync> PassthroughFs<D, S> {
     }
 
     fn open_file(dfd: i32, pathname: &CStr, flags: i32, mode: u32) -> io::Result<File> {
-        let fd = if flags & libc::O_CREAT == libc::O_CREAT {
+        let fd = if flags & libc::O_CREAT == libc::O_CREAT
+            || flags & libc::O_TMPFILE == libc::O_TMPFILE
+        {
             unsafe { libc::openat(dfd, pathname.as_ptr(), flags, mode) }
         } else {
             unsafe { libc::openat(dfd, pathname.as_ptr(), flags) }
@@ -832,28 +1889,163 @@ impl<D: AsyncDrive, S: BitmapSlice + Send + Sync> PassthroughFs<D, S> {
         Ok((file_or_handle, inode_stat, ids_altkey, handle_altkey))
     }
 
-    fn do_lookup(&self, parent: Inode, name: &CStr) -> io::Result<Entry> {
-        let name =
-            if parent == fuse::ROOT_ID && name.to_bytes_with_nul().starts_with(PARENT_DIR_CSTR) {
-                // Safe as this is a constant value and a valid C string.
-                CStr::from_bytes_with_nul(CURRENT_DIR_CSTR).unwrap()
-            } else {
-                name
-            };
+    // Resolve ".." of the root directory to the root itself, so it never escapes to the host.
+    fn dot_entry_name<'a>(&self, parent: Inode, name: &'a CStr) -> &'a CStr {
+        if parent == fuse::ROOT_ID && name.to_bytes_with_nul().starts_with(PARENT_DIR_CSTR) {
+            // Safe as this is a constant value and a valid C string.
+            CStr::from_bytes_with_nul(CURRENT_DIR_CSTR).unwrap()
+        } else {
+            name
+        }
+    }
+
+    fn do_lookup(&self, cfg: &Config, parent: Inode, name: &CStr) -> io::Result<Entry> {
+        let name = self.dot_entry_name(parent, name);
+        if let Some(entry) = self.synthetic_lookup(cfg, parent, name)? {
+            return Ok(entry);
+        }
 
         let dir = self.inode_map.get(parent)?;
         let dir_file = dir.get_file(&self.mount_fds)?;
         let (file_or_handle, st, ids_altkey, handle_altkey) = Self::open_file_or_handle(
-            self.cfg.inode_file_handles,
+            cfg.inode_file_handles,
             dir_file.as_raw_fd(),
             name,
             &self.mount_fds,
             |fd, flags, mode| Self::open_proc_file(&self.proc_self_fd, fd, flags, mode),
         )?;
 
+        self.do_lookup_entry(cfg, file_or_handle, st, ids_altkey, handle_altkey)
+    }
+
+    // Get the synthetic files in the directory `parent`.
+    fn synthetic_children(
+        &self,
+        parent: Inode,
+    ) -> io::Result<Vec<(CString, Arc<dyn SyntheticFile>)>> {
+        if self.synthetic.is_empty() {
+            return Ok(Vec::new());
+        }
+        let path = match self.synthetic.get(parent) {
+            Some(_) => None,
+            None => self.relative_path(parent).ok(),
+        };
+        self.synthetic.children(parent, path.as_deref())
+    }
+
+    // Look up a synthetic file, `None` if `name` should be looked up in the backing directory.
+    fn synthetic_lookup(
+        &self,
+        cfg: &Config,
+        parent: Inode,
+        name: &CStr,
+    ) -> io::Result<Option<Entry>> {
+        let children = self.synthetic_children(parent)?;
+        match children.iter().find(|(n, _)| n.as_c_str() == name) {
+            Some((_, file)) => self.synthetic_entry(cfg, parent, name, file).map(Some),
+            None if self.synthetic.get(parent).is_some() => {
+                Err(io::Error::from_raw_os_error(libc::ENOENT))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn synthetic_entry(
+        &self,
+        cfg: &Config,
+        parent: Inode,
+        name: &CStr,
+        file: &Arc<dyn SyntheticFile>,
+    ) -> io::Result<Entry> {
+        let inode = self.synthetic.inode(parent, name, file, || {
+            self.next_inode.fetch_add(1, Ordering::Relaxed)
+        });
+        Ok(Entry {
+            inode,
+            generation: 0,
+            attr: self.synthetic_stat(cfg, inode, file)?,
+            attr_flags: 0,
+            attr_timeout: cfg.attr_timeout,
+            entry_timeout: cfg.entry_timeout,
+        })
+    }
+
+    fn synthetic_stat(
+        &self,
+        cfg: &Config,
+        inode: Inode,
+        file: &Arc<dyn SyntheticFile>,
+    ) -> io::Result<libc::stat64> {
+        let mut st = file.stat()?;
+        st.st_ino = inode;
+        Ok(self.guest_stat(cfg, st))
+    }
+
+    // Refuse to access the xattrs storing remapped privileged xattrs by their own names.
+    fn check_remapped_xattr(&self, cfg: &Config, name: &CStr) -> io::Result<()> {
+        if cfg.xattr_on_error == XattrErrorPolicy::Remap && is_remapped_xattr(name.to_bytes()) {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+        Ok(())
+    }
+
+    // Refuse to modify the synthetic file `inode`, or the synthetic file `name` in it.
+    fn check_synthetic(&self, inode: Inode, name: Option<&CStr>) -> io::Result<()> {
+        let synthetic = self.synthetic.get(inode).is_some()
+            || match name {
+                Some(name) => self
+                    .synthetic_children(inode)?
+                    .iter()
+                    .any(|(n, _)| n.as_c_str() == name),
+                None => false,
+            };
+        if synthetic {
+            return Err(io::Error::from_raw_os_error(libc::EROFS));
+        }
+        Ok(())
+    }
+
+    // List the synthetic files of the directory `parent`, after its backing entries if any.
+    fn do_synthetic_readdir(
+        &self,
+        cfg: &Config,
+        parent: Inode,
+        offset: u64,
+        add_entry: &mut dyn FnMut(DirEntry, Entry) -> io::Result<usize>,
+    ) -> io::Result<()> {
+        let children = self.synthetic_children(parent)?;
+        let start = offset.saturating_sub(SYNTHETIC_OFFSET) as usize;
+        for (i, (name, file)) in children.iter().enumerate().skip(start) {
+            let entry = self.synthetic_entry(cfg, parent, name, file)?;
+            let dir_entry = DirEntry {
+                ino: entry.attr.st_ino,
+                offset: SYNTHETIC_OFFSET + i as u64 + 1,
+                type_: (entry.attr.st_mode & libc::S_IFMT) >> 12,
+                name: name.to_bytes(),
+            };
+            match add_entry(dir_entry, entry) {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(e) if i == start => return Err(e),
+                Err(_) => break,
+            }
+        }
+        Ok(())
+    }
+
+    // Find the inode matching the alternative keys or allocate a new one, and increase its
+    // lookup count.
+    fn do_lookup_entry(
+        &self,
+        cfg: &Config,
+        file_or_handle: FileOrHandle,
+        st: InodeStat,
+        ids_altkey: InodeAltKey,
+        handle_altkey: Option<InodeAltKey>,
+    ) -> io::Result<Entry> {
         // Whether to enable file DAX according to the value of dax_file_size
         let mut attr_flags: u32 = 0;
-        if let Some(dax_file_size) = self.cfg.dax_file_size {
+        if let Some(dax_file_size) = cfg.dax_file_size {
             // st.stat.st_size is i64
             if self.perfile_dax.load(Ordering::Relaxed)
                 && st.stat.st_size >= 0x0
@@ -884,15 +2076,15 @@ impl<D: AsyncDrive, S: BitmapSlice + Send + Sync> PassthroughFs<D, S> {
                         .compare_exchange(curr, new, Ordering::AcqRel, Ordering::Acquire)
                         .is_ok()
                     {
-                        found = Some(data.inode);
+                        found = Some((data.inode, data.generation));
                         break;
                     }
                 }
             }
         }
 
-        let inode = if let Some(v) = found {
-            v
+        let (inode, generation, created) = if let Some((inode, generation)) = found {
+            (inode, generation, false)
         } else {
             // Write guard get_alt_locked() and insert_lock() to avoid race conditions.
             let mut inodes = self.inode_map.get_map_mut();
@@ -909,7 +2101,7 @@ impl<D: AsyncDrive, S: BitmapSlice + Send + Sync> PassthroughFs<D, S> {
                         ids_altkey
                     );
                     data.refcount.fetch_add(1, Ordering::Relaxed);
-                    data.inode
+                    (data.inode, data.generation, false)
                 }
                 None => {
                     let inode = self.next_inode.fetch_add(1, Ordering::Relaxed);
@@ -927,32 +2119,162 @@ impl<D: AsyncDrive, S: BitmapSlice + Send + Sync> PassthroughFs<D, S> {
                         handle_altkey
                     );
 
+                    let generation = self
+                        .inode_map
+                        .next_generation_locked(&ids_altkey, handle_altkey.as_ref());
                     InodeMap::insert_locked(
                         inodes.deref_mut(),
                         inode,
-                        InodeData::new(inode, file_or_handle, 1, ids_altkey, st.get_stat().st_mode),
+                        InodeData::new(
+                            inode,
+                            file_or_handle,
+                            1,
+                            ids_altkey,
+                            st.get_stat().st_mode,
+                            generation,
+                        ),
                         ids_altkey,
                         handle_altkey,
                     );
-                    inode
+                    (inode, generation, true)
                 }
             }
         };
 
+        if created {
+            if let Some(hooks) = cfg.inode_hooks.as_ref() {
+                hooks.on_create(inode, &st.get_stat());
+            }
+        }
+
+        let mut attr = self.guest_stat(cfg, st.get_stat());
+        attr.st_blksize = self.guest_blksize(cfg, inode, attr.st_blksize as u32) as _;
         Ok(Entry {
             inode,
-            generation: 0,
-            attr: st.get_stat(),
+            generation,
+            attr,
             attr_flags,
-            attr_timeout: self.cfg.attr_timeout,
-            entry_timeout: self.cfg.entry_timeout,
+            attr_timeout: cfg.attr_timeout,
+            entry_timeout: cfg.entry_timeout,
         })
     }
 
-    fn forget_one(inodes: &mut MultiKeyMap, inode: Inode, count: u64) {
+    // Switch the credentials of the thread to the requester, mapped to the host.
+    fn set_creds(
+        &self,
+        cfg: &Config,
+        ctx: &Context,
+    ) -> io::Result<(Option<ScopedUid>, Option<ScopedGid>)> {
+        match cfg.id_offset {
+            Some(ids) => set_creds(ids.host_uid(ctx.uid), ids.host_gid(ctx.gid)),
+            None => set_creds(ctx.uid, ctx.gid),
+        }
+    }
+
+    // Fail IO conflicting with the locks of other owners if the file `fd` has mandatory locking
+    // enabled. The mode is only checked if there is a conflicting lock.
+    fn check_mandatory_lock(
+        &self,
+        inode: Inode,
+        fd: RawFd,
+        lock_owner: Option<u64>,
+        offset: u64,
+        size: u32,
+        write: bool,
+    ) -> io::Result<()> {
+        if !self
+            .posix_locks
+            .conflicts_io(inode, lock_owner, offset, size, write)
+        {
+            return Ok(());
+        }
+        let st = Self::stat_fd(fd, None)?;
+        if st.st_mode & (libc::S_ISGID | libc::S_IXGRP) == libc::S_ISGID {
+            Err(io::Error::from_raw_os_error(libc::EAGAIN))
+        } else {
+            Ok(())
+        }
+    }
+
+    // Check the restricted deletion flag of the directory `dir` before removing or renaming its
+    // entry `name` on behalf of `ctx`, as the kernel does for the credentials of the requester.
+    fn check_sticky(
+        &self,
+        cfg: &Config,
+        ctx: &Context,
+        dir: &impl AsRawFd,
+        name: &CStr,
+    ) -> io::Result<()> {
+        if !cfg.enforce_sticky || cfg.fowner_uids.contains(&ctx.uid) {
+            return Ok(());
+        }
+        let dir_st = self.guest_stat(cfg, Self::stat(dir, None)?);
+        if dir_st.st_mode & libc::S_ISVTX == 0 || dir_st.st_uid == ctx.uid {
+            return Ok(());
+        }
+        match Self::stat(dir, Some(name)) {
+            Ok(st) if self.guest_stat(cfg, st).st_uid != ctx.uid => {
+                Err(io::Error::from_raw_os_error(libc::EPERM))
+            }
+            Ok(_) => Ok(()),
+            // Let the backing file system fail the request, if it should.
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Map the owner and the mode of a backing file to the client.
+    fn guest_stat(&self, cfg: &Config, mut st: libc::stat64) -> libc::stat64 {
+        if let Some(ids) = cfg.id_offset {
+            st.st_uid = ids.guest_uid(st.st_uid);
+            st.st_gid = ids.guest_gid(st.st_gid);
+        }
+        st.st_mode = self.guest_mode(cfg, st.st_mode);
+        st
+    }
+
+    // Hide the mode bits disabled by the `nosuid` and `noexec` options.
+    fn guest_mode(&self, cfg: &Config, mut mode: u32) -> u32 {
+        if mode & libc::S_IFMT == libc::S_IFDIR {
+            return mode;
+        }
+        if cfg.noexec && mode & libc::S_IFMT == libc::S_IFREG {
+            if mode & libc::S_IXGRP != 0 {
+                mode &= !libc::S_ISGID;
+            }
+            mode &= !(libc::S_ISUID | libc::S_IXUSR | libc::S_IXGRP | libc::S_IXOTH);
+        }
+        if cfg.nosuid {
+            mode &= !libc::S_ISUID;
+            if mode & libc::S_IXGRP != 0 {
+                mode &= !libc::S_ISGID;
+            }
+        }
+        mode
+    }
+
+    // Restore the bits of `host`, the mode of the backing file, hidden by `guest_mode()` in the
+    // `mode` set by the client, which doesn't know about them.
+    fn host_mode(&self, cfg: &Config, mode: u32, host: u32) -> u32 {
+        mode | (host & !self.guest_mode(cfg, host) & 0o7777)
+    }
+
+    // Check an open request against the `nodev` and `noexec` options. `mode` is the file type.
+    fn check_open_mode(&self, cfg: &Config, mode: u32, flags: i32) -> io::Result<()> {
+        let file_type = mode & libc::S_IFMT;
+        if (cfg.nodev && (file_type == libc::S_IFCHR || file_type == libc::S_IFBLK))
+            || (cfg.noexec && flags & FMODE_EXEC != 0)
+        {
+            return Err(io::Error::from_raw_os_error(libc::EACCES));
+        }
+        Ok(())
+    }
+
+    // Returns whether the inode has been removed from the inode map.
+    fn forget_one(inodes: &mut MultiKeyMap, inode: Inode, count: u64) -> bool {
         // ROOT_ID should not be forgotten, or we're not able to access to files any more.
         if inode == fuse::ROOT_ID {
-            return;
+            return false;
         }
 
         if let Some(data) = inodes.get(&inode) {
@@ -962,6 +2284,13 @@ impl<D: AsyncDrive, S: BitmapSlice + Send + Sync> PassthroughFs<D, S> {
             // to loop here until we can decrement successfully.
             loop {
                 let curr = data.refcount.load(Ordering::Acquire);
+                debug_assert!(
+                    count <= curr,
+                    "fuse: forget inode {} with count {} exceeding refcount {}",
+                    inode,
+                    count,
+                    curr
+                );
 
                 // Saturating sub because it doesn't make sense for a refcount to go below zero and
                 // we don't want misbehaving clients to cause integer overflow.
@@ -984,32 +2313,74 @@ impl<D: AsyncDrive, S: BitmapSlice + Send + Sync> PassthroughFs<D, S> {
                     if new == 0 {
                         // We just removed the last refcount for this inode.
                         inodes.remove(&inode);
+                        return true;
                     }
                     break;
                 }
             }
         }
+
+        false
+    }
+
+    // Drop a lookup reference which has not been handed over to the kernel.
+    fn release_lookup(&self, cfg: &Config, inode: Inode) {
+        let mut inodes = self.inode_map.get_map_mut();
+        if Self::forget_one(&mut inodes, inode, 1) {
+            drop(inodes);
+            self.notify_forgotten(cfg, &[(inode, 1)]);
+        }
+    }
+
+    // Notify the inode observer of inodes forgotten, which must be called without holding the
+    // inode map lock.
+    fn notify_forgotten(&self, cfg: &Config, forgotten: &[(Inode, u64)]) {
+        if let Some(hooks) = cfg.inode_hooks.as_ref() {
+            for (inode, count) in forgotten {
+                hooks.on_forget(*inode, *count);
+            }
+        }
     }
 
-    fn do_release(&self, inode: Inode, handle: Handle) -> io::Result<()> {
-        self.handle_map.release(handle, inode)
+    fn do_release(&self, cfg: &Config, inode: Inode, handle: Handle) -> io::Result<()> {
+        self.handle_map.release(handle, inode)?;
+
+        if cfg.shared_fd {
+            let mut shared = self.shared_files.lock().unwrap();
+            if shared.get(&inode).map(|f| f.is_empty()).unwrap_or(false) {
+                shared.remove(&inode);
+            }
+        }
+
+        Ok(())
     }
 
     // Validate a path component, same as the one in vfs layer, but only do the validation if this
     // passthroughfs is used without vfs layer, to avoid double validation.
-    fn validate_path_component(&self, name: &CStr) -> io::Result<()> {
-        // !self.cfg.do_import means we're under vfs, and vfs has already done the validation
-        if !self.cfg.do_import {
+    fn validate_path_component(&self, cfg: &Config, name: &CStr) -> io::Result<()> {
+        self.validate_name_len(cfg, name)?;
+        // !cfg.do_import means we're under vfs, and vfs has already done the validation
+        if !cfg.do_import {
             return Ok(());
         }
         validate_path_component(name)
     }
+
+    // The length limit is specific to the passthroughfs configuration, so it's enforced even
+    // under vfs.
+    fn validate_name_len(&self, cfg: &Config, name: &CStr) -> io::Result<()> {
+        if name.to_bytes().len() > cfg.max_name_len {
+            return Err(io::Error::from_raw_os_error(libc::ENAMETOOLONG));
+        }
+        Ok(())
+    }
 }
 
 #[cfg(not(feature = "async-io"))]
 impl<D: AsyncDrive> BackendFileSystem<D> for PassthroughFs<D> {
     fn mount(&self) -> io::Result<(Entry, u64)> {
-        let entry = self.do_lookup(fuse::ROOT_ID, &CString::new(".").unwrap())?;
+        let cfg = self.cfg.load();
+        let entry = self.do_lookup(&cfg, fuse::ROOT_ID, &CString::new(".").unwrap())?;
         Ok((entry, VFS_MAX_INO))
     }
 
@@ -1018,6 +2389,61 @@ impl<D: AsyncDrive> BackendFileSystem<D> for PassthroughFs<D> {
     }
 }
 
+#[cfg(feature = "control-socket")]
+impl<D: AsyncDrive, S: BitmapSlice + Send + Sync> Introspect for PassthroughFs<D, S> {
+    fn stats(&self) -> Vec<(String, u64)> {
+        // Do not expect poisoned lock here, so safe to unwrap().
+        let inodes = self.inode_map.inodes.read().unwrap().len();
+        let handles = self.handle_map.handles.read().unwrap().len();
+
+        vec![
+            ("inodes".to_string(), inodes as u64),
+            ("handles".to_string(), handles as u64),
+        ]
+    }
+
+    fn handles(&self) -> Vec<HandleInfo> {
+        // Do not expect poisoned lock here, so safe to unwrap().
+        self.handle_map
+            .handles
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(handle, data)| HandleInfo {
+                handle: *handle,
+                inode: data.inode,
+                fd: data.get_handle_raw_fd(),
+            })
+            .collect()
+    }
+
+    fn inodes(&self, limit: usize) -> Vec<InodeInfo> {
+        // Don't resolve paths with the lock held, it may need to open file handles.
+        let inodes: Vec<Arc<InodeData>> = self
+            .inode_map
+            .inodes
+            .read()
+            .unwrap()
+            .iter()
+            .take(limit)
+            .map(|(_, data)| data.clone())
+            .collect();
+
+        inodes
+            .iter()
+            .map(|data| InodeInfo {
+                inode: data.inode,
+                refcount: data.refcount.load(Ordering::Relaxed),
+                mode: data.mode,
+                path: self
+                    .readlinkat_proc_file(data.inode)
+                    .ok()
+                    .map(|p| p.to_string_lossy().into_owned()),
+            })
+            .collect()
+    }
+}
+
 macro_rules! scoped_cred {
     ($name:ident, $ty:ty, $syscall_nr:expr) => {
         #[derive(Debug)]
@@ -1101,6 +2527,31 @@ fn set_creds(
     ScopedGid::new(gid).and_then(|gid| Ok((ScopedUid::new(uid)?, gid)))
 }
 
+// Clear `O_DIRECT` of the open file, returns false if it's not set.
+//
+// Direct I/O fails with EINVAL if the offset, size or buffer address isn't aligned to the logical
+// block size of the backing file system, and the buffers of FUSE requests aren't aligned. So the
+// handle falls back to buffered I/O instead of failing the request.
+fn clear_direct_io(fd: RawFd) -> io::Result<bool> {
+    // Safe because this doesn't modify any memory and we check the return value.
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if flags & libc::O_DIRECT == 0 {
+        return Ok(false);
+    }
+
+    // Safe because this doesn't modify any memory and we check the return value.
+    let res = unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_DIRECT) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    debug!("fuse: fall back to buffered I/O for fd {}", fd);
+
+    Ok(true)
+}
+
 fn ebadf() -> io::Error {
     io::Error::from_raw_os_error(libc::EBADF)
 }
@@ -1108,31 +2559,21 @@ fn ebadf() -> io::Error {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::abi::fuse_abi::{CreateIn, WRITE_CACHE, WRITE_KILL_PRIV};
     use crate::api::filesystem::*;
     use crate::api::{Vfs, VfsOptions};
+    use crate::transport::{FileReadWriteVolatile, FileVolatileSlice};
     use caps::{CapSet, Capability};
     use log;
     use std::ops::Deref;
+    use std::os::unix::ffi::OsStrExt;
     use vmm_sys_util::{tempdir::TempDir, tempfile::TempFile};
 
-    fn prepare_passthroughfs() -> PassthroughFs {
-        let source = TempDir::new().expect("Cannot create temporary directory.");
-        let parent_path =
-            TempDir::new_in(source.as_path()).expect("Cannot create temporary directory.");
-        let _child_path =
-            TempFile::new_in(parent_path.as_path()).expect("Cannot create temporary file.");
-
+    // Create a passthrough file system serving `root`, configured by `cfg`.
+    fn prepare_passthroughfs(root: &Path, cfg: Config) -> PassthroughFs {
         let fs_cfg = Config {
-            writeback: true,
-            do_import: true,
-            no_open: true,
-            inode_file_handles: false,
-            root_dir: source
-                .as_path()
-                .to_str()
-                .expect("source path to string")
-                .to_string(),
-            ..Default::default()
+            root_dir: root.to_str().expect("source path to string").to_string(),
+            ..cfg
         };
         let fs = PassthroughFs::<AsyncDriver, ()>::new(fs_cfg).unwrap();
         fs.import().unwrap();
@@ -1175,6 +2616,49 @@ mod tests {
         passthroughfs_no_open(false);
     }
 
+    #[test]
+    fn test_read_without_open() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::write(source.as_path().join("file"), b"stateless").unwrap();
+        let fs = prepare_passthroughfs(
+            source.as_path(),
+            Config {
+                no_open: true,
+                no_opendir: true,
+                ..Default::default()
+            },
+        );
+        let opts = fs
+            .init(FsOptions::ZERO_MESSAGE_OPEN | FsOptions::ZERO_MESSAGE_OPENDIR)
+            .unwrap();
+        assert!(opts.contains(FsOptions::ZERO_MESSAGE_OPEN | FsOptions::ZERO_MESSAGE_OPENDIR));
+
+        let ctx = Context::default();
+        let entry = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("file").unwrap())
+            .unwrap();
+        for offset in [0, 5] {
+            let mut w = VecWriter(Vec::new());
+            let n = fs
+                .read(&ctx, entry.inode, 0, &mut w, 0x100, offset, None, 0)
+                .unwrap();
+            assert_eq!(&w.0[..n], &b"stateless"[offset as usize..]);
+        }
+        // Both reads have been served by the backing file cached in the inode.
+        let data = fs.inode_map.get(entry.inode).unwrap();
+        let read_file = data.read_file.lock().unwrap();
+        assert_eq!(Arc::strong_count(read_file.as_ref().unwrap()), 1);
+        drop(read_file);
+
+        let mut names = Vec::new();
+        fs.readdir(&ctx, ROOT_ID, 0, 0x1000, 0, &mut |e| {
+            names.push(e.name.to_vec());
+            Ok(1)
+        })
+        .unwrap();
+        assert!(names.contains(&b"file".to_vec()));
+    }
+
     #[test]
     fn test_passthroughfs_inode_file_handles() {
         log::set_max_level(log::LevelFilter::Trace);
@@ -1193,20 +2677,16 @@ mod tests {
         let child_path =
             TempFile::new_in(parent_path.as_path()).expect("Cannot create temporary file.");
 
-        let fs_cfg = Config {
-            writeback: true,
-            do_import: true,
-            no_open: true,
-            inode_file_handles: true,
-            root_dir: source
-                .as_path()
-                .to_str()
-                .expect("source path to string")
-                .to_string(),
-            ..Default::default()
-        };
-        let fs = PassthroughFs::<AsyncDriver, ()>::new(fs_cfg).unwrap();
-        fs.import().unwrap();
+        let fs = prepare_passthroughfs(
+            source.as_path(),
+            Config {
+                writeback: true,
+                do_import: true,
+                no_open: true,
+                inode_file_handles: true,
+                ..Default::default()
+            },
+        );
 
         let ctx = Context::default();
 
@@ -1239,21 +2719,2597 @@ mod tests {
         //assert_eq!(matches!(data.file_or_handle, FileOrHandle::Handle(_)), true);
 
         let (_, duration) = fs.getattr(&ctx, c_entry.inode, None).unwrap();
-        assert_eq!(duration, fs.cfg.attr_timeout);
+        assert_eq!(duration, fs.cfg.load().attr_timeout);
 
         fs.destroy();
     }
 
     #[test]
     fn test_lookup_escape_root() {
-        let fs = prepare_passthroughfs();
-        let ctx = Context::default();
-
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let parent_path =
+            TempDir::new_in(source.as_path()).expect("Cannot create temporary directory.");
+        let _child_path =
+            TempFile::new_in(parent_path.as_path()).expect("Cannot create temporary file.");
+        let fs = prepare_passthroughfs(
+            source.as_path(),
+            Config {
+                writeback: true,
+                do_import: true,
+                no_open: true,
+                inode_file_handles: false,
+                ..Default::default()
+            },
+        );
+        let ctx = Context::default();
+
         let name = CString::new("..").unwrap();
         let entry = fs.lookup(&ctx, ROOT_ID, &name).unwrap();
         assert_eq!(entry.inode, ROOT_ID);
     }
 
+    #[test]
+    fn test_clone_range() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let data: Vec<u8> = (0..8192u32).map(|i| (i / 4096) as u8 + 1).collect();
+        std::fs::write(source.as_path().join("src"), &data).unwrap();
+        std::fs::write(source.as_path().join("dst"), b"").unwrap();
+
+        let fs = prepare_passthroughfs(
+            source.as_path(),
+            Config {
+                do_import: true,
+                ..Default::default()
+            },
+        );
+
+        let ctx = Context::default();
+        let src = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("src").unwrap())
+            .unwrap();
+        let dst = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("dst").unwrap())
+            .unwrap();
+
+        let (src_fh, _) = fs.open(&ctx, src.inode, libc::O_RDONLY as u32, 0).unwrap();
+        let (dst_fh, _) = fs.open(&ctx, dst.inode, libc::O_RDWR as u32, 0).unwrap();
+        let clone = |offset: u64, len: u64| {
+            fs.clone_range(
+                &ctx,
+                src.inode,
+                src_fh.unwrap(),
+                offset,
+                dst.inode,
+                dst_fh.unwrap(),
+                offset,
+                len,
+            )
+        };
+
+        match clone(0, 4096) {
+            Ok(count) => assert_eq!(count, 4096),
+            // The backing file system doesn't support reflink, the kernel falls back to copying
+            // the data itself, so nothing must have been written.
+            Err(e)
+                if e.raw_os_error() == Some(libc::EOPNOTSUPP)
+                    || e.raw_os_error() == Some(libc::EXDEV) =>
+            {
+                assert!(std::fs::read(source.as_path().join("dst"))
+                    .unwrap()
+                    .is_empty());
+                println!("skip test_clone_range, {}", e);
+                return;
+            }
+            Err(e) => panic!("failed to clone range, {}", e),
+        }
+        assert_eq!(
+            std::fs::read(source.as_path().join("dst")).unwrap(),
+            &data[..4096]
+        );
+        // A zero length clones the rest of the source file.
+        assert_eq!(clone(4096, 0).unwrap(), 4096);
+        assert_eq!(std::fs::read(source.as_path().join("dst")).unwrap(), data);
+
+        // The data isn't copied, both files share the physical extents.
+        let extents = |inode| fs.fiemap(&ctx, inode, 0, u64::MAX).unwrap();
+        let (src_extents, dst_extents) = (extents(src.inode), extents(dst.inode));
+        assert!(!dst_extents.is_empty());
+        assert_eq!(src_extents.len(), dst_extents.len());
+        for (s, d) in src_extents.iter().zip(dst_extents.iter()) {
+            assert_ne!(d.flags & FiemapExtent::SHARED, 0);
+            assert_eq!((d.logical, d.physical), (s.logical, s.physical));
+        }
+    }
+
+    #[test]
+    fn test_readahead_policy() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::create_dir(source.as_path().join("db")).unwrap();
+        std::fs::write(source.as_path().join("db/data"), b"").unwrap();
+        std::fs::write(source.as_path().join("log"), b"").unwrap();
+
+        // Rules match paths relative to the root, even if it's not given in canonical form.
+        let fs_cfg = Config {
+            root_dir: format!("{}/db/../", source.as_path().to_str().unwrap()),
+            readahead: ReadaheadPolicy::Sequential,
+            readahead_rules: vec![(PathBuf::from("db"), ReadaheadPolicy::Random)],
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<AsyncDriver, ()>::new(fs_cfg).unwrap();
+        fs.import().unwrap();
+
+        let ctx = Context::default();
+        let db = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("db").unwrap())
+            .unwrap();
+        let data = fs
+            .lookup(&ctx, db.inode, &CString::new("data").unwrap())
+            .unwrap();
+        let log = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("log").unwrap())
+            .unwrap();
+
+        assert_eq!(
+            fs.readahead_policy(&fs.cfg.load(), data.inode),
+            ReadaheadPolicy::Random
+        );
+        assert_eq!(
+            fs.readahead_policy(&fs.cfg.load(), log.inode),
+            ReadaheadPolicy::Sequential
+        );
+        fs.open(&ctx, data.inode, libc::O_RDONLY as u32, 0).unwrap();
+
+        assert_eq!(
+            ReadaheadPolicy::from_str("random").unwrap(),
+            ReadaheadPolicy::Random
+        );
+        ReadaheadPolicy::from_str("foo").unwrap_err();
+    }
+
+    #[test]
+    fn test_syncfs() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let fs = prepare_passthroughfs(source.as_path(), Config::default());
+        fs.syncfs(&Context::default()).unwrap();
+    }
+
+    #[test]
+    fn test_blksize_rules() {
+        use std::os::unix::fs::MetadataExt;
+
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::create_dir(source.as_path().join("chunks")).unwrap();
+        std::fs::write(source.as_path().join("chunks/data"), b"").unwrap();
+        std::fs::write(source.as_path().join("log"), b"").unwrap();
+
+        let fs = prepare_passthroughfs(
+            source.as_path(),
+            Config {
+                blksize_rules: vec![(PathBuf::from("chunks"), 1 << 20)],
+                ..Default::default()
+            },
+        );
+
+        let ctx = Context::default();
+        let chunks = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("chunks").unwrap())
+            .unwrap();
+        let data = fs
+            .lookup(&ctx, chunks.inode, &CString::new("data").unwrap())
+            .unwrap();
+        let log = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("log").unwrap())
+            .unwrap();
+
+        // Files without a rule report the block size of the backing file system.
+        let blksize = std::fs::metadata(source.as_path().join("log"))
+            .unwrap()
+            .blksize();
+        assert_eq!(log.attr.st_blksize as u64, blksize);
+        let (st, _) = fs.getattr(&ctx, log.inode, None).unwrap();
+        assert_eq!(st.st_blksize as u64, blksize);
+
+        assert_eq!(data.attr.st_blksize, 1 << 20);
+        let (st, _) = fs.getattr(&ctx, data.inode, None).unwrap();
+        assert_eq!(st.st_blksize, 1 << 20);
+    }
+
+    #[test]
+    fn test_verity() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::create_dir(source.as_path().join("dir")).unwrap();
+        std::fs::write(source.as_path().join("file"), vec![0x5au8; 8192]).unwrap();
+
+        let fs = prepare_passthroughfs(
+            source.as_path(),
+            Config {
+                require_verity: true,
+                ..Default::default()
+            },
+        );
+
+        let ctx = Context::default();
+        let dir = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("dir").unwrap())
+            .unwrap();
+        let file = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("file").unwrap())
+            .unwrap();
+
+        // Directories are not subject to `require_verity`.
+        fs.opendir(&ctx, dir.inode, libc::O_RDONLY as u32).unwrap();
+        let e = fs
+            .open(&ctx, file.inode, libc::O_RDONLY as u32, 0)
+            .unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EPERM));
+
+        // Neither creating files nor opening existing ones by create requests bypasses it.
+        let args = |flags: i32| CreateIn {
+            flags: flags as u32,
+            mode: 0o644,
+            umask: 0,
+            fuse_flags: 0,
+        };
+        for (name, flags) in [
+            ("new", libc::O_RDWR),
+            ("new", libc::O_RDWR | libc::O_EXCL),
+            ("file", libc::O_RDONLY),
+        ] {
+            let e = fs
+                .create(&ctx, ROOT_ID, &CString::new(name).unwrap(), args(flags))
+                .map(|_| ())
+                .unwrap_err();
+            assert_eq!(e.raw_os_error(), Some(libc::EPERM));
+        }
+        assert!(!source.as_path().join("new").exists());
+        let e = fs
+            .tmpfile(&ctx, ROOT_ID, 0o644, 0, libc::O_RDWR as u32)
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EPERM));
+
+        assert_eq!(
+            fs.enable_verity(&ctx, dir.inode)
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::EINVAL)
+        );
+
+        if let Err(e) = fs.enable_verity(&ctx, file.inode) {
+            // The backing file system isn't verity capable.
+            assert_eq!(e.raw_os_error(), Some(libc::EOPNOTSUPP));
+            return;
+        }
+        let digest = fs.measure_verity(&ctx, file.inode).unwrap();
+        assert_eq!(digest.len(), 32);
+        fs.open(&ctx, file.inode, libc::O_RDONLY as u32, 0).unwrap();
+        fs.create(
+            &ctx,
+            ROOT_ID,
+            &CString::new("file").unwrap(),
+            args(libc::O_RDONLY),
+        )
+        .unwrap();
+    }
+
+    #[cfg(feature = "control-socket")]
+    #[test]
+    fn test_introspect() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::write(source.as_path().join("file"), b"").unwrap();
+
+        let fs = prepare_passthroughfs(source.as_path(), Config::default());
+
+        let ctx = Context::default();
+        let file = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("file").unwrap())
+            .unwrap();
+        let (fh, _) = fs.open(&ctx, file.inode, libc::O_RDONLY as u32, 0).unwrap();
+
+        assert_eq!(
+            fs.stats(),
+            vec![("inodes".to_string(), 2), ("handles".to_string(), 1)]
+        );
+        let handles = fs.handles();
+        assert_eq!(handles.len(), 1);
+        assert_eq!(handles[0].handle, fh.unwrap());
+        assert_eq!(handles[0].inode, file.inode);
+
+        let inodes = fs.inodes(10);
+        assert_eq!(inodes.len(), 2);
+        assert_eq!(inodes[1].inode, file.inode);
+        assert_eq!(inodes[1].refcount, 1);
+        assert_eq!(
+            inodes[1].path.as_deref(),
+            source.as_path().join("file").to_str()
+        );
+        assert_eq!(fs.inodes(1).len(), 1);
+    }
+
+    #[test]
+    fn test_parallel_dirops() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let new_fs = |parallel_dirops: bool| {
+            let fs_cfg = Config {
+                parallel_dirops,
+                ..Default::default()
+            };
+            Arc::new(prepare_passthroughfs(source.as_path(), fs_cfg))
+        };
+
+        let fs = new_fs(false);
+        let opts = fs.init(FsOptions::PARALLEL_DIROPS).unwrap();
+        assert!(!opts.contains(FsOptions::PARALLEL_DIROPS));
+
+        let fs = new_fs(true);
+        let opts = fs.init(FsOptions::empty()).unwrap();
+        assert!(!opts.contains(FsOptions::PARALLEL_DIROPS));
+        let opts = fs.init(FsOptions::PARALLEL_DIROPS).unwrap();
+        assert!(opts.contains(FsOptions::PARALLEL_DIROPS));
+
+        // Create and look up files in the same directory from multiple threads.
+        let threads: Vec<_> = (0..8)
+            .map(|t| {
+                let fs = fs.clone();
+                std::thread::spawn(move || {
+                    let ctx = Context::default();
+                    for i in 0..32 {
+                        let name = CString::new(format!("f{}-{}", t, i)).unwrap();
+                        let args = crate::api::CreateIn {
+                            flags: libc::O_RDWR as u32,
+                            mode: 0o644,
+                            umask: 0,
+                            fuse_flags: 0,
+                        };
+                        let (entry, handle, _) = fs.create(&ctx, ROOT_ID, &name, args).unwrap();
+                        fs.release(&ctx, entry.inode, 0, handle.unwrap(), false, false, None)
+                            .unwrap();
+                        assert_eq!(fs.lookup(&ctx, ROOT_ID, &name).unwrap().inode, entry.inode);
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(std::fs::read_dir(source.as_path()).unwrap().count(), 8 * 32);
+    }
+
+    #[test]
+    fn test_atomic_o_trunc() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::write(source.as_path().join("file"), vec![0x5au8; 4096]).unwrap();
+
+        let fs = prepare_passthroughfs(source.as_path(), Config::default());
+        let opts = fs
+            .init(FsOptions::ATOMIC_O_TRUNC | FsOptions::ZERO_MESSAGE_OPEN)
+            .unwrap();
+        assert!(opts.contains(FsOptions::ATOMIC_O_TRUNC));
+
+        let ctx = Context::default();
+        let file = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("file").unwrap())
+            .unwrap();
+        assert_eq!(file.attr.st_size, 4096);
+
+        // The file is truncated by the open itself, there's no setattr in between.
+        let (fh, _) = fs
+            .open(&ctx, file.inode, (libc::O_WRONLY | libc::O_TRUNC) as u32, 0)
+            .unwrap();
+        let (st, _) = fs.getattr(&ctx, file.inode, fh).unwrap();
+        assert_eq!(st.st_size, 0);
+        assert_eq!(
+            std::fs::metadata(source.as_path().join("file"))
+                .unwrap()
+                .len(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_canonical_path() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::create_dir(source.as_path().join("dir")).unwrap();
+        std::fs::write(source.as_path().join("dir/file"), b"").unwrap();
+
+        let fs = prepare_passthroughfs(source.as_path(), Config::default());
+
+        let ctx = Context::default();
+        let dir = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("dir").unwrap())
+            .unwrap();
+        let file = fs
+            .lookup(&ctx, dir.inode, &CString::new("file").unwrap())
+            .unwrap();
+
+        let path = fs.canonical_path(&ctx, file.inode).unwrap();
+        assert_eq!(path.to_str().unwrap(), "/dir/file");
+        let path = fs.canonical_path(&ctx, ROOT_ID).unwrap();
+        assert_eq!(path.to_str().unwrap(), "/");
+
+        fs.unlink(&ctx, dir.inode, &CString::new("file").unwrap())
+            .unwrap();
+        let e = fs.canonical_path(&ctx, file.inode).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ENOENT));
+    }
+
+    #[test]
+    fn test_setattr_nsec_timestamps() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::write(source.as_path().join("file"), b"").unwrap();
+
+        let fs = prepare_passthroughfs(source.as_path(), Config::default());
+
+        let ctx = Context::default();
+        let file = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("file").unwrap())
+            .unwrap();
+        let (old, _) = fs.getattr(&ctx, file.inode, None).unwrap();
+
+        // Only set mtime, atime is left untouched.
+        let mut attr: libc::stat64 = unsafe { std::mem::zeroed() };
+        attr.st_mtime = 1_600_000_000;
+        attr.st_mtime_nsec = 123_456_789;
+        let (st, _) = fs
+            .setattr(&ctx, file.inode, attr, None, SetattrValid::MTIME)
+            .unwrap();
+        assert_eq!(st.st_mtime, 1_600_000_000);
+        assert_eq!(st.st_mtime_nsec, 123_456_789);
+        assert_eq!(st.st_atime, old.st_atime);
+        assert_eq!(st.st_atime_nsec, old.st_atime_nsec);
+
+        // Set atime to the current time, mtime is left untouched.
+        attr.st_atime = 0;
+        attr.st_atime_nsec = 0;
+        let (st, _) = fs
+            .setattr(
+                &ctx,
+                file.inode,
+                attr,
+                None,
+                SetattrValid::ATIME | SetattrValid::ATIME_NOW,
+            )
+            .unwrap();
+        assert!(st.st_atime >= old.st_atime);
+        assert_eq!(st.st_mtime, 1_600_000_000);
+        assert_eq!(st.st_mtime_nsec, 123_456_789);
+
+        let (st, _) = fs.getattr(&ctx, file.inode, None).unwrap();
+        assert_eq!(st.st_mtime_nsec, 123_456_789);
+    }
+
+    #[test]
+    fn test_open_direct_io() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::write(source.as_path().join("file"), vec![0x5au8; 8192]).unwrap();
+
+        let fs = prepare_passthroughfs(source.as_path(), Config::default());
+
+        let ctx = Context::default();
+        let file = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("file").unwrap())
+            .unwrap();
+
+        // The guest doesn't cache data of files opened with O_DIRECT, whether the backing file
+        // system supports direct I/O or not.
+        let (fh, opts) = fs
+            .open(
+                &ctx,
+                file.inode,
+                (libc::O_RDONLY | libc::O_DIRECT) as u32,
+                0,
+            )
+            .unwrap();
+        assert!(opts.contains(OpenOptions::DIRECT_IO));
+        let data = fs.handle_map.get(fh.unwrap(), file.inode).unwrap();
+        let fd = data.get_handle_raw_fd();
+
+        // If the backing file is opened with O_DIRECT, it could fall back to buffered I/O.
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        assert_eq!(clear_direct_io(fd).unwrap(), flags & libc::O_DIRECT != 0);
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        assert_eq!(flags & libc::O_DIRECT, 0);
+        assert!(!clear_direct_io(fd).unwrap());
+
+        let (_, opts) = fs.open(&ctx, file.inode, libc::O_RDONLY as u32, 0).unwrap();
+        assert!(!opts.contains(OpenOptions::DIRECT_IO));
+    }
+
+    #[test]
+    fn test_xattr_on_error() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::write(source.as_path().join("file"), b"").unwrap();
+        // A version 2 capability set with CAP_NET_RAW permitted.
+        let mut cap = vec![0x0u8; 20];
+        cap[..4].copy_from_slice(&0x0200_0000u32.to_le_bytes());
+        cap[4..8].copy_from_slice(&(1u32 << 13).to_le_bytes());
+        let security = CString::new("security.capability").unwrap();
+        let system = CString::new("system.fuse_backend_test").unwrap();
+
+        let new_fs = |policy: XattrErrorPolicy| {
+            let fs = prepare_passthroughfs(
+                source.as_path(),
+                Config {
+                    xattr: true,
+                    xattr_on_error: policy,
+                    ..Default::default()
+                },
+            );
+            let file = fs
+                .lookup(&Context::default(), ROOT_ID, &CString::new("file").unwrap())
+                .unwrap();
+            (fs, file.inode)
+        };
+        let ctx = Context::default();
+
+        let (fs, inode) = new_fs(XattrErrorPolicy::Fail);
+        let e = fs.setxattr(&ctx, inode, &system, b"v", 0).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EOPNOTSUPP));
+
+        // Copying a file with a capability succeeds whether or not the daemon is privileged.
+        let (fs, inode) = new_fs(XattrErrorPolicy::Ignore);
+        fs.setxattr(&ctx, inode, &security, &cap, 0).unwrap();
+        fs.setxattr(&ctx, inode, &system, b"v", 0).unwrap();
+        assert!(fs.getxattr(&ctx, inode, &system, 16).is_err());
+
+        let (fs, inode) = new_fs(XattrErrorPolicy::Remap);
+        fs.setxattr(&ctx, inode, &system, b"v", 0).unwrap();
+        match fs.getxattr(&ctx, inode, &system, 16).unwrap() {
+            GetxattrReply::Value(v) => assert_eq!(v, b"v"),
+            _ => panic!("unexpected getxattr reply"),
+        }
+        // The remapped name is listed as the original one.
+        let names = match fs.listxattr(&ctx, inode, 256).unwrap() {
+            ListxattrReply::Names(v) => v,
+            _ => panic!("unexpected listxattr reply"),
+        };
+        let names: Vec<&[u8]> = names.split(|&b| b == 0).collect();
+        assert!(names.contains(&system.as_bytes()));
+        assert!(!names.contains(&&b"user.system.fuse_backend_test"[..]));
+        match fs.listxattr(&ctx, inode, 0).unwrap() {
+            ListxattrReply::Count(n) => {
+                assert_eq!(n as usize, names.len() - 1 + names.concat().len())
+            }
+            _ => panic!("unexpected listxattr reply"),
+        }
+
+        // The remapped names can't be forged or accessed directly by the client.
+        let remapped = CString::new("user.system.fuse_backend_test").unwrap();
+        let forged = CString::new("user.security.capability").unwrap();
+        for e in [
+            fs.getxattr(&ctx, inode, &remapped, 0).err().unwrap(),
+            fs.setxattr(&ctx, inode, &forged, &cap, 0).unwrap_err(),
+            fs.removexattr(&ctx, inode, &remapped).unwrap_err(),
+        ] {
+            assert_eq!(e.raw_os_error(), Some(libc::EPERM));
+        }
+
+        fs.removexattr(&ctx, inode, &system).unwrap();
+        let e = fs.getxattr(&ctx, inode, &system, 0).err().unwrap();
+        assert_eq!(e.raw_os_error(), Some(libc::ENODATA));
+
+        assert_eq!(
+            "ignore".parse::<XattrErrorPolicy>().unwrap(),
+            XattrErrorPolicy::Ignore
+        );
+        assert!("foo".parse::<XattrErrorPolicy>().is_err());
+    }
+
+    struct TestObserver {
+        fs: Mutex<Option<std::sync::Weak<PassthroughFs<AsyncDriver, ()>>>>,
+        events: Mutex<Vec<(bool, Inode, u64)>>,
+    }
+
+    impl InodeObserver for TestObserver {
+        fn on_create(&self, inode: Inode, st: &libc::stat64) {
+            self.events
+                .lock()
+                .unwrap()
+                .push((true, inode, st.st_size as u64));
+            // Re-entering the file system must not deadlock.
+            if let Some(fs) = self.fs.lock().unwrap().as_ref().and_then(|fs| fs.upgrade()) {
+                fs.getattr(&Context::default(), inode, None).unwrap();
+            }
+        }
+
+        fn on_forget(&self, inode: Inode, nlookup: u64) {
+            self.events.lock().unwrap().push((false, inode, nlookup));
+            if let Some(fs) = self.fs.lock().unwrap().as_ref().and_then(|fs| fs.upgrade()) {
+                assert!(fs.getattr(&Context::default(), inode, None).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn test_inode_hooks() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::write(source.as_path().join("a"), b"aaa").unwrap();
+        std::fs::write(source.as_path().join("b"), b"bb").unwrap();
+
+        let observer = Arc::new(TestObserver {
+            fs: Mutex::new(None),
+            events: Mutex::new(Vec::new()),
+        });
+        let fs_cfg = Config {
+            inode_hooks: Some(observer.clone()),
+            ..Default::default()
+        };
+        let fs = Arc::new(prepare_passthroughfs(source.as_path(), fs_cfg));
+        *observer.fs.lock().unwrap() = Some(Arc::downgrade(&fs));
+        let ctx = Context::default();
+
+        let a = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("a").unwrap())
+            .unwrap();
+        let a2 = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("a").unwrap())
+            .unwrap();
+        assert_eq!(a.inode, a2.inode);
+        let b = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("b").unwrap())
+            .unwrap();
+        fs.forget(&ctx, a.inode, 1);
+        fs.batch_forget(&ctx, vec![(a.inode, 1), (b.inode, 1), (ROOT_ID, 1)]);
+
+        let events = observer.events.lock().unwrap().clone();
+        assert_eq!(events.len(), 5);
+        assert!(events[0].0);
+        assert_eq!(events[0].1, ROOT_ID);
+        assert_eq!(events[1], (true, a.inode, 3));
+        assert_eq!(events[2], (true, b.inode, 2));
+        assert_eq!(events[3], (false, a.inode, 1));
+        assert_eq!(events[4], (false, b.inode, 1));
+    }
+
+    #[test]
+    fn test_name_validation() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let fs = prepare_passthroughfs(source.as_path(), Config::default());
+        let ctx = Context::default();
+        let args = crate::abi::fuse_abi::CreateIn {
+            flags: libc::O_RDWR as u32,
+            mode: libc::S_IFREG | 0o644,
+            umask: 0,
+            fuse_flags: 0,
+        };
+        fn errno<T>(res: io::Result<T>) -> i32 {
+            res.err().unwrap().raw_os_error().unwrap()
+        }
+
+        let long = CString::new(vec![b'a'; 256]).unwrap();
+        assert_eq!(errno(fs.lookup(&ctx, ROOT_ID, &long)), libc::ENAMETOOLONG);
+        assert_eq!(
+            errno(fs.create(&ctx, ROOT_ID, &long, args)),
+            libc::ENAMETOOLONG
+        );
+        assert_eq!(
+            errno(fs.mkdir(&ctx, ROOT_ID, &long, 0o755, 0)),
+            libc::ENAMETOOLONG
+        );
+
+        let slash = CString::new("a/b").unwrap();
+        assert_eq!(errno(fs.lookup(&ctx, ROOT_ID, &slash)), libc::EINVAL);
+        assert_eq!(errno(fs.create(&ctx, ROOT_ID, &slash, args)), libc::EINVAL);
+
+        // Dot and dotdot are only allowed by lookup.
+        let dotdot = CString::new("..").unwrap();
+        assert_eq!(
+            errno(fs.mkdir(&ctx, ROOT_ID, &dotdot, 0o755, 0)),
+            libc::EINVAL
+        );
+        fs.lookup(&ctx, ROOT_ID, &dotdot).unwrap();
+        assert!(source.as_path().read_dir().unwrap().next().is_none());
+
+        let name = CString::new(vec![b'a'; 255]).unwrap();
+        fs.create(&ctx, ROOT_ID, &name, args).unwrap();
+
+        let fs = prepare_passthroughfs(
+            source.as_path(),
+            Config {
+                max_name_len: 8,
+                ..Default::default()
+            },
+        );
+        assert_eq!(errno(fs.lookup(&ctx, ROOT_ID, &name)), libc::ENAMETOOLONG);
+    }
+
+    #[test]
+    fn test_symlink_metadata() {
+        use std::os::unix::fs::MetadataExt;
+
+        // Changing owners and trusted xattrs needs root.
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let target = source.as_path().join("target");
+        let link = source.as_path().join("link");
+        std::fs::write(&target, b"data").unwrap();
+        std::os::unix::fs::symlink("target", &link).unwrap();
+        let target_meta = std::fs::metadata(&target).unwrap();
+        let fs = prepare_passthroughfs(
+            source.as_path(),
+            Config {
+                xattr: true,
+                ..Default::default()
+            },
+        );
+        let ctx = Context::default();
+        let entry = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("link").unwrap())
+            .unwrap();
+
+        // Changing the owner acts on the link itself.
+        let mut attr = entry.attr;
+        attr.st_uid = 1000;
+        attr.st_gid = 1001;
+        let (st, _) = fs
+            .setattr(
+                &ctx,
+                entry.inode,
+                attr,
+                None,
+                SetattrValid::UID | SetattrValid::GID,
+            )
+            .unwrap();
+        assert_eq!((st.st_uid, st.st_gid), (1000, 1001));
+        let meta = std::fs::symlink_metadata(&link).unwrap();
+        assert_eq!((meta.uid(), meta.gid()), (1000, 1001));
+        let meta = std::fs::metadata(&target).unwrap();
+        assert_eq!(
+            (meta.uid(), meta.gid()),
+            (target_meta.uid(), target_meta.gid())
+        );
+
+        // Mode changes of symlinks are ignored.
+        attr.st_mode = libc::S_IFLNK | 0o600;
+        fs.setattr(&ctx, entry.inode, attr, None, SetattrValid::MODE)
+            .unwrap();
+        assert_eq!(
+            std::fs::metadata(&target).unwrap().mode(),
+            target_meta.mode()
+        );
+
+        // Xattrs are set on the link itself.
+        let name = CString::new("trusted.fuse").unwrap();
+        fs.setxattr(&ctx, entry.inode, &name, b"link", 0).unwrap();
+        match fs.getxattr(&ctx, entry.inode, &name, 16).unwrap() {
+            GetxattrReply::Value(v) => assert_eq!(v, b"link"),
+            _ => panic!("unexpected getxattr reply"),
+        }
+        let target_entry = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("target").unwrap())
+            .unwrap();
+        assert!(fs.getxattr(&ctx, target_entry.inode, &name, 16).is_err());
+        fs.removexattr(&ctx, entry.inode, &name).unwrap();
+        assert!(fs.getxattr(&ctx, entry.inode, &name, 16).is_err());
+    }
+
+    #[test]
+    fn test_prime() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::create_dir(source.as_path().join("dir")).unwrap();
+        std::fs::write(source.as_path().join("dir/a"), b"a").unwrap();
+        let fs = prepare_passthroughfs(source.as_path(), Config::default());
+        let ctx = Context::default();
+
+        fs.prime(&[Path::new("dir/a"), Path::new("/dir")]).unwrap();
+        // The root, "dir" and "dir/a".
+        assert_eq!(fs.inode_map.get_map_mut().len(), 3);
+        let dir = fs.inode_map.get(ROOT_ID + 1).unwrap();
+        assert_eq!(dir.refcount.load(Ordering::Relaxed), 2);
+
+        // Lookups from the kernel hit the primed inodes.
+        let entry = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("dir").unwrap())
+            .unwrap();
+        assert_eq!(entry.inode, ROOT_ID + 1);
+
+        let e = fs
+            .prime(&[Path::new("dir/b"), Path::new("dir/a"), Path::new("../x")])
+            .unwrap_err();
+        let msg = e.to_string();
+        assert!(msg.contains("dir/b"));
+        assert!(msg.contains("../x"));
+        assert!(!msg.contains("dir/a:"));
+        assert_eq!(fs.inode_map.get_map_mut().len(), 3);
+    }
+
+    #[test]
+    fn test_prime_symlinks() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let root = source.as_path();
+        std::fs::create_dir(root.join("dir")).unwrap();
+        std::fs::write(root.join("dir/f"), b"f").unwrap();
+        // l0 -> l1 -> ... -> l40 -> dir, so resolving l0 follows 41 symlinks.
+        for i in 0..40 {
+            std::os::unix::fs::symlink(format!("l{}", i + 1), root.join(format!("l{}", i)))
+                .unwrap();
+        }
+        std::os::unix::fs::symlink("/dir", root.join("l40")).unwrap();
+        std::os::unix::fs::symlink("self", root.join("self")).unwrap();
+        std::os::unix::fs::symlink("./f/..", root.join("dir/up")).unwrap();
+        std::os::unix::fs::symlink("../dir", root.join("dir/back")).unwrap();
+        let fs = prepare_passthroughfs(root, Config::default());
+        let ctx = Context::default();
+        let f = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("dir").unwrap())
+            .and_then(|dir| fs.lookup(&ctx, dir.inode, &CString::new("f").unwrap()))
+            .unwrap();
+
+        // Lookup returns the symlink itself, even a self-referential one.
+        let entry = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("self").unwrap())
+            .unwrap();
+        assert_eq!(entry.attr.st_mode & libc::S_IFMT, libc::S_IFLNK);
+        assert_eq!(
+            fs.prime_path(&fs.cfg.load(), Path::new("self")).unwrap(),
+            entry.inode
+        );
+
+        let e = fs
+            .prime_path(&fs.cfg.load(), Path::new("self/f"))
+            .unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ELOOP));
+        let e = fs
+            .prime_path(&fs.cfg.load(), Path::new("l0/f"))
+            .unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ELOOP));
+        assert_eq!(
+            fs.prime_path(&fs.cfg.load(), Path::new("l1/f")).unwrap(),
+            f.inode
+        );
+        assert_eq!(
+            fs.prime_path(&fs.cfg.load(), Path::new("dir/back/f"))
+                .unwrap(),
+            f.inode
+        );
+        let e = fs
+            .prime_path(&fs.cfg.load(), Path::new("dir/up/f"))
+            .unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ENOTDIR));
+    }
+
+    struct VecReader(Vec<u8>);
+
+    impl io::Read for VecReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = std::cmp::min(buf.len(), self.0.len());
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0.drain(..len);
+            Ok(len)
+        }
+    }
+
+    impl ZeroCopyReader for VecReader {
+        fn read_to(
+            &mut self,
+            f: &mut dyn FileReadWriteVolatile,
+            count: usize,
+            off: u64,
+        ) -> io::Result<usize> {
+            let len = std::cmp::min(count, self.0.len());
+            // Safe because the slice is valid during the call.
+            let slice = unsafe { FileVolatileSlice::new(self.0.as_mut_ptr(), len) };
+            let len = f.write_at_volatile(slice, off)?;
+            self.0.drain(..len);
+            Ok(len)
+        }
+    }
+
+    struct VecWriter(Vec<u8>);
+
+    impl io::Write for VecWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl ZeroCopyWriter for VecWriter {
+        fn write_from(
+            &mut self,
+            f: &mut dyn FileReadWriteVolatile,
+            count: usize,
+            off: u64,
+        ) -> io::Result<usize> {
+            let mut buf = vec![0u8; count];
+            // Safe because the slice is valid during the call.
+            let slice = unsafe { FileVolatileSlice::new(buf.as_mut_ptr(), count) };
+            let len = f.read_at_volatile(slice, off)?;
+            self.0.extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn test_write_killpriv_v2() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let path = source.as_path().join("suid");
+        std::fs::write(&path, b"").unwrap();
+        let fs = prepare_passthroughfs(
+            source.as_path(),
+            Config {
+                killpriv_v2: true,
+                ..Default::default()
+            },
+        );
+        let opts = fs.init(FsOptions::HANDLE_KILLPRIV_V2).unwrap();
+        assert!(opts.contains(FsOptions::HANDLE_KILLPRIV_V2));
+        let ctx = Context::default();
+        let entry = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("suid").unwrap())
+            .unwrap();
+        let (handle, _) = fs.open(&ctx, entry.inode, libc::O_RDWR as u32, 0).unwrap();
+        let handle = handle.unwrap();
+
+        let write = |fuse_flags: u32| {
+            let mut r = VecReader(b"data".to_vec());
+            fs.write(
+                &ctx,
+                entry.inode,
+                handle,
+                &mut r,
+                4,
+                0,
+                None,
+                false,
+                0,
+                fuse_flags,
+            )
+            .unwrap();
+            std::fs::metadata(&path).unwrap().permissions().mode() & 0o7777
+        };
+        let reset = || {
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o6755)).unwrap();
+        };
+
+        // The bits are kept unless the kernel asks for clearing them.
+        reset();
+        assert_eq!(write(WRITE_CACHE), 0o6755);
+        reset();
+        assert_eq!(write(WRITE_KILL_PRIV), 0o755);
+
+        // Without CAP_FSETID, the bits cleared by the backing file system are restored.
+        let has_fsetid = caps::has_cap(None, CapSet::Effective, Capability::CAP_FSETID).unwrap();
+        if has_fsetid {
+            fs.cap_fsetid.store(false, Ordering::Relaxed);
+            caps::drop(None, CapSet::Effective, Capability::CAP_FSETID).unwrap();
+            reset();
+            assert_eq!(write(WRITE_CACHE), 0o6755);
+            reset();
+            assert_eq!(write(WRITE_KILL_PRIV), 0o755);
+            caps::raise(None, CapSet::Effective, Capability::CAP_FSETID).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_fsyncdir() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::create_dir(source.as_path().join("dir")).unwrap();
+        let fs = prepare_passthroughfs(source.as_path(), Config::default());
+        let ctx = Context::default();
+        let dir = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("dir").unwrap())
+            .unwrap();
+        let (handle, _) = fs.opendir(&ctx, dir.inode, libc::O_RDONLY as u32).unwrap();
+        let handle = handle.unwrap();
+        let args = crate::abi::fuse_abi::CreateIn {
+            flags: libc::O_RDWR as u32,
+            mode: 0o644,
+            umask: 0,
+            fuse_flags: 0,
+        };
+        fs.create(&ctx, dir.inode, &CString::new("file").unwrap(), args)
+            .unwrap();
+
+        fs.fsyncdir(&ctx, dir.inode, false, handle).unwrap();
+        fs.fsyncdir(&ctx, dir.inode, true, handle).unwrap();
+        assert!(fs.fsyncdir(&ctx, dir.inode, false, handle + 1).is_err());
+
+        // Directories are opened on demand if opendir is disabled, even if open is not.
+        fs.no_opendir.store(true, Ordering::Relaxed);
+        fs.fsyncdir(&ctx, dir.inode, false, 0).unwrap();
+    }
+
+    #[test]
+    fn test_append() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let path = source.as_path().join("log");
+        std::fs::write(&path, b"").unwrap();
+        let fs = Arc::new(prepare_passthroughfs(source.as_path(), Config::default()));
+        let ctx = Context::default();
+        let entry = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("log").unwrap())
+            .unwrap();
+        let inode = entry.inode;
+        let append = move |fs: &PassthroughFs<AsyncDriver, ()>, handle: Handle, data: &[u8]| {
+            let mut r = VecReader(data.to_vec());
+            // The offset from the kernel is stale.
+            fs.write(
+                &Context::default(),
+                inode,
+                handle,
+                &mut r,
+                data.len() as u32,
+                0,
+                None,
+                false,
+                libc::O_APPEND as u32,
+                0,
+            )
+            .unwrap();
+        };
+
+        // Concurrent appenders don't overwrite each other.
+        let flags = (libc::O_WRONLY | libc::O_APPEND) as u32;
+        let threads: Vec<_> = [b'a', b'b']
+            .iter()
+            .map(|&tag| {
+                let (handle, _) = fs.open(&ctx, entry.inode, flags, 0).unwrap();
+                let fs = fs.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..100 {
+                        append(&fs, handle.unwrap(), &[tag; 8]);
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        let data = std::fs::read(&path).unwrap();
+        assert_eq!(data.len(), 1600);
+        assert!(data.chunks(8).all(|c| c == [b'a'; 8] || c == [b'b'; 8]));
+
+        // Files are opened for each write without open requests.
+        fs.no_open.store(true, Ordering::Relaxed);
+        append(&fs, 0, b"tail");
+        assert!(std::fs::read(&path).unwrap().ends_with(b"tail"));
+
+        // The kernel handles `O_APPEND` with the writeback cache, so the offset is honored.
+        let fs_cfg = Config {
+            writeback: true,
+            ..Default::default()
+        };
+        let fs = prepare_passthroughfs(source.as_path(), fs_cfg);
+        fs.init(FsOptions::WRITEBACK_CACHE).unwrap();
+        let args = crate::abi::fuse_abi::CreateIn {
+            flags,
+            mode: 0o644,
+            umask: 0,
+            fuse_flags: 0,
+        };
+        let (entry, handle, _) = fs
+            .create(&ctx, ROOT_ID, &CString::new("cached").unwrap(), args)
+            .unwrap();
+        for data in [b"1111", b"2222"] {
+            let mut r = VecReader(data.to_vec());
+            fs.write(
+                &ctx,
+                entry.inode,
+                handle.unwrap(),
+                &mut r,
+                4,
+                0,
+                None,
+                true,
+                flags,
+                0,
+            )
+            .unwrap();
+        }
+        assert_eq!(
+            std::fs::read(source.as_path().join("cached")).unwrap(),
+            b"2222"
+        );
+    }
+
+    #[test]
+    fn test_reconfigure() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::write(source.as_path().join("file"), b"data").unwrap();
+        let cfg = Config {
+            root_dir: source.as_path().to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::<AsyncDriver, ()>::new(cfg.clone()).unwrap();
+        fs.import().unwrap();
+        let ctx = Context::default();
+        let name = CString::new("file").unwrap();
+        let entry = fs.lookup(&ctx, ROOT_ID, &name).unwrap();
+        let (handle, _) = fs.open(&ctx, entry.inode, libc::O_RDWR as u32, 0).unwrap();
+        let handle = handle.unwrap();
+        let write = |data: &[u8]| {
+            let mut r = VecReader(data.to_vec());
+            fs.write(
+                &ctx,
+                entry.inode,
+                handle,
+                &mut r,
+                data.len() as u32,
+                0,
+                None,
+                false,
+                0,
+                0,
+            )
+        };
+        write(b"DATA").unwrap();
+
+        // Options needing a remount are refused, and nothing is changed.
+        let e = fs
+            .reconfigure(Config {
+                root_dir: "/".to_string(),
+                attr_timeout: Duration::from_secs(60),
+                ..cfg.clone()
+            })
+            .unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EINVAL));
+        assert_eq!(fs.cfg.load().attr_timeout, cfg.attr_timeout);
+
+        fs.reconfigure(Config {
+            attr_timeout: Duration::from_secs(60),
+            entry_timeout: Duration::from_secs(30),
+            write_policy: WritePolicy::read_only(),
+            ..cfg.clone()
+        })
+        .unwrap();
+        let entry = fs.lookup(&ctx, ROOT_ID, &name).unwrap();
+        assert_eq!(entry.attr_timeout, Duration::from_secs(60));
+        assert_eq!(entry.entry_timeout, Duration::from_secs(30));
+        let (_, timeout) = fs.getattr(&ctx, entry.inode, Some(handle)).unwrap();
+        assert_eq!(timeout, Duration::from_secs(60));
+
+        // The open handle is kept, but can't write anymore.
+        let e = write(b"data").unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EROFS));
+        let mut w = VecWriter(Vec::new());
+        let n = fs
+            .read(&ctx, entry.inode, handle, &mut w, 0x100, 0, None, 0)
+            .unwrap();
+        assert_eq!(&w.0[..n], b"DATA");
+
+        fs.reconfigure(cfg).unwrap();
+        write(b"data").unwrap();
+    }
+
+    #[test]
+    fn test_read_replaced_file() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let path = source.as_path().join("log");
+        for shared_fd in [false, true] {
+            std::fs::write(&path, b"original").unwrap();
+            let fs = prepare_passthroughfs(
+                source.as_path(),
+                Config {
+                    shared_fd,
+                    ..Default::default()
+                },
+            );
+            let ctx = Context::default();
+            let name = CString::new("log").unwrap();
+            let old = fs.lookup(&ctx, ROOT_ID, &name).unwrap();
+            let (handle, _) = fs.open(&ctx, old.inode, libc::O_RDONLY as u32, 0).unwrap();
+            let handle = handle.unwrap();
+
+            // Rotate the file, the old one is unlinked by the rename.
+            let rotated = source.as_path().join("log.new");
+            std::fs::write(&rotated, b"replaced, and longer").unwrap();
+            std::fs::rename(&rotated, &path).unwrap();
+
+            let mut w = VecWriter(Vec::new());
+            let n = fs
+                .read(&ctx, old.inode, handle, &mut w, 0x100, 0, None, 0)
+                .unwrap();
+            assert_eq!(&w.0[..n], b"original");
+            let (st, _) = fs.getattr(&ctx, old.inode, Some(handle)).unwrap();
+            assert_eq!(st.st_size, 8);
+            assert_eq!(st.st_nlink, 0);
+            fs.fsync(&ctx, old.inode, false, handle).unwrap();
+
+            // The path now resolves to the new file, opened independently of the old handle.
+            let new = fs.lookup(&ctx, ROOT_ID, &name).unwrap();
+            assert_ne!(new.inode, old.inode);
+            let (new_handle, _) = fs.open(&ctx, new.inode, libc::O_RDONLY as u32, 0).unwrap();
+            let mut w = VecWriter(Vec::new());
+            let n = fs
+                .read(
+                    &ctx,
+                    new.inode,
+                    new_handle.unwrap(),
+                    &mut w,
+                    0x100,
+                    0,
+                    None,
+                    0,
+                )
+                .unwrap();
+            assert_eq!(&w.0[..n], b"replaced, and longer");
+            fs.release(&ctx, old.inode, 0, handle, false, false, None)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_shared_fd() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::write(source.as_path().join("file"), b"").unwrap();
+        let fs = prepare_passthroughfs(
+            source.as_path(),
+            Config {
+                shared_fd: true,
+                ..Default::default()
+            },
+        );
+        let ctx = Context::default();
+        let entry = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("file").unwrap())
+            .unwrap();
+        let backing_fds = |handles: &[Handle]| {
+            handles
+                .iter()
+                .map(|h| {
+                    fs.handle_map
+                        .get(*h, entry.inode)
+                        .unwrap()
+                        .get_handle_raw_fd()
+                })
+                .collect::<std::collections::HashSet<_>>()
+        };
+
+        let mut handles = Vec::new();
+        for _ in 0..50 {
+            let (handle, _) = fs
+                .open(&ctx, entry.inode, libc::O_RDONLY as u32, 0)
+                .unwrap();
+            handles.push(handle.unwrap());
+        }
+        assert_eq!(backing_fds(&handles).len(), 1);
+
+        // Opening for writing upgrades to a read-write backing file, shared by later handles.
+        for i in 0..50 {
+            let flags = if i % 2 == 0 {
+                libc::O_RDWR
+            } else {
+                libc::O_RDONLY
+            };
+            let (handle, _) = fs.open(&ctx, entry.inode, flags as u32, 0).unwrap();
+            handles.push(handle.unwrap());
+        }
+        assert_eq!(handles.len(), 100);
+        assert_eq!(backing_fds(&handles).len(), 2);
+        assert_eq!(backing_fds(&handles[50..]).len(), 1);
+
+        // Writes and reads use their own offsets.
+        let mut r = VecReader(b"shared".to_vec());
+        fs.write(
+            &ctx,
+            entry.inode,
+            handles[50],
+            &mut r,
+            6,
+            0,
+            None,
+            false,
+            0,
+            0,
+        )
+        .unwrap();
+        let data = fs.handle_map.get(handles[99], entry.inode).unwrap();
+        let mut buf = [0u8; 6];
+        std::os::unix::fs::FileExt::read_exact_at(&*data.file, &mut buf, 0).unwrap();
+        assert_eq!(&buf, b"shared");
+        drop(data);
+
+        // Files opened with flags affecting the open file description are not shared.
+        let (append, _) = fs
+            .open(
+                &ctx,
+                entry.inode,
+                (libc::O_WRONLY | libc::O_APPEND) as u32,
+                0,
+            )
+            .unwrap();
+        let mut all = handles.clone();
+        all.push(append.unwrap());
+        assert_eq!(backing_fds(&all).len(), 3);
+        fs.release(&ctx, entry.inode, 0, append.unwrap(), false, false, None)
+            .unwrap();
+
+        for handle in handles {
+            fs.release(&ctx, entry.inode, 0, handle, false, false, None)
+                .unwrap();
+        }
+        assert!(fs.shared_files.lock().unwrap().is_empty());
+    }
+
+    fn lgetxattr(path: &Path, name: &str) -> io::Result<Vec<u8>> {
+        let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        let name = CString::new(name).unwrap();
+        let mut buf = vec![0u8; 256];
+        let res = unsafe {
+            libc::lgetxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        buf.truncate(res as usize);
+        Ok(buf)
+    }
+
+    #[test]
+    fn test_security_context() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let root = source.as_path();
+        std::fs::create_dir(root.join("dir")).unwrap();
+        std::fs::write(root.join("dir/target"), b"t").unwrap();
+        let label = b"system_u:object_r:tmp_t:s0\0".to_vec();
+        let dir = CString::new(root.join("dir").as_os_str().as_bytes()).unwrap();
+        let name = CString::new("security.selinux").unwrap();
+        let res = unsafe {
+            libc::setxattr(
+                dir.as_ptr(),
+                name.as_ptr(),
+                label.as_ptr() as *const libc::c_void,
+                label.len(),
+                0,
+            )
+        };
+        if res != 0 {
+            println!("skip test_security_context, {}", io::Error::last_os_error());
+            return;
+        }
+
+        let fs = prepare_passthroughfs(
+            root,
+            Config {
+                xattr: true,
+                ..Default::default()
+            },
+        );
+        let ctx = Context::default();
+        let parent = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("dir").unwrap())
+            .unwrap();
+        let secctx = SecContext {
+            name: name.clone(),
+            value: b"system_u:object_r:user_tmp_t:s0\0".to_vec(),
+        };
+
+        // The symlink itself is labeled, not its target.
+        let link = CString::new("link").unwrap();
+        let entry = fs
+            .symlink(&ctx, &CString::new("target").unwrap(), parent.inode, &link)
+            .unwrap();
+        fs.init_security_context(&ctx, parent.inode, &link, entry.inode, &secctx)
+            .unwrap();
+        assert_eq!(
+            lgetxattr(&root.join("dir/link"), "security.selinux").unwrap(),
+            secctx.value
+        );
+        assert_ne!(
+            lgetxattr(&root.join("dir/target"), "security.selinux").ok(),
+            Some(secctx.value.clone())
+        );
+
+        let fifo = CString::new("fifo").unwrap();
+        let entry = fs
+            .mknod(&ctx, parent.inode, &fifo, libc::S_IFIFO | 0o600, 0, 0)
+            .unwrap();
+        fs.init_security_context(&ctx, parent.inode, &fifo, entry.inode, &secctx)
+            .unwrap();
+        assert_eq!(
+            lgetxattr(&root.join("dir/fifo"), "security.selinux").unwrap(),
+            secctx.value
+        );
+    }
+
+    #[test]
+    fn test_generation() {
+        use std::os::unix::fs::MetadataExt;
+
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let fs = prepare_passthroughfs(source.as_path(), Config::default());
+        let ctx = Context::default();
+
+        let old_path = source.as_path().join("old");
+        std::fs::write(&old_path, b"old").unwrap();
+        let old = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("old").unwrap())
+            .unwrap();
+        assert_eq!(old.generation, 0);
+        // The inode ID can't be reused as long as the inode holds the file open.
+        fs.forget(&ctx, old.inode, 1);
+        std::fs::remove_file(&old_path).unwrap();
+
+        // Keep creating files until the backing file system reuses the inode ID.
+        let mut new_path = None;
+        for i in 0..100 {
+            let path = source.as_path().join(format!("new{}", i));
+            std::fs::write(&path, b"new").unwrap();
+            if std::fs::metadata(&path).unwrap().ino() == old.attr.st_ino {
+                new_path = Some(path);
+                break;
+            }
+        }
+        let new_path = match new_path {
+            Some(p) => p,
+            None => {
+                println!("backing file system doesn't reuse inode IDs");
+                return;
+            }
+        };
+
+        let name = CString::new(new_path.file_name().unwrap().as_bytes()).unwrap();
+        let new = fs.lookup(&ctx, ROOT_ID, &name).unwrap();
+        assert_eq!(new.attr.st_ino, old.attr.st_ino);
+        assert_ne!(new.inode, old.inode);
+        assert_ne!(new.generation, old.generation);
+
+        let (handle, _) = fs.open(&ctx, new.inode, libc::O_RDONLY as u32, 0).unwrap();
+        let data = fs.handle_map.get(handle.unwrap(), new.inode).unwrap();
+        let (_guard, mut file) = data.get_file_mut();
+        let mut buf = Vec::new();
+        io::Read::read_to_end(&mut file, &mut buf).unwrap();
+        assert_eq!(buf, b"new");
+
+        // Looking up the same file again keeps its generation.
+        let again = fs.lookup(&ctx, ROOT_ID, &name).unwrap();
+        assert_eq!(again.inode, new.inode);
+        assert_eq!(again.generation, new.generation);
+    }
+
+    #[test]
+    fn test_generation_limit() {
+        let map = InodeMap::new();
+        let key = |ino| InodeAltKey::Ids {
+            ino,
+            dev: 1,
+            mnt: 0,
+        };
+
+        assert_eq!(map.next_generation_locked(&key(0), None), 0);
+        assert_eq!(map.next_generation_locked(&key(0), None), 1);
+        for ino in 1..MAX_GENERATIONS as u64 {
+            assert_eq!(map.next_generation_locked(&key(ino), None), 0);
+        }
+        assert_eq!(map.generations.lock().unwrap().keys.len(), MAX_GENERATIONS);
+
+        // Remembered keys are still bumped when full.
+        assert_eq!(map.next_generation_locked(&key(0), None), 2);
+
+        // A new key drops all of them, and forgotten keys never get a generation used before.
+        let ino = MAX_GENERATIONS as u64;
+        assert_eq!(map.next_generation_locked(&key(ino), None), 3);
+        assert_eq!(map.generations.lock().unwrap().keys.len(), 1);
+        assert_eq!(map.next_generation_locked(&key(0), None), 3);
+        assert_eq!(map.next_generation_locked(&key(1), None), 3);
+        assert_eq!(map.next_generation_locked(&key(1), None), 4);
+    }
+
+    #[test]
+    fn test_statx() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let fs = prepare_passthroughfs(source.as_path(), Config::default());
+        let ctx = Context::default();
+
+        std::fs::write(source.as_path().join("file"), b"hello").unwrap();
+        let entry = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("file").unwrap())
+            .unwrap();
+        let (stx, _) = fs
+            .statx(&ctx, entry.inode, None, 0, libc::STATX_ALL)
+            .unwrap();
+        assert_eq!(stx.size, 5);
+        assert_eq!(stx.ino, entry.attr.st_ino);
+        assert_eq!(stx.mode as u32, entry.attr.st_mode);
+        if stx.mask & libc::STATX_BTIME == 0 {
+            println!("backing file system doesn't report birth time");
+            return;
+        }
+        assert_ne!(stx.btime.tv_sec, 0);
+    }
+
+    #[test]
+    fn test_readlink_long_target() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let fs = prepare_passthroughfs(source.as_path(), Config::default());
+        let ctx = Context::default();
+
+        for (name, len) in [("short", 1usize), ("long", libc::PATH_MAX as usize - 1)] {
+            let target = "a".repeat(len);
+            std::os::unix::fs::symlink(&target, source.as_path().join(name)).unwrap();
+            let entry = fs
+                .lookup(&ctx, ROOT_ID, &CString::new(name).unwrap())
+                .unwrap();
+            assert_eq!(fs.readlink(&ctx, entry.inode).unwrap(), target.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_write_policy() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::write(source.as_path().join("file"), b"data").unwrap();
+        let fs = prepare_passthroughfs(
+            source.as_path(),
+            Config {
+                xattr: true,
+                write_policy: WritePolicy {
+                    xattr: true,
+                    ..WritePolicy::read_only()
+                },
+                ..Default::default()
+            },
+        );
+        let ctx = Context::default();
+        let erofs =
+            |r: io::Result<()>| assert_eq!(r.unwrap_err().raw_os_error(), Some(libc::EROFS));
+
+        let entry = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("file").unwrap())
+            .unwrap();
+        let tag = CString::new("user.tag").unwrap();
+        fs.setxattr(&ctx, entry.inode, &tag, b"v1", 0).unwrap();
+        fs.removexattr(&ctx, entry.inode, &tag).unwrap();
+
+        let (handle, _) = fs.open(&ctx, entry.inode, libc::O_RDWR as u32, 0).unwrap();
+        let mut r = VecReader(b"new".to_vec());
+        erofs(
+            fs.write(
+                &ctx,
+                entry.inode,
+                handle.unwrap(),
+                &mut r,
+                3,
+                0,
+                None,
+                false,
+                0,
+                0,
+            )
+            .map(|_| ()),
+        );
+        erofs(
+            fs.open(&ctx, entry.inode, (libc::O_RDWR | libc::O_TRUNC) as u32, 0)
+                .map(|_| ()),
+        );
+        let mut attr = entry.attr;
+        attr.st_mode = 0o600;
+        erofs(
+            fs.setattr(&ctx, entry.inode, attr, None, SetattrValid::MODE)
+                .map(|_| ()),
+        );
+        erofs(fs.unlink(&ctx, ROOT_ID, &CString::new("file").unwrap()));
+
+        // The file can't be mapped writable into the DAX window either.
+        #[cfg(feature = "virtiofs")]
+        {
+            use crate::abi::virtio_fs::{RemovemappingOne, SetupmappingFlags};
+            use crate::transport::FsCacheReqHandler;
+
+            struct NoMapping;
+            impl FsCacheReqHandler for NoMapping {
+                fn map(&mut self, _: u64, _: u64, _: u64, _: u64, _: RawFd) -> io::Result<()> {
+                    Ok(())
+                }
+                fn unmap(&mut self, _: Vec<RemovemappingOne>) -> io::Result<()> {
+                    Ok(())
+                }
+            }
+
+            let flags = SetupmappingFlags::READ | SetupmappingFlags::WRITE;
+            erofs(fs.setupmapping(
+                &ctx,
+                entry.inode,
+                0,
+                0,
+                4096,
+                flags.bits(),
+                0,
+                &mut NoMapping,
+            ));
+            let flags = SetupmappingFlags::READ.bits();
+            fs.setupmapping(&ctx, entry.inode, 0, 0, 4096, flags, 0, &mut NoMapping)
+                .unwrap();
+        }
+
+        assert_eq!(
+            std::fs::read(source.as_path().join("file")).unwrap(),
+            b"data"
+        );
+    }
+
+    #[test]
+    fn test_readdir_dot_entries() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::create_dir(source.as_path().join("dir")).unwrap();
+        std::fs::write(source.as_path().join("dir/file"), b"").unwrap();
+        let fs = prepare_passthroughfs(source.as_path(), Config::default());
+        let ctx = Context::default();
+        let root_ino =
+            std::os::unix::fs::MetadataExt::ino(&std::fs::metadata(source.as_path()).unwrap());
+        let dir_ino = std::os::unix::fs::MetadataExt::ino(
+            &std::fs::metadata(source.as_path().join("dir")).unwrap(),
+        );
+        let dir = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("dir").unwrap())
+            .unwrap();
+
+        let list = |inode: Inode| {
+            let (handle, _) = fs.opendir(&ctx, inode, libc::O_RDONLY as u32).unwrap();
+            let mut entries = Vec::new();
+            fs.readdir(&ctx, inode, handle.unwrap(), 4096, 0, &mut |e| {
+                entries.push((e.name.to_vec(), e.ino, e.type_, e.offset));
+                Ok(1)
+            })
+            .unwrap();
+            let mut plus = Vec::new();
+            fs.readdirplus(&ctx, inode, handle.unwrap(), 4096, 0, &mut |e, entry| {
+                plus.push((e.name.to_vec(), e.ino, entry.inode, entry.attr.st_ino));
+                Ok(1)
+            })
+            .unwrap();
+            (entries, plus)
+        };
+
+        // ".." of the mount root must refer to the root itself, not to the host parent.
+        let (entries, plus) = list(ROOT_ID);
+        assert_eq!(entries[0].0, b".");
+        assert_eq!(entries[0].1, root_ino);
+        assert_eq!(entries[1].0, b"..");
+        assert_eq!(entries[1].1, root_ino);
+        assert_eq!(entries[0].2, libc::DT_DIR as u32);
+        assert_eq!(entries[1].2, libc::DT_DIR as u32);
+        assert!(entries[0].3 != 0 && entries[1].3 != 0);
+        assert_eq!(plus[0], (b".".to_vec(), root_ino, 0, root_ino));
+        assert_eq!(plus[1], (b"..".to_vec(), root_ino, 0, root_ino));
+
+        let (entries, plus) = list(dir.inode);
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].0, b".");
+        assert_eq!(entries[0].1, dir_ino);
+        assert_eq!(entries[1].0, b"..");
+        assert_eq!(entries[1].1, root_ino);
+        assert_eq!(entries[2].0, b"file");
+        // No lookup reference is taken for "." and "..".
+        assert_eq!(plus[0], (b".".to_vec(), dir_ino, 0, dir_ino));
+        assert_eq!(plus[1], (b"..".to_vec(), root_ino, 0, root_ino));
+        assert!(plus[2].2 != 0);
+
+        // Resuming after each dot entry continues with the following ones.
+        let (handle, _) = fs.opendir(&ctx, dir.inode, libc::O_RDONLY as u32).unwrap();
+        let resume = |offset: u64| {
+            let mut names = Vec::new();
+            fs.readdir(&ctx, dir.inode, handle.unwrap(), 4096, offset, &mut |e| {
+                names.push(e.name.to_vec());
+                Ok(1)
+            })
+            .unwrap();
+            names
+        };
+        assert_eq!(resume(entries[0].3), vec![b"..".to_vec(), b"file".to_vec()]);
+        assert_eq!(resume(entries[1].3), vec![b"file".to_vec()]);
+    }
+
+    #[test]
+    fn test_readdir_resume() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        for i in 0..16 {
+            std::fs::write(source.as_path().join(format!("file{}", i)), b"").unwrap();
+        }
+        let fs = prepare_passthroughfs(source.as_path(), Config::default());
+        let ctx = Context::default();
+        let (handle, _) = fs.opendir(&ctx, ROOT_ID, libc::O_RDONLY as u32).unwrap();
+        let list = |offset: u64| {
+            let mut entries = Vec::new();
+            fs.readdir(&ctx, ROOT_ID, handle.unwrap(), 4096, offset, &mut |e| {
+                entries.push((e.name.to_vec(), e.offset));
+                Ok(1)
+            })
+            .unwrap();
+            entries
+        };
+
+        // Resuming at the offset of any entry, including the small offsets of "." and ".." the
+        // backing file system may also use for real entries, continues right after it.
+        let entries = list(0);
+        assert_eq!(entries.len(), 18);
+        for (i, (_, offset)) in entries.iter().enumerate() {
+            assert_eq!(list(*offset), entries[i + 1..].to_vec());
+        }
+    }
+
+    #[test]
+    fn test_async_read() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let content: Vec<u8> = (0..64 * 4096).map(|i| (i / 4096) as u8).collect();
+        std::fs::write(source.as_path().join("file"), &content).unwrap();
+        let new_fs = |async_read: bool| {
+            let fs_cfg = Config {
+                async_read,
+                ..Default::default()
+            };
+            Arc::new(prepare_passthroughfs(source.as_path(), fs_cfg))
+        };
+
+        let fs = new_fs(false);
+        let opts = fs.init(FsOptions::ASYNC_READ).unwrap();
+        assert!(!opts.contains(FsOptions::ASYNC_READ));
+
+        let fs = new_fs(true);
+        let opts = fs.init(FsOptions::empty()).unwrap();
+        assert!(!opts.contains(FsOptions::ASYNC_READ));
+        let opts = fs.init(FsOptions::ASYNC_READ).unwrap();
+        assert!(opts.contains(FsOptions::ASYNC_READ));
+
+        // Reads of the same handle served concurrently, in reverse order, each get the data at
+        // their own offset.
+        let ctx = Context::default();
+        let entry = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("file").unwrap())
+            .unwrap();
+        let (handle, _) = fs
+            .open(&ctx, entry.inode, libc::O_RDONLY as u32, 0)
+            .unwrap();
+        let handle = handle.unwrap();
+        let threads: Vec<_> = (0..64u64)
+            .rev()
+            .map(|i| {
+                let fs = fs.clone();
+                std::thread::spawn(move || {
+                    let mut w = VecWriter(Vec::new());
+                    let ctx = Context::default();
+                    fs.read(&ctx, entry.inode, handle, &mut w, 4096, i * 4096, None, 0)
+                        .unwrap();
+                    (i, w.0)
+                })
+            })
+            .collect();
+        for t in threads {
+            let (i, data) = t.join().unwrap();
+            assert_eq!(data, vec![i as u8; 4096]);
+        }
+    }
+
+    #[test]
+    fn test_fiemap() {
+        use std::os::unix::fs::FileExt;
+
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let file = File::create(source.as_path().join("a")).unwrap();
+        file.write_all_at(&[1u8; 4096], 0).unwrap();
+        file.write_all_at(&[2u8; 4096], 1 << 20).unwrap();
+        file.sync_all().unwrap();
+        let fs = prepare_passthroughfs(source.as_path(), Config::default());
+        let ctx = Context::default();
+        let a = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("a").unwrap())
+            .unwrap();
+
+        let extents = match fs.fiemap(&ctx, a.inode, 0, u64::MAX) {
+            Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => return,
+            res => res.unwrap(),
+        };
+        // Extents are contiguous, and the hole is reported as an unwritten extent.
+        let mut pos = 0;
+        for e in extents.iter() {
+            assert_eq!(e.logical, pos);
+            pos += e.length;
+        }
+        assert_eq!(pos, (1 << 20) + 4096);
+        let hole = extents
+            .iter()
+            .find(|e| e.logical <= 4096 && e.logical + e.length > 4096)
+            .unwrap();
+        assert_eq!(
+            hole.flags & FiemapExtent::UNWRITTEN,
+            FiemapExtent::UNWRITTEN
+        );
+        assert_eq!(hole.physical, 0);
+        assert_eq!(hole.logical + hole.length, 1 << 20);
+        assert!(extents[0].physical != 0);
+        assert_ne!(extents.last().unwrap().flags & FiemapExtent::LAST, 0);
+
+        // A range in the hole only reports the hole.
+        let extents = fs.fiemap(&ctx, a.inode, 8192, 4096).unwrap();
+        assert_eq!(extents.len(), 1);
+        assert_eq!(extents[0].logical, 8192);
+        assert_eq!(extents[0].length, 4096);
+
+        // Reflinked files share the physical extents, if the backing file system supports it.
+        let b = File::create(source.as_path().join("b")).unwrap();
+        // Safe because this doesn't modify any memory and we check the return value.
+        if unsafe { libc::ioctl(b.as_raw_fd(), libc::FICLONE, file.as_raw_fd()) } < 0 {
+            return;
+        }
+        let b = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("b").unwrap())
+            .unwrap();
+        let data = |inode| {
+            fs.fiemap(&ctx, inode, 0, u64::MAX)
+                .unwrap()
+                .into_iter()
+                .filter(|e| e.flags & FiemapExtent::UNWRITTEN == 0)
+                .collect::<Vec<_>>()
+        };
+        let (a_extents, b_extents) = (data(a.inode), data(b.inode));
+        assert_eq!(a_extents.len(), b_extents.len());
+        for (a, b) in a_extents.iter().zip(b_extents.iter()) {
+            assert_eq!(a.physical, b.physical);
+            assert_ne!(b.flags & FiemapExtent::SHARED, 0);
+        }
+    }
+
+    #[test]
+    fn test_io_yield_interval() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::write(source.as_path().join("file"), b"").unwrap();
+        let fs = prepare_passthroughfs(
+            source.as_path(),
+            Config {
+                io_yield_interval: 4096,
+                ..Default::default()
+            },
+        );
+        let yields = Arc::new(AtomicU64::new(0));
+        let counter = yields.clone();
+        fs.set_io_yield_hook(move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        let ctx = Context::default();
+        let entry = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("file").unwrap())
+            .unwrap();
+        let (handle, _) = fs.open(&ctx, entry.inode, libc::O_RDWR as u32, 0).unwrap();
+        let handle = handle.unwrap();
+
+        let data: Vec<u8> = (0..3 * 4096 + 100).map(|i| i as u8).collect();
+        let mut r = VecReader(data.clone());
+        let size = data.len() as u32;
+        let n = fs
+            .write(
+                &ctx,
+                entry.inode,
+                handle,
+                &mut r,
+                size,
+                0,
+                None,
+                false,
+                0,
+                0,
+            )
+            .unwrap();
+        assert_eq!(n, data.len());
+        assert_eq!(yields.load(Ordering::Relaxed), 3);
+
+        let mut w = VecWriter(Vec::new());
+        let n = fs
+            .read(&ctx, entry.inode, handle, &mut w, size, 0, None, 0)
+            .unwrap();
+        assert_eq!(n, data.len());
+        assert_eq!(w.0, data);
+        assert_eq!(yields.load(Ordering::Relaxed), 6);
+
+        // Short reads stop early without yielding any further.
+        let mut w = VecWriter(Vec::new());
+        let n = fs
+            .read(&ctx, entry.inode, handle, &mut w, 4 * 4096, 4096, None, 0)
+            .unwrap();
+        assert_eq!(n, 2 * 4096 + 100);
+        assert_eq!(yields.load(Ordering::Relaxed), 8);
+
+        // Requests within the interval are not split.
+        let mut w = VecWriter(Vec::new());
+        fs.read(&ctx, entry.inode, handle, &mut w, 4096, 0, None, 0)
+            .unwrap();
+        assert_eq!(yields.load(Ordering::Relaxed), 8);
+    }
+
+    #[test]
+    fn test_forget_nlookup() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::create_dir(source.as_path().join("dir")).unwrap();
+        std::fs::write(source.as_path().join("dir/file"), b"").unwrap();
+        let fs = prepare_passthroughfs(source.as_path(), Config::default());
+        let ctx = Context::default();
+        let name = CString::new("dir").unwrap();
+        let refcount = |inode: Inode| {
+            fs.inode_map
+                .get(inode)
+                .map(|data| data.refcount.load(Ordering::Relaxed))
+                .ok()
+        };
+
+        let dir = fs.lookup(&ctx, ROOT_ID, &name).unwrap();
+        assert_eq!(fs.lookup(&ctx, ROOT_ID, &name).unwrap().inode, dir.inode);
+        assert_eq!(refcount(dir.inode), Some(2));
+        fs.forget(&ctx, dir.inode, 1);
+        assert_eq!(refcount(dir.inode), Some(1));
+        fs.getattr(&ctx, dir.inode, None).unwrap();
+        fs.forget(&ctx, dir.inode, 1);
+        assert_eq!(refcount(dir.inode), None);
+        assert!(fs.getattr(&ctx, dir.inode, None).is_err());
+
+        // Entries which don't make it into the readdirplus reply keep no reference.
+        let dir = fs.lookup(&ctx, ROOT_ID, &name).unwrap();
+        let (handle, _) = fs.opendir(&ctx, dir.inode, libc::O_RDONLY as u32).unwrap();
+        let mut looked_up = Vec::new();
+        // The error is swallowed, as "." and ".." have been added already.
+        fs.readdirplus(
+            &ctx,
+            dir.inode,
+            handle.unwrap(),
+            4096,
+            0,
+            &mut |e, entry| {
+                if e.name == b"file" {
+                    looked_up.push(entry.inode);
+                    return Err(io::Error::from_raw_os_error(libc::EIO));
+                }
+                Ok(1)
+            },
+        )
+        .unwrap();
+        assert_eq!(looked_up.len(), 1);
+        assert_eq!(refcount(looked_up[0]), None);
+
+        let mut looked_up = Vec::new();
+        fs.readdirplus(
+            &ctx,
+            dir.inode,
+            handle.unwrap(),
+            4096,
+            0,
+            &mut |e, entry| {
+                if e.name == b"file" {
+                    looked_up.push(entry.inode);
+                    return Ok(0);
+                }
+                Ok(1)
+            },
+        )
+        .unwrap();
+        assert_eq!(looked_up.len(), 1);
+        assert_eq!(refcount(looked_up[0]), None);
+
+        let mut looked_up = Vec::new();
+        fs.readdirplus(
+            &ctx,
+            dir.inode,
+            handle.unwrap(),
+            4096,
+            0,
+            &mut |e, entry| {
+                if e.name == b"file" {
+                    looked_up.push(entry.inode);
+                }
+                Ok(1)
+            },
+        )
+        .unwrap();
+        assert_eq!(refcount(looked_up[0]), Some(1));
+        fs.forget(&ctx, looked_up[0], 1);
+        assert_eq!(refcount(looked_up[0]), None);
+        assert_eq!(refcount(dir.inode), Some(1));
+    }
+
+    #[test]
+    fn test_id_offset() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let ids = IdOffset {
+            uid_base: 100000,
+            gid_base: 200000,
+            range: 65536,
+        };
+        assert_eq!(ids.host_uid(1000), 101000);
+        assert_eq!(ids.guest_gid(201000), 1000);
+        assert_eq!(ids.host_uid(65536), NOBODY_ID);
+        assert_eq!(ids.guest_uid(0), NOBODY_ID);
+        assert_eq!(ids.guest_uid(165536), NOBODY_ID);
+
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        // The mapped ids need permissions to create files in the root directory.
+        std::fs::set_permissions(source.as_path(), PermissionsExt::from_mode(0o777)).unwrap();
+        let fs = prepare_passthroughfs(
+            source.as_path(),
+            Config {
+                id_offset: Some(ids),
+                ..Default::default()
+            },
+        );
+
+        let ctx = Context::default();
+        let name = CString::new("file").unwrap();
+        let args = crate::abi::fuse_abi::CreateIn {
+            flags: libc::O_RDWR as u32,
+            mode: 0o644,
+            umask: 0,
+            fuse_flags: 0,
+        };
+        let (entry, _handle, _) = fs.create(&ctx, ROOT_ID, &name, args).unwrap();
+        assert_eq!((entry.attr.st_uid, entry.attr.st_gid), (0, 0));
+        let meta = std::fs::metadata(source.as_path().join("file")).unwrap();
+        assert_eq!((meta.uid(), meta.gid()), (100000, 200000));
+        let (st, _) = fs.getattr(&ctx, entry.inode, None).unwrap();
+        assert_eq!((st.st_uid, st.st_gid), (0, 0));
+
+        let mut attr: libc::stat64 = unsafe { std::mem::zeroed() };
+        attr.st_uid = 1000;
+        attr.st_gid = 70000;
+        let (st, _) = fs
+            .setattr(
+                &ctx,
+                entry.inode,
+                attr,
+                None,
+                SetattrValid::UID | SetattrValid::GID,
+            )
+            .unwrap();
+        let meta = std::fs::metadata(source.as_path().join("file")).unwrap();
+        assert_eq!((meta.uid(), meta.gid()), (101000, NOBODY_ID));
+        assert_eq!((st.st_uid, st.st_gid), (1000, NOBODY_ID));
+    }
+
+    #[test]
+    fn test_sticky_dir() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let tmp = source.as_path().join("tmp");
+        std::fs::create_dir(&tmp).unwrap();
+        std::fs::set_permissions(&tmp, PermissionsExt::from_mode(0o1777)).unwrap();
+        for name in ["a", "b", "c"] {
+            std::fs::write(tmp.join(name), b"").unwrap();
+            std::os::unix::fs::chown(tmp.join(name), Some(1000), Some(1000)).unwrap();
+        }
+        let fs = prepare_passthroughfs(
+            source.as_path(),
+            Config {
+                enforce_sticky: true,
+                ..Default::default()
+            },
+        );
+
+        let ctx = |uid| Context {
+            uid,
+            gid: uid,
+            ..Default::default()
+        };
+        let name = |name| CString::new(name).unwrap();
+        let dir = fs.lookup(&ctx(0), ROOT_ID, &name("tmp")).unwrap().inode;
+
+        // Other users can't remove or rename the files of the owner.
+        let err = fs
+            .rename(&ctx(2000), dir, &name("a"), dir, &name("d"), 0)
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+        let err = fs.unlink(&ctx(2000), dir, &name("a")).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+        assert!(tmp.join("a").exists());
+
+        // Nor replace them.
+        std::fs::write(tmp.join("mine"), b"").unwrap();
+        std::os::unix::fs::chown(tmp.join("mine"), Some(2000), Some(2000)).unwrap();
+        let err = fs
+            .rename(&ctx(2000), dir, &name("mine"), dir, &name("a"), 0)
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+
+        // The owner of the file and privileged users can.
+        fs.rename(&ctx(1000), dir, &name("a"), dir, &name("d"), 0)
+            .unwrap();
+        fs.unlink(&ctx(0), dir, &name("b")).unwrap();
+        assert!(!tmp.join("b").exists());
+
+        // The rule is only enforced if enabled.
+        let mut cfg = Config::clone(&fs.cfg.load());
+        cfg.enforce_sticky = false;
+        let fs = PassthroughFs::<AsyncDriver, ()>::new(cfg).unwrap();
+        fs.import().unwrap();
+        let dir = fs.lookup(&ctx(0), ROOT_ID, &name("tmp")).unwrap().inode;
+        fs.unlink(&ctx(2000), dir, &name("c")).unwrap();
+    }
+
+    #[test]
+    fn test_mandatory_lock() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let path = source.as_path().join("locked");
+        std::fs::write(&path, b"").unwrap();
+        let fs = prepare_passthroughfs(
+            source.as_path(),
+            Config {
+                posix_locks: true,
+                ..Default::default()
+            },
+        );
+        let opts = fs.init(FsOptions::POSIX_LOCKS).unwrap();
+        assert!(opts.contains(FsOptions::POSIX_LOCKS));
+        let ctx = Context::default();
+        let entry = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("locked").unwrap())
+            .unwrap();
+        let (handle, _) = fs.open(&ctx, entry.inode, libc::O_RDWR as u32, 0).unwrap();
+        let handle = handle.unwrap();
+
+        let write = |owner: u64, offset: u64| {
+            let mut r = VecReader(b"data".to_vec());
+            fs.write(
+                &ctx,
+                entry.inode,
+                handle,
+                &mut r,
+                4,
+                offset,
+                Some(owner),
+                false,
+                0,
+                0,
+            )
+        };
+        let lock = FileLock {
+            start: 0,
+            end: 99,
+            lock_type: libc::F_WRLCK as u32,
+            pid: 1,
+        };
+        fs.setlk(&ctx, entry.inode, handle, 1, lock, 0).unwrap();
+        let e = fs.setlk(&ctx, entry.inode, handle, 2, lock, 0).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EAGAIN));
+        // Waiting for the lock would block the worker thread.
+        let e = fs
+            .setlkw(&ctx, entry.inode, handle, 2, lock, 0)
+            .unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EAGAIN));
+
+        // Advisory locks don't block IO.
+        write(2, 0).unwrap();
+
+        // Setgid without group execute enables mandatory locking.
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o2644)).unwrap();
+        let e = write(2, 96).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EAGAIN));
+        let mut w = VecWriter(Vec::new());
+        let e = fs
+            .read(&ctx, entry.inode, handle, &mut w, 4, 0, Some(2), 0)
+            .unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EAGAIN));
+        write(1, 0).unwrap();
+        write(2, 100).unwrap();
+
+        // Closing the file releases the locks of the owner.
+        fs.flush(&ctx, entry.inode, handle, 1).unwrap();
+        write(2, 0).unwrap();
+    }
+
+    #[test]
+    fn test_mount_flags() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let path = source.as_path().join("suid");
+        std::fs::write(&path, b"").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o6755)).unwrap();
+        let fs = prepare_passthroughfs(
+            source.as_path(),
+            Config {
+                nosuid: true,
+                ..Default::default()
+            },
+        );
+        let ctx = Context::default();
+        let name = |name| CString::new(name).unwrap();
+
+        let entry = fs.lookup(&ctx, ROOT_ID, &name("suid")).unwrap();
+        assert_eq!(entry.attr.st_mode & 0o7777, 0o755);
+        let (st, _) = fs.getattr(&ctx, entry.inode, None).unwrap();
+        assert_eq!(st.st_mode & 0o7777, 0o755);
+        fs.open(&ctx, entry.inode, (libc::O_RDONLY | FMODE_EXEC) as u32, 0)
+            .unwrap();
+
+        // Setgid without group execute marks mandatory locking, and is kept.
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o2644)).unwrap();
+        let (st, _) = fs.getattr(&ctx, entry.inode, None).unwrap();
+        assert_eq!(st.st_mode & 0o7777, 0o2644);
+
+        let mut cfg = Config::clone(&fs.cfg.load());
+        cfg.nosuid = false;
+        cfg.noexec = true;
+        cfg.nodev = true;
+        let fs = PassthroughFs::<AsyncDriver, ()>::new(cfg).unwrap();
+        fs.import().unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o4755)).unwrap();
+        let entry = fs.lookup(&ctx, ROOT_ID, &name("suid")).unwrap();
+        assert_eq!(entry.attr.st_mode & 0o7777, 0o644);
+        let e = fs.access(&ctx, entry.inode, libc::X_OK as u32).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EACCES));
+        let e = fs
+            .open(&ctx, entry.inode, (libc::O_RDONLY | FMODE_EXEC) as u32, 0)
+            .unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EACCES));
+        fs.open(&ctx, entry.inode, libc::O_RDONLY as u32, 0)
+            .unwrap();
+
+        // Changing the mode keeps the hidden setuid and execute bits of the backing file.
+        let mut attr = entry.attr;
+        attr.st_mode = libc::S_IFREG | 0o640;
+        let (st, _) = fs
+            .setattr(&ctx, entry.inode, attr, None, SetattrValid::MODE)
+            .unwrap();
+        assert_eq!(st.st_mode & 0o7777, 0o640);
+        let host = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(host & 0o7777, 0o4751);
+
+        // Directories are still searchable.
+        std::fs::create_dir(source.as_path().join("dir")).unwrap();
+        let dir = fs.lookup(&ctx, ROOT_ID, &name("dir")).unwrap();
+        assert_ne!(dir.attr.st_mode & 0o111, 0);
+
+        // Device nodes can't be opened.
+        let null = CString::new(source.as_path().join("null").into_os_string().into_vec()).unwrap();
+        if unsafe { libc::mknod(null.as_ptr(), libc::S_IFCHR | 0o666, libc::makedev(1, 3)) } == 0 {
+            let dev = fs.lookup(&ctx, ROOT_ID, &name("null")).unwrap();
+            let e = fs
+                .open(&ctx, dev.inode, libc::O_RDWR as u32, 0)
+                .unwrap_err();
+            assert_eq!(e.raw_os_error(), Some(libc::EACCES));
+        }
+    }
+
+    #[test]
+    fn test_import_from_fd() {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let parent = TempDir::new().expect("Cannot create temporary directory.");
+        let root = parent.as_path().join("root");
+        std::fs::create_dir_all(root.join("dir")).unwrap();
+        std::fs::write(root.join("dir/file"), b"hello").unwrap();
+        let root_file = std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_PATH | libc::O_DIRECTORY)
+            .open(&root)
+            .unwrap();
+        // Nothing may be reached through the path of the root any more.
+        let moved = parent.as_path().join("moved");
+        std::fs::rename(&root, &moved).unwrap();
+
+        let fs = PassthroughFs::<AsyncDriver, ()>::new(Config {
+            root_dir: root.to_str().unwrap().to_string(),
+            readahead_rules: vec![(PathBuf::from("dir"), ReadaheadPolicy::Random)],
+            ..Default::default()
+        })
+        .unwrap();
+        let file = std::fs::File::open(moved.join("dir/file")).unwrap();
+        fs.import_from_fd(file).unwrap_err();
+        fs.import_from_fd(root_file).unwrap();
+
+        let ctx = Context::default();
+        let dir = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("dir").unwrap())
+            .unwrap();
+        let entry = fs
+            .lookup(&ctx, dir.inode, &CString::new("file").unwrap())
+            .unwrap();
+        assert_eq!(entry.attr.st_size, 5);
+        assert_eq!(
+            fs.readahead_policy(&fs.cfg.load(), entry.inode),
+            ReadaheadPolicy::Random
+        );
+        let parent_entry = fs
+            .lookup(&ctx, ROOT_ID, &CString::new("..").unwrap())
+            .unwrap();
+        assert_eq!(parent_entry.inode, ROOT_ID);
+
+        let (handle, _) = fs
+            .open(&ctx, entry.inode, libc::O_RDONLY as u32, 0)
+            .unwrap();
+        let mut w = VecWriter(Vec::new());
+        let n = fs
+            .read(&ctx, entry.inode, handle.unwrap(), &mut w, 5, 0, None, 0)
+            .unwrap();
+        assert_eq!(n, 5);
+        assert_eq!(w.0, b"hello");
+    }
+
+    struct SyntheticContent(Vec<u8>);
+
+    impl SyntheticFile for SyntheticContent {
+        fn stat(&self) -> io::Result<libc::stat64> {
+            let mut st: libc::stat64 = unsafe { std::mem::zeroed() };
+            st.st_mode = libc::S_IFREG | 0o444;
+            st.st_size = self.0.len() as i64;
+            st.st_nlink = 1;
+            Ok(st)
+        }
+
+        fn read(&self, offset: u64, size: u32) -> io::Result<Vec<u8>> {
+            let start = std::cmp::min(offset as usize, self.0.len());
+            let end = std::cmp::min(start + size as usize, self.0.len());
+            Ok(self.0[start..end].to_vec())
+        }
+    }
+
+    struct SyntheticDir(Vec<(CString, Arc<dyn SyntheticFile>)>);
+
+    impl SyntheticFile for SyntheticDir {
+        fn stat(&self) -> io::Result<libc::stat64> {
+            let mut st: libc::stat64 = unsafe { std::mem::zeroed() };
+            st.st_mode = libc::S_IFDIR | 0o555;
+            st.st_nlink = 2;
+            Ok(st)
+        }
+
+        fn read(&self, _offset: u64, _size: u32) -> io::Result<Vec<u8>> {
+            Err(io::Error::from_raw_os_error(libc::EISDIR))
+        }
+
+        fn readdir(&self) -> io::Result<Vec<(CString, Arc<dyn SyntheticFile>)>> {
+            Ok(self.0.clone())
+        }
+    }
+
+    #[test]
+    fn test_synthetic_entries() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::write(source.as_path().join("real"), b"real").unwrap();
+        std::fs::write(source.as_path().join("meta.json"), b"stale").unwrap();
+        let meta: Arc<dyn SyntheticFile> = Arc::new(SyntheticContent(b"{\"v\":1}".to_vec()));
+        let gen: Arc<dyn SyntheticFile> = Arc::new(SyntheticDir(vec![(
+            CString::new("x").unwrap(),
+            Arc::new(SyntheticContent(b"xx".to_vec())),
+        )]));
+        let fs = prepare_passthroughfs(
+            source.as_path(),
+            Config {
+                synthetic_entries: vec![
+                    (PathBuf::from("/meta.json"), meta),
+                    (PathBuf::from("gen"), gen),
+                ],
+                ..Default::default()
+            },
+        );
+        let ctx = Context::default();
+        let name = |name| CString::new(name).unwrap();
+        let read = |inode| {
+            let (handle, opts) = fs.open(&ctx, inode, libc::O_RDONLY as u32, 0).unwrap();
+            assert!(opts.contains(OpenOptions::DIRECT_IO));
+            let mut w = VecWriter(Vec::new());
+            fs.read(&ctx, inode, handle.unwrap_or(0), &mut w, 100, 0, None, 0)
+                .unwrap();
+            fs.release(&ctx, inode, 0, handle.unwrap_or(0), false, false, None)
+                .unwrap();
+            w.0
+        };
+
+        // The synthetic file shadows the backing one.
+        let entry = fs.lookup(&ctx, ROOT_ID, &name("meta.json")).unwrap();
+        assert_eq!(entry.attr.st_size, 7);
+        assert_eq!(entry.attr.st_ino, entry.inode);
+        assert_eq!(read(entry.inode), b"{\"v\":1}");
+        let (st, _) = fs.getattr(&ctx, entry.inode, None).unwrap();
+        assert_eq!(st.st_mode, libc::S_IFREG | 0o444);
+        assert_eq!(
+            fs.lookup(&ctx, ROOT_ID, &name("meta.json")).unwrap().inode,
+            entry.inode
+        );
+
+        let gen = fs.lookup(&ctx, ROOT_ID, &name("gen")).unwrap();
+        let x = fs.lookup(&ctx, gen.inode, &name("x")).unwrap();
+        assert_eq!(read(x.inode), b"xx");
+        let e = fs.lookup(&ctx, gen.inode, &name("y")).err().unwrap();
+        assert_eq!(e.raw_os_error(), Some(libc::ENOENT));
+
+        let list = |inode| {
+            let (handle, _) = fs.opendir(&ctx, inode, 0).unwrap();
+            let mut names = Vec::new();
+            let mut offset = 0;
+            loop {
+                let mut batch = Vec::new();
+                fs.readdir(&ctx, inode, handle.unwrap_or(0), 4096, offset, &mut |e| {
+                    batch.push((String::from_utf8(e.name.to_vec()).unwrap(), e.offset));
+                    Ok(1)
+                })
+                .unwrap();
+                match batch.last() {
+                    Some((_, off)) => offset = *off,
+                    None => break,
+                }
+                names.extend(batch.into_iter().map(|(n, _)| n));
+            }
+            fs.releasedir(&ctx, inode, 0, handle.unwrap_or(0)).unwrap();
+            names.sort();
+            names
+        };
+        assert_eq!(list(ROOT_ID), [".", "..", "gen", "meta.json", "real"]);
+        assert_eq!(list(gen.inode), ["x"]);
+
+        // Synthetic files can't be modified.
+        let e = fs
+            .open(&ctx, entry.inode, libc::O_WRONLY as u32, 0)
+            .unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EROFS));
+        let e = fs.unlink(&ctx, ROOT_ID, &name("meta.json")).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EROFS));
+        let e = fs
+            .mknod(&ctx, gen.inode, &name("y"), libc::S_IFREG | 0o644, 0, 0)
+            .err()
+            .unwrap();
+        assert_eq!(e.raw_os_error(), Some(libc::EROFS));
+        assert_eq!(
+            std::fs::read(source.as_path().join("meta.json")).unwrap(),
+            b"stale"
+        );
+    }
+
+    #[test]
+    fn test_open_noflush() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::write(source.as_path().join("file"), b"data").unwrap();
+        let ctx = Context::default();
+        let open_opts = |posix_locks: bool, flags: i32| {
+            let fs = prepare_passthroughfs(
+                source.as_path(),
+                Config {
+                    posix_locks,
+                    ..Default::default()
+                },
+            );
+            fs.init(FsOptions::POSIX_LOCKS).unwrap();
+            let entry = fs
+                .lookup(&ctx, ROOT_ID, &CString::new("file").unwrap())
+                .unwrap();
+            let (handle, opts) = fs.open(&ctx, entry.inode, flags as u32, 0).unwrap();
+            fs.release(&ctx, entry.inode, 0, handle.unwrap(), true, false, None)
+                .unwrap();
+            opts
+        };
+
+        assert!(open_opts(false, libc::O_RDONLY).contains(OpenOptions::NOFLUSH));
+        assert!(!open_opts(false, libc::O_RDWR).contains(OpenOptions::NOFLUSH));
+        assert!(!open_opts(false, libc::O_WRONLY).contains(OpenOptions::NOFLUSH));
+        // Closing the file must release the POSIX locks of its owner.
+        assert!(!open_opts(true, libc::O_RDONLY).contains(OpenOptions::NOFLUSH));
+    }
+
+    // Keeps the hashed data itself as the digest.
+    struct CopyHasher(Vec<u8>);
+
+    impl AuditHasher for CopyHasher {
+        fn update(&mut self, data: &[u8]) {
+            self.0.extend_from_slice(data);
+        }
+
+        fn finish(self: Box<Self>) -> Vec<u8> {
+            self.0
+        }
+    }
+
+    #[derive(Default)]
+    struct AuditLog(Mutex<Vec<AuditRecord>>);
+
+    impl AuditSink for AuditLog {
+        fn audited(&self, path: &Path) -> bool {
+            path.starts_with("secret")
+        }
+
+        fn hasher(&self) -> Box<dyn AuditHasher> {
+            Box::new(CopyHasher(Vec::new()))
+        }
+
+        fn record(&self, record: AuditRecord) {
+            self.0.lock().unwrap().push(record);
+        }
+    }
+
+    #[test]
+    fn test_audit_hash() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        std::fs::create_dir(source.as_path().join("secret")).unwrap();
+        std::fs::write(source.as_path().join("secret/data"), b"0123456789").unwrap();
+        std::fs::write(source.as_path().join("public"), b"0123456789").unwrap();
+        let log = Arc::new(AuditLog::default());
+        let fs = prepare_passthroughfs(
+            source.as_path(),
+            Config {
+                audit_hash: Some(log.clone()),
+                ..Default::default()
+            },
+        );
+        let ctx = Context::default();
+        let lookup = |parent, name| {
+            fs.lookup(&ctx, parent, &CString::new(name).unwrap())
+                .unwrap()
+                .inode
+        };
+        let read = |inode, size, offset| {
+            let (handle, _) = fs.open(&ctx, inode, libc::O_RDONLY as u32, 0).unwrap();
+            let mut w = VecWriter(Vec::new());
+            fs.read(&ctx, inode, handle.unwrap(), &mut w, size, offset, None, 0)
+                .unwrap();
+            fs.release(&ctx, inode, 0, handle.unwrap(), false, false, None)
+                .unwrap();
+            w.0
+        };
+
+        let data = lookup(lookup(ROOT_ID, "secret"), "data");
+        assert_eq!(read(data, 6, 0), b"012345");
+        // Overlapping reads are recorded separately, and a short read only covers the data served.
+        assert_eq!(read(data, 100, 4), b"456789");
+        assert_eq!(read(lookup(ROOT_ID, "public"), 100, 0), b"0123456789");
+
+        let records = log.0.lock().unwrap();
+        assert_eq!(
+            *records,
+            [
+                AuditRecord {
+                    inode: data,
+                    offset: 0,
+                    len: 6,
+                    digest: b"012345".to_vec(),
+                },
+                AuditRecord {
+                    inode: data,
+                    offset: 4,
+                    len: 6,
+                    digest: b"456789".to_vec(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tmpfile() {
+        let source = TempDir::new().expect("Cannot create temporary directory.");
+        let fs = prepare_passthroughfs(source.as_path(), Config::default());
+
+        let ctx = Context::default();
+        let (entry, handle, _) = match fs.tmpfile(&ctx, ROOT_ID, 0o644, 0, libc::O_RDWR as u32) {
+            Ok(v) => v,
+            Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => {
+                println!("backing file system doesn't support O_TMPFILE");
+                return;
+            }
+            Err(e) => panic!("failed to create tmpfile: {:?}", e),
+        };
+        assert_ne!(entry.inode, 0);
+        assert_eq!(entry.attr.st_mode & libc::S_IFMT, libc::S_IFREG);
+        assert_eq!(entry.attr.st_nlink, 0);
+        assert!(handle.is_some());
+
+        let data = fs.handle_map.get(handle.unwrap(), entry.inode).unwrap();
+        let (_guard, mut file) = data.get_file_mut();
+        io::Write::write_all(&mut file, b"tmpfile").unwrap();
+
+        let name = CString::new("linked").unwrap();
+        match fs.link(&ctx, entry.inode, ROOT_ID, &name) {
+            Ok(linked) => {
+                assert_eq!(linked.inode, entry.inode);
+                assert_eq!(
+                    std::fs::read(source.as_path().join("linked")).unwrap(),
+                    b"tmpfile"
+                );
+            }
+            // linkat(AT_EMPTY_PATH) needs CAP_DAC_READ_SEARCH.
+            Err(e) => assert_eq!(e.raw_os_error(), Some(libc::ENOENT)),
+        }
+    }
+
     #[test]
     fn test_is_safe_inode() {
         let mode = libc::S_IFREG;
@@ -143,6 +143,18 @@ where
         self.alt.clear();
         self.main.clear()
     }
+
+    /// Returns the number of entries in the map, alternate keys are not counted.
+    #[cfg(any(test, feature = "control-socket"))]
+    pub fn len(&self) -> usize {
+        self.main.len()
+    }
+
+    /// Returns an iterator over the main keys and values of the map, sorted by main key.
+    #[cfg(feature = "control-socket")]
+    pub fn iter(&self) -> impl Iterator<Item = (&K1, &V)> {
+        self.main.iter().map(|(k1, (_, v))| (k1, v))
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,237 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! POSIX record locks held by the clients of passthrough.
+//!
+//! The locks are kept by the daemon instead of being forwarded to the backing files, where all of
+//! them would belong to the daemon process and never conflict with each other. They are keyed by
+//! the lock owner sent by the kernel, which identifies the open file description of the client.
+//!
+//! Blocking lock requests are not supported. Waiting for a conflicting lock would hold a worker
+//! thread for as long as the lock is held, without a way to abort the wait when the request is
+//! interrupted, so enough waiters can stall the whole session. They fail with `EAGAIN` instead,
+//! the same as non-blocking requests.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::Mutex;
+
+use crate::api::filesystem::FileLock;
+
+use super::Inode;
+
+const F_RDLCK: u32 = libc::F_RDLCK as u32;
+const F_WRLCK: u32 = libc::F_WRLCK as u32;
+const F_UNLCK: u32 = libc::F_UNLCK as u32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct RecordLock {
+    owner: u64,
+    lock_type: u32,
+    // Both ends are inclusive, like `fuse_file_lock`.
+    start: u64,
+    end: u64,
+    pid: u32,
+}
+
+impl RecordLock {
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start <= end && start <= self.end
+    }
+
+    // Whether the lock prevents `owner` from taking a lock of `lock_type` on the range.
+    fn conflicts(&self, owner: Option<u64>, lock_type: u32, start: u64, end: u64) -> bool {
+        Some(self.owner) != owner
+            && self.overlaps(start, end)
+            && (self.lock_type == F_WRLCK || lock_type == F_WRLCK)
+    }
+}
+
+#[derive(Default)]
+struct LockState {
+    locks: HashMap<Inode, Vec<RecordLock>>,
+}
+
+impl LockState {
+    fn conflict(
+        &self,
+        inode: Inode,
+        owner: Option<u64>,
+        lock_type: u32,
+        start: u64,
+        end: u64,
+    ) -> Option<RecordLock> {
+        self.locks
+            .get(&inode)?
+            .iter()
+            .find(|l| l.conflicts(owner, lock_type, start, end))
+            .copied()
+    }
+
+    // Remove the range from the locks of `owner`, splitting locks covering it partially.
+    fn unlock(&mut self, inode: Inode, owner: u64, start: u64, end: u64) {
+        let locks = match self.locks.get_mut(&inode) {
+            Some(locks) => locks,
+            None => return,
+        };
+        let mut kept = Vec::with_capacity(locks.len() + 1);
+        for l in locks.drain(..) {
+            if l.owner != owner || !l.overlaps(start, end) {
+                kept.push(l);
+                continue;
+            }
+            if l.start < start {
+                kept.push(RecordLock {
+                    end: start - 1,
+                    ..l
+                });
+            }
+            if l.end > end {
+                kept.push(RecordLock {
+                    start: end + 1,
+                    ..l
+                });
+            }
+        }
+        if kept.is_empty() {
+            self.locks.remove(&inode);
+        } else {
+            *locks = kept;
+        }
+    }
+}
+
+/// POSIX record locks of all inodes.
+#[derive(Default)]
+pub(super) struct PosixLocks {
+    state: Mutex<LockState>,
+}
+
+impl PosixLocks {
+    /// Get the first lock conflicting with `lock`, or `lock` with the type set to `F_UNLCK` if
+    /// it could be taken.
+    pub fn getlk(&self, inode: Inode, owner: u64, lock: FileLock) -> io::Result<FileLock> {
+        let (start, end) = Self::range(&lock)?;
+        let state = self.state.lock().unwrap();
+        Ok(
+            match state.conflict(inode, Some(owner), lock.lock_type, start, end) {
+                Some(l) => FileLock {
+                    start: l.start,
+                    end: l.end,
+                    lock_type: l.lock_type,
+                    pid: l.pid,
+                },
+                None => FileLock {
+                    lock_type: F_UNLCK,
+                    ..lock
+                },
+            },
+        )
+    }
+
+    /// Take or release a lock for `owner`. A conflicting lock fails with `EAGAIN`.
+    pub fn setlk(&self, inode: Inode, owner: u64, lock: FileLock) -> io::Result<()> {
+        let (start, end) = Self::range(&lock)?;
+        let mut state = self.state.lock().unwrap();
+        if lock.lock_type == F_UNLCK {
+            state.unlock(inode, owner, start, end);
+            return Ok(());
+        }
+
+        if state
+            .conflict(inode, Some(owner), lock.lock_type, start, end)
+            .is_some()
+        {
+            return Err(io::Error::from_raw_os_error(libc::EAGAIN));
+        }
+
+        // Converting a lock may release part of it, e.g. downgrading a write lock.
+        state.unlock(inode, owner, start, end);
+        state.locks.entry(inode).or_default().push(RecordLock {
+            owner,
+            lock_type: lock.lock_type,
+            start,
+            end,
+            pid: lock.pid,
+        });
+        Ok(())
+    }
+
+    /// Release all locks of `owner` on the inode, when the client closes the file.
+    pub fn release_owner(&self, inode: Inode, owner: u64) {
+        let mut state = self.state.lock().unwrap();
+        if state.locks.contains_key(&inode) {
+            state.unlock(inode, owner, 0, u64::MAX);
+        }
+    }
+
+    /// Check whether accessing the range conflicts with the locks of other owners. Reads only
+    /// conflict with write locks. The range conflicts with the locks of all owners if `owner`
+    /// is unknown.
+    pub fn conflicts_io(
+        &self,
+        inode: Inode,
+        owner: Option<u64>,
+        offset: u64,
+        size: u32,
+        write: bool,
+    ) -> bool {
+        if size == 0 {
+            return false;
+        }
+        let lock_type = if write { F_WRLCK } else { F_RDLCK };
+        let end = offset.saturating_add(size as u64 - 1);
+        self.state
+            .lock()
+            .unwrap()
+            .conflict(inode, owner, lock_type, offset, end)
+            .is_some()
+    }
+
+    fn range(lock: &FileLock) -> io::Result<(u64, u64)> {
+        if ![F_RDLCK, F_WRLCK, F_UNLCK].contains(&lock.lock_type) || lock.start > lock.end {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        Ok((lock.start, lock.end))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lock(lock_type: i32, start: u64, end: u64) -> FileLock {
+        FileLock {
+            start,
+            end,
+            lock_type: lock_type as u32,
+            pid: 1,
+        }
+    }
+
+    #[test]
+    fn test_posix_locks() {
+        let locks = PosixLocks::default();
+        locks.setlk(1, 1, lock(libc::F_RDLCK, 0, 99)).unwrap();
+        locks.setlk(1, 2, lock(libc::F_RDLCK, 50, 149)).unwrap();
+        let e = locks.setlk(1, 2, lock(libc::F_WRLCK, 0, 9)).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EAGAIN));
+        assert!(locks.conflicts_io(1, Some(2), 0, 10, true));
+        assert!(!locks.conflicts_io(1, Some(2), 0, 10, false));
+        assert!(!locks.conflicts_io(2, Some(2), 0, 10, true));
+
+        // Unlocking the middle of a range splits it.
+        locks.setlk(1, 1, lock(libc::F_UNLCK, 10, 19)).unwrap();
+        locks.setlk(1, 2, lock(libc::F_WRLCK, 10, 19)).unwrap();
+        let l = locks.getlk(1, 1, lock(libc::F_RDLCK, 15, 15)).unwrap();
+        assert_eq!((l.start, l.end), (10, 19));
+        assert_eq!(l.lock_type, libc::F_WRLCK as u32);
+        let l = locks.getlk(1, 2, lock(libc::F_RDLCK, 0, 9)).unwrap();
+        assert_eq!(l.lock_type, libc::F_UNLCK as u32);
+
+        locks.release_owner(1, 1);
+        assert!(!locks.conflicts_io(1, Some(2), 0, 10, true));
+        assert!(!locks.conflicts_io(1, None, 0, 10, false));
+        assert!(locks.conflicts_io(1, None, 10, 1, false));
+    }
+}
@@ -15,21 +15,51 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use super::*;
-use crate::abi::fuse_abi::{FOPEN_IN_KILL_SUIDGID, WRITE_KILL_PRIV};
+use crate::abi::fuse_abi::{Statx, SxTime, FOPEN_IN_KILL_SUIDGID, LK_FLOCK, WRITE_KILL_PRIV};
 #[cfg(any(feature = "vhost-user-fs", feature = "virtiofs"))]
 use crate::abi::virtio_fs;
 use crate::api::filesystem::{
-    Context, DirEntry, Entry, FileSystem, FsOptions, GetxattrReply, ListxattrReply, OpenOptions,
-    SetattrValid, ZeroCopyReader, ZeroCopyWriter,
+    Context, DirEntry, Entry, FiemapExtent, FileLock, FileSystem, FsOptions, FsOptionsExt,
+    GetxattrReply, ListxattrReply, OpenOptions, SecContext, SetattrValid, SupportedOps,
+    ZeroCopyReader, ZeroCopyWriter,
 };
-use crate::api::CreateIn;
+use crate::api::{is_dot_or_dotdot, CreateIn, ReplyBuf};
 use crate::async_util::AsyncDrive;
 use crate::bytes_to_cstr;
 #[cfg(any(feature = "vhost-user-fs", feature = "virtiofs"))]
 use crate::transport::FsCacheReqHandler;
 
+// Offset of the entry following "." in a directory stream.
+const DOT_OFFSET: u64 = 1;
+// Offset of the entry following "..", where the backing directory stream starts.
+const DOTDOT_OFFSET: u64 = 2;
+
+// Map the offset `d_off` of the backing directory stream to the offset reported to the client.
+// Backing offsets are shifted past the offsets of "." and "..", which could otherwise collide
+// with the cookies of real entries. A cookie reaching the offsets of synthetic files, like the end
+// of directory cookie of ext4, marks the end of the backing entries.
+fn backing_dir_offset(d_off: u64) -> u64 {
+    match d_off.checked_add(DOTDOT_OFFSET) {
+        Some(offset) if offset < SYNTHETIC_OFFSET => offset,
+        _ => SYNTHETIC_OFFSET,
+    }
+}
+
 impl<D: AsyncDrive> PassthroughFs<D> {
-    fn open_inode(&self, inode: Inode, mut flags: i32) -> io::Result<File> {
+    fn open_inode(&self, cfg: &Config, inode: Inode, flags: i32) -> io::Result<File> {
+        let data = self.inode_map.get(inode)?;
+        self.check_open_mode(cfg, data.mode, flags)?;
+        let flags = self.backing_open_flags(flags & !FMODE_EXEC);
+        let file = data.get_file(&self.mount_fds)?;
+
+        let file = Self::open_proc_file(&self.proc_self_fd, file.as_raw_fd(), flags, data.mode)?;
+        self.check_verity(cfg, &file, data.mode)?;
+
+        Ok(file)
+    }
+
+    // Get the flags to open the backing file for an open request with `flags`.
+    fn backing_open_flags(&self, mut flags: i32) -> i32 {
         // When writeback caching is enabled, the kernel may send read requests even if the
         // userspace program opened the file write-only. So we need to ensure that we have opened
         // the file for reading as well as writing.
@@ -45,139 +75,287 @@ impl<D: AsyncDrive> PassthroughFs<D> {
         // the file. Just allow this for now as it is the user's responsibility to enable writeback
         // caching only for directories that are not shared. It also means that we need to clear the
         // `O_APPEND` flag.
+        //
+        // Otherwise `O_APPEND` is kept for the backing file, so every write is appended to the end
+        // of the file atomically by the host, ignoring the offset from the kernel, which may be
+        // stale if the file is appended concurrently.
         if writeback && flags & libc::O_APPEND != 0 {
             flags &= !libc::O_APPEND;
         }
 
-        let data = self.inode_map.get(inode)?;
-        let file = data.get_file(&self.mount_fds)?;
+        flags
+    }
+
+    // Whether a file opened with `flags` may share its backing file with other handles. Flags
+    // changing the behavior of the open file description need a dedicated backing file.
+    fn shareable_open_flags(flags: i32) -> bool {
+        let private = libc::O_APPEND
+            | libc::O_DIRECT
+            | libc::O_DIRECTORY
+            | libc::O_DSYNC
+            | libc::O_NOATIME
+            | libc::O_NONBLOCK
+            | libc::O_PATH
+            | libc::O_SYNC
+            | libc::O_TRUNC;
+
+        flags & private == 0
+    }
+
+    // Open a backing file shared by handles of `inode`. Reads and writes use explicit offsets, so
+    // handles don't interfere with each other through the file offset.
+    fn open_shared_inode(&self, cfg: &Config, inode: Inode, flags: i32) -> io::Result<Arc<File>> {
+        let flags = self.backing_open_flags(flags);
+        let write = flags & libc::O_ACCMODE != libc::O_RDONLY;
+        let mut shared = self.shared_files.lock().unwrap();
+
+        if let Some(files) = shared.get(&inode) {
+            // A read-write backing file serves handles of any access mode.
+            if let Some(file) = files.rdwr.upgrade() {
+                return Ok(file);
+            }
+            if !write {
+                if let Some(file) = files.rdonly.upgrade() {
+                    return Ok(file);
+                }
+            }
+        }
+
+        let mode = if write { libc::O_RDWR } else { libc::O_RDONLY };
+        let file = match self.open_inode(cfg, inode, (flags & !libc::O_ACCMODE) | mode) {
+            Ok(file) => Arc::new(file),
+            // The file may not be readable for a write-only open, don't share it then.
+            Err(e) if write && flags & libc::O_ACCMODE == libc::O_WRONLY => {
+                debug!(
+                    "fuse: failed to open shared file for inode {}, {}",
+                    inode, e
+                );
+                return self.open_inode(cfg, inode, flags).map(Arc::new);
+            }
+            Err(e) => return Err(e),
+        };
+        let files = shared.entry(inode).or_default();
+        if write {
+            files.rdwr = Arc::downgrade(&file);
+        } else {
+            files.rdonly = Arc::downgrade(&file);
+        }
+
+        Ok(file)
+    }
+
+    // Copy `size` bytes from `offset` by calling `copy` with chunks of at most
+    // `cfg.io_yield_interval` bytes, calling the yield hook between the chunks. Stops at the
+    // first short copy, and only fails if nothing has been copied.
+    fn copy_with_yield(
+        &self,
+        cfg: &Config,
+        size: usize,
+        offset: u64,
+        copy: &mut dyn FnMut(usize, u64) -> io::Result<usize>,
+    ) -> io::Result<usize> {
+        let interval = cfg.io_yield_interval;
+        if interval == 0 || size <= interval {
+            return copy(size, offset);
+        }
 
-        Self::open_proc_file(&self.proc_self_fd, file.as_raw_fd(), flags, data.mode)
+        let hook = self.io_yield_hook.lock().unwrap().clone();
+        let mut done = 0;
+        while done < size {
+            if done > 0 {
+                if let Some(hook) = hook.as_ref() {
+                    hook();
+                }
+            }
+            let len = std::cmp::min(interval, size - done);
+            let n = match copy(len, offset + done as u64) {
+                Ok(n) => n,
+                Err(e) if done == 0 => return Err(e),
+                Err(_) => break,
+            };
+            done += n;
+            if n < len {
+                break;
+            }
+        }
+
+        Ok(done)
     }
 
     fn do_readdir(
         &self,
+        cfg: &Config,
         inode: Inode,
         handle: Handle,
         size: u32,
         offset: u64,
         add_entry: &mut dyn FnMut(DirEntry, RawFd) -> io::Result<usize>,
-    ) -> io::Result<()> {
+    ) -> io::Result<bool> {
         if size == 0 {
-            return Ok(());
+            return Ok(false);
         }
 
-        let mut buf = Vec::<u8>::with_capacity(size as usize);
-        let data = self.get_dirdata(handle, inode, libc::O_RDONLY)?;
-
-        {
-            // Since we are going to work with the kernel offset, we have to acquire the file lock
-            // for both the `lseek64` and `getdents64` syscalls to ensure that no other thread
-            // changes the kernel offset while we are using it.
-            let (guard, dir) = data.get_file_mut();
-
-            // Safe because this doesn't modify any memory and we check the return value.
-            let res =
-                unsafe { libc::lseek64(dir.as_raw_fd(), offset as libc::off64_t, libc::SEEK_SET) };
-            if res < 0 {
-                return Err(io::Error::last_os_error());
+        let mut buf = ReplyBuf::with_capacity(size as usize);
+        let data = self.get_dirdata(cfg, handle, inode, libc::O_RDONLY)?;
+        // Backing entries shadowed by synthetic files are skipped.
+        let shadowed = self.synthetic_children(inode).unwrap_or_default();
+
+        // The backing directory may list "." and ".." anywhere (ext4 returns them in hash order),
+        // so they are always reported first here, at offsets of their own, and skipped when they
+        // show up in the getdents64 output.
+        let dots: [(&[u8], u64); 2] = [
+            (&CURRENT_DIR_CSTR[..CURRENT_DIR_CSTR.len() - 1], DOT_OFFSET),
+            (&PARENT_DIR_CSTR[..PARENT_DIR_CSTR.len() - 1], DOTDOT_OFFSET),
+        ];
+        let mut added = false;
+        for (name, next) in dots.iter() {
+            if offset >= *next {
+                continue;
             }
-
-            // Safe because the kernel guarantees that it will only write to `buf` and we check the
-            // return value.
-            let res = unsafe {
-                libc::syscall(
-                    libc::SYS_getdents64,
-                    dir.as_raw_fd(),
-                    buf.as_mut_ptr() as *mut LinuxDirent64,
-                    size as libc::c_int,
-                )
+            let dir_entry = DirEntry {
+                ino: 0,
+                offset: *next,
+                type_: u32::from(libc::DT_DIR),
+                name,
             };
-            if res < 0 {
-                return Err(io::Error::last_os_error());
+            match add_entry(dir_entry, data.get_handle_raw_fd()) {
+                Ok(0) => return Ok(false),
+                Ok(_) => added = true,
+                Err(e) if !added => return Err(e),
+                Err(_) => return Ok(false),
             }
-
-            // Safe because we trust the value returned by kernel.
-            unsafe { buf.set_len(res as usize) };
-
-            // Explicitly drop the lock so that it's not held while we fill in the fuse buffer.
-            mem::drop(guard);
         }
+        // The offset in the backing directory stream, see `backing_dir_offset()`.
+        let mut offset = offset.saturating_sub(DOTDOT_OFFSET);
 
-        let mut rem = &buf[..];
-        let orig_rem_len = rem.len();
-        while !rem.is_empty() {
-            // We only use debug asserts here because these values are coming from the kernel and we
-            // trust them implicitly.
-            debug_assert!(
-                rem.len() >= size_of::<LinuxDirent64>(),
-                "fuse: not enough space left in `rem`"
-            );
+        // Skipped entries don't fill the reply, so keep reading until an entry is added, as an
+        // empty reply means the end of the directory.
+        loop {
+            {
+                // Since we are going to work with the kernel offset, we have to acquire the file
+                // lock for both the `lseek64` and `getdents64` syscalls to ensure that no other
+                // thread changes the kernel offset while we are using it.
+                let (guard, dir) = data.get_file_mut();
+
+                // Safe because this doesn't modify any memory and we check the return value.
+                let res = unsafe {
+                    libc::lseek64(dir.as_raw_fd(), offset as libc::off64_t, libc::SEEK_SET)
+                };
+                if res < 0 {
+                    return Err(io::Error::last_os_error());
+                }
 
-            let (front, back) = rem.split_at(size_of::<LinuxDirent64>());
+                // Safe because the kernel guarantees that it will only write to `buf` and we check
+                // the return value.
+                let res = unsafe {
+                    libc::syscall(
+                        libc::SYS_getdents64,
+                        dir.as_raw_fd(),
+                        buf.as_mut_ptr() as *mut LinuxDirent64,
+                        size as libc::c_int,
+                    )
+                };
+                if res < 0 {
+                    return Err(io::Error::last_os_error());
+                }
 
-            let dirent64 = LinuxDirent64::from_slice(front)
-                .expect("fuse: unable to get LinuxDirent64 from slice");
+                // Safe because we trust the value returned by kernel.
+                unsafe { buf.set_len(res as usize) };
+                if res == 0 {
+                    // The end of the directory has been reached.
+                    return Ok(true);
+                }
 
-            let namelen = dirent64.d_reclen as usize - size_of::<LinuxDirent64>();
-            debug_assert!(
-                namelen <= back.len(),
-                "fuse: back is smaller than `namelen`"
-            );
+                // Explicitly drop the lock so that it's not held while we fill in the fuse buffer.
+                mem::drop(guard);
+            }
 
-            let name = &back[..namelen];
-            let res = if name.starts_with(CURRENT_DIR_CSTR) || name.starts_with(PARENT_DIR_CSTR) {
-                // We don't want to report the "." and ".." entries. However, returning `Ok(0)` will
-                // break the loop so return `Ok` with a non-zero value instead.
-                Ok(1)
-            } else {
-                // The Sys_getdents64 in kernel will pad the name with '\0'
-                // bytes up to 8-byte alignment, so @name may contain a few null
-                // terminators.  This causes an extra lookup from fuse when
-                // called by readdirplus, because kernel path walking only takes
-                // name without null terminators, the dentry with more than 1
-                // null terminators added by readdirplus doesn't satisfy the
-                // path walking.
-                let name = bytes_to_cstr(name)
-                    .map_err(|e| {
-                        error!("fuse: do_readdir: {:?}", e);
-                        io::Error::from_raw_os_error(libc::EINVAL)
-                    })?
-                    .to_bytes();
-
-                add_entry(
-                    DirEntry {
-                        ino: dirent64.d_ino,
-                        offset: dirent64.d_off as u64,
-                        type_: u32::from(dirent64.d_ty),
-                        name,
-                    },
-                    data.get_handle_raw_fd(),
-                )
-            };
+            let mut rem = &buf[..];
+            let orig_rem_len = rem.len();
+            while !rem.is_empty() {
+                // We only use debug asserts here because these values are coming from the kernel
+                // and we trust them implicitly.
+                debug_assert!(
+                    rem.len() >= size_of::<LinuxDirent64>(),
+                    "fuse: not enough space left in `rem`"
+                );
+
+                let (front, back) = rem.split_at(size_of::<LinuxDirent64>());
+
+                let dirent64 = LinuxDirent64::from_slice(front)
+                    .expect("fuse: unable to get LinuxDirent64 from slice");
+
+                let namelen = dirent64.d_reclen as usize - size_of::<LinuxDirent64>();
+                debug_assert!(
+                    namelen <= back.len(),
+                    "fuse: back is smaller than `namelen`"
+                );
+
+                let name = &back[..namelen];
+                let skip = name.starts_with(CURRENT_DIR_CSTR)
+                    || name.starts_with(PARENT_DIR_CSTR)
+                    || shadowed
+                        .iter()
+                        .any(|(n, _)| name.starts_with(n.as_bytes_with_nul()));
+                let res = if skip {
+                    // Already reported above, or shadowed. Returning `Ok(0)` would break the loop,
+                    // so return `Ok` with a non-zero value instead.
+                    Ok(1)
+                } else {
+                    // The Sys_getdents64 in kernel will pad the name with '\0'
+                    // bytes up to 8-byte alignment, so @name may contain a few null
+                    // terminators.  This causes an extra lookup from fuse when
+                    // called by readdirplus, because kernel path walking only takes
+                    // name without null terminators, the dentry with more than 1
+                    // null terminators added by readdirplus doesn't satisfy the
+                    // path walking.
+                    let name = bytes_to_cstr(name)
+                        .map_err(|e| {
+                            error!("fuse: do_readdir: {:?}", e);
+                            io::Error::from_raw_os_error(libc::EINVAL)
+                        })?
+                        .to_bytes();
+
+                    add_entry(
+                        DirEntry {
+                            ino: dirent64.d_ino,
+                            offset: backing_dir_offset(dirent64.d_off as u64),
+                            type_: u32::from(dirent64.d_ty),
+                            name,
+                        },
+                        data.get_handle_raw_fd(),
+                    )
+                };
 
-            debug_assert!(
-                rem.len() >= dirent64.d_reclen as usize,
-                "fuse: rem is smaller than `d_reclen`"
-            );
+                debug_assert!(
+                    rem.len() >= dirent64.d_reclen as usize,
+                    "fuse: rem is smaller than `d_reclen`"
+                );
 
-            match res {
-                Ok(0) => break,
-                Ok(_) => rem = &rem[dirent64.d_reclen as usize..],
-                // If there's an error, we can only signal it if we haven't
-                // stored any entries yet - otherwise we'd end up with wrong
-                // lookup counts for the entries that are already in the
-                // buffer. So we return what we've collected until that point.
-                Err(e) if rem.len() == orig_rem_len => return Err(e),
-                Err(_) => return Ok(()),
+                match res {
+                    Ok(0) => return Ok(false),
+                    Ok(_) => {
+                        added |= !skip;
+                        rem = &rem[dirent64.d_reclen as usize..];
+                    }
+                    // If there's an error, we can only signal it if we haven't
+                    // stored any entries yet - otherwise we'd end up with wrong
+                    // lookup counts for the entries that are already in the
+                    // buffer. So we return what we've collected until that point.
+                    Err(e) if !added && rem.len() == orig_rem_len => return Err(e),
+                    Err(_) => return Ok(false),
+                }
+                offset = dirent64.d_off as u64;
+            }
+            if added {
+                return Ok(false);
             }
         }
-
-        Ok(())
     }
 
     fn do_open(
         &self,
+        cfg: &Config,
         inode: Inode,
         flags: u32,
         fuse_flags: u32,
@@ -189,15 +367,31 @@ impl<D: AsyncDrive> PassthroughFs<D> {
         } else {
             None
         };
-        let file = self.open_inode(inode, flags as i32)?;
+        let direct = flags & (libc::O_DIRECT as u32) != 0;
+        let file = if cfg.shared_fd && Self::shareable_open_flags(flags as i32) {
+            self.open_shared_inode(cfg, inode, flags as i32)?
+        } else {
+            match self.open_inode(cfg, inode, flags as i32) {
+                // The backing file system doesn't support direct I/O, use buffered I/O on the host
+                // while the guest still bypasses its page cache.
+                Err(e) if direct && e.raw_os_error() == Some(libc::EINVAL) => {
+                    Arc::new(self.open_inode(cfg, inode, flags as i32 & !libc::O_DIRECT)?)
+                }
+                res => Arc::new(res?),
+            }
+        };
         drop(killpriv);
 
-        let data = HandleData::new(inode, file);
+        if flags & (libc::O_DIRECTORY as u32) == 0 {
+            self.advise_readahead(cfg, inode, &file);
+        }
+
+        let data = HandleData::with_shared_file(inode, file);
         let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
         self.handle_map.insert(handle, data);
 
         let mut opts = OpenOptions::empty();
-        match self.cfg.cache_policy {
+        match cfg.cache_policy {
             // We only set the direct I/O option on files.
             CachePolicy::Never => opts.set(
                 OpenOptions::DIRECT_IO,
@@ -206,12 +400,23 @@ impl<D: AsyncDrive> PassthroughFs<D> {
             CachePolicy::Always => opts |= OpenOptions::KEEP_CACHE,
             _ => {}
         };
+        if direct {
+            opts.remove(OpenOptions::KEEP_CACHE);
+            opts |= OpenOptions::DIRECT_IO;
+        }
+        if self.no_flush.load(Ordering::Relaxed)
+            && flags & (libc::O_DIRECTORY as u32) == 0
+            && flags & (libc::O_ACCMODE as u32) == libc::O_RDONLY as u32
+        {
+            opts |= OpenOptions::NOFLUSH;
+        }
 
         Ok((Some(handle), opts))
     }
 
     fn do_getattr(
         &self,
+        cfg: &Config,
         inode: Inode,
         handle: Option<Handle>,
     ) -> io::Result<(libc::stat64, Duration)> {
@@ -248,12 +453,22 @@ impl<D: AsyncDrive> PassthroughFs<D> {
             e
         })?;
 
-        Ok((st, self.cfg.attr_timeout))
+        let mut st = self.guest_stat(cfg, st);
+        st.st_blksize = self.guest_blksize(cfg, inode, st.st_blksize as u32) as _;
+        Ok((st, cfg.attr_timeout))
     }
 
-    fn do_unlink(&self, parent: Inode, name: &CStr, flags: libc::c_int) -> io::Result<()> {
+    fn do_unlink(
+        &self,
+        cfg: &Config,
+        ctx: &Context,
+        parent: Inode,
+        name: &CStr,
+        flags: libc::c_int,
+    ) -> io::Result<()> {
         let data = self.inode_map.get(parent)?;
         let file = data.get_file(&self.mount_fds)?;
+        self.check_sticky(cfg, ctx, &file, name)?;
         // Safe because this doesn't modify any memory and we check the return value.
         let res = unsafe { libc::unlinkat(file.as_raw_fd(), name.as_ptr(), flags) };
         if res == 0 {
@@ -265,6 +480,7 @@ impl<D: AsyncDrive> PassthroughFs<D> {
 
     fn get_dirdata(
         &self,
+        cfg: &Config,
         handle: Handle,
         inode: Inode,
         flags: libc::c_int,
@@ -273,13 +489,32 @@ impl<D: AsyncDrive> PassthroughFs<D> {
         if !no_open {
             self.handle_map.get(handle, inode)
         } else {
-            let file = self.open_inode(inode, (flags | libc::O_DIRECTORY) as i32)?;
+            let file = self.open_inode(cfg, inode, (flags | libc::O_DIRECTORY) as i32)?;
             Ok(Arc::new(HandleData::new(inode, file)))
         }
     }
 
+    fn do_fsync(data: &HandleData, datasync: bool) -> io::Result<()> {
+        let fd = data.get_handle_raw_fd();
+
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res = unsafe {
+            if datasync {
+                libc::fdatasync(fd)
+            } else {
+                libc::fsync(fd)
+            }
+        };
+        if res == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
     fn get_data(
         &self,
+        cfg: &Config,
         handle: Handle,
         inode: Inode,
         flags: libc::c_int,
@@ -287,11 +522,32 @@ impl<D: AsyncDrive> PassthroughFs<D> {
         let no_open = self.no_open.load(Ordering::Relaxed);
         if !no_open {
             self.handle_map.get(handle, inode)
+        } else if flags & libc::O_ACCMODE == libc::O_RDONLY {
+            let file = self.inode_read_file(cfg, inode)?;
+            Ok(Arc::new(HandleData::with_shared_file(inode, file)))
         } else {
-            let file = self.open_inode(inode, flags as i32)?;
+            let file = self.open_inode(cfg, inode, flags as i32)?;
             Ok(Arc::new(HandleData::new(inode, file)))
         }
     }
+
+    // Get the backing file of a regular file for requests without a handle, opened on first use
+    // and cached in the inode instead of being opened for each request. Reads use explicit
+    // offsets, so concurrent requests can share it.
+    fn inode_read_file(&self, cfg: &Config, inode: Inode) -> io::Result<Arc<File>> {
+        let data = self.inode_map.get(inode)?;
+        if data.mode & libc::S_IFMT != libc::S_IFREG {
+            return self.open_inode(cfg, inode, libc::O_RDONLY).map(Arc::new);
+        }
+        // Concurrent requests wait for the first one to open the file instead of racing with it.
+        let mut read_file = data.read_file.lock().unwrap();
+        if let Some(file) = read_file.as_ref() {
+            return Ok(file.clone());
+        }
+        let file = Arc::new(self.open_inode(cfg, inode, libc::O_RDONLY)?);
+        *read_file = Some(file.clone());
+        Ok(file)
+    }
 }
 
 impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
@@ -299,38 +555,47 @@ impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
     type Handle = Handle;
 
     fn init(&self, capable: FsOptions) -> io::Result<FsOptions> {
-        if self.cfg.do_import {
+        let cfg = self.cfg.load();
+        if cfg.do_import {
             self.import()?;
         }
 
         let mut opts = FsOptions::DO_READDIRPLUS | FsOptions::READDIRPLUS_AUTO;
+        // `O_TRUNC` is passed down to the backing file by `open()`, so the truncation is atomic
+        // with the open instead of being a separate setattr request.
+        if capable.contains(FsOptions::ATOMIC_O_TRUNC) {
+            opts |= FsOptions::ATOMIC_O_TRUNC;
+        }
         // !cfg.do_import means we are under vfs, in which case capable is already
         // negotiated and must be honored.
-        if (!self.cfg.do_import || self.cfg.writeback)
-            && capable.contains(FsOptions::WRITEBACK_CACHE)
-        {
+        if (!cfg.do_import || cfg.writeback) && capable.contains(FsOptions::WRITEBACK_CACHE) {
             opts |= FsOptions::WRITEBACK_CACHE;
             self.writeback.store(true, Ordering::Relaxed);
         }
-        if (!self.cfg.do_import || self.cfg.no_open)
-            && capable.contains(FsOptions::ZERO_MESSAGE_OPEN)
-        {
+        if (!cfg.do_import || cfg.no_open) && capable.contains(FsOptions::ZERO_MESSAGE_OPEN) {
             opts |= FsOptions::ZERO_MESSAGE_OPEN;
             // We can't support FUSE_ATOMIC_O_TRUNC with no_open
             opts.remove(FsOptions::ATOMIC_O_TRUNC);
             self.no_open.store(true, Ordering::Relaxed);
         }
-        if (!self.cfg.do_import || self.cfg.no_opendir)
-            && capable.contains(FsOptions::ZERO_MESSAGE_OPENDIR)
-        {
+        if (!cfg.do_import || cfg.no_opendir) && capable.contains(FsOptions::ZERO_MESSAGE_OPENDIR) {
             opts |= FsOptions::ZERO_MESSAGE_OPENDIR;
             self.no_opendir.store(true, Ordering::Relaxed);
         }
-        if (!self.cfg.do_import || self.cfg.killpriv_v2)
-            && capable.contains(FsOptions::HANDLE_KILLPRIV_V2)
-        {
+        if (!cfg.do_import || cfg.killpriv_v2) && capable.contains(FsOptions::HANDLE_KILLPRIV_V2) {
             opts |= FsOptions::HANDLE_KILLPRIV_V2;
             self.killpriv_v2.store(true, Ordering::Relaxed);
+            let cap_fsetid =
+                caps::has_cap(None, caps::CapSet::Effective, caps::Capability::CAP_FSETID)
+                    .unwrap_or(false);
+            self.cap_fsetid.store(cap_fsetid, Ordering::Relaxed);
+        }
+
+        if cfg.parallel_dirops && capable.contains(FsOptions::PARALLEL_DIROPS) {
+            opts |= FsOptions::PARALLEL_DIROPS;
+        }
+        if cfg.async_read && capable.contains(FsOptions::ASYNC_READ) {
+            opts |= FsOptions::ASYNC_READ;
         }
 
         if capable.contains(FsOptions::PERFILE_DAX) {
@@ -338,6 +603,32 @@ impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
             self.perfile_dax.store(true, Ordering::Relaxed);
         }
 
+        if cfg.posix_locks && capable.contains(FsOptions::POSIX_LOCKS) {
+            opts |= FsOptions::POSIX_LOCKS;
+        }
+        // Nothing needs flushing for read-only files, but the flush request also releases the
+        // POSIX locks of the closing owner.
+        self.no_flush
+            .store(!opts.contains(FsOptions::POSIX_LOCKS), Ordering::Relaxed);
+
+        Ok(opts)
+    }
+
+    fn init_ext(&self, capable: FsOptionsExt) -> io::Result<FsOptionsExt> {
+        let cfg = self.cfg.load();
+        let mut opts = FsOptionsExt::empty();
+
+        if cfg.xattr && capable.contains(FsOptionsExt::SECURITY_CTX) {
+            opts |= FsOptionsExt::SECURITY_CTX;
+        }
+        // The kernel doesn't support stacking with writeback caching.
+        if cfg.max_stack_depth > 0
+            && capable.contains(FsOptionsExt::PASSTHROUGH)
+            && !self.writeback.load(Ordering::Relaxed)
+        {
+            opts |= FsOptionsExt::PASSTHROUGH;
+        }
+
         Ok(opts)
     }
 
@@ -350,6 +641,32 @@ impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
         };
     }
 
+    fn max_stack_depth(&self) -> u32 {
+        let cfg = self.cfg.load();
+        cfg.max_stack_depth
+    }
+
+    fn supported_ops(&self) -> SupportedOps {
+        let cfg = self.cfg.load();
+        let mut ops = SupportedOps::LSEEK
+            | SupportedOps::CLONE_RANGE
+            | SupportedOps::FALLOCATE
+            | SupportedOps::TMPFILE
+            | SupportedOps::STATX
+            | SupportedOps::VERITY
+            | SupportedOps::FIEMAP;
+        if cfg!(any(feature = "vhost-user-fs", feature = "virtiofs")) {
+            ops |= SupportedOps::DAX_MAPPING;
+        }
+        if cfg.posix_locks {
+            ops |= SupportedOps::POSIX_LOCKS;
+        }
+        if cfg.xattr {
+            ops |= SupportedOps::XATTR;
+        }
+        ops
+    }
+
     fn statfs(&self, _ctx: &Context, inode: Inode) -> io::Result<libc::statvfs64> {
         let data = self.inode_map.get(inode)?;
         let mut out = MaybeUninit::<libc::statvfs64>::zeroed();
@@ -363,26 +680,58 @@ impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
         }
     }
 
+    fn syncfs(&self, _ctx: &Context) -> io::Result<()> {
+        let data = self.inode_map.get(fuse::ROOT_ID)?;
+        let file = data.get_file(&self.mount_fds)?;
+        // `syncfs(2)` doesn't take `O_PATH` files, so open the root directory again.
+        let root = Self::open_proc_file(
+            &self.proc_self_fd,
+            file.as_raw_fd(),
+            libc::O_RDONLY | libc::O_CLOEXEC,
+            data.mode,
+        )?;
+
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res = unsafe { libc::syncfs(root.as_raw_fd()) };
+        if res < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
     fn lookup(&self, _ctx: &Context, parent: Inode, name: &CStr) -> io::Result<Entry> {
+        let cfg = self.cfg.load();
         // Don't use is_safe_path_component(), allow "." and ".." for NFS export support
         if name.to_bytes_with_nul().contains(&SLASH_ASCII) {
             return Err(io::Error::from_raw_os_error(libc::EINVAL));
         }
-        self.do_lookup(parent, name)
+        self.validate_name_len(&cfg, name)?;
+        self.do_lookup(&cfg, parent, name)
     }
 
     fn forget(&self, _ctx: &Context, inode: Inode, count: u64) {
+        let cfg = self.cfg.load();
         let mut inodes = self.inode_map.get_map_mut();
 
-        Self::forget_one(&mut inodes, inode, count)
+        if Self::forget_one(&mut inodes, inode, count) {
+            drop(inodes);
+            self.notify_forgotten(&cfg, &[(inode, count)]);
+        }
     }
 
     fn batch_forget(&self, _ctx: &Context, requests: Vec<(Inode, u64)>) {
+        let cfg = self.cfg.load();
         let mut inodes = self.inode_map.get_map_mut();
+        let mut forgotten = Vec::new();
 
         for (inode, count) in requests {
-            Self::forget_one(&mut inodes, inode, count)
+            if Self::forget_one(&mut inodes, inode, count) {
+                forgotten.push((inode, count));
+            }
         }
+        drop(inodes);
+        self.notify_forgotten(&cfg, &forgotten);
     }
 
     fn opendir(
@@ -391,11 +740,15 @@ impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
         inode: Inode,
         flags: u32,
     ) -> io::Result<(Option<Handle>, OpenOptions)> {
+        let cfg = self.cfg.load();
+        if self.synthetic.get(inode).is_some() {
+            return Ok((None, OpenOptions::empty()));
+        }
         if self.no_opendir.load(Ordering::Relaxed) {
             info!("fuse: opendir is not supported.");
             Err(io::Error::from_raw_os_error(libc::ENOSYS))
         } else {
-            self.do_open(inode, flags | (libc::O_DIRECTORY as u32), 0)
+            self.do_open(&cfg, inode, flags | (libc::O_DIRECTORY as u32), 0)
         }
     }
 
@@ -406,7 +759,11 @@ impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
         _flags: u32,
         handle: Handle,
     ) -> io::Result<()> {
-        self.do_release(inode, handle)
+        let cfg = self.cfg.load();
+        if self.synthetic.get(inode).is_some() {
+            return Ok(());
+        }
+        self.do_release(&cfg, inode, handle)
     }
 
     fn mkdir(
@@ -417,27 +774,33 @@ impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
         mode: u32,
         umask: u32,
     ) -> io::Result<Entry> {
-        self.validate_path_component(name)?;
+        let cfg = self.cfg.load();
+        cfg.write_policy.check_namespace()?;
+        self.validate_path_component(&cfg, name)?;
+        self.check_synthetic(parent, Some(name))?;
 
         let data = self.inode_map.get(parent)?;
 
         let res = {
-            let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
+            let (_uid, _gid) = self.set_creds(&cfg, ctx)?;
 
             let file = data.get_file(&self.mount_fds)?;
             // Safe because this doesn't modify any memory and we check the return value.
             unsafe { libc::mkdirat(file.as_raw_fd(), name.as_ptr(), mode & !umask) }
         };
         if res == 0 {
-            self.do_lookup(parent, name)
+            self.do_lookup(&cfg, parent, name)
         } else {
             Err(io::Error::last_os_error())
         }
     }
 
-    fn rmdir(&self, _ctx: &Context, parent: Inode, name: &CStr) -> io::Result<()> {
-        self.validate_path_component(name)?;
-        self.do_unlink(parent, name, libc::AT_REMOVEDIR)
+    fn rmdir(&self, ctx: &Context, parent: Inode, name: &CStr) -> io::Result<()> {
+        let cfg = self.cfg.load();
+        cfg.write_policy.check_namespace()?;
+        self.validate_path_component(&cfg, name)?;
+        self.check_synthetic(parent, Some(name))?;
+        self.do_unlink(&cfg, ctx, parent, name, libc::AT_REMOVEDIR)
     }
 
     fn readdir(
@@ -449,24 +812,41 @@ impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
         offset: u64,
         add_entry: &mut dyn FnMut(DirEntry) -> io::Result<usize>,
     ) -> io::Result<()> {
+        let cfg = self.cfg.load();
         if self.no_readdir.load(Ordering::Relaxed) {
             return Ok(());
         }
-        self.do_readdir(inode, handle, size, offset, &mut |mut dir_entry, dir| {
-            dir_entry.ino = {
-                // Safe because do_readdir() has ensured dir_entry.name is a
-                // valid [u8] generated by CStr::to_bytes().
-                let name = unsafe {
-                    CStr::from_bytes_with_nul_unchecked(std::slice::from_raw_parts(
-                        &dir_entry.name[0],
-                        dir_entry.name.len() + 1,
-                    ))
-                };
-
-                let st = Self::stat(&dir, Some(name))?;
-                st.st_ino
-            };
-
+        // Synthetic files are listed after the backing entries.
+        let end = offset >= SYNTHETIC_OFFSET
+            || self.synthetic.get(inode).is_some()
+            || self.do_readdir(
+                &cfg,
+                inode,
+                handle,
+                size,
+                offset,
+                &mut |mut dir_entry, dir| {
+                    dir_entry.ino = {
+                        // Safe because do_readdir() has ensured dir_entry.name is a
+                        // valid [u8] generated by CStr::to_bytes().
+                        let name = unsafe {
+                            CStr::from_bytes_with_nul_unchecked(std::slice::from_raw_parts(
+                                &dir_entry.name[0],
+                                dir_entry.name.len() + 1,
+                            ))
+                        };
+
+                        let st = Self::stat(&dir, Some(self.dot_entry_name(inode, name)))?;
+                        st.st_ino
+                    };
+
+                    add_entry(dir_entry)
+                },
+            )?;
+        if !end {
+            return Ok(());
+        }
+        self.do_synthetic_readdir(&cfg, inode, offset, &mut |dir_entry, _| {
             add_entry(dir_entry)
         })
     }
@@ -480,32 +860,58 @@ impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
         offset: u64,
         add_entry: &mut dyn FnMut(DirEntry, Entry) -> io::Result<usize>,
     ) -> io::Result<()> {
+        let cfg = self.cfg.load();
         if self.no_readdir.load(Ordering::Relaxed) {
             return Ok(());
         }
-        self.do_readdir(inode, handle, size, offset, &mut |mut dir_entry, _dir| {
-            // Safe because do_readdir() has ensured dir_entry.name is a
-            // valid [u8] generated by CStr::to_bytes().
-            let name = unsafe {
-                CStr::from_bytes_with_nul_unchecked(std::slice::from_raw_parts(
-                    &dir_entry.name[0],
-                    dir_entry.name.len() + 1,
-                ))
-            };
-            let entry = self.do_lookup(inode, name)?;
-            let ino = entry.inode;
-            dir_entry.ino = entry.attr.st_ino;
-
-            add_entry(dir_entry, entry).map(|r| {
-                // true when size is not large enough to hold entry.
-                if r == 0 {
-                    // Release the refcount acquired by self.do_lookup().
-                    let mut inodes = self.inode_map.get_map_mut();
-                    Self::forget_one(&mut inodes, ino, 1);
-                }
-                r
-            })
-        })
+        let end = offset >= SYNTHETIC_OFFSET
+            || self.synthetic.get(inode).is_some()
+            || self.do_readdir(
+                &cfg,
+                inode,
+                handle,
+                size,
+                offset,
+                &mut |mut dir_entry, dir| {
+                    // Safe because do_readdir() has ensured dir_entry.name is a
+                    // valid [u8] generated by CStr::to_bytes().
+                    let name = unsafe {
+                        CStr::from_bytes_with_nul_unchecked(std::slice::from_raw_parts(
+                            &dir_entry.name[0],
+                            dir_entry.name.len() + 1,
+                        ))
+                    };
+                    if is_dot_or_dotdot(name) {
+                        // The kernel never instantiates dentries for "." and "..", and it won't send
+                        // FORGET for them either, so report the attributes without taking a lookup
+                        // reference.
+                        let st = Self::stat(&dir, Some(self.dot_entry_name(inode, name)))?;
+                        dir_entry.ino = st.st_ino;
+                        let entry = Entry {
+                            inode: 0,
+                            attr: self.guest_stat(&cfg, st),
+                            ..Default::default()
+                        };
+                        return add_entry(dir_entry, entry);
+                    }
+
+                    let entry = self.do_lookup(&cfg, inode, name)?;
+                    let ino = entry.inode;
+                    dir_entry.ino = entry.attr.st_ino;
+
+                    let res = add_entry(dir_entry, entry);
+                    // The kernel only takes over the lookup reference if the entry has made it into the
+                    // reply, i.e. neither failed nor ran out of space.
+                    if !matches!(res, Ok(n) if n > 0) {
+                        self.release_lookup(&cfg, ino);
+                    }
+                    res
+                },
+            )?;
+        if !end {
+            return Ok(());
+        }
+        self.do_synthetic_readdir(&cfg, inode, offset, add_entry)
     }
 
     fn open(
@@ -515,11 +921,20 @@ impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
         flags: u32,
         fuse_flags: u32,
     ) -> io::Result<(Option<Handle>, OpenOptions)> {
+        let cfg = self.cfg.load();
+        cfg.write_policy.check_open(flags)?;
+        if self.synthetic.get(inode).is_some() {
+            if flags as i32 & (libc::O_ACCMODE | libc::O_TRUNC) != libc::O_RDONLY {
+                return Err(io::Error::from_raw_os_error(libc::EROFS));
+            }
+            // The content is generated on each read, so don't let the kernel cache it.
+            return Ok((None, OpenOptions::DIRECT_IO));
+        }
         if self.no_open.load(Ordering::Relaxed) {
             info!("fuse: open is not supported.");
             Err(io::Error::from_raw_os_error(libc::ENOSYS))
         } else {
-            self.do_open(inode, flags, fuse_flags)
+            self.do_open(&cfg, inode, flags, fuse_flags)
         }
     }
 
@@ -531,12 +946,19 @@ impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
         handle: Handle,
         _flush: bool,
         _flock_release: bool,
-        _lock_owner: Option<u64>,
+        lock_owner: Option<u64>,
     ) -> io::Result<()> {
+        let cfg = self.cfg.load();
+        if let Some(owner) = lock_owner {
+            self.posix_locks.release_owner(inode, owner);
+        }
+        if self.synthetic.get(inode).is_some() {
+            return Ok(());
+        }
         if self.no_open.load(Ordering::Relaxed) {
             Err(io::Error::from_raw_os_error(libc::ENOSYS))
         } else {
-            self.do_release(inode, handle)
+            self.do_release(&cfg, inode, handle)
         }
     }
 
@@ -547,23 +969,38 @@ impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
         name: &CStr,
         args: CreateIn,
     ) -> io::Result<(Entry, Option<Handle>, OpenOptions)> {
-        self.validate_path_component(name)?;
+        let cfg = self.cfg.load();
+        cfg.write_policy.check_namespace()?;
+        self.validate_path_component(&cfg, name)?;
+        self.check_synthetic(parent, Some(name))?;
 
         let dir = self.inode_map.get(parent)?;
         let dir_file = dir.get_file(&self.mount_fds)?;
 
-        let new_file = {
-            let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
+        // Files created now can't have fs-verity enabled, so with `require_verity` only existing
+        // files may be opened by create requests, which `open_inode()` checks.
+        let new_file = if cfg.require_verity {
+            if args.flags as i32 & libc::O_EXCL != 0 {
+                return Err(io::Error::from_raw_os_error(libc::EPERM));
+            }
+            None
+        } else {
+            let (_uid, _gid) = self.set_creds(&cfg, ctx)?;
 
             Self::create_file_excl(
                 dir_file.as_raw_fd(),
                 name,
-                args.flags as i32,
+                self.backing_open_flags(args.flags as i32),
                 args.mode & !(args.umask & 0o777),
             )?
         };
 
-        let entry = self.do_lookup(parent, name)?;
+        let entry = match self.do_lookup(&cfg, parent, name) {
+            Err(e) if cfg.require_verity && e.raw_os_error() == Some(libc::ENOENT) => {
+                return Err(io::Error::from_raw_os_error(libc::EPERM));
+            }
+            res => res?,
+        };
         let file = match new_file {
             // File didn't exist, now created by create_file_excl()
             Some(f) => f,
@@ -579,12 +1016,98 @@ impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
                     None
                 };
 
-                let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
-                self.open_inode(entry.inode, args.flags as i32)?
+                let (_uid, _gid) = self.set_creds(&cfg, ctx)?;
+                self.open_inode(&cfg, entry.inode, args.flags as i32)
+                    .map_err(|e| {
+                        self.release_lookup(&cfg, entry.inode);
+                        e
+                    })?
+            }
+        };
+
+        let ret_handle = if !self.no_open.load(Ordering::Relaxed) {
+            self.advise_readahead(&cfg, entry.inode, &file);
+            let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
+            let data = HandleData::new(entry.inode, file);
+
+            self.handle_map.insert(handle, data);
+            Some(handle)
+        } else {
+            None
+        };
+
+        let mut opts = OpenOptions::empty();
+        match cfg.cache_policy {
+            CachePolicy::Never => opts |= OpenOptions::DIRECT_IO,
+            CachePolicy::Always => opts |= OpenOptions::KEEP_CACHE,
+            _ => {}
+        };
+
+        Ok((entry, ret_handle, opts))
+    }
+
+    fn tmpfile(
+        &self,
+        ctx: &Context,
+        parent: Inode,
+        mode: u32,
+        umask: u32,
+        flags: u32,
+    ) -> io::Result<(Entry, Option<Handle>, OpenOptions)> {
+        let cfg = self.cfg.load();
+        cfg.write_policy.check_namespace()?;
+        self.check_synthetic(parent, None)?;
+        // Temporary files can't have fs-verity enabled while they're open.
+        if cfg.require_verity {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+        let dir = self.inode_map.get(parent)?;
+        let dir_file = dir.get_file(&self.mount_fds)?;
+
+        // Like open_inode(), the kernel may send read requests for write-only files when
+        // writeback caching is enabled, and it's responsible for handling `O_APPEND`.
+        let mut flags = flags as i32 | libc::O_TMPFILE | libc::O_CLOEXEC;
+        if self.writeback.load(Ordering::Relaxed) {
+            if flags & libc::O_ACCMODE == libc::O_WRONLY {
+                flags &= !libc::O_ACCMODE;
+                flags |= libc::O_RDWR;
             }
+            flags &= !libc::O_APPEND;
+        }
+        // Safe because this is a constant value and a valid C string.
+        let current = unsafe { CStr::from_bytes_with_nul_unchecked(CURRENT_DIR_CSTR) };
+
+        let file = {
+            let (_uid, _gid) = self.set_creds(&cfg, ctx)?;
+            Self::open_file(
+                dir_file.as_raw_fd(),
+                current,
+                flags & !libc::O_NOFOLLOW,
+                mode & !(umask & 0o777),
+            )?
+        };
+
+        // The file has no name, so reference it by an `O_PATH` fd reopened from the proc fd.
+        let stat = Self::stat(&file, None)?;
+        let path_file = Self::open_proc_file(
+            &self.proc_self_fd,
+            file.as_raw_fd(),
+            libc::O_PATH,
+            stat.st_mode,
+        )?;
+        // Safe because this is a constant value and a valid C string.
+        let empty = unsafe { CStr::from_bytes_with_nul_unchecked(EMPTY_CSTR) };
+        let mnt_id = match FileHandle::from_name_at(path_file.as_raw_fd(), empty) {
+            Ok(h) => h.mnt_id,
+            Err(_) => 0,
         };
+        let st = InodeStat { stat, mnt_id };
+        let ids_altkey = InodeAltKey::ids_from_stat(&st);
+        let entry =
+            self.do_lookup_entry(&cfg, FileOrHandle::File(path_file), st, ids_altkey, None)?;
 
         let ret_handle = if !self.no_open.load(Ordering::Relaxed) {
+            self.advise_readahead(&cfg, entry.inode, &file);
             let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
             let data = HandleData::new(entry.inode, file);
 
@@ -595,7 +1118,7 @@ impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
         };
 
         let mut opts = OpenOptions::empty();
-        match self.cfg.cache_policy {
+        match cfg.cache_policy {
             CachePolicy::Never => opts |= OpenOptions::DIRECT_IO,
             CachePolicy::Always => opts |= OpenOptions::KEEP_CACHE,
             _ => {}
@@ -604,9 +1127,12 @@ impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
         Ok((entry, ret_handle, opts))
     }
 
-    fn unlink(&self, _ctx: &Context, parent: Inode, name: &CStr) -> io::Result<()> {
-        self.validate_path_component(name)?;
-        self.do_unlink(parent, name, 0)
+    fn unlink(&self, ctx: &Context, parent: Inode, name: &CStr) -> io::Result<()> {
+        let cfg = self.cfg.load();
+        cfg.write_policy.check_namespace()?;
+        self.validate_path_component(&cfg, name)?;
+        self.check_synthetic(parent, Some(name))?;
+        self.do_unlink(&cfg, ctx, parent, name, 0)
     }
 
     #[cfg(any(feature = "vhost-user-fs", feature = "virtiofs"))]
@@ -626,13 +1152,16 @@ impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
             inode, foffset, len, flags, moffset
         );
 
+        let cfg = self.cfg.load();
         let open_flags = if (flags & virtio_fs::SetupmappingFlags::WRITE.bits()) != 0 {
+            cfg.write_policy.check_data()?;
+            self.check_synthetic(inode, None)?;
             libc::O_RDWR
         } else {
             libc::O_RDONLY
         };
 
-        let file = self.open_inode(inode, open_flags as i32)?;
+        let file = self.open_inode(&cfg, inode, open_flags as i32)?;
         (*vu_req).map(foffset, moffset, len, flags, file.as_raw_fd())
     }
 
@@ -655,18 +1184,53 @@ impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
         w: &mut dyn ZeroCopyWriter,
         size: u32,
         offset: u64,
-        _lock_owner: Option<u64>,
+        lock_owner: Option<u64>,
         _flags: u32,
     ) -> io::Result<usize> {
-        let data = self.get_data(handle, inode, libc::O_RDONLY)?;
+        let cfg = self.cfg.load();
+        if let Some(file) = self.synthetic.get(inode) {
+            let buf = file.read(offset, size)?;
+            let len = std::cmp::min(buf.len(), size as usize);
+            w.write_all(&buf[..len])?;
+            return Ok(len);
+        }
+        let data = self.get_data(&cfg, handle, inode, libc::O_RDONLY)?;
+        self.check_mandatory_lock(
+            inode,
+            data.get_handle_raw_fd(),
+            lock_owner,
+            offset,
+            size,
+            false,
+        )?;
 
         // Manually implement File::try_clone() by borrowing fd of data.file instead of dup().
         // It's safe because the `data` variable's lifetime spans the whole function,
         // so data.file won't be closed.
-        let f = unsafe { File::from_raw_fd(data.get_handle_raw_fd()) };
+        let fd = data.get_handle_raw_fd();
+        let f = unsafe { File::from_raw_fd(fd) };
         let mut f = ManuallyDrop::new(f);
 
-        w.write_from(&mut *f, size as usize, offset)
+        let mut hasher = self.audit_hasher(&cfg, &data);
+        let res = self.copy_with_yield(&cfg, size as usize, offset, &mut |size, offset| {
+            let mut f = AuditedFile::new(&mut f, &mut hasher);
+            match w.write_from(&mut f, size, offset) {
+                // Nothing has been copied on failure, so it's safe to retry.
+                Err(e) if e.raw_os_error() == Some(libc::EINVAL) && clear_direct_io(fd)? => {
+                    w.write_from(&mut f, size, offset)
+                }
+                res => res,
+            }
+        });
+        if let (Ok(len), Some(hasher), Some(sink)) = (&res, hasher, &cfg.audit_hash) {
+            sink.record(AuditRecord {
+                inode,
+                offset,
+                len: *len,
+                digest: hasher.finish(),
+            });
+        }
+        res
     }
 
     fn write(
@@ -677,28 +1241,72 @@ impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
         r: &mut dyn ZeroCopyReader,
         size: u32,
         offset: u64,
-        _lock_owner: Option<u64>,
+        lock_owner: Option<u64>,
         _delayed_write: bool,
-        _flags: u32,
+        flags: u32,
         fuse_flags: u32,
     ) -> io::Result<usize> {
-        let data = self.get_data(handle, inode, libc::O_RDWR)?;
+        let cfg = self.cfg.load();
+        cfg.write_policy.check_data()?;
+        self.check_synthetic(inode, None)?;
+        // Without open requests, the file is opened for each write, so honor `O_APPEND` of the
+        // file opened by the guest.
+        let data = self.get_data(
+            &cfg,
+            handle,
+            inode,
+            libc::O_RDWR | (flags as i32 & libc::O_APPEND),
+        )?;
+        self.check_mandatory_lock(
+            inode,
+            data.get_handle_raw_fd(),
+            lock_owner,
+            offset,
+            size,
+            true,
+        )?;
 
         // Manually implement File::try_clone() by borrowing fd of data.file instead of dup().
         // It's safe because the `data` variable's lifetime spans the whole function,
         // so data.file won't be closed.
-        let f = unsafe { File::from_raw_fd(data.get_handle_raw_fd()) };
+        let fd = data.get_handle_raw_fd();
+        let f = unsafe { File::from_raw_fd(fd) };
         let mut f = ManuallyDrop::new(f);
 
+        // With kill_priv_v2, the kernel decides whether the write should clear the setuid/setgid
+        // bits and file capabilities, regardless of the credentials of the request, which don't
+        // belong to the original writer for writes from the writeback cache.
+        let killpriv_v2 = self.killpriv_v2.load(Ordering::Relaxed);
+        let kill_priv = fuse_flags & WRITE_KILL_PRIV != 0;
+
         // Cap restored when _killpriv is dropped
-        let _killpriv =
-            if self.killpriv_v2.load(Ordering::Relaxed) && (fuse_flags & WRITE_KILL_PRIV != 0) {
-                self::drop_cap_fsetid()?
-            } else {
-                None
-            };
+        let _killpriv = if killpriv_v2 && kill_priv {
+            self::drop_cap_fsetid()?
+        } else {
+            None
+        };
+
+        // Without CAP_FSETID the backing file system always clears the setuid/setgid bits, so
+        // restore them if the kernel hasn't asked for it.
+        let mode = if killpriv_v2 && !kill_priv && !self.cap_fsetid.load(Ordering::Relaxed) {
+            Self::privileged_mode(fd)?
+        } else {
+            None
+        };
+
+        let res = self.copy_with_yield(&cfg, size as usize, offset, &mut |size, offset| match r
+            .read_to(&mut *f, size, offset)
+        {
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) && clear_direct_io(fd)? => {
+                r.read_to(&mut *f, size, offset)
+            }
+            res => res,
+        });
+        if let (Ok(_), Some(mode)) = (&res, mode) {
+            Self::restore_privileged_mode(fd, mode);
+        }
 
-        r.read_to(&mut *f, size as usize, offset)
+        res
     }
 
     fn getattr(
@@ -707,7 +1315,88 @@ impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
         inode: Inode,
         handle: Option<Handle>,
     ) -> io::Result<(libc::stat64, Duration)> {
-        self.do_getattr(inode, handle)
+        let cfg = self.cfg.load();
+        if let Some(file) = self.synthetic.get(inode) {
+            return Ok((self.synthetic_stat(&cfg, inode, &file)?, cfg.attr_timeout));
+        }
+        self.do_getattr(&cfg, inode, handle)
+    }
+
+    fn statx(
+        &self,
+        _ctx: &Context,
+        inode: Inode,
+        handle: Option<Handle>,
+        flags: u32,
+        mask: u32,
+    ) -> io::Result<(Statx, Duration)> {
+        let cfg = self.cfg.load();
+        let data = self.inode_map.get(inode)?;
+        let file = data.get_file(&self.mount_fds)?;
+        let hd = match handle {
+            Some(h) => Some(self.handle_map.get(h, inode)?),
+            None => None,
+        };
+        let fd = match &hd {
+            Some(hd) => hd.get_handle_raw_fd(),
+            None => file.as_raw_fd(),
+        };
+
+        let empty = unsafe { CStr::from_bytes_with_nul_unchecked(EMPTY_CSTR) };
+        let mut stx = MaybeUninit::<libc::statx>::zeroed();
+        // Safe because the kernel only writes into `stx` and we check the return value.
+        let res = unsafe {
+            libc::statx(
+                fd,
+                empty.as_ptr(),
+                libc::AT_EMPTY_PATH | (flags as i32 & libc::AT_STATX_SYNC_TYPE),
+                mask,
+                stx.as_mut_ptr(),
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because statx() succeeded and filled in the structure.
+        let stx = unsafe { stx.assume_init() };
+
+        let time = |t: libc::statx_timestamp| SxTime {
+            tv_sec: t.tv_sec,
+            tv_nsec: t.tv_nsec,
+            reserved: 0,
+        };
+        let st = Statx {
+            mask: stx.stx_mask,
+            blksize: self.guest_blksize(&cfg, inode, stx.stx_blksize),
+            attributes: stx.stx_attributes,
+            nlink: stx.stx_nlink,
+            uid: self
+                .cfg
+                .load()
+                .id_offset
+                .map_or(stx.stx_uid, |ids| ids.guest_uid(stx.stx_uid)),
+            gid: self
+                .cfg
+                .load()
+                .id_offset
+                .map_or(stx.stx_gid, |ids| ids.guest_gid(stx.stx_gid)),
+            mode: self.guest_mode(&cfg, stx.stx_mode as u32) as u16,
+            ino: stx.stx_ino,
+            size: stx.stx_size,
+            blocks: stx.stx_blocks,
+            attributes_mask: stx.stx_attributes_mask,
+            atime: time(stx.stx_atime),
+            btime: time(stx.stx_btime),
+            ctime: time(stx.stx_ctime),
+            mtime: time(stx.stx_mtime),
+            rdev_major: stx.stx_rdev_major,
+            rdev_minor: stx.stx_rdev_minor,
+            dev_major: stx.stx_dev_major,
+            dev_minor: stx.stx_dev_minor,
+            ..Default::default()
+        };
+
+        Ok((st, cfg.attr_timeout))
     }
 
     fn setattr(
@@ -718,6 +1407,9 @@ impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
         handle: Option<Handle>,
         valid: SetattrValid,
     ) -> io::Result<(libc::stat64, Duration)> {
+        let cfg = self.cfg.load();
+        cfg.write_policy.check_setattr(valid)?;
+        self.check_synthetic(inode, None)?;
         let inode_data = self.inode_map.get(inode)?;
 
         enum Data {
@@ -743,13 +1435,23 @@ impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
             }
         };
 
-        if valid.contains(SetattrValid::MODE) {
+        // Linux can't change the mode of a symlink itself, and chmod(2) on the proc path would
+        // follow the link and change the mode of its target instead, so leave symlinks alone.
+        if valid.contains(SetattrValid::MODE) && inode_data.mode & libc::S_IFMT != libc::S_IFLNK {
+            // Don't drop the bits hidden by `nosuid` and `noexec` from the backing file.
+            let mode = if cfg.nosuid || cfg.noexec {
+                let st = Self::stat(&file, None)?;
+                self.host_mode(&cfg, attr.st_mode, st.st_mode)
+            } else {
+                attr.st_mode
+            };
+
             // Safe because this doesn't modify any memory and we check the return value.
             let res = unsafe {
                 match data {
-                    Data::Handle(_, fd) => libc::fchmod(fd, attr.st_mode),
+                    Data::Handle(_, fd) => libc::fchmod(fd, mode),
                     Data::ProcPath(ref p) => {
-                        libc::fchmodat(self.proc_self_fd.as_raw_fd(), p.as_ptr(), attr.st_mode, 0)
+                        libc::fchmodat(self.proc_self_fd.as_raw_fd(), p.as_ptr(), mode, 0)
                     }
                 }
             };
@@ -759,14 +1461,15 @@ impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
         }
 
         if valid.intersects(SetattrValid::UID | SetattrValid::GID) {
+            let ids = cfg.id_offset;
             let uid = if valid.contains(SetattrValid::UID) {
-                attr.st_uid
+                ids.map_or(attr.st_uid, |ids| ids.host_uid(attr.st_uid))
             } else {
                 // Cannot use -1 here because these are unsigned values.
                 ::std::u32::MAX
             };
             let gid = if valid.contains(SetattrValid::GID) {
-                attr.st_gid
+                ids.map_or(attr.st_gid, |ids| ids.host_gid(attr.st_gid))
             } else {
                 // Cannot use -1 here because these are unsigned values.
                 ::std::u32::MAX
@@ -805,7 +1508,7 @@ impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
                 Data::Handle(_, fd) => unsafe { libc::ftruncate(fd, attr.st_size) },
                 _ => {
                     // There is no `ftruncateat` so we need to get a new fd and truncate it.
-                    let f = self.open_inode(inode, libc::O_NONBLOCK | libc::O_RDWR)?;
+                    let f = self.open_inode(&cfg, inode, libc::O_NONBLOCK | libc::O_RDWR)?;
                     unsafe { libc::ftruncate(f.as_raw_fd(), attr.st_size) }
                 }
             };
@@ -814,7 +1517,14 @@ impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
             }
         }
 
-        if valid.intersects(SetattrValid::ATIME | SetattrValid::MTIME) {
+        // Timestamps not being set are passed as `UTIME_OMIT`, so the nanoseconds of the others
+        // are kept as is.
+        if valid.intersects(
+            SetattrValid::ATIME
+                | SetattrValid::MTIME
+                | SetattrValid::ATIME_NOW
+                | SetattrValid::MTIME_NOW,
+        ) {
             let mut tvs = [
                 libc::timespec {
                     tv_sec: 0,
@@ -852,25 +1562,32 @@ impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
             }
         }
 
-        self.do_getattr(inode, handle)
+        self.do_getattr(&cfg, inode, handle)
     }
 
     fn rename(
         &self,
-        _ctx: &Context,
+        ctx: &Context,
         olddir: Inode,
         oldname: &CStr,
         newdir: Inode,
         newname: &CStr,
         flags: u32,
     ) -> io::Result<()> {
-        self.validate_path_component(oldname)?;
-        self.validate_path_component(newname)?;
+        let cfg = self.cfg.load();
+        cfg.write_policy.check_namespace()?;
+        self.validate_path_component(&cfg, oldname)?;
+        self.validate_path_component(&cfg, newname)?;
+        self.check_synthetic(olddir, Some(oldname))?;
+        self.check_synthetic(newdir, Some(newname))?;
 
         let old_inode = self.inode_map.get(olddir)?;
         let new_inode = self.inode_map.get(newdir)?;
         let old_file = old_inode.get_file(&self.mount_fds)?;
         let new_file = new_inode.get_file(&self.mount_fds)?;
+        // An existing target is replaced, or moved to the old name with RENAME_EXCHANGE.
+        self.check_sticky(&cfg, ctx, &old_file, oldname)?;
+        self.check_sticky(&cfg, ctx, &new_file, newname)?;
 
         // Safe because this doesn't modify any memory and we check the return value.
         // TODO: Switch to libc::renameat2 once https://github.com/rust-lang/libc/pull/1508 lands
@@ -901,13 +1618,16 @@ impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
         rdev: u32,
         umask: u32,
     ) -> io::Result<Entry> {
-        self.validate_path_component(name)?;
+        let cfg = self.cfg.load();
+        cfg.write_policy.check_namespace()?;
+        self.validate_path_component(&cfg, name)?;
+        self.check_synthetic(parent, Some(name))?;
 
         let data = self.inode_map.get(parent)?;
         let file = data.get_file(&self.mount_fds)?;
 
         let res = {
-            let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
+            let (_uid, _gid) = self.set_creds(&cfg, ctx)?;
 
             // Safe because this doesn't modify any memory and we check the return value.
             unsafe {
@@ -922,7 +1642,7 @@ impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
         if res < 0 {
             Err(io::Error::last_os_error())
         } else {
-            self.do_lookup(parent, name)
+            self.do_lookup(&cfg, parent, name)
         }
     }
 
@@ -933,7 +1653,10 @@ impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
         newparent: Inode,
         newname: &CStr,
     ) -> io::Result<Entry> {
-        self.validate_path_component(newname)?;
+        let cfg = self.cfg.load();
+        cfg.write_policy.check_namespace()?;
+        self.validate_path_component(&cfg, newname)?;
+        self.check_synthetic(newparent, Some(newname))?;
 
         let data = self.inode_map.get(inode)?;
         let new_inode = self.inode_map.get(newparent)?;
@@ -954,7 +1677,7 @@ impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
             )
         };
         if res == 0 {
-            self.do_lookup(newparent, newname)
+            self.do_lookup(&cfg, newparent, newname)
         } else {
             Err(io::Error::last_os_error())
         }
@@ -967,19 +1690,56 @@ impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
         parent: Inode,
         name: &CStr,
     ) -> io::Result<Entry> {
-        self.validate_path_component(name)?;
+        let cfg = self.cfg.load();
+        cfg.write_policy.check_namespace()?;
+        self.validate_path_component(&cfg, name)?;
+        self.check_synthetic(parent, Some(name))?;
 
         let data = self.inode_map.get(parent)?;
 
         let res = {
-            let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
+            let (_uid, _gid) = self.set_creds(&cfg, ctx)?;
 
             let file = data.get_file(&self.mount_fds)?;
             // Safe because this doesn't modify any memory and we check the return value.
             unsafe { libc::symlinkat(linkname.as_ptr(), file.as_raw_fd(), name.as_ptr()) }
         };
         if res == 0 {
-            self.do_lookup(parent, name)
+            self.do_lookup(&cfg, parent, name)
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    fn init_security_context(
+        &self,
+        _ctx: &Context,
+        parent: Inode,
+        name: &CStr,
+        _inode: Inode,
+        secctx: &SecContext,
+    ) -> io::Result<()> {
+        let data = self.inode_map.get(parent)?;
+        let file = data.get_file(&self.mount_fds)?;
+        let mut path = format!("/proc/self/fd/{}/", file.as_raw_fd()).into_bytes();
+        path.extend_from_slice(name.to_bytes());
+        let pathname =
+            CString::new(path).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        // Use `lsetxattr` to label a new symlink itself instead of its target. The context is
+        // set under its own name, bypassing the xattr error policy.
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res = unsafe {
+            libc::lsetxattr(
+                pathname.as_ptr(),
+                secctx.name.as_ptr(),
+                secctx.value.as_ptr() as *const libc::c_void,
+                secctx.value.len(),
+                0,
+            )
+        };
+        if res == 0 {
+            Ok(())
         } else {
             Err(io::Error::last_os_error())
         }
@@ -988,27 +1748,62 @@ impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
     fn readlink(&self, _ctx: &Context, inode: Inode) -> io::Result<Vec<u8>> {
         // Safe because this is a constant value and a valid C string.
         let empty = unsafe { CStr::from_bytes_with_nul_unchecked(EMPTY_CSTR) };
-        let mut buf = Vec::<u8>::with_capacity(libc::PATH_MAX as usize);
         let data = self.inode_map.get(inode)?;
         let file = data.get_file(&self.mount_fds)?;
 
-        // Safe because this will only modify the contents of `buf` and we check the return value.
-        let res = unsafe {
-            libc::readlinkat(
-                file.as_raw_fd(),
-                empty.as_ptr(),
-                buf.as_mut_ptr() as *mut libc::c_char,
-                libc::PATH_MAX as usize,
-            )
-        };
-        if res < 0 {
-            return Err(io::Error::last_os_error());
+        // readlinkat() doesn't append a terminating NUL and silently truncates the target to the
+        // buffer size, so the target is complete only if it's shorter than the buffer. Grow the
+        // buffer until it is, the target may be changed by others meanwhile.
+        let mut size = READLINK_INIT_SIZE;
+        loop {
+            let mut buf = Vec::<u8>::with_capacity(size);
+            // Safe because this will only modify the contents of `buf` and we check the return
+            // value.
+            let res = unsafe {
+                libc::readlinkat(
+                    file.as_raw_fd(),
+                    empty.as_ptr(),
+                    buf.as_mut_ptr() as *mut libc::c_char,
+                    size,
+                )
+            };
+            if res < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let len = res as usize;
+            if len < size {
+                // Safe because the kernel has filled `len` bytes, which is within the capacity.
+                unsafe { buf.set_len(len) };
+                return Ok(buf);
+            } else if size >= libc::PATH_MAX as usize {
+                return Err(io::Error::from_raw_os_error(libc::ENAMETOOLONG));
+            }
+            size = std::cmp::min(size * 2, libc::PATH_MAX as usize);
         }
+    }
 
-        // Safe because we trust the value returned by kernel.
-        unsafe { buf.set_len(res as usize) };
+    fn canonical_path(&self, _ctx: &Context, inode: Inode) -> io::Result<CString> {
+        let data = self.inode_map.get(inode)?;
+        let file = data.get_file(&self.mount_fds)?;
+        // An unlinked file has no path anymore, and the `/proc/self/fd` link would end with
+        // " (deleted)" instead.
+        let st = Self::stat_fd(file.as_raw_fd(), None)?;
+        if st.st_nlink == 0 {
+            return Err(io::Error::from_raw_os_error(libc::ENOENT));
+        }
 
-        Ok(buf)
+        // Don't disclose where the export lives on the host, the path is relative to the root.
+        // A file moved out of the exported tree behind our back can't be reached from the root.
+        let path = self
+            .relative_path(inode)
+            .map_err(|e| match e.raw_os_error() {
+                Some(libc::ENOENT) => io::Error::from_raw_os_error(libc::EXDEV),
+                _ => e,
+            })?;
+        let path = Path::new("/").join(path);
+        CString::new(path.into_os_string().into_vec())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
     }
 
     fn flush(
@@ -1016,8 +1811,13 @@ impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
         _ctx: &Context,
         inode: Inode,
         handle: Handle,
-        _lock_owner: u64,
+        lock_owner: u64,
     ) -> io::Result<()> {
+        // POSIX locks are released when any file descriptor of the owner is closed.
+        self.posix_locks.release_owner(inode, lock_owner);
+        if self.synthetic.get(inode).is_some() {
+            return Ok(());
+        }
         if self.no_open.load(Ordering::Relaxed) {
             return Err(io::Error::from_raw_os_error(libc::ENOSYS));
         }
@@ -1041,6 +1841,55 @@ impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
         }
     }
 
+    fn getlk(
+        &self,
+        _ctx: &Context,
+        inode: Inode,
+        _handle: Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<FileLock> {
+        let cfg = self.cfg.load();
+        if !cfg.posix_locks || flags & LK_FLOCK != 0 {
+            return Err(io::Error::from_raw_os_error(libc::ENOSYS));
+        }
+        self.posix_locks.getlk(inode, owner, lock)
+    }
+
+    fn setlk(
+        &self,
+        _ctx: &Context,
+        inode: Inode,
+        _handle: Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<()> {
+        let cfg = self.cfg.load();
+        if !cfg.posix_locks || flags & LK_FLOCK != 0 {
+            return Err(io::Error::from_raw_os_error(libc::ENOSYS));
+        }
+        self.posix_locks.setlk(inode, owner, lock)
+    }
+
+    fn setlkw(
+        &self,
+        _ctx: &Context,
+        inode: Inode,
+        _handle: Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<()> {
+        let cfg = self.cfg.load();
+        if !cfg.posix_locks || flags & LK_FLOCK != 0 {
+            return Err(io::Error::from_raw_os_error(libc::ENOSYS));
+        }
+        // Don't block the worker thread waiting for the lock, see `PosixLocks`.
+        self.posix_locks.setlk(inode, owner, lock)
+    }
+
     fn fsync(
         &self,
         _ctx: &Context,
@@ -1048,37 +1897,39 @@ impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
         datasync: bool,
         handle: Handle,
     ) -> io::Result<()> {
-        let data = self.get_data(handle, inode, libc::O_RDONLY)?;
-        let fd = data.get_handle_raw_fd();
-
-        // Safe because this doesn't modify any memory and we check the return value.
-        let res = unsafe {
-            if datasync {
-                libc::fdatasync(fd)
-            } else {
-                libc::fsync(fd)
-            }
-        };
-        if res == 0 {
-            Ok(())
-        } else {
-            Err(io::Error::last_os_error())
+        let cfg = self.cfg.load();
+        if self.synthetic.get(inode).is_some() {
+            return Ok(());
         }
+        let data = self.get_data(&cfg, handle, inode, libc::O_RDONLY)?;
+
+        Self::do_fsync(&data, datasync)
     }
 
     fn fsyncdir(
         &self,
-        ctx: &Context,
+        _ctx: &Context,
         inode: Inode,
         datasync: bool,
         handle: Handle,
     ) -> io::Result<()> {
-        self.fsync(ctx, inode, datasync, handle)
+        let cfg = self.cfg.load();
+        // The handle is from `opendir`, which may be disabled independently of `open`.
+        let data = self.get_dirdata(&cfg, handle, inode, libc::O_RDONLY)?;
+
+        Self::do_fsync(&data, datasync)
     }
 
     fn access(&self, ctx: &Context, inode: Inode, mask: u32) -> io::Result<()> {
+        let cfg = self.cfg.load();
+        if self.synthetic.get(inode).is_some() {
+            if mask as i32 & libc::W_OK != 0 {
+                return Err(io::Error::from_raw_os_error(libc::EROFS));
+            }
+            return Ok(());
+        }
         let data = self.inode_map.get(inode)?;
-        let st = Self::stat(&data.get_file(&self.mount_fds)?, None)?;
+        let st = self.guest_stat(&cfg, Self::stat(&data.get_file(&self.mount_fds)?, None)?);
         let mode = mask as i32 & (libc::R_OK | libc::W_OK | libc::X_OK);
 
         if mode == libc::F_OK {
@@ -1126,9 +1977,13 @@ impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
         value: &[u8],
         flags: u32,
     ) -> io::Result<()> {
-        if !self.cfg.xattr {
+        let cfg = self.cfg.load();
+        cfg.write_policy.check_xattr()?;
+        self.check_synthetic(inode, None)?;
+        if !cfg.xattr {
             return Err(io::Error::from_raw_os_error(libc::ENOSYS));
         }
+        self.check_remapped_xattr(&cfg, name)?;
 
         let data = self.inode_map.get(inode)?;
         let file = data.get_file(&self.mount_fds)?;
@@ -1136,21 +1991,40 @@ impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
             .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
 
         // The f{set,get,remove,list}xattr functions don't work on an fd opened with `O_PATH` so we
-        // need to use the {set,get,remove,list}xattr variants.
-        // Safe because this doesn't modify any memory and we check the return value.
-        let res = unsafe {
-            libc::setxattr(
-                pathname.as_ptr(),
-                name.as_ptr(),
-                value.as_ptr() as *const libc::c_void,
-                value.len(),
-                flags as libc::c_int,
-            )
+        // need to use the {set,get,remove,list}xattr variants. The proc link resolves to the
+        // inode itself, which is opened with `O_NOFOLLOW`, so symlinks are not followed here.
+        let set = |name: &CStr| {
+            // Safe because this doesn't modify any memory and we check the return value.
+            let res = unsafe {
+                libc::setxattr(
+                    pathname.as_ptr(),
+                    name.as_ptr(),
+                    value.as_ptr() as *const libc::c_void,
+                    value.len(),
+                    flags as libc::c_int,
+                )
+            };
+            if res == 0 {
+                Ok(())
+            } else {
+                Err(io::Error::last_os_error())
+            }
         };
-        if res == 0 {
-            Ok(())
-        } else {
-            Err(io::Error::last_os_error())
+
+        match set(name) {
+            Err(e) if cfg.xattr_on_error.applies(name, &e) => {
+                if cfg.xattr_on_error == XattrErrorPolicy::Remap {
+                    debug!("fuse: remap xattr {:?} of inode {}: {}", name, inode, e);
+                    set(&remapped_xattr_name(name))
+                } else {
+                    warn!(
+                        "fuse: ignore failure to set xattr {:?} of inode {}: {}",
+                        name, inode, e
+                    );
+                    Ok(())
+                }
+            }
+            res => res,
         }
     }
 
@@ -1161,53 +2035,86 @@ impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
         name: &CStr,
         size: u32,
     ) -> io::Result<GetxattrReply> {
-        if !self.cfg.xattr {
+        let cfg = self.cfg.load();
+        if !cfg.xattr {
             return Err(io::Error::from_raw_os_error(libc::ENOSYS));
         }
+        self.check_remapped_xattr(&cfg, name)?;
 
         let data = self.inode_map.get(inode)?;
         let file = data.get_file(&self.mount_fds)?;
-        let mut buf = Vec::<u8>::with_capacity(size as usize);
+        let mut buf = ReplyBuf::with_capacity(size as usize);
         let pathname = CString::new(format!("/proc/self/fd/{}", file.as_raw_fd(),))
             .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
 
         // The f{set,get,remove,list}xattr functions don't work on an fd opened with `O_PATH` so we
         // need to use the {set,get,remove,list}xattr variants.
-        // Safe because this will only modify the contents of `buf`.
-        let res = unsafe {
-            libc::getxattr(
-                pathname.as_ptr(),
-                name.as_ptr(),
-                buf.as_mut_ptr() as *mut libc::c_void,
-                size as libc::size_t,
-            )
+        let mut get = |name: &CStr| {
+            // Safe because this will only modify the contents of `buf`.
+            let res = unsafe {
+                libc::getxattr(
+                    pathname.as_ptr(),
+                    name.as_ptr(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    size as libc::size_t,
+                )
+            };
+            if res < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(res)
+            }
         };
-        if res < 0 {
-            return Err(io::Error::last_os_error());
-        }
+
+        let res = match get(name) {
+            Err(e)
+                if cfg.xattr_on_error == XattrErrorPolicy::Remap
+                    && is_privileged_xattr(name.to_bytes())
+                    && matches!(
+                        e.raw_os_error(),
+                        Some(libc::ENODATA) | Some(libc::EOPNOTSUPP)
+                    ) =>
+            {
+                get(&remapped_xattr_name(name))
+            }
+            res => res,
+        }?;
 
         if size == 0 {
             Ok(GetxattrReply::Count(res as u32))
         } else {
             // Safe because we trust the value returned by kernel.
             unsafe { buf.set_len(res as usize) };
-            Ok(GetxattrReply::Value(buf))
+            Ok(GetxattrReply::Value(buf.into_inner()))
         }
     }
 
     fn listxattr(&self, _ctx: &Context, inode: Inode, size: u32) -> io::Result<ListxattrReply> {
-        if !self.cfg.xattr {
+        let cfg = self.cfg.load();
+        if !cfg.xattr {
             return Err(io::Error::from_raw_os_error(libc::ENOSYS));
         }
 
         let data = self.inode_map.get(inode)?;
         let file = data.get_file(&self.mount_fds)?;
-        let mut buf = Vec::<u8>::with_capacity(size as usize);
+        let mut buf = ReplyBuf::with_capacity(size as usize);
         let pathname = CString::new(format!("/proc/self/fd/{}", file.as_raw_fd()))
             .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
 
         // The f{set,get,remove,list}xattr functions don't work on an fd opened with `O_PATH` so we
         // need to use the {set,get,remove,list}xattr variants.
+        if cfg.xattr_on_error == XattrErrorPolicy::Remap {
+            // The size of the list seen by the client isn't known before getting the whole list.
+            let names = unmap_xattr_list(&list_all_xattrs(&pathname)?);
+            return if size == 0 {
+                Ok(ListxattrReply::Count(names.len() as u32))
+            } else if names.len() > size as usize {
+                Err(io::Error::from_raw_os_error(libc::ERANGE))
+            } else {
+                Ok(ListxattrReply::Names(names))
+            };
+        }
+
         // Safe because this will only modify the contents of `buf`.
         let res = unsafe {
             libc::listxattr(
@@ -1225,14 +2132,18 @@ impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
         } else {
             // Safe because we trust the value returned by kernel.
             unsafe { buf.set_len(res as usize) };
-            Ok(ListxattrReply::Names(buf))
+            Ok(ListxattrReply::Names(buf.into_inner()))
         }
     }
 
     fn removexattr(&self, _ctx: &Context, inode: Inode, name: &CStr) -> io::Result<()> {
-        if !self.cfg.xattr {
+        let cfg = self.cfg.load();
+        cfg.write_policy.check_xattr()?;
+        self.check_synthetic(inode, None)?;
+        if !cfg.xattr {
             return Err(io::Error::from_raw_os_error(libc::ENOSYS));
         }
+        self.check_remapped_xattr(&cfg, name)?;
 
         let data = self.inode_map.get(inode)?;
         let file = data.get_file(&self.mount_fds)?;
@@ -1241,12 +2152,38 @@ impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
 
         // The f{set,get,remove,list}xattr functions don't work on an fd opened with `O_PATH` so we
         // need to use the {set,get,remove,list}xattr variants.
-        // Safe because this doesn't modify any memory and we check the return value.
-        let res = unsafe { libc::removexattr(pathname.as_ptr(), name.as_ptr()) };
-        if res == 0 {
-            Ok(())
-        } else {
-            Err(io::Error::last_os_error())
+        let remove = |name: &CStr| {
+            // Safe because this doesn't modify any memory and we check the return value.
+            let res = unsafe { libc::removexattr(pathname.as_ptr(), name.as_ptr()) };
+            if res == 0 {
+                Ok(())
+            } else {
+                Err(io::Error::last_os_error())
+            }
+        };
+
+        match remove(name) {
+            Err(e)
+                if cfg.xattr_on_error == XattrErrorPolicy::Remap
+                    && is_privileged_xattr(name.to_bytes())
+                    && matches!(
+                        e.raw_os_error(),
+                        Some(libc::ENODATA)
+                            | Some(libc::EOPNOTSUPP)
+                            | Some(libc::EPERM)
+                            | Some(libc::EACCES)
+                    ) =>
+            {
+                // Report the original error if there is nothing remapped either.
+                remove(&remapped_xattr_name(name)).map_err(|re| {
+                    if re.raw_os_error() == Some(libc::ENODATA) {
+                        e
+                    } else {
+                        re
+                    }
+                })
+            }
+            res => res,
         }
     }
 
@@ -1259,8 +2196,11 @@ impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
         offset: u64,
         length: u64,
     ) -> io::Result<()> {
+        let cfg = self.cfg.load();
+        cfg.write_policy.check_data()?;
+        self.check_synthetic(inode, None)?;
         // Let the Arc<HandleData> in scope, otherwise fd may get invalid.
-        let data = self.get_data(handle, inode, libc::O_RDWR)?;
+        let data = self.get_data(&cfg, handle, inode, libc::O_RDWR)?;
         let fd = data.get_handle_raw_fd();
 
         // Safe because this doesn't modify any memory and we check the return value.
@@ -1279,6 +2219,205 @@ impl<D: AsyncDrive> FileSystem for PassthroughFs<D> {
         }
     }
 
+    fn clone_range(
+        &self,
+        _ctx: &Context,
+        src_inode: Inode,
+        src_handle: Handle,
+        src_offset: u64,
+        dst_inode: Inode,
+        dst_handle: Handle,
+        dst_offset: u64,
+        len: u64,
+    ) -> io::Result<usize> {
+        let cfg = self.cfg.load();
+        cfg.write_policy.check_data()?;
+        // Let the Arc<HandleData> in scope, otherwise fd may get invalid.
+        let src_data = self.get_data(&cfg, src_handle, src_inode, libc::O_RDONLY)?;
+        let dst_data = self.get_data(&cfg, dst_handle, dst_inode, libc::O_RDWR)?;
+        let src_fd = src_data.get_handle_raw_fd();
+        let dst_fd = dst_data.get_handle_raw_fd();
+
+        // Reflink only works within the same backing file system.
+        let src_st = Self::stat_fd(src_fd, None)?;
+        let dst_st = Self::stat_fd(dst_fd, None)?;
+        if src_st.st_dev != dst_st.st_dev {
+            return Err(io::Error::from_raw_os_error(libc::EXDEV));
+        }
+
+        let range = libc::file_clone_range {
+            src_fd: src_fd as i64,
+            src_offset,
+            src_length: len,
+            dest_offset: dst_offset,
+        };
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res = unsafe { libc::ioctl(dst_fd, libc::FICLONERANGE, &range) };
+        if res < 0 {
+            let e = io::Error::last_os_error();
+            // Unaligned ranges and file systems without reflink support fail with EINVAL or
+            // ENOTTY, let the kernel fall back to copying data instead of failing the request.
+            match e.raw_os_error() {
+                Some(libc::EINVAL) | Some(libc::ENOTTY) => {
+                    Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+                }
+                _ => Err(e),
+            }
+        } else if len == 0 {
+            // A zero length clones up to the end of the source file.
+            Ok((src_st.st_size as u64).saturating_sub(src_offset) as usize)
+        } else {
+            Ok(len as usize)
+        }
+    }
+
+    fn enable_verity(&self, _ctx: &Context, inode: Inode) -> io::Result<()> {
+        let data = self.inode_map.get(inode)?;
+        if data.mode & libc::S_IFMT != libc::S_IFREG {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        // Don't go through `open_inode()`, which refuses non-verity files if `require_verity` is
+        // set. The kernel also requires a read-only fd without any writers to enable fs-verity.
+        let file = data.get_file(&self.mount_fds)?;
+        let file = Self::open_proc_file(
+            &self.proc_self_fd,
+            file.as_raw_fd(),
+            libc::O_RDONLY,
+            data.mode,
+        )?;
+
+        let arg = FsverityEnableArg {
+            version: 1,
+            hash_algorithm: FS_VERITY_HASH_ALG_SHA256,
+            block_size: FS_VERITY_BLOCK_SIZE,
+            ..Default::default()
+        };
+        // Safe because the kernel only reads `arg` and we check the return value.
+        let res = unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_ENABLE_VERITY as _, &arg) };
+        if res < 0 {
+            let e = io::Error::last_os_error();
+            // Backing file systems without fs-verity support may fail with ENOTTY.
+            match e.raw_os_error() {
+                Some(libc::ENOTTY) => Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP)),
+                _ => Err(e),
+            }
+        } else {
+            Ok(())
+        }
+    }
+
+    fn measure_verity(&self, _ctx: &Context, inode: Inode) -> io::Result<Vec<u8>> {
+        let data = self.inode_map.get(inode)?;
+        let file = data.get_file(&self.mount_fds)?;
+        let file = Self::open_proc_file(
+            &self.proc_self_fd,
+            file.as_raw_fd(),
+            libc::O_RDONLY,
+            data.mode,
+        )?;
+
+        let mut digest = FsverityDigest {
+            digest_algorithm: 0,
+            digest_size: FS_VERITY_MAX_DIGEST_SIZE as u16,
+            digest: [0u8; FS_VERITY_MAX_DIGEST_SIZE],
+        };
+        // Safe because the kernel writes at most `digest_size` bytes into `digest` and we check
+        // the return value.
+        let res = unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_MEASURE_VERITY as _, &mut digest) };
+        if res < 0 {
+            let e = io::Error::last_os_error();
+            match e.raw_os_error() {
+                Some(libc::ENOTTY) => Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP)),
+                _ => Err(e),
+            }
+        } else {
+            let size = std::cmp::min(digest.digest_size as usize, FS_VERITY_MAX_DIGEST_SIZE);
+            Ok(digest.digest[..size].to_vec())
+        }
+    }
+
+    fn fiemap(
+        &self,
+        _ctx: &Context,
+        inode: Inode,
+        start: u64,
+        len: u64,
+    ) -> io::Result<Vec<FiemapExtent>> {
+        let data = self.inode_map.get(inode)?;
+        let file = data.get_file(&self.mount_fds)?;
+        let file = Self::open_proc_file(
+            &self.proc_self_fd,
+            file.as_raw_fd(),
+            libc::O_RDONLY,
+            data.mode,
+        )?;
+        let size = Self::stat(&file, None)?.st_size as u64;
+        let end = std::cmp::min(start.saturating_add(len), size);
+
+        let mut extents = Vec::new();
+        // End of the range covered by the reported extents so far.
+        let mut pos = start;
+        let mut last = false;
+        while pos < end && !last {
+            let mut arg = FiemapArg {
+                fm_start: pos,
+                fm_length: end - pos,
+                fm_flags: FIEMAP_FLAG_SYNC,
+                fm_extent_count: FIEMAP_BATCH_SIZE as u32,
+                ..Default::default()
+            };
+            // Safe because the kernel writes at most `fm_extent_count` extents into `arg` and we
+            // check the return value.
+            let res = unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP as _, &mut arg) };
+            if res < 0 {
+                let e = io::Error::last_os_error();
+                return match e.raw_os_error() {
+                    Some(libc::ENOTTY) => Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP)),
+                    _ => Err(e),
+                };
+            }
+            let mapped = std::cmp::min(arg.fm_mapped_extents as usize, FIEMAP_BATCH_SIZE);
+            if mapped == 0 {
+                break;
+            }
+
+            for e in arg.fm_extents[..mapped].iter() {
+                // Report holes in the file as unwritten extents.
+                if e.fe_logical > pos {
+                    extents.push(FiemapExtent {
+                        logical: pos,
+                        physical: 0,
+                        length: e.fe_logical - pos,
+                        flags: FiemapExtent::UNWRITTEN,
+                    });
+                }
+                extents.push(FiemapExtent {
+                    logical: e.fe_logical,
+                    physical: e.fe_physical,
+                    length: e.fe_length,
+                    flags: e.fe_flags & !FiemapExtent::LAST,
+                });
+                pos = std::cmp::max(pos, e.fe_logical + e.fe_length);
+                last = e.fe_flags & FiemapExtent::LAST != 0;
+            }
+        }
+        if pos < end {
+            extents.push(FiemapExtent {
+                logical: pos,
+                physical: 0,
+                length: end - pos,
+                flags: FiemapExtent::UNWRITTEN,
+            });
+        }
+        if end == size || last {
+            if let Some(e) = extents.last_mut() {
+                e.flags |= FiemapExtent::LAST;
+            }
+        }
+
+        Ok(extents)
+    }
+
     fn lseek(
         &self,
         _ctx: &Context,
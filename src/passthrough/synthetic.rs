@@ -0,0 +1,157 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Synthetic files overlaid on the backing directory tree of passthrough.
+//!
+//! A synthetic file is generated by the daemon, e.g. metadata computed on the fly, and shows up
+//! in a backing directory without existing there. It shadows a backing file with the same name.
+//! Synthetic files are read-only, and get their inodes from the same counter as backing files.
+//! Their inodes are never forgotten.
+
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use super::Inode;
+
+/// Offset of the first synthetic entry of a directory in readdir replies.
+///
+/// Backing file systems use offsets up to `i64::MAX`, so offsets from here on are free.
+pub(super) const SYNTHETIC_OFFSET: u64 = 1 << 63;
+
+/// A file generated by the daemon and served by passthrough as if it was a backing file.
+pub trait SyntheticFile: Send + Sync {
+    /// Get the attributes of the file. The type of the file is taken from `st_mode` and `st_ino`
+    /// is replaced with the inode number allocated by passthrough.
+    fn stat(&self) -> io::Result<libc::stat64>;
+
+    /// Read up to `size` bytes of content at `offset`.
+    fn read(&self, offset: u64, size: u32) -> io::Result<Vec<u8>>;
+
+    /// List the entries of a synthetic directory, without `.` and `..`.
+    fn readdir(&self) -> io::Result<Vec<(CString, Arc<dyn SyntheticFile>)>> {
+        Err(io::Error::from_raw_os_error(libc::ENOTDIR))
+    }
+}
+
+impl fmt::Debug for dyn SyntheticFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SyntheticFile({:p})", self)
+    }
+}
+
+// Synthetic files are compared by identity.
+impl PartialEq for dyn SyntheticFile {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(
+            self as *const Self as *const u8,
+            other as *const Self as *const u8,
+        )
+    }
+}
+
+type SyntheticEntries = Vec<(CString, Arc<dyn SyntheticFile>)>;
+
+#[derive(Default)]
+struct SyntheticNodes {
+    files: HashMap<Inode, Arc<dyn SyntheticFile>>,
+    inodes: HashMap<(Inode, CString), Inode>,
+}
+
+/// Synthetic files of a passthrough file system and the inodes allocated to them.
+#[derive(Default)]
+pub(super) struct SyntheticFiles {
+    // Synthetic files in backing directories, keyed by the path of the directory relative to the
+    // root.
+    entries: HashMap<PathBuf, SyntheticEntries>,
+    nodes: RwLock<SyntheticNodes>,
+}
+
+impl SyntheticFiles {
+    /// Create from `Config::synthetic_entries`.
+    pub fn new(entries: &[(PathBuf, Arc<dyn SyntheticFile>)]) -> io::Result<Self> {
+        let mut files = SyntheticFiles::default();
+        for (path, file) in entries {
+            let mut names = Vec::new();
+            for component in path.components() {
+                match component {
+                    Component::RootDir | Component::CurDir => {}
+                    Component::Normal(name) => names.push(name),
+                    Component::ParentDir | Component::Prefix(_) => {
+                        return Err(io::Error::from_raw_os_error(libc::EINVAL))
+                    }
+                }
+            }
+            let name = names
+                .pop()
+                .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
+            let name = CString::new(name.as_bytes())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            let dir = names.iter().collect::<PathBuf>();
+            files
+                .entries
+                .entry(dir)
+                .or_default()
+                .push((name, file.clone()));
+        }
+        Ok(files)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Get the synthetic file of `inode`, `None` for backing inodes.
+    pub fn get(&self, inode: Inode) -> Option<Arc<dyn SyntheticFile>> {
+        if self.is_empty() {
+            return None;
+        }
+        self.nodes.read().unwrap().files.get(&inode).cloned()
+    }
+
+    /// Get the entries of the synthetic directory `parent`, or the synthetic files in the
+    /// backing directory at `path`.
+    pub fn children(&self, parent: Inode, path: Option<&Path>) -> io::Result<SyntheticEntries> {
+        if let Some(file) = self.get(parent) {
+            return file.readdir();
+        }
+        Ok(path
+            .and_then(|p| self.entries.get(p))
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    /// Get the inode of the synthetic file `name` in `parent`, allocating one with `alloc` the
+    /// first time.
+    pub fn inode(
+        &self,
+        parent: Inode,
+        name: &CStr,
+        file: &Arc<dyn SyntheticFile>,
+        alloc: impl FnOnce() -> Inode,
+    ) -> Inode {
+        let key = (parent, name.to_owned());
+        {
+            let nodes = self.nodes.read().unwrap();
+            if let Some(inode) = nodes.inodes.get(&key) {
+                if nodes
+                    .files
+                    .get(inode)
+                    .map_or(false, |f| Arc::ptr_eq(f, file))
+                {
+                    return *inode;
+                }
+            }
+        }
+
+        let mut nodes = self.nodes.write().unwrap();
+        let inode = *nodes.inodes.entry(key).or_insert_with(alloc);
+        // Directories may return new files for the same name, keep serving the latest one.
+        nodes.files.insert(inode, file.clone());
+        inode
+    }
+}
@@ -10,10 +10,13 @@
 
 use mio::unix::SourceFd;
 use mio::{Events, Interest, Poll, Token, Waker};
+use std::ffi::CStr;
 use std::fs::{File, OpenOptions};
+use std::io::Read;
+use std::mem::size_of;
 use std::ops::Deref;
-use std::os::unix::fs::PermissionsExt;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
@@ -22,10 +25,20 @@ use nix::fcntl::{fcntl, FcntlArg, OFlag};
 use nix::mount::{mount, umount2, MntFlags, MsFlags};
 use nix::poll::{poll, PollFd, PollFlags};
 use nix::sys::epoll::{epoll_ctl, EpollEvent, EpollFlags, EpollOp};
-use nix::unistd::{getgid, getuid, read};
+use nix::unistd::{getgid, getuid, read, write};
+use vm_memory::ByteValued;
 
 use super::{
-    super::pagesize, Error::IoError, Error::SessionFailure, FuseBuf, Reader, Result, Writer,
+    super::pagesize, BufferProvider, ChannelBuffer, Error::IoError, Error::SessionClosed,
+    Error::SessionFailure, FuseBuf, FuseTransport, Reader, Reply, Request, Result,
+    TransportCounters, TransportLogger, TransportStats, Writer,
+};
+use crate::abi::fuse_abi::{
+    FsOptions, InHeader, InitIn, NotifyInvalEntryOut, NotifyInvalInodeOut, NotifyOpcode, Opcode,
+    OutHeader, KERNEL_MINOR_VERSION_NOTIFY_INVAL,
+};
+use crate::api::server::{
+    encode_notify, max_payload_size, negotiate_max_write, notify_entry_name, MIN_READ_BUFFER,
 };
 
 // These follows definition from libfuse.
@@ -34,11 +47,71 @@ const FUSE_HEADER_SIZE: usize = 0x1000;
 const POLL_EVENTS_CAPACITY: usize = 1024;
 
 const FUSE_DEVICE: &str = "/dev/fuse";
+const CUSE_DEVICE: &str = "/dev/cuse";
 const FUSE_FSTYPE: &str = "fuse";
 
 const EXIT_FUSE_EVENT: Token = Token(0);
 const FUSE_DEV_EVENT: Token = Token(1);
 
+/// The state of a fuse session to be handed over to another process.
+///
+/// It's used to upgrade a fuse daemon without interrupting the mountpoint. The kernel only sends
+/// the INIT request once per connection, so the new daemon resumes serving the inherited
+/// connection with the INIT parameters received by the old daemon:
+/// 1. The old daemon stops its channels and calls
+///    [FuseSession::export_state()](struct.FuseSession.html#method.export_state).
+/// 2. The old daemon sends the bytes from [encode()](SessionState::encode) along with the fd of
+///    [file](SessionState::file) as `SCM_RIGHTS` ancillary data over a unix domain socket, then
+///    exits without umounting.
+/// 3. The new daemon receives the bytes and the fd, rebuilds the state by
+///    [decode()](SessionState::decode), and passes it to
+///    [FuseSession::import_state()](struct.FuseSession.html#method.import_state) of a session
+///    created for the same mountpoint.
+/// 4. The new daemon calls `Server::resume()` with [init](SessionState::init), instead of
+///    waiting for an INIT request, and then starts serving channels.
+///
+/// Requests which have been read by the old daemon but not replied are lost, so the old daemon
+/// should drain its channels before exporting the state. The new file system driver also needs
+/// to recognize the inodes and handles held by the kernel, which isn't covered by the state.
+#[derive(Debug)]
+pub struct SessionState {
+    /// The connection to the in kernel fuse driver.
+    pub file: File,
+    /// The INIT request received from the kernel, if any.
+    pub init: Option<InitIn>,
+}
+
+impl SessionState {
+    /// Encode the state, except for the connection file, into bytes.
+    pub fn encode(&self) -> Vec<u8> {
+        match self.init.as_ref() {
+            Some(init) => {
+                let mut buf = vec![1u8];
+                buf.extend_from_slice(init.as_slice());
+                buf
+            }
+            None => vec![0u8],
+        }
+    }
+
+    /// Rebuild the state from bytes generated by [encode()](SessionState::encode) and the
+    /// connection file received from the old daemon.
+    pub fn decode(buf: &[u8], file: File) -> Result<SessionState> {
+        let init = match buf.split_first() {
+            Some((0, [])) => None,
+            Some((1, v)) if v.len() == size_of::<InitIn>() => {
+                // The buffer may not be aligned for `InitIn`.
+                let mut init = InitIn::default();
+                init.as_mut_slice().copy_from_slice(v);
+                Some(init)
+            }
+            _ => return Err(SessionFailure("invalid session state".to_string())),
+        };
+
+        Ok(SessionState { file, init })
+    }
+}
+
 /// A fuse session manager to manage the connection with the in kernel fuse driver.
 pub struct FuseSession {
     mountpoint: PathBuf,
@@ -46,34 +119,40 @@ pub struct FuseSession {
     subtype: String,
     file: Option<File>,
     bufsize: usize,
+    buf_provider: Option<Arc<dyn BufferProvider>>,
+    logger: Option<Arc<dyn TransportLogger>>,
+    counters: Arc<TransportCounters>,
     readonly: bool,
+    allow_other: bool,
+    // Owner of the mount if only the owner and root are allowed to access it.
+    allow_root: Option<u32>,
     wakers: Mutex<Vec<Arc<Waker>>>,
+    // The INIT request received from the kernel, shared with all channels.
+    init: Arc<Mutex<Option<InitIn>>>,
 }
 
 impl FuseSession {
     /// Create a new fuse session, without mounting/connecting to the in kernel fuse driver.
+    ///
+    /// The mountpoint is mounted with `allow_other`. Use [FuseSession::builder()] for other
+    /// options.
+    #[deprecated(since = "0.4.0", note = "use FuseSession::builder() instead")]
     pub fn new(
         mountpoint: &Path,
         fsname: &str,
         subtype: &str,
         readonly: bool,
     ) -> Result<FuseSession> {
-        let dest = mountpoint
-            .canonicalize()
-            .map_err(|_| SessionFailure(format!("invalid mountpoint {:?}", mountpoint)))?;
-        if !dest.is_dir() {
-            return Err(SessionFailure(format!("{:?} is not a directory", dest)));
-        }
+        FuseSessionBuilder::new(mountpoint)
+            .fsname(fsname)
+            .subtype(subtype)
+            .readonly(readonly)
+            .build()
+    }
 
-        Ok(FuseSession {
-            mountpoint: dest,
-            fsname: fsname.to_owned(),
-            subtype: subtype.to_owned(),
-            file: None,
-            bufsize: FUSE_KERN_BUF_SIZE * pagesize() + FUSE_HEADER_SIZE,
-            readonly,
-            wakers: Mutex::new(Vec::new()),
-        })
+    /// Start building a fuse session for `mountpoint`.
+    pub fn builder<P: AsRef<Path>>(mountpoint: P) -> FuseSessionBuilder {
+        FuseSessionBuilder::new(mountpoint)
     }
 
     /// Mount the fuse mountpoint, building connection with the in kernel fuse driver.
@@ -82,7 +161,13 @@ impl FuseSession {
         if self.readonly {
             flags |= MsFlags::MS_RDONLY;
         }
-        let file = fuse_kern_mount(&self.mountpoint, &self.fsname, &self.subtype, flags)?;
+        let file = fuse_kern_mount(
+            &self.mountpoint,
+            &self.fsname,
+            &self.subtype,
+            flags,
+            self.allow_other || self.allow_root.is_some(),
+        )?;
 
         fcntl(file.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))
             .map_err(|e| SessionFailure(format!("set fd nonblocking: {}", e)))?;
@@ -91,6 +176,56 @@ impl FuseSession {
         Ok(())
     }
 
+    /// Mount the fuse mountpoint from within a user namespace, without a privileged helper.
+    ///
+    /// Like [mount()](FuseSession::mount), the fuse file system is mounted by the `mount(2)`
+    /// syscall directly with the opened fuse device passed as the `fd=` mount option, so no
+    /// setuid `fusermount` is involved. It's viable for rootless containers, which own their user
+    /// and mount namespaces, e.g. created by `unshare --user --map-root-user --mount`. Viability
+    /// is checked by [check_userns_mount()](FuseSession::check_userns_mount) first, so a clear
+    /// error is returned instead of `EPERM` from the kernel. Mounting fuse in a user namespace
+    /// requires linux 4.18 or later.
+    pub fn mount_in_userns(&mut self) -> Result<()> {
+        Self::check_userns_mount()?;
+        self.mount()
+    }
+
+    /// Check whether the fuse file system can be mounted without a privileged helper.
+    ///
+    /// It requires `CAP_SYS_ADMIN` in the current user namespace, and the current mount namespace
+    /// to be owned by the current user namespace.
+    pub fn check_userns_mount() -> Result<()> {
+        const HINT: &str = "run in a new user and mount namespace, e.g. by `unshare -Urm`";
+
+        let cap_sys_admin = caps::has_cap(
+            None,
+            caps::CapSet::Effective,
+            caps::Capability::CAP_SYS_ADMIN,
+        )
+        .map_err(|e| SessionFailure(format!("failed to get capabilities: {}", e)))?;
+        if !cap_sys_admin {
+            return Err(SessionFailure(format!(
+                "mounting fuse requires CAP_SYS_ADMIN in the current user namespace, {}",
+                HINT
+            )));
+        }
+
+        let userns = std::fs::metadata("/proc/self/ns/user")
+            .map_err(|e| SessionFailure(format!("failed to get user namespace: {}", e)))?;
+        match mntns_owner() {
+            Ok(owner) if owner != userns.ino() => Err(SessionFailure(format!(
+                "the mount namespace is not owned by the current user namespace, {}",
+                HINT
+            ))),
+            Ok(_) => Ok(()),
+            // NS_GET_USERNS is not supported by the kernel, let the mount syscall decide.
+            Err(e) => {
+                warn!("fuse: failed to get owner of the mount namespace, {}", e);
+                Ok(())
+            }
+        }
+    }
+
     /// Expose the associated FUSE session file.
     pub fn get_fuse_file(&mut self) -> Option<&File> {
         self.file.as_ref()
@@ -101,6 +236,37 @@ impl FuseSession {
         self.file = Some(file);
     }
 
+    /// Export the state of the session to hand it over to another process.
+    ///
+    /// The connection is moved into the returned state, so the session won't umount the
+    /// mountpoint when dropped. See [SessionState](struct.SessionState.html) for the hand over
+    /// protocol.
+    pub fn export_state(&mut self) -> Result<SessionState> {
+        let file = self
+            .file
+            .take()
+            .ok_or_else(|| SessionFailure("invalid fuse session".to_string()))?;
+        let init = *self.init.lock().unwrap();
+
+        Ok(SessionState { file, init })
+    }
+
+    /// Resume the session with the state exported by another process.
+    pub fn import_state(&mut self, state: SessionState) -> Result<()> {
+        if self.file.is_some() {
+            return Err(SessionFailure(
+                "fuse session is already connected".to_string(),
+            ));
+        }
+
+        fcntl(state.file.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))
+            .map_err(|e| SessionFailure(format!("set fd nonblocking: {}", e)))?;
+        *self.init.lock().unwrap() = state.init;
+        self.file = Some(state.file);
+
+        Ok(())
+    }
+
     /// Destroy a fuse session.
     pub fn umount(&mut self) -> Result<()> {
         if let Some(file) = self.file.take() {
@@ -134,13 +300,141 @@ impl FuseSession {
         self.bufsize
     }
 
+    /// Set the provider of buffers for channels created afterwards.
+    pub fn set_buffer_provider(&mut self, provider: Arc<dyn BufferProvider>) {
+        self.buf_provider = Some(provider);
+    }
+
+    /// Set the logger to report transport errors of channels created afterwards.
+    pub fn set_logger(&mut self, logger: Arc<dyn TransportLogger>) {
+        self.logger = Some(logger);
+    }
+
+    /// Get statistics of replies written by all channels of the session.
+    ///
+    /// Counters are updated with relaxed atomics, so the snapshot is only roughly consistent
+    /// while requests are being served.
+    pub fn transport_stats(&self) -> TransportStats {
+        self.counters.snapshot()
+    }
+
+    /// Get the FUSE ABI version of the in kernel fuse driver.
+    ///
+    /// The version is reported by the kernel in the INIT request, so `(0, 0)` is returned until
+    /// the INIT request has been received by one of the channels.
+    pub fn abi_version(&self) -> (u32, u32) {
+        match *self.init.lock().unwrap() {
+            Some(init) => (init.major, init.minor),
+            None => (0, 0),
+        }
+    }
+
+    /// Get the directory of the connection in the fuse control file system,
+    /// `/sys/fs/fuse/connections/<N>`, where `N` is the device number of the mounted file system.
+    ///
+    /// The device number is found in `/proc/self/mountinfo` instead of calling `stat(2)` on the
+    /// mountpoint, which would wait for the session to serve a GETATTR request.
+    pub fn connection_dir(&self) -> Result<PathBuf> {
+        let mountinfo = std::fs::read_to_string("/proc/self/mountinfo")
+            .map_err(|e| SessionFailure(format!("read mountinfo: {}", e)))?;
+        let id = fuse_connection_id(&mountinfo, &self.mountpoint).ok_or_else(|| {
+            SessionFailure(format!("{} is not mounted", self.mountpoint.display()))
+        })?;
+        Ok(PathBuf::from(format!("/sys/fs/fuse/connections/{}", id)))
+    }
+
+    /// Notify the kernel to invalidate the cached attributes and data of the inode `ino`.
+    ///
+    /// The cached pages in the range of `len` bytes at `off` are dropped, up to the end of the
+    /// file if `len` is 0 or negative, and only the attributes are invalidated if `off` is
+    /// negative. It's meant for files changed behind the kernel's back, e.g. by another node of a
+    /// distributed file system.
+    ///
+    /// It fails with `ENOSYS` if the kernel doesn't support it or the INIT request hasn't been
+    /// received yet, and with `ENOENT` if the kernel doesn't have the inode cached.
+    pub fn notify_inval_inode(&self, ino: u64, off: i64, len: i64) -> Result<()> {
+        let out = NotifyInvalInodeOut { ino, off, len };
+        self.notify(NotifyOpcode::InvalInode, &[out.as_slice()])
+    }
+
+    /// Notify the kernel to drop the cached entry `name` of the directory `parent`, and the
+    /// cached attributes of `parent`.
+    ///
+    /// It's meant for entries renamed or removed behind the kernel's back, the entry is looked up
+    /// again on next access. The name isn't checked against the negotiated `max_write`: the
+    /// kernel bounds it by `FUSE_NAME_MAX` bytes whatever `max_write` is, and fails longer ones
+    /// with `ENAMETOOLONG`, so they fail the same way without being sent. Otherwise it fails like
+    /// [notify_inval_inode()](Self::notify_inval_inode), with `ENOENT` if the kernel doesn't
+    /// have `parent` cached.
+    pub fn notify_inval_entry(&self, parent: u64, name: &CStr) -> Result<()> {
+        let name = notify_entry_name(name).map_err(IoError)?;
+        let out = NotifyInvalEntryOut {
+            parent,
+            namelen: (name.len() - 1) as u32,
+            padding: 0,
+        };
+        self.notify(NotifyOpcode::InvalEntry, &[out.as_slice(), name])
+    }
+
+    // Notifications which only need the fuse device are sent by the session, while those
+    // depending on the state of the `Server`, such as FUSE_NOTIFY_RETRIEVE, are sent by the
+    // `Server`.
+    //
+    // Send a notification to the kernel. It's written to the fuse device by a single write,
+    // which the kernel handles as a whole, so it never interleaves with the replies written by
+    // the channels concurrently.
+    fn notify(&self, code: NotifyOpcode, args: &[&[u8]]) -> Result<()> {
+        if self.abi_version().1 < KERNEL_MINOR_VERSION_NOTIFY_INVAL {
+            return Err(IoError(std::io::Error::from_raw_os_error(libc::ENOSYS)));
+        }
+        let file = self
+            .file
+            .as_ref()
+            .ok_or_else(|| SessionFailure("invalid fuse session".to_string()))?;
+
+        let buf = encode_notify(code, args);
+        loop {
+            match write(file.as_raw_fd(), &buf) {
+                Ok(n) if n == buf.len() => return Ok(()),
+                Ok(_) => return Err(IoError(std::io::ErrorKind::WriteZero.into())),
+                Err(Errno::EINTR) => {}
+                Err(e) => return Err(IoError(std::io::Error::from_raw_os_error(e as i32))),
+            }
+        }
+    }
+
+    /// Get the buffer size big enough to receive any request from the kernel.
+    ///
+    /// The `Server` negotiates `max_write` with the kernel according to the maximum number of
+    /// pages per request supported by the kernel, so the size is the payload of a maximum sized
+    /// request plus space for the request headers. Before the INIT request has been received, the
+    /// default buffer size of the session is returned.
+    pub fn recommended_buffer_size(&self) -> usize {
+        match *self.init.lock().unwrap() {
+            Some(init) => negotiated_buffer_size(&init),
+            None => self.bufsize,
+        }
+    }
+
     /// Create a new fuse message channel.
+    ///
+    /// Its buffer has the default size of the session until the INIT request has been received,
+    /// and [recommended_buffer_size()](Self::recommended_buffer_size) afterwards. Channels
+    /// created before resize their buffer once the INIT request has been served, so it neither
+    /// wastes memory nor is too small for the `max_write` negotiated with the kernel.
     pub fn new_channel(&self) -> Result<FuseChannel> {
         if let Some(file) = &self.file {
             let file = file
                 .try_clone()
                 .map_err(|e| SessionFailure(format!("dup fd: {}", e)))?;
-            let channel = FuseChannel::new(file, self.bufsize)?;
+            let buf =
+                alloc_channel_buffer(self.buf_provider.as_deref(), self.recommended_buffer_size())?;
+            let mut channel = FuseChannel::new(file, buf)?;
+            channel.init = Some(self.init.clone());
+            channel.buf_provider = self.buf_provider.clone();
+            channel.logger = self.logger.clone();
+            channel.counters = Some(self.counters.clone());
+            channel.allow_root = self.allow_root;
             let waker = channel.get_waker();
             self.add_waker(waker)?;
 
@@ -180,16 +474,168 @@ impl Drop for FuseSession {
     }
 }
 
+/// Builder of [FuseSession] with chained setters.
+///
+/// ```no_run
+/// # use fuse_backend_rs::transport::FuseSession;
+/// let mut session = FuseSession::builder("/mnt/fuse")
+///     .fsname("myfs")
+///     .readonly(true)
+///     .auto_mkdir(true)
+///     .mount()
+///     .unwrap();
+/// let channel = session.new_channel().unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct FuseSessionBuilder {
+    mountpoint: PathBuf,
+    fsname: String,
+    subtype: String,
+    readonly: bool,
+    allow_other: Option<bool>,
+    allow_root: bool,
+    auto_mkdir: bool,
+    bufsize: usize,
+}
+
+impl FuseSessionBuilder {
+    /// Create a builder for a session to be mounted at `mountpoint`.
+    pub fn new<P: AsRef<Path>>(mountpoint: P) -> Self {
+        FuseSessionBuilder {
+            mountpoint: mountpoint.as_ref().to_path_buf(),
+            fsname: String::new(),
+            subtype: String::new(),
+            readonly: false,
+            allow_other: None,
+            allow_root: false,
+            auto_mkdir: false,
+            bufsize: FUSE_KERN_BUF_SIZE * pagesize() + FUSE_HEADER_SIZE,
+        }
+    }
+
+    /// Set the file system name, which shows up as the mount source.
+    pub fn fsname(mut self, fsname: &str) -> Self {
+        self.fsname = fsname.to_owned();
+        self
+    }
+
+    /// Set the file system subtype, the mount type becomes `fuse.<subtype>`.
+    pub fn subtype(mut self, subtype: &str) -> Self {
+        self.subtype = subtype.to_owned();
+        self
+    }
+
+    /// Mount the file system read-only.
+    pub fn readonly(mut self, readonly: bool) -> Self {
+        self.readonly = readonly;
+        self
+    }
+
+    /// Allow users other than the owner to access the mount, which is the default unless
+    /// `allow_root` is set.
+    pub fn allow_other(mut self, allow_other: bool) -> Self {
+        self.allow_other = Some(allow_other);
+        self
+    }
+
+    /// Only allow the owner and root to access the mount.
+    ///
+    /// The kernel doesn't know about `allow_root`, so the mount is made with `allow_other` and
+    /// channels reply `EACCES` to requests from other users, like libfuse does. It conflicts with
+    /// `allow_other`.
+    pub fn allow_root(mut self, allow_root: bool) -> Self {
+        self.allow_root = allow_root;
+        self
+    }
+
+    /// Create the mountpoint directory, and its parents, if it doesn't exist.
+    pub fn auto_mkdir(mut self, auto_mkdir: bool) -> Self {
+        self.auto_mkdir = auto_mkdir;
+        self
+    }
+
+    /// Set the size of the buffer for each channel to receive requests.
+    ///
+    /// It should be big enough for the largest request, see
+    /// [FuseSession::recommended_buffer_size()].
+    pub fn buffer_size(mut self, bufsize: usize) -> Self {
+        self.bufsize = bufsize;
+        self
+    }
+
+    /// Validate the options and create the session, without mounting it.
+    pub fn build(self) -> Result<FuseSession> {
+        if self.allow_root && self.allow_other == Some(true) {
+            return Err(SessionFailure(
+                "allow_other and allow_root are mutually exclusive".to_string(),
+            ));
+        }
+        if self.bufsize < MIN_READ_BUFFER as usize {
+            return Err(SessionFailure(format!(
+                "buffer size {} is less than the minimum {}",
+                self.bufsize, MIN_READ_BUFFER
+            )));
+        }
+        if self.auto_mkdir && !self.mountpoint.exists() {
+            std::fs::create_dir_all(&self.mountpoint).map_err(|e| {
+                SessionFailure(format!("create mountpoint {:?}: {}", self.mountpoint, e))
+            })?;
+        }
+
+        let dest = self
+            .mountpoint
+            .canonicalize()
+            .map_err(|_| SessionFailure(format!("invalid mountpoint {:?}", self.mountpoint)))?;
+        if !dest.is_dir() {
+            return Err(SessionFailure(format!("{:?} is not a directory", dest)));
+        }
+
+        Ok(FuseSession {
+            mountpoint: dest,
+            fsname: self.fsname,
+            subtype: self.subtype,
+            file: None,
+            bufsize: self.bufsize,
+            buf_provider: None,
+            logger: None,
+            counters: Arc::new(TransportCounters::default()),
+            readonly: self.readonly,
+            allow_other: self.allow_other.unwrap_or(!self.allow_root),
+            allow_root: if self.allow_root {
+                Some(getuid().as_raw())
+            } else {
+                None
+            },
+            wakers: Mutex::new(Vec::new()),
+            init: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    /// Create the session and mount it.
+    pub fn mount(self) -> Result<FuseSession> {
+        let mut session = self.build()?;
+        session.mount()?;
+        Ok(session)
+    }
+}
+
 /// A fuse channel abstruction. Each session can hold multiple channels.
 pub struct FuseChannel {
     file: File,
     poll: Poll,
     waker: Arc<Waker>,
-    buf: Vec<u8>,
+    buf: ChannelBuffer,
+    init: Option<Arc<Mutex<Option<InitIn>>>>,
+    // Size of the buffer needed by the INIT request received, until the buffer is resized.
+    resize: Option<usize>,
+    buf_provider: Option<Arc<dyn BufferProvider>>,
+    logger: Option<Arc<dyn TransportLogger>>,
+    counters: Option<Arc<TransportCounters>>,
+    allow_root: Option<u32>,
 }
 
 impl FuseChannel {
-    fn new(file: File, bufsize: usize) -> Result<Self> {
+    fn new(file: File, buf: ChannelBuffer) -> Result<Self> {
         let poll = Poll::new().map_err(|e| SessionFailure(format!("epoll create: {}", e)))?;
         let waker = Waker::new(poll.registry(), EXIT_FUSE_EVENT)
             .map_err(|e| SessionFailure(format!("epoll register session fd: {}", e)))?;
@@ -212,26 +658,195 @@ impl FuseChannel {
             file,
             poll,
             waker,
-            buf: vec![0x0u8; bufsize],
+            buf,
+            init: None,
+            resize: None,
+            buf_provider: None,
+            logger: None,
+            counters: None,
+            allow_root: None,
         })
     }
 
+    /// Open a channel to serve a character device in userspace.
+    ///
+    /// Each call opens a new CUSE connection, whose requests should be handled by a
+    /// [CuseServer](crate::api::server::CuseServer). The character device is created once the
+    /// `CUSE_INIT` request has been replied, and removed when the channel is dropped.
+    pub fn open_cuse() -> Result<FuseChannel> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(CUSE_DEVICE)
+            .map_err(|e| SessionFailure(format!("open {}: {}", CUSE_DEVICE, e)))?;
+        fcntl(file.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))
+            .map_err(|e| SessionFailure(format!("set fd nonblocking: {}", e)))?;
+        let buf = Box::new(vec![
+            0x0u8;
+            FUSE_KERN_BUF_SIZE * pagesize() + FUSE_HEADER_SIZE
+        ]);
+
+        FuseChannel::new(file, buf)
+    }
+
+    /// Create a channel serving fuse messages proxied over a `SOCK_SEQPACKET` unix socket.
+    ///
+    /// It allows privilege separation, a privileged broker owns the fuse device and relays each
+    /// request read from it as one datagram to the socket, and writes each datagram received from
+    /// the socket back to the fuse device as one reply, so the file system never touches the fuse
+    /// device. Datagrams preserve message boundaries just like the fuse device does, so requests
+    /// and replies are framed without any extra header. The channel is closed once the broker
+    /// has shut down its end of the socket.
+    ///
+    /// Both ends of the socket should have a send buffer big enough for the largest message, i.e.
+    /// `bufsize` bytes, otherwise sending fails with `EMSGSIZE`.
+    pub fn from_seqpacket(socket: File, bufsize: usize) -> Result<FuseChannel> {
+        let mut sock_type: libc::c_int = 0;
+        let mut len = size_of::<libc::c_int>() as libc::socklen_t;
+        // Safe because the kernel only writes `len` bytes into `sock_type` and we check the result.
+        let res = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_TYPE,
+                &mut sock_type as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if res < 0 {
+            return Err(SessionFailure(format!(
+                "get socket type: {}",
+                std::io::Error::last_os_error()
+            )));
+        }
+        if sock_type != libc::SOCK_SEQPACKET {
+            return Err(SessionFailure(format!(
+                "socket type {} is not SOCK_SEQPACKET",
+                sock_type
+            )));
+        }
+        fcntl(socket.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))
+            .map_err(|e| SessionFailure(format!("set fd nonblocking: {}", e)))?;
+
+        FuseChannel::new(socket, Box::new(vec![0x0u8; bufsize]))
+    }
+
+    /// Set the logger to report transport errors of the channel.
+    pub fn set_logger(&mut self, logger: Arc<dyn TransportLogger>) {
+        self.logger = Some(logger);
+    }
+
+    fn log(&self, level: log::Level, args: std::fmt::Arguments<'_>) {
+        match self.logger.as_ref() {
+            Some(logger) => logger.log(level, args),
+            None => log!(level, "{}", args),
+        }
+    }
+
+    // The epoll fd becomes readable once there's a request or an exit event pending.
+    pub(crate) fn poll_fd(&self) -> RawFd {
+        self.poll.as_raw_fd()
+    }
+
     fn get_waker(&self) -> Arc<Waker> {
         self.waker.clone()
     }
 
+    // Record the INIT request so the session could report the kernel capabilities.
+    fn check_init(&mut self, len: usize) {
+        if let Some(init) = self.init.as_ref() {
+            if let Some(v) = parse_init(&self.buf[..len]) {
+                *init.lock().unwrap() = Some(v);
+                let size = negotiated_buffer_size(&v);
+                if size != self.buf.len() {
+                    self.resize = Some(size);
+                }
+            }
+        }
+    }
+
+    // The buffer is still used to serve the INIT request when it's received, so it's resized
+    // before reading the next request.
+    fn resize_buffer(&mut self) -> Result<()> {
+        if let Some(size) = self.resize.take() {
+            self.log(
+                log::Level::Info,
+                format_args!(
+                    "resize channel buffer from {} to {} bytes for the negotiated max_write",
+                    self.buf.len(),
+                    size
+                ),
+            );
+            self.buf = alloc_channel_buffer(self.buf_provider.as_deref(), size)?;
+        }
+        Ok(())
+    }
+
+    // Reply EACCES to requests from users other than the owner and root if mounted with
+    // `allow_root`. Requests which don't check permissions, or have no reply, are passed through
+    // as libfuse does.
+    fn deny_request(&self, len: usize) -> bool {
+        let owner = match self.allow_root {
+            Some(owner) => owner,
+            None => return false,
+        };
+        let in_header = match InHeader::from_slice(&self.buf[..len.min(size_of::<InHeader>())]) {
+            Some(v) => *v,
+            None => return false,
+        };
+        if in_header.uid == owner || in_header.uid == 0 {
+            return false;
+        }
+        match Opcode::from(in_header.opcode) {
+            Opcode::Init
+            | Opcode::Destroy
+            | Opcode::Forget
+            | Opcode::BatchForget
+            | Opcode::Interrupt
+            | Opcode::NotifyReply
+            | Opcode::Read
+            | Opcode::Write
+            | Opcode::Fsync
+            | Opcode::Release
+            | Opcode::Readdir
+            | Opcode::Readdirplus
+            | Opcode::Fsyncdir
+            | Opcode::Releasedir => return false,
+            _ => {}
+        }
+
+        let out = OutHeader {
+            len: size_of::<OutHeader>() as u32,
+            error: -libc::EACCES,
+            unique: in_header.unique,
+        };
+        if let Err(e) = nix::unistd::write(self.file.as_raw_fd(), out.as_slice()) {
+            self.log(
+                log::Level::Warn,
+                format_args!("reply EACCES to request {}: {}", in_header.unique, e),
+            );
+        }
+        true
+    }
+
     /// Get next available FUSE request from the underlying fuse device file.
     ///
     /// Returns:
     /// - Ok(None): signal has pending on the exiting event channel
     /// - Ok(Some((reader, writer))): reader to receive request and writer to send reply
+    /// - Err(SessionClosed): the filesystem has been umounted, which is a normal shutdown
     /// - Err(e): error message
     pub fn get_request(&mut self) -> Result<Option<(Reader, Writer)>> {
+        self.resize_buffer()?;
         let mut events = Events::with_capacity(POLL_EVENTS_CAPACITY);
         loop {
-            self.poll
-                .poll(&mut events, None)
-                .map_err(|e| SessionFailure(format!("epoll wait: {}", e)))?;
+            if let Err(e) = self.poll.poll(&mut events, None) {
+                // Interrupted by a signal, just wait again.
+                if e.kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(SessionFailure(format!("epoll wait: {}", e)));
+            }
 
             for event in events.iter() {
                 if event.is_readable() {
@@ -246,8 +861,18 @@ impl FuseChannel {
                             return Ok(None);
                         }
                         FUSE_DEV_EVENT => {
-                            match read(fd, &mut self.buf) {
+                            match read(fd, &mut self.buf[..]) {
+                                // Only a socket transport reads nothing, once its peer has
+                                // closed the connection.
+                                Ok(0) => {
+                                    info!("fuse transport peer closed");
+                                    return Err(SessionClosed);
+                                }
                                 Ok(len) => {
+                                    self.check_init(len);
+                                    if self.deny_request(len) {
+                                        continue;
+                                    }
                                     // ###############################################
                                     // Note: it's a heavy hack to reuse the same underlying data
                                     // buffer for both Reader and Writer, in order to reduce memory
@@ -263,7 +888,13 @@ impl FuseChannel {
                                     // Reader::new() and Writer::new() should always return success.
                                     let reader =
                                         Reader::new(FuseBuf::new(&mut self.buf[..len])).unwrap();
-                                    let writer = Writer::new(fd, buf).unwrap();
+                                    let mut writer = Writer::new(fd, buf).unwrap();
+                                    if let Some(logger) = self.logger.as_ref() {
+                                        writer.set_logger(logger.as_ref());
+                                    }
+                                    if let Some(counters) = self.counters.as_ref() {
+                                        writer.set_stats(counters);
+                                    }
                                     return Ok(Some((reader, writer)));
                                 }
                                 Err(e) => match e {
@@ -278,10 +909,16 @@ impl FuseChannel {
                                     }
                                     Errno::ENODEV => {
                                         info!("fuse filesystem umounted");
-                                        return Ok(None);
+                                        return Err(SessionClosed);
                                     }
                                     e => {
-                                        warn! {"read fuse dev failed on fd {}: {}", fd, e};
+                                        self.log(
+                                            log::Level::Warn,
+                                            format_args!(
+                                                "read fuse dev failed on fd {}: {}",
+                                                fd, e
+                                            ),
+                                        );
                                         return Err(SessionFailure(format!(
                                             "read new request: {:?}",
                                             e
@@ -291,7 +928,7 @@ impl FuseChannel {
                             }
                         }
                         x => {
-                            error!("unexpected epoll event");
+                            self.log(log::Level::Error, format_args!("unexpected epoll event"));
                             return Err(SessionFailure(format!("unexpected epoll event: {}", x.0)));
                         }
                     }
@@ -307,8 +944,160 @@ impl FuseChannel {
     }
 }
 
+/// A [FuseTransport] serving the requests of a fuse channel.
+///
+/// Requests are copied out of the channel buffer and replies are written to the fuse device as
+/// is, so it's slower than serving the channel directly. It's meant to share code paths with
+/// custom transports, e.g. to test them against a real kernel.
+pub struct FuseDevTransport {
+    channel: Mutex<FuseChannel>,
+    file: File,
+    max_reply_size: usize,
+}
+
+impl FuseDevTransport {
+    /// Create a transport receiving requests from `channel`.
+    pub fn new(channel: FuseChannel) -> Result<Self> {
+        let file = channel
+            .file
+            .try_clone()
+            .map_err(|e| SessionFailure(format!("dup fd: {}", e)))?;
+        let max_reply_size = channel.buf.len();
+        Ok(FuseDevTransport {
+            channel: Mutex::new(channel),
+            file,
+            max_reply_size,
+        })
+    }
+}
+
+impl FuseTransport for FuseDevTransport {
+    fn recv(&self) -> std::io::Result<Option<Request>> {
+        let mut channel = self.channel.lock().unwrap();
+        match channel.get_request() {
+            Ok(Some((mut reader, _))) => {
+                let mut data = vec![0u8; reader.available_bytes()];
+                reader.read_exact(&mut data)?;
+                Ok(Some(Request::new(data)))
+            }
+            Ok(None) | Err(SessionClosed) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn send(&self, reply: Reply) -> std::io::Result<()> {
+        // The fuse device takes a reply in a single write, or rejects it.
+        loop {
+            match write(self.file.as_raw_fd(), reply.as_bytes()) {
+                Ok(len) if len == reply.as_bytes().len() => return Ok(()),
+                Ok(_) => return Err(std::io::Error::from(std::io::ErrorKind::WriteZero)),
+                Err(Errno::EINTR) => {}
+                Err(e) => return Err(std::io::Error::from_raw_os_error(e as i32)),
+            }
+        }
+    }
+
+    fn max_reply_size(&self) -> usize {
+        self.max_reply_size
+    }
+}
+
+fn alloc_channel_buffer(
+    provider: Option<&dyn BufferProvider>,
+    size: usize,
+) -> Result<ChannelBuffer> {
+    match provider {
+        Some(provider) => {
+            let buf = provider
+                .alloc_buffer(size)
+                .map_err(|e| SessionFailure(format!("allocate channel buffer: {}", e)))?;
+            if buf.len() < size {
+                return Err(SessionFailure(format!(
+                    "channel buffer too small: {} < {}",
+                    buf.len(),
+                    size
+                )));
+            }
+            Ok(buf)
+        }
+        None => Ok(Box::new(vec![0x0u8; size])),
+    }
+}
+
+// Get the size of a maximum sized request, with the `max_write` and `max_pages` the `Server`
+// negotiates if the kernel supports FUSE_MAX_PAGES.
+fn negotiated_buffer_size(init: &InitIn) -> usize {
+    let flags = FsOptions::from_bits_truncate(init.flags) & FsOptions::MAX_PAGES;
+    let (max_write, max_pages) = negotiate_max_write(flags);
+    max_payload_size(max_write, max_pages) + FUSE_HEADER_SIZE
+}
+
+// Parse the INIT request from the kernel, returns None for other requests.
+fn parse_init(buf: &[u8]) -> Option<InitIn> {
+    let hdr_len = size_of::<InHeader>();
+    if buf.len() < hdr_len + size_of::<InitIn>() {
+        return None;
+    }
+
+    let in_header = InHeader::from_slice(&buf[..hdr_len])?;
+    if in_header.opcode != Opcode::Init as u32 {
+        return None;
+    }
+
+    InitIn::from_slice(&buf[hdr_len..hdr_len + size_of::<InitIn>()]).copied()
+}
+
+// Find the device number of the last fuse file system mounted on `mountpoint` in the content of
+// `/proc/self/mountinfo`, encoded like the kernel does to name the fuse connection.
+fn fuse_connection_id(mountinfo: &str, mountpoint: &Path) -> Option<u64> {
+    // Special characters of the mountpoint are escaped as octal numbers.
+    let mut escaped = String::new();
+    for c in mountpoint.to_string_lossy().chars() {
+        match c {
+            ' ' | '\t' | '\n' | '\\' => escaped.push_str(&format!("\\{:03o}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+
+    mountinfo.lines().rev().find_map(|line| {
+        let (mount, fs) = line.split_once(" - ")?;
+        let fields: Vec<&str> = mount.split(' ').collect();
+        let fstype = fs.split(' ').next()?;
+        if fields.get(4) != Some(&escaped.as_str())
+            || !(fstype == "fuse" || fstype.starts_with("fuse.") || fstype == "virtiofs")
+        {
+            return None;
+        }
+        let (major, minor) = fields.get(2)?.split_once(':')?;
+        Some((major.parse::<u64>().ok()? << 20) | minor.parse::<u64>().ok()?)
+    })
+}
+
+// Get the inode number of the user namespace owning the current mount namespace.
+fn mntns_owner() -> std::io::Result<u64> {
+    // NS_GET_USERNS from linux/nsfs.h.
+    const NS_GET_USERNS: libc::c_ulong = 0xb701;
+
+    let mntns = File::open("/proc/self/ns/mnt")?;
+    // Safe because the ioctl doesn't modify any memory of the process and we check the result.
+    let fd = unsafe { libc::ioctl(mntns.as_raw_fd(), NS_GET_USERNS as _) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // Safe because we own the fd returned by the ioctl.
+    let owner = unsafe { File::from_raw_fd(fd) };
+
+    Ok(owner.metadata()?.ino())
+}
+
 /// Mount a fuse file system
-fn fuse_kern_mount(mountpoint: &Path, fsname: &str, subtype: &str, flags: MsFlags) -> Result<File> {
+fn fuse_kern_mount(
+    mountpoint: &Path,
+    fsname: &str,
+    subtype: &str,
+    flags: MsFlags,
+    allow_other: bool,
+) -> Result<File> {
     let file = OpenOptions::new()
         .create(false)
         .read(true)
@@ -319,7 +1108,8 @@ fn fuse_kern_mount(mountpoint: &Path, fsname: &str, subtype: &str, flags: MsFlag
         .metadata()
         .map_err(|e| SessionFailure(format!("stat {:?}: {}", mountpoint, e)))?;
     let opts = format!(
-        "default_permissions,allow_other,fd={},rootmode={:o},user_id={},group_id={}",
+        "default_permissions,{}fd={},rootmode={:o},user_id={},group_id={}",
+        if allow_other { "allow_other," } else { "" },
         file.as_raw_fd(),
         meta.permissions().mode() & libc::S_IFMT,
         getuid(),
@@ -377,11 +1167,16 @@ fn fuse_kern_umount(mountpoint: &str, file: File) -> Result<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::abi::fuse_abi::FUSE_NAME_MAX;
+    use crate::api::server::{FUSE_DEFAULT_MAX_PAGES_PER_REQ, MAX_REQ_PAGES};
+    use std::ffi::CString;
     use std::os::unix::io::FromRawFd;
     use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use vmm_sys_util::tempdir::TempDir;
 
     #[test]
+    #[allow(deprecated)]
     fn test_new_session() {
         let se = FuseSession::new(Path::new("haha"), "foo", "bar", true);
         assert!(se.is_err());
@@ -391,12 +1186,464 @@ mod tests {
         assert!(se.is_ok());
     }
 
+    #[test]
+    fn test_session_builder() {
+        let dir = TempDir::new().unwrap();
+        let se = FuseSession::builder(dir.as_path())
+            .fsname("foo")
+            .subtype("bar")
+            .build()
+            .unwrap();
+        assert_eq!(se.fsname(), "foo");
+        assert_eq!(se.subtype(), "bar");
+        assert!(se.allow_other);
+        assert!(se.allow_root.is_none());
+
+        let e = FuseSession::builder(dir.as_path())
+            .allow_other(true)
+            .allow_root(true)
+            .build()
+            .err()
+            .unwrap();
+        assert!(e.to_string().contains("mutually exclusive"));
+        let se = FuseSession::builder(dir.as_path())
+            .allow_root(true)
+            .build()
+            .unwrap();
+        assert!(!se.allow_other);
+        assert_eq!(se.allow_root, Some(getuid().as_raw()));
+
+        let e = FuseSession::builder(dir.as_path())
+            .buffer_size(4096)
+            .build()
+            .err()
+            .unwrap();
+        assert!(e.to_string().contains("buffer size"));
+        let se = FuseSession::builder(dir.as_path())
+            .buffer_size(0x10000)
+            .build()
+            .unwrap();
+        assert_eq!(se.bufsize(), 0x10000);
+
+        let mnt = dir.as_path().join("a/b");
+        assert!(FuseSession::builder(&mnt).build().is_err());
+        FuseSession::builder(&mnt).auto_mkdir(true).build().unwrap();
+        assert!(mnt.is_dir());
+    }
+
+    #[test]
+    fn test_allow_root_channel() {
+        let mut fds = [0 as RawFd; 2];
+        assert_eq!(
+            unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_SEQPACKET, 0, fds.as_mut_ptr()) },
+            0
+        );
+        let (socket, peer) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        let mut channel = FuseChannel::from_seqpacket(socket, 0x1000).unwrap();
+        channel.allow_root = Some(1000);
+
+        let send = |opcode: Opcode, unique: u64, uid: u32| {
+            let header = InHeader {
+                len: size_of::<InHeader>() as u32,
+                opcode: opcode as u32,
+                unique,
+                uid,
+                ..Default::default()
+            };
+            nix::unistd::write(peer.as_raw_fd(), header.as_slice()).unwrap();
+        };
+        // Requests from others are denied, except for those not checking permissions.
+        send(Opcode::Getattr, 1, 1001);
+        send(Opcode::Read, 2, 1001);
+        send(Opcode::Getattr, 3, 0);
+        send(Opcode::Getattr, 4, 1000);
+        for unique in [2, 3, 4] {
+            let (mut reader, _) = channel.get_request().unwrap().unwrap();
+            assert_eq!(reader.read_obj::<InHeader>().unwrap().unique, unique);
+        }
+
+        let mut buf = [0u8; 64];
+        assert_eq!(
+            read(peer.as_raw_fd(), &mut buf).unwrap(),
+            size_of::<OutHeader>()
+        );
+        let out = OutHeader::from_slice(&buf[..size_of::<OutHeader>()]).unwrap();
+        assert_eq!(out.unique, 1);
+        assert_eq!(out.error, -libc::EACCES);
+    }
+
+    #[test]
+    fn test_seqpacket_channel() {
+        let socketpair = |ty| {
+            let mut fds = [0 as RawFd; 2];
+            assert_eq!(
+                unsafe { libc::socketpair(libc::AF_UNIX, ty, 0, fds.as_mut_ptr()) },
+                0
+            );
+            unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) }
+        };
+
+        let (stream, _peer) = socketpair(libc::SOCK_STREAM);
+        assert!(FuseChannel::from_seqpacket(stream, 0x1000).is_err());
+
+        let (socket, peer) = socketpair(libc::SOCK_SEQPACKET);
+        let mut channel = FuseChannel::from_seqpacket(socket, 0x1000).unwrap();
+        let header = InHeader {
+            len: size_of::<InHeader>() as u32,
+            opcode: Opcode::Getattr as u32,
+            unique: 7,
+            ..Default::default()
+        };
+        // Two requests queued back to back are still received one at a time.
+        for _ in 0..2 {
+            assert_eq!(
+                nix::unistd::write(peer.as_raw_fd(), header.as_slice()).unwrap(),
+                size_of::<InHeader>()
+            );
+        }
+        for _ in 0..2 {
+            let (mut reader, mut writer) = channel.get_request().unwrap().unwrap();
+            assert_eq!(reader.available_bytes(), size_of::<InHeader>());
+            assert_eq!(reader.read_obj::<InHeader>().unwrap().unique, 7);
+            std::io::Write::write_all(&mut writer, &[0x5au8; 24]).unwrap();
+        }
+
+        let mut buf = [0u8; 64];
+        for _ in 0..2 {
+            assert_eq!(read(peer.as_raw_fd(), &mut buf).unwrap(), 24);
+        }
+
+        drop(peer);
+        assert!(matches!(channel.get_request(), Err(SessionClosed)));
+    }
+
+    #[test]
+    fn test_check_userns_mount() {
+        let has_cap = |cap| caps::has_cap(None, caps::CapSet::Effective, cap).unwrap();
+        if !has_cap(caps::Capability::CAP_SYS_ADMIN) {
+            let e = FuseSession::check_userns_mount().unwrap_err();
+            assert!(e.to_string().contains("user namespace"));
+            return;
+        }
+
+        // Capabilities are per thread, drop CAP_SYS_ADMIN in a dedicated thread.
+        std::thread::spawn(move || {
+            caps::drop(
+                None,
+                caps::CapSet::Effective,
+                caps::Capability::CAP_SYS_ADMIN,
+            )
+            .unwrap();
+            let e = FuseSession::check_userns_mount().unwrap_err();
+            assert!(e.to_string().contains("CAP_SYS_ADMIN"));
+
+            let dir = TempDir::new().unwrap();
+            let mut se = FuseSession::builder(dir.as_path())
+                .fsname("foo")
+                .subtype("bar")
+                .build()
+                .unwrap();
+            assert!(se.mount_in_userns().is_err());
+            assert!(se.get_fuse_file().is_none());
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_fuse_connection_id() {
+        let mountinfo = "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+40 22 0:45 / /mnt/a\\040b rw,nosuid,nodev shared:20 - fuse.test test rw,user_id=0
+41 22 0:46 / /mnt/c rw,nosuid,nodev shared:21 - tmpfs tmpfs rw
+42 41 0:47 / /mnt/c rw,nosuid,nodev shared:22 - fuse fuse rw,user_id=0
+";
+        assert_eq!(
+            fuse_connection_id(mountinfo, Path::new("/mnt/a b")),
+            Some(45)
+        );
+        assert_eq!(fuse_connection_id(mountinfo, Path::new("/mnt/c")), Some(47));
+        assert_eq!(fuse_connection_id(mountinfo, Path::new("/")), None);
+        assert_eq!(fuse_connection_id(mountinfo, Path::new("/mnt/d")), None);
+    }
+
+    #[test]
+    fn test_abi_version() {
+        let dir = TempDir::new().unwrap();
+        let se = FuseSession::builder(dir.as_path())
+            .fsname("foo")
+            .subtype("bar")
+            .build()
+            .unwrap();
+        assert_eq!(se.abi_version(), (0, 0));
+        assert_eq!(se.recommended_buffer_size(), se.bufsize());
+
+        let in_header = InHeader {
+            len: (size_of::<InHeader>() + size_of::<InitIn>()) as u32,
+            opcode: Opcode::Init as u32,
+            ..Default::default()
+        };
+        let init = InitIn {
+            major: 7,
+            minor: 31,
+            max_readahead: 0,
+            flags: 0,
+        };
+        let mut buf = in_header.as_slice().to_vec();
+        buf.extend_from_slice(init.as_slice());
+        assert!(parse_init(&buf[..buf.len() - 1]).is_none());
+
+        *se.init.lock().unwrap() = parse_init(&buf);
+        assert_eq!(se.abi_version(), (7, 31));
+        assert_eq!(
+            se.recommended_buffer_size(),
+            FUSE_DEFAULT_MAX_PAGES_PER_REQ as usize * pagesize() + FUSE_HEADER_SIZE
+        );
+
+        let init = InitIn {
+            flags: FsOptions::MAX_PAGES.bits(),
+            ..init
+        };
+        buf.truncate(size_of::<InHeader>());
+        buf.extend_from_slice(init.as_slice());
+        *se.init.lock().unwrap() = parse_init(&buf);
+        assert_eq!(
+            se.recommended_buffer_size(),
+            MAX_REQ_PAGES as usize * pagesize() + FUSE_HEADER_SIZE
+        );
+
+        let in_header = InHeader {
+            opcode: Opcode::Lookup as u32,
+            ..in_header
+        };
+        buf[..size_of::<InHeader>()].copy_from_slice(in_header.as_slice());
+        assert!(parse_init(&buf).is_none());
+    }
+
+    struct ArenaProvider {
+        size: usize,
+        count: AtomicUsize,
+    }
+
+    impl BufferProvider for ArenaProvider {
+        fn alloc_buffer(&self, _size: usize) -> std::io::Result<ChannelBuffer> {
+            self.count.fetch_add(1, Ordering::Relaxed);
+            Ok(Box::new(vec![0x0u8; self.size]))
+        }
+    }
+
+    #[test]
+    fn test_buffer_provider() {
+        let dir = TempDir::new().unwrap();
+        let mut se = FuseSession::builder(dir.as_path())
+            .fsname("foo")
+            .subtype("bar")
+            .build()
+            .unwrap();
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let _writer = unsafe { File::from_raw_fd(fds[1]) };
+        se.set_fuse_file(unsafe { File::from_raw_fd(fds[0]) });
+
+        let provider = Arc::new(ArenaProvider {
+            size: se.bufsize(),
+            count: AtomicUsize::new(0),
+        });
+        se.set_buffer_provider(provider.clone());
+        let ch = se.new_channel().unwrap();
+        assert_eq!(provider.count.load(Ordering::Relaxed), 1);
+        assert_eq!(ch.buf.len(), se.bufsize());
+
+        let small = Arc::new(ArenaProvider {
+            size: se.bufsize() - 1,
+            count: AtomicUsize::new(0),
+        });
+        se.set_buffer_provider(small.clone());
+        assert!(se.new_channel().is_err());
+        assert_eq!(small.count.load(Ordering::Relaxed), 1);
+
+        // Don't try to umount the fake session.
+        se.file = None;
+    }
+
+    // Create a fake session writing to a pipe, returning the read end of the pipe.
+    fn notify_session(dir: &Path) -> (FuseSession, File) {
+        let mut se = FuseSession::builder(dir)
+            .fsname("foo")
+            .subtype("bar")
+            .build()
+            .unwrap();
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        se.set_fuse_file(unsafe { File::from_raw_fd(fds[1]) });
+        (se, unsafe { File::from_raw_fd(fds[0]) })
+    }
+
+    #[test]
+    fn test_notify_inval_inode() {
+        let dir = TempDir::new().unwrap();
+        let (mut se, peer) = notify_session(dir.as_path());
+        match se.notify_inval_inode(5, 0, -1) {
+            Err(IoError(e)) => assert_eq!(e.raw_os_error(), Some(libc::ENOSYS)),
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        *se.init.lock().unwrap() = Some(InitIn {
+            major: 7,
+            minor: 31,
+            ..Default::default()
+        });
+        se.notify_inval_inode(5, 4096, 8192).unwrap();
+        let mut buf = [0u8; 64];
+        let len = size_of::<OutHeader>() + size_of::<NotifyInvalInodeOut>();
+        assert_eq!(read(peer.as_raw_fd(), &mut buf).unwrap(), len);
+        let out = OutHeader::from_slice(&buf[..size_of::<OutHeader>()]).unwrap();
+        assert_eq!(out.len as usize, len);
+        assert_eq!(out.error, NotifyOpcode::InvalInode as i32);
+        assert_eq!(out.unique, 0);
+        let arg = NotifyInvalInodeOut::from_slice(&buf[size_of::<OutHeader>()..len]).unwrap();
+        assert_eq!((arg.ino, arg.off, arg.len), (5, 4096, 8192));
+
+        // Don't try to umount the fake session.
+        se.file = None;
+    }
+
+    #[test]
+    fn test_notify_inval_entry() {
+        let dir = TempDir::new().unwrap();
+        let (mut se, peer) = notify_session(dir.as_path());
+        *se.init.lock().unwrap() = Some(InitIn {
+            major: 7,
+            minor: 31,
+            ..Default::default()
+        });
+
+        let name = CString::new("file").unwrap();
+        se.notify_inval_entry(1, &name).unwrap();
+        let mut buf = [0u8; 64];
+        let len = size_of::<OutHeader>() + size_of::<NotifyInvalEntryOut>() + 5;
+        assert_eq!(read(peer.as_raw_fd(), &mut buf).unwrap(), len);
+        let out = OutHeader::from_slice(&buf[..size_of::<OutHeader>()]).unwrap();
+        assert_eq!(out.len as usize, len);
+        assert_eq!(out.error, NotifyOpcode::InvalEntry as i32);
+        assert_eq!(out.unique, 0);
+        let arg = NotifyInvalEntryOut::from_slice(&buf[size_of::<OutHeader>()..len - 5]).unwrap();
+        assert_eq!((arg.parent, arg.namelen), (1, 4));
+        assert_eq!(&buf[len - 5..len], b"file\0");
+
+        let long = CString::new(vec![b'a'; FUSE_NAME_MAX + 1]).unwrap();
+        match se.notify_inval_entry(1, &long) {
+            Err(IoError(e)) => assert_eq!(e.raw_os_error(), Some(libc::ENAMETOOLONG)),
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        // Don't try to umount the fake session.
+        se.file = None;
+    }
+
+    #[test]
+    fn test_resize_buffer() {
+        let dir = TempDir::new().unwrap();
+        let mut se = FuseSession::builder(dir.as_path())
+            .fsname("foo")
+            .subtype("bar")
+            .buffer_size(MAX_REQ_PAGES as usize * pagesize() + FUSE_HEADER_SIZE)
+            .build()
+            .unwrap();
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let writer = unsafe { File::from_raw_fd(fds[1]) };
+        se.set_fuse_file(unsafe { File::from_raw_fd(fds[0]) });
+        let mut ch = se.new_channel().unwrap();
+        assert_eq!(ch.buf.len(), se.bufsize());
+
+        // The kernel doesn't support FUSE_MAX_PAGES, so requests are capped at 32 pages.
+        let in_header = InHeader {
+            len: (size_of::<InHeader>() + size_of::<InitIn>()) as u32,
+            opcode: Opcode::Init as u32,
+            ..Default::default()
+        };
+        let init = InitIn {
+            major: 7,
+            minor: 31,
+            max_readahead: 0,
+            flags: 0,
+        };
+        let mut buf = in_header.as_slice().to_vec();
+        buf.extend_from_slice(init.as_slice());
+        write(writer.as_raw_fd(), &buf).unwrap();
+        ch.get_request().unwrap().unwrap();
+        // The buffer is still in use while serving the INIT request.
+        assert_eq!(ch.buf.len(), se.bufsize());
+
+        let in_header = InHeader {
+            len: size_of::<InHeader>() as u32,
+            opcode: Opcode::Getattr as u32,
+            ..Default::default()
+        };
+        write(writer.as_raw_fd(), in_header.as_slice()).unwrap();
+        ch.get_request().unwrap().unwrap();
+        let capped = FUSE_DEFAULT_MAX_PAGES_PER_REQ as usize * pagesize() + FUSE_HEADER_SIZE;
+        assert_eq!(ch.buf.len(), capped);
+        assert_eq!(se.new_channel().unwrap().buf.len(), capped);
+
+        // Don't try to umount the fake session.
+        se.file = None;
+    }
+
+    #[test]
+    fn test_session_state() {
+        let dir = TempDir::new().unwrap();
+        let mut se = FuseSession::builder(dir.as_path())
+            .fsname("foo")
+            .subtype("bar")
+            .build()
+            .unwrap();
+        assert!(se.export_state().is_err());
+
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let _writer = unsafe { File::from_raw_fd(fds[1]) };
+        se.set_fuse_file(unsafe { File::from_raw_fd(fds[0]) });
+        let init = InitIn {
+            major: 7,
+            minor: 31,
+            max_readahead: 0x1000,
+            flags: FsOptions::MAX_PAGES.bits(),
+        };
+        *se.init.lock().unwrap() = Some(init);
+
+        let state = se.export_state().unwrap();
+        assert!(se.get_fuse_file().is_none());
+        let buf = state.encode();
+        let state = SessionState::decode(&buf, state.file).unwrap();
+        assert_eq!(state.init.unwrap().max_readahead, 0x1000);
+
+        let mut se2 = FuseSession::builder(dir.as_path())
+            .fsname("foo")
+            .subtype("bar")
+            .build()
+            .unwrap();
+        se2.import_state(state).unwrap();
+        assert_eq!(se2.abi_version(), (7, 31));
+        assert_eq!(se2.recommended_buffer_size(), se.recommended_buffer_size());
+        let fd = se2.get_fuse_file().unwrap().as_raw_fd();
+        assert_eq!(fd, fds[0]);
+        let flags = fcntl(fd, FcntlArg::F_GETFL).unwrap();
+        assert_ne!(flags & libc::O_NONBLOCK, 0);
+
+        assert!(SessionState::decode(&buf[..3], se2.export_state().unwrap().file).is_err());
+        let state = SessionState::decode(&[0], File::open("/dev/null").unwrap()).unwrap();
+        assert!(state.init.is_none());
+        assert_eq!(state.encode(), vec![0]);
+    }
+
     #[test]
     fn test_new_channel() {
         let ch = FuseChannel::new(
             // Provide a valid FD to allow poll register to work.
             unsafe { File::from_raw_fd(std::io::stdout().as_raw_fd()) },
-            3,
+            Box::new(vec![0x0u8; 3]),
         );
         assert!(ch.is_ok());
     }
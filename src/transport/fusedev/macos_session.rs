@@ -33,7 +33,10 @@ use nix::sys::uio::IoVec;
 use nix::unistd::{close, execv, fork, getpid, read, ForkResult};
 use nix::{cmsg_space, NixPath};
 
-use super::{Error::SessionFailure, FuseBuf, Reader, Result, Writer};
+use super::{
+    BufferProvider, ChannelBuffer, Error::SessionClosed, Error::SessionFailure, FuseBuf, Reader,
+    Result, Writer,
+};
 use crate::transport::pagesize;
 
 // These follows definition from libfuse.
@@ -58,6 +61,7 @@ pub struct FuseSession {
     subtype: String,
     file: Option<File>,
     bufsize: usize,
+    buf_provider: Option<Arc<dyn BufferProvider>>,
     disk: Arc<Mutex<Option<DADiskRef>>>,
     dasession: Arc<AtomicPtr<c_void>>,
     readonly: bool,
@@ -86,6 +90,7 @@ impl FuseSession {
             subtype: subtype.to_owned(),
             file: None,
             bufsize: FUSE_KERN_BUF_SIZE * pagesize() + FUSE_HEADER_SIZE,
+            buf_provider: None,
             disk: Arc::new(Mutex::new(None)),
             dasession: Arc::new(AtomicPtr::new(unsafe {
                 DASessionCreate(std::ptr::null()) as *mut c_void
@@ -150,13 +155,38 @@ impl FuseSession {
         self.bufsize
     }
 
+    /// Set the provider of buffers for channels created afterwards.
+    pub fn set_buffer_provider(&mut self, provider: Arc<dyn BufferProvider>) {
+        self.buf_provider = Some(provider);
+    }
+
+    fn alloc_channel_buffer(&self) -> Result<ChannelBuffer> {
+        match self.buf_provider.as_ref() {
+            Some(provider) => {
+                let buf = provider
+                    .alloc_buffer(self.bufsize)
+                    .map_err(|e| SessionFailure(format!("allocate channel buffer: {}", e)))?;
+                if buf.len() < self.bufsize {
+                    return Err(SessionFailure(format!(
+                        "channel buffer too small: {} < {}",
+                        buf.len(),
+                        self.bufsize
+                    )));
+                }
+                Ok(buf)
+            }
+            None => Ok(Box::new(vec![0x0u8; self.bufsize])),
+        }
+    }
+
     /// Create a new fuse message channel.
     pub fn new_channel(&self) -> Result<FuseChannel> {
         if let Some(file) = &self.file {
             let file = file
                 .try_clone()
                 .map_err(|e| SessionFailure(format!("dup fd: {}", e)))?;
-            FuseChannel::new(file, self.bufsize)
+            let buf = self.alloc_channel_buffer()?;
+            FuseChannel::new(file, buf)
         } else {
             Err(SessionFailure("invalid fuse session".to_string()))
         }
@@ -179,15 +209,12 @@ impl Drop for FuseSession {
 /// A fuse channel abstruction. Each session can hold multiple channels.
 pub struct FuseChannel {
     file: File,
-    buf: Vec<u8>,
+    buf: ChannelBuffer,
 }
 
 impl FuseChannel {
-    fn new(file: File, bufsize: usize) -> Result<Self> {
-        Ok(FuseChannel {
-            file,
-            buf: vec![0x0u8; bufsize],
-        })
+    fn new(file: File, buf: ChannelBuffer) -> Result<Self> {
+        Ok(FuseChannel { file, buf })
     }
 
     /// Get next available FUSE request from the underlying fuse device file.
@@ -195,11 +222,12 @@ impl FuseChannel {
     /// Returns:
     /// - Ok(None): signal has pending on the exiting event channel
     /// - Ok(Some((reader, writer))): reader to receive request and writer to send reply
+    /// - Err(SessionClosed): the filesystem has been umounted, which is a normal shutdown
     /// - Err(e): error message
     pub fn get_request(&mut self) -> Result<Option<(Reader, Writer)>> {
         let fd = self.file.as_raw_fd();
         loop {
-            match read(fd, &mut self.buf) {
+            match read(fd, &mut self.buf[..]) {
                 Ok(len) => {
                     // ###############################################
                     // Note: it's a heavy hack to reuse the same underlying data
@@ -227,7 +255,7 @@ impl FuseChannel {
                     }
                     Errno::ENODEV => {
                         info!("fuse filesystem umounted");
-                        return Ok(None);
+                        return Err(SessionClosed);
                     }
                     e => {
                         warn! {"read fuse dev failed on fd {}: {}", fd, e};
@@ -4,12 +4,16 @@
 
 //! Traits and Structs to implement the /dev/fuse Fuse transport layer.
 
+use std::cmp;
 use std::collections::VecDeque;
 use std::fmt;
 use std::io::{self, IoSlice, Write};
 use std::marker::PhantomData;
 use std::mem::ManuallyDrop;
+use std::ops::DerefMut;
 use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
 
 use nix::sys::uio::{writev, IoVec};
 use nix::unistd::write;
@@ -28,6 +32,9 @@ mod macos_session;
 #[cfg(target_os = "macos")]
 pub use macos_session::*;
 
+// Maximum number of parts of a reply committed without allocating.
+const COMMIT_INLINE_PARTS: usize = 4;
+
 /// Error codes for Virtio queue related operations.
 #[derive(Debug)]
 pub enum Error {
@@ -45,6 +52,8 @@ pub enum Error {
     VolatileMemoryError(VolatileMemoryError),
     /// Session errors
     SessionFailure(String),
+    /// The session has been closed by the kernel because the filesystem has been umounted.
+    SessionClosed,
 }
 
 impl fmt::Display for Error {
@@ -62,6 +71,7 @@ impl fmt::Display for Error {
             SplitOutOfBounds(off) => write!(f, "`DescriptorChain` split is out of bounds: {}", off),
             VolatileMemoryError(e) => write!(f, "volatile memory error: {}", e),
             SessionFailure(e) => write!(f, "fuse session failure: {}", e),
+            SessionClosed => write!(f, "fuse session has been closed"),
         }
     }
 }
@@ -77,9 +87,175 @@ impl From<Error> for std::io::Error {
 /// Result for fusedev transport driver related operations.
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// A reply writer backed by an owned buffer instead of the fuse device.
+///
+/// Data written to the fuse device by the writers returned by [writer()](OwnedWriter::writer)
+/// is appended to the buffer, so replies can be checked without a fuse device, e.g. by unit tests
+/// of file systems and servers.
+pub struct OwnedWriter<S: BitmapSlice = ()> {
+    data_buf: Vec<u8>,
+    out: Mutex<Vec<u8>>,
+    phantom: PhantomData<S>,
+}
+
+impl<S: BitmapSlice + Default> OwnedWriter<S> {
+    /// Get a writer whose output is appended to the buffer.
+    pub fn writer(&mut self) -> Writer<'_, S> {
+        // Safe because the writer borrows `data_buf` and never frees it.
+        let buf =
+            unsafe { Vec::from_raw_parts(self.data_buf.as_mut_ptr(), 0, self.data_buf.len()) };
+        Writer {
+            fd: -1,
+            buffered: false,
+            capture: false,
+            buf: ManuallyDrop::new(buf),
+            partial_write: false,
+            bitmapslice: S::default(),
+            sink: ReplySink(Some(&self.out)),
+            logger: WriterLogger::default(),
+            stats: WriterStats::default(),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Consume the writer and return all data written so far.
+    pub fn into_inner(self) -> Vec<u8> {
+        self.out.into_inner().unwrap()
+    }
+}
+
+/// Logger for errors of the fuse device transport.
+///
+/// Transport errors are reported to the global `log` logger by default. A custom logger may be
+/// set on a [FuseSession] or a [Writer] to attach contextual information, such as the tenant or
+/// the mountpoint of a session, to these messages.
+pub trait TransportLogger: Send + Sync {
+    /// Log a message of the fuse device transport.
+    fn log(&self, level: log::Level, args: fmt::Arguments<'_>) {
+        log!(level, "{}", args);
+    }
+}
+
+/// The default [TransportLogger], which forwards messages to the `log` crate.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultTransportLogger;
+
+impl TransportLogger for DefaultTransportLogger {}
+
+/// Statistics of replies written to the fuse device by writers of a session.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TransportStats {
+    /// Number of times a [Writer] has been split by [Writer::split_at()].
+    pub split_count: u64,
+    /// Size of the largest reply written to the fuse device, in bytes.
+    pub max_reply_bytes: u64,
+    /// Number of replies gathered from two writers by [Writer::commit()].
+    pub commit_iovec_count: u64,
+}
+
+// Counters backing `TransportStats`, shared by a session and all its channels.
+#[derive(Debug, Default)]
+pub(crate) struct TransportCounters {
+    split_count: AtomicU64,
+    max_reply_bytes: AtomicU64,
+    commit_iovec_count: AtomicU64,
+}
+
+impl TransportCounters {
+    pub(crate) fn snapshot(&self) -> TransportStats {
+        TransportStats {
+            split_count: self.split_count.load(Ordering::Relaxed),
+            max_reply_bytes: self.max_reply_bytes.load(Ordering::Relaxed),
+            commit_iovec_count: self.commit_iovec_count.load(Ordering::Relaxed),
+        }
+    }
+}
+
 /// Fake trait to simplify implementation when vhost-user-fs is not used.
 pub trait FsCacheReqHandler {}
 
+/// Fake trait to simplify implementation when vhost-user-fs is not used.
+#[cfg(feature = "async-io")]
+pub trait AsyncFsCacheReqHandler {}
+
+/// A buffer owned by a fuse channel to receive requests and send replies.
+pub type ChannelBuffer = Box<dyn DerefMut<Target = [u8]> + Send + Sync>;
+
+/// Provider of the buffers used by fuse channels.
+///
+/// By default channel buffers are allocated from the heap. A provider may be installed on a
+/// [FuseSession] to back channel buffers with preallocated memory instead, such as an arena of
+/// huge pages.
+pub trait BufferProvider: Send + Sync {
+    /// Allocate a buffer of at least `size` bytes for a new channel.
+    fn alloc_buffer(&self, size: usize) -> io::Result<ChannelBuffer>;
+}
+
+/// A fuse request received by a [FuseTransport].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Request {
+    data: Vec<u8>,
+}
+
+impl Request {
+    /// Wrap a complete fuse request, starting with its `fuse_in_header`.
+    pub fn new(data: Vec<u8>) -> Self {
+        Request { data }
+    }
+
+    /// Get the bytes of the request.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Consume the request and return its bytes.
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// A fuse reply to be sent by a [FuseTransport].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Reply {
+    data: Vec<u8>,
+}
+
+impl Reply {
+    /// Wrap a complete fuse reply, starting with its `fuse_out_header`.
+    pub fn new(data: Vec<u8>) -> Self {
+        Reply { data }
+    }
+
+    /// Get the bytes of the reply.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Consume the reply and return its bytes.
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// A source of fuse requests and sink of their replies, driven by
+/// [Server::serve_transport()](crate::api::server::Server::serve_transport).
+///
+/// It allows serving file systems to clients other than the kernel fuse driver, e.g. over a
+/// custom RPC protocol, by reusing the request dispatching of the server. Requests and replies are
+/// whole messages in the fuse wire format. [FuseDevTransport] implements it for the fuse device.
+pub trait FuseTransport: Send + Sync {
+    /// Wait for the next request, or return `None` once the client has gone away.
+    fn recv(&self) -> io::Result<Option<Request>>;
+
+    /// Send the reply to a request.
+    fn send(&self, reply: Reply) -> io::Result<()>;
+
+    /// Get the maximum size of a reply, in bytes.
+    fn max_reply_size(&self) -> usize {
+        crate::api::server::MAX_BUFFER_SIZE as usize + super::pagesize()
+    }
+}
+
 /// A buffer reference wrapper for fuse requests.
 #[derive(Debug)]
 pub struct FuseBuf<'a> {
@@ -109,6 +285,7 @@ impl<'a, S: BitmapSlice + Default> Reader<'a, S> {
                 buffers,
                 bytes_consumed: 0,
             },
+            owned: None,
         })
     }
 }
@@ -122,11 +299,145 @@ impl<'a, S: BitmapSlice + Default> Reader<'a, S> {
 pub struct Writer<'a, S: BitmapSlice = ()> {
     fd: RawFd,
     buffered: bool,
+    // Replies are kept in memory instead of being written to the fuse device.
+    capture: bool,
     buf: ManuallyDrop<Vec<u8>>,
+    // Writes larger than the available space are truncated instead of failing.
+    partial_write: bool,
     bitmapslice: S,
+    sink: ReplySink<'a>,
+    logger: WriterLogger<'a>,
+    stats: WriterStats<'a>,
     phantom: PhantomData<&'a mut [S]>,
 }
 
+// Reports errors to the logger set by `Writer::set_logger()`, or to `log` if there's none.
+#[derive(Clone, Copy, Default)]
+struct WriterLogger<'a>(Option<&'a dyn TransportLogger>);
+
+impl WriterLogger<'_> {
+    fn error(&self, args: fmt::Arguments<'_>) {
+        match self.0 {
+            Some(logger) => logger.log(log::Level::Error, args),
+            None => error!("{}", args),
+        }
+    }
+}
+
+impl fmt::Debug for WriterLogger<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("WriterLogger")
+            .field(&self.0.is_some())
+            .finish()
+    }
+}
+
+impl PartialEq for WriterLogger<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        match (self.0, other.0) {
+            (Some(a), Some(b)) => std::ptr::eq(
+                a as *const dyn TransportLogger as *const u8,
+                b as *const dyn TransportLogger as *const u8,
+            ),
+            (a, b) => a.is_none() && b.is_none(),
+        }
+    }
+}
+
+impl Eq for WriterLogger<'_> {}
+
+// Updates the counters set by `Writer::set_stats()`, if any.
+#[derive(Clone, Copy, Debug, Default)]
+struct WriterStats<'a>(Option<&'a TransportCounters>);
+
+impl WriterStats<'_> {
+    fn split(&self) {
+        if let Some(c) = self.0 {
+            c.split_count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn reply(&self, len: usize, gathered: bool) {
+        if let Some(c) = self.0 {
+            c.max_reply_bytes.fetch_max(len as u64, Ordering::Relaxed);
+            if gathered {
+                c.commit_iovec_count.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl PartialEq for WriterStats<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        match (self.0, other.0) {
+            (Some(a), Some(b)) => std::ptr::eq(a, b),
+            (a, b) => a.is_none() && b.is_none(),
+        }
+    }
+}
+
+impl Eq for WriterStats<'_> {}
+
+// Receives the data written by writers of an `OwnedWriter` instead of the fuse device.
+#[derive(Clone, Copy, Debug, Default)]
+struct ReplySink<'a>(Option<&'a Mutex<Vec<u8>>>);
+
+impl ReplySink<'_> {
+    fn write(&self, bufs: &[&[u8]]) -> Option<usize> {
+        self.0.map(|out| {
+            let mut out = out.lock().unwrap();
+            bufs.iter().fold(0, |acc, b| {
+                out.extend_from_slice(b);
+                acc + b.len()
+            })
+        })
+    }
+}
+
+impl PartialEq for ReplySink<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        match (self.0, other.0) {
+            (Some(a), Some(b)) => std::ptr::eq(a, b),
+            (a, b) => a.is_none() && b.is_none(),
+        }
+    }
+}
+
+impl Eq for ReplySink<'_> {}
+
+/// Space reserved in the buffer of a [Writer] for an object only known after writing the data
+/// behind it, such as a length or a count.
+///
+/// It's returned by [Writer::reserve()] and must be filled by [ReservedSlot::fill()] before the
+/// writer is committed, otherwise the reserved space is sent as zeros.
+#[must_use]
+#[derive(Debug)]
+pub struct ReservedSlot<T: ByteValued> {
+    offset: usize,
+    phantom: PhantomData<T>,
+}
+
+impl<T: ByteValued> ReservedSlot<T> {
+    /// Write `val` into the space reserved in `writer`, which must be the writer the slot has
+    /// been reserved from.
+    pub fn fill<S: BitmapSlice>(self, writer: &mut Writer<'_, S>, val: T) -> io::Result<()> {
+        let end = self.offset + std::mem::size_of::<T>();
+        if end > writer.buf.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "reserved slot {}..{} out of the written data {}",
+                    self.offset,
+                    end,
+                    writer.buf.len()
+                ),
+            ));
+        }
+        writer.buf[self.offset..end].copy_from_slice(val.as_slice());
+        Ok(())
+    }
+}
+
 impl<'a, S: BitmapSlice + Default> Writer<'a, S> {
     /// Construct a new Writer
     pub fn new(fd: RawFd, data_buf: &'a mut [u8]) -> Result<Writer<'a, S>> {
@@ -134,18 +445,83 @@ impl<'a, S: BitmapSlice + Default> Writer<'a, S> {
         Ok(Writer {
             fd,
             buffered: false,
+            capture: false,
             buf: ManuallyDrop::new(buf),
+            partial_write: false,
             bitmapslice: S::default(),
+            sink: ReplySink::default(),
+            logger: WriterLogger::default(),
+            stats: WriterStats::default(),
             phantom: PhantomData,
         })
     }
+
+    /// Construct an [OwnedWriter] which keeps replies in memory instead of writing them to the
+    /// fuse device, with a buffer of `capacity` bytes for each writer.
+    pub fn new_owned(capacity: usize) -> OwnedWriter<S> {
+        OwnedWriter {
+            data_buf: vec![0u8; capacity],
+            out: Mutex::new(Vec::new()),
+            phantom: PhantomData,
+        }
+    }
 }
 
 impl<'a, S: BitmapSlice> Writer<'a, S> {
+    /// Set the logger to report errors of writing to the fuse device.
+    ///
+    /// Writers split from this one afterwards share the same logger.
+    pub fn set_logger(&mut self, logger: &'a dyn TransportLogger) {
+        self.logger = WriterLogger(Some(logger));
+    }
+
+    /// Set whether writes larger than [available_bytes()](Self::available_bytes) are truncated.
+    ///
+    /// By default such writes fail with `InvalidData` and write nothing. With partial writes
+    /// enabled, they write as much data as fits and return the number of bytes written, like
+    /// `write(2)`, and return 0 once the writer is full. Writers split from this one afterwards
+    /// share the same mode.
+    ///
+    /// An unbuffered writer sends each write to the fuse device as a complete reply and can't be
+    /// written again, so a truncated write ends the reply there. Looping on partial writes only
+    /// works with buffered writers, e.g. split ones, which send everything on `commit()`.
+    pub fn set_partial_write(&mut self, enabled: bool) {
+        self.partial_write = enabled;
+    }
+
+    // Account replies of the writer, and writers split from it afterwards, into `counters`.
+    pub(crate) fn set_stats(&mut self, counters: &'a TransportCounters) {
+        self.stats = WriterStats(Some(counters));
+    }
+
+    /// Construct a Writer which captures replies into `data_buf` instead of writing them to the
+    /// fuse device.
+    ///
+    /// `commit()` gathers the buffers of split writers to the start of `data_buf`, so a complete
+    /// reply could be found at the start of `data_buf` once it has been written.
+    pub(crate) fn capture<'b>(&self, data_buf: &'b mut [u8]) -> Writer<'b, S> {
+        let buf = unsafe { Vec::from_raw_parts(data_buf.as_mut_ptr(), 0, data_buf.len()) };
+        Writer {
+            fd: self.fd,
+            buffered: true,
+            capture: true,
+            buf: ManuallyDrop::new(buf),
+            partial_write: false,
+            bitmapslice: self.bitmapslice.clone(),
+            sink: ReplySink::default(),
+            logger: WriterLogger::default(),
+            stats: WriterStats::default(),
+            phantom: PhantomData,
+        }
+    }
+
     /// Splits this `Writer` into two at the given offset in the buffer.
     /// After the split, `self` will be able to write up to `offset` bytes while the returned
     /// `Writer` can write up to `available_bytes() - offset` bytes.  Returns an error if
     /// `offset > self.available_bytes()`.
+    ///
+    /// The dirty bitmap of the returned `Writer` is sliced at `offset`, so both track the part of
+    /// the buffer they write to.
     pub fn split_at(&mut self, offset: usize) -> Result<Writer<'a, S>> {
         if self.buf.capacity() < offset {
             return Err(Error::SplitOutOfBounds(offset));
@@ -158,6 +534,7 @@ impl<'a, S: BitmapSlice> Writer<'a, S> {
         };
         let cap2 = self.buf.capacity() - offset;
         let ptr = self.buf.as_mut_ptr();
+        self.stats.split();
 
         // Safe because both buffers refer to different parts of the same underlying `data_buf`.
         self.buf = unsafe { ManuallyDrop::new(Vec::from_raw_parts(ptr, len1, offset)) };
@@ -167,34 +544,133 @@ impl<'a, S: BitmapSlice> Writer<'a, S> {
         Ok(Writer {
             fd: self.fd,
             buffered: true,
+            capture: self.capture,
             buf,
-            bitmapslice: self.bitmapslice.clone(),
+            partial_write: self.partial_write,
+            bitmapslice: self.bitmapslice.slice_at(offset),
+            sink: self.sink,
+            logger: self.logger,
+            stats: self.stats,
             phantom: PhantomData,
         })
     }
 
     /// Commit all internal buffers of self and others
     /// We need this because the lifetime of others is usually shorter than self.
+    ///
+    /// Short writes are retried until both buffers have been fully written or a hard error
+    /// happens, and the total number of bytes written is returned.
     pub fn commit(&mut self, other: Option<&Writer<'a, S>>) -> io::Result<usize> {
+        match other {
+            Some(other) => self.commit_all(&[other]),
+            None => self.commit_all(&[]),
+        }
+    }
+
+    /// Commit the internal buffers of self and any number of others as a single reply.
+    ///
+    /// Like [commit()](Self::commit), but for a reply split into more than two parts, e.g. a
+    /// header, data and a footer. `others` must be split from this writer in the order of their
+    /// offsets. The buffers are gathered by a single `writev(2)`, or `write(2)` if there's only one
+    /// non-empty buffer, so the kernel still receives the reply in one shot.
+    pub fn commit_all(&mut self, others: &[&Writer<'a, S>]) -> io::Result<usize> {
         if !self.buffered {
             return Ok(0);
         }
 
-        let o = other.map(|v| v.buf.as_slice()).unwrap_or(&[]);
-        let res = match (self.buf.len(), o.len()) {
-            (0, 0) => Ok(0),
-            (0, _) => write(self.fd, o),
-            (_, 0) => write(self.fd, self.buf.as_slice()),
-            (_, _) => {
-                let bufs = [IoVec::from_slice(self.buf.as_slice()), IoVec::from_slice(o)];
-                writev(self.fd, &bufs)
+        let total = others
+            .iter()
+            .fold(self.buf.len(), |acc, o| acc + o.buf.len());
+        if self.capture {
+            // Move the data of `others` right behind our own data, they are all in the buffer
+            // passed to `capture()` and split from later parts of it in order.
+            // Safe because each destination is in between our data and the source.
+            let mut len = self.buf.len();
+            for o in others {
+                unsafe {
+                    std::ptr::copy(o.buf.as_ptr(), self.buf.as_mut_ptr().add(len), o.buf.len())
+                };
+                len += o.buf.len();
             }
+            return Ok(total);
+        }
+
+        let mut inline = [&[][..]; COMMIT_INLINE_PARTS];
+        let mut spilled = Vec::new();
+        let parts = std::iter::once(self.buf.as_slice())
+            .chain(others.iter().map(|o| o.buf.as_slice()))
+            .filter(|b| !b.is_empty());
+        let segs: &[&[u8]] = if others.len() < COMMIT_INLINE_PARTS {
+            let mut count = 0;
+            for part in parts {
+                inline[count] = part;
+                count += 1;
+            }
+            &inline[..count]
+        } else {
+            spilled.extend(parts);
+            &spilled
         };
+        if let Some(count) = self.sink.write(segs) {
+            return Ok(count);
+        }
 
-        res.map_err(|e| {
-            error! {"fail to write to fuse device on commit: {}", e};
-            io::Error::from_raw_os_error(e as i32)
-        })
+        let mut inline_iovs = [IoVec::from_slice(&[][..]); COMMIT_INLINE_PARTS];
+        let mut spilled_iovs = Vec::new();
+        let iovs: &mut [IoVec<&[u8]>] = if segs.len() <= COMMIT_INLINE_PARTS {
+            &mut inline_iovs[..segs.len()]
+        } else {
+            spilled_iovs.resize(segs.len(), IoVec::from_slice(&[][..]));
+            &mut spilled_iovs
+        };
+        for (iov, seg) in iovs.iter_mut().zip(segs) {
+            *iov = IoVec::from_slice(seg);
+        }
+
+        let mut written = 0;
+        // The first part not completely written yet, and the bytes of it written by previous
+        // short writes.
+        let mut first = 0;
+        let mut skip = 0;
+        while written < total {
+            let res = if iovs.len() - first == 1 {
+                write(self.fd, &segs[first][skip..])
+            } else {
+                writev(self.fd, &iovs[first..])
+            };
+
+            match res {
+                Ok(0) => {
+                    self.logger.error(format_args!(
+                        "fail to write to fuse device on commit: zero bytes written"
+                    ));
+                    return Err(io::Error::from(io::ErrorKind::WriteZero));
+                }
+                Ok(cnt) => {
+                    written += cnt;
+                    skip += cnt;
+                    while first < segs.len() && skip >= segs[first].len() {
+                        skip -= segs[first].len();
+                        first += 1;
+                    }
+                    if first < segs.len() {
+                        iovs[first] = IoVec::from_slice(&segs[first][skip..]);
+                    }
+                }
+                Err(nix::errno::Errno::EINTR) => {}
+                Err(nix::errno::Errno::EAGAIN) => wait_writable(self.fd, self.logger)?,
+                Err(e) => {
+                    self.logger.error(format_args!(
+                        "fail to write to fuse device on commit: {}",
+                        e
+                    ));
+                    return Err(io::Error::from_raw_os_error(e as i32));
+                }
+            }
+        }
+        self.stats.reply(written, segs.len() > 1);
+
+        Ok(written)
     }
 
     /// Returns number of bytes already written to the internal buffer.
@@ -218,6 +694,26 @@ impl<'a, S: BitmapSlice> Writer<'a, S> {
         self.write_all(val.as_slice())
     }
 
+    /// Reserve space for an object of type `T` at the current position, to be filled by
+    /// [ReservedSlot::fill()] once the data following it has been written.
+    ///
+    /// The reserved space is zeroed. It only works for buffered writers, e.g. split writers,
+    /// because unbuffered writers send the data to the fuse device immediately.
+    pub fn reserve<T: ByteValued>(&mut self) -> io::Result<ReservedSlot<T>> {
+        if !self.buffered {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "can't reserve space in an unbuffered writer",
+            ));
+        }
+        let offset = self.buf.len();
+        self.write_obj(T::default())?;
+        Ok(ReservedSlot {
+            offset,
+            phantom: PhantomData,
+        })
+    }
+
     /// Writes data to the writer from a file descriptor.
     /// Returns the number of bytes written to the writer.
     pub fn write_from<F: FileReadWriteVolatile>(
@@ -241,7 +737,7 @@ impl<'a, S: BitmapSlice> Writer<'a, S> {
         if self.buffered {
             Ok(cnt)
         } else {
-            Self::do_write(self.fd, &self.buf[..cnt])
+            self.do_write(&self.buf[..cnt])
         }
     }
 
@@ -270,7 +766,7 @@ impl<'a, S: BitmapSlice> Writer<'a, S> {
         if self.buffered {
             Ok(cnt)
         } else {
-            Self::do_write(self.fd, &self.buf[..cnt])
+            self.do_write(&self.buf[..cnt])
         }
     }
 
@@ -299,6 +795,15 @@ impl<'a, S: BitmapSlice> Writer<'a, S> {
         Ok(())
     }
 
+    // Get how much of `sz` bytes may be written, depending on the partial write mode.
+    fn writable_len(&self, sz: usize) -> io::Result<usize> {
+        if self.partial_write {
+            assert!(self.buffered || self.buf.is_empty());
+            return Ok(cmp::min(sz, self.available_bytes()));
+        }
+        self.check_available_space(sz).map(|_| sz)
+    }
+
     fn check_available_space(&self, sz: usize) -> io::Result<()> {
         assert!(self.buffered || self.buf.len() == 0);
         if sz > self.available_bytes() {
@@ -315,11 +820,20 @@ impl<'a, S: BitmapSlice> Writer<'a, S> {
         }
     }
 
-    fn do_write(fd: RawFd, data: &[u8]) -> io::Result<usize> {
-        let res = write(fd, data);
+    fn do_write(&self, data: &[u8]) -> io::Result<usize> {
+        if let Some(count) = self.sink.write(&[data]) {
+            return Ok(count);
+        }
+        let res = write(self.fd, data);
+        if let Ok(count) = res {
+            self.stats.reply(count, false);
+        }
 
         res.map_err(|e| {
-            error! {"fail to write to fuse device fd {}: {}, {:?}", fd, e, data};
+            self.logger.error(format_args!(
+                "fail to write to fuse device fd {}: {}, {:?}",
+                self.fd, e, data
+            ));
             io::Error::new(io::ErrorKind::Other, format!("{}", e))
         })
     }
@@ -327,13 +841,13 @@ impl<'a, S: BitmapSlice> Writer<'a, S> {
 
 impl<'a, S: BitmapSlice> io::Write for Writer<'a, S> {
     fn write(&mut self, data: &[u8]) -> io::Result<usize> {
-        self.check_available_space(data.len())?;
+        let data = &data[..self.writable_len(data.len())?];
 
         if self.buffered {
             self.buf.extend_from_slice(data);
             Ok(data.len())
         } else {
-            Self::do_write(self.fd, data).map(|x| {
+            self.do_write(data).map(|x| {
                 self.account_written(x);
                 x
             })
@@ -342,31 +856,48 @@ impl<'a, S: BitmapSlice> io::Write for Writer<'a, S> {
 
     // default write_vectored only writes the first non-empty IoSlice. Override it.
     fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
-        self.check_available_space(bufs.iter().fold(0, |acc, x| acc + x.len()))?;
+        let mut len = self.writable_len(bufs.iter().fold(0, |acc, x| acc + x.len()))?;
+        let slices: Vec<&[u8]> = bufs
+            .iter()
+            .filter(|b| !b.is_empty())
+            .map_while(|b| {
+                let n = cmp::min(len, b.len());
+                len -= n;
+                if n > 0 {
+                    Some(&b[..n])
+                } else {
+                    None
+                }
+            })
+            .collect();
 
         if self.buffered {
-            let count = bufs.iter().filter(|b| !b.is_empty()).fold(0, |acc, b| {
+            let count = slices.iter().fold(0, |acc, b| {
                 self.buf.extend_from_slice(b);
                 acc + b.len()
             });
             Ok(count)
         } else {
-            let buf: Vec<IoVec<&[u8]>> = bufs
-                .iter()
-                .filter(|b| !b.is_empty())
-                .map(|b| IoVec::from_slice(b))
-                .collect();
+            let buf: Vec<IoVec<&[u8]>> = slices.iter().map(|b| IoVec::from_slice(b)).collect();
 
             if buf.is_empty() {
                 return Ok(0);
             }
+            if let Some(count) = self.sink.write(&slices) {
+                self.account_written(count);
+                return Ok(count);
+            }
             writev(self.fd, buf.as_slice())
                 .map(|x| {
                     self.account_written(x);
+                    self.stats.reply(x, false);
                     x
                 })
                 .map_err(|e| {
-                    error! {"fail to write to fuse device on commit: {}", e};
+                    self.logger.error(format_args!(
+                        "fail to write to fuse device on commit: {}",
+                        e
+                    ));
                     io::Error::new(io::ErrorKind::Other, format!("{}", e))
                 })
         }
@@ -438,7 +969,10 @@ mod async_io {
                         x
                     })
                     .map_err(|e| {
-                        error! {"fail to write to fuse device fd {}: {}, {:?}", self.fd, e, data};
+                        self.logger.error(format_args!(
+                            "fail to write to fuse device fd {}: {}, {:?}",
+                            self.fd, e, data
+                        ));
                         io::Error::new(io::ErrorKind::Other, format!("{}", e))
                     })
             }
@@ -470,7 +1004,10 @@ mod async_io {
                         x
                     })
                     .map_err(|e| {
-                        error! {"fail to write to fuse device fd {}: {}, {:?}", self.fd, e, data};
+                        self.logger.error(format_args!(
+                            "fail to write to fuse device fd {}: {}, {:?}",
+                            self.fd, e, data
+                        ));
                         io::Error::new(io::ErrorKind::Other, format!("{}", e))
                     })
             }
@@ -504,7 +1041,10 @@ mod async_io {
                         x
                     })
                     .map_err(|e| {
-                        error! {"fail to write to fuse device fd {}: {}, {:?}", self.fd, e, data};
+                        self.logger.error(format_args!(
+                            "fail to write to fuse device fd {}: {}, {:?}",
+                            self.fd, e, data
+                        ));
                         io::Error::new(io::ErrorKind::Other, format!("{}", e))
                     })
             }
@@ -569,18 +1109,67 @@ mod async_io {
             other: Option<&Writer<'a, S>>,
         ) -> io::Result<usize> {
             let o = other.map(|v| v.buf.as_slice()).unwrap_or(&[]);
+            let total = self.buf.len() + o.len();
+            let mut written = 0;
+
+            while written < total {
+                let res = if written < self.buf.len() {
+                    let head = &self.buf[written..];
+                    if o.is_empty() {
+                        AsyncUtil::write(drive.clone(), self.fd, head, 0).await
+                    } else {
+                        AsyncUtil::write2(drive.clone(), self.fd, head, o, 0).await
+                    }
+                } else {
+                    let tail = &o[written - self.buf.len()..];
+                    AsyncUtil::write(drive.clone(), self.fd, tail, 0).await
+                };
 
-            let res = match (self.buf.len(), o.len()) {
-                (0, 0) => Ok(0),
-                (0, _) => AsyncUtil::write(drive, self.fd, o, 0).await,
-                (_, 0) => AsyncUtil::write(drive, self.fd, self.buf.as_slice(), 0).await,
-                (_, _) => AsyncUtil::write2(drive, self.fd, self.buf.as_slice(), o, 0).await,
-            };
+                match res {
+                    Ok(0) => {
+                        self.logger.error(format_args!(
+                            "fail to write to fuse device on commit: zero bytes written"
+                        ));
+                        return Err(io::Error::from(io::ErrorKind::WriteZero));
+                    }
+                    Ok(cnt) => written += cnt,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => {
+                        self.logger.error(format_args!(
+                            "fail to write to fuse device on commit: {}",
+                            e
+                        ));
+                        return Err(e);
+                    }
+                }
+            }
 
-            res.map_err(|e| {
-                error! {"fail to write to fuse device on commit: {}", e};
-                e
-            })
+            Ok(written)
+        }
+    }
+}
+
+// Block until `fd` becomes writable, so that a short write to a nonblocking fd can be resumed.
+fn wait_writable(fd: RawFd, logger: WriterLogger) -> io::Result<()> {
+    let mut pfd = libc::pollfd {
+        fd,
+        events: libc::POLLOUT,
+        revents: 0,
+    };
+
+    loop {
+        // Safe because we only pass a valid pollfd and check the return value.
+        let ret = unsafe { libc::poll(&mut pfd, 1, -1) };
+        if ret >= 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            logger.error(format_args!(
+                "fail to wait for fuse device to be writable: {}",
+                err
+            ));
+            return Err(err);
         }
     }
 }
@@ -588,8 +1177,11 @@ mod async_io {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::io::{Read, Seek, SeekFrom, Write};
-    use std::os::unix::io::AsRawFd;
+    use crate::abi::fuse_abi::Dirent;
+    use std::io::{self, Read, Seek, SeekFrom, Write};
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::sync::Arc;
+    use vm_memory::bitmap::{Bitmap, WithBitmapSlice};
     use vmm_sys_util::tempfile::TempFile;
 
     #[test]
@@ -712,6 +1304,65 @@ mod tests {
         }
     }
 
+    // Records the ranges marked dirty, relative to the start of the buffer.
+    #[derive(Clone, Debug, Default)]
+    struct DirtyLog {
+        base: usize,
+        ranges: Arc<Mutex<Vec<(usize, usize)>>>,
+    }
+
+    impl<'a> WithBitmapSlice<'a> for DirtyLog {
+        type S = Self;
+    }
+
+    impl BitmapSlice for DirtyLog {}
+
+    impl Bitmap for DirtyLog {
+        fn mark_dirty(&self, offset: usize, len: usize) {
+            self.ranges.lock().unwrap().push((self.base + offset, len));
+        }
+
+        fn dirty_at(&self, offset: usize) -> bool {
+            let offset = self.base + offset;
+            self.ranges
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|&(start, len)| offset >= start && offset < start + len)
+        }
+
+        fn slice_at(&self, offset: usize) -> Self {
+            DirtyLog {
+                base: self.base + offset,
+                ranges: self.ranges.clone(),
+            }
+        }
+    }
+
+    #[test]
+    fn split_at_slices_bitmap() {
+        let mut buf = [0u8; 128];
+        let mut reader = Reader::<DirtyLog>::new(FuseBuf::new(&mut buf)).unwrap();
+        let other = reader.split_at(32).unwrap();
+        let log = reader.buffers.buffers[0].bitmap().clone();
+        reader.buffers.buffers[0].bitmap().mark_dirty(0, 32);
+        other.buffers.buffers[0].bitmap().mark_dirty(0, 96);
+        assert_eq!(*log.ranges.lock().unwrap(), vec![(0, 32), (32, 96)]);
+        assert!(log.dirty_at(127));
+        assert!(!log.dirty_at(128));
+
+        let file = TempFile::new().unwrap().into_file();
+        let mut buf = vec![0x0u8; 128];
+        let mut writer = Writer::<DirtyLog>::new(file.as_raw_fd(), &mut buf).unwrap();
+        let mut other = writer.split_at(16).unwrap();
+        let third = other.split_at(32).unwrap();
+        writer.bitmapslice.mark_dirty(0, 16);
+        other.bitmapslice.mark_dirty(0, 32);
+        third.bitmapslice.mark_dirty(8, 8);
+        let log = writer.bitmapslice.ranges.lock().unwrap().clone();
+        assert_eq!(log, vec![(0, 16), (16, 32), (56, 8)]);
+    }
+
     #[test]
     fn writer_simple_commit_header() {
         let file = TempFile::new().unwrap().into_file();
@@ -773,6 +1424,66 @@ mod tests {
         writer.commit(None).unwrap();
     }
 
+    #[test]
+    fn writer_commit_many() {
+        let mut file = TempFile::new().unwrap().into_file();
+        let mut buf = vec![0x0u8; 64];
+        let mut writer = Writer::<()>::new(file.as_raw_fd(), &mut buf).unwrap();
+        let mut data = writer.split_at(8).unwrap();
+        let mut empty = data.split_at(16).unwrap();
+        let mut footer = empty.split_at(0).unwrap();
+        let mut trailing = footer.split_at(4).unwrap();
+        writer.write_all(&[0x1u8; 8]).unwrap();
+        data.write_all(&[0x2u8; 16]).unwrap();
+        footer.write_all(&[0x3u8; 4]).unwrap();
+        let tail = trailing.split_at(0).unwrap();
+
+        // Empty segments in between are skipped.
+        assert_eq!(
+            writer
+                .commit_all(&[&data, &empty, &footer, &trailing, &tail])
+                .unwrap(),
+            28
+        );
+        let mut expected = vec![0x1u8; 8];
+        expected.extend_from_slice(&[0x2u8; 16]);
+        expected.extend_from_slice(&[0x3u8; 4]);
+        let mut out = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut out).unwrap();
+        assert_eq!(out, expected);
+
+        // A single trailing writer, and none at all.
+        let mut buf = vec![0x0u8; 16];
+        let mut writer = Writer::<()>::new(file.as_raw_fd(), &mut buf).unwrap();
+        let mut other = writer.split_at(4).unwrap();
+        writer.write_all(&[0x4u8; 4]).unwrap();
+        other.write_all(&[0x5u8; 4]).unwrap();
+        assert_eq!(writer.commit_all(&[&other]).unwrap(), 8);
+        assert_eq!(writer.commit_all(&[]).unwrap(), 4);
+        expected.extend_from_slice(&[0x4u8; 4]);
+        expected.extend_from_slice(&[0x5u8; 4]);
+        expected.extend_from_slice(&[0x4u8; 4]);
+        let mut out = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut out).unwrap();
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn writer_capture_commit_many() {
+        let writer = Writer::<()>::new(-1, &mut []).unwrap();
+        let mut buf = vec![0x0u8; 32];
+        let mut capture = writer.capture(&mut buf);
+        let mut data = capture.split_at(4).unwrap();
+        let mut footer = data.split_at(8).unwrap();
+        capture.write_all(&[0x1u8; 2]).unwrap();
+        data.write_all(&[0x2u8; 3]).unwrap();
+        footer.write_all(&[0x3u8; 1]).unwrap();
+        assert_eq!(capture.commit_all(&[&data, &footer]).unwrap(), 6);
+        assert_eq!(buf[..6], [1, 1, 2, 2, 2, 3]);
+    }
+
     #[test]
     fn writer_split_commit_all() {
         let file = TempFile::new().unwrap().into_file();
@@ -803,6 +1514,197 @@ mod tests {
         writer.commit(Some(&other)).unwrap();
     }
 
+    #[test]
+    fn writer_commit_short_write() {
+        let mut fds = [0 as RawFd; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let (rfd, wfd) = (fds[0], fds[1]);
+        // Shrink the pipe and make it nonblocking so that writes larger than a page come back short.
+        assert!(unsafe { libc::fcntl(wfd, libc::F_SETPIPE_SZ, 4096) } >= 0);
+        assert_eq!(
+            unsafe { libc::fcntl(wfd, libc::F_SETFL, libc::O_NONBLOCK) },
+            0
+        );
+
+        let reader = std::thread::spawn(move || {
+            let mut file = unsafe { std::fs::File::from_raw_fd(rfd) };
+            let mut data = Vec::new();
+            file.read_to_end(&mut data).unwrap();
+            data
+        });
+
+        let mut buf = vec![0x0u8; 5 * 4096];
+        let mut writer = Writer::<()>::new(wfd, &mut buf).unwrap();
+        let mut other = writer.split_at(3 * 4096).unwrap();
+        writer.write_all(&[0xa5u8; 3 * 4096]).unwrap();
+        other.write_all(&[0x5au8; 2 * 4096]).unwrap();
+
+        assert_eq!(writer.commit(Some(&other)).unwrap(), 5 * 4096);
+        unsafe { libc::close(wfd) };
+
+        let data = reader.join().unwrap();
+        assert_eq!(data.len(), 5 * 4096);
+        assert!(data[..3 * 4096].iter().all(|b| *b == 0xa5));
+        assert!(data[3 * 4096..].iter().all(|b| *b == 0x5a));
+    }
+
+    #[test]
+    fn writer_capture_commit() {
+        let file = TempFile::new().unwrap().into_file();
+        let mut buf = vec![0x0u8; 16];
+        let writer = Writer::<()>::new(file.as_raw_fd(), &mut buf).unwrap();
+        let mut data = vec![0x0u8; 64];
+        let mut capture = writer.capture(&mut data);
+        let mut other = capture.split_at(8).unwrap();
+
+        capture.write_all(&[0x1u8; 4]).unwrap();
+        other.write_all(&[0x2u8; 6]).unwrap();
+        assert_eq!(capture.commit(Some(&other)).unwrap(), 10);
+        assert_eq!(file.metadata().unwrap().len(), 0);
+        assert_eq!(&data[..10], &[1, 1, 1, 1, 2, 2, 2, 2, 2, 2]);
+    }
+
+    #[test]
+    fn writer_stats() {
+        let file = TempFile::new().unwrap().into_file();
+        let counters = TransportCounters::default();
+        let mut buf = vec![0x0u8; 64];
+        let mut writer = Writer::<()>::new(file.as_raw_fd(), &mut buf).unwrap();
+        writer.set_stats(&counters);
+        let mut other = writer.split_at(8).unwrap();
+        assert_eq!(writer.stats, other.stats);
+
+        writer.write_all(&[0x1u8; 8]).unwrap();
+        other.write_all(&[0x2u8; 16]).unwrap();
+        writer.commit(Some(&other)).unwrap();
+        assert_eq!(
+            counters.snapshot(),
+            TransportStats {
+                split_count: 1,
+                max_reply_bytes: 24,
+                commit_iovec_count: 1,
+            }
+        );
+
+        let mut buf = vec![0x0u8; 64];
+        let mut writer = Writer::<()>::new(file.as_raw_fd(), &mut buf).unwrap();
+        writer.set_stats(&counters);
+        writer.write_all(&[0x3u8; 32]).unwrap();
+        let stats = counters.snapshot();
+        assert_eq!(stats.max_reply_bytes, 32);
+        assert_eq!(stats.commit_iovec_count, 1);
+    }
+
+    #[derive(Default)]
+    struct RecordLogger(Mutex<Vec<(log::Level, String)>>);
+
+    impl TransportLogger for RecordLogger {
+        fn log(&self, level: log::Level, args: fmt::Arguments<'_>) {
+            self.0
+                .lock()
+                .unwrap()
+                .push((level, format!("tenant-1: {}", args)));
+        }
+    }
+
+    #[test]
+    fn writer_logger() {
+        let logger = RecordLogger::default();
+        let mut buf = vec![0x0u8; 16];
+        let mut writer = Writer::<()>::new(-1, &mut buf).unwrap();
+        writer.set_logger(&logger);
+
+        let mut other = writer.split_at(8).unwrap();
+        assert_eq!(writer.logger, other.logger);
+        other.write_all(&[1u8; 4]).unwrap();
+        writer.write_all(&[2u8; 4]).unwrap();
+        assert!(writer.commit(Some(&other)).is_err());
+
+        let msgs = logger.0.lock().unwrap();
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(msgs[0].0, log::Level::Error);
+        assert!(msgs[0]
+            .1
+            .starts_with("tenant-1: fail to write to fuse device on commit"));
+    }
+
+    #[test]
+    fn owned_writer() {
+        let mut owned = Writer::<()>::new_owned(16);
+
+        // Unbuffered writes go straight to the buffer, like writes to the fuse device.
+        let mut writer = owned.writer();
+        assert_eq!(writer.available_bytes(), 16);
+        writer.write_all(&[0x1u8; 3]).unwrap();
+        assert_eq!(writer.bytes_written(), 3);
+
+        // Split writers are only appended on commit.
+        let mut writer = owned.writer();
+        let mut other = writer.split_at(8).unwrap();
+        writer.write_all(&[0x2u8; 2]).unwrap();
+        other.write_all(&[0x3u8; 4]).unwrap();
+        assert_eq!(writer.commit(Some(&other)).unwrap(), 6);
+
+        let mut writer = owned.writer();
+        let bufs = [IoSlice::new(&[0x4u8; 1]), IoSlice::new(&[0x5u8; 2])];
+        assert_eq!(writer.write_vectored(&bufs).unwrap(), 3);
+        owned.writer().write_all(&[0u8; 32]).unwrap_err();
+
+        assert_eq!(owned.into_inner(), vec![1, 1, 1, 2, 2, 3, 3, 3, 3, 4, 5, 5]);
+    }
+
+    #[test]
+    fn partial_write() {
+        let mut owned = Writer::<()>::new_owned(16);
+
+        // Oversized writes fail and write nothing by default.
+        let mut writer = owned.writer();
+        let mut other = writer.split_at(4).unwrap();
+        assert_eq!(
+            other.write(&[0x1u8; 13]).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        let bufs = [IoSlice::new(&[0x1u8; 8]), IoSlice::new(&[0x1u8; 5])];
+        other.write_vectored(&bufs).unwrap_err();
+        assert_eq!(other.bytes_written(), 0);
+        writer.commit(Some(&other)).unwrap();
+
+        // Buffered writers fill up to capacity, then accept nothing more.
+        let mut writer = owned.writer();
+        writer.set_partial_write(true);
+        let mut other = writer.split_at(4).unwrap();
+        writer.write_all(&[0x2u8; 4]).unwrap();
+        let bufs = [IoSlice::new(&[0x3u8; 8]), IoSlice::new(&[0x4u8; 5])];
+        assert_eq!(other.write_vectored(&bufs).unwrap(), 12);
+        assert_eq!(other.write(&[0x5u8; 1]).unwrap(), 0);
+        assert_eq!(other.available_bytes(), 0);
+        assert_eq!(writer.commit(Some(&other)).unwrap(), 16);
+
+        // An unbuffered writer sends the truncated data as the reply.
+        let mut writer = owned.writer();
+        writer.set_partial_write(true);
+        assert_eq!(writer.write(&[0x6u8; 20]).unwrap(), 16);
+
+        let mut expected = vec![0x2u8; 4];
+        expected.extend_from_slice(&[0x3; 8]);
+        expected.extend_from_slice(&[0x4; 4]);
+        expected.extend_from_slice(&[0x6; 16]);
+        assert_eq!(owned.into_inner(), expected);
+    }
+
+    #[test]
+    fn reader_from_vec() {
+        let mut reader = Reader::<()>::from_vec((0u8..16).collect());
+        assert_eq!(reader.available_bytes(), 16);
+
+        let mut other = reader.split_at(4).unwrap();
+        drop(reader);
+        let mut buf = [0u8; 16];
+        assert_eq!(other.read(&mut buf).unwrap(), 12);
+        assert_eq!(&buf[..12], &(4u8..16).collect::<Vec<u8>>()[..]);
+        assert_eq!(other.bytes_read(), 12);
+    }
+
     #[test]
     fn read_full() {
         let mut buf2 = [0u8; 48];
@@ -863,6 +1765,57 @@ mod tests {
         assert!(reader.read_obj::<u64>().is_err());
     }
 
+    #[test]
+    fn read_cstr() {
+        let mut buf2 = *b"old\0new\0bad";
+        let mut reader = Reader::<()>::new(FuseBuf::new(&mut buf2)).unwrap();
+
+        assert_eq!(reader.read_cstr().unwrap().as_bytes(), b"old");
+        assert_eq!(reader.read_cstr().unwrap().as_bytes(), b"new");
+        assert_eq!(reader.bytes_read(), 8);
+        let e = reader.read_cstr().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn read_dirent() {
+        let mut buf2 = Vec::new();
+        for (ino, name) in [(1u64, &b"a"[..]), (2u64, &b"12345678"[..])].iter() {
+            let dirent = Dirent {
+                ino: *ino,
+                off: *ino,
+                namelen: name.len() as u32,
+                type_: libc::DT_REG as u32,
+            };
+            buf2.extend_from_slice(dirent.as_slice());
+            buf2.extend_from_slice(name);
+            buf2.resize((buf2.len() + 7) & !7, 0);
+        }
+        assert_eq!(buf2.len(), 64);
+        let mut reader = Reader::<()>::new(FuseBuf::new(&mut buf2)).unwrap();
+
+        let (dirent, name) = reader.read_dirent().unwrap();
+        assert_eq!(dirent.ino, 1);
+        assert_eq!(name, b"a");
+        assert_eq!(reader.bytes_read(), 32);
+        let (dirent, name) = reader.read_dirent().unwrap();
+        assert_eq!(dirent.ino, 2);
+        assert_eq!(name, b"12345678");
+        assert_eq!(reader.available_bytes(), 0);
+
+        // The name length exceeds the buffer.
+        let mut buf3 = Dirent {
+            namelen: 16,
+            ..Default::default()
+        }
+        .as_slice()
+        .to_vec();
+        buf3.extend_from_slice(b"short\0\0\0");
+        let mut reader = Reader::<()>::new(FuseBuf::new(&mut buf3)).unwrap();
+        let e = reader.read_dirent().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
     #[test]
     fn read_exact_to() {
         let mut buf2 = [0u8; 48];
@@ -893,6 +1846,48 @@ mod tests {
         assert_eq!(reader.bytes_read(), 48);
     }
 
+    #[test]
+    fn read_to_at_vectored() {
+        let mut buf2 = [0u8; 48];
+        for (i, v) in buf2.iter_mut().enumerate() {
+            *v = i as u8;
+        }
+        let mut reader = Reader::<()>::new(FuseBuf::new(&mut buf2)).unwrap();
+        let mut file1 = TempFile::new().unwrap().into_file();
+        let mut file2 = TempFile::new().unwrap().into_file();
+
+        assert_eq!(
+            reader
+                .read_to_at_vectored(&[
+                    (file1.as_raw_fd(), 4, 16),
+                    (file2.as_raw_fd(), 0, 8),
+                    (file1.as_raw_fd(), 32, 8),
+                ])
+                .expect("failed to read to files"),
+            32
+        );
+        assert_eq!(reader.available_bytes(), 16);
+        assert_eq!(reader.bytes_read(), 32);
+
+        let mut content = Vec::new();
+        file1.read_to_end(&mut content).unwrap();
+        assert_eq!(content.len(), 40);
+        assert_eq!(&content[4..20], &(0u8..16).collect::<Vec<u8>>()[..]);
+        assert_eq!(&content[32..40], &(24u8..32).collect::<Vec<u8>>()[..]);
+        content.clear();
+        file2.read_to_end(&mut content).unwrap();
+        assert_eq!(content, (16u8..24).collect::<Vec<u8>>());
+
+        // Only the remaining data is consumed if the targets ask for more.
+        assert_eq!(
+            reader
+                .read_to_at_vectored(&[(file2.as_raw_fd(), 8, 32)])
+                .expect("failed to read to file"),
+            16
+        );
+        assert_eq!(reader.available_bytes(), 0);
+    }
+
     #[test]
     fn write_obj() {
         let file1 = TempFile::new().unwrap().into_file();
@@ -904,6 +1899,33 @@ mod tests {
         assert_eq!(writer.available_bytes(), 40);
     }
 
+    #[test]
+    fn writer_reserve() {
+        let file = TempFile::new().unwrap().into_file();
+        let mut buf = vec![0x0u8; 48];
+        let mut writer = Writer::<()>::new(file.as_raw_fd(), &mut buf).unwrap();
+        assert!(writer.reserve::<u32>().is_err());
+
+        writer.buffered = true;
+        let slot = writer.reserve::<u32>().unwrap();
+        assert_eq!(writer.bytes_written(), 4);
+        writer.write_all(b"payload").unwrap();
+        let len = writer.bytes_written() as u32;
+        slot.fill(&mut writer, len).unwrap();
+        assert_eq!(writer.commit(None).unwrap(), 11);
+        assert_eq!(&buf[..4], &11u32.to_ne_bytes());
+        assert_eq!(&buf[4..11], b"payload");
+
+        // Slots can't be filled beyond the written data.
+        let mut writer = Writer::<()>::new(file.as_raw_fd(), &mut buf).unwrap();
+        writer.buffered = true;
+        let slot = ReservedSlot::<u64> {
+            offset: 0,
+            phantom: PhantomData,
+        };
+        assert!(slot.fill(&mut writer, 1).is_err());
+    }
+
     #[test]
     fn write_all_from() {
         let file1 = TempFile::new().unwrap().into_file();
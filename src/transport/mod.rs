@@ -11,17 +11,22 @@
 use libc::{sysconf, _SC_PAGESIZE};
 use std::cmp;
 use std::collections::VecDeque;
+use std::ffi::CString;
+use std::fs::File;
 #[cfg(feature = "async-io")]
 use std::io::IoSlice;
 #[cfg(all(feature = "async-io", feature = "virtiofs"))]
 use std::io::IoSliceMut;
 use std::io::{self, Read};
-use std::mem::{size_of, MaybeUninit};
+use std::mem::{size_of, ManuallyDrop, MaybeUninit};
+use std::os::unix::io::{FromRawFd, RawFd};
 use std::ptr::copy_nonoverlapping;
+use std::sync::Arc;
 
 use lazy_static::lazy_static;
 use vm_memory::{ByteValued, VolatileSlice};
 
+use crate::abi::fuse_abi::Dirent;
 use crate::BitmapSlice;
 
 pub mod file_traits;
@@ -31,13 +36,20 @@ pub use file_volatile_slice::FileVolatileSlice;
 
 #[cfg(feature = "virtiofs")]
 pub mod virtiofs;
+#[cfg(all(feature = "virtiofs", feature = "async-io"))]
+pub use self::virtiofs::AsyncFsCacheReqHandler;
 #[cfg(feature = "virtiofs")]
 pub use self::virtiofs::{Error, FsCacheReqHandler, Result, Writer};
 
 #[cfg(all(feature = "fusedev", not(feature = "virtiofs")))]
 pub mod fusedev;
+#[cfg(all(feature = "fusedev", not(feature = "virtiofs"), feature = "async-io"))]
+pub use self::fusedev::AsyncFsCacheReqHandler;
 #[cfg(all(feature = "fusedev", not(feature = "virtiofs")))]
-pub use self::fusedev::{Error, FsCacheReqHandler, FuseBuf, FuseSession, Result, Writer};
+pub use self::fusedev::{
+    Error, FsCacheReqHandler, FuseBuf, FuseSession, FuseTransport, OwnedWriter, Reply, Request,
+    ReservedSlot, Result, TransportLogger, TransportStats, Writer,
+};
 
 #[derive(Clone)]
 struct IoBuffers<'a, S> {
@@ -288,12 +300,36 @@ impl<S: BitmapSlice> IoBuffers<'_, S> {
 #[derive(Clone)]
 pub struct Reader<'a, S = ()> {
     buffers: IoBuffers<'a, S>,
+    // Keeps the buffer of a reader created by `from_vec()` alive.
+    owned: Option<Arc<[u8]>>,
 }
 
 impl<S: BitmapSlice> Default for Reader<'_, S> {
     fn default() -> Self {
         Reader {
             buffers: IoBuffers::default(),
+            owned: None,
+        }
+    }
+}
+
+impl<S: BitmapSlice + Default> Reader<'static, S> {
+    /// Construct a Reader over an owned buffer, such as a fuse request built by unit tests.
+    pub fn from_vec(buf: Vec<u8>) -> Reader<'static, S> {
+        let owned: Arc<[u8]> = buf.into();
+        let mut buffers = VecDeque::new();
+        // Safe because the buffer is kept alive by the reader and its clones, and it's never
+        // written through the slice.
+        buffers.push_back(unsafe {
+            VolatileSlice::with_bitmap(owned.as_ptr() as *mut u8, owned.len(), S::default())
+        });
+
+        Reader {
+            buffers: IoBuffers {
+                buffers,
+                bytes_consumed: 0,
+            },
+            owned: Some(owned),
         }
     }
 }
@@ -316,6 +352,58 @@ impl<S: BitmapSlice> Reader<'_, S> {
         Ok(unsafe { obj.assume_init() })
     }
 
+    /// Reads a NUL-terminated string from the descriptor chain buffer.
+    ///
+    /// The terminating NUL byte is consumed but not included in the returned string. Names in
+    /// FUSE requests are packed back to back without padding, e.g. the old and new names of
+    /// `FUSE_RENAME` or the name and value of `FUSE_SETXATTR`, so nothing after the NUL byte is
+    /// consumed. Returns an `InvalidData` error if no NUL byte is found.
+    pub fn read_cstr(&mut self) -> io::Result<CString> {
+        let mut name = Vec::new();
+        let mut c = [0u8; 1];
+
+        loop {
+            if self.available_bytes() == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "string is not NUL-terminated",
+                ));
+            }
+            self.read_exact(&mut c)?;
+            if c[0] == 0 {
+                break;
+            }
+            name.push(c[0]);
+        }
+
+        // Safe to unwrap because there's no NUL byte in `name`.
+        Ok(CString::new(name).unwrap())
+    }
+
+    /// Reads a `fuse_dirent` record from the descriptor chain buffer.
+    ///
+    /// Returns the dirent header and the name, and the padding after the name, which aligns the
+    /// next record to 8 bytes, is consumed too.
+    pub fn read_dirent(&mut self) -> io::Result<(Dirent, Vec<u8>)> {
+        let dirent: Dirent = self.read_obj()?;
+        let namelen = dirent.namelen as usize;
+        let reclen = size_of::<Dirent>() + namelen;
+        let padding = ((reclen + 7) & !7) - reclen;
+        if namelen + padding > self.available_bytes() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "dirent name length exceeds buffer",
+            ));
+        }
+
+        let mut name = vec![0u8; namelen];
+        self.read_exact(&mut name)?;
+        let mut pad = [0u8; 8];
+        self.read_exact(&mut pad[..padding])?;
+
+        Ok((dirent, name))
+    }
+
     /// Reads data from the descriptor chain buffer into a file descriptor.
     /// Returns the number of bytes read from the descriptor chain buffer.
     /// The number of bytes read can be less than `count` if there isn't
@@ -366,6 +454,38 @@ impl<S: BitmapSlice> Reader<'_, S> {
         Ok(())
     }
 
+    /// Reads data from the descriptor chain buffer into several file descriptors at given offsets.
+    ///
+    /// Each target is a `(fd, offset, count)` tuple, and targets are filled in order, so one
+    /// request could be scattered into multiple backing files without an intermediate copy.
+    /// Short writes are retried until the target is filled or no data is left in the descriptor
+    /// chain buffer. Returns the total number of bytes read from the descriptor chain buffer, an
+    /// error is only returned if nothing has been read.
+    pub fn read_to_at_vectored(&mut self, targets: &[(RawFd, u64, usize)]) -> io::Result<usize> {
+        let mut total = 0;
+
+        for (fd, off, count) in targets.iter() {
+            // Safe because the fd is borrowed from the caller and never closed here.
+            let mut file = ManuallyDrop::new(unsafe { File::from_raw_fd(*fd) });
+            let mut done = 0;
+
+            while done < *count {
+                match self.read_to_at(&mut *file, count - done, off + done as u64) {
+                    Ok(0) => return Ok(total),
+                    Ok(n) => {
+                        done += n;
+                        total += n;
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) if total == 0 => return Err(e),
+                    Err(_) => return Ok(total),
+                }
+            }
+        }
+
+        Ok(total)
+    }
+
     /// Returns number of bytes available for reading.  May return an error if the combined
     /// lengths of all the buffers in the DescriptorChain would cause an integer overflow.
     pub fn available_bytes(&self) -> usize {
@@ -381,10 +501,14 @@ impl<S: BitmapSlice> Reader<'_, S> {
     /// After the split, `self` will be able to read up to `offset` bytes while the returned
     /// `Reader` can read up to `available_bytes() - offset` bytes.  Returns an error if
     /// `offset > self.available_bytes()`.
+    ///
+    /// No data is copied, and the buffers of both halves keep the slices of their dirty bitmaps
+    /// at the same offsets.
     pub fn split_at(&mut self, offset: usize) -> Result<Self> {
-        self.buffers
-            .split_at(offset)
-            .map(|buffers| Reader { buffers })
+        self.buffers.split_at(offset).map(|buffers| Reader {
+            buffers,
+            owned: self.owned.clone(),
+        })
     }
 }
 
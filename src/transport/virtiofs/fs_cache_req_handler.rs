@@ -5,6 +5,8 @@
 use std::io;
 use std::os::unix::io::RawFd;
 
+#[cfg(feature = "async-io")]
+use async_trait::async_trait;
 #[cfg(feature = "vhost-user-fs")]
 use vhost::vhost_user::message::{
     VhostUserFSSlaveMsg, VhostUserFSSlaveMsgFlags, VHOST_USER_FS_SLAVE_ENTRIES,
@@ -44,6 +46,27 @@ pub trait FsCacheReqHandler: Send + Sync + 'static {
     fn unmap(&mut self, requests: Vec<RemovemappingOne>) -> io::Result<()>;
 }
 
+/// Trait to support virtio-fs DAX Window operations in asynchronous mode.
+///
+/// It's the counterpart of [FsCacheReqHandler] for the async server, so that a worker serving
+/// other requests won't be blocked while a DAX Window mapping request is in progress.
+#[cfg(feature = "async-io")]
+#[async_trait]
+pub trait AsyncFsCacheReqHandler: Send + Sync + 'static {
+    /// Setup a dedicated mapping so that guest can access file data in DAX style.
+    async fn map(
+        &mut self,
+        foffset: u64,
+        moffset: u64,
+        len: u64,
+        flags: u64,
+        fd: RawFd,
+    ) -> io::Result<()>;
+
+    /// Remove those mappings that provide the access to file data.
+    async fn unmap(&mut self, requests: Vec<RemovemappingOne>) -> io::Result<()>;
+}
+
 #[cfg(feature = "vhost-user-fs")]
 impl FsCacheReqHandler for SlaveFsCacheReq {
     fn map(
@@ -84,3 +107,23 @@ impl FsCacheReqHandler for SlaveFsCacheReq {
         Ok(())
     }
 }
+
+// The slave request channel is a blocking socket, and messages are small enough to be sent inline.
+#[cfg(all(feature = "vhost-user-fs", feature = "async-io"))]
+#[async_trait]
+impl AsyncFsCacheReqHandler for SlaveFsCacheReq {
+    async fn map(
+        &mut self,
+        foffset: u64,
+        moffset: u64,
+        len: u64,
+        flags: u64,
+        fd: RawFd,
+    ) -> io::Result<()> {
+        FsCacheReqHandler::map(self, foffset, moffset, len, flags, fd)
+    }
+
+    async fn unmap(&mut self, requests: Vec<RemovemappingOne>) -> io::Result<()> {
+        FsCacheReqHandler::unmap(self, requests)
+    }
+}
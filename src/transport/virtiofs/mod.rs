@@ -55,6 +55,8 @@ use vm_memory::{
 use super::{FileReadWriteVolatile, FileVolatileSlice, IoBuffers, Reader};
 
 mod fs_cache_req_handler;
+#[cfg(feature = "async-io")]
+pub use self::fs_cache_req_handler::AsyncFsCacheReqHandler;
 pub use self::fs_cache_req_handler::FsCacheReqHandler;
 
 /// Error codes for Virtio queue related operations.
@@ -152,6 +154,7 @@ impl<'a> Reader<'a> {
                 buffers,
                 bytes_consumed: 0,
             },
+            owned: None,
         })
     }
 }
@@ -166,6 +169,8 @@ impl<'a> Reader<'a> {
 #[derive(Clone)]
 pub struct Writer<'a, S = ()> {
     buffers: IoBuffers<'a, S>,
+    // Writes larger than the available space are truncated instead of failing.
+    partial_write: bool,
 }
 
 impl<'a> Writer<'a> {
@@ -207,6 +212,7 @@ impl<'a> Writer<'a> {
                 buffers,
                 bytes_consumed: 0,
             },
+            partial_write: false,
         })
     }
 }
@@ -282,9 +288,20 @@ impl<'a, S: BitmapSlice> Writer<'a, S> {
     /// `Writer` can write up to `available_bytes() - offset` bytes.  Returns an error if
     /// `offset > self.available_bytes()`.
     pub fn split_at(&mut self, offset: usize) -> Result<Self> {
-        self.buffers
-            .split_at(offset)
-            .map(|buffers| Writer { buffers })
+        self.buffers.split_at(offset).map(|buffers| Writer {
+            buffers,
+            partial_write: self.partial_write,
+        })
+    }
+
+    /// Set whether writes larger than [available_bytes()](Self::available_bytes) are truncated.
+    ///
+    /// By default such writes fail with `InvalidData` and write nothing. With partial writes
+    /// enabled, they write as much data as fits and return the number of bytes written, like
+    /// `write(2)`, and return 0 once the writer is full. Writers split from this one afterwards
+    /// share the same mode.
+    pub fn set_partial_write(&mut self, enabled: bool) {
+        self.partial_write = enabled;
     }
 
     /// Commit all internal buffers of self and others
@@ -293,6 +310,20 @@ impl<'a, S: BitmapSlice> Writer<'a, S> {
         Ok(0)
     }
 
+    /// Commit all internal buffers of self and others
+    /// This is provided just to be compatible with fusedev
+    pub fn commit_all(&mut self, _others: &[&Self]) -> io::Result<usize> {
+        Ok(0)
+    }
+
+    // Get how much of `sz` bytes may be written, depending on the partial write mode.
+    fn writable_len(&self, sz: usize) -> io::Result<usize> {
+        if self.partial_write {
+            return Ok(cmp::min(sz, self.available_bytes()));
+        }
+        self.check_available_space(sz).map(|_| sz)
+    }
+
     fn check_available_space(&self, sz: usize) -> io::Result<()> {
         if sz > self.available_bytes() {
             Err(io::Error::new(
@@ -311,7 +342,7 @@ impl<'a, S: BitmapSlice> Writer<'a, S> {
 
 impl<'a, S: BitmapSlice> io::Write for Writer<'a, S> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.check_available_space(buf.len())?;
+        let buf = &buf[..self.writable_len(buf.len())?];
 
         self.buffers.consume_for_write(buf.len(), |bufs| {
             let mut rem = buf;
@@ -331,11 +362,16 @@ impl<'a, S: BitmapSlice> io::Write for Writer<'a, S> {
     }
 
     fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
-        self.check_available_space(bufs.iter().fold(0, |acc, x| acc + x.len()))?;
+        let mut len = self.writable_len(bufs.iter().fold(0, |acc, x| acc + x.len()))?;
 
         let mut count = 0;
         for buf in bufs.iter().filter(|b| !b.is_empty()) {
-            count += self.write(buf)?;
+            let n = cmp::min(len, buf.len());
+            if n == 0 {
+                break;
+            }
+            count += self.write(&buf[..n])?;
+            len -= n;
         }
         Ok(count)
     }
@@ -1028,6 +1064,37 @@ mod tests {
         assert_eq!(writer.bytes_written(), 48);
     }
 
+    #[test]
+    fn partial_write() {
+        use DescriptorType::*;
+
+        let memory_start_addr = GuestAddress(0x0);
+        let memory = GuestMemoryMmap::from_ranges(&vec![(memory_start_addr, 0x10000)]).unwrap();
+
+        let chain = create_descriptor_chain(
+            &memory,
+            GuestAddress(0x0),
+            GuestAddress(0x100),
+            vec![(Writable, 16), (Writable, 16)],
+            0,
+        )
+        .expect("create_descriptor_chain failed");
+        let mut writer = Writer::new(&memory, chain).expect("failed to create Writer");
+
+        let buf = vec![0xdeu8; 40];
+        writer.write(&buf[..]).unwrap_err();
+        assert_eq!(writer.bytes_written(), 0);
+
+        writer.set_partial_write(true);
+        let mut other = writer.split_at(8).unwrap();
+        assert_eq!(writer.write(&buf[..]).unwrap(), 8);
+        let slices = [IoSlice::new(&buf[..16]), IoSlice::new(&buf[16..])];
+        assert_eq!(other.write_vectored(&slices).unwrap(), 24);
+        assert_eq!(other.write(&buf[..]).unwrap(), 0);
+        assert_eq!(other.available_bytes(), 0);
+        assert_eq!(other.bytes_written(), 24);
+    }
+
     #[test]
     fn read_exact_to() {
         use DescriptorType::*;
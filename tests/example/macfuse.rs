@@ -17,7 +17,7 @@ use fuse_backend_rs::abi::fuse_abi::Attr;
 use fuse_backend_rs::api::filesystem::{Context, DirEntry, Entry, FileSystem, ZeroCopyWriter};
 use fuse_backend_rs::api::{server::Server, BackendFileSystem, Vfs, VfsOptions};
 use fuse_backend_rs::async_util::AsyncDriver;
-use fuse_backend_rs::transport::fusedev::{FuseChannel, FuseSession};
+use fuse_backend_rs::transport::fusedev::{Error, FuseChannel, FuseSession};
 
 pub(crate) struct HelloFileSystem {}
 
@@ -331,11 +331,15 @@ impl FuseServer {
         // Given error EBADF, it means kernel has shut down this session.
         let _ebadf = std::io::Error::from_raw_os_error(libc::EBADF);
         loop {
-            if let Some((reader, writer)) = self
-                .ch
-                .get_request()
-                .map_err(|_| std::io::Error::from_raw_os_error(libc::EINVAL))?
-            {
+            let req = match self.ch.get_request() {
+                Ok(req) => req,
+                Err(Error::SessionClosed) => {
+                    info!("fuse filesystem umounted");
+                    break;
+                }
+                Err(_) => return Err(std::io::Error::from_raw_os_error(libc::EINVAL)),
+            };
+            if let Some((reader, writer)) = req {
                 if let Err(e) = self.server.handle_message(reader, writer, None, None) {
                     match e {
                         fuse_backend_rs::Error::EncodeMessage(_ebadf) => {
@@ -11,7 +11,7 @@ use std::thread;
 use fuse_backend_rs::api::{server::Server, Vfs, VfsOptions};
 use fuse_backend_rs::async_util::AsyncDriver;
 use fuse_backend_rs::passthrough::{Config, PassthroughFs};
-use fuse_backend_rs::transport::fusedev::{FuseChannel, FuseSession};
+use fuse_backend_rs::transport::fusedev::{Error, FuseChannel, FuseSession};
 
 /// A fusedev daemon example
 #[allow(dead_code)]
@@ -54,9 +54,10 @@ impl Daemon {
     /// Mounts a fusedev daemon to the mountpoint, then start service threads to handle
     /// FUSE requests.
     pub fn mount(&mut self) -> Result<()> {
-        let mut se =
-            FuseSession::new(Path::new(&self.mountpoint), "passthru_example", "", false).unwrap();
-        se.mount().unwrap();
+        let mut se = FuseSession::builder(Path::new(&self.mountpoint))
+            .fsname("passthru_example")
+            .build()?;
+        se.mount()?;
         for _ in 0..self.thread_cnt {
             let mut server = FuseServer {
                 server: self.server.clone(),
@@ -101,11 +102,15 @@ impl FuseServer {
         // Given error EBADF, it means kernel has shut down this session.
         let _ebadf = std::io::Error::from_raw_os_error(libc::EBADF);
         loop {
-            if let Some((reader, writer)) = self
-                .ch
-                .get_request()
-                .map_err(|_| std::io::Error::from_raw_os_error(libc::EINVAL))?
-            {
+            let req = match self.ch.get_request() {
+                Ok(req) => req,
+                Err(Error::SessionClosed) => {
+                    info!("fuse filesystem umounted");
+                    break;
+                }
+                Err(_) => return Err(std::io::Error::from_raw_os_error(libc::EINVAL)),
+            };
+            if let Some((reader, writer)) = req {
                 if let Err(e) = self.server.handle_message(reader, writer, None, None) {
                     match e {
                         fuse_backend_rs::Error::EncodeMessage(_ebadf) => {
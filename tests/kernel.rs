@@ -0,0 +1,131 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! End to end tests of a passthrough file system mounted through the kernel fuse driver.
+//!
+//! They need root privileges and `/dev/fuse`, and are only built with the `root-tests` feature:
+//! `cargo test --features root-tests --test kernel`. Each test is skipped, instead of failing,
+//! when the file system can't be mounted.
+
+#![cfg(all(
+    feature = "root-tests",
+    feature = "fusedev",
+    not(feature = "virtiofs"),
+    target_os = "linux"
+))]
+
+mod example;
+
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use vmm_sys_util::tempdir::TempDir;
+
+use crate::example::passthroughfs::Daemon;
+
+// A passthrough file system serving `src` at `mnt`, unmounted when dropped.
+struct Mount {
+    src: TempDir,
+    mnt: TempDir,
+    daemon: Daemon,
+}
+
+impl Mount {
+    // Mount an empty directory, `None` if mounting isn't possible here.
+    fn new() -> Option<Self> {
+        // Safe because geteuid() has no side effect.
+        if unsafe { libc::geteuid() } != 0 || !Path::new("/dev/fuse").exists() {
+            eprintln!("skipped: needs root privileges and /dev/fuse");
+            return None;
+        }
+
+        let src = TempDir::new().unwrap();
+        let mnt = TempDir::new().unwrap();
+        let mut daemon = Daemon::new(
+            src.as_path().to_str().unwrap(),
+            mnt.as_path().to_str().unwrap(),
+            2,
+        )
+        .unwrap();
+        if let Err(e) = daemon.mount() {
+            eprintln!("skipped: failed to mount fuse file system, {}", e);
+            return None;
+        }
+        Some(Mount { src, mnt, daemon })
+    }
+
+    fn src(&self, name: &str) -> PathBuf {
+        self.src.as_path().join(name)
+    }
+
+    fn mnt(&self, name: &str) -> PathBuf {
+        self.mnt.as_path().join(name)
+    }
+}
+
+impl Drop for Mount {
+    fn drop(&mut self) {
+        self.daemon.umount().unwrap();
+    }
+}
+
+fn list(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(dir)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn integration_test_kernel_stat_read() {
+    let mount = match Mount::new() {
+        Some(m) => m,
+        None => return,
+    };
+    let data: Vec<u8> = (0..0x30000u32).map(|i| i as u8).collect();
+    fs::write(mount.src("file"), &data).unwrap();
+    fs::create_dir(mount.src("dir")).unwrap();
+
+    let src = fs::metadata(mount.src("file")).unwrap();
+    let st = fs::metadata(mount.mnt("file")).unwrap();
+    assert!(st.is_file());
+    assert_eq!(st.len(), data.len() as u64);
+    assert_eq!(st.mode(), src.mode());
+    assert_eq!((st.uid(), st.gid()), (src.uid(), src.gid()));
+    assert!(fs::metadata(mount.mnt("dir")).unwrap().is_dir());
+    let e = fs::metadata(mount.mnt("missing")).unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(libc::ENOENT));
+
+    // Large enough to be split into several read requests.
+    assert_eq!(fs::read(mount.mnt("file")).unwrap(), data);
+}
+
+#[test]
+fn integration_test_kernel_write_readdir_rename() {
+    let mount = match Mount::new() {
+        Some(m) => m,
+        None => return,
+    };
+    fs::write(mount.src("old"), b"old").unwrap();
+
+    fs::write(mount.mnt("new"), b"written through fuse").unwrap();
+    assert_eq!(fs::read(mount.src("new")).unwrap(), b"written through fuse");
+    fs::create_dir(mount.mnt("dir")).unwrap();
+    assert_eq!(list(mount.mnt.as_path()), ["dir", "new", "old"]);
+
+    fs::rename(mount.mnt("new"), mount.mnt("dir/renamed")).unwrap();
+    fs::rename(mount.mnt("old"), mount.mnt("old2")).unwrap();
+    assert_eq!(list(mount.src.as_path()), ["dir", "old2"]);
+    assert_eq!(list(&mount.src("dir")), ["renamed"]);
+    assert_eq!(
+        fs::read(mount.mnt("dir/renamed")).unwrap(),
+        b"written through fuse"
+    );
+
+    fs::remove_file(mount.mnt("dir/renamed")).unwrap();
+    fs::remove_dir(mount.mnt("dir")).unwrap();
+    assert_eq!(list(mount.mnt.as_path()), ["old2"]);
+}